@@ -2,6 +2,7 @@
 
 use super::KernelInterface;
 use failure::Error;
+use std::net::IpAddr;
 use std::process::Output;
 
 impl dyn KernelInterface {
@@ -12,4 +13,96 @@ impl dyn KernelInterface {
     pub fn del_if_from_bridge(&self, br: &str, iface: &str) -> Result<Output, Error> {
         self.run_command("brctl", &["delif", br, iface])
     }
+
+    /// Finds the interface traffic from `ip` arrives on. For hosts behind a bridge this is the
+    /// bridge member they were last seen on, or the bridge itself if that isn't known
+    pub fn get_ingress_port(&self, ip: IpAddr) -> Result<String, Error> {
+        let output = self.run_command("ip", &["route", "get", &ip.to_string()])?;
+        let dev = match word_after(&String::from_utf8(output.stdout)?, "dev") {
+            Some(dev) => dev,
+            None => bail!("No route to {}", ip),
+        };
+        if !dev.starts_with("br-") {
+            return Ok(dev);
+        }
+
+        let output = self.run_command("ip", &["neighbor", "show", &ip.to_string()])?;
+        let mac = match word_after(&String::from_utf8(output.stdout)?, "lladdr") {
+            Some(mac) => mac,
+            None => return Ok(dev),
+        };
+        let output = self.run_command("bridge", &["fdb", "show", "br", &dev])?;
+        let fdb = String::from_utf8(output.stdout)?;
+        let port = fdb
+            .lines()
+            .find(|line| line.starts_with(&mac))
+            .and_then(|line| word_after(line, "dev"));
+        Ok(port.unwrap_or(dev))
+    }
+
+    /// The bridge the interface is a member of, if any
+    pub fn get_bridge_master(&self, iface: &str) -> Result<Option<String>, Error> {
+        let output = self.run_command("ip", &["-o", "link", "show", "dev", iface])?;
+        if !output.status.success() {
+            bail!("No interface {}", iface);
+        }
+        Ok(word_after(&String::from_utf8(output.stdout)?, "master"))
+    }
+}
+
+fn word_after(output: &str, key: &str) -> Option<String> {
+    let mut words = output.split_whitespace();
+    words.find(|word| *word == key)?;
+    words.next().map(|word| word.to_string())
+}
+
+#[test]
+fn test_get_ingress_port() {
+    use crate::KI;
+
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    KI.set_mock(Box::new(move |program, args| {
+        let stdout: &[u8] = match (program.as_str(), args[0].as_str()) {
+            ("ip", "route") if args[2] == "192.168.10.100" => {
+                b"192.168.10.100 dev br-lan src 192.168.10.1 uid 0\n    cache"
+            }
+            ("ip", "route") if args[2] == "192.168.10.101" => {
+                b"192.168.10.101 dev br-lan src 192.168.10.1 uid 0\n    cache"
+            }
+            ("ip", "route") => b"fd00::1 from :: dev wg0 proto static src fd00::2 metric 1024",
+            ("ip", "neighbor") if args[2] == "192.168.10.100" => {
+                b"192.168.10.100 dev br-lan lladdr 00:00:00:aa:00:03 REACHABLE"
+            }
+            ("ip", "neighbor") => b"",
+            ("bridge", "fdb") => {
+                b"00:00:00:aa:00:01 dev eth0 master br-lan permanent
+00:00:00:aa:00:03 dev eth1 master br-lan
+33:33:00:00:00:01 dev wlan0 self permanent"
+            }
+            _ => panic!("Unexpected command {} {:?}", program, args),
+        };
+        Ok(Output {
+            stdout: stdout.to_vec(),
+            stderr: b"".to_vec(),
+            status: ExitStatus::from_raw(0),
+        })
+    }));
+
+    assert_eq!(
+        KI.get_ingress_port("192.168.10.100".parse().unwrap())
+            .unwrap(),
+        "eth1"
+    );
+    // not in the neighbor table yet
+    assert_eq!(
+        KI.get_ingress_port("192.168.10.101".parse().unwrap())
+            .unwrap(),
+        "br-lan"
+    );
+    assert_eq!(
+        KI.get_ingress_port("fd00::1".parse().unwrap()).unwrap(),
+        "wg0"
+    );
 }
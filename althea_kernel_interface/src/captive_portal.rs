@@ -0,0 +1,230 @@
+//! Firewall rules for the captive portal, plain http from the LAN to anywhere but the router
+//! itself is redirected to the local port the portal page is served on. Requests to the router
+//! are left alone so that the dashboard keeps working. The page is served over both ip versions
+//! so the same rule goes into iptables and ip6tables.
+//!
+//! When the portal is only showing a notice the redirect skips devices in an ipset, a device is
+//! added once it's been shown the page and drops out of the set again after a while, so that it
+//! sees the notice now and then without being cut off.
+
+use super::KernelInterface;
+use failure::Error;
+use std::net::IpAddr;
+
+/// The iptables command for each ip version, with the set of devices that have seen the notice
+/// and the family that set is created with
+const IP_VERSIONS: [(&str, &str, &str); 2] = [
+    ("iptables", "althea_portal_seen", "inet"),
+    ("ip6tables", "althea_portal_seen6", "inet6"),
+];
+/// How long in seconds a device that's seen the notice is left alone before it's shown it again
+const SEEN_TIMEOUT: &str = "3600";
+
+fn portal_rule<'a>(
+    action: &'a str,
+    lan_nic: &'a str,
+    port: &'a str,
+    seen_set: Option<&'a str>,
+) -> Vec<&'a str> {
+    let mut rule = vec!["-w", "-t", "nat", action, "PREROUTING"];
+    if action == "-I" {
+        rule.push("1");
+    }
+    rule.extend_from_slice(&["-i", lan_nic]);
+    if let Some(set) = seen_set {
+        rule.extend_from_slice(&["-m", "set", "!", "--match-set", set, "src"]);
+    }
+    rule.extend_from_slice(&[
+        "-p",
+        "tcp",
+        "--dport",
+        "80",
+        "-m",
+        "addrtype",
+        "!",
+        "--dst-type",
+        "LOCAL",
+        "-j",
+        "REDIRECT",
+        "--to-ports",
+        port,
+    ]);
+    rule
+}
+
+impl dyn KernelInterface {
+    /// Redirects LAN http to the portal, with `notice_only` each device is only redirected until
+    /// it's been marked with `mark_portal_seen`
+    pub fn enable_captive_portal(
+        &self,
+        lan_nic: &str,
+        port: u16,
+        notice_only: bool,
+    ) -> Result<(), Error> {
+        let port = port.to_string();
+        for (program, set, family) in IP_VERSIONS.iter() {
+            let seen_set = if notice_only {
+                self.run_command(
+                    "ipset",
+                    &[
+                        "create",
+                        *set,
+                        "hash:ip",
+                        "family",
+                        *family,
+                        "timeout",
+                        SEEN_TIMEOUT,
+                        "-exist",
+                    ],
+                )?;
+                Some(*set)
+            } else {
+                None
+            };
+            self.add_iptables_rule(program, &portal_rule("-I", lan_nic, &port, seen_set))?;
+        }
+        Ok(())
+    }
+
+    /// Removes the portal redirect, whichever kind is in place
+    pub fn disable_captive_portal(&self, lan_nic: &str, port: u16) -> Result<(), Error> {
+        let port = port.to_string();
+        for (program, set, _family) in IP_VERSIONS.iter() {
+            for seen_set in [None, Some(*set)].iter() {
+                // -D fails if the rule isn't there, which is what we want anyways
+                let check =
+                    self.run_command(program, &portal_rule("-C", lan_nic, &port, *seen_set))?;
+                if check.status.success() {
+                    self.run_command(program, &portal_rule("-D", lan_nic, &port, *seen_set))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Lets a device through a notice only portal for a while, it's been shown the notice
+    pub fn mark_portal_seen(&self, ip: IpAddr) -> Result<(), Error> {
+        // ipv4 requests to the portal page's dual stack socket show up as mapped addresses
+        let ip = match ip {
+            IpAddr::V6(v6) if v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => {
+                v6.to_ipv4().map(IpAddr::V4).unwrap_or(ip)
+            }
+            _ => ip,
+        };
+        let set = match ip {
+            IpAddr::V4(_) => IP_VERSIONS[0].1,
+            IpAddr::V6(_) => IP_VERSIONS[1].1,
+        };
+        let output = self.run_command("ipset", &["add", set, &ip.to_string(), "-exist"])?;
+        if !output.status.success() {
+            bail!(
+                "Failed to add {} to {}: {}",
+                ip,
+                set,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_enable_captive_portal() {
+    use crate::KI;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{ExitStatus, Output};
+
+    let mut counter = 0;
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        let expected = if counter <= 2 {
+            "iptables"
+        } else {
+            "ip6tables"
+        };
+        assert_eq!(program, expected);
+        match counter {
+            1 | 3 => {
+                assert_eq!(args[3], "-C");
+                assert_eq!(args[5], "-i");
+                Ok(Output {
+                    stdout: b"".to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(256),
+                })
+            }
+            2 | 4 => {
+                assert_eq!(
+                    args,
+                    vec![
+                        "-w",
+                        "-t",
+                        "nat",
+                        "-I",
+                        "PREROUTING",
+                        "1",
+                        "-i",
+                        "br-lan",
+                        "-p",
+                        "tcp",
+                        "--dport",
+                        "80",
+                        "-m",
+                        "addrtype",
+                        "!",
+                        "--dst-type",
+                        "LOCAL",
+                        "-j",
+                        "REDIRECT",
+                        "--to-ports",
+                        "4880"
+                    ]
+                );
+                Ok(Output {
+                    stdout: b"".to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(0),
+                })
+            }
+            _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
+        }
+    }));
+    KI.enable_captive_portal("br-lan", 4880, false).unwrap();
+}
+
+#[test]
+fn test_notice_only_portal() {
+    use crate::KI;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{ExitStatus, Output};
+
+    assert_eq!(
+        portal_rule("-I", "br-lan", "4880", Some("althea_portal_seen"))[6..15],
+        [
+            "-i",
+            "br-lan",
+            "-m",
+            "set",
+            "!",
+            "--match-set",
+            "althea_portal_seen",
+            "src",
+            "-p"
+        ]
+    );
+
+    KI.set_mock(Box::new(move |program, args| {
+        assert_eq!(program, "ipset");
+        assert_eq!(
+            args,
+            vec!["add", "althea_portal_seen", "192.168.10.20", "-exist"]
+        );
+        Ok(Output {
+            stdout: b"".to_vec(),
+            stderr: b"".to_vec(),
+            status: ExitStatus::from_raw(0),
+        })
+    }));
+    KI.mark_portal_seen("::ffff:192.168.10.20".parse().unwrap())
+        .unwrap();
+}
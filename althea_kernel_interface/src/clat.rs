@@ -0,0 +1,132 @@
+//! 464XLAT for gateways whose carrier only gives them IPv6, so that IPv4-only manual peers and
+//! exits can still be reached. The CLAT is OpenWrt's 464xlat protocol, an interface with it
+//! tunneled over the WAN translates our IPv4 traffic to the carrier's NAT64 prefix, which it
+//! discovers on its own, and installs an IPv4 default route through itself.
+
+use super::KernelInterface;
+use failure::Error;
+
+/// The name of the uci interface we add for the CLAT, netifd calls the device `464-xlat`
+const CLAT_UCI_IFACE: &str = "xlat";
+
+/// True if `ip addr show` output has an address of the given family with global scope
+fn has_global_addr(output: &str, family: &str) -> bool {
+    output.lines().any(|line| {
+        let mut words = line.split_whitespace();
+        words.next() == Some(family) && line.contains("scope global")
+    })
+}
+
+/// Finds the uci interface a device belongs to in the output of `uci show network`
+fn find_uci_interface(network: &[(String, String)], dev: &str) -> Option<String> {
+    network.iter().find_map(|(key, value)| {
+        let mut parts = key.split('.');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("network"), Some(iface), Some("ifname"))
+                if value.split_whitespace().any(|ifname| ifname == dev) =>
+            {
+                Some(iface.to_string())
+            }
+            _ => None,
+        }
+    })
+}
+
+impl dyn KernelInterface {
+    /// True if the uplink has a global IPv6 address but no IPv4 address, as on a carrier that
+    /// only hands out IPv6 or DS-Lite where IPv4 only exists inside the tunnel
+    pub fn is_ipv6_only_uplink(&self, iface: &str) -> Result<bool, Error> {
+        let output = self.run_command("ip", &["addr", "show", "dev", iface])?;
+        let output = String::from_utf8(output.stdout)?;
+        Ok(has_global_addr(&output, "inet6") && !has_global_addr(&output, "inet"))
+    }
+
+    /// True if the CLAT interface is configured
+    pub fn clat_enabled(&self) -> bool {
+        match self.get_uci_var(&format!("network.{}.proto", CLAT_UCI_IFACE)) {
+            Ok(proto) => proto.trim() == "464xlat",
+            Err(_) => false,
+        }
+    }
+
+    /// Configures and brings up a CLAT over the uci interface of the device `uplink`
+    pub fn setup_clat(&self, uplink: &str) -> Result<(), Error> {
+        let network: Vec<(String, String)> = self.uci_show(Some("network"))?.into_iter().collect();
+        let tunlink = match find_uci_interface(&network, uplink) {
+            Some(iface) => iface,
+            None => bail!("No uci interface for {}", uplink),
+        };
+        let prefix = format!("network.{}", CLAT_UCI_IFACE);
+        self.set_uci_var(&prefix, "interface")?;
+        self.set_uci_var(&format!("{}.proto", prefix), "464xlat")?;
+        self.set_uci_var(&format!("{}.tunlink", prefix), &tunlink)?;
+        self.uci_commit("network")?;
+        self.run_command("ifup", &[CLAT_UCI_IFACE])?;
+        Ok(())
+    }
+
+    /// Takes the CLAT down and removes its configuration
+    pub fn remove_clat(&self) -> Result<(), Error> {
+        self.run_command("ifdown", &[CLAT_UCI_IFACE])?;
+        self.del_uci_var(&format!("network.{}", CLAT_UCI_IFACE))?;
+        self.uci_commit("network")?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_is_ipv6_only_uplink() {
+    use crate::KI;
+
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+
+    KI.set_mock(Box::new(move |program, args| {
+        assert_eq!(program, "ip");
+        let stdout: &[u8] = match args[3].as_str() {
+            "eth0" => {
+                b"2: eth0: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc fq_codel state UP
+    link/ether 00:00:00:aa:00:02 brd ff:ff:ff:ff:ff:ff
+    inet6 2001:db8::2/64 scope global dynamic noprefixroute
+       valid_lft 86000sec preferred_lft 14000sec
+    inet6 fe80::200:ff:feaa:2/64 scope link
+       valid_lft forever preferred_lft forever"
+            }
+            "eth1" => {
+                b"3: eth1: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc fq_codel state UP
+    link/ether 00:00:00:aa:00:03 brd ff:ff:ff:ff:ff:ff
+    inet 203.0.113.2/24 brd 203.0.113.255 scope global eth1
+       valid_lft forever preferred_lft forever
+    inet6 2001:db8::3/64 scope global dynamic noprefixroute
+       valid_lft 86000sec preferred_lft 14000sec"
+            }
+            _ => {
+                b"4: eth2: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc fq_codel state UP
+    inet6 fe80::200:ff:feaa:4/64 scope link
+       valid_lft forever preferred_lft forever"
+            }
+        };
+        Ok(Output {
+            stdout: stdout.to_vec(),
+            stderr: b"".to_vec(),
+            status: ExitStatus::from_raw(0),
+        })
+    }));
+
+    assert!(KI.is_ipv6_only_uplink("eth0").unwrap());
+    assert!(!KI.is_ipv6_only_uplink("eth1").unwrap());
+    // no uplink at all
+    assert!(!KI.is_ipv6_only_uplink("eth2").unwrap());
+
+    let network = vec![
+        ("network.lan.ifname".to_string(), "eth0.1 eth1".to_string()),
+        ("network.backhaul.ifname".to_string(), "eth0".to_string()),
+        ("network.backhaul.proto".to_string(), "dhcpv6".to_string()),
+    ];
+    assert_eq!(
+        find_uci_interface(&network, "eth0"),
+        Some("backhaul".to_string())
+    );
+    assert_eq!(find_uci_interface(&network, "eth2"), None);
+}
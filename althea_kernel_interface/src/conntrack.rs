@@ -0,0 +1,52 @@
+//! Reading the connection tracking table to see which addresses have connections open
+
+use super::KernelInterface;
+use failure::Error;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::str::from_utf8;
+
+/// The source of the original direction of every entry in `conntrack -L` output, that's the
+/// first `src=` on each line, the second is the reply direction
+fn parse_conntrack_sources(output: &str) -> HashSet<IpAddr> {
+    output
+        .lines()
+        .filter_map(|line| {
+            line.split_whitespace()
+                .find(|field| field.starts_with("src="))
+                .and_then(|field| field["src=".len()..].parse().ok())
+        })
+        .collect()
+}
+
+impl dyn KernelInterface {
+    /// Every ipv6 address that's the source of a tracked connection right now, ipv4 is left out
+    /// since it's NATed and the table can be large enough that listing it isn't free
+    pub fn get_conntrack_sources(&self) -> Result<HashSet<IpAddr>, Error> {
+        let output = self.run_command("conntrack", &["-L", "-f", "ipv6"])?;
+        if !output.status.success() {
+            bail!(
+                "conntrack failed to list connections with {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Ok(parse_conntrack_sources(from_utf8(&output.stdout)?))
+    }
+}
+
+#[test]
+fn test_parse_conntrack_sources() {
+    let output = "\
+tcp      6 431999 ESTABLISHED src=2001:db8:1::10 dst=2606:4700::1 sport=51234 dport=443 src=2606:4700::1 dst=2001:db8:1::10 sport=443 dport=51234 [ASSURED] mark=0 use=1
+udp      17 29 src=2001:db8:1::11 dst=2001:4860:4860::8888 sport=40000 dport=53 src=2001:4860:4860::8888 dst=2001:db8:1::11 sport=53 dport=40000 mark=0 use=1
+tcp      6 86399 ESTABLISHED src=2001:db8:1::10 dst=2606:4700::2 sport=51235 dport=443 src=2606:4700::2 dst=2001:db8:1::10 sport=443 dport=51235 [ASSURED] mark=0 use=1
+icmp     1 29 src=172.168.1.2 dst=1.1.1.1 type=8 code=0 id=1 src=1.1.1.1 dst=172.168.1.2 type=0 code=0 id=1 mark=0 use=1
+garbage
+";
+    let sources = parse_conntrack_sources(output);
+    let expected: HashSet<IpAddr> = vec!["2001:db8:1::10", "2001:db8:1::11", "172.168.1.2"]
+        .into_iter()
+        .map(|ip| ip.parse().unwrap())
+        .collect();
+    assert_eq!(sources, expected);
+}
@@ -158,7 +158,7 @@ impl dyn KernelInterface {
         &self,
         target: &FilterTarget,
     ) -> Result<HashMap<(IpAddr, String), u64>, Error> {
-        self.run_command(
+        let created = self.run_command(
             "ipset",
             &[
                 "create",
@@ -169,6 +169,12 @@ impl dyn KernelInterface {
                 "counters",
             ],
         )?;
+        if !created.status.success() {
+            // left over from a read that didn't get to destroy it, its counters have most
+            // likely been billed already and swapping them back in would bill them again
+            warn!("Flushing leftover tmp_{}", target.set_name());
+            self.run_command("ipset", &["flush", &format!("tmp_{}", target.set_name())])?;
+        }
 
         self.run_command(
             "ipset",
@@ -4,6 +4,12 @@ use failure::Error;
 
 impl dyn KernelInterface {
     pub fn delete_tunnel(&self, interface: &str) -> Result<(), Error> {
+        if self
+            .try_netlink("delete link", |nl| nl.del_link(interface))
+            .is_some()
+        {
+            return Ok(());
+        }
         let output = self.run_command("ip", &["link", "del", &interface])?;
         if !output.stderr.is_empty() {
             return Err(KernelInterfaceError::RuntimeError(format!(
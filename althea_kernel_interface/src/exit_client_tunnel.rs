@@ -2,7 +2,9 @@ use super::{KernelInterface, KernelInterfaceError};
 
 use failure::Error;
 
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use ipnetwork::IpNetwork;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use althea_types::WgKey;
 
@@ -156,6 +158,88 @@ impl dyn KernelInterface {
         Ok(())
     }
 
+    /// Routes ipv6 out the exit tunnel and hands the subnet the exit delegated to us out to the
+    /// LAN using router advertisements and DHCPv6. Must run after set_client_exit_tunnel_config
+    /// since that resets the allowed ips of the exit peer
+    pub fn set_client_exit_ipv6(&self, exit_pubkey: WgKey, subnet: IpNetwork) -> Result<(), Error> {
+        let subnet = match subnet {
+            IpNetwork::V6(subnet) => subnet,
+            IpNetwork::V4(_) => bail!("Exit delegated a non ipv6 subnet {}", subnet),
+        };
+        self.run_command(
+            "wg",
+            &[
+                "set",
+                "wg_exit",
+                "peer",
+                &format!("{}", exit_pubkey),
+                "allowed-ips",
+                "0.0.0.0/0,::/0",
+            ],
+        )?;
+        self.run_command(
+            "ip",
+            &["-6", "route", "replace", "default", "dev", "wg_exit"],
+        )?;
+
+        // the router takes the first address in the subnet for itself
+        let lan_addr = format!(
+            "{}/{}",
+            Ipv6Addr::from(u128::from(subnet.network()) + 1),
+            subnet.prefix()
+        );
+        // reloading the network and odhcpd interrupts the LAN so only do it when something changed
+        if self.get_uci_var("network.lan.ip6addr").ok() == Some(lan_addr.clone()) {
+            return Ok(());
+        }
+        info!("Delegating {} to the LAN", subnet);
+        self.set_uci_var("network.lan.ip6addr", &lan_addr)?;
+        self.set_uci_var("dhcp.lan.ra", "server")?;
+        self.set_uci_var("dhcp.lan.dhcpv6", "server")?;
+        self.uci_commit("network")?;
+        self.uci_commit("dhcp")?;
+        self.refresh_initd("network")?;
+        self.refresh_initd("odhcpd")?;
+
+        Ok(())
+    }
+
+    /// Switches the LAN between ipv6 only, pointing clients at a DNS64 resolver, and dual stack.
+    /// Ipv6 only turns off DHCPv4 so that LAN clients stop picking up ipv4 addresses and
+    /// announces the resolver in router advertisements and over DHCPv6. Must run after
+    /// set_client_exit_ipv6 has given the LAN its subnet
+    pub fn set_lan_ipv6_only(&self, dns64_resolver: Option<IpAddr>) -> Result<(), Error> {
+        // restarting dnsmasq and odhcpd interrupts the LAN so only do it when something changed
+        if self.get_lan_ipv6_only() == dns64_resolver {
+            return Ok(());
+        }
+        match dns64_resolver {
+            Some(resolver) => {
+                info!("Switching the LAN to ipv6 only using DNS64 at {}", resolver);
+                self.set_uci_var("dhcp.lan.ignore", "1")?;
+                self.set_uci_var("dhcp.lan.dns", &resolver.to_string())?;
+            }
+            None => {
+                info!("Switching the LAN back to dual stack");
+                let _ = self.del_uci_var("dhcp.lan.ignore");
+                let _ = self.del_uci_var("dhcp.lan.dns");
+            }
+        }
+        self.uci_commit("dhcp")?;
+        self.refresh_initd("dnsmasq")?;
+        self.refresh_initd("odhcpd")?;
+
+        Ok(())
+    }
+
+    /// The DNS64 resolver the LAN is pointed at if it's ipv6 only
+    pub fn get_lan_ipv6_only(&self) -> Option<IpAddr> {
+        if self.get_uci_var("dhcp.lan.ignore").ok()? != "1" {
+            return None;
+        }
+        self.get_uci_var("dhcp.lan.dns").ok()?.parse().ok()
+    }
+
     /// Adds nat rules for all clients, phone clients and lan clients alike hit
     /// these same rules, there is no forward spec here becuase forward is in general
     /// allowed on the routers and we stick to restricting input and output.
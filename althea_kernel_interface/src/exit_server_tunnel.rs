@@ -1,8 +1,25 @@
 use super::{KernelInterface, KernelInterfaceError};
-use althea_types::WgKey;
+use crate::file_io::write_out;
+use althea_types::{NatPortRange, PortBlock, WgKey};
 use failure::Error;
-use std::collections::HashSet;
-use std::net::IpAddr;
+use ipnetwork::IpNetwork;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Mutex;
+
+/// Where the generated cgnat ruleset is written before being loaded by nft
+const CGNAT_RULESET_FILE: &str = "/tmp/althea_cgnat.nft";
+/// Where the generated static nat ruleset is written before being loaded by nft
+const STATIC_NAT_RULESET_FILE: &str = "/tmp/althea_static_nat.nft";
+/// Where the generated port blocking ruleset is written before being loaded by nft
+const PORT_POLICY_RULESET_FILE: &str = "/tmp/althea_port_policy.nft";
+
+lazy_static! {
+    /// The last ruleset nft loaded from each ruleset file, a ruleset that's the same as what's
+    /// already in the kernel isn't loaded again
+    static ref LOADED_RULESETS: Mutex<HashMap<&'static str, Vec<String>>> =
+        Mutex::new(HashMap::new());
+}
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub struct ExitClient {
@@ -10,6 +27,140 @@ pub struct ExitClient {
     pub public_key: WgKey,
     pub mesh_ip: IpAddr,
     pub port: u16,
+    /// the ipv6 subnet delegated to this client's LAN, if any
+    pub internet_ipv6: Option<IpNetwork>,
+    /// the share of a public ipv4 address this client is translated to in cgnat mode
+    pub nat_port_range: Option<NatPortRange>,
+    /// a public ipv4 address this client has to itself, translated 1:1 in both directions
+    pub public_ipv4: Option<Ipv4Addr>,
+}
+
+/// Ports blocked for a single client, matched by both their tunnel address and their LAN subnet
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ClientPortBlocks {
+    pub internal_ip: IpAddr,
+    pub internet_ipv6: Option<IpNetwork>,
+    pub blocks: Vec<PortBlock>,
+}
+
+/// The outbound ports an exit refuses to forward, for everyone and per client
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
+pub struct PortPolicy {
+    pub global: Vec<PortBlock>,
+    pub clients: Vec<ClientPortBlocks>,
+}
+
+/// Builds an nft ruleset rejecting client traffic to the blocked ports on its way out of the
+/// external interface, in an inet table so one rule covers both ipv4 and ipv6
+fn port_policy_ruleset(external_interface: &str, policy: &PortPolicy) -> Vec<String> {
+    let mut lines = vec![
+        "table inet althea_port_policy".to_string(),
+        "delete table inet althea_port_policy".to_string(),
+        "table inet althea_port_policy {".to_string(),
+        "    chain forward {".to_string(),
+        "        type filter hook forward priority -10; policy accept;".to_string(),
+    ];
+    let prefix = format!(
+        "        iifname \"wg_exit\" oifname \"{}\"",
+        external_interface
+    );
+    for block in policy.global.iter() {
+        lines.push(format!("{} {} reject", prefix, block));
+    }
+    let mut clients: Vec<&ClientPortBlocks> = policy.clients.iter().collect();
+    // sorted so that the same policy always produces the same ruleset
+    clients.sort_by_key(|c| c.internal_ip);
+    for c in clients {
+        let family = match c.internal_ip {
+            IpAddr::V4(_) => "ip",
+            IpAddr::V6(_) => "ip6",
+        };
+        for block in c.blocks.iter() {
+            lines.push(format!(
+                "{} {} saddr {} {} reject",
+                prefix, family, c.internal_ip, block
+            ));
+            if let Some(subnet) = c.internet_ipv6 {
+                lines.push(format!("{} ip6 saddr {} {} reject", prefix, subnet, block));
+            }
+        }
+    }
+    lines.push("    }".to_string());
+    lines.push("}".to_string());
+    lines
+}
+
+/// Builds an nft ruleset translating each client to their share of the public addresses, the
+/// table is deleted and recreated in the same transaction so reloading it is atomic. Clients
+/// without a port range yet are masqueraded behind the exit's own address
+fn cgnat_ruleset(external_interface: &str, clients: &HashSet<ExitClient>) -> Vec<String> {
+    let mut lines = vec![
+        "table ip althea_cgnat".to_string(),
+        "delete table ip althea_cgnat".to_string(),
+        "table ip althea_cgnat {".to_string(),
+        "    chain postrouting {".to_string(),
+        "        type nat hook postrouting priority 100; policy accept;".to_string(),
+    ];
+    let mut clients: Vec<&ExitClient> = clients.iter().collect();
+    // sorted so that the same clients always produce the same ruleset
+    clients.sort_by_key(|c| c.internal_ip);
+    for c in clients {
+        if let Some(range) = c.nat_port_range {
+            lines.push(format!(
+                "        oifname \"{}\" ip saddr {} ip protocol {{ tcp, udp }} snat to {}:{}-{}",
+                external_interface, c.internal_ip, range.public_ip, range.start, range.end
+            ));
+            lines.push(format!(
+                "        oifname \"{}\" ip saddr {} snat to {}",
+                external_interface, c.internal_ip, range.public_ip
+            ));
+        }
+    }
+    lines.push(format!(
+        "        oifname \"{}\" iifname \"wg_exit\" masquerade",
+        external_interface
+    ));
+    lines.push("    }".to_string());
+    lines.push("}".to_string());
+    lines
+}
+
+/// Builds an nft ruleset translating each client with a static address to it 1:1, incoming
+/// traffic for the address is sent to the client and their outgoing traffic leaves from it. The
+/// chains have a higher priority than the cgnat and masquerade rules so they win for these clients
+fn static_nat_ruleset(external_interface: &str, clients: &HashSet<ExitClient>) -> Vec<String> {
+    let mut clients: Vec<(Ipv4Addr, IpAddr)> = clients
+        .iter()
+        .filter_map(|c| c.public_ipv4.map(|public_ip| (public_ip, c.internal_ip)))
+        .collect();
+    // sorted so that the same clients always produce the same ruleset
+    clients.sort();
+
+    let mut lines = vec![
+        "table ip althea_static_nat".to_string(),
+        "delete table ip althea_static_nat".to_string(),
+        "table ip althea_static_nat {".to_string(),
+        "    chain prerouting {".to_string(),
+        "        type nat hook prerouting priority -100; policy accept;".to_string(),
+    ];
+    for (public_ip, internal_ip) in clients.iter() {
+        lines.push(format!(
+            "        iifname \"{}\" ip daddr {} dnat to {}",
+            external_interface, public_ip, internal_ip
+        ));
+    }
+    lines.push("    }".to_string());
+    lines.push("    chain postrouting {".to_string());
+    lines.push("        type nat hook postrouting priority 90; policy accept;".to_string());
+    for (public_ip, internal_ip) in clients.iter() {
+        lines.push(format!(
+            "        oifname \"{}\" ip saddr {} snat to {}",
+            external_interface, internal_ip, public_ip
+        ));
+    }
+    lines.push("    }".to_string());
+    lines.push("}".to_string());
+    lines
 }
 
 impl dyn KernelInterface {
@@ -37,7 +188,10 @@ impl dyn KernelInterface {
             args.push("endpoint".into());
             args.push(format!("[{}]:{}", c.mesh_ip, c.port));
             args.push("allowed-ips".into());
-            args.push(format!("{}", c.internal_ip));
+            match c.internet_ipv6 {
+                Some(subnet) => args.push(format!("{},{}", c.internal_ip, subnet)),
+                None => args.push(format!("{}", c.internal_ip)),
+            }
             args.push("persistent-keepalive".into());
             args.push("5".into());
 
@@ -60,6 +214,24 @@ impl dyn KernelInterface {
             }
         }
 
+        // wireguard only accepts the subnet from the right peer, the kernel still needs a route
+        // to send traffic for it into the tunnel
+        for c in clients.iter() {
+            if let Some(subnet) = c.internet_ipv6 {
+                self.run_command(
+                    "ip",
+                    &[
+                        "-6",
+                        "route",
+                        "replace",
+                        &subnet.to_string(),
+                        "dev",
+                        "wg_exit",
+                    ],
+                )?;
+            }
+        }
+
         // setup traffic classes for enforcement with flow id's derived from the ip
         // only get the flows list once
         let flows = self.get_flows("wg_exit")?;
@@ -119,19 +291,40 @@ impl dyn KernelInterface {
         Ok(())
     }
 
-    pub fn setup_nat(&self, external_interface: &str) -> Result<(), Error> {
+    /// Sets up forwarding between the exit tunnel and the internet, when `masquerade` is false
+    /// address translation is left to set_cgnat_rules
+    pub fn setup_nat(&self, external_interface: &str, masquerade: bool) -> Result<(), Error> {
+        if masquerade {
+            self.add_iptables_rule(
+                "iptables",
+                &[
+                    "-w",
+                    "-t",
+                    "nat",
+                    "-A",
+                    "POSTROUTING",
+                    "-o",
+                    external_interface,
+                    "-j",
+                    "MASQUERADE",
+                ],
+            )?;
+        }
+
         self.add_iptables_rule(
             "iptables",
             &[
                 "-w",
                 "-t",
-                "nat",
+                "filter",
                 "-A",
-                "POSTROUTING",
+                "FORWARD",
                 "-o",
                 external_interface,
+                "-i",
+                "wg_exit",
                 "-j",
-                "MASQUERADE",
+                "ACCEPT",
             ],
         )?;
 
@@ -144,14 +337,20 @@ impl dyn KernelInterface {
                 "-A",
                 "FORWARD",
                 "-o",
-                external_interface,
-                "-i",
                 "wg_exit",
+                "-i",
+                external_interface,
+                "-m",
+                "state",
+                "--state",
+                "RELATED,ESTABLISHED",
                 "-j",
                 "ACCEPT",
             ],
         )?;
 
+        // new connections to clients with a static address, set_static_nat_rules decides
+        // which those are
         self.add_iptables_rule(
             "iptables",
             &[
@@ -165,9 +364,9 @@ impl dyn KernelInterface {
                 "-i",
                 external_interface,
                 "-m",
-                "state",
-                "--state",
-                "RELATED,ESTABLISHED",
+                "conntrack",
+                "--ctstate",
+                "DNAT",
                 "-j",
                 "ACCEPT",
             ],
@@ -175,4 +374,239 @@ impl dyn KernelInterface {
 
         Ok(())
     }
+
+    /// Loads a generated ruleset with nft unless it's the one that was last loaded from `file`
+    fn load_ruleset(
+        &self,
+        file: &'static str,
+        ruleset: Vec<String>,
+        name: &str,
+    ) -> Result<(), Error> {
+        let mut loaded = LOADED_RULESETS.lock().unwrap();
+        if loaded.get(file) == Some(&ruleset) {
+            trace!("No change in the {} rules, not loading them", name);
+            return Ok(());
+        }
+        write_out(file, ruleset.clone())?;
+        let output = self.run_command("nft", &["-f", file])?;
+        if !output.status.success() {
+            // whatever is in the kernel now, it's not what we last loaded
+            loaded.remove(file);
+            return Err(KernelInterfaceError::RuntimeError(format!(
+                "received error loading {} rules: {}",
+                name,
+                String::from_utf8(output.stderr)?
+            ))
+            .into());
+        }
+        loaded.insert(file, ruleset);
+        Ok(())
+    }
+
+    /// Replaces the cgnat ruleset, translating each client to their share of the public
+    /// addresses on the way out of the external interface
+    pub fn set_cgnat_rules(
+        &self,
+        external_interface: &str,
+        clients: &HashSet<ExitClient>,
+    ) -> Result<(), Error> {
+        self.load_ruleset(
+            CGNAT_RULESET_FILE,
+            cgnat_ruleset(external_interface, clients),
+            "cgnat",
+        )
+    }
+
+    /// Replaces the static nat ruleset, mapping each client with a static public address to it
+    /// in both directions
+    pub fn set_static_nat_rules(
+        &self,
+        external_interface: &str,
+        clients: &HashSet<ExitClient>,
+    ) -> Result<(), Error> {
+        self.load_ruleset(
+            STATIC_NAT_RULESET_FILE,
+            static_nat_ruleset(external_interface, clients),
+            "static nat",
+        )
+    }
+
+    /// Replaces the port blocking ruleset, client traffic to a blocked port is rejected instead
+    /// of being forwarded to the internet
+    pub fn set_port_policy_rules(
+        &self,
+        external_interface: &str,
+        policy: &PortPolicy,
+    ) -> Result<(), Error> {
+        self.load_ruleset(
+            PORT_POLICY_RULESET_FILE,
+            port_policy_ruleset(external_interface, policy),
+            "port policy",
+        )
+    }
+}
+
+#[test]
+fn test_cgnat_ruleset() {
+    let client = |ip: &str, range: Option<&str>| ExitClient {
+        internal_ip: ip.parse().unwrap(),
+        public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+            .parse()
+            .unwrap(),
+        mesh_ip: "fd00::1".parse().unwrap(),
+        port: 59999,
+        internet_ipv6: None,
+        nat_port_range: range.map(|r| r.parse().unwrap()),
+        public_ipv4: None,
+    };
+    let mut clients = HashSet::new();
+    clients.insert(client("172.16.0.2", None));
+    clients.insert(client("172.16.0.1", Some("203.0.113.5:1024-2023")));
+
+    assert_eq!(
+        cgnat_ruleset("eth0", &clients),
+        vec![
+            "table ip althea_cgnat",
+            "delete table ip althea_cgnat",
+            "table ip althea_cgnat {",
+            "    chain postrouting {",
+            "        type nat hook postrouting priority 100; policy accept;",
+            "        oifname \"eth0\" ip saddr 172.16.0.1 ip protocol { tcp, udp } snat to 203.0.113.5:1024-2023",
+            "        oifname \"eth0\" ip saddr 172.16.0.1 snat to 203.0.113.5",
+            "        oifname \"eth0\" iifname \"wg_exit\" masquerade",
+            "    }",
+            "}",
+        ]
+    );
+}
+
+#[test]
+fn test_static_nat_ruleset() {
+    let client = |ip: &str, public_ip: Option<&str>| ExitClient {
+        internal_ip: ip.parse().unwrap(),
+        public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+            .parse()
+            .unwrap(),
+        mesh_ip: "fd00::1".parse().unwrap(),
+        port: 59999,
+        internet_ipv6: None,
+        nat_port_range: None,
+        public_ipv4: public_ip.map(|ip| ip.parse().unwrap()),
+    };
+    let mut clients = HashSet::new();
+    clients.insert(client("172.16.0.1", None));
+    clients.insert(client("172.16.0.3", Some("203.0.113.9")));
+    clients.insert(client("172.16.0.2", Some("203.0.113.8")));
+
+    assert_eq!(
+        static_nat_ruleset("eth0", &clients),
+        vec![
+            "table ip althea_static_nat",
+            "delete table ip althea_static_nat",
+            "table ip althea_static_nat {",
+            "    chain prerouting {",
+            "        type nat hook prerouting priority -100; policy accept;",
+            "        iifname \"eth0\" ip daddr 203.0.113.8 dnat to 172.16.0.2",
+            "        iifname \"eth0\" ip daddr 203.0.113.9 dnat to 172.16.0.3",
+            "    }",
+            "    chain postrouting {",
+            "        type nat hook postrouting priority 90; policy accept;",
+            "        oifname \"eth0\" ip saddr 172.16.0.2 snat to 203.0.113.8",
+            "        oifname \"eth0\" ip saddr 172.16.0.3 snat to 203.0.113.9",
+            "    }",
+            "}",
+        ]
+    );
+}
+
+#[test]
+fn test_static_nat_rules_loaded_once() {
+    use crate::KI;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+    use std::sync::Arc;
+
+    let loads = Arc::new(Mutex::new(0));
+    let counter = loads.clone();
+    KI.set_mock(Box::new(move |program, args| {
+        assert_eq!(program, "nft");
+        assert_eq!(args, vec!["-f", STATIC_NAT_RULESET_FILE]);
+        *counter.lock().unwrap() += 1;
+        Ok(Output {
+            stdout: b"".to_vec(),
+            stderr: b"".to_vec(),
+            status: ExitStatus::from_raw(0),
+        })
+    }));
+    let client = |public_ip: &str| ExitClient {
+        internal_ip: "172.16.0.2".parse().unwrap(),
+        public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+            .parse()
+            .unwrap(),
+        mesh_ip: "fd00::1".parse().unwrap(),
+        port: 59999,
+        internet_ipv6: None,
+        nat_port_range: None,
+        public_ipv4: Some(public_ip.parse().unwrap()),
+    };
+    let mut clients = HashSet::new();
+    clients.insert(client("203.0.113.8"));
+
+    KI.set_static_nat_rules("eth0", &clients).unwrap();
+    KI.set_static_nat_rules("eth0", &clients).unwrap();
+    assert_eq!(*loads.lock().unwrap(), 1);
+
+    clients.clear();
+    clients.insert(client("203.0.113.9"));
+    KI.set_static_nat_rules("eth0", &clients).unwrap();
+    assert_eq!(*loads.lock().unwrap(), 2);
+}
+
+#[test]
+fn test_port_policy_ruleset() {
+    use althea_types::PortProtocol;
+
+    let smtp = PortBlock {
+        protocol: PortProtocol::Tcp,
+        port: 25,
+        port_end: None,
+    };
+    let range = PortBlock {
+        protocol: PortProtocol::Udp,
+        port: 6881,
+        port_end: Some(6889),
+    };
+    let policy = PortPolicy {
+        global: vec![smtp],
+        clients: vec![
+            ClientPortBlocks {
+                internal_ip: "172.16.0.2".parse().unwrap(),
+                internet_ipv6: Some("2001:db8:0:2::/64".parse().unwrap()),
+                blocks: vec![range],
+            },
+            ClientPortBlocks {
+                internal_ip: "172.16.0.1".parse().unwrap(),
+                internet_ipv6: None,
+                blocks: vec![range],
+            },
+        ],
+    };
+
+    assert_eq!(
+        port_policy_ruleset("eth0", &policy),
+        vec![
+            "table inet althea_port_policy",
+            "delete table inet althea_port_policy",
+            "table inet althea_port_policy {",
+            "    chain forward {",
+            "        type filter hook forward priority -10; policy accept;",
+            "        iifname \"wg_exit\" oifname \"eth0\" tcp dport 25 reject",
+            "        iifname \"wg_exit\" oifname \"eth0\" ip saddr 172.16.0.1 udp dport 6881-6889 reject",
+            "        iifname \"wg_exit\" oifname \"eth0\" ip saddr 172.16.0.2 udp dport 6881-6889 reject",
+            "        iifname \"wg_exit\" oifname \"eth0\" ip6 saddr 2001:db8:0:2::/64 udp dport 6881-6889 reject",
+            "    }",
+            "}",
+        ]
+    );
 }
@@ -0,0 +1,110 @@
+//! Reading the OpenWRT firewall config to tell what can reach services on the router itself
+
+use super::KernelInterface;
+use failure::Error;
+use std::collections::HashMap;
+
+/// True if a `dest_port` option, a port, a range like `4877-4880` or a list of them, covers `port`
+fn covers_port(dest_port: &str, port: u16) -> bool {
+    dest_port.split_whitespace().any(|entry| {
+        let mut range = entry.splitn(2, |c| c == '-' || c == ':');
+        let start = range.next().and_then(|start| start.parse::<u16>().ok());
+        let end = match range.next() {
+            Some(end) => end.parse::<u16>().ok(),
+            None => start,
+        };
+        match (start, end) {
+            (Some(start), Some(end)) => start <= port && port <= end,
+            _ => false,
+        }
+    })
+}
+
+/// The zones in a `uci show firewall` that accept input to `port` on the router, either because
+/// they accept all input or through a rule
+fn zones_accepting_port(firewall: &HashMap<String, String>, port: u16) -> Vec<String> {
+    let option = |section: &str, name: &str| firewall.get(&format!("{}.{}", section, name));
+    let mut zones = Vec::new();
+    for (section, kind) in firewall
+        .iter()
+        .filter(|(key, _)| key.matches('.').count() == 1)
+    {
+        let zone = match kind.as_str() {
+            "zone" if option(section, "input").map(String::as_str) == Some("ACCEPT") => {
+                option(section, "name")
+            }
+            // rules without a dest are for traffic to the router itself
+            "rule"
+                if option(section, "target").map(String::as_str) == Some("ACCEPT")
+                    && option(section, "dest").is_none()
+                    && option(section, "enabled").map(String::as_str) != Some("0")
+                    && option(section, "dest_port").map_or(false, |p| covers_port(p, port)) =>
+            {
+                option(section, "src")
+            }
+            _ => None,
+        };
+        if let Some(zone) = zone {
+            if !zones.contains(zone) {
+                zones.push(zone.clone());
+            }
+        }
+    }
+    zones.sort();
+    zones
+}
+
+impl dyn KernelInterface {
+    /// The firewall zones that can reach `port` on the router by name, `*` for a rule that
+    /// accepts it from every zone
+    pub fn get_zones_accepting_port(&self, port: u16) -> Result<Vec<String>, Error> {
+        Ok(zones_accepting_port(
+            &self.uci_show(Some("firewall"))?,
+            port,
+        ))
+    }
+}
+
+#[test]
+fn test_zones_accepting_port() {
+    let firewall: HashMap<String, String> = vec![
+        ("firewall.@zone[0]", "zone"),
+        ("firewall.@zone[0].name", "lan"),
+        ("firewall.@zone[0].input", "ACCEPT"),
+        ("firewall.@zone[1]", "zone"),
+        ("firewall.@zone[1].name", "wan"),
+        ("firewall.@zone[1].input", "REJECT"),
+        ("firewall.@zone[2]", "zone"),
+        ("firewall.@zone[2].name", "mesh"),
+        ("firewall.@zone[2].input", "REJECT"),
+        ("firewall.@rule[0]", "rule"),
+        ("firewall.@rule[0].name", "Allow-Mesh-SSH"),
+        ("firewall.@rule[0].src", "mesh"),
+        ("firewall.@rule[0].dest_port", "22"),
+        ("firewall.@rule[0].target", "ACCEPT"),
+        ("firewall.@rule[1]", "rule"),
+        ("firewall.@rule[1].src", "wan"),
+        ("firewall.@rule[1].dest_port", "80 4870-4880"),
+        ("firewall.@rule[1].target", "ACCEPT"),
+        ("firewall.@rule[2]", "rule"),
+        ("firewall.@rule[2].src", "mesh"),
+        ("firewall.@rule[2].dest_port", "4877"),
+        ("firewall.@rule[2].target", "ACCEPT"),
+        ("firewall.@rule[2].enabled", "0"),
+        // forwarded through the router, not to it
+        ("firewall.@rule[3]", "rule"),
+        ("firewall.@rule[3].src", "mesh"),
+        ("firewall.@rule[3].dest", "lan"),
+        ("firewall.@rule[3].dest_port", "4877"),
+        ("firewall.@rule[3].target", "ACCEPT"),
+    ]
+    .into_iter()
+    .map(|(key, value)| (key.to_string(), value.to_string()))
+    .collect();
+
+    assert_eq!(zones_accepting_port(&firewall, 4877), vec!["lan", "wan"]);
+    assert_eq!(zones_accepting_port(&firewall, 22), vec!["lan", "mesh"]);
+    assert_eq!(zones_accepting_port(&firewall, 4881), vec!["lan"]);
+    assert!(covers_port("4877:4880", 4880));
+    assert!(!covers_port("garbage", 4880));
+}
@@ -0,0 +1,229 @@
+//! A guest wifi network isolated from the LAN. Guests get their own bridge and subnet, an ap on
+//! every radio and a firewall zone that can reach wherever the LAN forwards to (the internet) but
+//! not the LAN or the router itself beyond dhcp and dns. Everything lives in UCI so that it
+//! survives reboots, the uci sections we own are all named with a `guest` prefix. The sponsored
+//! network is set up the same way under its own name.
+
+use super::KernelInterface;
+use failure::Error;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+
+/// The name of the guest network interface in UCI
+const GUEST_NETWORK: &str = "guest";
+/// The bridge OpenWRT creates for the guest network
+const GUEST_BRIDGE: &str = "br-guest";
+/// Where we keep the guest bandwidth limit, netifd ignores options it doesn't know
+const GUEST_LIMIT_KEY: &str = "network.guest.althea_bandwidth_limit";
+
+/// Every section in a UCI config with the given prefix, keys from `uci show` are either
+/// `config.section` for the section type or `config.section.option` for its options
+fn sections_with_prefix(config: &HashMap<String, String>, prefix: &str) -> Vec<String> {
+    let mut sections: Vec<String> = config
+        .keys()
+        .filter(|key| key.matches('.').count() == 1)
+        .filter(|key| {
+            key.splitn(2, '.')
+                .nth(1)
+                .map(|name| name.starts_with(prefix))
+                .unwrap_or(false)
+        })
+        .cloned()
+        .collect();
+    sections.sort();
+    sections
+}
+
+/// The zones the LAN zone forwards to, the guest zone gets the same
+fn lan_forwards(firewall: &HashMap<String, String>) -> Vec<String> {
+    let mut dests = Vec::new();
+    for (key, value) in firewall.iter() {
+        if key.ends_with(".src") && value == "lan" {
+            let section = key.trim_end_matches(".src");
+            if let Some(dest) = firewall.get(&format!("{}.dest", section)) {
+                if !dests.contains(dest) {
+                    dests.push(dest.clone());
+                }
+            }
+        }
+    }
+    dests.sort();
+    dests
+}
+
+impl dyn KernelInterface {
+    /// The wifi radios on this device by their UCI section name, radio0, radio1 etc
+    pub fn get_wifi_radios(&self) -> Result<Vec<String>, Error> {
+        let wireless = self.uci_show(Some("wireless"))?;
+        let mut radios: Vec<String> = wireless
+            .iter()
+            .filter(|(key, value)| key.matches('.').count() == 1 && *value == "wifi-device")
+            .map(|(key, _)| key.trim_start_matches("wireless.").to_string())
+            .collect();
+        radios.sort();
+        Ok(radios)
+    }
+
+    /// Creates or updates the guest network, an open network is created if no key is provided
+    pub fn set_guest_network(
+        &self,
+        ssid: &str,
+        key: Option<&str>,
+        router_ip: Ipv4Addr,
+    ) -> Result<(), Error> {
+        self.set_isolated_network(GUEST_NETWORK, ssid, key, router_ip)
+    }
+
+    /// Removes the guest network entirely
+    pub fn remove_guest_network(&self) -> Result<(), Error> {
+        self.remove_isolated_network(GUEST_NETWORK)
+    }
+
+    /// Sets up a network isolated from the LAN like the guest network under the given name,
+    /// which is used for the bridge, dhcp pool and firewall zone and prefixes every UCI section
+    pub(crate) fn set_isolated_network(
+        &self,
+        name: &str,
+        ssid: &str,
+        key: Option<&str>,
+        router_ip: Ipv4Addr,
+    ) -> Result<(), Error> {
+        let network = format!("network.{}", name);
+        self.set_uci_var(&network, "interface")?;
+        self.set_uci_var(&format!("{}.type", network), "bridge")?;
+        self.set_uci_var(&format!("{}.proto", network), "static")?;
+        self.set_uci_var(&format!("{}.ipaddr", network), &router_ip.to_string())?;
+        self.set_uci_var(&format!("{}.netmask", network), "255.255.255.0")?;
+
+        let dhcp = format!("dhcp.{}", name);
+        self.set_uci_var(&dhcp, "dhcp")?;
+        self.set_uci_var(&format!("{}.interface", dhcp), name)?;
+        self.set_uci_var(&format!("{}.start", dhcp), "100")?;
+        self.set_uci_var(&format!("{}.limit", dhcp), "150")?;
+        self.set_uci_var(&format!("{}.leasetime", dhcp), "1h")?;
+
+        let zone = format!("firewall.{}", name);
+        self.set_uci_var(&zone, "zone")?;
+        self.set_uci_var(&format!("{}.name", zone), name)?;
+        self.set_uci_var(&format!("{}.network", zone), name)?;
+        self.set_uci_var(&format!("{}.input", zone), "REJECT")?;
+        self.set_uci_var(&format!("{}.output", zone), "ACCEPT")?;
+        self.set_uci_var(&format!("{}.forward", zone), "REJECT")?;
+        for (rule, proto, port) in [("dhcp", "udp", "67-68"), ("dns", "tcp udp", "53")].iter() {
+            let section = format!("firewall.{}_{}", name, rule);
+            self.set_uci_var(&section, "rule")?;
+            self.set_uci_var(&format!("{}.name", section), &format!("{}-{}", name, rule))?;
+            self.set_uci_var(&format!("{}.src", section), name)?;
+            self.set_uci_var(&format!("{}.proto", section), proto)?;
+            self.set_uci_var(&format!("{}.dest_port", section), port)?;
+            self.set_uci_var(&format!("{}.target", section), "ACCEPT")?;
+        }
+        let firewall = self.uci_show(Some("firewall"))?;
+        for section in sections_with_prefix(&firewall, &format!("{}_forward", name)) {
+            self.del_uci_var(&section)?;
+        }
+        for dest in lan_forwards(&firewall) {
+            let section = format!("firewall.{}_forward_{}", name, dest);
+            self.set_uci_var(&section, "forwarding")?;
+            self.set_uci_var(&format!("{}.src", section), name)?;
+            self.set_uci_var(&format!("{}.dest", section), &dest)?;
+        }
+
+        for radio in self.get_wifi_radios()? {
+            let section = format!("wireless.{}_{}", name, radio);
+            self.set_uci_var(&section, "wifi-iface")?;
+            self.set_uci_var(&format!("{}.device", section), &radio)?;
+            self.set_uci_var(&format!("{}.network", section), name)?;
+            self.set_uci_var(&format!("{}.mode", section), "ap")?;
+            self.set_uci_var(&format!("{}.ssid", section), ssid)?;
+            // clients can't see each other either
+            self.set_uci_var(&format!("{}.isolate", section), "1")?;
+            match key {
+                Some(key) => {
+                    self.set_uci_var(&format!("{}.encryption", section), "psk2")?;
+                    self.set_uci_var(&format!("{}.key", section), key)?;
+                }
+                None => {
+                    self.set_uci_var(&format!("{}.encryption", section), "none")?;
+                    let _ = self.del_uci_var(&format!("{}.key", section));
+                }
+            }
+        }
+
+        self.apply_isolated_network()
+    }
+
+    pub(crate) fn remove_isolated_network(&self, name: &str) -> Result<(), Error> {
+        for config in ["wireless", "firewall", "dhcp", "network"].iter() {
+            let values = self.uci_show(Some(*config))?;
+            for section in sections_with_prefix(&values, name) {
+                self.del_uci_var(&section)?;
+            }
+        }
+        self.apply_isolated_network()
+    }
+
+    fn apply_isolated_network(&self) -> Result<(), Error> {
+        for config in ["wireless", "firewall", "dhcp", "network"].iter() {
+            self.uci_commit(config)?;
+        }
+        self.openwrt_reset_network()?;
+        self.openwrt_reset_wireless()?;
+        self.refresh_initd("firewall")?;
+        self.refresh_initd("dnsmasq")?;
+        Ok(())
+    }
+
+    /// Limits how fast guests can download in mbit/s, or lifts the limit. The limit is also
+    /// saved so that it can be put back with `restore_guest_bandwidth_limit`
+    pub fn set_guest_bandwidth_limit(&self, limit: Option<usize>) -> Result<(), Error> {
+        match limit {
+            Some(val) => self.set_uci_var(GUEST_LIMIT_KEY, &val.to_string())?,
+            None => {
+                let _ = self.del_uci_var(GUEST_LIMIT_KEY);
+            }
+        }
+        self.uci_commit("network")?;
+        self.set_codel_shaping(GUEST_BRIDGE, limit)
+    }
+
+    pub fn get_guest_bandwidth_limit(&self) -> Option<usize> {
+        self.get_uci_var(GUEST_LIMIT_KEY).ok()?.parse().ok()
+    }
+
+    /// Puts the saved guest bandwidth limit back in place, the qdisc is lost whenever the
+    /// bridge is recreated
+    pub fn restore_guest_bandwidth_limit(&self) -> Result<(), Error> {
+        match self.get_guest_bandwidth_limit() {
+            Some(limit) => self.set_codel_shaping(GUEST_BRIDGE, Some(limit)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn test_guest_sections() {
+    let mut firewall = HashMap::new();
+    for (key, value) in [
+        ("firewall.guest", "zone"),
+        ("firewall.guest.name", "guest"),
+        ("firewall.guest_forward_exit", "forwarding"),
+        ("firewall.guest_forward_exit.src", "guest"),
+        ("firewall.guest_forward_exit.dest", "exit"),
+        ("firewall.lan_exit", "forwarding"),
+        ("firewall.lan_exit.src", "lan"),
+        ("firewall.lan_exit.dest", "exit"),
+        ("firewall.@zone[0]", "zone"),
+        ("firewall.@zone[0].name", "lan"),
+    ]
+    .iter()
+    {
+        firewall.insert(key.to_string(), value.to_string());
+    }
+
+    assert_eq!(
+        sections_with_prefix(&firewall, "guest"),
+        vec!["firewall.guest", "firewall.guest_forward_exit"]
+    );
+    assert_eq!(lan_forwards(&firewall), vec!["exit"]);
+}
@@ -0,0 +1,75 @@
+//! Byte and packet counters for every network interface out of /proc/net/dev, sampled twice
+//! they give the throughput of each interface
+
+use super::KernelInterface;
+use failure::Error;
+use std::collections::HashMap;
+use std::fs::read_to_string;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InterfaceCounters {
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+}
+
+/// Lines like '  eth0: 1234 56 0 0 0 0 0 0 7890 12 0 0 0 0 0 0' after the two header lines, the
+/// receive columns come first then the transmit ones
+fn parse_proc_net_dev(contents: &str) -> Result<HashMap<String, InterfaceCounters>, Error> {
+    let mut counters = HashMap::new();
+    for line in contents.lines().skip(2) {
+        let mut parts = line.splitn(2, ':');
+        let iface = parts.next().unwrap_or("").trim();
+        let values: Vec<u64> = match parts.next() {
+            Some(values) => values
+                .split_whitespace()
+                .map(|value| value.parse())
+                .collect::<Result<_, _>>()?,
+            None => bail!("Malformed /proc/net/dev line {}", line),
+        };
+        if values.len() < 16 {
+            bail!("Too few counters in /proc/net/dev line {}", line);
+        }
+        counters.insert(
+            iface.to_string(),
+            InterfaceCounters {
+                rx_bytes: values[0],
+                rx_packets: values[1],
+                tx_bytes: values[8],
+                tx_packets: values[9],
+            },
+        );
+    }
+    Ok(counters)
+}
+
+impl dyn KernelInterface {
+    /// The counters of every interface by name, loopback included
+    pub fn get_interface_counters(&self) -> Result<HashMap<String, InterfaceCounters>, Error> {
+        parse_proc_net_dev(&read_to_string("/proc/net/dev")?)
+    }
+}
+
+#[test]
+fn test_parse_proc_net_dev() {
+    let contents = "Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo:   58476     612    0    0    0     0          0         0    58476     612    0    0    0     0       0          0
+  eth0: 9876543210 7654321    0   12    0     0          0      1234 1234567890 2345678    0    0    0     0       0          0
+wg_exit:    1000      10    0    0    0     0          0         0     2000      20    0    0    0     0       0          0
+";
+    let counters = parse_proc_net_dev(contents).unwrap();
+    assert_eq!(counters.len(), 3);
+    assert_eq!(
+        counters["eth0"],
+        InterfaceCounters {
+            rx_bytes: 9_876_543_210,
+            rx_packets: 7_654_321,
+            tx_bytes: 1_234_567_890,
+            tx_packets: 2_345_678,
+        }
+    );
+    assert_eq!(counters["wg_exit"].tx_bytes, 2000);
+    assert!(parse_proc_net_dev("header\nheader\n  eth0: 1 2 3\n").is_err());
+}
@@ -27,6 +27,12 @@ impl dyn KernelInterface {
 
     /// Deletes an named interface
     pub fn del_interface(&self, name: &str) -> Result<(), Error> {
+        if self
+            .try_netlink("delete link", |nl| nl.del_link(name))
+            .is_some()
+        {
+            return Ok(());
+        }
         self.run_command("ip", &["link", "del", "dev", name])?;
         Ok(())
     }
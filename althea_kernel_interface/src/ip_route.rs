@@ -1,9 +1,36 @@
 use super::KernelInterface;
+use crate::netlink::parse_route;
 
 use std::net::IpAddr;
+use std::sync::Mutex;
 
 use failure::Error;
 
+/// The routing table the backup uplink's default route is kept in while failed over to it
+pub const FAILOVER_TABLE: &str = "30";
+/// Priority of the rule that looks up the main table for everything but its default route
+const FAILOVER_MAIN_PREF: &str = "31000";
+/// Priority of the rule pointing at the failover table, right after and ahead of the main table
+const FAILOVER_PREF: &str = "31001";
+
+lazy_static! {
+    /// The uplink we've failed over from. Its default route stays in the main table while we're
+    /// on the backup, so it's what `ip route list default` keeps showing, but it goes nowhere
+    static ref FAILED_UPLINK: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/// Whether a route goes out of the uplink we've failed over from
+fn via_failed_uplink(route: &[String]) -> bool {
+    let failed = FAILED_UPLINK.lock().unwrap();
+    let failed = match failed.as_ref() {
+        Some(iface) => iface,
+        None => return false,
+    };
+    route
+        .windows(2)
+        .any(|pair| pair[0] == "dev" && pair[1] == *failed)
+}
+
 pub enum IpRoute {
     /// For creating default routes
     DefaultRoute,
@@ -43,8 +70,134 @@ impl dyn KernelInterface {
         )
     }
 
+    /// Returns the default route which goes out of the given interface, if the interface has
+    /// no default route of its own (common for point to point modems) a plain device route is
+    /// returned instead
+    pub fn get_iface_default_route(&self, iface: &str) -> Result<Vec<String>, Error> {
+        let output = self.run_command("ip", &["route", "list", "default", "dev", iface])?;
+        let stdout = String::from_utf8(output.stdout)?;
+
+        match stdout.lines().find(|line| line.starts_with("default")) {
+            Some(line) => {
+                let mut route: Vec<String> =
+                    line.split_whitespace().map(|s| s.to_string()).collect();
+                // ip omits the dev when it's part of the query, add it back
+                if !route.contains(&"dev".to_string()) {
+                    route.push("dev".to_string());
+                    route.push(iface.to_string());
+                }
+                Ok(route)
+            }
+            None => Ok(vec![
+                "default".to_string(),
+                "dev".to_string(),
+                iface.to_string(),
+            ]),
+        }
+    }
+
+    /// Routes everything that isn't covered by a more specific route out of the given default
+    /// route, without touching the default route in the main table. The route goes into a table
+    /// of its own, looked up right after a rule that has the main table looked up for everything
+    /// but its default route, so on removal the main table is just as it was
+    pub fn set_failover_route(&self, route: &[String]) -> Result<(), Error> {
+        let mut args = vec!["route", "replace", "default"];
+        // only the next hop matters, proto and metric belong to the original route
+        let mut tokens = route.iter();
+        while let Some(token) = tokens.next() {
+            if token == "via" || token == "dev" {
+                args.push(token.as_str());
+                if let Some(value) = tokens.next() {
+                    args.push(value.as_str());
+                }
+            }
+        }
+        args.push("table");
+        args.push(FAILOVER_TABLE);
+        let output = self.run_command("ip", &args)?;
+        if !output.status.success() {
+            bail!(
+                "Failed to add failover route with {}",
+                String::from_utf8(output.stderr)?
+            );
+        }
+
+        let rules: [(&str, &[&str]); 2] = [
+            (
+                FAILOVER_MAIN_PREF,
+                &["lookup", "main", "suppress_prefixlength", "0"],
+            ),
+            (FAILOVER_PREF, &["lookup", FAILOVER_TABLE]),
+        ];
+        for (pref, rule) in rules.iter().cloned() {
+            let existing = self.run_command("ip", &["rule", "list", "pref", pref])?;
+            if !existing.stdout.is_empty() {
+                continue;
+            }
+            let mut args = vec!["rule", "add", "pref", pref];
+            args.extend(rule.iter());
+            let output = self.run_command("ip", &args)?;
+            if !output.status.success() {
+                bail!(
+                    "Failed to add failover rule with {}",
+                    String::from_utf8(output.stderr)?
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks an uplink as failed over from, or none with None. Its default route is then never
+    /// taken as the one in the settings, which manual peers and dns servers are routed over
+    pub fn set_failed_uplink(&self, iface: Option<&str>) {
+        *FAILED_UPLINK.lock().unwrap() = iface.map(|iface| iface.to_string());
+    }
+
+    /// Undoes set_failover_route, fine to call when it's not set
+    pub fn clear_failover_route(&self) -> Result<(), Error> {
+        for pref in [FAILOVER_PREF, FAILOVER_MAIN_PREF].iter().cloned() {
+            let existing = self.run_command("ip", &["rule", "list", "pref", pref])?;
+            if !existing.stdout.is_empty() {
+                self.run_command("ip", &["rule", "del", "pref", pref])?;
+            }
+        }
+        self.run_command("ip", &["route", "flush", "table", FAILOVER_TABLE])?;
+        Ok(())
+    }
+
+    /// Removes the host routes pinned through a gateway on the given interface, these are the
+    /// routes we add for manual peers and dns servers and must be cleared when changing uplinks
+    pub fn remove_pinned_routes(&self, iface: &str) -> Result<(), Error> {
+        let output = self.run_command("ip", &["route", "list", "dev", iface])?;
+        let stdout = String::from_utf8(output.stdout)?;
+
+        for line in stdout.lines() {
+            if line.starts_with("default") || !line.contains("via") {
+                continue;
+            }
+            if let Some(dest) = line.split_whitespace().next() {
+                self.run_command("ip", &["route", "del", dest, "dev", iface])?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn set_route<T: ToString>(&self, to: &T, route: &[String]) -> Result<(), Error> {
         let to = to.to_string();
+        let dest = match to.as_str() {
+            "default" => Some(None),
+            addr => addr.parse().ok().map(Some),
+        };
+        if let (Some(dest), Some((via, dev))) = (dest, parse_route(route)) {
+            if self
+                .try_netlink("add route", |nl| {
+                    nl.add_route(dest, via, dev.as_ref().map(|s| s.as_str()))
+                })
+                .is_some()
+            {
+                return Ok(());
+            }
+        }
         let mut def_route = vec!["route", "add", &to];
 
         let tokens = route.iter().skip(1);
@@ -65,8 +218,8 @@ impl dyn KernelInterface {
             None => return Ok(()),
         };
 
-        if !def_route.contains(&String::from("wg_exit")) {
-            // update the default route if default route is not wg exit
+        if !def_route.contains(&String::from("wg_exit")) && !via_failed_uplink(&def_route) {
+            // update the default route if default route is not wg exit or the dead uplink
             *settings_default_route = def_route.clone();
         }
         Ok(())
@@ -91,7 +244,7 @@ impl dyn KernelInterface {
             Some(route) => {
                 if route.contains(&String::from("wg_exit")) {
                     self.set_route(&IpRoute::DefaultRoute, settings_default_route)?;
-                } else {
+                } else if !via_failed_uplink(&route) {
                     *settings_default_route = route;
                 }
             }
@@ -249,3 +402,73 @@ fn test_set_default_route() {
     KI.set_route(&IpRoute::DefaultRoute, &vec![])
         .expect("Unable to set default route");
 }
+
+#[test]
+fn test_set_failover_route() {
+    use crate::KI;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+
+    let mut counter = 0;
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        assert_eq!(program, "ip");
+        let stdout = match counter {
+            1 => {
+                assert_eq!(
+                    args,
+                    vec![
+                        "route",
+                        "replace",
+                        "default",
+                        "via",
+                        "192.168.8.1",
+                        "dev",
+                        "wwan0",
+                        "table",
+                        "30"
+                    ]
+                );
+                ""
+            }
+            2 => {
+                assert_eq!(args, vec!["rule", "list", "pref", "31000"]);
+                ""
+            }
+            3 => {
+                assert_eq!(
+                    args,
+                    vec![
+                        "rule",
+                        "add",
+                        "pref",
+                        "31000",
+                        "lookup",
+                        "main",
+                        "suppress_prefixlength",
+                        "0"
+                    ]
+                );
+                ""
+            }
+            // already in place from an earlier failover
+            4 => {
+                assert_eq!(args, vec!["rule", "list", "pref", "31001"]);
+                "31001:\tfrom all lookup 30\n"
+            }
+            _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
+        };
+        Ok(Output {
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: b"".to_vec(),
+            status: ExitStatus::from_raw(0),
+        })
+    }));
+
+    let route: Vec<String> = "default via 192.168.8.1 dev wwan0 proto static metric 20"
+        .split_whitespace()
+        .map(|s| s.to_string())
+        .collect();
+    KI.set_failover_route(&route).unwrap();
+}
@@ -14,7 +14,10 @@ use std::time::Instant;
 use std::str;
 
 pub mod bridge_tools;
+mod captive_portal;
 mod check_cron;
+mod clat;
+mod conntrack;
 mod counter;
 mod create_wg_key;
 mod delete_tunnel;
@@ -22,8 +25,11 @@ mod dns;
 mod exit_client_tunnel;
 mod exit_server_tunnel;
 pub mod file_io;
+mod firewall;
 mod fs_sync;
 mod get_neighbors;
+mod guest_network;
+mod interface_counters;
 mod interface_tools;
 mod ip_addr;
 mod ip_route;
@@ -31,19 +37,37 @@ mod iptables;
 mod is_openwrt;
 mod link_local_tools;
 mod manipulate_uci;
+mod mtu;
+mod nat64;
+pub mod netlink;
+mod ntp;
 pub mod open_tunnel;
 mod openwrt_ubus;
 pub mod opkg_feeds;
 mod ping_check;
 mod set_system_password;
 mod setup_wg_if;
+mod split_tunnel;
+mod sponsored_network;
+mod system_health;
+mod traceroute;
 mod traffic_control;
 mod udp_socket_table;
 pub mod wg_iface_counter;
+mod wg_keepalive;
+mod wifi_regulatory;
+mod wifi_stations;
 
 pub use crate::counter::FilterTarget;
 pub use crate::create_wg_key::WgKeypair;
-pub use crate::exit_server_tunnel::ExitClient;
+pub use crate::exit_server_tunnel::{ClientPortBlocks, ExitClient, PortPolicy};
+pub use crate::interface_counters::InterfaceCounters;
+pub use crate::netlink::Netlink;
+pub use crate::split_tunnel::PolicyRoute;
+pub use crate::sponsored_network::SponsoredCounters;
+pub use crate::system_health::{ConntrackUsage, LoadAverage, MemoryInfo, StorageInfo, ThermalZone};
+pub use crate::wifi_regulatory::{RegDomain, RegRule};
+pub use crate::wifi_stations::WifiStation;
 
 use failure::Error;
 use std::net::AddrParseError;
@@ -91,6 +115,18 @@ lazy_static! {
     pub static ref KI: Box<dyn KernelInterface> = Box::new(LinuxCommandRunner {});
 }
 
+#[cfg(feature = "netlink")]
+lazy_static! {
+    /// Probed once, if the kernel can't do netlink everything shells out like before
+    static ref NETLINK: Option<Netlink> = match Netlink::connect() {
+        Ok(netlink) => Some(netlink),
+        Err(e) => {
+            warn!("Netlink is unavailable, using shell commands {:?}", e);
+            None
+        }
+    };
+}
+
 pub trait CommandRunner {
     fn run_command(&self, program: &str, args: &[&str]) -> Result<Output, Error>;
     fn set_mock(&self, mock: Box<dyn FnMut(String, Vec<String>) -> Result<Output, Error> + Send>);
@@ -182,7 +218,19 @@ impl CommandRunner for TestCommandRunner {
     }
 }
 
-pub trait KernelInterface: CommandRunner + Sync + Send {}
+pub trait KernelInterface: CommandRunner + Sync + Send {
+    /// The netlink backend, operations that support it try it first and shell out if there
+    /// is none or it fails. Always None unless built with the netlink feature
+    fn netlink(&self) -> Option<&Netlink> {
+        None
+    }
+}
 
-impl KernelInterface for LinuxCommandRunner {}
+impl KernelInterface for LinuxCommandRunner {
+    #[cfg(feature = "netlink")]
+    fn netlink(&self) -> Option<&Netlink> {
+        NETLINK.as_ref()
+    }
+}
+// tests mock the shell commands so they never use netlink
 impl KernelInterface for TestCommandRunner {}
@@ -0,0 +1,116 @@
+//! Path MTU probing for per hop tunnels. WireGuard packets that are too large for some radio
+//! link between two peers are silently dropped, so small packets (like Babel hellos) go through
+//! while anything full sized is blackholed. To avoid this we probe the path to the peer with
+//! unfragmentable pings and size the tunnel interface to fit.
+
+use super::KernelInterface;
+use failure::Error;
+use std::cmp::max;
+use std::net::IpAddr;
+
+/// WireGuard encapsulation overhead over ipv6, 40 byte ip header, 8 byte udp header,
+/// and 32 bytes of WireGuard header and authentication tag
+const WG_OVERHEAD_V6: u16 = 80;
+/// WireGuard encapsulation overhead over ipv4, 20 byte ip header instead of 40
+const WG_OVERHEAD_V4: u16 = 60;
+/// ICMP echo overhead on top of the ping payload
+const PING_OVERHEAD_V6: u16 = 48;
+const PING_OVERHEAD_V4: u16 = 28;
+/// Link MTUs seen in the wild, largest first, ethernet, PPPoE, and various radio and tunneled
+/// backhauls down to the ipv6 minimum
+const CANDIDATE_MTUS: [u16; 8] = [1500, 1492, 1480, 1460, 1420, 1400, 1360, 1280];
+/// The lowest MTU any ipv6 link can have, the mesh runs ipv6 over the tunnels so this is also
+/// the lowest a tunnel interface can go
+pub const MIN_PATH_MTU: u16 = 1280;
+
+impl dyn KernelInterface {
+    /// Finds the largest candidate MTU at which an unfragmentable ping reaches the peer. If not
+    /// even the smallest gets through the peer is most likely dropping pings rather than sitting
+    /// behind a link that small, so that's an error and the tunnel keeps the mtu it has
+    pub fn probe_path_mtu(&self, ip: &IpAddr, phy_iface: Option<&str>) -> Result<u16, Error> {
+        let overhead = match ip {
+            IpAddr::V4(_) => PING_OVERHEAD_V4,
+            IpAddr::V6(_) => PING_OVERHEAD_V6,
+        };
+        let ip_str = ip.to_string();
+        for mtu in CANDIDATE_MTUS.iter() {
+            let size = format!("{}", mtu - overhead);
+            let mut args = vec!["-c", "1", "-W", "1", "-M", "do", "-s", &size];
+            if let Some(iface) = phy_iface {
+                args.push("-I");
+                args.push(iface);
+            }
+            args.push(&ip_str);
+
+            let output = self.run_command("ping", &args)?;
+            if output.status.success() {
+                trace!("Path MTU to {} is {}", ip, mtu);
+                return Ok(*mtu);
+            }
+        }
+        bail!("No mtu probe to {} got through", ip)
+    }
+
+    /// The MTU a WireGuard interface needs so that its packets fit in the given path MTU. Paths
+    /// too small for that still get the ipv6 minimum, the encapsulated packets are fragmented by
+    /// our kernel on the way out which is slower, but an interface below it can't carry ipv6
+    pub fn wg_mtu_for_path(&self, path_mtu: u16, endpoint: &IpAddr) -> u16 {
+        let overhead = match endpoint {
+            IpAddr::V4(_) => WG_OVERHEAD_V4,
+            IpAddr::V6(_) => WG_OVERHEAD_V6,
+        };
+        max(path_mtu.saturating_sub(overhead), MIN_PATH_MTU)
+    }
+
+    pub fn set_mtu(&self, iface: &str, mtu: u16) -> Result<(), Error> {
+        let output = self.run_command(
+            "ip",
+            &["link", "set", "dev", iface, "mtu", &mtu.to_string()],
+        )?;
+        if !output.status.success() {
+            bail!(
+                "Failed to set mtu on {} with {}",
+                iface,
+                String::from_utf8(output.stderr)?
+            );
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_probe_path_mtu() {
+    use crate::KI;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+    let mut counter = 0;
+
+    // the first two sizes are dropped, the third gets through
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        assert_eq!(program, "ping");
+        let expected_size = match counter {
+            1 => "1452",
+            2 => "1444",
+            3 => "1432",
+            _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
+        };
+        assert_eq!(args[7], expected_size);
+        assert_eq!(args[9], "eth0");
+        Ok(Output {
+            stdout: b"".to_vec(),
+            stderr: b"".to_vec(),
+            status: ExitStatus::from_raw(if counter == 3 { 0 } else { 256 }),
+        })
+    }));
+
+    let ip: IpAddr = "fe80::1".parse().unwrap();
+    assert_eq!(KI.probe_path_mtu(&ip, Some("eth0")).unwrap(), 1480);
+    assert_eq!(KI.wg_mtu_for_path(1480, &ip), 1400);
+    assert_eq!(KI.wg_mtu_for_path(1280, &ip), MIN_PATH_MTU);
+    assert_eq!(
+        KI.wg_mtu_for_path(1360, &"192.0.2.1".parse().unwrap()),
+        1300
+    );
+}
@@ -0,0 +1,102 @@
+//! NAT64 on the exit, so that clients with IPv6-only LANs can still reach the IPv4 internet.
+//! Translation is done by a Jool instance in netfilter mode, which picks up traffic to the NAT64
+//! prefix as it comes in from the exit tunnel and sends it out from the exit's own IPv4
+//! addresses. DNS64 is left to a resolver the exit points clients at.
+
+use super::{KernelInterface, KernelInterfaceError};
+use failure::Error;
+use ipnetwork::IpNetwork;
+
+/// The name of our Jool instance
+const JOOL_INSTANCE: &str = "althea";
+
+/// Prefix lengths RFC 6052 allows for embedding ipv4 addresses
+const NAT64_PREFIX_LENGTHS: [u8; 6] = [32, 40, 48, 56, 64, 96];
+
+/// Finds the pool6 prefix in the output of `jool global display --csv`
+fn parse_jool_pool6(output: &str) -> Option<IpNetwork> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(2, ',');
+            match (fields.next(), fields.next()) {
+                (Some("pool6"), Some(value)) => value.trim().parse().ok(),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+impl dyn KernelInterface {
+    /// The prefix our NAT64 instance translates, none if it isn't running
+    pub fn get_nat64_prefix(&self) -> Result<Option<IpNetwork>, Error> {
+        let output =
+            self.run_command("jool", &["-i", JOOL_INSTANCE, "global", "display", "--csv"])?;
+        // the instance doesn't exist
+        if !output.status.success() {
+            return Ok(None);
+        }
+        Ok(parse_jool_pool6(&String::from_utf8(output.stdout)?))
+    }
+
+    /// Starts translating the given prefix, an instance for another prefix is replaced
+    pub fn set_nat64(&self, prefix: IpNetwork) -> Result<(), Error> {
+        match prefix {
+            IpNetwork::V6(v6) if NAT64_PREFIX_LENGTHS.contains(&v6.prefix()) => {}
+            _ => bail!("{} is not a valid NAT64 prefix", prefix),
+        }
+        match self.get_nat64_prefix()? {
+            Some(current) if current == prefix => return Ok(()),
+            Some(_) => self.remove_nat64()?,
+            None => {}
+        }
+
+        self.run_command("modprobe", &["jool"])?;
+        let output = self.run_command(
+            "jool",
+            &[
+                "instance",
+                "add",
+                JOOL_INSTANCE,
+                "--netfilter",
+                "--pool6",
+                &prefix.to_string(),
+            ],
+        )?;
+        if !output.status.success() {
+            return Err(KernelInterfaceError::RuntimeError(format!(
+                "received error starting NAT64: {}",
+                String::from_utf8(output.stderr)?
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Stops our NAT64 instance, if it's running
+    pub fn remove_nat64(&self) -> Result<(), Error> {
+        if self.get_nat64_prefix()?.is_none() {
+            return Ok(());
+        }
+        let output = self.run_command("jool", &["instance", "remove", JOOL_INSTANCE])?;
+        if !output.status.success() {
+            return Err(KernelInterfaceError::RuntimeError(format!(
+                "received error stopping NAT64: {}",
+                String::from_utf8(output.stderr)?
+            ))
+            .into());
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_parse_jool_pool6() {
+    let output = "Name,Value\nmanually-enabled,true\npool6,64:ff9b::/96\nlowest-ipv6-mtu,1280\n";
+    assert_eq!(
+        parse_jool_pool6(output),
+        Some("64:ff9b::/96".parse().unwrap())
+    );
+    assert_eq!(parse_jool_pool6("Name,Value\npool6,(unset)\n"), None);
+    assert_eq!(parse_jool_pool6(""), None);
+}
@@ -0,0 +1,154 @@
+//! A netlink backend for the most common KernelInterface operations, creating and deleting wg
+//! links, configuring wg peers, addresses and routes. The `ip` and `wg` binaries shipped by
+//! different firmwares vary in version and output format, talking to the kernel directly
+//! sidesteps that. Netlink is used when the kernel supports it, which is probed once on start,
+//! and any operation that fails over netlink is retried by shelling out the old way.
+//!
+//! rtnetlink needs a tokio 0.2 runtime of its own next to the one actix runs on, so the backend
+//! is only built with the `netlink` feature until rita moves to the same runtime. Without it
+//! `Netlink` can't be constructed and every operation shells out.
+
+use super::KernelInterface;
+use althea_types::WgKey;
+use failure::Error;
+use std::net::{IpAddr, SocketAddr};
+
+#[cfg(feature = "netlink")]
+mod backend;
+
+#[cfg(feature = "netlink")]
+pub use self::backend::Netlink;
+
+/// Stands in for the backend when it's not built, there are no values of it so the closures
+/// handed to `try_netlink` are never run
+#[cfg(not(feature = "netlink"))]
+pub enum Netlink {}
+
+#[cfg(not(feature = "netlink"))]
+impl Netlink {
+    pub fn add_wg_link(&self, _name: &str) -> Result<(), Error> {
+        match *self {}
+    }
+
+    pub fn del_link(&self, _name: &str) -> Result<(), Error> {
+        match *self {}
+    }
+
+    pub fn set_link_up(&self, _name: &str) -> Result<(), Error> {
+        match *self {}
+    }
+
+    pub fn add_address(&self, _name: &str, _ip: IpAddr, _prefix: u8) -> Result<(), Error> {
+        match *self {}
+    }
+
+    pub fn add_route(
+        &self,
+        _to: Option<IpAddr>,
+        _via: Option<IpAddr>,
+        _dev: Option<&str>,
+    ) -> Result<(), Error> {
+        match *self {}
+    }
+
+    pub fn wg_peers(&self, _name: &str) -> Result<Vec<WgKey>, Error> {
+        match *self {}
+    }
+
+    pub fn wg_set(
+        &self,
+        _name: &str,
+        _listen_port: u16,
+        _private_key: &WgKey,
+        _peer: &WgPeerConfig,
+    ) -> Result<(), Error> {
+        match *self {}
+    }
+
+    pub fn wg_set_endpoint(
+        &self,
+        _name: &str,
+        _public_key: &WgKey,
+        _endpoint: &SocketAddr,
+    ) -> Result<(), Error> {
+        match *self {}
+    }
+
+    pub fn scope_id(&self, _name: &str) -> Result<u32, Error> {
+        match *self {}
+    }
+}
+
+/// A wg peer as `wg set` would take it
+pub struct WgPeerConfig<'a> {
+    pub public_key: &'a WgKey,
+    pub endpoint: SocketAddr,
+    /// (address, prefix length) pairs
+    pub allowed_ips: &'a [(IpAddr, u8)],
+    pub persistent_keepalive: u16,
+}
+
+/// Splits an `ip route` style route into its gateway and device, None if it uses anything else
+/// and so has to be handed to `ip` as is
+pub fn parse_route(route: &[String]) -> Option<(Option<IpAddr>, Option<String>)> {
+    let mut via = None;
+    let mut dev = None;
+    // the first token is the destination, the caller supplies its own
+    let mut tokens = route.iter().skip(1);
+    while let Some(token) = tokens.next() {
+        match token.as_str() {
+            "via" => via = Some(tokens.next()?.parse().ok()?),
+            "dev" => dev = Some(tokens.next()?.clone()),
+            _ => return None,
+        }
+    }
+    if via.is_none() && dev.is_none() {
+        return None;
+    }
+    Some((via, dev))
+}
+
+impl dyn KernelInterface {
+    /// Runs an operation over netlink if we can, None means there is no netlink backend or the
+    /// operation failed and the caller should shell out instead
+    pub(crate) fn try_netlink<T>(
+        &self,
+        what: &str,
+        op: impl FnOnce(&Netlink) -> Result<T, Error>,
+    ) -> Option<T> {
+        let netlink = self.netlink()?;
+        match op(netlink) {
+            Ok(val) => Some(val),
+            Err(e) => {
+                warn!(
+                    "Netlink {} failed with {:?}, falling back to shell",
+                    what, e
+                );
+                None
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parse_route() {
+    let route = |s: &str| -> Vec<String> { s.split_whitespace().map(|s| s.to_string()).collect() };
+
+    assert_eq!(
+        parse_route(&route("default via 192.168.1.1 dev eth0")),
+        Some((
+            Some("192.168.1.1".parse().unwrap()),
+            Some("eth0".to_string())
+        ))
+    );
+    assert_eq!(
+        parse_route(&route("default dev wwan0")),
+        Some((None, Some("wwan0".to_string())))
+    );
+    // anything ip would need to interpret stays with ip
+    assert_eq!(
+        parse_route(&route("default via 192.168.1.1 dev eth0 proto static")),
+        None
+    );
+    assert_eq!(parse_route(&route("default")), None);
+}
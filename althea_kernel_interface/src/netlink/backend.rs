@@ -0,0 +1,221 @@
+//! The netlink backend proper, only built with the netlink feature
+
+use super::WgPeerConfig;
+use althea_types::WgKey;
+use failure::Error;
+use futures::TryStreamExt;
+use rtnetlink::Handle;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use tokio::runtime::Runtime;
+use wireguard_uapi::{set, DeviceInterface, RouteSocket, WgSocket};
+
+pub struct Netlink {
+    /// rtnetlink is async, calls are run to completion on this runtime which also drives the
+    /// netlink connection
+    runtime: Mutex<Runtime>,
+    handle: Handle,
+}
+
+impl Netlink {
+    /// Opens the netlink sockets, fails if the kernel is missing rtnetlink or the wireguard
+    /// generic netlink family
+    pub fn connect() -> Result<Netlink, Error> {
+        let runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_io()
+            .build()?;
+        let (connection, handle, _) = runtime.enter(rtnetlink::new_connection)?;
+        runtime.spawn(connection);
+        // make sure the wireguard family exists before we commit to netlink
+        WgSocket::connect().map_err(|e| format_err!("No wireguard netlink {:?}", e))?;
+        Ok(Netlink {
+            runtime: Mutex::new(runtime),
+            handle,
+        })
+    }
+
+    fn link_index(&self, name: &str) -> Result<Option<u32>, Error> {
+        let links = self
+            .handle
+            .link()
+            .get()
+            .set_name_filter(name.to_string())
+            .execute()
+            .try_collect::<Vec<_>>();
+        match self.runtime.lock().unwrap().block_on(links) {
+            Ok(links) => Ok(links.first().map(|link| link.header.index)),
+            // the kernel answers a filtered get for a missing link with ENODEV
+            Err(rtnetlink::Error::NetlinkError(ref msg)) if msg.code == -19 => Ok(None),
+            Err(e) => bail!("Failed to get link {} {:?}", name, e),
+        }
+    }
+
+    fn require_link(&self, name: &str) -> Result<u32, Error> {
+        match self.link_index(name)? {
+            Some(index) => Ok(index),
+            None => bail!("No interface by the name {}", name),
+        }
+    }
+
+    /// Creates a wg link, an existing link by the same name is left alone
+    pub fn add_wg_link(&self, name: &str) -> Result<(), Error> {
+        if self.link_index(name)?.is_some() {
+            return Ok(());
+        }
+        let mut route = RouteSocket::connect().map_err(|e| format_err!("{:?}", e))?;
+        route
+            .add_device(name)
+            .map_err(|e| format_err!("Failed to add wg link {} {:?}", name, e))?;
+        Ok(())
+    }
+
+    pub fn del_link(&self, name: &str) -> Result<(), Error> {
+        let index = self.require_link(name)?;
+        let request = self.handle.link().del(index).execute();
+        self.runtime
+            .lock()
+            .unwrap()
+            .block_on(request)
+            .map_err(|e| format_err!("Failed to delete link {} {:?}", name, e))
+    }
+
+    pub fn set_link_up(&self, name: &str) -> Result<(), Error> {
+        let index = self.require_link(name)?;
+        let request = self.handle.link().set(index).up().execute();
+        self.runtime
+            .lock()
+            .unwrap()
+            .block_on(request)
+            .map_err(|e| format_err!("Failed to set link {} up {:?}", name, e))
+    }
+
+    pub fn add_address(&self, name: &str, ip: IpAddr, prefix: u8) -> Result<(), Error> {
+        let index = self.require_link(name)?;
+        let request = self.handle.address().add(index, ip, prefix).execute();
+        self.runtime
+            .lock()
+            .unwrap()
+            .block_on(request)
+            .map_err(|e| format_err!("Failed to add {}/{} to {} {:?}", ip, prefix, name, e))
+    }
+
+    /// Adds a route to a single address or everything (`None`), via a gateway and/or out a
+    /// device
+    pub fn add_route(
+        &self,
+        to: Option<IpAddr>,
+        via: Option<IpAddr>,
+        dev: Option<&str>,
+    ) -> Result<(), Error> {
+        let index = match dev {
+            Some(dev) => Some(self.require_link(dev)?),
+            None => None,
+        };
+        let request = match (to, via) {
+            (Some(IpAddr::V6(_)), _) | (_, Some(IpAddr::V6(_))) => {
+                let mut request = self.handle.route().add_v6();
+                if let Some(IpAddr::V6(to)) = to {
+                    request = request.destination_prefix(to, 128);
+                }
+                if let Some(IpAddr::V6(via)) = via {
+                    request = request.gateway(via);
+                }
+                if let Some(index) = index {
+                    request = request.output_interface(index);
+                }
+                futures::future::Either::Left(request.execute())
+            }
+            _ => {
+                let mut request = self.handle.route().add_v4();
+                if let Some(IpAddr::V4(to)) = to {
+                    request = request.destination_prefix(to, 32);
+                }
+                if let Some(IpAddr::V4(via)) = via {
+                    request = request.gateway(via);
+                }
+                if let Some(index) = index {
+                    request = request.output_interface(index);
+                }
+                futures::future::Either::Right(request.execute())
+            }
+        };
+        self.runtime
+            .lock()
+            .unwrap()
+            .block_on(request)
+            .map_err(|e| format_err!("Failed to add route to {:?} {:?}", to, e))
+    }
+
+    pub fn wg_peers(&self, name: &str) -> Result<Vec<WgKey>, Error> {
+        let mut wg = WgSocket::connect().map_err(|e| format_err!("{:?}", e))?;
+        let device = wg
+            .get_device(DeviceInterface::from_name(name))
+            .map_err(|e| format_err!("Failed to get wg device {} {:?}", name, e))?;
+        Ok(device
+            .peers
+            .iter()
+            .map(|peer| WgKey::from(peer.public_key))
+            .collect())
+    }
+
+    /// Sets the private key and listen port of a wg link and adds or updates a peer
+    pub fn wg_set(
+        &self,
+        name: &str,
+        listen_port: u16,
+        private_key: &WgKey,
+        peer: &WgPeerConfig,
+    ) -> Result<(), Error> {
+        let private_key = as_key(private_key);
+        let public_key = as_key(peer.public_key);
+        let allowed_ips: Vec<set::AllowedIp> = peer
+            .allowed_ips
+            .iter()
+            .map(|(ipaddr, mask)| set::AllowedIp {
+                ipaddr,
+                cidr_mask: Some(*mask),
+            })
+            .collect();
+        let device = set::Device::from_ifname(name)
+            .private_key(&private_key)
+            .listen_port(listen_port)
+            .peers(vec![set::Peer::from_public_key(&public_key)
+                .endpoint(&peer.endpoint)
+                .persistent_keepalive_interval(peer.persistent_keepalive)
+                .allowed_ips(allowed_ips)]);
+
+        let mut wg = WgSocket::connect().map_err(|e| format_err!("{:?}", e))?;
+        wg.set_device(device)
+            .map_err(|e| format_err!("Failed to configure wg link {} {:?}", name, e))
+    }
+
+    /// Points an existing wg peer at a new endpoint, leaving the rest of its config alone
+    pub fn wg_set_endpoint(
+        &self,
+        name: &str,
+        public_key: &WgKey,
+        endpoint: &SocketAddr,
+    ) -> Result<(), Error> {
+        let public_key = as_key(public_key);
+        let device = set::Device::from_ifname(name)
+            .peers(vec![
+                set::Peer::from_public_key(&public_key).endpoint(endpoint)
+            ]);
+
+        let mut wg = WgSocket::connect().map_err(|e| format_err!("{:?}", e))?;
+        wg.set_device(device)
+            .map_err(|e| format_err!("Failed to set the endpoint on {} {:?}", name, e))
+    }
+
+    /// The interface index for a link local scope id
+    pub fn scope_id(&self, name: &str) -> Result<u32, Error> {
+        self.require_link(name)
+    }
+}
+
+fn as_key(key: &WgKey) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    out.copy_from_slice(key.as_ref());
+    out
+}
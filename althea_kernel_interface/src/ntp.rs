@@ -0,0 +1,100 @@
+use super::KernelInterface;
+use failure::Error;
+
+/// ntpd's one shot mode waits for as long as it takes to reach a server, this is how long it's
+/// given before it's killed
+const NTP_TIMEOUT_SECS: &str = "15";
+/// How long ntpd is left to query servers when we only want to know how far off we are, it sends
+/// its first requests right away so a few seconds gets a reply from any server that's reachable
+const NTP_QUERY_SECS: &str = "5";
+
+/// The offsets busybox ntpd reports in its debug output, lines like
+/// 'ntpd: reply from 192.0.2.1: offset:+0.000512 delay:0.013 status:0x24 ...'
+fn parse_ntp_offsets(output: &str) -> Vec<f64> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let offset = line.split("offset:").nth(1)?;
+            offset.split_whitespace().next()?.parse().ok()
+        })
+        .collect()
+}
+
+impl dyn KernelInterface {
+    /// Steps the system clock to what the given ntp servers say in one go, rather than slewing it
+    /// there over hours like a running ntpd would, using busybox ntpd's one shot mode. Returns
+    /// once the clock is set or the servers couldn't be reached in time
+    pub fn sync_time_ntp(&self, servers: &[String]) -> Result<(), Error> {
+        if servers.is_empty() {
+            bail!("No ntp servers to sync with");
+        }
+        let mut args = vec![NTP_TIMEOUT_SECS, "ntpd", "-n", "-q"];
+        for server in servers {
+            args.push("-p");
+            args.push(server);
+        }
+        let output = self.run_command("timeout", &args)?;
+        if !output.status.success() {
+            bail!(
+                "ntpd failed to sync the clock with {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Ok(())
+    }
+
+    /// How many seconds off the given ntp servers say our clock is, positive if we're behind,
+    /// without touching the clock. ntpd is run in query only mode until it's killed, so the exit
+    /// status says nothing, it's the replies it logged that count
+    pub fn ntp_offset(&self, servers: &[String]) -> Result<f64, Error> {
+        if servers.is_empty() {
+            bail!("No ntp servers to ask");
+        }
+        let mut args = vec![NTP_QUERY_SECS, "ntpd", "-n", "-w", "-d"];
+        for server in servers {
+            args.push("-p");
+            args.push(server);
+        }
+        let output = self.run_command("timeout", &args)?;
+        let mut offsets = parse_ntp_offsets(&String::from_utf8_lossy(&output.stderr));
+        if offsets.is_empty() {
+            bail!("No replies from ntp servers {:?}", servers);
+        }
+        offsets.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        Ok(offsets[offsets.len() / 2])
+    }
+}
+
+#[test]
+fn test_ntp_offset() {
+    use crate::KI;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+
+    KI.set_mock(Box::new(move |program, args| {
+        assert_eq!(program, "timeout");
+        assert_eq!(
+            args,
+            vec!["5", "ntpd", "-n", "-w", "-d", "-p", "a.example", "-p", "b.example"]
+        );
+        Ok(Output {
+            stdout: b"".to_vec(),
+            stderr: b"ntpd: sending query to 192.0.2.1
+ntpd: reply from 192.0.2.1: offset:+3600.000512 delay:0.013248 status:0x24 strat:2 refid:0x0a000001 rootdelay:0.001 reach:0x01
+ntpd: reply from 192.0.2.2: offset:+3599.5 delay:0.02 status:0x24 strat:2 refid:0x0a000001 rootdelay:0.001 reach:0x01
+ntpd: reply from 192.0.2.1: offset:+3600.25 delay:0.013 status:0x24 strat:2 refid:0x0a000001 rootdelay:0.001 reach:0x03
+"
+            .to_vec(),
+            status: ExitStatus::from_raw(124 << 8),
+        })
+    }));
+    let servers = vec!["a.example".to_string(), "b.example".to_string()];
+    assert!((KI.ntp_offset(&servers).unwrap() - 3600.000512).abs() < 1e-9);
+
+    assert_eq!(
+        parse_ntp_offsets("ntpd: reply from 192.0.2.1: offset:-2.5 delay:0.1"),
+        vec![-2.5]
+    );
+    assert!(parse_ntp_offsets("ntpd: bad address 'a.example'").is_empty());
+}
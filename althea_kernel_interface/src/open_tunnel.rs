@@ -1,7 +1,9 @@
 use super::{KernelInterface, KernelInterfaceError};
+use crate::netlink::{Netlink, WgPeerConfig};
 use althea_types::WgKey;
 use failure::Error;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::fs::read_to_string;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
 use std::path::Path;
 
 fn to_wg_local(ip: &IpAddr) -> IpAddr {
@@ -51,7 +53,66 @@ fn socket_to_string(endpoint: &SocketAddr, interface_name: Option<String>) -> St
     }
 }
 
+/// Link local endpoints need the scope of the interface they're reached through
+fn scoped_endpoint(
+    nl: &Netlink,
+    endpoint: &SocketAddr,
+    phy_name: &Option<String>,
+) -> Result<SocketAddr, Error> {
+    Ok(match (endpoint, phy_name) {
+        (SocketAddr::V6(addr), Some(phy)) if is_link_local(IpAddr::V6(*addr.ip())) => {
+            SocketAddr::V6(SocketAddrV6::new(
+                *addr.ip(),
+                addr.port(),
+                0,
+                nl.scope_id(phy)?,
+            ))
+        }
+        (addr, _) => *addr,
+    })
+}
+
 impl dyn KernelInterface {
+    /// Points the peer of an existing tunnel at a new endpoint, for when the other end's address
+    /// changes under an established tunnel. The rest of the peer's config is left alone
+    pub fn set_tunnel_endpoint(
+        &self,
+        interface: &str,
+        remote_pub_key: &WgKey,
+        endpoint: &SocketAddr,
+    ) -> Result<(), Error> {
+        let phy_name = self.get_device_name(endpoint.ip()).ok();
+        if is_link_local(endpoint.ip()) && phy_name.is_none() {
+            bail!("No interface to reach link local endpoint {}", endpoint);
+        }
+        let updated = self.try_netlink("wg set endpoint", |nl| {
+            let endpoint = scoped_endpoint(nl, endpoint, &phy_name)?;
+            nl.wg_set_endpoint(interface, remote_pub_key, &endpoint)
+        });
+        if updated.is_some() {
+            return Ok(());
+        }
+        let output = self.run_command(
+            "wg",
+            &[
+                "set",
+                interface,
+                "peer",
+                &remote_pub_key.to_string(),
+                "endpoint",
+                &socket_to_string(endpoint, phy_name),
+            ],
+        )?;
+        if !output.stderr.is_empty() {
+            return Err(KernelInterfaceError::RuntimeError(format!(
+                "received error from wg command: {}",
+                String::from_utf8(output.stderr)?
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
     pub fn open_tunnel(
         &self,
         interface: &String,
@@ -63,6 +124,7 @@ impl dyn KernelInterface {
         external_nic: Option<String>,
         settings_default_route: &mut Vec<String>,
         allowed_ipv4_address: Option<Ipv4Addr>,
+        persistent_keepalive: u16,
     ) -> Result<(), Error> {
         let external_peer;
         let phy_name = match self.get_device_name(endpoint.ip()) {
@@ -81,6 +143,84 @@ impl dyn KernelInterface {
             Some(_) => format!("::/0,0.0.0.0/0"),
         };
 
+        let configured = self.try_netlink("wg set", |nl| {
+            let private_key: WgKey = read_to_string(private_key_path)?.trim().parse()?;
+            let endpoint = scoped_endpoint(nl, endpoint, &phy_name)?;
+            let mut allowed_ips = vec![(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)];
+            if allowed_ipv4_address.is_some() {
+                allowed_ips.push((IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+            }
+            nl.wg_set(
+                interface,
+                port,
+                &private_key,
+                &WgPeerConfig {
+                    public_key: remote_pub_key,
+                    endpoint,
+                    allowed_ips: &allowed_ips,
+                    persistent_keepalive,
+                },
+            )
+        });
+        if configured.is_none() {
+            self.wg_set_shell(
+                interface,
+                port,
+                endpoint,
+                remote_pub_key,
+                private_key_path,
+                phy_name,
+                &allowed_addresses,
+                persistent_keepalive,
+            )?;
+        }
+
+        for (addr, prefix) in [(*own_ip, 128), (to_wg_local(own_ip), 64)].iter() {
+            let added = self.try_netlink("add address", |nl| {
+                nl.add_address(interface, *addr, *prefix)
+            });
+            if added.is_none() {
+                let addr = match prefix {
+                    128 => addr.to_string(),
+                    _ => format!("{}/{}", addr, prefix),
+                };
+                self.run_command("ip", &["address", "add", &addr, "dev", &interface])?;
+            }
+        }
+
+        if external_peer {
+            self.manual_peers_route(&endpoint.ip(), settings_default_route)?;
+        }
+
+        if self
+            .try_netlink("set link up", |nl| nl.set_link_up(interface))
+            .is_some()
+        {
+            return Ok(());
+        }
+        let output = self.run_command("ip", &["link", "set", "dev", &interface, "up"])?;
+        if !output.stderr.is_empty() {
+            return Err(KernelInterfaceError::RuntimeError(format!(
+                "received error setting wg interface up: {}",
+                String::from_utf8(output.stderr)?
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Configures the wg side of a tunnel with the wg tool
+    fn wg_set_shell(
+        &self,
+        interface: &str,
+        port: u16,
+        endpoint: &SocketAddr,
+        remote_pub_key: &WgKey,
+        private_key_path: &Path,
+        phy_name: Option<String>,
+        allowed_addresses: &str,
+        persistent_keepalive: u16,
+    ) -> Result<(), Error> {
         let socket_connect_str = socket_to_string(endpoint, phy_name);
         trace!("socket conenct string: {}", socket_connect_str);
         let output = self.run_command(
@@ -99,7 +239,7 @@ impl dyn KernelInterface {
                 "allowed-ips",
                 &allowed_addresses,
                 "persistent-keepalive",
-                "5",
+                &persistent_keepalive.to_string(),
             ],
         )?;
         if !output.stderr.is_empty() {
@@ -109,34 +249,6 @@ impl dyn KernelInterface {
             ))
             .into());
         }
-        let _output = self.run_command(
-            "ip",
-            &["address", "add", &format!("{}", own_ip), "dev", &interface],
-        )?;
-
-        self.run_command(
-            "ip",
-            &[
-                "address",
-                "add",
-                &format!("{}/64", to_wg_local(own_ip)),
-                "dev",
-                &interface,
-            ],
-        )?;
-
-        if external_peer {
-            self.manual_peers_route(&endpoint.ip(), settings_default_route)?;
-        }
-
-        let output = self.run_command("ip", &["link", "set", "dev", &interface, "up"])?;
-        if !output.stderr.is_empty() {
-            return Err(KernelInterfaceError::RuntimeError(format!(
-                "received error setting wg interface up: {}",
-                String::from_utf8(output.stderr)?
-            ))
-            .into());
-        }
         Ok(())
     }
 }
@@ -253,6 +365,61 @@ fe80::433:25ff:fe8c:e1ea dev eth0 lladdr 1a:32:06:78:05:0a STALE
         None,
         &mut vec![],
         None,
+        5,
     )
     .unwrap();
 }
+
+#[test]
+fn test_set_tunnel_endpoint() {
+    use crate::KI;
+
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+
+    let endpoint: SocketAddr = "[fe80::12:34:56:78:91]:8088".parse().unwrap();
+    let remote_pub_key: WgKey = "x8AcR9wI4t97aowYFlis077BDBk9SLdq6khMiixuTsQ="
+        .parse()
+        .unwrap();
+
+    let mut counter = 0;
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        match counter {
+            1 => {
+                assert_eq!(program, "ip");
+                assert_eq!(args, &["neighbor"]);
+                Ok(Output {
+                    stdout: b"fe80:0:0:12:34:56:78:91 dev eth2 lladdr 76:59:8e:98:00:81 REACHABLE"
+                        .to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(0),
+                })
+            }
+            2 => {
+                assert_eq!(program, "wg");
+                assert_eq!(
+                    args,
+                    &[
+                        "set",
+                        "wg1",
+                        "peer",
+                        "x8AcR9wI4t97aowYFlis077BDBk9SLdq6khMiixuTsQ=",
+                        "endpoint",
+                        "[fe80::12:34:56:78:91%eth2]:8088",
+                    ]
+                );
+                Ok(Output {
+                    stdout: b"".to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(0),
+                })
+            }
+            _ => panic!("command called too many times"),
+        }
+    }));
+
+    KI.set_tunnel_endpoint("wg1", &remote_pub_key, &endpoint)
+        .unwrap();
+}
@@ -24,4 +24,20 @@ impl dyn KernelInterface {
             Ok(false)
         }
     }
+
+    /// Pings an address out of a specific interface using the system ping binary, used to check
+    /// an uplink even when it is not currently carrying the default route
+    pub fn ping_check_iface(
+        &self,
+        iface: &str,
+        ip: &IpAddr,
+        timeout: Duration,
+    ) -> Result<bool, Error> {
+        let timeout = format!("{}", timeout.as_secs().max(1));
+        let output = self.run_command(
+            "ping",
+            &["-c", "1", "-W", &timeout, "-I", iface, &ip.to_string()],
+        )?;
+        Ok(output.status.success())
+    }
 }
@@ -8,6 +8,9 @@ use failure::Error;
 
 impl dyn KernelInterface {
     pub fn get_peers(&self, iface_name: &str) -> Result<Vec<WgKey>, Error> {
+        if let Some(peers) = self.try_netlink("get peers", |nl| nl.wg_peers(iface_name)) {
+            return Ok(peers);
+        }
         let output = self.run_command("wg", &["show", iface_name, "peers"])?;
 
         let output = from_utf8(&output.stdout)?;
@@ -26,6 +29,24 @@ impl dyn KernelInterface {
         Ok(peers)
     }
 
+    /// Every tunnel interface (wg0, wg1 and so on, not wg_exit and friends) along with the
+    /// public keys of its peers
+    pub fn get_wg_tunnels(&self) -> Result<Vec<(String, Vec<WgKey>)>, Error> {
+        let mut tunnels = Vec::new();
+        for iface in self.get_interfaces()? {
+            let is_tunnel = iface.starts_with("wg")
+                && iface.len() > 2
+                && iface[2..].chars().all(|c| c.is_ascii_digit());
+            if !is_tunnel {
+                continue;
+            }
+            // an interface we failed to configure has no peers, it's still reported
+            let peers = self.get_peers(&iface).unwrap_or_default();
+            tunnels.push((iface, peers));
+        }
+        Ok(tunnels)
+    }
+
     /// checks the existing interfaces to find an interface name that isn't in use.
     /// then calls iproute2 to set up a new interface.
     pub fn setup_wg_if(&self) -> Result<String, Error> {
@@ -43,6 +64,12 @@ impl dyn KernelInterface {
 
     /// calls iproute2 to set up a new interface with a given name.
     pub fn setup_wg_if_named(&self, name: &str) -> Result<(), Error> {
+        if self
+            .try_netlink("add wg link", |nl| nl.add_wg_link(name))
+            .is_some()
+        {
+            return Ok(());
+        }
         let output = self.run_command("ip", &["link", "add", &name, "type", "wireguard"])?;
         let stderr = String::from_utf8(output.stderr)?;
         if !stderr.is_empty() {
@@ -79,6 +106,28 @@ impl dyn KernelInterface {
         }
         Ok(num)
     }
+
+    /// When each peer on the interface last completed a handshake with us, None if it never has
+    pub fn get_last_handshakes(
+        &self,
+        iface_name: &str,
+    ) -> Result<Vec<(WgKey, Option<SystemTime>)>, Error> {
+        let output = self.run_command("wg", &["show", iface_name, "latest-handshakes"])?;
+        let mut handshakes = Vec::new();
+        for line in from_utf8(&output.stdout)?.lines() {
+            let mut fields = line.split_whitespace();
+            let (key, timestamp) = match (fields.next(), fields.next()) {
+                (Some(key), Some(timestamp)) => (key.parse()?, timestamp.parse()?),
+                _ => bail!("Invalid latest-handshakes line {:?}", line),
+            };
+            let time = match timestamp {
+                0 => None,
+                secs => Some(UNIX_EPOCH + Duration::from_secs(secs)),
+            };
+            handshakes.push((key, time));
+        }
+        Ok(handshakes)
+    }
 }
 
 #[test]
@@ -151,3 +200,36 @@ fn test_get_wg_exit_clients_online() {
 
     assert_eq!(KI.get_wg_exit_clients_online().unwrap(), 1);
 }
+
+#[test]
+fn test_get_last_handshakes() {
+    use crate::KI;
+
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+
+    KI.set_mock(Box::new(move |program, args| {
+        assert_eq!(program, "wg");
+        assert_eq!(args, &["show", "wg0", "latest-handshakes"]);
+        Ok(Output {
+            stdout: b"88gbNAZx7NoNK9hatYuDkeZOjQ8EBmJ8VBpcFhXPqHs=\t1536936247\n9jRr6euMHu3tBIsZyqxUmjbuKVVFZCBOYApOR2pLNkQ=\t0".to_vec(),
+            stderr: b"".to_vec(),
+            status: ExitStatus::from_raw(0),
+        })
+    }));
+
+    let handshakes = KI.get_last_handshakes("wg0").unwrap();
+    assert_eq!(handshakes.len(), 2);
+    assert_eq!(
+        handshakes[0].0,
+        "88gbNAZx7NoNK9hatYuDkeZOjQ8EBmJ8VBpcFhXPqHs="
+            .parse()
+            .unwrap()
+    );
+    assert_eq!(
+        handshakes[0].1,
+        Some(UNIX_EPOCH + Duration::from_secs(1_536_936_247))
+    );
+    assert_eq!(handshakes[1].1, None);
+}
@@ -0,0 +1,302 @@
+//! Policy routing for split tunneling. The routes for destinations that shouldn't simply follow
+//! the default route out the exit tunnel live in a table of their own, which a single rule has
+//! looked up ahead of the main table for traffic from the LAN. The router's own traffic, like the
+//! exit tunnel itself, never sees the table. The table has no default route so anything it
+//! doesn't cover falls through to the main table as usual.
+
+use super::KernelInterface;
+use failure::Error;
+use ipnetwork::IpNetwork;
+
+/// The routing table the split tunnel routes are kept in
+pub const SPLIT_TUNNEL_TABLE: &str = "20";
+/// Priority of the rule pointing at the table, ahead of the main table at 32766
+const SPLIT_TUNNEL_PREF: &str = "100";
+/// Marks the masquerade rules we add for bypass routes, so that they can be told apart from
+/// those for the uplink itself
+const SPLIT_TUNNEL_COMMENT: &str = "rita-split-tunnel";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyRoute {
+    /// Out the uplink, given as the tokens of its default route like `default via 192.168.8.1
+    /// dev eth0 proto dhcp`
+    Uplink(Vec<String>),
+    /// Out the exit tunnel
+    ExitTunnel,
+    /// Dropped with an unreachable error
+    Blackhole,
+}
+
+fn family(dest: &IpNetwork) -> &'static str {
+    match dest {
+        IpNetwork::V4(_) => "-4",
+        IpNetwork::V6(_) => "-6",
+    }
+}
+
+/// The arguments to `ip` that add the route to our table
+fn policy_route_args(dest: &IpNetwork, route: &PolicyRoute) -> Vec<String> {
+    let mut args = vec![family(dest).to_string(), "route".into(), "replace".into()];
+    match route {
+        PolicyRoute::Uplink(default_route) => {
+            args.push(dest.to_string());
+            // only the next hop matters, proto and metric belong to the original route
+            let mut tokens = default_route.iter();
+            while let Some(token) = tokens.next() {
+                if token == "via" || token == "dev" {
+                    args.push(token.clone());
+                    if let Some(value) = tokens.next() {
+                        args.push(value.clone());
+                    }
+                }
+            }
+        }
+        PolicyRoute::ExitTunnel => {
+            args.push(dest.to_string());
+            args.push("dev".into());
+            args.push("wg_exit".into());
+        }
+        PolicyRoute::Blackhole => {
+            args.push("unreachable".into());
+            args.push(dest.to_string());
+        }
+    }
+    args.push("table".into());
+    args.push(SPLIT_TUNNEL_TABLE.into());
+    args
+}
+
+/// The uplinks in `iptables -t nat -S POSTROUTING` output that we masquerade for
+fn parse_masquerades(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter(|line| line.contains(SPLIT_TUNNEL_COMMENT))
+        .filter_map(|line| {
+            let mut tokens = line.split_whitespace();
+            tokens.find(|t| *t == "-o")?;
+            tokens.next().map(|dev| dev.to_string())
+        })
+        .collect()
+}
+
+fn masquerade_args<'a>(action: &'a str, dev: &'a str) -> Vec<&'a str> {
+    vec![
+        "-t",
+        "nat",
+        action,
+        "POSTROUTING",
+        "-o",
+        dev,
+        "-m",
+        "comment",
+        "--comment",
+        SPLIT_TUNNEL_COMMENT,
+        "-j",
+        "MASQUERADE",
+    ]
+}
+
+impl dyn KernelInterface {
+    /// Points traffic from `lan_nic` at the split tunnel table, or takes the rule away. Rules
+    /// left by older versions without the `iif` are replaced
+    fn set_split_tunnel_rule(
+        &self,
+        version: &str,
+        lan_nic: &str,
+        enabled: bool,
+    ) -> Result<(), Error> {
+        let wanted = format!("iif {} lookup {}", lan_nic, SPLIT_TUNNEL_TABLE);
+        let rules =
+            self.run_command("ip", &[version, "rule", "list", "pref", SPLIT_TUNNEL_PREF])?;
+        let rules = String::from_utf8(rules.stdout)?;
+        if enabled && rules.lines().count() == 1 && rules.contains(&wanted) {
+            return Ok(());
+        }
+        for _ in rules.lines() {
+            self.run_command("ip", &[version, "rule", "del", "pref", SPLIT_TUNNEL_PREF])?;
+        }
+        if enabled {
+            let output = self.run_command(
+                "ip",
+                &[
+                    version,
+                    "rule",
+                    "add",
+                    "pref",
+                    SPLIT_TUNNEL_PREF,
+                    "iif",
+                    lan_nic,
+                    "lookup",
+                    SPLIT_TUNNEL_TABLE,
+                ],
+            )?;
+            if !output.status.success() {
+                bail!(
+                    "Failed to add split tunnel rule with {}",
+                    String::from_utf8(output.stderr)?
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the contents of the split tunnel table with these routes for traffic coming in
+    /// from `lan_nic`, adding the rule that looks it up if it's missing. Uplinks that bypass
+    /// routes go out of are masqueraded, and no longer once they're not used. With no routes
+    /// everything is taken down
+    pub fn set_split_tunnel_routes(
+        &self,
+        lan_nic: &str,
+        routes: &[(IpNetwork, PolicyRoute)],
+    ) -> Result<(), Error> {
+        for version in ["-4", "-6"].iter() {
+            self.set_split_tunnel_rule(version, lan_nic, !routes.is_empty())?;
+            self.run_command(
+                "ip",
+                &[version, "route", "flush", "table", SPLIT_TUNNEL_TABLE],
+            )?;
+        }
+
+        // traffic leaving through the uplink needs the uplink's address
+        let mut uplinks: Vec<String> = Vec::new();
+        for (dest, route) in routes {
+            let args = policy_route_args(dest, route);
+            let args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+            let output = self.run_command("ip", &args)?;
+            if !output.status.success() {
+                bail!(
+                    "Failed to add split tunnel route for {} with {}",
+                    dest,
+                    String::from_utf8(output.stderr)?
+                );
+            }
+            if let (IpNetwork::V4(_), PolicyRoute::Uplink(_)) = (dest, route) {
+                if let Some(dev) = args.iter().skip_while(|a| **a != "dev").nth(1) {
+                    if !uplinks.iter().any(|u| u == dev) {
+                        uplinks.push(dev.to_string());
+                    }
+                }
+            }
+        }
+
+        let current = self.run_command("iptables", &["-t", "nat", "-S", "POSTROUTING"])?;
+        let current = parse_masquerades(&String::from_utf8(current.stdout)?);
+        for dev in current.iter().filter(|dev| !uplinks.contains(dev)) {
+            self.run_command("iptables", &masquerade_args("-D", dev))?;
+        }
+        for dev in uplinks.iter().filter(|dev| !current.contains(dev)) {
+            self.add_iptables_rule("iptables", &masquerade_args("-A", dev))?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_policy_route_args() {
+    let dest: IpNetwork = "10.5.0.0/16".parse().unwrap();
+    let uplink: Vec<String> = "default via 192.168.8.1 dev eth0 proto dhcp metric 600"
+        .split_whitespace()
+        .map(|s| s.to_string())
+        .collect();
+    assert_eq!(
+        policy_route_args(&dest, &PolicyRoute::Uplink(uplink)),
+        vec![
+            "-4",
+            "route",
+            "replace",
+            "10.5.0.0/16",
+            "via",
+            "192.168.8.1",
+            "dev",
+            "eth0",
+            "table",
+            "20"
+        ]
+    );
+
+    let dest: IpNetwork = "2001:db8::/32".parse().unwrap();
+    assert_eq!(
+        policy_route_args(&dest, &PolicyRoute::ExitTunnel),
+        vec![
+            "-6",
+            "route",
+            "replace",
+            "2001:db8::/32",
+            "dev",
+            "wg_exit",
+            "table",
+            "20"
+        ]
+    );
+    assert_eq!(
+        policy_route_args(&dest, &PolicyRoute::Blackhole),
+        vec![
+            "-6",
+            "route",
+            "replace",
+            "unreachable",
+            "2001:db8::/32",
+            "table",
+            "20"
+        ]
+    );
+}
+
+#[test]
+fn test_split_tunnel_teardown() {
+    use crate::KI;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{ExitStatus, Output};
+
+    let mut counter = 0;
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        let output = |stdout: &str| {
+            Ok(Output {
+                stdout: stdout.as_bytes().to_vec(),
+                stderr: b"".to_vec(),
+                status: ExitStatus::from_raw(0),
+            })
+        };
+        match counter {
+            // a rule from before the iif went in is taken away
+            1 => {
+                assert_eq!(args, vec!["-4", "rule", "list", "pref", "100"]);
+                output("100:\tfrom all lookup 20\n")
+            }
+            2 => {
+                assert_eq!(args, vec!["-4", "rule", "del", "pref", "100"]);
+                output("")
+            }
+            3 => {
+                assert_eq!(args, vec!["-4", "route", "flush", "table", "20"]);
+                output("")
+            }
+            4 => {
+                assert_eq!(args, vec!["-6", "rule", "list", "pref", "100"]);
+                output("")
+            }
+            5 => {
+                assert_eq!(args, vec!["-6", "route", "flush", "table", "20"]);
+                output("")
+            }
+            6 => {
+                assert_eq!(program, "iptables");
+                output(
+                    "-P POSTROUTING ACCEPT
+-A POSTROUTING -o eth0 -j MASQUERADE
+-A POSTROUTING -o eth0 -m comment --comment rita-split-tunnel -j MASQUERADE
+",
+                )
+            }
+            // only our masquerade goes, not the uplink's own
+            7 => {
+                assert_eq!(program, "iptables");
+                assert_eq!(args, masquerade_args("-D", "eth0"));
+                output("")
+            }
+            _ => panic!("Unexpected call {} {} {:?}", counter, program, args),
+        }
+    }));
+    KI.set_split_tunnel_routes("br-lan", &[]).unwrap();
+}
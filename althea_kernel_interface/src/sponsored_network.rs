@@ -0,0 +1,100 @@
+//! The sponsored network, an open wifi network for the public whose traffic the router owner pays
+//! for. It's set up just like the guest network but under its own name, so it has its own bridge
+//! whose byte counters tell us exactly how much sponsored traffic there has been. Once the daily
+//! cap is used up the bridge is cut off with tc until the cap resets.
+
+use super::KernelInterface;
+use failure::Error;
+use std::net::Ipv4Addr;
+
+/// The name of the sponsored network interface in UCI
+const SPONSORED_NETWORK: &str = "sponsored";
+/// The bridge OpenWRT creates for the sponsored network
+const SPONSORED_BRIDGE: &str = "br-sponsored";
+
+/// Bytes that have gone through the sponsored bridge since it was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SponsoredCounters {
+    /// sent by sponsored users
+    pub upload: u64,
+    /// sent to sponsored users
+    pub download: u64,
+}
+
+impl dyn KernelInterface {
+    /// Creates or updates the sponsored network, it's always open
+    pub fn set_sponsored_network(&self, ssid: &str, router_ip: Ipv4Addr) -> Result<(), Error> {
+        self.set_isolated_network(SPONSORED_NETWORK, ssid, None, router_ip)
+    }
+
+    /// Removes the sponsored network entirely
+    pub fn remove_sponsored_network(&self) -> Result<(), Error> {
+        self.remove_isolated_network(SPONSORED_NETWORK)
+    }
+
+    pub fn get_sponsored_counters(&self) -> Result<SponsoredCounters, Error> {
+        let counters = match self.get_interface_counters()?.remove(SPONSORED_BRIDGE) {
+            Some(val) => val,
+            None => bail!("No counters for {}", SPONSORED_BRIDGE),
+        };
+        // the bridge receives what the users send
+        Ok(SponsoredCounters {
+            upload: counters.rx_bytes,
+            download: counters.tx_bytes,
+        })
+    }
+
+    /// Drops everything going in or out of the sponsored bridge, or lifts that
+    pub fn set_sponsored_cutoff(&self, cutoff: bool) -> Result<(), Error> {
+        // clear out whatever is there, these fail harmlessly if there's nothing
+        let _ = self.run_command("tc", &["qdisc", "del", "dev", SPONSORED_BRIDGE, "root"]);
+        let _ = self.run_command("tc", &["qdisc", "del", "dev", SPONSORED_BRIDGE, "ingress"]);
+        if !cutoff {
+            return Ok(());
+        }
+
+        let commands: [&[&str]; 4] = [
+            &[
+                "qdisc",
+                "add",
+                "dev",
+                SPONSORED_BRIDGE,
+                "root",
+                "handle",
+                "1:",
+                "prio",
+            ],
+            &[
+                "filter",
+                "add",
+                "dev",
+                SPONSORED_BRIDGE,
+                "parent",
+                "1:",
+                "matchall",
+                "action",
+                "drop",
+            ],
+            &["qdisc", "add", "dev", SPONSORED_BRIDGE, "ingress"],
+            &[
+                "filter",
+                "add",
+                "dev",
+                SPONSORED_BRIDGE,
+                "parent",
+                "ffff:",
+                "matchall",
+                "action",
+                "drop",
+            ],
+        ];
+        for args in commands.iter() {
+            let output = self.run_command("tc", args)?;
+            if !output.status.success() {
+                let res = String::from_utf8(output.stderr)?;
+                bail!("Failed to cut off {}! {:?}", SPONSORED_BRIDGE, res);
+            }
+        }
+        Ok(())
+    }
+}
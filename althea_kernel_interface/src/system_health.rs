@@ -0,0 +1,243 @@
+//! Reads how much of the router's resources are in use, load, memory, free storage, the
+//! conntrack table, temperature and our own memory, almost all of it straight out of /proc and
+//! /sys so that it's cheap enough to check every minute
+
+use super::KernelInterface;
+use failure::Error;
+use std::fs::{read_dir, read_to_string};
+use std::str::from_utf8;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LoadAverage {
+    pub one: f32,
+    pub five: f32,
+    pub fifteen: f32,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryInfo {
+    pub total_kb: u64,
+    /// what could be handed out without swapping, including caches the kernel would drop
+    pub available_kb: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StorageInfo {
+    pub total_kb: u64,
+    pub free_kb: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConntrackUsage {
+    pub count: u64,
+    pub max: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ThermalZone {
+    /// the sensor's type, like cpu-thermal or wlan
+    pub name: String,
+    pub celsius: f32,
+}
+
+fn parse_loadavg(contents: &str) -> Result<LoadAverage, Error> {
+    let mut fields = contents
+        .split_whitespace()
+        .map(|field| field.parse::<f32>());
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some(Ok(one)), Some(Ok(five)), Some(Ok(fifteen))) => {
+            Ok(LoadAverage { one, five, fifteen })
+        }
+        _ => bail!("Malformed /proc/loadavg {}", contents),
+    }
+}
+
+/// The kB value of one /proc/meminfo or /proc/self/status line, like 'MemTotal:  124720 kB'
+fn find_kb(contents: &str, key: &str) -> Option<u64> {
+    contents.lines().find_map(|line| {
+        let mut parts = line.splitn(2, ':');
+        if parts.next()? != key {
+            return None;
+        }
+        parts.next()?.split_whitespace().next()?.parse().ok()
+    })
+}
+
+fn parse_meminfo(contents: &str) -> Result<MemoryInfo, Error> {
+    let total_kb = match find_kb(contents, "MemTotal") {
+        Some(val) => val,
+        None => bail!("No MemTotal in /proc/meminfo"),
+    };
+    // kernels before 3.14 don't have MemAvailable, this is roughly what it stands for
+    let available_kb = match find_kb(contents, "MemAvailable") {
+        Some(val) => val,
+        None => ["MemFree", "Buffers", "Cached"]
+            .iter()
+            .filter_map(|key| find_kb(contents, key))
+            .sum(),
+    };
+    Ok(MemoryInfo {
+        total_kb,
+        available_kb,
+    })
+}
+
+/// The output of `df -k <path>`, busybox wraps long filesystem names onto a line of their own so
+/// the numbers are counted back from the end
+fn parse_df(output: &str) -> Result<StorageInfo, Error> {
+    let fields: Vec<&str> = output
+        .lines()
+        .skip(1)
+        .flat_map(str::split_whitespace)
+        .collect();
+    if fields.len() < 5 {
+        bail!("Malformed df output {}", output);
+    }
+    let total_kb = fields[fields.len() - 5].parse()?;
+    let free_kb = fields[fields.len() - 3].parse()?;
+    Ok(StorageInfo { total_kb, free_kb })
+}
+
+fn read_number(path: &str) -> Result<u64, Error> {
+    Ok(read_to_string(path)?.trim().parse()?)
+}
+
+impl dyn KernelInterface {
+    pub fn get_load_average(&self) -> Result<LoadAverage, Error> {
+        parse_loadavg(&read_to_string("/proc/loadavg")?)
+    }
+
+    pub fn get_memory_info(&self) -> Result<MemoryInfo, Error> {
+        parse_meminfo(&read_to_string("/proc/meminfo")?)
+    }
+
+    /// Size and free space of the filesystem `path` is on
+    pub fn get_storage_info(&self, path: &str) -> Result<StorageInfo, Error> {
+        let output = self.run_command("df", &["-k", path])?;
+        if !output.status.success() {
+            bail!(
+                "df failed for {} with {}",
+                path,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        parse_df(from_utf8(&output.stdout)?)
+    }
+
+    /// None if connection tracking isn't loaded
+    pub fn get_conntrack_usage(&self) -> Result<Option<ConntrackUsage>, Error> {
+        let count = "/proc/sys/net/netfilter/nf_conntrack_count";
+        if read_to_string(count).is_err() {
+            return Ok(None);
+        }
+        Ok(Some(ConntrackUsage {
+            count: read_number(count)?,
+            max: read_number("/proc/sys/net/netfilter/nf_conntrack_max")?,
+        }))
+    }
+
+    /// Every thermal zone that has a reading, some drivers error out when a sensor is powered
+    /// down so those are left out rather than failing the lot
+    pub fn get_thermal_zones(&self) -> Result<Vec<ThermalZone>, Error> {
+        let mut zones = Vec::new();
+        let entries = match read_dir("/sys/class/thermal") {
+            Ok(entries) => entries,
+            // no sensors at all, common in VMs
+            Err(_) => return Ok(zones),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            let is_zone = path.file_name().map_or(false, |name| {
+                name.to_string_lossy().starts_with("thermal_zone")
+            });
+            if !is_zone {
+                continue;
+            }
+            let temp = match read_to_string(path.join("temp")) {
+                Ok(temp) => temp,
+                Err(_) => continue,
+            };
+            // millidegrees, negative below freezing
+            let millidegrees: i64 = match temp.trim().parse() {
+                Ok(val) => val,
+                Err(_) => continue,
+            };
+            let name = read_to_string(path.join("type"))
+                .map(|name| name.trim().to_string())
+                .unwrap_or_else(|_| path.to_string_lossy().to_string());
+            zones.push(ThermalZone {
+                name,
+                celsius: millidegrees as f32 / 1000.0,
+            });
+        }
+        zones.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(zones)
+    }
+
+    /// Our own resident memory in kB
+    pub fn get_own_rss(&self) -> Result<u64, Error> {
+        match find_kb(&read_to_string("/proc/self/status")?, "VmRSS") {
+            Some(val) => Ok(val),
+            None => bail!("No VmRSS in /proc/self/status"),
+        }
+    }
+}
+
+#[test]
+fn test_parse_system_health() {
+    assert_eq!(
+        parse_loadavg("0.52 0.58 0.59 1/98 2045\n").unwrap(),
+        LoadAverage {
+            one: 0.52,
+            five: 0.58,
+            fifteen: 0.59
+        }
+    );
+    assert!(parse_loadavg("").is_err());
+
+    let meminfo = "\
+MemTotal:         124720 kB
+MemFree:           35200 kB
+MemAvailable:      61236 kB
+Buffers:            4096 kB
+Cached:            20480 kB
+";
+    assert_eq!(
+        parse_meminfo(meminfo).unwrap(),
+        MemoryInfo {
+            total_kb: 124_720,
+            available_kb: 61236
+        }
+    );
+    let old_meminfo = "\
+MemTotal:         124720 kB
+MemFree:           35200 kB
+Buffers:            4096 kB
+Cached:            20480 kB
+";
+    assert_eq!(parse_meminfo(old_meminfo).unwrap().available_kb, 59776);
+    assert!(parse_meminfo("Cached: 20480 kB").is_err());
+
+    let df = "\
+Filesystem           1K-blocks      Used Available Use% Mounted on
+overlayfs:/overlay        4864       836      4028  17% /
+";
+    assert_eq!(
+        parse_df(df).unwrap(),
+        StorageInfo {
+            total_kb: 4864,
+            free_kb: 4028
+        }
+    );
+    let wrapped = "\
+Filesystem           1K-blocks      Used Available Use% Mounted on
+/dev/mapper/a-very-long-volume-name
+                      20511312   9876544   9569808  51% /
+";
+    assert_eq!(parse_df(wrapped).unwrap().free_kb, 9_569_808);
+    assert!(parse_df("df: /nowhere: No such file or directory").is_err());
+
+    let status = "Name:\trita\nVmPeak:\t   40960 kB\nVmRSS:\t   18432 kB\n";
+    assert_eq!(find_kb(status, "VmRSS"), Some(18432));
+    assert_eq!(find_kb(status, "VmSwap"), None);
+}
@@ -0,0 +1,95 @@
+use super::KernelInterface;
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use failure::Error;
+
+impl dyn KernelInterface {
+    /// Sends a single traceroute probe to `dest` with the given ttl, returning the address that
+    /// answered and the round trip time in milliseconds or None if nothing answered in time.
+    /// Tracing one hop at a time lets callers report each hop as soon as it's known.
+    pub fn trace_hop(
+        &self,
+        dest: &IpAddr,
+        ttl: u8,
+        timeout: Duration,
+    ) -> Result<Option<(IpAddr, f32)>, Error> {
+        let ttl = ttl.to_string();
+        let wait = timeout.as_secs().max(1).to_string();
+        let family = if dest.is_ipv6() { "-6" } else { "-4" };
+        let output = self.run_command(
+            "traceroute",
+            &[
+                family,
+                "-n",
+                "-q",
+                "1",
+                "-f",
+                &ttl,
+                "-m",
+                &ttl,
+                "-w",
+                &wait,
+                &dest.to_string(),
+            ],
+        )?;
+        if !output.status.success() {
+            bail!(
+                "traceroute to {} failed with {}",
+                dest,
+                String::from_utf8(output.stderr)?
+            );
+        }
+        Ok(parse_hop(&String::from_utf8(output.stdout)?))
+    }
+}
+
+/// Hop lines look like ` 3  fd00::1  1.234 ms` or ` 3  *` when the probe timed out
+fn parse_hop(output: &str) -> Option<(IpAddr, f32)> {
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // skips the header, which some versions print on stdout
+        if fields.len() < 2 || fields[0].parse::<u8>().is_err() {
+            continue;
+        }
+        let ip = fields[1].parse().ok()?;
+        let rtt = fields.get(2)?.parse().ok()?;
+        return Some((ip, rtt));
+    }
+    None
+}
+
+#[test]
+fn test_trace_hop() {
+    use crate::KI;
+
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+
+    assert_eq!(parse_hop(" 4  *\n"), None);
+    assert_eq!(parse_hop(""), None);
+
+    KI.set_mock(Box::new(move |program, args| {
+        assert_eq!(program, "traceroute");
+        assert_eq!(
+            args,
+            &["-6", "-n", "-q", "1", "-f", "2", "-m", "2", "-w", "1", "fd00::3"]
+        );
+
+        Ok(Output {
+            stdout: b"traceroute to fd00::3 (fd00::3), 2 hops max, 72 byte packets
+ 2  fd00::2  3.182 ms
+"
+            .to_vec(),
+            stderr: b"".to_vec(),
+            status: ExitStatus::from_raw(0),
+        })
+    }));
+
+    let hop = KI
+        .trace_hop(&"fd00::3".parse().unwrap(), 2, Duration::from_millis(500))
+        .unwrap();
+    assert_eq!(hop, Some(("fd00::2".parse().unwrap(), 3.182)));
+}
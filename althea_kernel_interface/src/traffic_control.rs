@@ -163,6 +163,35 @@ impl dyn KernelInterface {
         Ok(())
     }
 
+    /// Prioritizes latency sensitive traffic on a tunnel. An htb hierarchy splits the tunnel
+    /// into a priority class and a bulk class, each with its own fq_codel, the priority class
+    /// is served first and both can borrow up to `max_rate`. Rates are in kbit/s. Falls back to
+    /// plain codel shaping if the kernel can't do it
+    pub fn set_qos_shaping(
+        &self,
+        iface_name: &str,
+        max_rate: u32,
+        priority_rate: u32,
+    ) -> Result<(), Error> {
+        if self.has_qdisc(iface_name)? {
+            self.delete_qdisc(iface_name)?;
+        }
+
+        for command in qos_commands(iface_name, max_rate, priority_rate) {
+            let args: Vec<&str> = command.iter().map(|s| s.as_str()).collect();
+            let output = self.run_command("tc", &args)?;
+            if !output.status.success() {
+                let res = String::from_utf8(output.stderr)?;
+                warn!(
+                    "Failed to set up qos on {} with {:?}, falling back to codel",
+                    iface_name, res
+                );
+                return self.set_codel_shaping(iface_name, Some((max_rate / 1000) as usize));
+            }
+        }
+        Ok(())
+    }
+
     /// Creates a qdisc limit with the given bandwidth tuned for the correct rate
     /// this limit uses tbf which is classless and faster since we leave prioritization
     /// to the fq_codel on the ingress and egress interfaces
@@ -351,6 +380,90 @@ impl dyn KernelInterface {
     }
 }
 
+/// htb class ids for the qos hierarchy, the root class is 1:1
+const QOS_PRIORITY_CLASS: &str = "10";
+const QOS_BULK_CLASS: &str = "20";
+
+/// DSCP values sent to the priority class as tos bytes, EF, CS5 and CS6
+const QOS_PRIORITY_TOS: [&str; 3] = ["0xb8", "0xa0", "0xc0"];
+
+/// The tc commands that build the qos hierarchy on an interface without a root qdisc
+fn qos_commands(iface_name: &str, max_rate: u32, priority_rate: u32) -> Vec<Vec<String>> {
+    // the priority class can't be guaranteed more than the whole tunnel
+    let priority_rate = priority_rate.min(max_rate);
+    // htb won't take a rate of zero
+    let bulk_rate = (max_rate - priority_rate).max(8);
+    let ceil = format!("{}kbit", max_rate);
+
+    let mut commands = vec![
+        format!(
+            "qdisc add dev {} root handle 1: htb default {}",
+            iface_name, QOS_BULK_CLASS
+        ),
+        format!(
+            "class add dev {} parent 1: classid 1:1 htb rate {} ceil {}",
+            iface_name, ceil, ceil
+        ),
+    ];
+    for (class, rate, prio) in [
+        (QOS_PRIORITY_CLASS, priority_rate, 0),
+        (QOS_BULK_CLASS, bulk_rate, 1),
+    ]
+    .iter()
+    {
+        commands.push(format!(
+            "class add dev {} parent 1:1 classid 1:{} htb rate {}kbit ceil {} prio {}",
+            iface_name, class, rate, ceil, prio
+        ));
+        commands.push(format!(
+            "qdisc add dev {} parent 1:{} handle {}: fq_codel",
+            iface_name, class, class
+        ));
+    }
+
+    let filter = format!("filter add dev {} parent 1: prio 1 protocol", iface_name);
+    for tos in QOS_PRIORITY_TOS.iter() {
+        commands.push(format!(
+            "{} ip u32 match ip tos {} 0xfc flowid 1:{}",
+            filter, tos, QOS_PRIORITY_CLASS
+        ));
+        commands.push(format!(
+            "{} ipv6 u32 match ip6 priority {} 0xfc flowid 1:{}",
+            filter, tos, QOS_PRIORITY_CLASS
+        ));
+    }
+    // packets under 128 bytes, for ipv4 that's the total length and for ipv6 the payload length
+    commands.push(format!(
+        "{} ip u32 match u16 0x0000 0xff80 at 2 flowid 1:{}",
+        filter, QOS_PRIORITY_CLASS
+    ));
+    commands.push(format!(
+        "{} ipv6 u32 match u16 0x0000 0xff80 at 4 flowid 1:{}",
+        filter, QOS_PRIORITY_CLASS
+    ));
+
+    commands
+        .iter()
+        .map(|c| c.split_whitespace().map(|s| s.to_string()).collect())
+        .collect()
+}
+
+#[test]
+fn test_qos_commands() {
+    let commands = qos_commands("wg0", 10_000, 20_000);
+    assert_eq!(
+        commands[0].join(" "),
+        "qdisc add dev wg0 root handle 1: htb default 20"
+    );
+    // the priority rate is capped at the tunnel rate and bulk gets the minimum
+    assert!(commands.iter().any(|c| c.join(" ")
+        == "class add dev wg0 parent 1:1 classid 1:10 htb rate 10000kbit ceil 10000kbit prio 0"));
+    assert!(commands.iter().any(|c| c.join(" ")
+        == "class add dev wg0 parent 1:1 classid 1:20 htb rate 8kbit ceil 10000kbit prio 1"));
+    let filters = commands.iter().filter(|c| c[0] == "filter").count();
+    assert_eq!(filters, QOS_PRIORITY_TOS.len() * 2 + 2);
+}
+
 #[test]
 fn get_id() {
     use crate::KI;
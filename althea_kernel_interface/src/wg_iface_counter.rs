@@ -29,7 +29,7 @@ pub fn prepare_usage_history(
                     history.download = 0;
                 }
                 if history.upload > bytes.upload {
-                    history.download = 0;
+                    history.upload = 0;
                 }
             }
             None => {
@@ -0,0 +1,100 @@
+//! Helpers for keeping WireGuard tunnels to peers behind NAT alive. WireGuard follows a peer
+//! when its endpoint changes, but if nothing is sent for a while the NAT mapping on the peer's
+//! side expires and we can no longer reach them until they send to us first.
+
+use super::KernelInterface;
+use althea_types::WgKey;
+use failure::Error;
+use std::collections::HashMap;
+
+impl dyn KernelInterface {
+    /// The endpoint WireGuard currently has for the first peer of every wg interface, from a
+    /// single `wg show all`. Interfaces whose peer we haven't heard from yet are left out
+    pub fn get_wg_endpoints(&self) -> Result<HashMap<String, String>, Error> {
+        let output = self.run_command("wg", &["show", "all", "endpoints"])?;
+        if !output.status.success() {
+            bail!(
+                "Failed to get wg endpoints {}",
+                String::from_utf8(output.stderr)?
+            );
+        }
+        let stdout = String::from_utf8(output.stdout)?;
+        let mut endpoints = HashMap::new();
+        for line in stdout.lines() {
+            // interface, peer key, endpoint
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if let [iface, _, endpoint] = fields.as_slice() {
+                if *endpoint != "(none)" {
+                    endpoints
+                        .entry(iface.to_string())
+                        .or_insert_with(|| endpoint.to_string());
+                }
+            }
+        }
+        Ok(endpoints)
+    }
+
+    /// Sets the persistent keepalive interval for a peer in seconds, 0 disables keepalives
+    pub fn set_wg_keepalive(&self, name: &str, peer: &WgKey, interval: u16) -> Result<(), Error> {
+        let output = self.run_command(
+            "wg",
+            &[
+                "set",
+                name,
+                "peer",
+                &peer.to_string(),
+                "persistent-keepalive",
+                &interval.to_string(),
+            ],
+        )?;
+        if !output.status.success() {
+            bail!(
+                "Failed to set keepalive for {} {}",
+                name,
+                String::from_utf8(output.stderr)?
+            );
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_get_wg_endpoints() {
+    use crate::KI;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+
+    let mut counter = 0;
+
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        assert_eq!(program, "wg");
+        assert_eq!(args, vec!["show", "all", "endpoints"]);
+        match counter {
+            1 => Ok(Output {
+                stdout: b"wg0\tfvLYbeMV+RYbzJEc4lNEPuK8ulva/5wcSJBz0W5t3hM=\t71.8.186.226:60000
+wg1\tx8AcR9wI4t97aowYFlis077BDBk9SLdq6khMiixuTsQ=\t(none)
+wg_exit\tfvLYbeMV+RYbzJEc4lNEPuK8ulva/5wcSJBz0W5t3hM=\t[fd00::1]:59999
+wg_exit\tx8AcR9wI4t97aowYFlis077BDBk9SLdq6khMiixuTsQ=\t[fd00::2]:59999
+"
+                .to_vec(),
+                stderr: b"".to_vec(),
+                status: ExitStatus::from_raw(0),
+            }),
+            // a failed command is an error even if it printed nothing
+            2 => Ok(Output {
+                stdout: b"".to_vec(),
+                stderr: b"".to_vec(),
+                status: ExitStatus::from_raw(1 << 8),
+            }),
+            _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
+        }
+    }));
+
+    let endpoints = KI.get_wg_endpoints().unwrap();
+    assert_eq!(endpoints.len(), 2);
+    assert_eq!(endpoints["wg0"], "71.8.186.226:60000");
+    assert_eq!(endpoints["wg_exit"], "[fd00::1]:59999");
+    assert!(KI.get_wg_endpoints().is_err());
+}
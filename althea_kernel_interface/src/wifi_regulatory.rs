@@ -0,0 +1,183 @@
+//! Reads the regulatory domain the wifi drivers are enforcing from `iw reg get` and the power and
+//! channel each wireless interface is transmitting at, so that power and country changes can be
+//! checked against what's legal before they're made.
+
+use super::KernelInterface;
+use failure::Error;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RegRule {
+    pub start_mhz: u32,
+    pub end_mhz: u32,
+    /// the most that may be radiated, antenna gain included
+    pub max_eirp_dbm: u8,
+    pub dfs: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RegDomain {
+    /// two letter ISO country code, 00 for the world domain
+    pub country: String,
+    pub rules: Vec<RegRule>,
+}
+
+impl RegDomain {
+    /// The power limit for a channel centered on this frequency, None if it's not allowed at all
+    pub fn max_eirp(&self, freq_mhz: u32) -> Option<u8> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.start_mhz <= freq_mhz && freq_mhz <= rule.end_mhz)
+            .map(|rule| rule.max_eirp_dbm)
+            .max()
+    }
+}
+
+/// One rule line, like '(5250 - 5330 @ 80), (N/A, 23), (0 ms), DFS, AUTO-BW'
+fn parse_reg_rule(line: &str) -> Option<RegRule> {
+    let mut groups = line.trim().trim_start_matches('(').split("), (");
+    let mut range = groups.next()?.split(|c| c == '-' || c == '@');
+    let start_mhz = range.next()?.trim().parse().ok()?;
+    let end_mhz = range.next()?.trim().parse().ok()?;
+    // the gain, usually N/A, then the power
+    let power = groups.next()?.split(',').nth(1)?.trim();
+    let max_eirp_dbm = power
+        .trim_end_matches(')')
+        .split_whitespace()
+        .next()?
+        .parse::<f32>()
+        .ok()? as u8;
+    Some(RegRule {
+        start_mhz,
+        end_mhz,
+        max_eirp_dbm,
+        dfs: line.contains("DFS"),
+    })
+}
+
+/// The first domain in the output of `iw reg get`, the global one. Radios with a domain of their
+/// own are listed after it and are left out
+fn parse_reg_get(output: &str) -> Result<RegDomain, Error> {
+    let mut domain: Option<RegDomain> = None;
+    for line in output.lines() {
+        if let Some(domain) = domain.as_mut() {
+            if line.trim().is_empty() || !line.starts_with(char::is_whitespace) {
+                break;
+            }
+            if let Some(rule) = parse_reg_rule(line) {
+                domain.rules.push(rule);
+            }
+        } else if line.starts_with("country ") {
+            let country = line["country ".len()..].split(':').next().unwrap_or("");
+            domain = Some(RegDomain {
+                country: country.trim().to_string(),
+                rules: Vec::new(),
+            });
+        }
+    }
+    match domain {
+        Some(domain) => Ok(domain),
+        None => bail!("No regulatory domain in {}", output),
+    }
+}
+
+/// The power line of `iw dev <iface> info`, like '	txpower 20.00 dBm'
+fn parse_txpower(output: &str) -> Option<f32> {
+    output.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        if words.next()? != "txpower" {
+            return None;
+        }
+        words.next()?.parse().ok()
+    })
+}
+
+/// The channel line of `iw dev <iface> info`, like '	channel 36 (5180 MHz), width: 80 MHz'
+fn parse_channel(output: &str) -> Option<u16> {
+    output.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        if words.next()? != "channel" {
+            return None;
+        }
+        words.next()?.parse().ok()
+    })
+}
+
+impl dyn KernelInterface {
+    /// The regulatory domain the drivers are currently enforcing
+    pub fn get_wifi_regdomain(&self) -> Result<RegDomain, Error> {
+        let output = self.run_command("iw", &["reg", "get"])?;
+        if !output.status.success() {
+            bail!(
+                "Failed to get the regulatory domain {}",
+                String::from_utf8(output.stderr)?
+            );
+        }
+        parse_reg_get(&String::from_utf8(output.stdout)?)
+    }
+
+    /// What the given wireless interface is transmitting at in dBm, None if the driver doesn't
+    /// say or the interface is down
+    pub fn get_wifi_txpower(&self, iface: &str) -> Result<Option<f32>, Error> {
+        let output = self.run_command("iw", &["dev", iface, "info"])?;
+        if !output.status.success() {
+            bail!(
+                "Failed to get info for {} {}",
+                iface,
+                String::from_utf8(output.stderr)?
+            );
+        }
+        Ok(parse_txpower(&String::from_utf8(output.stdout)?))
+    }
+
+    /// The channel the given wireless interface is on, None if the interface is down. This is
+    /// how to find out which one the driver picked for a radio set to 'auto'
+    pub fn get_wifi_channel(&self, iface: &str) -> Result<Option<u16>, Error> {
+        let output = self.run_command("iw", &["dev", iface, "info"])?;
+        if !output.status.success() {
+            bail!(
+                "Failed to get info for {} {}",
+                iface,
+                String::from_utf8(output.stderr)?
+            );
+        }
+        Ok(parse_channel(&String::from_utf8(output.stdout)?))
+    }
+}
+
+#[test]
+fn test_parse_reg_get() {
+    let output = "global
+country US: DFS-FCC
+\t(2400 - 2472 @ 40), (N/A, 30), (N/A)
+\t(5150 - 5250 @ 80), (N/A, 23), (N/A), AUTO-BW
+\t(5250 - 5350 @ 80), (N/A, 24), (0 ms), DFS, AUTO-BW
+\t(5730 - 5850 @ 80), (N/A, 30), (N/A), AUTO-BW
+
+phy#1 (self-managed)
+country 00: DFS-UNSET
+\t(2402 - 2472 @ 40), (6, 20), (N/A)
+";
+    let domain = parse_reg_get(output).unwrap();
+    assert_eq!(domain.country, "US");
+    assert_eq!(domain.rules.len(), 4);
+    assert_eq!(
+        domain.rules[2],
+        RegRule {
+            start_mhz: 5250,
+            end_mhz: 5350,
+            max_eirp_dbm: 24,
+            dfs: true,
+        }
+    );
+    assert_eq!(domain.max_eirp(2437), Some(30));
+    assert_eq!(domain.max_eirp(5180), Some(23));
+    // channel 144 isn't allowed with this domain
+    assert_eq!(domain.max_eirp(5720), None);
+    assert!(parse_reg_get("").is_err());
+
+    let info = "Interface wlan0\n\tifindex 12\n\ttype AP\n\tchannel 36 (5180 MHz), width: 80 MHz, center1: 5210 MHz\n\ttxpower 23.00 dBm\n";
+    assert_eq!(parse_txpower(info), Some(23.0));
+    assert_eq!(parse_txpower("Interface wlan0\n"), None);
+    assert_eq!(parse_channel(info), Some(36));
+    assert_eq!(parse_channel("Interface wlan0\n"), None);
+}
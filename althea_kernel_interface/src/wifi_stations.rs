@@ -0,0 +1,174 @@
+//! Lists the stations associated with the wireless interfaces on this device by parsing the
+//! output of `iw`, used to show users who is connected to their router.
+
+use super::KernelInterface;
+use failure::Error;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WifiStation {
+    pub mac: String,
+    pub signal_dbm: Option<i32>,
+    pub tx_bitrate_mbps: Option<f32>,
+    pub rx_bitrate_mbps: Option<f32>,
+    /// Time spent transmitting to and receiving from this station in microseconds, not every
+    /// driver reports this
+    pub airtime_us: Option<u64>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    /// Seconds since the station associated
+    pub connected_time: Option<u64>,
+}
+
+/// Takes the first whitespace separated word of an iw value, so '-29 [-31, -33] dBm' is -29
+/// and '65.0 MBit/s MCS 7' is 65.0
+fn first_word<T: std::str::FromStr>(value: &str) -> Option<T> {
+    value.split_whitespace().next()?.parse().ok()
+}
+
+fn parse_station_dump(output: &str) -> Vec<WifiStation> {
+    let mut stations = Vec::new();
+    let mut current: Option<WifiStation> = None;
+    for line in output.lines() {
+        if line.starts_with("Station ") {
+            if let Some(station) = current.take() {
+                stations.push(station);
+            }
+            if let Some(mac) = line.split_whitespace().nth(1) {
+                current = Some(WifiStation {
+                    mac: mac.to_string(),
+                    ..Default::default()
+                });
+            }
+            continue;
+        }
+        let station = match current.as_mut() {
+            Some(station) => station,
+            None => continue,
+        };
+        let mut parts = line.splitn(2, ':');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key.trim(), value.trim()),
+            _ => continue,
+        };
+        match key {
+            "signal" => station.signal_dbm = first_word(value),
+            "tx bitrate" => station.tx_bitrate_mbps = first_word(value),
+            "rx bitrate" => station.rx_bitrate_mbps = first_word(value),
+            "rx bytes" => station.rx_bytes = first_word(value).unwrap_or(0),
+            "tx bytes" => station.tx_bytes = first_word(value).unwrap_or(0),
+            "connected time" => station.connected_time = first_word(value),
+            "rx duration" | "tx duration" => {
+                if let Some(us) = first_word::<u64>(value) {
+                    station.airtime_us = Some(station.airtime_us.unwrap_or(0) + us);
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some(station) = current.take() {
+        stations.push(station);
+    }
+    stations
+}
+
+impl dyn KernelInterface {
+    /// Returns the wireless interfaces on this device along with the radio (phy) they belong to
+    pub fn get_wifi_ifaces(&self) -> Result<Vec<(String, String)>, Error> {
+        let output = self.run_command("iw", &["dev"])?;
+        let stdout = String::from_utf8(output.stdout)?;
+        let mut ifaces = Vec::new();
+        let mut phy = None;
+        for line in stdout.lines() {
+            let line = line.trim();
+            if line.starts_with("phy#") {
+                phy = Some(line.replace("#", ""));
+            } else if line.starts_with("Interface ") {
+                if let (Some(phy), Some(iface)) = (phy.clone(), line.split_whitespace().nth(1)) {
+                    ifaces.push((phy, iface.to_string()));
+                }
+            }
+        }
+        Ok(ifaces)
+    }
+
+    /// Returns the stations currently associated with the given wireless interface
+    pub fn get_wifi_stations(&self, iface: &str) -> Result<Vec<WifiStation>, Error> {
+        let output = self.run_command("iw", &["dev", iface, "station", "dump"])?;
+        if !output.status.success() {
+            bail!(
+                "Failed to get stations for {} {}",
+                iface,
+                String::from_utf8(output.stderr)?
+            );
+        }
+        Ok(parse_station_dump(&String::from_utf8(output.stdout)?))
+    }
+}
+
+#[test]
+fn test_get_wifi_stations() {
+    use crate::KI;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+
+    let mut counter = 0;
+
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        match counter {
+            1 => {
+                assert_eq!(program, "iw");
+                assert_eq!(args, vec!["dev", "wlan0", "station", "dump"]);
+                Ok(Output {
+                    stdout: b"Station 12:34:56:78:9a:bc (on wlan0)
+\tinactive time:\t304 ms
+\trx bytes:\t18816
+\trx packets:\t75
+\ttx bytes:\t5386
+\ttx packets:\t21
+\tsignal:  \t-29 [-31, -33] dBm
+\ttx bitrate:\t65.0 MBit/s MCS 7
+\trx bitrate:\t1.0 MBit/s
+\trx duration:\t5432 us
+\ttx duration:\t1234 us
+\tauthorized:\tyes
+\tconnected time:\t10 seconds
+Station de:ad:be:ef:00:01 (on wlan0)
+\trx bytes:\t100
+\ttx bytes:\t200
+\tsignal:  \t-70 dBm
+"
+                    .to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(0),
+                })
+            }
+            _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
+        }
+    }));
+
+    let stations = KI.get_wifi_stations("wlan0").unwrap();
+    assert_eq!(
+        stations,
+        vec![
+            WifiStation {
+                mac: "12:34:56:78:9a:bc".to_string(),
+                signal_dbm: Some(-29),
+                tx_bitrate_mbps: Some(65.0),
+                rx_bitrate_mbps: Some(1.0),
+                airtime_us: Some(6666),
+                rx_bytes: 18816,
+                tx_bytes: 5386,
+                connected_time: Some(10),
+            },
+            WifiStation {
+                mac: "de:ad:be:ef:00:01".to_string(),
+                signal_dbm: Some(-70),
+                rx_bytes: 100,
+                tx_bytes: 200,
+                ..Default::default()
+            }
+        ]
+    );
+}
@@ -1,14 +1,17 @@
 use crate::wg_key::WgKey;
 use arrayvec::ArrayString;
-use clarity::Address;
+use clarity::{Address, Signature};
 use failure::Error;
+use ipnetwork::IpNetwork;
 use num256::Uint256;
+use sodiumoxide::crypto::hash::sha256;
 use std::collections::hash_map::DefaultHasher;
 use std::fmt;
 use std::fmt::Display;
 use std::hash::{Hash, Hasher};
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
+use std::ops::BitOr;
 use std::str::FromStr;
 
 #[cfg(feature = "actix")]
@@ -143,6 +146,21 @@ pub struct ExitRegistrationDetails {
     pub phone_code: Option<String>,
 }
 
+/// A period during which an exit will be unavailable, times are in seconds since the unix epoch
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Hash)]
+pub struct MaintenanceWindow {
+    pub start: u64,
+    pub end: u64,
+    pub message: String,
+}
+
+impl MaintenanceWindow {
+    /// True if the window is in progress or starts within `lead_time` seconds of `now`
+    pub fn is_imminent(&self, now: u64, lead_time: u64) -> bool {
+        now < self.end && now + lead_time >= self.start
+    }
+}
+
 /// This is the state an exit can be in
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Hash)]
 #[serde(tag = "state")]
@@ -154,6 +172,10 @@ pub enum ExitState {
 
         #[serde(default)]
         auto_register: bool,
+        /// Seconds to wait before sending the setup request again without being asked to, None
+        /// if it shouldn't be until the user has done something about the message
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry_after: Option<u64>,
     },
     Registering {
         general_details: ExitDetails,
@@ -165,6 +187,10 @@ pub enum ExitState {
         #[serde(default)]
         email_code: Option<String>,
         phone_code: Option<String>,
+        /// wrong codes that can still be submitted before a new one has to be sent, None if
+        /// there's no limit
+        #[serde(default)]
+        remaining_attempts: Option<u32>,
     },
     Registered {
         general_details: ExitDetails,
@@ -175,6 +201,19 @@ pub enum ExitState {
         message: String,
     },
     Disabled,
+    /// The exit has stopped serving clients for the time being, registration is retained
+    Suspended {
+        general_details: ExitDetails,
+        message: String,
+    },
+    /// The exit is still serving us but has a maintenance window scheduled or in progress,
+    /// clients should move to another exit before it begins
+    Maintenance {
+        general_details: ExitDetails,
+        our_details: ExitClientDetails,
+        window: MaintenanceWindow,
+        message: String,
+    },
 }
 
 impl Default for ExitState {
@@ -202,6 +241,14 @@ impl ExitState {
                 ref general_details,
                 ..
             } => Some(general_details),
+            &ExitState::Suspended {
+                ref general_details,
+                ..
+            } => Some(general_details),
+            &ExitState::Maintenance {
+                ref general_details,
+                ..
+            } => Some(general_details),
             _ => None,
         }
     }
@@ -211,6 +258,9 @@ impl ExitState {
             &ExitState::Registered {
                 ref our_details, ..
             } => Some(our_details),
+            &ExitState::Maintenance {
+                ref our_details, ..
+            } => Some(our_details),
             _ => None,
         }
     }
@@ -224,6 +274,19 @@ impl ExitState {
             &ExitState::Registered { ref message, .. } => message.clone(),
             &ExitState::Denied { ref message, .. } => message.clone(),
             &ExitState::Disabled => "Exit disabled".to_string(),
+            &ExitState::Suspended { ref message, .. } => message.clone(),
+            &ExitState::Maintenance { ref message, .. } => message.clone(),
+        }
+    }
+
+    /// Exits in these states still have our registration on file and should be polled
+    /// for status updates
+    pub fn is_registered(&self) -> bool {
+        match self {
+            ExitState::Registered { .. }
+            | ExitState::Suspended { .. }
+            | ExitState::Maintenance { .. } => true,
+            _ => false,
         }
     }
 }
@@ -235,6 +298,18 @@ pub struct ExitClientIdentity {
     pub global: Identity,
     pub reg_details: ExitRegistrationDetails,
     pub low_balance: Option<bool>,
+    /// a prepaid voucher code to redeem once registered
+    #[serde(default)]
+    pub voucher: Option<String>,
+    /// The addresses this client has with the exit it's currently using, other exits hand out
+    /// the same ones if they are free so that failing over between them doesn't change them
+    #[serde(default)]
+    pub preferred_internal_ip: Option<IpAddr>,
+    #[serde(default)]
+    pub preferred_ipv6_subnet: Option<IpNetwork>,
+    /// The answer to the exit's signup challenge, if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signup_response: Option<String>,
 }
 
 /// Wrapper for secure box containing an exit client identity
@@ -274,11 +349,347 @@ pub struct ExitDetails {
     pub description: String,
     #[serde(default = "default_verif_mode")]
     pub verif_mode: ExitVerifMode,
+    /// If clients share public ipv4 addresses, each getting a fixed range of ports, rather than
+    /// all being masqueraded behind the exit's address
+    #[serde(default)]
+    pub shared_ipv4: bool,
+    /// The terms this exit serves clients under, older exits don't send any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub terms: Option<SignedExitTerms>,
+    /// Present if the exit translates ipv6 to ipv4 for clients with ipv6 only LANs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nat64: Option<Nat64Details>,
+    /// How busy the exit was over its last billing round, older exits don't send this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub load: Option<ExitLoad>,
+    /// Messages from the exit's operator to its clients, older exits don't send any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub announcements: Vec<SignedAnnouncement>,
+    /// Ports the exit blocks for all clients
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub port_blocks: Vec<PortBlock>,
+    /// What new clients have to do before the exit takes their signup, older exits don't have
+    /// a challenge
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signup_challenge: Option<SignupChallenge>,
+    /// The exit's clock when it answered, in seconds since the unix epoch. It's inside the
+    /// encrypted exit state so clients can check their clock against it without trusting
+    /// whoever is in between
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<u64>,
+}
+
+/// A step an exit makes new clients go through before signing them up, so that bots can't run
+/// through its address pools
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+pub enum SignupChallenge {
+    /// The client has to find a nonce for its identity and `seed` with a hash starting with
+    /// `difficulty` zero bits, see `solve_signup_pow`. The exit changes the seed every so often
+    /// so that solutions can't be worked out ahead of time or kept around
+    ProofOfWork {
+        difficulty: u8,
+        #[serde(default)]
+        seed: u64,
+    },
+    /// The client has to send a passphrase the operator gives out, `prompt` tells the user
+    /// where to get it
+    Passphrase { prompt: String },
+}
+
+/// sha256 of the identity's wg key and mesh ip followed by the exit's seed and the nonce, the
+/// work is tied to the identity so that a solution can't be reused to sign up another one
+fn signup_pow_hash(id: &Identity, seed: u64, nonce: u64) -> [u8; 32] {
+    let mut data = Vec::new();
+    data.extend_from_slice(id.wg_public_key.as_ref());
+    data.extend_from_slice(id.mesh_ip.to_string().as_bytes());
+    data.extend_from_slice(&seed.to_be_bytes());
+    data.extend_from_slice(&nonce.to_be_bytes());
+    sha256::hash(&data).0
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+pub fn check_signup_pow(id: &Identity, seed: u64, nonce: u64, difficulty: u8) -> bool {
+    leading_zero_bits(&signup_pow_hash(id, seed, nonce)) >= u32::from(difficulty)
+}
+
+/// Takes around 2^difficulty hashes, gives up with None once `cancelled` returns true
+pub fn solve_signup_pow(
+    id: &Identity,
+    seed: u64,
+    difficulty: u8,
+    cancelled: impl Fn() -> bool,
+) -> Option<u64> {
+    for nonce in 0.. {
+        if cancelled() {
+            return None;
+        }
+        if check_signup_pow(id, seed, nonce, difficulty) {
+            return Some(nonce);
+        }
+    }
+    None
 }
 
+/// How much room an exit has left for more traffic
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum CapacityClass {
+    /// the exit doesn't know its capacity
+    Unknown,
+    Plenty,
+    Limited,
+    Full,
+}
+
+impl CapacityClass {
+    pub fn from_utilization(utilization: Option<u8>) -> CapacityClass {
+        match utilization {
+            None => CapacityClass::Unknown,
+            Some(percent) if percent < 50 => CapacityClass::Plenty,
+            Some(percent) if percent < 85 => CapacityClass::Limited,
+            Some(_) => CapacityClass::Full,
+        }
+    }
+}
+
+/// A summary of an exit's load sent with its details, so that clients picking an exit can
+/// stay away from crowded ones
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct ExitLoad {
+    /// clients with a tunnel to the exit
+    pub clients: u32,
+    /// throughput as a percentage of the exit's capacity, in whichever direction is busier,
+    /// None if the exit doesn't know its capacity
+    pub utilization: Option<u8>,
+    pub capacity: CapacityClass,
+}
+
+/// How a client with an ipv6 only LAN reaches the ipv4 internet through the exit
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct Nat64Details {
+    /// ipv4 addresses are reached at this prefix with the address in the last bits
+    pub prefix: IpNetwork,
+    /// answers with addresses in the prefix for names that only have ipv4 addresses
+    pub dns64_resolver: IpAddr,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum PortProtocol {
+    Tcp,
+    Udp,
+}
+
+impl Display for PortProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PortProtocol::Tcp => write!(f, "tcp"),
+            PortProtocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// Outbound traffic to a destination port, or range of them, that an exit doesn't forward, for
+/// example SMTP where the exit's jurisdiction requires blocking it
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct PortBlock {
+    pub protocol: PortProtocol,
+    pub port: u16,
+    /// the last port of the range, None to block only `port`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port_end: Option<u16>,
+}
+
+impl PortBlock {
+    pub fn is_valid(&self) -> bool {
+        self.port != 0 && self.port_end.map_or(true, |end| end >= self.port)
+    }
+}
+
+/// In nft syntax, `tcp dport 25` or `udp dport 1000-2000`
+impl Display for PortBlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.port_end {
+            Some(end) if end != self.port => {
+                write!(f, "{} dport {}-{}", self.protocol, self.port, end)
+            }
+            _ => write!(f, "{} dport {}", self.protocol, self.port),
+        }
+    }
+}
+
+/// What an exit charges and where it operates, clients accept these before using the exit and
+/// again whenever they change
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+pub struct ExitTerms {
+    /// wei per byte
+    pub exit_price: u64,
+    /// kbps a client with a low balance is throttled to rather than cut off
+    pub free_tier_throughput: u32,
+    /// where the exit's traffic leaves for the internet, and whose laws apply to it
+    pub jurisdiction: String,
+}
+
+impl ExitTerms {
+    /// True if a client that accepted `accepted` can't be worse off under these terms
+    pub fn no_worse_than(&self, accepted: &ExitTerms) -> bool {
+        self.exit_price <= accepted.exit_price
+            && self.free_tier_throughput >= accepted.free_tier_throughput
+            && self.jurisdiction == accepted.jurisdiction
+    }
+
+    /// True if a client that accepted `accepted` can take these terms without asking its user,
+    /// because it can't be worse off or because the jurisdiction is the same and the price is
+    /// within `max_price`, the most the user has said they'll pay for an exit
+    pub fn acceptable_under(&self, accepted: &ExitTerms, max_price: Option<u64>) -> bool {
+        self.no_worse_than(accepted)
+            || (self.jurisdiction == accepted.jurisdiction
+                && max_price.map_or(false, |max| self.exit_price <= max))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct SignedExitTerms {
+    pub terms: ExitTerms,
+    /// the eth address of the exit, which must have made the signature
+    pub signer: Address,
+    /// by the exit's eth key over the terms
+    pub signature: Signature,
+}
+
+impl Eq for SignedExitTerms {}
+
+// the signature is determined by the rest
+impl Hash for SignedExitTerms {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.terms.hash(state);
+        self.signer.hash(state);
+    }
+}
+
+/// A message from an exit's operator to its clients, such as notice of upcoming maintenance or a
+/// price change
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+pub struct Announcement {
+    /// Increases with each announcement an exit makes, clients use it to keep track of which
+    /// ones they have seen
+    pub id: u64,
+    /// seconds since the unix epoch
+    pub posted: u64,
+    /// The announcement is no longer shown after this, in seconds since the unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+    pub message: String,
+}
+
+impl Announcement {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires.map_or(false, |expires| now >= expires)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct SignedAnnouncement {
+    pub announcement: Announcement,
+    /// the eth address of the exit, which must have made the signature
+    pub signer: Address,
+    /// by the exit's eth key over the announcement
+    pub signature: Signature,
+}
+
+impl Eq for SignedAnnouncement {}
+
+// the signature is determined by the rest
+impl Hash for SignedAnnouncement {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.announcement.hash(state);
+        self.signer.hash(state);
+    }
+}
+
+/// The public ipv4 address and range of source ports a client's traffic is translated to when
+/// the exit shares its public addresses between clients
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct NatPortRange {
+    pub public_ip: Ipv4Addr,
+    pub start: u16,
+    /// inclusive
+    pub end: u16,
+}
+
+impl Display for NatPortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}-{}", self.public_ip, self.start, self.end)
+    }
+}
+
+impl FromStr for NatPortRange {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<NatPortRange, Error> {
+        let mut parts = s.splitn(2, ':');
+        let public_ip = parts.next().unwrap_or_default().parse()?;
+        let mut ports = parts.next().unwrap_or_default().splitn(2, '-');
+        let start = ports.next().unwrap_or_default().parse()?;
+        let end = ports.next().unwrap_or_default().parse()?;
+        if start > end {
+            bail!("Port range {} is backwards", s);
+        }
+        Ok(NatPortRange {
+            public_ip,
+            start,
+            end,
+        })
+    }
+}
+
+/// The plan a client is on at an exit and how much of it they've used
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Default)]
+pub struct ExitPlan {
+    pub name: String,
+    /// in kbit/s, None if the client isn't limited beyond what the exit can do
+    #[serde(default)]
+    pub rate_limit: Option<u32>,
+    /// bytes per calendar month in UTC, None for no quota
+    #[serde(default)]
+    pub monthly_quota: Option<u64>,
+    /// bytes up and down so far this month, as of the exit's last count
+    #[serde(default)]
+    pub used_this_month: u64,
+    /// once the quota is used up the client is held to the free tier until the month is over
+    #[serde(default)]
+    pub quota_exceeded: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
 pub struct ExitClientDetails {
     pub client_internal_ip: IpAddr,
+    /// The ipv6 subnet delegated to this client for its LAN, if the exit has ipv6 to give out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub internet_ipv6_subnet: Option<IpNetwork>,
+    /// Where this client's traffic appears to come from if the exit shares its public ipv4
+    /// addresses, mostly useful for troubleshooting and abuse reports
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nat_port_range: Option<NatPortRange>,
+    /// A public ipv4 address the exit has given this client to itself, the client is reachable
+    /// from the internet at this address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_ipv4: Option<Ipv4Addr>,
+    /// Ports the exit blocks for this client in particular, on top of the ones it blocks for
+    /// everyone
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub port_blocks: Vec<PortBlock>,
+    /// The plan this client is on, None if the exit treats it like everyone else
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plan: Option<ExitPlan>,
 }
 
 #[cfg(feature = "actix")]
@@ -293,6 +704,89 @@ pub struct LocalIdentity {
     pub wg_port: u16,
     pub have_tunnel: Option<bool>, // If we have an existing tunnel, None if we don't know
     pub global: Identity,
+    /// The fee this node currently charges to forward traffic, advertised so that
+    /// neighbors can compare prices. None for older nodes that don't send it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_fee: Option<u32>,
+    /// Proof that the sender holds the private key for the wg key in `global`, in a hello
+    /// this answers the challenge we were given and in a hello response the one we handed out.
+    /// None for older nodes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<HelloAuth>,
+    /// The optional behaviors the sender supports, older nodes send nothing and so support none
+    #[serde(default, skip_serializing_if = "FeatureFlags::is_empty")]
+    pub features: FeatureFlags,
+}
+
+/// A set of optional protocol behaviors, exchanged in hellos so that each side only uses the new
+/// behaviors both understand. Only ever add flags, the bits of old ones must never be reused
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy, Default)]
+pub struct FeatureFlags(pub u64);
+
+impl FeatureFlags {
+    /// Answers hello challenges, see `HelloAuth`
+    pub const HELLO_AUTH: FeatureFlags = FeatureFlags(1);
+    /// Signs a `PaymentReceipt` for payments once they're validated, handed out by
+    /// `/payment_status`
+    pub const PAYMENT_RECEIPTS: FeatureFlags = FeatureFlags(1 << 1);
+    /// Exchanges `TrafficCounts` so that both sides can check their counters agree
+    pub const COUNTER_RECONCILIATION: FeatureFlags = FeatureFlags(1 << 2);
+    /// Keeps a debt journal and takes an `Iou` for debts that couldn't be paid
+    pub const DEBT_JOURNAL: FeatureFlags = FeatureFlags(1 << 3);
+    /// Gossips the `MeshService` table to neighbors
+    pub const SERVICE_DISCOVERY: FeatureFlags = FeatureFlags(1 << 4);
+    /// Answers `/payment_status` and drops txids its full node hasn't seen, so payers should
+    /// check on payments it acknowledged
+    pub const PAYMENT_STATUS: FeatureFlags = FeatureFlags(1 << 5);
+    /// Can have tunnels with its endpoint on an ipv4 address, for links without ipv6 link
+    /// local addresses
+    pub const IPV4_TUNNELS: FeatureFlags = FeatureFlags(1 << 6);
+    /// Can route its clients through more than one exit at once, so neighbors may see its
+    /// traffic headed for several exits
+    pub const MULTI_EXIT: FeatureFlags = FeatureFlags(1 << 7);
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// True if every flag in `other` is set
+    pub fn contains(self, other: FeatureFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The flags both sides support
+    pub fn negotiate(self, other: FeatureFlags) -> FeatureFlags {
+        FeatureFlags(self.0 & other.0)
+    }
+}
+
+impl BitOr for FeatureFlags {
+    type Output = FeatureFlags;
+
+    fn bitor(self, other: FeatureFlags) -> FeatureFlags {
+        FeatureFlags(self.0 | other.0)
+    }
+}
+
+/// A challenge handed out by the hello endpoint before a tunnel is negotiated. It's
+/// stateless, the mac lets the issuer check that it created the challenge for this
+/// requester and that it hasn't expired
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct HelloChallenge {
+    /// the wg key of the node issuing the challenge
+    pub pubkey: WgKey,
+    pub nonce: [u8; 32],
+    /// seconds since the unix epoch, by the issuers clock, after which this is rejected
+    pub expires: u64,
+    pub mac: [u8; 32],
+}
+
+/// Answer to a HelloChallenge, an hmac over the challenge keyed with the Diffie-Hellman
+/// shared secret of the two nodes wg keys, which only the private key holders can derive
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct HelloAuth {
+    pub challenge: HelloChallenge,
+    pub proof: [u8; 32],
 }
 
 #[cfg(feature = "actix")]
@@ -301,15 +795,62 @@ impl Message for LocalIdentity {
 }
 
 /// This is all the data a light client needs to open a light client tunnel
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
 pub struct LightClientLocalIdentity {
     pub wg_port: u16,
     pub have_tunnel: Option<bool>, // If we have an existing tunnel, None if we don't know
     pub global: Identity,
     pub tunnel_address: Ipv4Addr, // we have to replicate dhcp ourselves due to the android vpn api
     pub price: u128, // the local_fee of the node passing light client traffic, much bigger
-                     // than the actual babel price field for ergonomics around downcasting
-                     // the number after upcasting when we compute it.
+    // than the actual babel price field for ergonomics around downcasting
+    // the number after upcasting when we compute it.
+    /// Presented to the next gateway the light client connects to so that it keeps its address
+    /// and this gateway lets go of it, older gateways don't send this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handoff: Option<SignedLightClientHandoff>,
+}
+
+/// A gateway's statement that a light client was connected to it with an address, which lets the
+/// client roam to another gateway
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct LightClientHandoff {
+    /// the gateway that issued the handoff
+    pub gateway: Identity,
+    /// the light client, which is billed under this identity on every gateway
+    pub client: Identity,
+    pub tunnel_address: Ipv4Addr,
+    /// unix time in seconds
+    pub issued: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct SignedLightClientHandoff {
+    pub handoff: LightClientHandoff,
+    /// by the eth key of the issuing gateway
+    pub signature: Signature,
+}
+
+impl Eq for SignedLightClientHandoff {}
+
+// the signature is determined by the rest
+impl Hash for SignedLightClientHandoff {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.handoff.hash(state);
+    }
+}
+
+/// Sent by a light client to a gateway it's roaming to, which passes it on to the old gateway
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct LightClientRoam {
+    pub id: LocalIdentity,
+    pub handoff: SignedLightClientHandoff,
+    /// the gateway the light client is moving to
+    pub gateway: Identity,
+    /// unix time in seconds
+    pub issued: u64,
+    /// by the eth key of the light client over the gateway and issued, so that the handoff
+    /// can't be used to move the client by anyone else who has seen it
+    pub signature: Signature,
 }
 
 #[cfg(feature = "actix")]
@@ -327,6 +868,126 @@ pub struct PaymentTx {
     pub amount: Uint256,
     // populated when transaction is published
     pub txid: Option<Uint256>,
+    /// populated instead of the txid when paying over a Guac payment channel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<ChannelSummary>,
+    /// picked at random by the payer for each payment and kept through every retry, so that
+    /// the payee can tell a retry from a new payment, None from nodes that predate it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payment_id: Option<u64>,
+}
+
+/// The state of a Guac payment channel after a payment, sent to the payee so that it can
+/// check the update against its own view of the channel
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone)]
+pub struct ChannelSummary {
+    pub channel_id: Uint256,
+    pub sequence_number: Uint256,
+    /// everything the payer has sent over the channel, including this payment
+    pub total_paid: Uint256,
+}
+
+/// Signed by the payee of an on chain payment once it has found it in the chain, the payer keeps these
+/// so that it can prove a payment was accepted if there's ever a dispute
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct PaymentReceipt {
+    pub txid: Uint256,
+    pub amount: Uint256,
+    /// random bytes chosen by the payee, so that every receipt is unique
+    pub nonce: [u8; 32],
+    /// the eth address of the payee, which must have made the signature
+    pub payee: Address,
+    /// by the payee's eth key over the txid, amount and nonce
+    pub signature: Signature,
+}
+
+/// Where the payee is with validating an on chain payment, for the payer to ask about after
+/// its payment was acknowledged
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(tag = "status")]
+pub enum PaymentStatus {
+    /// waiting on the transaction to make it into the chain
+    Pending,
+    /// credited to the payer, with our receipt for it if we sign them and it's ready
+    Validated {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        receipt: Option<PaymentReceipt>,
+    },
+    Failed {
+        reason: String,
+    },
+    /// never heard of it, or it was too long ago to remember
+    Unknown,
+}
+
+/// What one side of a tunnel counted over a reconciliation window, sent to the other side so
+/// that disagreements between the counters both bill from show up before the debts diverge
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TrafficCounts {
+    pub from: Identity,
+    /// the window counted, seconds since the unix epoch divided by the window length
+    pub window: u64,
+    /// bytes the sender received from the recipient
+    pub received: u64,
+    /// bytes the sender sent to the recipient
+    pub sent: u64,
+}
+
+/// A signed promise to pay, sent to a neighbor we owe but couldn't pay, usually because we've lost
+/// our connection to the blockchain. Each one replaces the last so the amount is everything owed
+/// when it was signed rather than an increment
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Iou {
+    pub from: Identity,
+    pub to: Identity,
+    pub amount: Uint256,
+    /// a higher sequence replaces a lower one
+    pub sequence: u64,
+    /// by the eth key of `from` over the rest
+    pub signature: Signature,
+}
+
+/// Tells a neighbor that we've written off some of what it owes us so that it stops counting it
+/// as owed, signed like an IOU so that no one else can forgive debts on our behalf
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct DebtWriteOff {
+    pub from: Identity,
+    pub to: Identity,
+    pub amount: Uint256,
+    /// seconds since the unix epoch, write offs are only accepted while recent and newer than
+    /// the last one from the same neighbor
+    pub time: u64,
+    /// by the eth key of `from` over the rest
+    pub signature: Signature,
+}
+
+/// A service such as a NAS or game server that a node offers to the rest of the mesh, gossiped
+/// hop by hop between neighbors that support `FeatureFlags::SERVICE_DISCOVERY`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone)]
+pub struct MeshService {
+    /// the mesh ip of the node offering the service
+    pub provider: IpAddr,
+    pub name: String,
+    pub port: u16,
+    #[serde(default)]
+    pub description: String,
+    /// how many neighbors the entry has been passed through, 0 for the provider itself
+    #[serde(default)]
+    pub hops: u8,
+}
+
+/// A `MeshService` as it's gossiped, signed by the provider so that relays can't make up entries
+/// or keep one alive after the provider stops sending it
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct SignedMeshService {
+    pub service: MeshService,
+    /// the provider's eth address, the signature is by its key
+    pub eth_address: Address,
+    /// set by the provider each time it sends its services, relays pass it on unchanged and only
+    /// a higher sequence keeps the entry alive
+    pub sequence: u64,
+    /// over everything but `service.hops`, which relays change
+    pub signature: Signature,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
@@ -375,3 +1036,46 @@ pub struct OracleUpdate {
     /// A json payload to be merged into the existing settings
     pub merge_json: serde_json::Value,
 }
+
+#[test]
+fn test_feature_flags() {
+    let ours = FeatureFlags::HELLO_AUTH | FeatureFlags::PAYMENT_RECEIPTS;
+    let theirs = FeatureFlags::HELLO_AUTH | FeatureFlags(1 << 40);
+    let negotiated = ours.negotiate(theirs);
+    assert!(negotiated.contains(FeatureFlags::HELLO_AUTH));
+    assert!(!negotiated.contains(FeatureFlags::PAYMENT_RECEIPTS));
+
+    // hellos from old nodes have no features and new ones don't send an empty set
+    let old: LocalIdentity = serde_json::from_str(
+        r#"{"wg_port":60000,"have_tunnel":null,"global":{"mesh_ip":"fd00::1","eth_address":"0x0101010101010101010101010101010101010101","wg_public_key":"8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk=","nickname":null}}"#,
+    )
+    .unwrap();
+    assert!(old.features.is_empty());
+    assert!(!serde_json::to_string(&old).unwrap().contains("features"));
+}
+
+#[test]
+fn test_signup_pow() {
+    let id: Identity = serde_json::from_str(
+        r#"{"mesh_ip":"fd00::1","eth_address":"0x0101010101010101010101010101010101010101","wg_public_key":"8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk=","nickname":null}"#,
+    )
+    .unwrap();
+    assert_eq!(leading_zero_bits(&[0, 0b0001_0000, 0xff]), 11);
+    assert_eq!(leading_zero_bits(&[0, 0]), 16);
+
+    let nonce = solve_signup_pow(&id, 7, 12, || false).unwrap();
+    assert!(check_signup_pow(&id, 7, nonce, 12));
+    assert!(check_signup_pow(&id, 7, nonce, 0));
+    assert_eq!(solve_signup_pow(&id, 7, 12, || true), None);
+    // the hash covers the mesh ip and the seed
+    let mut other = id;
+    other.mesh_ip = "fd00::2".parse().unwrap();
+    assert_ne!(
+        signup_pow_hash(&id, 7, nonce),
+        signup_pow_hash(&other, 7, nonce)
+    );
+    assert_ne!(
+        signup_pow_hash(&id, 7, nonce),
+        signup_pow_hash(&id, 8, nonce)
+    );
+}
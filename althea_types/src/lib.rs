@@ -7,9 +7,13 @@ extern crate arrayvec;
 
 pub mod interop;
 pub mod rtt;
+pub mod signing_key;
+pub mod wei;
 pub mod wg_key;
 
 pub use crate::interop::*;
 pub use crate::rtt::RTTimestamps;
+pub use crate::signing_key::SigningPubkey;
+pub use crate::wei::Wei;
 pub use crate::wg_key::WgKey;
 pub use std::str::FromStr;
@@ -0,0 +1,140 @@
+/// This file under Apache 2.0
+use base64;
+use serde::de::{Deserialize, Error, SeqAccess, Unexpected, Visitor};
+use serde::ser::{Serialize, Serializer};
+use serde::Deserializer;
+use sodiumoxide::crypto::sign;
+use std::fmt;
+use std::str::FromStr;
+
+/// An Ed25519 public key that signed documents from an operator are checked against, things like
+/// exit lists and provisioning profiles. Written out in base64 like a WgKey, an array of the raw
+/// bytes is still read for settings from before it was
+#[derive(Hash, Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SigningPubkey([u8; 32]);
+
+impl SigningPubkey {
+    /// If `signature` is a valid detached signature over `data` by this key
+    pub fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        match sign::Signature::from_slice(signature) {
+            Some(signature) => sign::verify_detached(&signature, data, &sign::PublicKey(self.0)),
+            None => false,
+        }
+    }
+}
+
+impl From<[u8; 32]> for SigningPubkey {
+    fn from(val: [u8; 32]) -> SigningPubkey {
+        SigningPubkey(val)
+    }
+}
+
+impl From<sign::PublicKey> for SigningPubkey {
+    fn from(val: sign::PublicKey) -> SigningPubkey {
+        SigningPubkey(val.0)
+    }
+}
+
+impl fmt::Display for SigningPubkey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", base64::encode(&self.0))
+    }
+}
+
+impl FromStr for SigningPubkey {
+    type Err = base64::DecodeError;
+
+    fn from_str(s: &str) -> Result<SigningPubkey, Self::Err> {
+        let mut output = [0u8; 32];
+
+        if s.len() != 44 {
+            return Err(base64::DecodeError::InvalidLength);
+        }
+
+        match base64::decode_config_slice(s, base64::STANDARD, &mut output) {
+            Ok(_) => Ok(SigningPubkey(output)),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Serialize for SigningPubkey {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+struct SigningPubkeyVisitor;
+
+impl<'de> Visitor<'de> for SigningPubkeyVisitor {
+    type Value = SigningPubkey;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "expects a valid base64-encoded string with length of 44 or an array of 32 bytes"
+        )
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        match SigningPubkey::from_str(s) {
+            Ok(key) => Ok(key),
+            Err(_) => Err(Error::invalid_value(Unexpected::Str(s), &self)),
+        }
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut output = [0u8; 32];
+        for (i, byte) in output.iter_mut().enumerate() {
+            *byte = match seq.next_element()? {
+                Some(val) => val,
+                None => return Err(Error::invalid_length(i, &self)),
+            };
+        }
+        if seq.next_element::<u8>()?.is_some() {
+            return Err(Error::invalid_length(33, &self));
+        }
+        Ok(SigningPubkey(output))
+    }
+}
+
+impl<'de> Deserialize<'de> for SigningPubkey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(SigningPubkeyVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signing_pubkey() {
+        let (pubkey, secretkey) = sign::gen_keypair();
+        let key = SigningPubkey::from(pubkey);
+        let sign::Signature(signature) = sign::sign_detached(b"profile", &secretkey);
+        assert!(key.verify(b"profile", &signature));
+        assert!(!key.verify(b"profile2", &signature));
+        assert!(!key.verify(b"profile", &signature[..10]));
+
+        let string = serde_json::to_string(&key).unwrap();
+        assert_eq!(string.len(), 46);
+        assert_eq!(serde_json::from_str::<SigningPubkey>(&string).unwrap(), key);
+        // settings from before keys were base64 have the bytes
+        let bytes = serde_json::to_string(&pubkey.0).unwrap();
+        assert_eq!(serde_json::from_str::<SigningPubkey>(&bytes).unwrap(), key);
+        assert!(serde_json::from_str::<SigningPubkey>("[1, 2, 3]").is_err());
+    }
+}
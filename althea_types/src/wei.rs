@@ -0,0 +1,159 @@
+//! Amounts of wei for billing math. Prices are wei per byte and byte counts are u64, so the
+//! product of the two always fits, but sums of them and conversions into the narrower types used
+//! for display and usage tracking may not. `Wei` is signed like a debt and only offers checked and
+//! saturating operations, so callers always decide what an overflow means instead of panicking or
+//! wrapping around silently.
+
+use num256::{Int256, Uint256};
+use num_traits::{Bounded, CheckedAdd, CheckedMul, CheckedSub, ToPrimitive, Zero};
+use std::fmt;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(transparent)]
+pub struct Wei(Int256);
+
+impl Wei {
+    pub fn zero() -> Wei {
+        Wei(Int256::zero())
+    }
+
+    /// What `bytes` cost at `price` wei per byte, this can't overflow
+    pub fn from_price(price: u64, bytes: u64) -> Wei {
+        let product = Uint256::from(u128::from(price) * u128::from(bytes));
+        // anything under 2^128 fits in a signed 256 bit int
+        Wei(product.to_int256().unwrap())
+    }
+
+    /// None for amounts too large for a signed amount
+    pub fn from_uint256(amount: &Uint256) -> Option<Wei> {
+        amount.to_int256().map(Wei)
+    }
+
+    pub fn checked_add(&self, other: &Wei) -> Option<Wei> {
+        self.0.checked_add(&other.0).map(Wei)
+    }
+
+    pub fn checked_sub(&self, other: &Wei) -> Option<Wei> {
+        self.0.checked_sub(&other.0).map(Wei)
+    }
+
+    pub fn checked_mul(&self, other: &Wei) -> Option<Wei> {
+        self.0.checked_mul(&other.0).map(Wei)
+    }
+
+    pub fn saturating_add(&self, other: &Wei) -> Wei {
+        self.checked_add(other)
+            .unwrap_or_else(|| self.limit(other.is_negative()))
+    }
+
+    pub fn saturating_sub(&self, other: &Wei) -> Wei {
+        self.checked_sub(other)
+            .unwrap_or_else(|| self.limit(!other.is_negative()))
+    }
+
+    pub fn saturating_mul(&self, other: &Wei) -> Wei {
+        self.checked_mul(other)
+            .unwrap_or_else(|| self.limit(self.is_negative() != other.is_negative()))
+    }
+
+    fn limit(&self, negative: bool) -> Wei {
+        if negative {
+            Wei(Int256::min_value())
+        } else {
+            Wei(Int256::max_value())
+        }
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.0 < Int256::zero()
+    }
+
+    /// None for negative amounts
+    pub fn to_uint256(&self) -> Option<Uint256> {
+        self.0.to_uint256()
+    }
+
+    /// None if the amount is negative or doesn't fit
+    pub fn to_u64(&self) -> Option<u64> {
+        self.0.to_u64()
+    }
+
+    /// None if the amount is negative or doesn't fit
+    pub fn to_u32(&self) -> Option<u32> {
+        self.0.to_u32()
+    }
+
+    /// None if the amount doesn't fit
+    pub fn to_i128(&self) -> Option<i128> {
+        self.0.to_i128()
+    }
+
+    pub fn into_int256(self) -> Int256 {
+        self.0
+    }
+}
+
+impl From<Int256> for Wei {
+    fn from(amount: Int256) -> Wei {
+        Wei(amount)
+    }
+}
+
+impl From<u64> for Wei {
+    fn from(amount: u64) -> Wei {
+        Wei::from_price(amount, 1)
+    }
+}
+
+impl From<i128> for Wei {
+    fn from(amount: i128) -> Wei {
+        Wei(Int256::from(amount))
+    }
+}
+
+impl From<Wei> for Int256 {
+    fn from(amount: Wei) -> Int256 {
+        amount.0
+    }
+}
+
+impl fmt::Display for Wei {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wei_math() {
+        let price = Wei::from_price(u64::max_value(), u64::max_value());
+        assert_eq!(price.to_u64(), None);
+        assert!(price.to_i128().is_none());
+        assert_eq!(Wei::from_price(10, 5).to_u32(), Some(50));
+
+        let max = Wei(Int256::max_value());
+        let min = Wei(Int256::min_value());
+        let one = Wei::from(1u64);
+        let minus_one = Wei::from(-1i128);
+        assert_eq!(max.checked_add(&one), None);
+        assert_eq!(max.saturating_add(&one), max);
+        assert_eq!(min.saturating_sub(&one), min);
+        assert_eq!(min.saturating_add(&minus_one), min);
+        assert_eq!(max.saturating_sub(&minus_one), max);
+        assert_eq!(min.saturating_mul(&minus_one), max);
+        assert_eq!(one.saturating_sub(&Wei::from(2u64)), minus_one);
+        assert_eq!(minus_one.to_u64(), None);
+        assert_eq!(minus_one.to_uint256(), None);
+        assert_eq!(minus_one.to_i128(), Some(-1));
+
+        let uint_max: Uint256 = Uint256::max_value();
+        assert_eq!(Wei::from_uint256(&uint_max), None);
+        assert_eq!(
+            serde_json::to_string(&Wei::from(-5i128)).unwrap(),
+            serde_json::to_string(&Int256::from(-5i128)).unwrap()
+        );
+    }
+}
@@ -327,11 +327,60 @@ pub fn set_metric_factor(
     })
 }
 
-pub fn monitor(stream: TcpStream, iface: &str) -> impl Future<Item = TcpStream, Error = Error> {
-    let command = &format!(
-        "interface {} max-rtt-penalty 500 enable-timestamps true",
-        iface
-    );
+/// Babel's per interface tuning knobs that we manage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterfaceParams {
+    /// how often hellos are sent in milliseconds
+    pub hello_interval: u32,
+    /// the most that is added to an interface's cost when its rtt is high
+    pub max_rtt_penalty: u16,
+    /// the rtt in milliseconds below which no penalty is added
+    pub rtt_min: u16,
+    /// the rtt in milliseconds at which the full penalty is added
+    pub rtt_max: u16,
+}
+
+impl InterfaceParams {
+    /// As babeld interface options, babeld takes the hello interval in seconds
+    fn options(&self) -> String {
+        format!(
+            "hello-interval {}.{:03} max-rtt-penalty {} rtt-min {} rtt-max {} enable-timestamps true",
+            self.hello_interval / 1000,
+            self.hello_interval % 1000,
+            self.max_rtt_penalty,
+            self.rtt_min,
+            self.rtt_max
+        )
+    }
+}
+
+/// Sets the options interfaces we monitor from now on start out with
+pub fn set_interface_defaults(
+    stream: TcpStream,
+    params: InterfaceParams,
+) -> impl Future<Item = TcpStream, Error = Error> {
+    run_command(stream, &format!("default {}", params.options())).then(|result| {
+        if let Err(e) = result {
+            return Err(e);
+        }
+        let (stream, _out) = result.unwrap();
+        Ok(stream)
+    })
+}
+
+/// Starts monitoring an interface, or updates the options of an interface that's already being
+/// monitored. The rxcost is the base cost babel advertises for the interface, None leaves it at
+/// the default or whatever was set last.
+pub fn monitor(
+    stream: TcpStream,
+    iface: &str,
+    rxcost: Option<u16>,
+    params: InterfaceParams,
+) -> impl Future<Item = TcpStream, Error = Error> {
+    let command = &match rxcost {
+        Some(cost) => format!("interface {} {} rxcost {}", iface, params.options(), cost),
+        None => format!("interface {} {}", iface, params.options()),
+    };
     let iface = iface.to_string();
     run_command(stream, &command).then(move |result| {
         if let Err(e) = result {
@@ -716,4 +765,18 @@ ok\n";
     fn only_ok_in_output() {
         read_babel_sync("ok\n").unwrap();
     }
+
+    #[test]
+    fn interface_options() {
+        let params = InterfaceParams {
+            hello_interval: 4500,
+            max_rtt_penalty: 500,
+            rtt_min: 10,
+            rtt_max: 120,
+        };
+        assert_eq!(
+            params.options(),
+            "hello-interval 4.500 max-rtt-penalty 500 rtt-min 10 rtt-max 120 enable-timestamps true"
+        );
+    }
 }
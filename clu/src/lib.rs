@@ -17,6 +17,7 @@ use rand::{thread_rng, Rng};
 use regex::Regex;
 use settings;
 use settings::exit::RitaExitSettings;
+use settings::payment::PaymentSettings;
 use settings::RitaCommonSettings;
 use std::fs::File;
 use std::io::Read;
@@ -73,6 +74,43 @@ pub fn cleanup() -> Result<(), Error> {
     Ok(())
 }
 
+/// Sets our eth address from the external signer or the local key, generating a local key if
+/// neither is configured. With an external signer no key is generated, the whole point is that
+/// it isn't stored on the router
+fn init_eth_key(payment_settings: &mut PaymentSettings) -> Result<(), Error> {
+    if let Some(signer) = payment_settings.external_signer.clone() {
+        info!(
+            "Starting with Eth address {:?} held by the external signer at {}",
+            signer.address, signer.url
+        );
+        payment_settings.eth_address = Some(signer.address);
+        return Ok(());
+    }
+
+    let eth_private_key_option = payment_settings.eth_private_key.clone();
+
+    match eth_private_key_option {
+        Some(existing_eth_private_key) => {
+            info!(
+                "Starting with Eth address {:?}",
+                existing_eth_private_key.to_public_key()?
+            );
+
+            payment_settings.eth_address = Some(existing_eth_private_key.to_public_key()?);
+        }
+        None => {
+            info!("Eth key details not configured, generating");
+            let key_buf: [u8; 32] = rand::random();
+            let new_private_key = PrivateKey::from_slice(&key_buf)?;
+            payment_settings.eth_private_key = Some(new_private_key);
+
+            payment_settings.eth_address = Some(new_private_key.to_public_key()?)
+        }
+    }
+
+    Ok(())
+}
+
 fn linux_init(config: Arc<RwLock<settings::client::RitaSettingsStruct>>) -> Result<(), Error> {
     cleanup()?;
     KI.restore_default_route(&mut config.get_network_mut().default_route)?;
@@ -171,27 +209,7 @@ fn linux_init(config: Arc<RwLock<settings::client::RitaSettingsStruct>>) -> Resu
     // Yield the mut lock
     drop(network_settings);
 
-    let mut payment_settings = config.get_payment_mut();
-    let eth_private_key_option = payment_settings.eth_private_key.clone();
-
-    match eth_private_key_option {
-        Some(existing_eth_private_key) => {
-            info!(
-                "Starting with Eth address {:?}",
-                existing_eth_private_key.to_public_key()?
-            );
-
-            payment_settings.eth_address = Some(existing_eth_private_key.to_public_key()?);
-        }
-        None => {
-            info!("Eth key details not configured, generating");
-            let key_buf: [u8; 32] = rand::random();
-            let new_private_key = PrivateKey::from_slice(&key_buf)?;
-            payment_settings.eth_private_key = Some(new_private_key);
-
-            payment_settings.eth_address = Some(new_private_key.to_public_key()?)
-        }
-    }
+    init_eth_key(&mut config.get_payment_mut())?;
 
     Ok(())
 }
@@ -255,27 +273,7 @@ fn linux_exit_init(
 
     drop(network_settings);
 
-    let mut payment_settings = config.get_payment_mut();
-    let eth_private_key_option = payment_settings.eth_private_key.clone();
-
-    match eth_private_key_option {
-        Some(existing_eth_private_key) => {
-            info!(
-                "Starting with Eth address {:?}",
-                existing_eth_private_key.to_public_key()?
-            );
-
-            payment_settings.eth_address = Some(existing_eth_private_key.to_public_key()?);
-        }
-        None => {
-            info!("Eth key details not configured, generating");
-            let key_buf: [u8; 32] = rand::random();
-            let new_private_key = PrivateKey::from_slice(&key_buf)?;
-            payment_settings.eth_private_key = Some(new_private_key);
-
-            payment_settings.eth_address = Some(new_private_key.to_public_key()?)
-        }
-    }
+    init_eth_key(&mut config.get_payment_mut())?;
 
     Ok(())
 }
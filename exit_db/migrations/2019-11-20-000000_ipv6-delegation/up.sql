@@ -0,0 +1 @@
+ALTER TABLE clients ADD COLUMN internet_ipv6 varchar(44) DEFAULT '' NOT NULL;
\ No newline at end of file
@@ -0,0 +1 @@
+ALTER TABLE clients ADD COLUMN nat_port_range varchar(32) DEFAULT '' NOT NULL;
\ No newline at end of file
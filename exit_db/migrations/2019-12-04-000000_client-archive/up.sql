@@ -0,0 +1 @@
+ALTER TABLE clients ADD COLUMN archived_time bigint DEFAULT 0 NOT NULL;
\ No newline at end of file
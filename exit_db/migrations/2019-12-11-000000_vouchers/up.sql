@@ -0,0 +1,9 @@
+-- prepaid vouchers sold by the community, created by the operator and redeemed by clients
+-- on signup. amount is in wei as a decimal string
+CREATE TABLE vouchers
+(
+    code varchar(64) PRIMARY KEY,
+    amount varchar(80) NOT NULL,
+    redeemed_by varchar(44) DEFAULT '' NOT NULL,
+    redeemed_time bigint DEFAULT 0 NOT NULL
+);
\ No newline at end of file
@@ -0,0 +1,14 @@
+-- hourly rollups of each client's usage, written by the exit's traffic watcher. hour is the
+-- start of the hour in seconds since the unix epoch, upload and download are in bytes from the
+-- client's point of view
+CREATE TABLE usage_records
+(
+    mesh_ip varchar(40) NOT NULL,
+    hour bigint NOT NULL,
+    wg_pubkey varchar(44) NOT NULL,
+    upload bigint DEFAULT 0 NOT NULL,
+    download bigint DEFAULT 0 NOT NULL,
+    PRIMARY KEY (mesh_ip, hour)
+);
+-- for range queries across all clients and pruning
+CREATE INDEX usage_records_hour ON usage_records (hour);
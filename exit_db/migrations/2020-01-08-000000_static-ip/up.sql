@@ -0,0 +1 @@
+ALTER TABLE clients ADD COLUMN public_ipv4 varchar(32) DEFAULT '' NOT NULL;
\ No newline at end of file
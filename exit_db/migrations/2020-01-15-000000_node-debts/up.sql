@@ -0,0 +1,11 @@
+-- the debts each exit instance in a cluster has with each client, summed to answer clients
+-- consistently whichever instance they reach. debt is a decimal Int256 from the exit's point of
+-- view, updated is when the instance last wrote it in seconds since the unix epoch
+CREATE TABLE node_debts
+(
+    node_id varchar NOT NULL,
+    mesh_ip varchar(40) NOT NULL,
+    debt varchar NOT NULL,
+    updated bigint NOT NULL,
+    PRIMARY KEY (node_id, mesh_ip)
+);
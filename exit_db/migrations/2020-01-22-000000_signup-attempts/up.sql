@@ -0,0 +1,9 @@
+-- signup attempts from new clients counted per fixed window, key is ip/<gateway address> or
+-- id/<wireguard key>, window_start is in seconds since the unix epoch
+CREATE TABLE signup_attempts
+(
+    key varchar NOT NULL,
+    window_start bigint NOT NULL,
+    attempts bigint NOT NULL,
+    PRIMARY KEY (key, window_start)
+);
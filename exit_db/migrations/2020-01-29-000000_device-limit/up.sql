@@ -0,0 +1 @@
+ALTER TABLE clients ADD COLUMN device_limit integer DEFAULT 0 NOT NULL;
\ No newline at end of file
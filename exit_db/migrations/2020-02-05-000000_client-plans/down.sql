@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE clients DROP COLUMN plan_name;
+ALTER TABLE clients DROP COLUMN rate_limit;
+ALTER TABLE clients DROP COLUMN monthly_quota;
\ No newline at end of file
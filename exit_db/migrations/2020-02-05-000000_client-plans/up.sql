@@ -0,0 +1,3 @@
+ALTER TABLE clients ADD COLUMN plan_name varchar(64) DEFAULT '' NOT NULL;
+ALTER TABLE clients ADD COLUMN rate_limit integer DEFAULT 0 NOT NULL;
+ALTER TABLE clients ADD COLUMN monthly_quota bigint DEFAULT 0 NOT NULL;
\ No newline at end of file
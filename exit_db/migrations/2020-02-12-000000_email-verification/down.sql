@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE clients DROP COLUMN email_code_time;
+ALTER TABLE clients DROP COLUMN email_attempts;
\ No newline at end of file
@@ -0,0 +1,2 @@
+ALTER TABLE clients ADD COLUMN email_code_time bigint DEFAULT 0 NOT NULL;
+ALTER TABLE clients ADD COLUMN email_attempts integer DEFAULT 0 NOT NULL;
\ No newline at end of file
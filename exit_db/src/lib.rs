@@ -1,7 +1,10 @@
 #[macro_use]
 extern crate diesel;
 #[macro_use]
+extern crate failure;
+#[macro_use]
 extern crate serde_derive;
 
+pub mod migrations;
 pub mod models;
 pub mod schema;
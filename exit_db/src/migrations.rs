@@ -0,0 +1,144 @@
+//! The migrations in exit_db/migrations, built into the binary so that an exit brings its database
+//! up to date on its own when it starts instead of operators running the diesel cli on every
+//! upgrade. What's been run is tracked in the same table the diesel cli uses, so a database that
+//! was migrated by hand carries on from where it is. New migrations have to be added to
+//! `MIGRATIONS` as well as to the directory.
+
+use diesel::connection::SimpleConnection;
+use diesel::prelude::{Connection, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use failure::Error;
+use std::collections::HashSet;
+
+/// Postgres advisory lock held while migrating, so that exit instances sharing a database and
+/// starting together don't both migrate it
+const MIGRATION_LOCK: i64 = 0x616c_7468_6561;
+
+const CREATE_MIGRATIONS_TABLE: &str = "CREATE TABLE IF NOT EXISTS __diesel_schema_migrations (
+    version VARCHAR(50) PRIMARY KEY NOT NULL,
+    run_on TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);";
+
+table! {
+    __diesel_schema_migrations (version) {
+        version -> VarChar,
+        run_on -> Timestamp,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Migration {
+    /// the migration's directory, like 2019-02-22-214628_rita-setup
+    pub name: &'static str,
+    up: &'static str,
+}
+
+impl Migration {
+    /// The version diesel records for this migration, the digits before the first underscore
+    pub fn version(&self) -> String {
+        self.name
+            .split('_')
+            .next()
+            .unwrap_or_default()
+            .replace('-', "")
+    }
+}
+
+macro_rules! migration {
+    ($name:literal) => {
+        Migration {
+            name: $name,
+            up: include_str!(concat!("../migrations/", $name, "/up.sql")),
+        }
+    };
+}
+
+/// Every migration, oldest first
+pub const MIGRATIONS: &[Migration] = &[
+    migration!("00000000000000_diesel_initial_setup"),
+    migration!("2019-02-22-214628_rita-setup"),
+    migration!("2019-11-20-000000_ipv6-delegation"),
+    migration!("2019-11-27-000000_nat-port-range"),
+    migration!("2019-12-04-000000_client-archive"),
+    migration!("2019-12-11-000000_vouchers"),
+    migration!("2019-12-18-000000_usage-records"),
+    migration!("2020-01-08-000000_static-ip"),
+    migration!("2020-01-15-000000_node-debts"),
+    migration!("2020-01-22-000000_signup-attempts"),
+    migration!("2020-01-29-000000_device-limit"),
+    migration!("2020-02-05-000000_client-plans"),
+    migration!("2020-02-12-000000_email-verification"),
+];
+
+fn applied_versions(conn: &PgConnection) -> Result<HashSet<String>, Error> {
+    use self::__diesel_schema_migrations::dsl::{__diesel_schema_migrations, version};
+    conn.batch_execute(CREATE_MIGRATIONS_TABLE)?;
+    Ok(__diesel_schema_migrations
+        .select(version)
+        .load::<String>(conn)?
+        .into_iter()
+        .collect())
+}
+
+/// The migrations that haven't been run on this database, oldest first
+pub fn pending_migrations(conn: &PgConnection) -> Result<Vec<Migration>, Error> {
+    let applied = applied_versions(conn)?;
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version()))
+        .cloned()
+        .collect())
+}
+
+fn run_migrations_locked(conn: &PgConnection) -> Result<Vec<Migration>, Error> {
+    use self::__diesel_schema_migrations::dsl::{__diesel_schema_migrations, version};
+    // checked again now that we hold the lock, another instance may have just migrated
+    let pending = pending_migrations(conn)?;
+    for migration in pending.iter() {
+        conn.transaction::<_, Error, _>(|| {
+            conn.batch_execute(migration.up)?;
+            diesel::insert_into(__diesel_schema_migrations)
+                .values(version.eq(migration.version()))
+                .execute(conn)?;
+            Ok(())
+        })
+        .map_err(|e| format_err!("Migration {} failed with {}", migration.name, e))?;
+    }
+    Ok(pending)
+}
+
+/// Runs every pending migration, each in a transaction of its own so that a failed one leaves
+/// the database as the one before it left it, returns the migrations that were run
+pub fn run_pending_migrations(conn: &PgConnection) -> Result<Vec<Migration>, Error> {
+    conn.batch_execute(&format!("SELECT pg_advisory_lock({});", MIGRATION_LOCK))?;
+    let res = run_migrations_locked(conn);
+    let unlock = conn.batch_execute(&format!("SELECT pg_advisory_unlock({});", MIGRATION_LOCK));
+    let ran = res?;
+    unlock?;
+    Ok(ran)
+}
+
+#[test]
+fn test_migrations() {
+    use std::fs::read_dir;
+    use std::path::Path;
+
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations");
+    let mut on_disk: Vec<String> = read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    on_disk.sort();
+    let embedded: Vec<String> = MIGRATIONS
+        .iter()
+        .map(|migration| migration.name.to_string())
+        .collect();
+    // a migration that's only in the directory would never be run
+    assert_eq!(embedded, on_disk);
+
+    let versions: Vec<String> = MIGRATIONS.iter().map(Migration::version).collect();
+    assert_eq!(versions[0], "00000000000000");
+    assert_eq!(versions[1], "20190222214628");
+    for pair in versions.windows(2) {
+        assert!(pair[0] < pair[1]);
+    }
+}
@@ -1,4 +1,8 @@
 use crate::schema::clients;
+use crate::schema::node_debts;
+use crate::schema::signup_attempts;
+use crate::schema::usage_records;
+use crate::schema::vouchers;
 
 #[derive(Queryable, Serialize, Deserialize, Debug, Insertable, Clone, AsChangeset, Default)]
 #[table_name = "clients"]
@@ -18,4 +22,75 @@ pub struct Client {
     pub text_sent: i32,
     pub last_seen: i64,
     pub last_balance_warning_time: i64,
+    pub internet_ipv6: String,
+    pub nat_port_range: String,
+    /// when the client was archived for inactivity, 0 if it's not
+    pub archived_time: i64,
+    /// the public ipv4 address from the static pool this client is translated to one to one,
+    /// empty if it doesn't have one
+    #[serde(default)]
+    pub public_ipv4: String,
+    /// how many devices this client may have online, 0 for the exit's default
+    #[serde(default)]
+    pub device_limit: i32,
+    /// the name of the plan the client is on, empty if it's not on one
+    #[serde(default)]
+    pub plan_name: String,
+    /// in kbit/s, 0 for no limit
+    #[serde(default)]
+    pub rate_limit: i32,
+    /// bytes per calendar month, 0 for no quota
+    #[serde(default)]
+    pub monthly_quota: i64,
+    /// when the current email code was made, 0 for codes from before codes expired
+    #[serde(default)]
+    pub email_code_time: i64,
+    /// wrong email codes submitted against the current code
+    #[serde(default)]
+    pub email_attempts: i32,
+}
+
+#[derive(Queryable, Serialize, Deserialize, Debug, Insertable, Clone, AsChangeset, Default)]
+#[table_name = "vouchers"]
+pub struct Voucher {
+    pub code: String,
+    /// in wei as a decimal string
+    pub amount: String,
+    /// the wg key of the client that redeemed it, empty if it's still unused
+    pub redeemed_by: String,
+    pub redeemed_time: i64,
+}
+
+#[derive(Queryable, Serialize, Deserialize, Debug, Insertable, Clone, PartialEq, Eq)]
+#[table_name = "usage_records"]
+pub struct UsageRecord {
+    pub mesh_ip: String,
+    /// start of the hour in seconds since the unix epoch
+    pub hour: i64,
+    pub wg_pubkey: String,
+    /// bytes from the client's point of view
+    pub upload: i64,
+    pub download: i64,
+}
+
+#[derive(Queryable, Serialize, Deserialize, Debug, Insertable, Clone, PartialEq, Eq)]
+#[table_name = "node_debts"]
+pub struct NodeDebt {
+    /// the exit instance that wrote the debt
+    pub node_id: String,
+    pub mesh_ip: String,
+    /// in wei as a decimal string, negative when the client owes us
+    pub debt: String,
+    /// seconds since the unix epoch
+    pub updated: i64,
+}
+
+#[derive(Queryable, Serialize, Deserialize, Debug, Insertable, Clone, PartialEq, Eq)]
+#[table_name = "signup_attempts"]
+pub struct SignupAttempt {
+    /// `ip/` and the gateway address or `id/` and the wireguard key of whoever made them
+    pub key: String,
+    /// seconds since the unix epoch
+    pub window_start: i64,
+    pub attempts: i64,
 }
@@ -15,5 +15,51 @@ table! {
         text_sent -> Int4,
         last_seen -> Int8,
         last_balance_warning_time -> Int8,
+        internet_ipv6 -> Varchar,
+        nat_port_range -> Varchar,
+        archived_time -> Int8,
+        public_ipv4 -> Varchar,
+        device_limit -> Int4,
+        plan_name -> Varchar,
+        rate_limit -> Int4,
+        monthly_quota -> Int8,
+        email_code_time -> Int8,
+        email_attempts -> Int4,
+    }
+}
+
+table! {
+    node_debts (node_id, mesh_ip) {
+        node_id -> Varchar,
+        mesh_ip -> Varchar,
+        debt -> Varchar,
+        updated -> Int8,
+    }
+}
+
+table! {
+    signup_attempts (key, window_start) {
+        key -> Varchar,
+        window_start -> Int8,
+        attempts -> Int8,
+    }
+}
+
+table! {
+    usage_records (mesh_ip, hour) {
+        mesh_ip -> Varchar,
+        hour -> Int8,
+        wg_pubkey -> Varchar,
+        upload -> Int8,
+        download -> Int8,
+    }
+}
+
+table! {
+    vouchers (code) {
+        code -> Varchar,
+        amount -> Varchar,
+        redeemed_by -> Varchar,
+        redeemed_time -> Int8,
     }
 }
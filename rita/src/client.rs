@@ -53,36 +53,62 @@ mod rita_common;
 use crate::rita_client::enable_remote_logging;
 use crate::rita_client::rita_loop::check_rita_client_actors;
 use crate::rita_client::rita_loop::start_rita_client_endpoints;
+use crate::rita_client::rita_loop::start_rita_client_watchdog;
+use crate::rita_common::control_socket::start_control_socket;
 use crate::rita_common::rita_loop::check_rita_common_actors;
 use crate::rita_common::rita_loop::start_core_rita_endpoints;
 
+use crate::rita_client::dashboard::announcements::*;
+use crate::rita_client::dashboard::backup::*;
 use crate::rita_client::dashboard::backup_created::*;
+use crate::rita_client::dashboard::captive_portal::*;
 use crate::rita_client::dashboard::eth_private_key::*;
 use crate::rita_client::dashboard::exits::*;
 use crate::rita_client::dashboard::interfaces::*;
+use crate::rita_client::dashboard::ipv6_only::*;
 use crate::rita_client::dashboard::localization::*;
 use crate::rita_client::dashboard::logging::*;
 use crate::rita_client::dashboard::mesh_ip::*;
+use crate::rita_client::dashboard::mesh_map::*;
 use crate::rita_client::dashboard::neighbors::*;
 use crate::rita_client::dashboard::notifications::*;
 use crate::rita_client::dashboard::prices::*;
+use crate::rita_client::dashboard::protective_mode::*;
 use crate::rita_client::dashboard::release_feed::*;
 use crate::rita_client::dashboard::remote_access::*;
+use crate::rita_client::dashboard::remote_management::*;
 use crate::rita_client::dashboard::router::*;
+use crate::rita_client::dashboard::split_tunnel::*;
 use crate::rita_client::dashboard::system_chain::*;
+use crate::rita_client::dashboard::topup::*;
 use crate::rita_client::dashboard::usage::*;
+use crate::rita_client::dashboard::wan::*;
 use crate::rita_client::dashboard::wifi::*;
 use crate::rita_common::dashboard::auth::*;
 use crate::rita_common::dashboard::babel::*;
+use crate::rita_common::dashboard::billing_audit::*;
+use crate::rita_common::dashboard::channels::*;
 use crate::rita_common::dashboard::dao::*;
 use crate::rita_common::dashboard::debts::*;
 use crate::rita_common::dashboard::development::*;
+use crate::rita_common::dashboard::diagnostics::*;
+use crate::rita_common::dashboard::live::*;
 use crate::rita_common::dashboard::nickname::*;
 use crate::rita_common::dashboard::own_info::*;
+use crate::rita_common::dashboard::path_trace::*;
+use crate::rita_common::dashboard::price_simulation::*;
+use crate::rita_common::dashboard::schedule::*;
+use crate::rita_common::dashboard::security::*;
+use crate::rita_common::dashboard::services::*;
 use crate::rita_common::dashboard::settings::*;
+use crate::rita_common::dashboard::sla::*;
+use crate::rita_common::dashboard::system_health::*;
 use crate::rita_common::dashboard::token_bridge::*;
+use crate::rita_common::dashboard::traffic_alerts::*;
+use crate::rita_common::dashboard::tunnels::*;
 use crate::rita_common::dashboard::usage::*;
 use crate::rita_common::dashboard::wallet::*;
+use crate::rita_common::dashboard::watchdog::*;
 use crate::rita_common::dashboard::wg_key::*;
 use crate::rita_common::network_endpoints::*;
 
@@ -224,6 +250,16 @@ fn main() {
         error!("Failed to setup cron!");
     }
 
+    // the guest bandwidth limit is a qdisc, which doesn't survive a reboot
+    if KI.is_openwrt() {
+        if let Err(e) = KI.restore_guest_bandwidth_limit() {
+            warn!(
+                "Failed to restore the guest network bandwidth limit {:?}",
+                e
+            );
+        }
+    }
+
     let args: Args = Docopt::new((*USAGE).as_str())
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
@@ -245,6 +281,7 @@ fn main() {
 
     check_rita_common_actors();
     check_rita_client_actors();
+    start_rita_client_watchdog();
     start_core_rita_endpoints(2);
     start_rita_client_endpoints(1);
     start_client_dashboard();
@@ -256,138 +293,12 @@ fn main() {
 fn start_client_dashboard() {
     // dashboard
     server::new(|| {
-        App::new()
-            .middleware(middleware::Headers)
-            .middleware(middleware::Auth)
-            .route("/backup_created", Method::GET, get_backup_created)
-            .route("/backup_created/{status}", Method::POST, set_backup_created)
-            .route("/dao_list", Method::GET, get_dao_list)
-            .route("/dao_list/add/{address}", Method::POST, add_to_dao_list)
-            .route(
-                "/dao_list/remove/{address}",
-                Method::POST,
-                remove_from_dao_list,
-            )
-            .route("/debts", Method::GET, get_debts)
-            .route("/debts/reset", Method::POST, reset_debt)
-            .route("/exits/sync", Method::POST, exits_sync)
-            .route("/exits", Method::GET, get_exit_info)
-            .route("/exits", Method::POST, add_exits)
-            .route("/exits/{name}/register", Method::POST, register_to_exit)
-            .route("/exits/{name}/reset", Method::POST, reset_exit)
-            .route("/exits/{name}/select", Method::POST, select_exit)
-            .route("/local_fee", Method::GET, get_local_fee)
-            .route("/local_fee/{fee}", Method::POST, set_local_fee)
-            .route("/dao_fee", Method::GET, get_dao_fee)
-            .route("/dao_fee/{fee}", Method::POST, set_dao_fee)
-            .route("/metric_factor", Method::GET, get_metric_factor)
-            .route("/metric_factor/{factor}", Method::POST, set_metric_factor)
-            .route(
-                "/exits/{name}/verify/{code}",
-                Method::POST,
-                verify_on_exit_with_code,
-            )
-            .route("/info", Method::GET, get_own_info)
-            .route("/interfaces", Method::GET, get_interfaces_endpoint)
-            .route("/interfaces", Method::POST, set_interfaces_endpoint)
-            .route("/interfaces/mesh", Method::GET, wlan_mesh_get)
-            .route("/interfaces/lightclient", Method::GET, wlan_lightclient_get)
-            .route("/interfaces/mesh/{enabled}", Method::POST, wlan_mesh_set)
-            .route(
-                "/interfaces/lightclient/{enabled}",
-                Method::POST,
-                wlan_lightclient_set,
-            )
-            .route("/eth_private_key", Method::GET, get_eth_private_key)
-            .route("/eth_private_key", Method::POST, set_eth_private_key)
-            .route("/mesh_ip", Method::GET, get_mesh_ip)
-            .route("/mesh_ip", Method::POST, set_mesh_ip)
-            .route("/neighbors", Method::GET, get_neighbor_info)
-            .route("/routes", Method::GET, get_routes)
-            .route("/remote_logging/enabled", Method::GET, get_remote_logging)
-            .route(
-                "/remote_logging/enabled/{enabled}",
-                Method::POST,
-                remote_logging,
-            )
-            .route(
-                "/remote_logging/level",
-                Method::GET,
-                get_remote_logging_level,
-            )
-            .route(
-                "/remote_logging/level/{level}",
-                Method::POST,
-                remote_logging_level,
-            )
-            .route("/settings", Method::GET, get_settings)
-            .route("/settings", Method::POST, set_settings)
-            .route("/version", Method::GET, version)
-            .route("/wg_public_key", Method::GET, get_wg_public_key)
-            .route("/wifi_settings", Method::POST, set_wifi_multi)
-            .route("/wifi_settings/pass", Method::POST, set_wifi_pass)
-            .route("/wifi_settings/ssid", Method::POST, set_wifi_ssid)
-            .route("/wifi_settings/channel", Method::POST, set_wifi_channel)
-            .route(
-                "/wifi_settings/get_channels/{radio}",
-                Method::GET,
-                get_allowed_wifi_channels,
-            )
-            .route("/wifi_settings", Method::GET, get_wifi_config)
-            .route("/withdraw/{address}/{amount}", Method::POST, withdraw)
-            .route("/withdraw_all/{address}", Method::POST, withdraw_all)
-            .route(
-                "/withdraw_eth/{address}/{amount}",
-                Method::POST,
-                withdraw_eth,
-            )
-            .route(
-                "/auto_price/enabled/{status}",
-                Method::POST,
-                set_auto_pricing,
-            )
-            .route("/auto_price/enabled", Method::GET, auto_pricing_status)
-            .route("/prices", Method::GET, get_prices)
-            .route(
-                "/blockchain/set/{chain_id}",
-                Method::POST,
-                set_system_blockchain,
-            )
-            .route("/blockchain/get", Method::GET, get_system_blockchain)
-            .route("/nickname/get", Method::GET, get_nickname)
-            .route("/nickname/set", Method::POST, set_nickname)
-            .route(
-                "/low_balance_notification",
-                Method::GET,
-                get_low_balance_notification,
-            )
-            .route(
-                "/low_balance_notification/{status}",
-                Method::POST,
-                set_low_balance_notification,
-            )
-            .route("/usage/relay", Method::GET, get_relay_usage)
-            .route("/usage/client", Method::GET, get_client_usage)
-            .route("/usage/payments", Method::GET, get_payments)
-            .route("/token_bridge/status", Method::GET, get_bridge_status)
-            .route("/router/reboot", Method::POST, reboot_router)
-            .route("/router/update", Method::POST, update_router)
-            .route("/router/password", Method::POST, set_pass)
-            .route("/release_feed/get", Method::GET, get_release_feed_http)
-            .route(
-                "/release_feed/set/{feed}",
-                Method::POST,
-                set_release_feed_http,
-            )
-            .route("/remote_access", Method::GET, get_remote_access_status)
-            .route(
-                "/remote_access/{status}",
-                Method::POST,
-                set_remote_access_status,
-            )
-            .route("/wipe", Method::POST, wipe)
-            .route("/crash_actors", Method::POST, crash_actors)
-            .route("/localization", Method::GET, get_localization)
+        client_dashboard_routes(
+            App::new()
+                .middleware(middleware::ErrorResponses)
+                .middleware(middleware::Headers)
+                .middleware(middleware::Auth),
+        )
     })
     .workers(1)
     .bind(format!(
@@ -397,4 +308,266 @@ fn start_client_dashboard() {
     .unwrap()
     .shutdown_timeout(0)
     .start();
+
+    // the same api for rita-ctl and scripts on the router, the socket's permissions
+    // stand in for the dashboard password
+    start_control_socket(|| {
+        client_dashboard_routes(App::new().middleware(middleware::ErrorResponses))
+    });
+}
+
+/// Every dashboard endpoint, shared by the dashboard and the control socket
+fn client_dashboard_routes(app: App) -> App {
+    app.route("/announcements", Method::GET, get_announcements)
+        .route("/announcements/seen", Method::POST, set_announcements_seen)
+        .route("/backup", Method::POST, create_backup)
+        .route("/restore", Method::POST, restore_backup)
+        .route("/backup_created", Method::GET, get_backup_created)
+        .route("/backup_created/{status}", Method::POST, set_backup_created)
+        .route("/captive_portal", Method::GET, get_captive_portal)
+        .route(
+            "/captive_portal/enabled/{enabled}",
+            Method::POST,
+            set_captive_portal_enabled,
+        )
+        .route(
+            "/captive_portal/announcements/{enabled}",
+            Method::POST,
+            set_captive_portal_announcements,
+        )
+        .route(
+            "/captive_portal/text",
+            Method::POST,
+            set_captive_portal_text,
+        )
+        .route("/channels", Method::GET, get_payment_channels)
+        .route("/channels/open", Method::POST, open_payment_channel)
+        .route(
+            "/channels/close/{address}",
+            Method::POST,
+            close_payment_channel,
+        )
+        .route("/dao_list", Method::GET, get_dao_list)
+        .route("/dao_list/add/{address}", Method::POST, add_to_dao_list)
+        .route(
+            "/dao_list/remove/{address}",
+            Method::POST,
+            remove_from_dao_list,
+        )
+        .route("/debts", Method::GET, get_debts)
+        .route("/debts/reset", Method::POST, reset_debt)
+        .route("/debts/write_off", Method::POST, write_off_debt)
+        .route("/debts/history/{identity}", Method::GET, get_debt_history)
+        .route("/debts/divergence", Method::GET, get_counter_divergence)
+        .route("/debts/journal", Method::GET, get_debt_journal)
+        .route("/diagnostics", Method::GET, get_diagnostics)
+        .route(
+            "/debts/receipts/verify",
+            Method::POST,
+            verify_payment_receipt,
+        )
+        .route("/traffic_alerts", Method::GET, get_traffic_alerts)
+        .route("/billing/audit", Method::GET, get_billing_audit)
+        .route("/tunnels/{pubkey}/reset", Method::POST, reset_tunnel)
+        .route("/neighbors/paused", Method::GET, get_paused_neighbors)
+        .route("/neighbors/{pubkey}/pause", Method::POST, pause_neighbor)
+        .route("/neighbors/{pubkey}/resume", Method::POST, resume_neighbor)
+        .route("/path_trace/{dest_ip}", Method::GET, path_trace)
+        .route("/price_simulation", Method::GET, get_price_simulation)
+        .route("/watchdog", Method::GET, get_watchdog_status)
+        .route("/system_health", Method::GET, get_system_health_report)
+        .route("/security_status", Method::GET, get_security_status)
+        .route("/live", Method::GET, live_updates)
+        .route("/exits/sync", Method::POST, exits_sync)
+        .route("/exits", Method::GET, get_exit_info)
+        .route("/exits", Method::POST, add_exits)
+        .route("/exits/{name}/register", Method::POST, register_to_exit)
+        .route("/exits/{name}/reset", Method::POST, reset_exit)
+        .route("/exits/{name}/select", Method::POST, select_exit)
+        .route(
+            "/exits/{name}/accept_terms",
+            Method::POST,
+            accept_exit_terms,
+        )
+        .route("/exits/price_limit", Method::GET, get_exit_price_limit)
+        .route("/exits/price_limit", Method::POST, set_exit_price_limit)
+        .route("/exits/{name}", Method::DELETE, delete_exit)
+        .route("/exits/{name}", Method::PATCH, patch_exit)
+        .route("/babel/settings", Method::GET, get_babel_settings)
+        .route("/babel/settings", Method::POST, set_babel_settings)
+        .route("/local_fee", Method::GET, get_local_fee)
+        .route("/local_fee/{fee}", Method::POST, set_local_fee)
+        .route("/dao_fee", Method::GET, get_dao_fee)
+        .route("/dao_fee/{fee}", Method::POST, set_dao_fee)
+        .route("/metric_factor", Method::GET, get_metric_factor)
+        .route("/metric_factor/{factor}", Method::POST, set_metric_factor)
+        .route(
+            "/exits/{name}/verify/{code}",
+            Method::POST,
+            verify_on_exit_with_code,
+        )
+        .route(
+            "/exits/{name}/resend_email",
+            Method::POST,
+            resend_exit_email,
+        )
+        .route(
+            "/exits/{name}/passphrase",
+            Method::POST,
+            register_with_passphrase,
+        )
+        .route("/voucher/{code}", Method::POST, set_exit_voucher)
+        .route("/info", Method::GET, get_own_info)
+        .route("/interfaces", Method::GET, get_interfaces_endpoint)
+        .route("/interfaces", Method::POST, set_interfaces_endpoint)
+        .route(
+            "/interfaces/{iface}/mode",
+            Method::POST,
+            set_interface_mode_endpoint,
+        )
+        .route("/interfaces/mesh", Method::GET, wlan_mesh_get)
+        .route("/interfaces/lightclient", Method::GET, wlan_lightclient_get)
+        .route("/interfaces/mesh/{enabled}", Method::POST, wlan_mesh_set)
+        .route(
+            "/interfaces/lightclient/{enabled}",
+            Method::POST,
+            wlan_lightclient_set,
+        )
+        .route("/eth_private_key", Method::GET, get_eth_private_key)
+        .route("/eth_private_key", Method::POST, set_eth_private_key)
+        .route("/mesh_ip", Method::GET, get_mesh_ip)
+        .route("/mesh_ip", Method::POST, set_mesh_ip)
+        .route("/neighbors", Method::GET, get_neighbor_info)
+        .route("/routes", Method::GET, get_routes)
+        .route("/mesh_map", Method::GET, get_mesh_map)
+        .route("/remote_logging/enabled", Method::GET, get_remote_logging)
+        .route(
+            "/remote_logging/enabled/{enabled}",
+            Method::POST,
+            remote_logging,
+        )
+        .route(
+            "/remote_logging/level",
+            Method::GET,
+            get_remote_logging_level,
+        )
+        .route(
+            "/remote_logging/level/{level}",
+            Method::POST,
+            remote_logging_level,
+        )
+        .route("/settings", Method::GET, get_settings)
+        .route("/settings", Method::POST, set_settings)
+        .route("/settings/problems", Method::GET, get_settings_problems)
+        .route("/version", Method::GET, version)
+        .route("/wg_public_key", Method::GET, get_wg_public_key)
+        .route("/wifi_settings", Method::POST, set_wifi_multi)
+        .route("/wifi_settings/pass", Method::POST, set_wifi_pass)
+        .route("/wifi_settings/ssid", Method::POST, set_wifi_ssid)
+        .route("/wifi_settings/channel", Method::POST, set_wifi_channel)
+        .route("/wifi_settings/txpower", Method::GET, get_wifi_txpower)
+        .route("/wifi_settings/txpower", Method::POST, set_wifi_txpower)
+        .route("/wifi_settings/country", Method::GET, get_wifi_country)
+        .route("/wifi_settings/country", Method::POST, set_wifi_country)
+        .route("/wifi_settings/guest", Method::GET, get_guest_network)
+        .route("/wifi_settings/guest", Method::POST, set_guest_network)
+        .route(
+            "/wifi_settings/sponsored",
+            Method::GET,
+            get_sponsored_network,
+        )
+        .route(
+            "/wifi_settings/sponsored",
+            Method::POST,
+            set_sponsored_network,
+        )
+        .route(
+            "/wifi_settings/get_channels/{radio}",
+            Method::GET,
+            get_allowed_wifi_channels,
+        )
+        .route("/wifi_settings", Method::GET, get_wifi_config)
+        .route("/wifi_clients", Method::GET, get_wifi_clients)
+        .route("/withdraw/{address}/{amount}", Method::POST, withdraw)
+        .route("/withdraw_all/{address}", Method::POST, withdraw_all)
+        .route(
+            "/withdraw_eth/{address}/{amount}",
+            Method::POST,
+            withdraw_eth,
+        )
+        .route(
+            "/auto_price/enabled/{status}",
+            Method::POST,
+            set_auto_pricing,
+        )
+        .route("/auto_price/enabled", Method::GET, auto_pricing_status)
+        .route("/prices", Method::GET, get_prices)
+        .route("/prices/neighbors", Method::GET, get_neighbor_prices)
+        .route("/protective_mode", Method::GET, get_protective_mode)
+        .route("/protective_mode", Method::POST, set_protective_mode)
+        .route("/topup", Method::GET, get_topup_address)
+        .route("/topup/watch", Method::POST, start_topup_watch)
+        .route("/topup/status", Method::GET, get_topup_status)
+        .route("/split_tunnel", Method::GET, get_split_tunnel)
+        .route("/split_tunnel", Method::POST, set_split_tunnel)
+        .route("/ipv6_only", Method::GET, get_ipv6_only)
+        .route("/ipv6_only/{enabled}", Method::POST, set_ipv6_only)
+        .route(
+            "/blockchain/set/{chain_id}",
+            Method::POST,
+            set_system_blockchain,
+        )
+        .route("/blockchain/get", Method::GET, get_system_blockchain)
+        .route("/nickname/get", Method::GET, get_nickname)
+        .route("/nickname/set", Method::POST, set_nickname)
+        .route("/services", Method::GET, get_services)
+        .route("/services/local", Method::GET, get_local_services)
+        .route("/services/local", Method::POST, set_local_services)
+        .route(
+            "/services/enabled/{enabled}",
+            Method::POST,
+            set_service_discovery,
+        )
+        .route("/schedule", Method::GET, get_schedule)
+        .route("/schedule", Method::POST, set_schedule)
+        .route("/sla", Method::GET, get_sla)
+        .route(
+            "/low_balance_notification",
+            Method::GET,
+            get_low_balance_notification,
+        )
+        .route(
+            "/low_balance_notification/{status}",
+            Method::POST,
+            set_low_balance_notification,
+        )
+        .route("/usage/relay", Method::GET, get_relay_usage)
+        .route("/usage/client", Method::GET, get_client_usage)
+        .route("/usage/sponsored", Method::GET, get_sponsored_usage)
+        .route("/usage/payments", Method::GET, get_payments)
+        .route("/token_bridge/status", Method::GET, get_bridge_status)
+        .route("/router/reboot", Method::POST, reboot_router)
+        .route("/router/update", Method::POST, update_router)
+        .route("/router/password", Method::POST, set_pass)
+        .route("/release_feed/get", Method::GET, get_release_feed_http)
+        .route(
+            "/release_feed/set/{feed}",
+            Method::POST,
+            set_release_feed_http,
+        )
+        .route("/remote_access", Method::GET, get_remote_access_status)
+        .route(
+            "/remote_management/audit",
+            Method::GET,
+            get_remote_management_audit,
+        )
+        .route(
+            "/remote_access/{status}",
+            Method::POST,
+            set_remote_access_status,
+        )
+        .route("/wipe", Method::POST, wipe)
+        .route("/crash_actors", Method::POST, crash_actors)
+        .route("/localization", Method::GET, get_localization)
+        .route("/wan/status", Method::GET, get_wan_status)
 }
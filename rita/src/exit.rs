@@ -30,14 +30,12 @@ extern crate phonenumber;
 
 use actix_web::http::Method;
 use actix_web::{http, server, App};
+use althea_types::ExitLoad;
 use althea_types::SystemChain;
 use althea_types::WgKey;
-use diesel::r2d2::ConnectionManager;
-use diesel::PgConnection;
 use docopt::Docopt;
 use env_logger;
 use openssl_probe;
-use r2d2::Pool;
 use settings::exit::ExitNetworkSettings;
 use settings::exit::ExitVerifSettings;
 use std::collections::HashMap;
@@ -62,36 +60,65 @@ mod rita_exit;
 use rita_common::rita_loop::check_rita_common_actors;
 use rita_common::rita_loop::start_core_rita_endpoints;
 
+use rita_exit::dashboard::start_exit_admin_dashboard;
 use rita_exit::rita_loop::check_rita_exit_actors;
 use rita_exit::rita_loop::start_rita_exit_endpoints;
+use rita_exit::rita_loop::start_rita_exit_watchdog;
 
+use crate::rita_exit::database::store::{
+    check_migrations, export_to_file, import_from_file, run_migrations, StorePool,
+};
+
+use crate::rita_common::control_socket::start_control_socket;
 use crate::rita_common::dashboard::auth::*;
 use crate::rita_common::dashboard::babel::*;
+use crate::rita_common::dashboard::billing_audit::*;
+use crate::rita_common::dashboard::channels::*;
 use crate::rita_common::dashboard::dao::*;
 use crate::rita_common::dashboard::debts::*;
 use crate::rita_common::dashboard::development::*;
+use crate::rita_common::dashboard::diagnostics::*;
+use crate::rita_common::dashboard::live::*;
 use crate::rita_common::dashboard::nickname::*;
 use crate::rita_common::dashboard::own_info::*;
+use crate::rita_common::dashboard::path_trace::*;
+use crate::rita_common::dashboard::price_simulation::*;
+use crate::rita_common::dashboard::schedule::*;
+use crate::rita_common::dashboard::security::*;
+use crate::rita_common::dashboard::services::*;
 use crate::rita_common::dashboard::settings::*;
+use crate::rita_common::dashboard::sla::*;
 use crate::rita_common::dashboard::token_bridge::*;
+use crate::rita_common::dashboard::traffic_alerts::*;
+use crate::rita_common::dashboard::tunnels::*;
 use crate::rita_common::dashboard::usage::*;
 use crate::rita_common::dashboard::wallet::*;
+use crate::rita_common::dashboard::watchdog::*;
 use crate::rita_common::dashboard::wg_key::*;
 use crate::rita_common::network_endpoints::*;
+use crate::rita_exit::database::retention;
 use crate::rita_exit::network_endpoints::*;
+use crate::rita_exit::organizer::*;
 
 #[derive(Debug, Deserialize, Default)]
 pub struct Args {
     flag_config: String,
     flag_future: bool,
+    flag_export_db: Option<String>,
+    flag_import_db: Option<String>,
+    flag_check_migrations: bool,
 }
 
 lazy_static! {
     static ref USAGE: String = format!(
-        "Usage: rita_exit --config=<settings>
+        "Usage: rita_exit --config=<settings> [--future] [--export-db=<file> | --import-db=<file> | --check-migrations]
 Options:
     -c, --config=<settings>   Name of config file
     --future                    Enable B side of A/B releases
+    --export-db=<file>          Write the client database out to a json file and exit
+    --import-db=<file>          Load a json file from --export-db into an empty database and exit
+    --check-migrations          List the database migrations that haven't been run and exit, with
+                                1 if there are any
 About:
     Version {}
     git hash {}",
@@ -159,6 +186,10 @@ lazy_static! {
     pub static ref EXIT_ALLOWED_COUNTRIES: HashSet<String> =
         SETTING.get_allowed_countries().clone();
 }
+// updated by the traffic watcher every round and sent to clients along with our details
+lazy_static! {
+    pub static ref EXIT_LOAD: Arc<RwLock<Option<ExitLoad>>> = Arc::new(RwLock::new(None));
+}
 // price is updated at runtime, but we only want to grab a read lock to update it every few seconds
 // since this is done cooperatively in get_exit_info() only one read lock is aquired but we can
 // still update it every UPDATE_INTERVAL seconds
@@ -171,23 +202,11 @@ lazy_static! {
 }
 
 lazy_static! {
-    pub static ref DB_POOL: Arc<RwLock<Pool<ConnectionManager<PgConnection>>>> = {
-        let db_uri = SETTING.get_db_uri();
-
-        if !(db_uri.contains("postgres://")
-            || db_uri.contains("postgresql://")
-            || db_uri.contains("psql://"))
-        {
-            panic!("You must provide a valid postgressql database uri!");
+    pub static ref DB_POOL: Arc<RwLock<StorePool>> = {
+        match StorePool::open(&SETTING.get_db_uri(), SETTING.get_workers() + 1) {
+            Ok(pool) => Arc::new(RwLock::new(pool)),
+            Err(e) => panic!("Failed to open the client database! {:?}", e),
         }
-
-        let manager = ConnectionManager::new(SETTING.get_db_uri());
-        Arc::new(RwLock::new(
-            r2d2::Pool::builder()
-                .max_size(SETTING.get_workers() + 1)
-                .build(manager)
-                .expect("Failed to create pool."),
-        ))
     };
 }
 
@@ -222,6 +241,9 @@ fn sanity_check_config() {
     {
         panic!("GEOIP enforcement configured but not api key provided!");
     }
+    if let Err(e) = retention::timeouts() {
+        panic!("Bad client retention settings: {}", e);
+    }
 }
 
 fn main() {
@@ -243,7 +265,45 @@ fn main() {
     let settings_file = args.flag_config;
 
     // to get errors before lazy static
-    RitaExitSettingsStruct::new(&settings_file).expect("Settings parse failure");
+    let settings = RitaExitSettingsStruct::new(&settings_file).expect("Settings parse failure");
+
+    // moving between databases doesn't need anything else running
+    let db_uri = Arc::new(RwLock::new(settings)).get_db_uri();
+    if args.flag_check_migrations {
+        let pending = check_migrations(&db_uri).expect("Checking migrations failed");
+        if pending.is_empty() {
+            println!("The database is up to date");
+            return;
+        }
+        for migration in pending {
+            println!("{} has not been run", migration);
+        }
+        std::process::exit(1);
+    }
+    // before anything touches the database, with a lock so exits sharing it take turns
+    run_migrations(&db_uri).expect("Database migration failed");
+    if let Some(file) = args.flag_export_db {
+        let dump = export_to_file(&db_uri, &file).expect("Export failed");
+        println!(
+            "Exported {} clients, {} vouchers and {} usage records to {}",
+            dump.clients.len(),
+            dump.vouchers.len(),
+            dump.usage_records.len(),
+            file
+        );
+        return;
+    }
+    if let Some(file) = args.flag_import_db {
+        let dump = import_from_file(&db_uri, &file).expect("Import failed");
+        println!(
+            "Imported {} clients, {} vouchers and {} usage records from {}",
+            dump.clients.len(),
+            dump.vouchers.len(),
+            dump.usage_records.len(),
+            file
+        );
+        return;
+    }
 
     trace!("Starting");
     info!(
@@ -258,54 +318,24 @@ fn main() {
 
     check_rita_common_actors();
     check_rita_exit_actors();
+    start_rita_exit_watchdog();
     let workers = SETTING.get_workers();
     start_core_rita_endpoints(workers as usize);
     start_rita_exit_endpoints(workers as usize);
     start_rita_exit_dashboard();
+    start_exit_admin_dashboard();
 
     system.run();
 }
 
 fn start_rita_exit_dashboard() {
-    // Dashboard
+    // dashboard
     server::new(|| {
-        App::new()
-            .middleware(middleware::Headers)
-            .route("/info", Method::GET, get_own_info)
-            .route("/local_fee", Method::GET, get_local_fee)
-            .route("/local_fee/{fee}", Method::POST, set_local_fee)
-            .route("/dao_fee", Method::GET, get_dao_fee)
-            .route("/dao_fee/{fee}", Method::POST, set_dao_fee)
-            .route("/metric_factor", Method::GET, get_metric_factor)
-            .route("/metric_factor/{factor}", Method::POST, set_metric_factor)
-            .route("/settings", Method::GET, get_settings)
-            .route("/settings", Method::POST, set_settings)
-            .route("/version", Method::GET, version)
-            .route("/wg_public_key", Method::GET, get_wg_public_key)
-            .route("/wipe", Method::POST, wipe)
-            .route("/database", Method::DELETE, nuke_db)
-            .route("/debts", Method::GET, get_debts)
-            .route("/debts/reset", Method::POST, reset_debt)
-            .route("/dao_list", Method::GET, get_dao_list)
-            .route("/dao_list/add/{address}", Method::POST, add_to_dao_list)
-            .route(
-                "/dao_list/remove/{address}",
-                Method::POST,
-                remove_from_dao_list,
-            )
-            .route("/withdraw/{address}/{amount}", Method::POST, withdraw)
-            .route("/withdraw_all/{address}", Method::POST, withdraw_all)
-            .route(
-                "/withdraw_eth/{address}/{amount}",
-                Method::POST,
-                withdraw_eth,
-            )
-            .route("/nickname/get/", Method::GET, get_nickname)
-            .route("/nickname/set/", Method::POST, set_nickname)
-            .route("/router/password/", Method::POST, set_pass)
-            .route("/crash_actors", Method::POST, crash_actors)
-            .route("/usage/payments", Method::GET, get_payments)
-            .route("/token_bridge/status", Method::GET, get_bridge_status)
+        exit_dashboard_routes(
+            App::new()
+                .middleware(middleware::ErrorResponses)
+                .middleware(middleware::Headers),
+        )
     })
     .bind(format!(
         "[::0]:{}",
@@ -315,4 +345,143 @@ fn start_rita_exit_dashboard() {
     .workers(1)
     .shutdown_timeout(0)
     .start();
+
+    // the same api for rita-ctl and scripts on the exit
+    start_control_socket(|| {
+        exit_dashboard_routes(App::new().middleware(middleware::ErrorResponses))
+    });
+}
+
+/// Every dashboard endpoint, shared by the dashboard and the control socket
+fn exit_dashboard_routes(app: App) -> App {
+    app.route("/info", Method::GET, get_own_info)
+        .route("/babel/settings", Method::GET, get_babel_settings)
+        .route("/babel/settings", Method::POST, set_babel_settings)
+        .route("/local_fee", Method::GET, get_local_fee)
+        .route("/local_fee/{fee}", Method::POST, set_local_fee)
+        .route("/dao_fee", Method::GET, get_dao_fee)
+        .route("/dao_fee/{fee}", Method::POST, set_dao_fee)
+        .route("/metric_factor", Method::GET, get_metric_factor)
+        .route("/metric_factor/{factor}", Method::POST, set_metric_factor)
+        .route("/settings", Method::GET, get_settings)
+        .route("/settings", Method::POST, set_settings)
+        .route("/settings/problems", Method::GET, get_settings_problems)
+        .route("/version", Method::GET, version)
+        .route("/wg_public_key", Method::GET, get_wg_public_key)
+        .route("/wipe", Method::POST, wipe)
+        .route("/database", Method::DELETE, nuke_db)
+        .route("/database/status", Method::GET, get_db_status)
+        .route("/database/retention", Method::GET, get_retention_plan)
+        .route("/nat64", Method::GET, get_nat64_status)
+        .route("/usage/clients", Method::GET, get_usage_totals)
+        .route(
+            "/usage/clients/{mesh_ip}",
+            Method::GET,
+            get_client_usage_records,
+        )
+        .route("/static_ips", Method::GET, get_static_ips)
+        .route("/announcements", Method::GET, get_exit_announcements)
+        .route("/announcements", Method::POST, post_exit_announcement)
+        .route(
+            "/announcements/{id}",
+            Method::DELETE,
+            delete_exit_announcement,
+        )
+        .route(
+            "/clients/{mesh_ip}/static_ip",
+            Method::POST,
+            set_client_static_ip,
+        )
+        .route(
+            "/clients/{mesh_ip}/static_ip",
+            Method::DELETE,
+            remove_client_static_ip,
+        )
+        .route("/devices", Method::GET, get_client_devices)
+        .route(
+            "/clients/{mesh_ip}/device_limit/{limit}",
+            Method::POST,
+            set_client_device_limit,
+        )
+        .route("/clients/{mesh_ip}/plan", Method::GET, get_client_plan)
+        .route(
+            "/clients/{mesh_ip}/plan",
+            Method::POST,
+            set_client_plan_endpoint,
+        )
+        .route(
+            "/clients/{mesh_ip}/plan",
+            Method::DELETE,
+            remove_client_plan,
+        )
+        .route("/port_policy", Method::GET, get_port_policy)
+        .route("/port_policy", Method::POST, set_port_policy)
+        .route(
+            "/clients/{mesh_ip}/port_policy",
+            Method::POST,
+            set_client_port_policy,
+        )
+        .route("/organizer/stats", Method::GET, get_organizer_stats)
+        .route("/organizer/clients", Method::GET, get_organizer_clients)
+        .route("/debts", Method::GET, get_debts)
+        .route("/debts/reset", Method::POST, reset_debt)
+        .route("/debts/write_off", Method::POST, write_off_debt)
+        .route("/debts/history/{identity}", Method::GET, get_debt_history)
+        .route("/debts/divergence", Method::GET, get_counter_divergence)
+        .route("/debts/journal", Method::GET, get_debt_journal)
+        .route("/diagnostics", Method::GET, get_diagnostics)
+        .route(
+            "/debts/receipts/verify",
+            Method::POST,
+            verify_payment_receipt,
+        )
+        .route("/traffic_alerts", Method::GET, get_traffic_alerts)
+        .route("/billing/audit", Method::GET, get_billing_audit)
+        .route("/tunnels/{pubkey}/reset", Method::POST, reset_tunnel)
+        .route("/neighbors/paused", Method::GET, get_paused_neighbors)
+        .route("/neighbors/{pubkey}/pause", Method::POST, pause_neighbor)
+        .route("/neighbors/{pubkey}/resume", Method::POST, resume_neighbor)
+        .route("/path_trace/{dest_ip}", Method::GET, path_trace)
+        .route("/price_simulation", Method::GET, get_price_simulation)
+        .route("/watchdog", Method::GET, get_watchdog_status)
+        .route("/security_status", Method::GET, get_security_status)
+        .route("/live", Method::GET, live_updates)
+        .route("/channels", Method::GET, get_payment_channels)
+        .route("/channels/open", Method::POST, open_payment_channel)
+        .route(
+            "/channels/close/{address}",
+            Method::POST,
+            close_payment_channel,
+        )
+        .route("/dao_list", Method::GET, get_dao_list)
+        .route("/dao_list/add/{address}", Method::POST, add_to_dao_list)
+        .route(
+            "/dao_list/remove/{address}",
+            Method::POST,
+            remove_from_dao_list,
+        )
+        .route("/withdraw/{address}/{amount}", Method::POST, withdraw)
+        .route("/withdraw_all/{address}", Method::POST, withdraw_all)
+        .route(
+            "/withdraw_eth/{address}/{amount}",
+            Method::POST,
+            withdraw_eth,
+        )
+        .route("/nickname/get/", Method::GET, get_nickname)
+        .route("/nickname/set/", Method::POST, set_nickname)
+        .route("/services", Method::GET, get_services)
+        .route("/services/local", Method::GET, get_local_services)
+        .route("/services/local", Method::POST, set_local_services)
+        .route(
+            "/services/enabled/{enabled}",
+            Method::POST,
+            set_service_discovery,
+        )
+        .route("/schedule", Method::GET, get_schedule)
+        .route("/schedule", Method::POST, set_schedule)
+        .route("/sla", Method::GET, get_sla)
+        .route("/router/password/", Method::POST, set_pass)
+        .route("/crash_actors", Method::POST, crash_actors)
+        .route("/usage/payments", Method::GET, get_payments)
+        .route("/token_bridge/status", Method::GET, get_bridge_status)
 }
@@ -1,14 +1,30 @@
 //! This is the Actix-web middleware that attaches the content headers we need for
-//! the client dashboard
+//! the client dashboard, formats its errors, as well as the rate limiter used on the endpoints
+//! exposed to the mesh
 
 use crate::http::{header, HttpTryFrom, Method, StatusCode};
+use crate::rita_common::dashboard::error::DashboardError;
+use crate::rita_common::watchdog::{Journal, Watchdog, WatchdogEventKind};
 use crate::SETTING;
+use actix::SystemService;
 use actix_web::middleware::{Middleware, Response, Started};
 use actix_web::{FromRequest, HttpRequest, HttpResponse, Result};
 use actix_web_httpauth::extractors::basic::{BasicAuth, Config};
 use actix_web_httpauth::extractors::AuthenticationError;
 use regex::Regex;
+use settings::network::RateLimitSettings;
 use settings::RitaCommonSettings;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Hard cap on tracked sources, otherwise a spoofed flood could use up all of our memory on
+/// buckets. Once it's reached idle sources are dropped and new ones are limited until there's room
+const MAX_TRACKED_SOURCES: usize = 10_000;
+/// Dropping idle sources means going over every bucket, so a full limiter does it at most this
+/// often rather than on every request from a new source
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct Headers;
 
@@ -53,6 +69,32 @@ impl<S> Middleware<S> for Headers {
     }
 }
 
+/// Sends every error from a dashboard endpoint as an `ErrorResponse`, see dashboard::error. The
+/// rest of the response, like the auth challenge header, is left alone
+pub struct ErrorResponses;
+
+impl<S> Middleware<S> for ErrorResponses {
+    fn start(&self, _req: &HttpRequest<S>) -> Result<Started> {
+        Ok(Started::Done)
+    }
+
+    fn response(&self, req: &HttpRequest<S>, mut resp: HttpResponse) -> Result<Response> {
+        let error = match resp.error() {
+            Some(e) => DashboardError::from_actix(e, resp.status()),
+            None => return Ok(Response::Done(resp)),
+        };
+        trace!("Error on {} {:?}", req.path(), error);
+        let body = serde_json::to_vec(&error.to_response())?;
+        *resp.status_mut() = error.code.status();
+        resp.headers_mut().insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static("application/json"),
+        );
+        resp.set_body(body);
+        Ok(Response::Done(resp))
+    }
+}
+
 // for some reason the Headers struct doesn't get this
 #[allow(dead_code)]
 pub struct Auth;
@@ -81,3 +123,290 @@ impl<S> Middleware<S> for Auth {
         }
     }
 }
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    last_update: Instant,
+    /// requests rejected since the bucket was last full
+    rejected: u32,
+    banned_until: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RateLimitResult {
+    Allowed,
+    Limited,
+    Banned,
+    /// this request got the source banned
+    NewBan,
+}
+
+#[derive(Debug, Default)]
+struct RateLimiter {
+    buckets: HashMap<IpAddr, TokenBucket>,
+    last_prune: Option<Instant>,
+}
+
+lazy_static! {
+    static ref RATE_LIMITER: Arc<Mutex<RateLimiter>> = Arc::new(Mutex::new(RateLimiter::default()));
+}
+
+/// What a request is rate limited by. Anyone with a routed ipv6 prefix can pick a new source
+/// address for every request, so those are limited per /64. Link local addresses aren't, every
+/// neighbor on a link shares fe80::/64 and they'd all be limited together
+fn source_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) if ip.segments()[0] & 0xffc0 != 0xfe80 => {
+            let s = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0))
+        }
+        _ => ip,
+    }
+}
+
+fn check_rate_limit(
+    limiter: &mut RateLimiter,
+    ip: IpAddr,
+    limits: &RateLimitSettings,
+    now: Instant,
+) -> RateLimitResult {
+    let source = source_key(ip);
+    let buckets = &mut limiter.buckets;
+    if buckets.len() >= MAX_TRACKED_SOURCES && !buckets.contains_key(&source) {
+        let due = match limiter.last_prune {
+            Some(last) => now - last >= PRUNE_INTERVAL,
+            None => true,
+        };
+        if due {
+            limiter.last_prune = Some(now);
+            let ban_duration = Duration::from_secs(limits.ban_duration);
+            buckets.retain(|_, b| match b.banned_until {
+                Some(until) => until > now,
+                None => now - b.last_update < ban_duration,
+            });
+        }
+        if buckets.len() >= MAX_TRACKED_SOURCES {
+            return RateLimitResult::Limited;
+        }
+    }
+
+    let burst = f64::from(limits.burst);
+    let bucket = buckets.entry(source).or_insert(TokenBucket {
+        tokens: burst,
+        last_update: now,
+        rejected: 0,
+        banned_until: None,
+    });
+
+    if let Some(until) = bucket.banned_until {
+        if until > now {
+            return RateLimitResult::Banned;
+        }
+        info!("Rate limit ban for {} has expired", source);
+        bucket.banned_until = None;
+        bucket.rejected = 0;
+    }
+
+    let elapsed = now - bucket.last_update;
+    let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_millis()) / 1000.0;
+    bucket.tokens = (bucket.tokens + elapsed * f64::from(limits.requests_per_second)).min(burst);
+    bucket.last_update = now;
+    if bucket.tokens >= burst {
+        bucket.rejected = 0;
+    }
+
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        RateLimitResult::Allowed
+    } else {
+        bucket.rejected += 1;
+        if bucket.rejected >= limits.ban_threshold {
+            warn!(
+                "Banning {} for {} seconds after {} rate limited requests",
+                source, limits.ban_duration, bucket.rejected
+            );
+            bucket.banned_until = Some(now + Duration::from_secs(limits.ban_duration));
+            RateLimitResult::NewBan
+        } else {
+            RateLimitResult::Limited
+        }
+    }
+}
+
+/// Per source token bucket rate limiter for the endpoints we expose to the mesh, sources that
+/// keep hammering us after being limited are temporarily banned and the ban is journaled with
+/// the watchdog
+pub struct RateLimit;
+
+impl<S> Middleware<S> for RateLimit {
+    fn start(&self, req: &HttpRequest<S>) -> Result<Started> {
+        let ip = match req.peer_addr() {
+            Some(addr) => addr.ip(),
+            None => return Ok(Started::Done),
+        };
+        let limits = SETTING.get_network().rate_limit.clone();
+
+        let res = check_rate_limit(
+            &mut RATE_LIMITER.lock().unwrap(),
+            ip,
+            &limits,
+            Instant::now(),
+        );
+        match res {
+            RateLimitResult::Allowed => Ok(Started::Done),
+            RateLimitResult::Limited => {
+                trace!("Rate limited {} on {}", ip, req.path());
+                Ok(Started::Response(HttpResponse::TooManyRequests().finish()))
+            }
+            RateLimitResult::Banned => Ok(Started::Response(HttpResponse::Forbidden().finish())),
+            RateLimitResult::NewBan => {
+                Watchdog::from_registry().do_send(Journal {
+                    source: "RateLimit",
+                    kind: WatchdogEventKind::SourceBanned {
+                        source: source_key(ip).to_string(),
+                        path: req.path().to_string(),
+                        duration: limits.ban_duration,
+                    },
+                });
+                Ok(Started::Response(HttpResponse::Forbidden().finish()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit_and_ban() {
+        let limits = RateLimitSettings {
+            requests_per_second: 1,
+            burst: 2,
+            ban_threshold: 3,
+            ban_duration: 60,
+        };
+        let ip: IpAddr = "fd00::1".parse().unwrap();
+        let other: IpAddr = "fd00::2".parse().unwrap();
+        let mut buckets = RateLimiter::default();
+        let start = Instant::now();
+
+        for _ in 0..2 {
+            assert_eq!(
+                check_rate_limit(&mut buckets, ip, &limits, start),
+                RateLimitResult::Allowed
+            );
+        }
+        assert_eq!(
+            check_rate_limit(&mut buckets, ip, &limits, start),
+            RateLimitResult::Limited
+        );
+        // other sources are unaffected
+        assert_eq!(
+            check_rate_limit(&mut buckets, other, &limits, start),
+            RateLimitResult::Allowed
+        );
+        // the bucket refills over time
+        let later = start + Duration::from_secs(1);
+        assert_eq!(
+            check_rate_limit(&mut buckets, ip, &limits, later),
+            RateLimitResult::Allowed
+        );
+        assert_eq!(
+            check_rate_limit(&mut buckets, ip, &limits, later),
+            RateLimitResult::Limited
+        );
+        assert_eq!(
+            check_rate_limit(&mut buckets, ip, &limits, later),
+            RateLimitResult::NewBan
+        );
+        assert_eq!(
+            check_rate_limit(&mut buckets, ip, &limits, later),
+            RateLimitResult::Banned
+        );
+        // banned even once the bucket has refilled
+        let refilled = later + Duration::from_secs(10);
+        assert_eq!(
+            check_rate_limit(&mut buckets, ip, &limits, refilled),
+            RateLimitResult::Banned
+        );
+        let expired = later + Duration::from_secs(61);
+        assert_eq!(
+            check_rate_limit(&mut buckets, ip, &limits, expired),
+            RateLimitResult::Allowed
+        );
+    }
+
+    #[test]
+    fn test_rate_limit_sources() {
+        let limits = RateLimitSettings {
+            requests_per_second: 1,
+            burst: 1,
+            ban_threshold: 100,
+            ban_duration: 60,
+        };
+        let mut limiter = RateLimiter::default();
+        let start = Instant::now();
+        let check = |limiter: &mut RateLimiter, ip: &str, now| {
+            check_rate_limit(limiter, ip.parse().unwrap(), &limits, now)
+        };
+
+        // rotating addresses within a /64 doesn't get around the limit
+        assert_eq!(
+            check(&mut limiter, "2001:db8::1", start),
+            RateLimitResult::Allowed
+        );
+        assert_eq!(
+            check(&mut limiter, "2001:db8::2", start),
+            RateLimitResult::Limited
+        );
+        assert_eq!(
+            check(&mut limiter, "2001:db8:0:1::1", start),
+            RateLimitResult::Allowed
+        );
+        // but neighbors on the same link are limited separately
+        assert_eq!(
+            check(&mut limiter, "fe80::1", start),
+            RateLimitResult::Allowed
+        );
+        assert_eq!(
+            check(&mut limiter, "fe80::2", start),
+            RateLimitResult::Allowed
+        );
+        assert_eq!(
+            check(&mut limiter, "10.0.0.1", start),
+            RateLimitResult::Allowed
+        );
+        assert_eq!(
+            check(&mut limiter, "10.0.0.2", start),
+            RateLimitResult::Allowed
+        );
+
+        // fill the limiter up, past the cap new sources are limited until idle ones can go
+        let tracked = limiter.buckets.len();
+        for i in 0..(MAX_TRACKED_SOURCES - tracked) as u32 {
+            let ip = IpAddr::V4(i.to_be_bytes().into());
+            check_rate_limit(&mut limiter, ip, &limits, start);
+        }
+        assert_eq!(limiter.buckets.len(), MAX_TRACKED_SOURCES);
+        let soon = start + Duration::from_secs(10);
+        assert_eq!(
+            check(&mut limiter, "fd00::1", soon),
+            RateLimitResult::Limited
+        );
+        assert_eq!(limiter.buckets.len(), MAX_TRACKED_SOURCES);
+        // sources already tracked are still served
+        assert_eq!(
+            check(&mut limiter, "10.0.0.1", soon),
+            RateLimitResult::Allowed
+        );
+        // once the others have been idle for a ban duration they make room
+        let idle = start + Duration::from_secs(61);
+        assert_eq!(
+            check(&mut limiter, "fd00::1", idle),
+            RateLimitResult::Allowed
+        );
+        assert_eq!(limiter.buckets.len(), 2);
+    }
+}
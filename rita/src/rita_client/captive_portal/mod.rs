@@ -0,0 +1,351 @@
+//! The captive portal shows new subscribers why their internet isn't working yet. When it's
+//! enabled and the router is either not registered with an exit or out of money, plain http
+//! requests from the LAN are redirected by the firewall to a small page served locally with a
+//! message the operator can customize. Https can't be redirected without certificate errors so
+//! it's left alone, most devices probe for captive portals over http anyways. The operator can
+//! also have the page show the current exit's announcements, say about an outage.
+//!
+//! A router that still has some money but is in protective mode only shows the low balance
+//! notice, each device is redirected to the page once and can then carry on at the trickle
+//! protective mode allows until it's time to remind it again.
+
+use crate::rita_client::rita_loop::Tick;
+use crate::rita_common::utils::now_secs;
+use crate::KI;
+use crate::SETTING;
+use actix::{Actor, Context, Handler, Message, Recipient, Supervised, SystemService};
+use actix_web::server::{self, StopServer};
+use actix_web::{App, AsyncResponder, HttpRequest, HttpResponse};
+use althea_types::ExitState;
+use failure::Error;
+use futures01::Future;
+use num256::Uint256;
+use settings::captive_portal::CaptivePortalSettings;
+use settings::client::RitaClientSettings;
+use settings::RitaCommonSettings;
+
+/// The bridge OpenWRT puts all the LAN ports and wifi on
+pub const LAN_NIC: &str = "br-lan";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum PortalReason {
+    Unregistered,
+    LowBalance,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CaptivePortalStatus {
+    /// why the portal is currently being shown, None if it isn't
+    pub active: Option<PortalReason>,
+    /// the page is only shown to each device now and then rather than in place of every page
+    pub notice_only: bool,
+    pub settings: CaptivePortalSettings,
+}
+
+#[derive(Default)]
+pub struct CaptivePortal {
+    active: Option<PortalReason>,
+    notice_only: bool,
+    /// the port the portal rules currently redirect to and whether they're notice only, if they
+    /// are in place
+    redirect: Option<(u16, bool)>,
+    /// the port the page server is bound to and how to stop it, it's started the first time the
+    /// portal is needed and started again on the new port if the port is changed
+    server: Option<(u16, Recipient<StopServer>)>,
+    /// set by ProtectiveMode, the low balance notice is shown while it's active
+    protective_mode: bool,
+}
+
+impl Actor for CaptivePortal {
+    type Context = Context<Self>;
+}
+
+impl Supervised for CaptivePortal {}
+impl SystemService for CaptivePortal {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        // rules left over from before a restart would redirect the LAN with nothing listening
+        if let Err(e) = KI.disable_captive_portal(LAN_NIC, SETTING.get_captive_portal().port) {
+            warn!("Failed to clear captive portal rules {:?}", e);
+        }
+        info!("Captive portal started");
+    }
+}
+
+/// Why the portal should be shown given our registration state and balance, unregistered takes
+/// priority since topping up won't help until that's sorted
+fn portal_reason(registered: bool, balance: &Uint256, protective: bool) -> Option<PortalReason> {
+    if !registered {
+        Some(PortalReason::Unregistered)
+    } else if *balance == 0u32.into() || protective {
+        Some(PortalReason::LowBalance)
+    } else {
+        None
+    }
+}
+
+/// Only a router that's run out of money entirely has its http redirected for good, one in
+/// protective mode can still reach the internet and just needs to be told why it's slow
+fn is_notice_only(reason: Option<PortalReason>, balance: &Uint256) -> bool {
+    reason == Some(PortalReason::LowBalance) && *balance != 0u32.into()
+}
+
+fn is_registered() -> bool {
+    match SETTING.get_exit_client().get_current_exit() {
+        Some(exit) => match exit.info {
+            ExitState::Registered { .. } | ExitState::Maintenance { .. } => true,
+            _ => false,
+        },
+        None => false,
+    }
+}
+
+impl Handler<Tick> for CaptivePortal {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _: Tick, _ctx: &mut Context<Self>) -> Self::Result {
+        let settings = SETTING.get_captive_portal().clone();
+        let balance = SETTING.get_payment().balance.clone();
+        let reason = if settings.enabled {
+            portal_reason(is_registered(), &balance, self.protective_mode)
+        } else {
+            None
+        };
+        let notice_only = is_notice_only(reason, &balance);
+        if reason != self.active || notice_only != self.notice_only {
+            info!(
+                "Captive portal state changed to {:?}, notice only {}",
+                reason, notice_only
+            );
+            self.active = reason;
+            self.notice_only = notice_only;
+        }
+
+        let redirect = reason.map(|_| (settings.port, notice_only));
+        if redirect != self.redirect {
+            if let Some((old_port, _)) = self.redirect {
+                KI.disable_captive_portal(LAN_NIC, old_port)?;
+                self.redirect = None;
+            }
+            if let Some((port, notice_only)) = redirect {
+                self.restart_server(port)?;
+                KI.enable_captive_portal(LAN_NIC, port, notice_only)?;
+                self.redirect = redirect;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CaptivePortal {
+    /// Makes sure the page server is listening on the given port, stopping the one on the old
+    /// port if it's been changed
+    fn restart_server(&mut self, port: u16) -> Result<(), Error> {
+        if let Some((old_port, server)) = self.server.take() {
+            if old_port == port {
+                self.server = Some((old_port, server));
+                return Ok(());
+            }
+            info!("Captive portal port changed from {} to {}", old_port, port);
+            if let Err(e) = server.do_send(StopServer { graceful: false }) {
+                warn!("Failed to stop captive portal server {:?}", e);
+            }
+        }
+        self.server = Some((port, start_portal_server(port)?));
+        Ok(())
+    }
+}
+
+/// Protective mode went on or off
+pub struct SetProtectiveMode(pub bool);
+
+impl Message for SetProtectiveMode {
+    type Result = ();
+}
+
+impl Handler<SetProtectiveMode> for CaptivePortal {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetProtectiveMode, _ctx: &mut Context<Self>) -> Self::Result {
+        self.protective_mode = msg.0;
+    }
+}
+
+pub struct GetCaptivePortalStatus;
+
+impl Message for GetCaptivePortalStatus {
+    type Result = Result<CaptivePortalStatus, Error>;
+}
+
+impl Handler<GetCaptivePortalStatus> for CaptivePortal {
+    type Result = Result<CaptivePortalStatus, Error>;
+
+    fn handle(&mut self, _: GetCaptivePortalStatus, _ctx: &mut Context<Self>) -> Self::Result {
+        Ok(CaptivePortalStatus {
+            active: self.active,
+            notice_only: self.notice_only,
+            settings: SETTING.get_captive_portal().clone(),
+        })
+    }
+}
+
+fn start_portal_server(port: u16) -> Result<Recipient<StopServer>, Error> {
+    info!("Starting captive portal page server on port {}", port);
+    let server = server::new(|| App::new().default_resource(|r| r.f(portal_page)))
+        .workers(1)
+        .bind(format!("[::0]:{}", port))?
+        .shutdown_timeout(0)
+        .start();
+    Ok(server.recipient())
+}
+
+fn escape_html(input: &str) -> String {
+    input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The messages of the current exit's announcements that haven't expired, newest first
+fn current_announcements() -> Vec<String> {
+    let now = now_secs();
+    match SETTING.get_exit_client().get_current_exit() {
+        Some(exit) => exit
+            .announcements
+            .iter()
+            .rev()
+            .filter(|announcement| !announcement.is_expired(now))
+            .map(|announcement| announcement.message.clone())
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// `continue_to` is the page the request was for, linked below a notice so that it can be
+/// loaded once the device is let through
+fn render_page(
+    settings: &CaptivePortalSettings,
+    reason: Option<PortalReason>,
+    announcements: &[String],
+    continue_to: Option<&str>,
+) -> String {
+    let message = match reason {
+        Some(PortalReason::Unregistered) => settings.unregistered_message.as_str(),
+        Some(PortalReason::LowBalance) => settings.low_balance_message.as_str(),
+        None => "Your internet connection is working, try loading the page again.",
+    };
+    let announcements: String = announcements
+        .iter()
+        .map(|announcement| format!("<p>{}</p>", escape_html(announcement)))
+        .collect();
+    let continue_link = match continue_to {
+        Some(url) => format!(
+            "<p><a href=\"{url}\">Continue to {url}</a></p>",
+            url = escape_html(url)
+        ),
+        None => String::new(),
+    };
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><meta name=\"viewport\" \
+         content=\"width=device-width, initial-scale=1\"><title>{title}</title></head>\
+         <body><h1>{title}</h1><p>{message}</p>{announcements}{continue_link}</body></html>",
+        title = escape_html(&settings.title),
+        message = escape_html(message),
+        announcements = announcements,
+        continue_link = continue_link
+    )
+}
+
+fn portal_page(req: &HttpRequest) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    // the redirect keeps the original host and path, so this is where the device was going
+    let requested = req
+        .headers()
+        .get("Host")
+        .and_then(|host| host.to_str().ok())
+        .map(|host| format!("http://{}{}", host, req.uri()));
+    CaptivePortal::from_registry()
+        .send(GetCaptivePortalStatus)
+        .from_err()
+        .and_then(move |status| {
+            let status = status?;
+            let announcements = if status.settings.show_announcements {
+                current_announcements()
+            } else {
+                Vec::new()
+            };
+            let continue_to = if status.notice_only {
+                if let Some(peer) = peer {
+                    if let Err(e) = KI.mark_portal_seen(peer) {
+                        warn!("Failed to let {} past the captive portal {:?}", peer, e);
+                    }
+                }
+                requested
+            } else {
+                None
+            };
+            // captive portal detection expects anything but its usual response, so this
+            // must not be cached or the device will keep thinking it's captive
+            Ok(HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .header("Cache-Control", "no-store")
+                .body(render_page(
+                    &status.settings,
+                    status.active,
+                    &announcements,
+                    continue_to.as_ref().map(String::as_str),
+                )))
+        })
+        .responder()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_portal_page() {
+        assert_eq!(
+            portal_reason(false, &0u32.into(), false),
+            Some(PortalReason::Unregistered)
+        );
+        assert_eq!(
+            portal_reason(true, &0u32.into(), false),
+            Some(PortalReason::LowBalance)
+        );
+        assert_eq!(portal_reason(true, &1u32.into(), false), None);
+        assert_eq!(
+            portal_reason(true, &1u32.into(), true),
+            Some(PortalReason::LowBalance)
+        );
+
+        let mut settings = CaptivePortalSettings::default();
+        settings.low_balance_message = "Top up at <b>the co-op</b>".to_string();
+        let page = render_page(&settings, Some(PortalReason::LowBalance), &[], None);
+        assert!(page.contains("Top up at &lt;b&gt;the co-op&lt;/b&gt;"));
+        assert!(page.contains(&settings.title));
+        assert!(!page.contains("Continue to"));
+
+        let announcements = vec!["Outage in <Springfield>".to_string()];
+        let page = render_page(&settings, None, &announcements, None);
+        assert!(page.contains("<p>Outage in &lt;Springfield&gt;</p>"));
+
+        let page = render_page(
+            &settings,
+            Some(PortalReason::LowBalance),
+            &[],
+            Some("http://example.com/?a=1&b=2"),
+        );
+        assert!(page.contains(
+            "<a href=\"http://example.com/?a=1&amp;b=2\">Continue to http://example.com/?a=1&amp;b=2</a>"
+        ));
+
+        let low = Some(PortalReason::LowBalance);
+        assert!(is_notice_only(low, &1u32.into()));
+        assert!(!is_notice_only(low, &0u32.into()));
+        assert!(!is_notice_only(
+            Some(PortalReason::Unregistered),
+            &1u32.into()
+        ));
+    }
+}
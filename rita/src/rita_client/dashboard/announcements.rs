@@ -0,0 +1,26 @@
+use crate::rita_client::exit_manager::announcements::{
+    list_announcements, mark_announcements_seen, ExitAnnouncement,
+};
+use crate::rita_common::utils::now_secs;
+use crate::ARGS;
+use crate::SETTING;
+use ::actix_web::{HttpRequest, HttpResponse, Json};
+use failure::Error;
+use settings::client::RitaClientSettings;
+use settings::FileWrite;
+
+pub fn get_announcements(_req: HttpRequest) -> Result<Json<Vec<ExitAnnouncement>>, Error> {
+    debug!("/announcements GET hit");
+    Ok(Json(list_announcements(&*SETTING.get_exits(), now_secs())))
+}
+
+pub fn set_announcements_seen(_req: HttpRequest) -> Result<HttpResponse, Error> {
+    debug!("/announcements/seen POST hit");
+    mark_announcements_seen(&mut *SETTING.get_exits_mut());
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+    Ok(HttpResponse::Ok().json(()))
+}
@@ -0,0 +1,188 @@
+//! Backup and restore of the router configuration and identity. The backup contains the full
+//! settings, including the wireguard and eth private keys, as well as the usage history. Since
+//! that's enough to impersonate the router and spend its funds the bundle is encrypted with a
+//! key derived from a user provided password before it ever leaves the device.
+//!
+//! Restoring puts the old identity back in place and marks every exit we were registered with
+//! for re-registration, the exit already knows our identity so no new verification is needed.
+//! A reboot is required for the restored keys and mesh ip to take effect.
+
+use crate::rita_common::usage_tracker::GetUsageHistory;
+use crate::rita_common::usage_tracker::RestoreUsageHistory;
+use crate::rita_common::usage_tracker::UsageTracker;
+use crate::ARGS;
+use crate::KI;
+use crate::SETTING;
+use ::actix::registry::SystemService;
+use ::actix::Arbiter;
+use ::actix_web::{AsyncResponder, HttpResponse, Json};
+use althea_types::ExitState;
+use failure::Error;
+use futures01::{future, Future};
+use settings::client::RitaClientSettings;
+use settings::FileWrite;
+use settings::RitaCommonSettings;
+use sodiumoxide::crypto::pwhash;
+use sodiumoxide::crypto::secretbox;
+use std::boxed::Box;
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+/// Backups shorter than this are trivially brute forced
+const MIN_PASSWORD_LENGTH: usize = 8;
+/// How long the reboot after a restore is put off, long enough for the response to get out
+const REBOOT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BackupRequest {
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RestoreRequest {
+    pub password: String,
+    pub backup: EncryptedBackup,
+}
+
+/// The encrypted bundle handed to the user, the salt is needed to re-derive the key
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EncryptedBackup {
+    pub salt: [u8; 32],
+    pub nonce: [u8; 24],
+    pub ciphertext: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct BackupContents {
+    settings: serde_json::Value,
+    usage: UsageTracker,
+}
+
+fn derive_key(password: &str, salt: &pwhash::Salt) -> Result<secretbox::Key, Error> {
+    let mut key = secretbox::Key([0; secretbox::KEYBYTES]);
+    {
+        let secretbox::Key(ref mut kb) = key;
+        if pwhash::derive_key(
+            kb,
+            password.as_bytes(),
+            salt,
+            pwhash::OPSLIMIT_INTERACTIVE,
+            pwhash::MEMLIMIT_INTERACTIVE,
+        )
+        .is_err()
+        {
+            bail!("Failed to derive backup key");
+        }
+    }
+    Ok(key)
+}
+
+fn encrypt_backup(password: &str, contents: &BackupContents) -> Result<EncryptedBackup, Error> {
+    let plaintext = serde_json::to_vec(contents)?;
+    let salt = pwhash::gen_salt();
+    let key = derive_key(password, &salt)?;
+    let nonce = secretbox::gen_nonce();
+    let ciphertext = secretbox::seal(&plaintext, &nonce, &key);
+    Ok(EncryptedBackup {
+        salt: salt.0,
+        nonce: nonce.0,
+        ciphertext,
+    })
+}
+
+fn decrypt_backup(password: &str, backup: &EncryptedBackup) -> Result<BackupContents, Error> {
+    let key = derive_key(password, &pwhash::Salt(backup.salt))?;
+    let nonce = secretbox::Nonce(backup.nonce);
+    let plaintext = match secretbox::open(&backup.ciphertext, &nonce, &key) {
+        Ok(val) => val,
+        Err(_) => bail!("Could not decrypt backup, wrong password or corrupted file"),
+    };
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+pub fn create_backup(
+    req: Json<BackupRequest>,
+) -> Box<dyn Future<Item = Json<EncryptedBackup>, Error = Error>> {
+    debug!("/backup POST hit");
+    let password = req.into_inner().password;
+    if password.len() < MIN_PASSWORD_LENGTH {
+        return Box::new(future::err(format_err!(
+            "Backup password must be at least {} characters",
+            MIN_PASSWORD_LENGTH
+        )));
+    }
+    let settings = match SETTING.get_all() {
+        Ok(val) => val,
+        Err(e) => return Box::new(future::err(e)),
+    };
+
+    UsageTracker::from_registry()
+        .send(GetUsageHistory)
+        .from_err()
+        .and_then(move |usage| {
+            let contents = BackupContents {
+                settings,
+                usage: usage?,
+            };
+            let backup = encrypt_backup(&password, &contents)?;
+
+            SETTING.get_network_mut().backup_created = true;
+            if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+                warn!("Failed to save backup created flag {:?}", e);
+            }
+            Ok(Json(backup))
+        })
+        .responder()
+}
+
+pub fn restore_backup(
+    req: Json<RestoreRequest>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    debug!("/restore POST hit");
+    let req = req.into_inner();
+    let contents = match decrypt_backup(&req.password, &req.backup) {
+        Ok(val) => val,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    if let Err(e) = SETTING.merge(contents.settings) {
+        return Box::new(future::err(e));
+    }
+
+    // the exits we were registered with still have our identity on file, so instead of
+    // going through verification again we just ask them for our details once we're back up
+    let mut exits = SETTING.get_exits_mut();
+    for (name, exit) in exits.iter_mut() {
+        if exit.info.is_registered() {
+            info!("Restored exit {} will be re-registered", name);
+            exit.info = ExitState::GotInfo {
+                general_details: exit.info.general_details().unwrap().clone(),
+                message: "Restored from backup".to_string(),
+                auto_register: true,
+                retry_after: None,
+            };
+            exit.restored = true;
+        }
+    }
+    drop(exits);
+
+    UsageTracker::from_registry()
+        .send(RestoreUsageHistory(contents.usage))
+        .from_err()
+        .and_then(|res| {
+            res?;
+            SETTING.write().unwrap().write(&ARGS.flag_config)?;
+
+            // the restored keys and mesh ip only take effect on startup, rebooting right away
+            // would cut off the response and the restore would look like it failed
+            if KI.is_openwrt() {
+                Arbiter::spawn(Delay::new(Instant::now() + REBOOT_DELAY).then(|_| {
+                    if let Err(e) = KI.run_command("reboot", &[]) {
+                        error!("Failed to reboot after restoring a backup {:?}", e);
+                    }
+                    Ok(())
+                }));
+            }
+            Ok(HttpResponse::Ok().json(()))
+        })
+        .responder()
+}
@@ -0,0 +1,78 @@
+use crate::rita_client::captive_portal::{
+    CaptivePortal, CaptivePortalStatus, GetCaptivePortalStatus,
+};
+use crate::ARGS;
+use crate::SETTING;
+use actix::SystemService;
+use actix_web::{AsyncResponder, HttpRequest, HttpResponse, Json, Path};
+use failure::Error;
+use futures01::Future;
+use settings::client::RitaClientSettings;
+use settings::FileWrite;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CaptivePortalText {
+    pub title: Option<String>,
+    pub unregistered_message: Option<String>,
+    pub low_balance_message: Option<String>,
+}
+
+pub fn get_captive_portal(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<CaptivePortalStatus>, Error = Error>> {
+    trace!("get_captive_portal: Hit");
+    CaptivePortal::from_registry()
+        .send(GetCaptivePortalStatus)
+        .from_err()
+        .and_then(move |reply| Ok(Json(reply?)))
+        .responder()
+}
+
+/// Changes take effect on the next client loop tick
+pub fn set_captive_portal_enabled(path: Path<bool>) -> Result<HttpResponse, Error> {
+    let value = path.into_inner();
+    debug!("Set captive portal enabled {} hit!", value);
+    SETTING.get_captive_portal_mut().enabled = value;
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+    Ok(HttpResponse::Ok().json(()))
+}
+
+/// Shows the current exit's announcements on the portal page or stops showing them
+pub fn set_captive_portal_announcements(path: Path<bool>) -> Result<HttpResponse, Error> {
+    let value = path.into_inner();
+    debug!("Set captive portal announcements {} hit!", value);
+    SETTING.get_captive_portal_mut().show_announcements = value;
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+    Ok(HttpResponse::Ok().json(()))
+}
+
+pub fn set_captive_portal_text(text: Json<CaptivePortalText>) -> Result<HttpResponse, Error> {
+    debug!("Set captive portal text hit!");
+    let text = text.into_inner();
+    {
+        let mut portal = SETTING.get_captive_portal_mut();
+        if let Some(title) = text.title {
+            portal.title = title;
+        }
+        if let Some(message) = text.unregistered_message {
+            portal.unregistered_message = message;
+        }
+        if let Some(message) = text.low_balance_message {
+            portal.low_balance_message = message;
+        }
+    }
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+    Ok(HttpResponse::Ok().json(()))
+}
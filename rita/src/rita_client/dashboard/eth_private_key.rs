@@ -1,3 +1,4 @@
+use crate::rita_common::dashboard::error::DashboardError;
 use crate::ARGS;
 use crate::KI;
 use crate::SETTING;
@@ -27,7 +28,7 @@ pub fn get_eth_private_key(_req: HttpRequest) -> Result<HttpResponse, Error> {
         None => {
             let error_msg = "No eth key configured yet";
             warn!("{}", error_msg);
-            ret.insert("error".to_owned(), error_msg.to_owned());
+            return Err(DashboardError::not_ready(error_msg).into());
         }
     }
 
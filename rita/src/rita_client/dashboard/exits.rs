@@ -1,29 +1,32 @@
 //! The Exit info endpoint gathers infromation about exit status and presents it to the dashbaord.
 
+use crate::rita_client::exit_manager::exit_list::{merge_exits, parse_exit_list};
+use crate::rita_client::exit_manager::exit_resend_email_request;
+use crate::rita_client::exit_manager::exit_selection::ExitProbe;
 use crate::rita_client::exit_manager::exit_setup_request;
+use crate::rita_client::exit_manager::terms::{offered_terms, pending_terms};
+use crate::rita_client::exit_manager::{ExitManager, GetExitProbes};
+use crate::rita_common::dashboard::error::{DashboardError, ErrorCode};
 use crate::rita_common::dashboard::Dashboard;
+use crate::rita_common::route_cache::get_routes;
 use crate::ARGS;
 use crate::KI;
 use crate::SETTING;
 use actix::{Handler, Message, ResponseFuture, SystemService};
 use actix_web::client;
 use actix_web::error::PayloadError;
-use actix_web::http::StatusCode;
 use actix_web::AsyncResponder;
 use actix_web::HttpMessage;
 use actix_web::Path;
+use actix_web::Query;
 use actix_web::{HttpRequest, HttpResponse, Json};
-use althea_types::ExitState;
+use althea_types::{ExitState, ExitTerms, Identity};
 use babel_monitor::do_we_have_route;
-use babel_monitor::open_babel_stream;
-use babel_monitor::parse_routes;
-use babel_monitor::start_connection;
 use bytes::Bytes;
 use failure::Error;
 use futures01::{future, Future};
-use settings::client::{ExitServer, RitaClientSettings};
+use settings::client::{ExitClientSettings, ExitServer, RitaClientSettings, SplitTunnelAction};
 use settings::FileWrite;
-use settings::RitaCommonSettings;
 use std::boxed::Box;
 use std::collections::HashMap;
 use std::time::Duration;
@@ -36,6 +39,37 @@ pub struct ExitInfo {
     have_route: bool,
     is_reachable: bool,
     is_tunnel_working: bool,
+    /// latest latency, jitter and throughput measurements and the resulting score
+    probe: Option<ExitProbe>,
+    /// terms the exit is offering that the user has to accept before we use it again
+    pending_terms: Option<ExitTerms>,
+}
+
+/// The most we're willing to pay an exit and what to do when it asks for more
+#[derive(Serialize, Deserialize)]
+pub struct ExitPriceLimit {
+    pub max_exit_price: Option<u64>,
+    pub price_failover: bool,
+}
+
+/// Where to move to when deleting the current exit
+#[derive(Deserialize, Debug, Default)]
+pub struct DeleteExitQuery {
+    pub replacement: Option<String>,
+}
+
+/// The changes to make to an exit, fields that aren't set are left alone
+#[derive(Deserialize, Debug, Default)]
+pub struct ExitEdit {
+    /// renames the exit
+    pub nickname: Option<String>,
+    /// changing the exit's identity means registering with it again
+    pub id: Option<Identity>,
+    pub registration_port: Option<u16>,
+    pub description: Option<String>,
+    /// an empty region clears it
+    pub region: Option<String>,
+    pub priority: Option<u32>,
 }
 
 pub struct GetExitInfo;
@@ -73,50 +107,53 @@ impl Handler<GetExitInfo> for Dashboard {
     type Result = ResponseFuture<Vec<ExitInfo>, Error>;
 
     fn handle(&mut self, _msg: GetExitInfo, _ctx: &mut Self::Context) -> Self::Result {
-        let babel_port = SETTING.get_network().babel_port;
-
         Box::new(
-            open_babel_stream(babel_port)
+            ExitManager::from_registry()
+                .send(GetExitProbes)
                 .from_err()
-                .and_then(move |stream| {
-                    start_connection(stream).and_then(move |stream| {
-                        parse_routes(stream).and_then(move |routes| {
-                            let route_table_sample = routes.1;
-                            let mut output = Vec::new();
-
-                            let exit_client = SETTING.get_exit_client();
-                            let current_exit = exit_client.get_current_exit();
-
-                            for exit in exit_client.exits.clone().into_iter() {
-                                let selected = is_selected(&exit.1, current_exit);
-                                let have_route =
-                                    do_we_have_route(&exit.1.id.mesh_ip, &route_table_sample)?;
-
-                                // failed pings block for one second, so we should be sure it's at least reasonable
-                                // to expect the pings to work before issuing them.
-                                let reachable = if have_route {
-                                    KI.ping_check(&exit.1.id.mesh_ip, EXIT_PING_TIMEOUT)?
-                                } else {
-                                    false
-                                };
-                                let tunnel_working = match (have_route, selected) {
-                                    (true, true) => is_tunnel_working(&exit.1, current_exit),
-                                    _ => false,
-                                };
-
-                                output.push(ExitInfo {
-                                    nickname: exit.0,
-                                    exit_settings: exit.1.clone(),
-                                    is_selected: selected,
-                                    have_route,
-                                    is_reachable: reachable,
-                                    is_tunnel_working: tunnel_working,
-                                })
-                            }
-
-                            Ok(output)
+                .and_then(move |probes| {
+                    let probes = probes.unwrap_or_default();
+                    get_routes().map(move |cached| (probes, cached))
+                })
+                .and_then(move |(probes, cached)| {
+                    let route_table_sample = cached.routes;
+                    let mut output = Vec::new();
+
+                    let exit_client = SETTING.get_exit_client();
+                    let current_exit = exit_client.get_current_exit();
+
+                    for exit in exit_client.exits.clone().into_iter() {
+                        let selected = is_selected(&exit.1, current_exit);
+                        let have_route = do_we_have_route(&exit.1.id.mesh_ip, &route_table_sample)?;
+
+                        // failed pings block for one second, so we should be sure it's at least reasonable
+                        // to expect the pings to work before issuing them.
+                        let reachable = if have_route {
+                            KI.ping_check(&exit.1.id.mesh_ip, EXIT_PING_TIMEOUT)?
+                        } else {
+                            false
+                        };
+                        let tunnel_working = match (have_route, selected) {
+                            (true, true) => is_tunnel_working(&exit.1, current_exit),
+                            _ => false,
+                        };
+
+                        let probe = probes.get(&exit.0).cloned();
+                        let pending_terms = pending_terms(&exit.1).cloned();
+
+                        output.push(ExitInfo {
+                            nickname: exit.0,
+                            exit_settings: exit.1.clone(),
+                            is_selected: selected,
+                            have_route,
+                            is_reachable: reachable,
+                            is_tunnel_working: tunnel_working,
+                            probe,
+                            pending_terms,
                         })
-                    })
+                    }
+
+                    Ok(output)
                 }),
         )
     }
@@ -140,32 +177,20 @@ pub fn exits_sync(
     let list_url = match list_url_json.get("url") {
         Some(url) if url.starts_with("https://") => url,
         Some(_unsafe_url) => {
-            let mut ret = HashMap::new();
-            ret.insert(
-                "error".to_owned(),
-                "Attempted to use a non-HTTPS url".to_owned(),
-            );
-            return Box::new(future::ok(
-                HttpResponse::new(StatusCode::BAD_REQUEST)
-                    .into_builder()
-                    .json(ret),
+            return Box::new(future::err(
+                DashboardError::invalid_input("Attempted to use a non-HTTPS url").into(),
             ));
         }
         None => {
-            let mut ret = HashMap::new();
-
-            ret.insert(
-                "error".to_owned(),
-                "Could not find a \"url\" key in supplied JSON".to_owned(),
-            );
-            return Box::new(future::ok(
-                HttpResponse::new(StatusCode::BAD_REQUEST)
-                    .into_builder()
-                    .json(ret),
+            return Box::new(future::err(
+                DashboardError::invalid_input("Could not find a \"url\" key in supplied JSON")
+                    .into(),
             ));
         }
     }
     .to_string();
+    let pubkey = SETTING.get_exit_client().exit_list_pubkey;
+    let last_sequence = SETTING.get_exit_client().exit_list_sequence;
 
     let res = client::get(list_url.clone())
         .header("User-Agent", "Actix-web")
@@ -178,10 +203,12 @@ pub fn exits_sync(
                 .body()
                 .then(move |message_body: Result<Bytes, PayloadError>| {
                     if let Err(e) = message_body {
-                        return Box::new(future::ok(
-                            HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
-                                .into_builder()
-                                .json(format!("Actix encountered a payload error {:?}", e)),
+                        return Box::new(future::err(
+                            DashboardError::new(
+                                ErrorCode::UpstreamFailed,
+                                format!("Actix encountered a payload error {:?}", e),
+                            )
+                            .into(),
                         ));
                     }
                     let message_body = message_body.unwrap();
@@ -189,21 +216,16 @@ pub fn exits_sync(
                     // .json() only works on application/json content types unlike reqwest which handles bytes
                     // transparently actix requests need to get the body and deserialize using serde_json in
                     // an explicit fashion
-                    match serde_json::from_slice::<HashMap<String, ExitServer>>(&message_body) {
-                        Ok(mut new_exits) => {
+                    match parse_exit_list(&message_body, pubkey, last_sequence) {
+                        Ok((new_exits, sequence)) => {
                             info!("exit_sync list: {:#?}", new_exits);
 
                             let mut exit_client = SETTING.get_exit_client_mut();
-
-                            // if the entry already exists copy the registration info over
-                            for new_exit in new_exits.iter_mut() {
-                                let nick = new_exit.0;
-                                let new_settings = new_exit.1;
-                                if let Some(old_exit) = exit_client.exits.get(nick) {
-                                    new_settings.info = old_exit.info.clone();
-                                }
+                            merge_exits(&mut exit_client.exits, new_exits);
+                            if let Some(sequence) = sequence {
+                                exit_client.exit_list_sequence =
+                                    exit_client.exit_list_sequence.max(sequence);
                             }
-                            exit_client.exits.extend(new_exits);
                             let exits = exit_client.exits.clone();
                             drop(exit_client);
 
@@ -216,24 +238,19 @@ pub fn exits_sync(
                             Box::new(future::ok(HttpResponse::Ok().json(exits)))
                         }
                         Err(e) => {
-                            let mut ret = HashMap::<String, String>::new();
-
                             error!(
-                                "Could not deserialize exit list at {:?} because of error: {:?}",
+                                "Could not verify or deserialize exit list at {:?} because of error: {:?}",
                                 list_url, e
                             );
-                            ret.insert(
-                                "error".to_owned(),
-                                format!(
-                            "Could not deserialize exit list at URL {:?} because of error {:?}",
-                             list_url, e
-                             ),
-                            );
-
-                            Box::new(future::ok(
-                                HttpResponse::new(StatusCode::BAD_REQUEST)
-                                    .into_builder()
-                                    .json(ret),
+                            Box::new(future::err(
+                                DashboardError::new(
+                                    ErrorCode::InvalidExitList,
+                                    format!(
+                                        "Could not verify or deserialize exit list at URL {:?} because of error {:?}",
+                                        list_url, e
+                                    ),
+                                )
+                                .into(),
                             ))
                         }
                     }
@@ -259,7 +276,6 @@ pub fn reset_exit(path: Path<String>) -> Box<dyn Future<Item = HttpResponse, Err
     debug!("/exits/{}/reset hit", exit_name);
 
     let mut exits = SETTING.get_exits_mut();
-    let mut ret = HashMap::new();
 
     if let Some(exit) = exits.get_mut(&exit_name) {
         info!(
@@ -272,18 +288,10 @@ pub fn reset_exit(path: Path<String>) -> Box<dyn Future<Item = HttpResponse, Err
             error!("Failed to delete wg_exit {:?}", e)
         };
 
-        Box::new(future::ok(HttpResponse::Ok().json(ret)))
+        Box::new(future::ok(HttpResponse::Ok().json(json!({}))))
     } else {
         error!("Requested a reset on unknown exit {:?}", exit_name);
-        ret.insert(
-            "error".to_owned(),
-            format!("Requested reset on unknown exit {:?}", exit_name),
-        );
-        Box::new(future::ok(
-            HttpResponse::new(StatusCode::BAD_REQUEST)
-                .into_builder()
-                .json(ret),
-        ))
+        Box::new(future::err(DashboardError::unknown_exit(&exit_name).into()))
     }
 }
 
@@ -292,7 +300,6 @@ pub fn select_exit(path: Path<String>) -> Box<dyn Future<Item = HttpResponse, Er
     debug!("/exits/{}/select hit", exit_name);
 
     let mut exit_client = SETTING.get_exit_client_mut();
-    let mut ret = HashMap::new();
 
     if exit_client.exits.contains_key(&exit_name) {
         info!("Selecting exit {:?}", exit_name);
@@ -305,18 +312,10 @@ pub fn select_exit(path: Path<String>) -> Box<dyn Future<Item = HttpResponse, Er
             return Box::new(future::err(e));
         }
 
-        Box::new(future::ok(HttpResponse::Ok().json(ret)))
+        Box::new(future::ok(HttpResponse::Ok().json(json!({}))))
     } else {
         error!("Requested selection of an unknown exit {:?}", exit_name);
-        ret.insert(
-            "error".to_owned(),
-            format!("Requested selection of an unknown exit {:?}", exit_name),
-        );
-        Box::new(future::ok(
-            HttpResponse::new(StatusCode::BAD_REQUEST)
-                .into_builder()
-                .json(ret),
-        ))
+        Box::new(future::err(DashboardError::unknown_exit(&exit_name).into()))
     }
 }
 
@@ -326,44 +325,457 @@ pub fn register_to_exit(path: Path<String>) -> Box<dyn Future<Item = HttpRespons
 
     debug!("Attempting to register on exit {:?}", exit_name);
 
-    Box::new(exit_setup_request(exit_name, None).then(|res| {
-        let mut ret = HashMap::new();
-        match res {
-            Ok(_) => future::ok(HttpResponse::Ok().json(ret)),
-            Err(e) => {
-                error!("exit_setup_request() failed with: {:?}", e);
-                ret.insert("error".to_owned(), "Exit setup request failed".to_owned());
-                ret.insert("rust_error".to_owned(), format!("{:?}", e));
-                future::ok(
-                    HttpResponse::new(StatusCode::BAD_REQUEST)
-                        .into_builder()
-                        .json(ret),
+    Box::new(exit_setup_request(exit_name, None).then(|res| match res {
+        Ok(_) => future::ok(HttpResponse::Ok().json(json!({}))),
+        Err(e) => {
+            error!("exit_setup_request() failed with: {:?}", e);
+            future::err(
+                DashboardError::new(
+                    ErrorCode::ExitRequestFailed,
+                    format!("Exit setup request failed {}", e),
                 )
-            }
+                .into(),
+            )
+        }
+    }))
+}
+
+/// Asks the exit to send our email verification code again, it's a new code if the old one
+/// expired or was guessed at too many times
+pub fn resend_exit_email(
+    path: Path<String>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let exit_name = path.into_inner();
+    debug!("/exits/{}/resend_email hit", exit_name);
+
+    Box::new(exit_resend_email_request(exit_name).then(|res| match res {
+        Ok(_) => future::ok(HttpResponse::Ok().json(json!({}))),
+        Err(e) => {
+            error!("exit_resend_email_request() failed with: {:?}", e);
+            future::err(
+                DashboardError::new(
+                    ErrorCode::ExitRequestFailed,
+                    format!("Exit resend email request failed {}", e),
+                )
+                .into(),
+            )
         }
     }))
 }
 
+/// The answer to an exit's passphrase signup challenge, sent in the body so that it stays out
+/// of urls and access logs
+#[derive(Deserialize, Debug)]
+pub struct SignupPassphrase {
+    pub passphrase: String,
+}
+
+/// Saves the passphrase for an exit's signup challenge and registers with it
+pub fn register_with_passphrase(
+    req: (Path<String>, Json<SignupPassphrase>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let exit_name = req.0.into_inner();
+    let passphrase = req.1.into_inner().passphrase;
+    debug!("/exits/{}/passphrase hit", exit_name);
+
+    match SETTING.get_exits_mut().get_mut(&exit_name) {
+        Some(exit) => exit.signup_passphrase = Some(passphrase),
+        None => return Box::new(future::err(DashboardError::unknown_exit(&exit_name).into())),
+    }
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Box::new(future::err(e));
+    }
+
+    Box::new(exit_setup_request(exit_name, None).then(|res| match res {
+        Ok(_) => future::ok(HttpResponse::Ok().json(json!({}))),
+        Err(e) => {
+            error!("exit_setup_request() failed with: {:?}", e);
+            future::err(
+                DashboardError::new(
+                    ErrorCode::ExitRequestFailed,
+                    format!("Exit setup request failed {}", e),
+                )
+                .into(),
+            )
+        }
+    }))
+}
+
+/// Saves a prepaid voucher to be redeemed the next time we register with an exit
+pub fn set_exit_voucher(path: Path<String>) -> Result<HttpResponse, Error> {
+    let voucher = path.into_inner();
+    debug!("/voucher hit");
+    SETTING.get_exit_client_mut().voucher = Some(voucher);
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+    Ok(HttpResponse::Ok().json(()))
+}
+
 pub fn verify_on_exit_with_code(
     path: Path<(String, String)>,
 ) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
     let (exit_name, code) = path.into_inner();
     debug!("/exits/{}/verify/{} hit", exit_name, code);
 
-    Box::new(exit_setup_request(exit_name, Some(code)).then(|res| {
-        let mut ret = HashMap::new();
-        match res {
-            Ok(_) => future::ok(HttpResponse::Ok().json(ret)),
+    Box::new(
+        exit_setup_request(exit_name, Some(code)).then(|res| match res {
+            Ok(_) => future::ok(HttpResponse::Ok().json(json!({}))),
             Err(e) => {
                 error!("exit_setup_request() failed with: {:?}", e);
-                ret.insert("error".to_owned(), "Exit setup request failed".to_owned());
-                ret.insert("rust_error".to_owned(), format!("{:?}", e));
-                future::ok(
-                    HttpResponse::new(StatusCode::BAD_REQUEST)
-                        .into_builder()
-                        .json(ret),
+                future::err(
+                    DashboardError::new(
+                        ErrorCode::ExitRequestFailed,
+                        format!("Exit setup request failed {}", e),
+                    )
+                    .into(),
                 )
             }
+        }),
+    )
+}
+
+/// Accepts the terms the exit is currently offering, the exit is used again from the next tick
+pub fn accept_exit_terms(path: Path<String>) -> Result<HttpResponse, Error> {
+    let exit_name = path.into_inner();
+    debug!("/exits/{}/accept_terms hit", exit_name);
+
+    let mut exits = SETTING.get_exits_mut();
+    let exit = match exits.get_mut(&exit_name) {
+        Some(exit) => exit,
+        None => return Err(DashboardError::unknown_exit(&exit_name).into()),
+    };
+    let offered = match offered_terms(exit) {
+        Some(offered) => offered.clone(),
+        None => {
+            return Err(DashboardError::not_ready(format!(
+                "Exit {:?} hasn't offered any terms",
+                exit_name
+            ))
+            .into());
         }
+    };
+    info!("Accepting terms {:?} for exit {:?}", offered, exit_name);
+    exit.accepted_terms = Some(offered);
+    drop(exits);
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+    Ok(HttpResponse::Ok().json(()))
+}
+
+pub fn get_exit_price_limit(_req: HttpRequest) -> Result<Json<ExitPriceLimit>, Error> {
+    debug!("/exits/price_limit GET hit");
+    let exit_client = SETTING.get_exit_client();
+    Ok(Json(ExitPriceLimit {
+        max_exit_price: exit_client.max_exit_price,
+        price_failover: exit_client.price_failover,
     }))
 }
+
+pub fn set_exit_price_limit(limit: Json<ExitPriceLimit>) -> Result<HttpResponse, Error> {
+    debug!("/exits/price_limit POST hit");
+    let limit = limit.into_inner();
+    let mut exit_client = SETTING.get_exit_client_mut();
+    exit_client.max_exit_price = limit.max_exit_price;
+    exit_client.price_failover = limit.price_failover;
+    drop(exit_client);
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+    Ok(HttpResponse::Ok().json(()))
+}
+
+/// Removes the exit named `name`, moving to `replacement` first if it's the current exit. Exits
+/// split tunnel rules send traffic to can't be removed until the rules are changed
+fn remove_exit(
+    exit_client: &mut ExitClientSettings,
+    name: &str,
+    replacement: Option<String>,
+) -> Result<ExitServer, DashboardError> {
+    if !exit_client.exits.contains_key(name) {
+        return Err(DashboardError::unknown_exit(name));
+    }
+    let in_use = exit_client
+        .split_tunnel
+        .iter()
+        .any(|rule| match &rule.action {
+            SplitTunnelAction::Exit(exit) => exit == name,
+            _ => false,
+        });
+    if in_use {
+        return Err(DashboardError::invalid_input(format!(
+            "Exit {:?} is used by split tunnel rules, change them first",
+            name
+        )));
+    }
+    if exit_client.current_exit.as_ref().map(|s| s.as_str()) == Some(name) {
+        match replacement {
+            Some(ref replacement) if replacement == name => {
+                return Err(DashboardError::invalid_input(
+                    "An exit can't replace itself",
+                ));
+            }
+            Some(replacement) => {
+                if !exit_client.exits.contains_key(&replacement) {
+                    return Err(DashboardError::unknown_exit(&replacement));
+                }
+                exit_client.current_exit = Some(replacement);
+            }
+            None => {
+                return Err(DashboardError::invalid_input(format!(
+                    "Exit {:?} is selected, choose a replacement to delete it",
+                    name
+                )));
+            }
+        }
+    }
+    Ok(exit_client.exits.remove(name).unwrap())
+}
+
+/// Applies an edit to the exit named `name`, returning the exit's name after the edit and if
+/// its identity changed
+fn edit_exit(
+    exit_client: &mut ExitClientSettings,
+    name: &str,
+    edit: ExitEdit,
+) -> Result<(String, bool), DashboardError> {
+    let mut exit = match exit_client.exits.get(name) {
+        Some(exit) => exit.clone(),
+        None => return Err(DashboardError::unknown_exit(name)),
+    };
+    let new_name = match edit.nickname {
+        Some(new_name) if new_name.is_empty() => {
+            return Err(DashboardError::invalid_input(
+                "Exit nicknames can't be empty",
+            ));
+        }
+        Some(new_name) if new_name != name && exit_client.exits.contains_key(&new_name) => {
+            return Err(DashboardError::invalid_input(format!(
+                "There's already an exit named {:?}",
+                new_name
+            )));
+        }
+        Some(new_name) => new_name,
+        None => name.to_string(),
+    };
+    if edit.registration_port == Some(0) {
+        return Err(DashboardError::invalid_input("Invalid registration port 0"));
+    }
+
+    let new_id = match edit.id {
+        Some(id) if id != exit.id => {
+            exit.id = id;
+            // the registration and terms were with whoever had the old identity
+            exit.info = ExitState::New;
+            exit.accepted_terms = None;
+            true
+        }
+        _ => false,
+    };
+    if let Some(port) = edit.registration_port {
+        exit.registration_port = port;
+    }
+    if let Some(description) = edit.description {
+        exit.description = description;
+    }
+    if let Some(region) = edit.region {
+        exit.region = if region.is_empty() {
+            None
+        } else {
+            Some(region)
+        };
+    }
+    if let Some(priority) = edit.priority {
+        exit.priority = priority;
+    }
+
+    if new_name != name {
+        exit_client.exits.remove(name);
+        if exit_client.current_exit.as_ref().map(|s| s.as_str()) == Some(name) {
+            exit_client.current_exit = Some(new_name.clone());
+        }
+        for rule in exit_client.split_tunnel.iter_mut() {
+            if let SplitTunnelAction::Exit(exit) = &mut rule.action {
+                if exit == name {
+                    *exit = new_name.clone();
+                }
+            }
+        }
+    }
+    exit_client.exits.insert(new_name.clone(), exit);
+    Ok((new_name, new_id))
+}
+
+pub fn delete_exit(req: (Path<String>, Query<DeleteExitQuery>)) -> Result<HttpResponse, Error> {
+    let (path, query) = req;
+    let exit_name = path.into_inner();
+    debug!("/exits/{} DELETE hit with {:?}", exit_name, query);
+
+    let mut exit_client = SETTING.get_exit_client_mut();
+    let was_selected = exit_client.current_exit.as_ref() == Some(&exit_name);
+    remove_exit(&mut exit_client, &exit_name, query.into_inner().replacement)?;
+    info!("Deleted exit {:?}", exit_name);
+    drop(exit_client);
+
+    // the tunnel is set up again to the replacement
+    if was_selected {
+        if let Err(e) = KI.del_interface("wg_exit") {
+            error!("Failed to delete wg_exit {:?}", e)
+        };
+    }
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+    Ok(HttpResponse::Ok().json(()))
+}
+
+pub fn patch_exit(req: (Path<String>, Json<ExitEdit>)) -> Result<HttpResponse, Error> {
+    let (path, edit) = req;
+    let exit_name = path.into_inner();
+    debug!("/exits/{} PATCH hit with {:?}", exit_name, edit);
+
+    let mut exit_client = SETTING.get_exit_client_mut();
+    let (new_name, new_id) = edit_exit(&mut exit_client, &exit_name, edit.into_inner())?;
+    info!("Edited exit {:?}, now named {:?}", exit_name, new_name);
+    let is_selected = exit_client.current_exit.as_ref() == Some(&new_name);
+    let exit = exit_client.exits[&new_name].clone();
+    drop(exit_client);
+
+    // the tunnel is to an exit we aren't registered with anymore
+    if new_id && is_selected {
+        if let Err(e) = KI.del_interface("wg_exit") {
+            error!("Failed to delete wg_exit {:?}", e)
+        };
+    }
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+    Ok(HttpResponse::Ok().json(exit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use settings::client::SplitTunnelRule;
+
+    fn exit(last: u8) -> ExitServer {
+        ExitServer {
+            id: Identity {
+                mesh_ip: format!("fd00::{}", last).parse().unwrap(),
+                eth_address: format!("0x{:040x}", last).parse().unwrap(),
+                wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                    .parse()
+                    .unwrap(),
+                nickname: None,
+            },
+            registration_port: 4875,
+            description: String::new(),
+            info: ExitState::New,
+            accepted_terms: None,
+            region: None,
+            priority: 0,
+            announcements: Vec::new(),
+            seen_announcement: 0,
+            signup_passphrase: None,
+            restored: false,
+        }
+    }
+
+    fn exit_client() -> ExitClientSettings {
+        let mut exit_client = ExitClientSettings::default();
+        exit_client.exits.insert("a".to_string(), exit(1));
+        exit_client.exits.insert("b".to_string(), exit(2));
+        exit_client.current_exit = Some("a".to_string());
+        exit_client
+    }
+
+    #[test]
+    fn test_remove_exit() {
+        let mut exit_client = exit_client();
+        assert!(remove_exit(&mut exit_client, "c", None).is_err());
+        // the current exit needs a real replacement
+        assert!(remove_exit(&mut exit_client, "a", None).is_err());
+        assert!(remove_exit(&mut exit_client, "a", Some("a".to_string())).is_err());
+        assert!(remove_exit(&mut exit_client, "a", Some("c".to_string())).is_err());
+        assert_eq!(exit_client.exits.len(), 2);
+
+        exit_client.split_tunnel.push(SplitTunnelRule {
+            destination: "8.8.0.0/16".parse().unwrap(),
+            action: SplitTunnelAction::Exit("b".to_string()),
+        });
+        assert!(remove_exit(&mut exit_client, "b", None).is_err());
+        exit_client.split_tunnel.clear();
+        assert_eq!(remove_exit(&mut exit_client, "b", None), Ok(exit(2)));
+
+        exit_client.exits.insert("b".to_string(), exit(2));
+        assert!(remove_exit(&mut exit_client, "a", Some("b".to_string())).is_ok());
+        assert_eq!(exit_client.current_exit, Some("b".to_string()));
+        assert!(!exit_client.exits.contains_key("a"));
+    }
+
+    #[test]
+    fn test_edit_exit() {
+        let mut exit_client = exit_client();
+        exit_client.exits.get_mut("a").unwrap().info = ExitState::Denied {
+            message: "Not in service area".to_string(),
+        };
+        exit_client.split_tunnel.push(SplitTunnelRule {
+            destination: "8.8.0.0/16".parse().unwrap(),
+            action: SplitTunnelAction::Exit("a".to_string()),
+        });
+
+        let rename = |nickname: &str| ExitEdit {
+            nickname: Some(nickname.to_string()),
+            ..ExitEdit::default()
+        };
+        assert!(edit_exit(&mut exit_client, "c", ExitEdit::default()).is_err());
+        assert!(edit_exit(&mut exit_client, "a", rename("b")).is_err());
+        assert!(edit_exit(&mut exit_client, "a", rename("")).is_err());
+
+        let edit = ExitEdit {
+            nickname: Some("us_west".to_string()),
+            region: Some("us-west".to_string()),
+            priority: Some(2),
+            ..ExitEdit::default()
+        };
+        assert_eq!(
+            edit_exit(&mut exit_client, "a", edit),
+            Ok(("us_west".to_string(), false))
+        );
+        assert_eq!(exit_client.current_exit, Some("us_west".to_string()));
+        assert_eq!(
+            exit_client.split_tunnel[0].action,
+            SplitTunnelAction::Exit("us_west".to_string())
+        );
+        let edited = &exit_client.exits["us_west"];
+        assert_eq!(edited.region, Some("us-west".to_string()));
+        assert_eq!(edited.priority, 2);
+        // the registration is kept through a rename
+        assert_ne!(edited.info, ExitState::New);
+        assert!(!exit_client.exits.contains_key("a"));
+
+        let edit = ExitEdit {
+            id: Some(exit(3).id),
+            region: Some(String::new()),
+            ..ExitEdit::default()
+        };
+        assert_eq!(
+            edit_exit(&mut exit_client, "us_west", edit),
+            Ok(("us_west".to_string(), true))
+        );
+        let edited = &exit_client.exits["us_west"];
+        assert_eq!(edited.info, ExitState::New);
+        assert_eq!(edited.region, None);
+    }
+}
@@ -1,4 +1,6 @@
 //! A generalized interface for modifying networking interface assignments using UCI
+use crate::rita_common::dashboard::error::DashboardError;
+use crate::rita_common::peer_listener::Listen;
 use crate::rita_common::peer_listener::PeerListener;
 use crate::rita_common::peer_listener::UnListen;
 use crate::ARGS;
@@ -127,31 +129,79 @@ pub fn ethernet2mode(ifname: &str, setting_name: &str) -> Result<InterfaceMode,
     })
 }
 
-fn set_interface_mode(iface_name: &str, mode: InterfaceMode) -> Result<(), Error> {
+fn set_interface_mode(
+    iface_name: &str,
+    mode: InterfaceMode,
+    ingress: Option<&str>,
+) -> Result<(), Error> {
     trace!("InterfaceToSet recieved");
-    let iface_name = iface_name;
     let target_mode = mode;
     let interfaces = get_interfaces()?;
     let current_mode = get_current_interface_mode(&interfaces, iface_name);
     if !interfaces.contains_key(iface_name) {
-        bail!("Attempted to configure non-existant or unavailable interface!");
-    } else if target_mode == InterfaceMode::WAN {
+        return Err(DashboardError::invalid_input(
+            "Attempted to configure non-existant or unavailable interface!",
+        )
+        .into());
+    } else if is_wan(target_mode) {
         // we can only have one WAN interface, check for others
-        // StaticWAN entires are not identified seperately but if they ever are
-        // you'll have to handle them here
-        for entry in interfaces {
-            let mode = entry.1;
-            if mode == InterfaceMode::WAN {
-                bail!("There can only be one WAN interface!");
+        for (iface, mode) in interfaces.iter() {
+            if iface != iface_name && is_wan(*mode) {
+                return Err(
+                    DashboardError::invalid_input("There can only be one WAN interface!").into(),
+                );
             }
         }
     }
+    if let Some(ingress) = ingress {
+        check_dashboard_port(iface_name, current_mode, target_mode, ingress)?;
+    }
 
     trace!("Transforming ethernet");
     ethernet_transform_mode(iface_name, current_mode, target_mode)
 }
 
-/// Transform a wired inteface from mode A to mode B
+fn is_wan(mode: InterfaceMode) -> bool {
+    match mode {
+        InterfaceMode::WAN | InterfaceMode::StaticWAN { .. } => true,
+        _ => false,
+    }
+}
+
+/// Refuses changes that would cut off the dashboard connection they were made over, `ingress`
+/// is the interface the request came in on
+fn check_dashboard_port(
+    ifname: &str,
+    current: InterfaceMode,
+    target: InterfaceMode,
+    ingress: &str,
+) -> Result<(), Error> {
+    if current == target {
+        return Ok(());
+    }
+    let cut_off = if ingress == ifname {
+        true
+    } else if ingress == "br-lan" {
+        // we couldn't tell which lan port the request came from
+        current == InterfaceMode::LAN
+    } else if ingress.starts_with("wg") {
+        // tunnels can run over any mesh port
+        current == InterfaceMode::Mesh
+    } else {
+        false
+    };
+    if cut_off {
+        return Err(DashboardError::invalid_input(format!(
+            "Changing {} would cut off this dashboard connection, use another port",
+            ifname
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Transform a wired inteface from mode A to mode B, the UCI changes are only committed if all
+/// of them succeed and Rita's settings are only updated once they have been
 pub fn ethernet_transform_mode(
     ifname: &str,
     a: InterfaceMode,
@@ -172,15 +222,11 @@ pub fn ethernet_transform_mode(
 
     // if we have edited UCI and it fails we set this var to handle cleanup later
     let mut return_codes = Vec::new();
-    // in case of failure we revert to here
-    let old_network_settings = { SETTING.get_network().clone() };
     let filtered_ifname = format!("network.rita_{}", ifname.replace(".", ""));
 
     match a {
         // Wan is very simple, just delete it
         InterfaceMode::WAN | InterfaceMode::StaticWAN { .. } => {
-            SETTING.get_network_mut().external_nic = None;
-
             let ret = KI.del_uci_var("network.backhaul");
             return_codes.push(ret);
         }
@@ -192,13 +238,8 @@ pub fn ethernet_transform_mode(
             let ret = KI.set_uci_var("network.lan.ifname", &new_list);
             return_codes.push(ret);
         }
-        // for mesh we need to send an unlisten so that Rita stops
-        // listening then we can remove the section, we also need to remove it
-        // from the config
+        // for mesh we remove the section, Rita stops listening once it's committed
         InterfaceMode::Mesh => {
-            PeerListener::from_registry().do_send(UnListen(ifname.to_string()));
-            SETTING.get_network_mut().peer_interfaces.remove(ifname);
-
             let ret = KI.del_uci_var(&filtered_ifname);
             return_codes.push(ret);
         }
@@ -208,8 +249,6 @@ pub fn ethernet_transform_mode(
     match b {
         // here we add back all the properties of backhaul we removed
         InterfaceMode::WAN => {
-            SETTING.get_network_mut().external_nic = Some(ifname.to_string());
-
             let ret = KI.set_uci_var("network.backhaul", "interface");
             return_codes.push(ret);
             let ret = KI.set_uci_var("network.backhaul.ifname", ifname);
@@ -222,8 +261,6 @@ pub fn ethernet_transform_mode(
             ipaddr,
             gateway,
         } => {
-            SETTING.get_network_mut().external_nic = Some(ifname.to_string());
-
             let ret = KI.set_uci_var("network.backhaul", "interface");
             return_codes.push(ret);
             let ret = KI.set_uci_var("network.backhaul.ifname", ifname);
@@ -262,11 +299,6 @@ pub fn ethernet_transform_mode(
             }
         }
         InterfaceMode::Mesh => {
-            SETTING
-                .get_network_mut()
-                .peer_interfaces
-                .insert(ifname.to_string());
-
             let ret = KI.set_uci_var(&filtered_ifname, "interface");
             return_codes.push(ret);
             let ret = KI.set_uci_var(&format!("{}.ifname", filtered_ifname), ifname);
@@ -286,19 +318,36 @@ pub fn ethernet_transform_mode(
     }
     if error_occured {
         let res = KI.uci_revert("network");
-        *SETTING.get_network_mut() = old_network_settings;
         bail!("Error running UCI commands! Revert attempted: {:?}", res);
     }
 
     KI.uci_commit(&"network")?;
     KI.openwrt_reset_network()?;
+
+    if is_wan(a) {
+        SETTING.get_network_mut().external_nic = None;
+    }
+    if is_wan(b) {
+        SETTING.get_network_mut().external_nic = Some(ifname.to_string());
+    }
+    // the listener binds to the interface once it's up if it isn't yet
+    if a == InterfaceMode::Mesh {
+        PeerListener::from_registry().do_send(UnListen(ifname.to_string()));
+        SETTING.get_network_mut().peer_interfaces.remove(ifname);
+    }
+    if b == InterfaceMode::Mesh {
+        SETTING
+            .get_network_mut()
+            .peer_interfaces
+            .insert(ifname.to_string());
+        PeerListener::from_registry().do_send(Listen(ifname.to_string()));
+    }
     SETTING.write().unwrap().write(&ARGS.flag_config)?;
 
     // We edited disk contents, force global sync
     KI.fs_sync()?;
 
-    trace!("Successsfully transformed ethernet mode, rebooting");
-    KI.run_command("reboot", &[])?;
+    trace!("Successsfully transformed ethernet mode");
 
     Ok(())
 }
@@ -311,7 +360,7 @@ pub fn ethernet_transform_mode(
 
 fn wlan_toggle_get(uci_spec: &str) -> Result<bool, Error> {
     if !KI.is_openwrt() {
-        bail!("Not an OpenWRT device!");
+        return Err(DashboardError::not_openwrt().into());
     }
     let bad_wireless = "Wireless config not correct";
     let current_state = KI.uci_show(Some(uci_spec))?;
@@ -336,31 +385,31 @@ fn wlan_toggle_get(uci_spec: &str) -> Result<bool, Error> {
     Ok(current_state)
 }
 
-pub fn wlan_mesh_get(_: HttpRequest) -> HttpResponse {
+pub fn wlan_mesh_get(_: HttpRequest) -> Result<HttpResponse, Error> {
     let res = wlan_toggle_get("wireless.mesh.disabled");
     match res {
-        Ok(b) => HttpResponse::Ok().json(b),
+        Ok(b) => Ok(HttpResponse::Ok().json(b)),
         Err(e) => {
             error!("get mesh failed with {:?}", e);
-            HttpResponse::InternalServerError().into()
+            Err(e)
         }
     }
 }
 
-pub fn wlan_lightclient_get(_: HttpRequest) -> HttpResponse {
+pub fn wlan_lightclient_get(_: HttpRequest) -> Result<HttpResponse, Error> {
     let res = wlan_toggle_get("wireless.lightclient.disabled");
     match res {
-        Ok(b) => HttpResponse::Ok().json(b),
+        Ok(b) => Ok(HttpResponse::Ok().json(b)),
         Err(e) => {
             error!("get lightclient failed with {:?}", e);
-            HttpResponse::InternalServerError().into()
+            Err(e)
         }
     }
 }
 
 fn wlan_toggle_set(uci_spec: &str, enabled: bool) -> Result<(), Error> {
     if !KI.is_openwrt() {
-        bail!("Not an OpenWRT device!");
+        return Err(DashboardError::not_openwrt().into());
     }
     let bad_wireless = "Wireless config not correct";
     trace!("wlan toggle: uci_spec {}, enabled: {}", uci_spec, enabled,);
@@ -409,26 +458,26 @@ fn wlan_toggle_set(uci_spec: &str, enabled: bool) -> Result<(), Error> {
     Ok(())
 }
 
-pub fn wlan_mesh_set(enabled: Path<bool>) -> HttpResponse {
+pub fn wlan_mesh_set(enabled: Path<bool>) -> Result<HttpResponse, Error> {
     let enabled = enabled.into_inner();
     let res = wlan_toggle_set("wireless.mesh.disabled", enabled);
     match res {
-        Ok(_) => HttpResponse::Ok().into(),
+        Ok(_) => Ok(HttpResponse::Ok().into()),
         Err(e) => {
             error!("set mesh failed with {:?}", e);
-            HttpResponse::InternalServerError().into()
+            Err(e)
         }
     }
 }
 
-pub fn wlan_lightclient_set(enabled: Path<bool>) -> HttpResponse {
+pub fn wlan_lightclient_set(enabled: Path<bool>) -> Result<HttpResponse, Error> {
     let enabled = enabled.into_inner();
     let res = wlan_toggle_set("wireless.lightclient.disabled", enabled);
     match res {
-        Ok(_) => HttpResponse::Ok().into(),
+        Ok(_) => Ok(HttpResponse::Ok().into()),
         Err(e) => {
             error!("set lightclient failed with {:?}", e);
-            HttpResponse::InternalServerError().into()
+            Err(e)
         }
     }
 }
@@ -505,6 +554,23 @@ mod tests {
         assert_eq!(b, "");
     }
 
+    #[test]
+    fn test_check_dashboard_port() {
+        use InterfaceMode::*;
+
+        // the port we're connected through
+        assert!(check_dashboard_port("eth1", LAN, Mesh, "eth1").is_err());
+        assert!(check_dashboard_port("eth1", LAN, LAN, "eth1").is_ok());
+        assert!(check_dashboard_port("eth2", LAN, Mesh, "eth1").is_ok());
+        // somewhere on the lan bridge, but we don't know where
+        assert!(check_dashboard_port("eth2", LAN, WAN, "br-lan").is_err());
+        assert!(check_dashboard_port("eth2", Mesh, LAN, "br-lan").is_ok());
+        // over a tunnel
+        assert!(check_dashboard_port("eth2", Mesh, LAN, "wg0").is_err());
+        assert!(check_dashboard_port("eth2", WAN, Mesh, "wg0").is_ok());
+        assert!(check_dashboard_port("eth0", WAN, LAN, "lo").is_ok());
+    }
+
     #[test]
     fn test_list_add() {
         let a = "";
@@ -533,15 +599,47 @@ pub fn get_interfaces_endpoint(
     }
 }
 
-pub fn set_interfaces_endpoint(interface: Json<InterfaceToSet>) -> HttpResponse {
+/// The interface a dashboard request came in on, None if we can't tell who made it
+fn request_ingress(req: &HttpRequest) -> Result<Option<String>, Error> {
+    match req.peer_addr() {
+        Some(addr) => Ok(Some(KI.get_ingress_port(addr.ip())?)),
+        None => Ok(None),
+    }
+}
+
+pub fn set_interfaces_endpoint(
+    (interface, req): (Json<InterfaceToSet>, HttpRequest),
+) -> Result<HttpResponse, Error> {
     let interface = interface.into_inner();
     debug!("set /interfaces hit");
 
-    match set_interface_mode(&interface.interface, interface.mode) {
-        Ok(_) => HttpResponse::Ok().into(),
+    let ingress = request_ingress(&req)?;
+    match set_interface_mode(
+        &interface.interface,
+        interface.mode,
+        ingress.as_ref().map(String::as_str),
+    ) {
+        Ok(_) => Ok(HttpResponse::Ok().into()),
         Err(e) => {
             error!("Set interfaces failed with {:?}", e);
-            HttpResponse::InternalServerError().into()
+            Err(e)
+        }
+    }
+}
+
+pub fn set_interface_mode_endpoint(
+    (iface, mode, req): (Path<String>, Json<InterfaceMode>, HttpRequest),
+) -> Result<HttpResponse, Error> {
+    let iface = iface.into_inner();
+    let mode = mode.into_inner();
+    debug!("/interfaces/{}/mode hit with {:?}", iface, mode);
+
+    let ingress = request_ingress(&req)?;
+    match set_interface_mode(&iface, mode, ingress.as_ref().map(String::as_str)) {
+        Ok(_) => Ok(HttpResponse::Ok().into()),
+        Err(e) => {
+            error!("Set interface mode failed with {:?}", e);
+            Err(e)
         }
     }
 }
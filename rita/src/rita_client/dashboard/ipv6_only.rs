@@ -0,0 +1,57 @@
+use crate::rita_client::exit_manager::lan_dns64_resolver;
+use crate::ARGS;
+use crate::KI;
+use crate::SETTING;
+use actix_web::{HttpRequest, HttpResponse, Json, Path};
+use althea_types::Nat64Details;
+use failure::Error;
+use settings::client::RitaClientSettings;
+use settings::FileWrite;
+use std::net::IpAddr;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Ipv6OnlyStatus {
+    /// if the user asked for an ipv6 only LAN
+    pub enabled: bool,
+    /// the DNS64 resolver handed out on the LAN, none while it's dual stack
+    pub dns64_resolver: Option<IpAddr>,
+    /// what the current exit offers, none if it can't translate to ipv4 for us
+    pub exit_nat64: Option<Nat64Details>,
+}
+
+pub fn get_ipv6_only(_req: HttpRequest) -> Result<Json<Ipv6OnlyStatus>, Error> {
+    debug!("Get ipv6 only hit!");
+    let exit_client = SETTING.get_exit_client();
+    let exit_nat64 = exit_client
+        .get_current_exit()
+        .and_then(|exit| exit.info.general_details())
+        .and_then(|details| details.nat64);
+    Ok(Json(Ipv6OnlyStatus {
+        enabled: exit_client.ipv6_only_lan,
+        dns64_resolver: KI.get_lan_ipv6_only(),
+        exit_nat64,
+    }))
+}
+
+/// Reconfigures the LAN right away if we're signed up with an exit, otherwise it's done once
+/// the exit tunnel comes up
+pub fn set_ipv6_only(path: Path<bool>) -> Result<HttpResponse, Error> {
+    let value = path.into_inner();
+    debug!("Set ipv6 only hit!");
+    SETTING.get_exit_client_mut().ipv6_only_lan = value;
+
+    let current_exit = SETTING.get_exit_client().get_current_exit().cloned();
+    if let Some(exit) = current_exit {
+        if let (Some(general_details), Some(our_details)) =
+            (exit.info.general_details(), exit.info.our_details())
+        {
+            KI.set_lan_ipv6_only(lan_dns64_resolver(general_details, our_details))?;
+        }
+    }
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+    Ok(HttpResponse::Ok().json(()))
+}
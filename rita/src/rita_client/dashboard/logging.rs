@@ -1,7 +1,7 @@
+use crate::rita_common::dashboard::error::DashboardError;
 use crate::ARGS;
 use crate::KI;
 use crate::SETTING;
-use actix_web::http::StatusCode;
 use actix_web::{HttpRequest, HttpResponse, Path};
 use failure::Error;
 use log::LevelFilter;
@@ -42,9 +42,9 @@ pub fn remote_logging_level(path: Path<String>) -> Result<HttpResponse, Error> {
     let log_level: LevelFilter = match level.parse() {
         Ok(level) => level,
         Err(e) => {
-            return Ok(HttpResponse::new(StatusCode::BAD_REQUEST)
-                .into_builder()
-                .json(format!("Could not parse loglevel {:?}", e)));
+            return Err(
+                DashboardError::invalid_input(format!("Could not parse loglevel {:?}", e)).into(),
+            );
         }
     };
 
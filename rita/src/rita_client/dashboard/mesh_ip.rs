@@ -1,3 +1,4 @@
+use crate::rita_common::dashboard::error::DashboardError;
 use crate::ARGS;
 use crate::KI;
 use crate::SETTING;
@@ -20,7 +21,7 @@ pub fn get_mesh_ip(_req: HttpRequest) -> Result<HttpResponse, Error> {
         None => {
             let error_msg = "No mesh IP configured yet";
             warn!("{}", error_msg);
-            ret.insert("error".to_owned(), error_msg.to_owned());
+            return Err(DashboardError::not_ready(error_msg).into());
         }
     }
 
@@ -30,8 +31,6 @@ pub fn get_mesh_ip(_req: HttpRequest) -> Result<HttpResponse, Error> {
 pub fn set_mesh_ip(mesh_ip_data: Json<HashMap<String, String>>) -> Result<HttpResponse, Error> {
     debug!("/mesh_ip POST hit");
 
-    let mut ret = HashMap::new();
-
     match mesh_ip_data.into_inner().get("mesh_ip") {
         Some(ip_str) => match ip_str.parse::<IpAddr>() {
             Ok(parsed) => {
@@ -43,23 +42,22 @@ pub fn set_mesh_ip(mesh_ip_data: Json<HashMap<String, String>>) -> Result<HttpRe
                     parsed
                 );
                     info!("{}", error_msg);
-                    ret.insert("error".to_owned(), error_msg);
+                    return Err(DashboardError::invalid_input(error_msg).into());
                 }
             }
             Err(e) => {
                 let error_msg = format!(
-                    "set_mesh_ip: Failed to parse the address string {:?}",
-                    ip_str
+                    "set_mesh_ip: Failed to parse the address string {:?} {}",
+                    ip_str, e
                 );
                 info!("{}", error_msg);
-                ret.insert("error".to_owned(), error_msg);
-                ret.insert("rust_error".to_owned(), e.to_string());
+                return Err(DashboardError::invalid_input(error_msg).into());
             }
         },
         None => {
             let error_msg = "set_mesh_ip: \"mesh_ip\" not found in supplied JSON";
             info!("{}", error_msg);
-            ret.insert("error".to_owned(), error_msg.to_string());
+            return Err(DashboardError::invalid_input(error_msg).into());
         }
     }
 
@@ -73,5 +71,5 @@ pub fn set_mesh_ip(mesh_ip_data: Json<HashMap<String, String>>) -> Result<HttpRe
     }
 
     // Note: This will never be reached
-    Ok(HttpResponse::Ok().json(ret))
+    Ok(HttpResponse::Ok().json(json!({})))
 }
@@ -0,0 +1,237 @@
+//! A picture of the mesh beyond our immediate neighbors for the dashboard to render. Babel only
+//! tells us the next hop for each destination along with the metric and price of the route, so
+//! the map is a tree rooted at us, with edges from us to each neighbor and from each neighbor to
+//! the destinations we route through it. That's not the full topology of the mesh but it is the
+//! path our traffic actually takes.
+
+use crate::rita_common::route_cache::get_routes;
+use crate::rita_common::tunnel_manager::{GetNeighbors, Neighbor, TunnelManager};
+use crate::SETTING;
+use actix::SystemService;
+use actix_web::{AsyncResponder, HttpRequest, Json};
+use babel_monitor::Route;
+use failure::Error;
+use futures01::{future, Future};
+use ipnetwork::IpNetwork;
+use settings::RitaCommonSettings;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MeshMapNode {
+    pub ip: IpAddr,
+    pub nickname: Option<String>,
+    /// true for our own node
+    pub local: bool,
+    pub neighbor: bool,
+    /// the fee this node charges in wei/byte, we only know this for neighbors and ourselves
+    pub fee: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MeshMapEdge {
+    pub from: IpAddr,
+    pub to: IpAddr,
+    pub metric: u16,
+    /// price in wei/byte of the route from `from` to `to`
+    pub price: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MeshMap {
+    pub nodes: Vec<MeshMapNode>,
+    pub edges: Vec<MeshMapEdge>,
+}
+
+pub fn get_mesh_map(_req: HttpRequest) -> Box<dyn Future<Item = Json<MeshMap>, Error = Error>> {
+    debug!("/mesh_map GET hit");
+    let network = SETTING.get_network();
+    let us = match network.mesh_ip {
+        Some(ip) => MeshMapNode {
+            ip,
+            nickname: network.nickname.map(|n| n.to_string()),
+            local: true,
+            neighbor: false,
+            fee: Some(SETTING.get_payment().local_fee),
+        },
+        None => return Box::new(future::err(format_err!("No mesh ip configured yet"))),
+    };
+    drop(network);
+
+    TunnelManager::from_registry()
+        .send(GetNeighbors)
+        .from_err()
+        .and_then(move |neighbors| {
+            let neighbors = neighbors?;
+            Ok(get_routes()
+                .and_then(move |cached| Ok(Json(build_mesh_map(us, &neighbors, &cached.routes)))))
+        })
+        .flatten()
+        .responder()
+}
+
+fn is_host_route(prefix: &IpNetwork) -> bool {
+    match prefix {
+        IpNetwork::V4(net) => net.prefix() == 32,
+        IpNetwork::V6(net) => net.prefix() == 128,
+    }
+}
+
+fn build_mesh_map(us: MeshMapNode, neighbors: &[Neighbor], routes: &[Route]) -> MeshMap {
+    let our_ip = us.ip;
+    let mut nodes: HashMap<IpAddr, MeshMapNode> = HashMap::new();
+    let mut edges = Vec::new();
+    nodes.insert(our_ip, us);
+
+    // there may be several tunnels to a single neighbor, babel routes over one of them
+    let mut by_iface = HashMap::new();
+    for neigh in neighbors {
+        let id = neigh.identity.global;
+        by_iface.insert(neigh.iface_name.as_str(), id.mesh_ip);
+        nodes.insert(
+            id.mesh_ip,
+            MeshMapNode {
+                ip: id.mesh_ip,
+                nickname: id.nickname.map(|n| n.to_string()),
+                local: false,
+                neighbor: true,
+                fee: neigh.identity.local_fee,
+            },
+        );
+    }
+
+    let installed: Vec<&Route> = routes
+        .iter()
+        .filter(|r| r.installed && is_host_route(&r.prefix) && r.prefix.ip() != our_ip)
+        .collect();
+
+    // the price of reaching each neighbor, subtracted from the price of routes through them
+    let mut neighbor_prices = HashMap::new();
+    for route in installed.iter() {
+        if let Some(hop) = by_iface.get(route.iface.as_str()) {
+            if *hop == route.prefix.ip() {
+                neighbor_prices.insert(*hop, route.price);
+                edges.push(MeshMapEdge {
+                    from: our_ip,
+                    to: *hop,
+                    metric: route.metric,
+                    price: route.price,
+                });
+            }
+        }
+    }
+    for route in installed.iter() {
+        let dest = route.prefix.ip();
+        let hop = match by_iface.get(route.iface.as_str()) {
+            Some(hop) if *hop != dest => *hop,
+            _ => continue,
+        };
+        nodes.entry(dest).or_insert(MeshMapNode {
+            ip: dest,
+            nickname: None,
+            local: false,
+            neighbor: false,
+            fee: None,
+        });
+        let hop_price = neighbor_prices.get(&hop).cloned().unwrap_or(0);
+        edges.push(MeshMapEdge {
+            from: hop,
+            to: dest,
+            metric: route.refmetric,
+            price: route.price.saturating_sub(hop_price),
+        });
+    }
+
+    let mut nodes: Vec<MeshMapNode> = nodes.drain().map(|(_k, v)| v).collect();
+    nodes.sort_by(|a, b| a.ip.cmp(&b.ip));
+    MeshMap { nodes, edges }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rita_common::utils::test_identity;
+    use althea_types::{FeatureFlags, LocalIdentity};
+
+    fn neighbor(ip: &str, iface: &str) -> Neighbor {
+        let id = test_identity(ip);
+        Neighbor {
+            identity: LocalIdentity {
+                wg_port: 65535,
+                have_tunnel: Some(true),
+                global: id,
+                local_fee: Some(10),
+                auth: None,
+                features: FeatureFlags::default(),
+            },
+            iface_name: iface.to_string(),
+            tunnel_ip: "fe80::1".parse().unwrap(),
+            speed_limit: None,
+            reputation: 1.0,
+        }
+    }
+
+    fn route(prefix: &str, iface: &str, metric: u16, refmetric: u16, price: u32) -> Route {
+        Route {
+            id: String::new(),
+            iface: iface.to_string(),
+            xroute: false,
+            installed: true,
+            neigh_ip: "fe80::1".parse().unwrap(),
+            prefix: prefix.parse().unwrap(),
+            metric,
+            refmetric,
+            full_path_rtt: 0.0,
+            price,
+            fee: 0,
+        }
+    }
+
+    #[test]
+    fn test_build_mesh_map() {
+        let us: IpAddr = "fd00::1".parse().unwrap();
+        let neighbors = vec![neighbor("fd00::2", "wg0"), neighbor("fd00::2", "wg1")];
+        let mut not_installed = route("fd00::4/128", "wg0", 500, 400, 30);
+        not_installed.installed = false;
+        let routes = vec![
+            route("fd00::2/128", "wg1", 96, 0, 10),
+            route("fd00::3/128", "wg1", 352, 256, 25),
+            route("fd00::1/128", "wg1", 192, 96, 20),
+            route("::/0", "wg1", 400, 300, 50),
+            not_installed,
+        ];
+        let local = MeshMapNode {
+            ip: us,
+            nickname: None,
+            local: true,
+            neighbor: false,
+            fee: Some(5),
+        };
+        let map = build_mesh_map(local, &neighbors, &routes);
+
+        let ips: Vec<IpAddr> = map.nodes.iter().map(|n| n.ip).collect();
+        assert_eq!(
+            ips,
+            vec![us, "fd00::2".parse().unwrap(), "fd00::3".parse().unwrap()]
+        );
+        assert!(map.nodes[0].local);
+        assert!(map.nodes[1].neighbor);
+        assert_eq!(
+            map.edges,
+            vec![
+                MeshMapEdge {
+                    from: us,
+                    to: "fd00::2".parse().unwrap(),
+                    metric: 96,
+                    price: 10,
+                },
+                MeshMapEdge {
+                    from: "fd00::2".parse().unwrap(),
+                    to: "fd00::3".parse().unwrap(),
+                    metric: 256,
+                    price: 15,
+                }
+            ]
+        );
+    }
+}
@@ -4,19 +4,29 @@
 //!
 //! For more documentation on specific functions see the router-dashboard file in the docs folder
 
+pub mod announcements;
+pub mod backup;
 pub mod backup_created;
+pub mod captive_portal;
 pub mod eth_private_key;
 pub mod exits;
 pub mod interfaces;
+pub mod ipv6_only;
 pub mod localization;
 pub mod logging;
 pub mod mesh_ip;
+pub mod mesh_map;
 pub mod neighbors;
 pub mod notifications;
 pub mod prices;
+pub mod protective_mode;
 pub mod release_feed;
 pub mod remote_access;
+pub mod remote_management;
 pub mod router;
+pub mod split_tunnel;
 pub mod system_chain;
+pub mod topup;
 pub mod usage;
+pub mod wan;
 pub mod wifi;
@@ -1,5 +1,6 @@
 use crate::rita_common::debt_keeper::{DebtKeeper, Dump, NodeDebtData};
 use crate::rita_common::network_monitor::{GetStats, IfaceStats, NetworkMonitor, Stats};
+use crate::rita_common::route_cache;
 use crate::rita_common::tunnel_manager::{GetNeighbors, Neighbor, TunnelManager};
 use crate::SETTING;
 use actix::SystemService;
@@ -9,15 +10,11 @@ use althea_types::Identity;
 use arrayvec::ArrayString;
 use babel_monitor::get_installed_route;
 use babel_monitor::get_route_via_neigh;
-use babel_monitor::open_babel_stream;
-use babel_monitor::parse_routes;
-use babel_monitor::start_connection;
 use babel_monitor::Route;
 use failure::Error;
 use futures01::Future;
 use num256::{Int256, Uint256};
 use settings::client::RitaClientSettings;
-use settings::RitaCommonSettings;
 use std::collections::HashMap;
 
 #[derive(Serialize)]
@@ -37,18 +34,9 @@ pub struct NodeInfo {
 }
 
 pub fn get_routes(_req: HttpRequest) -> Box<dyn Future<Item = Json<Vec<Route>>, Error = Error>> {
-    let babel_port = SETTING.get_network().babel_port;
-    Box::new(
-        open_babel_stream(babel_port)
-            .from_err()
-            .and_then(move |stream| {
-                start_connection(stream).and_then(move |stream| {
-                    parse_routes(stream)
-                        .and_then(|(_stream, routes)| Ok(Json(routes)))
-                        .responder()
-                })
-            }),
-    )
+    route_cache::get_routes()
+        .and_then(|cached| Ok(Json(cached.routes)))
+        .responder()
 }
 
 /// Gets info about neighbors, including interested data about what their route
@@ -73,33 +61,23 @@ pub fn get_neighbor_info(
 
                         let combined_list = merge_debts_and_neighbors(neighbors, debts);
 
-                        let babel_port = SETTING.get_network().babel_port;
-
-                        open_babel_stream(babel_port)
-                            .from_err()
-                            .and_then(move |stream| {
-                                start_connection(stream).and_then(move |stream| {
-                                    parse_routes(stream)
-                                        .and_then(|(_stream, routes)| {
-                                            let route_table_sample = routes;
-
-                                            NetworkMonitor::from_registry()
-                                                .send(GetStats {})
-                                                .from_err()
-                                                .and_then(|stats| {
-                                                    let stats = stats.unwrap();
-                                                    let output = generate_neighbors_list(
-                                                        stats,
-                                                        route_table_sample,
-                                                        combined_list,
-                                                    );
-
-                                                    Ok(Json(output))
-                                                })
-                                        })
-                                        .responder()
+                        route_cache::get_routes().and_then(|cached| {
+                            let route_table_sample = cached.routes;
+
+                            NetworkMonitor::from_registry()
+                                .send(GetStats {})
+                                .from_err()
+                                .and_then(|stats| {
+                                    let stats = stats.unwrap();
+                                    let output = generate_neighbors_list(
+                                        stats,
+                                        route_table_sample,
+                                        combined_list,
+                                    );
+
+                                    Ok(Json(output))
                                 })
-                            })
+                        })
                     })
             }),
     )
@@ -1,15 +1,18 @@
 use crate::rita_client::traffic_watcher::GetExitDestPrice;
 use crate::rita_client::traffic_watcher::TrafficWatcher;
+use crate::rita_common::tunnel_manager::{GetNeighbors, TunnelManager};
 use crate::ARGS;
 use crate::SETTING;
 use actix::SystemService;
 use actix_web::Path;
-use actix_web::{HttpRequest, HttpResponse, Json, Result};
+use actix_web::{AsyncResponder, HttpRequest, HttpResponse, Json, Result};
+use althea_types::Identity;
 use failure::Error;
 use futures01::Future;
 use num256::Uint256;
 use settings::FileWrite;
 use settings::RitaCommonSettings;
+use std::collections::HashMap;
 
 pub fn auto_pricing_status(_req: HttpRequest) -> Result<Json<bool>, Error> {
     debug!("Get Auto pricing enabled hit!");
@@ -51,3 +54,122 @@ pub fn get_prices(_req: HttpRequest) -> Box<dyn Future<Item = Json<Prices>, Erro
     });
     Box::new(b)
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct NeighborPrice {
+    id: Identity,
+    nickname: Option<String>,
+    /// None if the neighbor is running a version that doesn't advertise its fee
+    local_fee: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct NeighborPrices {
+    our_fee: u32,
+    /// the median fee advertised by our neighbors
+    median_neighbor_fee: Option<u32>,
+    /// true if we charge more than most of our neighbors
+    uncompetitive: bool,
+    neighbors: Vec<NeighborPrice>,
+}
+
+/// Lists the fees our neighbors advertise in their hellos alongside our own
+pub fn get_neighbor_prices(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<NeighborPrices>, Error = Error>> {
+    debug!("/prices/neighbors GET hit");
+    TunnelManager::from_registry()
+        .send(GetNeighbors)
+        .from_err()
+        .and_then(|neighbors| {
+            let neighbors = neighbors?;
+            // there may be several tunnels to a single neighbor
+            let mut by_id: HashMap<Identity, NeighborPrice> = HashMap::new();
+            for neigh in neighbors {
+                let id = neigh.identity.global;
+                by_id.insert(
+                    id,
+                    NeighborPrice {
+                        id,
+                        nickname: id.nickname.map(|n| n.to_string()),
+                        local_fee: neigh.identity.local_fee,
+                    },
+                );
+            }
+            let neighbors: Vec<NeighborPrice> = by_id.drain().map(|(_k, v)| v).collect();
+
+            let our_fee = SETTING.get_payment().local_fee;
+            let median_neighbor_fee = median_fee(&neighbors);
+            let uncompetitive = match median_neighbor_fee {
+                Some(median) => our_fee > median,
+                None => false,
+            };
+            if uncompetitive {
+                warn!(
+                    "Our fee {} is higher than the median neighbor fee {:?}",
+                    our_fee, median_neighbor_fee
+                );
+            }
+
+            Ok(Json(NeighborPrices {
+                our_fee,
+                median_neighbor_fee,
+                uncompetitive,
+                neighbors,
+            }))
+        })
+        .responder()
+}
+
+/// The median of the fees neighbors advertise, with an even count it's the mean of the middle
+/// two rounded down. Neighbors that don't advertise a fee are left out
+fn median_fee(neighbors: &[NeighborPrice]) -> Option<u32> {
+    let mut fees: Vec<u32> = neighbors.iter().filter_map(|n| n.local_fee).collect();
+    if fees.is_empty() {
+        return None;
+    }
+    fees.sort();
+    let middle = fees.len() / 2;
+    if fees.len() % 2 == 0 {
+        // summed as u64 so that two large fees can't overflow
+        let sum = u64::from(fees[middle - 1]) + u64::from(fees[middle]);
+        Some((sum / 2) as u32)
+    } else {
+        Some(fees[middle])
+    }
+}
+
+#[test]
+fn test_median_fee() {
+    use crate::rita_common::utils::test_identity;
+
+    let neighbors = |fees: &[Option<u32>]| -> Vec<NeighborPrice> {
+        fees.iter()
+            .map(|fee| NeighborPrice {
+                id: test_identity("fd00::1"),
+                nickname: None,
+                local_fee: *fee,
+            })
+            .collect()
+    };
+
+    assert_eq!(median_fee(&[]), None);
+    // neighbors too old to advertise a fee don't count
+    assert_eq!(median_fee(&neighbors(&[None, None])), None);
+    assert_eq!(
+        median_fee(&neighbors(&[Some(30), Some(10), Some(20)])),
+        Some(20)
+    );
+    assert_eq!(
+        median_fee(&neighbors(&[Some(40), Some(10), Some(30), Some(20)])),
+        Some(25)
+    );
+    assert_eq!(
+        median_fee(&neighbors(&[Some(10), None, Some(20), None])),
+        Some(15)
+    );
+    assert_eq!(
+        median_fee(&neighbors(&[Some(std::u32::MAX), Some(std::u32::MAX)])),
+        Some(std::u32::MAX)
+    );
+}
@@ -0,0 +1,58 @@
+use crate::rita_client::protective_mode::{
+    GetProtectiveModeStatus, ProtectiveMode, ProtectiveModeStatus,
+};
+use crate::rita_common::dashboard::error::DashboardError;
+use crate::ARGS;
+use crate::SETTING;
+use actix::SystemService;
+use actix_web::{AsyncResponder, HttpRequest, HttpResponse, Json};
+use failure::Error;
+use futures01::Future;
+use num256::Uint256;
+use settings::client::RitaClientSettings;
+use settings::FileWrite;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProtectiveModeUpdate {
+    pub enabled: Option<bool>,
+    pub threshold: Option<Uint256>,
+    pub trickle_mbps: Option<usize>,
+}
+
+pub fn get_protective_mode(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<ProtectiveModeStatus>, Error = Error>> {
+    trace!("get_protective_mode: Hit");
+    ProtectiveMode::from_registry()
+        .send(GetProtectiveModeStatus)
+        .from_err()
+        .and_then(move |reply| Ok(Json(reply?)))
+        .responder()
+}
+
+/// Changes take effect on the next client loop tick
+pub fn set_protective_mode(update: Json<ProtectiveModeUpdate>) -> Result<HttpResponse, Error> {
+    debug!("Set protective mode hit!");
+    let update = update.into_inner();
+    if update.trickle_mbps == Some(0) {
+        return Err(DashboardError::invalid_input("The trickle rate can't be 0").into());
+    }
+    {
+        let mut protective_mode = SETTING.get_protective_mode_mut();
+        if let Some(enabled) = update.enabled {
+            protective_mode.enabled = enabled;
+        }
+        if let Some(threshold) = update.threshold {
+            protective_mode.threshold = threshold;
+        }
+        if let Some(trickle_mbps) = update.trickle_mbps {
+            protective_mode.trickle_mbps = trickle_mbps;
+        }
+    }
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+    Ok(HttpResponse::Ok().json(()))
+}
@@ -1,5 +1,5 @@
+use crate::rita_common::dashboard::error::DashboardError;
 use crate::KI;
-use actix_web::http::StatusCode;
 use actix_web::HttpRequest;
 use actix_web::HttpResponse;
 use actix_web::Path;
@@ -9,7 +9,7 @@ use failure::Error;
 
 pub fn get_release_feed_http(_req: HttpRequest) -> Result<HttpResponse, Error> {
     if !KI.is_openwrt() {
-        return Ok(HttpResponse::new(StatusCode::BAD_REQUEST));
+        return Err(DashboardError::not_openwrt().into());
     }
     let res = get_release_feed()?;
     Ok(HttpResponse::Ok().json(res))
@@ -17,23 +17,20 @@ pub fn get_release_feed_http(_req: HttpRequest) -> Result<HttpResponse, Error> {
 
 pub fn set_release_feed_http(path: Path<String>) -> Result<HttpResponse, Error> {
     if !KI.is_openwrt() {
-        return Ok(HttpResponse::new(StatusCode::BAD_REQUEST));
+        return Err(DashboardError::not_openwrt().into());
     }
 
     let val = path.into_inner().parse();
     if val.is_err() {
-        return Ok(HttpResponse::new(StatusCode::BAD_REQUEST)
-            .into_builder()
-            .json(format!(
-                "Could not parse {:?} into a ReleaseStatus enum!",
-                val
-            )));
+        return Err(DashboardError::invalid_input(format!(
+            "Could not parse {:?} into a ReleaseStatus enum!",
+            val
+        ))
+        .into());
     }
     let val = val.unwrap();
     if let Err(e) = set_release_feed(val) {
-        return Ok(HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
-            .into_builder()
-            .json(format!("Failed to write new release feed with {:?}", e)));
+        bail!("Failed to write new release feed with {:?}", e);
     }
 
     Ok(HttpResponse::Ok().json(()))
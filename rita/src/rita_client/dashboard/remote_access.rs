@@ -1,6 +1,6 @@
+use crate::rita_common::dashboard::error::DashboardError;
 use crate::KI;
 use crate::SETTING;
-use actix_web::http::StatusCode;
 use actix_web::HttpRequest;
 use actix_web::HttpResponse;
 use actix_web::Path;
@@ -14,7 +14,7 @@ static FIREWALL_CONFIG: &str = "/etc/config/firewall";
 
 pub fn get_remote_access_status(_req: HttpRequest) -> Result<HttpResponse, Error> {
     if !KI.is_openwrt() {
-        return Ok(HttpResponse::new(StatusCode::BAD_REQUEST));
+        return Err(DashboardError::not_openwrt().into());
     }
     let lines = get_lines(DROPBEAR_CONFIG)?;
     for line in lines.iter() {
@@ -0,0 +1,9 @@
+use crate::rita_client::remote_management::{audit_log, AuditEntry};
+use actix_web::{HttpRequest, Json};
+use failure::Error;
+
+/// Every command the operator has sent us and what came of it, oldest first
+pub fn get_remote_management_audit(_req: HttpRequest) -> Result<Json<Vec<AuditEntry>>, Error> {
+    trace!("get_remote_management_audit: Hit");
+    Ok(Json(audit_log()?))
+}
@@ -0,0 +1,33 @@
+use crate::rita_client::split_tunnel::validate_rules;
+use crate::rita_common::dashboard::error::DashboardError;
+use crate::ARGS;
+use crate::SETTING;
+use actix_web::{HttpRequest, HttpResponse, Json};
+use failure::Error;
+use settings::client::{RitaClientSettings, SplitTunnelRule};
+use settings::FileWrite;
+
+pub fn get_split_tunnel(_req: HttpRequest) -> Result<Json<Vec<SplitTunnelRule>>, Error> {
+    debug!("Get split tunnel hit!");
+    Ok(Json(SETTING.get_exit_client().split_tunnel.clone()))
+}
+
+/// Replaces every rule, the routes change on the next exit manager tick
+pub fn set_split_tunnel(rules: Json<Vec<SplitTunnelRule>>) -> Result<HttpResponse, Error> {
+    debug!("Set split tunnel hit!");
+    let rules = validate_rules(rules.into_inner(), &SETTING.get_exits());
+    let rules = match rules {
+        Ok(rules) => rules,
+        Err(e) => {
+            info!("Invalid split tunnel rules {}", e);
+            return Err(DashboardError::invalid_input(e.to_string()).into());
+        }
+    };
+    SETTING.get_exit_client_mut().split_tunnel = rules;
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+    Ok(HttpResponse::Ok().json(()))
+}
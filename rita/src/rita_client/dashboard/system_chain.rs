@@ -1,6 +1,6 @@
+use crate::rita_common::dashboard::error::DashboardError;
 use crate::ARGS;
 use crate::SETTING;
-use ::actix_web::http::StatusCode;
 use ::actix_web::Path;
 use ::actix_web::{HttpRequest, HttpResponse};
 use althea_types::SystemChain;
@@ -19,9 +19,11 @@ pub fn set_system_blockchain(path: Path<String>) -> Result<HttpResponse, Error>
     info!("Blockchain change endpoint hit!");
     let id: Result<SystemChain, ()> = path.into_inner().parse();
     if id.is_err() {
-        return Ok(HttpResponse::new(StatusCode::BAD_REQUEST)
-            .into_builder()
-            .json(format!("Could not parse {:?} into a SystemChain enum!", id)));
+        return Err(DashboardError::invalid_input(format!(
+            "Could not parse {:?} into a SystemChain enum!",
+            id
+        ))
+        .into());
     }
     let id = id.unwrap();
 
@@ -0,0 +1,49 @@
+use crate::rita_client::topup::{
+    chain_id, payment_uri, GetTopUpStatus, StartTopUp, TopUp, TopUpAddress, TopUpStatus,
+};
+use crate::rita_common::dashboard::error::DashboardError;
+use crate::SETTING;
+use actix::SystemService;
+use actix_web::{AsyncResponder, HttpRequest, Json};
+use failure::Error;
+use futures01::Future;
+use settings::RitaCommonSettings;
+
+/// Where to send money to top up this router, `uri` is meant to be shown as a QR code
+pub fn get_topup_address(_req: HttpRequest) -> Result<Json<TopUpAddress>, Error> {
+    debug!("/topup GET hit");
+    let payment = SETTING.get_payment();
+    let address = match payment.eth_address {
+        Some(address) => address,
+        None => return Err(DashboardError::not_ready("No eth address configured yet").into()),
+    };
+    Ok(Json(TopUpAddress {
+        address,
+        system_chain: payment.system_chain,
+        chain_id: chain_id(payment.system_chain),
+        uri: payment_uri(&address, payment.system_chain),
+    }))
+}
+
+/// Starts watching the chain for a deposit, call again to keep watching
+pub fn start_topup_watch(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<TopUpStatus>, Error = Error>> {
+    debug!("/topup/watch POST hit");
+    TopUp::from_registry()
+        .send(StartTopUp)
+        .from_err()
+        .and_then(move |reply| Ok(Json(reply?)))
+        .responder()
+}
+
+pub fn get_topup_status(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<TopUpStatus>, Error = Error>> {
+    trace!("/topup/status GET hit");
+    TopUp::from_registry()
+        .send(GetTopUpStatus)
+        .from_err()
+        .and_then(move |reply| Ok(Json(reply?)))
+        .responder()
+}
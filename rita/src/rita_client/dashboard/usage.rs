@@ -1,21 +1,29 @@
 use crate::rita_common::usage_tracker::GetUsage;
+use crate::rita_common::usage_tracker::UsageGranularity;
 use crate::rita_common::usage_tracker::UsageHour;
 use crate::rita_common::usage_tracker::UsageTracker;
 use crate::rita_common::usage_tracker::UsageType;
 use ::actix::registry::SystemService;
-use ::actix_web::{AsyncResponder, HttpRequest, Json};
+use ::actix_web::{AsyncResponder, Json, Query};
 use failure::Error;
 use futures01::Future;
 use std::boxed::Box;
 use std::collections::VecDeque;
 
+#[derive(Deserialize)]
+pub struct UsageQuery {
+    #[serde(default)]
+    granularity: UsageGranularity,
+}
+
 pub fn get_client_usage(
-    _req: HttpRequest,
+    query: Query<UsageQuery>,
 ) -> Box<dyn Future<Item = Json<VecDeque<UsageHour>>, Error = Error>> {
-    trace!("/usage/relay hit");
+    trace!("/usage/client hit");
     UsageTracker::from_registry()
         .send(GetUsage {
             kind: UsageType::Client,
+            granularity: query.granularity,
         })
         .from_err()
         .and_then(|reply| Ok(Json(reply?)))
@@ -23,12 +31,27 @@ pub fn get_client_usage(
 }
 
 pub fn get_relay_usage(
-    _req: HttpRequest,
+    query: Query<UsageQuery>,
 ) -> Box<dyn Future<Item = Json<VecDeque<UsageHour>>, Error = Error>> {
     trace!("/usage/relay hit");
     UsageTracker::from_registry()
         .send(GetUsage {
             kind: UsageType::Relay,
+            granularity: query.granularity,
+        })
+        .from_err()
+        .and_then(|reply| Ok(Json(reply?)))
+        .responder()
+}
+
+pub fn get_sponsored_usage(
+    query: Query<UsageQuery>,
+) -> Box<dyn Future<Item = Json<VecDeque<UsageHour>>, Error = Error>> {
+    trace!("/usage/sponsored hit");
+    UsageTracker::from_registry()
+        .send(GetUsage {
+            kind: UsageType::Sponsored,
+            granularity: query.granularity,
         })
         .from_err()
         .and_then(|reply| Ok(Json(reply?)))
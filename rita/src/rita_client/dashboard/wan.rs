@@ -0,0 +1,15 @@
+use crate::rita_client::wan_manager::{GetWanStatus, WanManager, WanStatus};
+use actix::SystemService;
+use actix_web::{AsyncResponder, HttpRequest, Json};
+use failure::Error;
+use futures01::Future;
+
+/// Reports which uplink is currently carrying our internet traffic
+pub fn get_wan_status(_req: HttpRequest) -> Box<dyn Future<Item = Json<WanStatus>, Error = Error>> {
+    trace!("get_wan_status: Hit");
+    WanManager::from_registry()
+        .send(GetWanStatus)
+        .from_err()
+        .and_then(move |reply| Ok(Json(reply?)))
+        .responder()
+}
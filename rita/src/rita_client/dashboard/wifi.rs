@@ -1,14 +1,25 @@
 //! These endpoints are used to modify mundane wireless settings
 
+use crate::rita_client::traffic_watcher::{
+    GetSponsoredUsage, SponsoredNetworkChanged, SponsoredUsage, TrafficWatcher,
+};
+use crate::rita_common::dashboard::error::{DashboardError, ErrorCode};
+use crate::ARGS;
 use crate::KI;
 use crate::SETTING;
-use ::actix_web::http::StatusCode;
+use ::actix::SystemService;
 use ::actix_web::Path;
-use ::actix_web::{HttpRequest, HttpResponse, Json};
+use ::actix_web::{AsyncResponder, HttpRequest, HttpResponse, Json};
+use althea_kernel_interface::WifiStation;
 use failure::Error;
+use futures01::Future;
 use serde_json::Value;
+use settings::client::RitaClientSettings;
+use settings::sponsored_network::SponsoredNetworkSettings;
+use settings::FileWrite;
 use settings::RitaCommonSettings;
 use std::collections::HashMap;
+use std::net::Ipv4Addr;
 
 /// legal in the US and around the world, don't allow odd channels
 pub const ALLOWED_TWO: [u16; 3] = [1, 6, 11];
@@ -76,11 +87,27 @@ pub struct WifiChannel {
     pub channel: u16,
 }
 
+/// None puts the radio back to its driver's default power
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct WifiTxPower {
+    pub radio: String,
+    /// in dBm
+    pub txpower: Option<u8>,
+}
+
+/// Applies to every radio
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct WifiCountry {
+    pub country: String,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum WifiToken {
     WifiChannel(WifiChannel),
     WifiSSID(WifiSSID),
     WifiPass(WifiPass),
+    WifiTxPower(WifiTxPower),
+    WifiCountry(WifiCountry),
 }
 
 /// A string of characters which we don't let users use because of corrupted UCI configs
@@ -88,6 +115,17 @@ static FORBIDDEN_CHARS: &str = "'/\"\\";
 
 static MINIMUM_PASS_CHARS: usize = 8;
 
+/// Power is never recommended below this, most radios are barely useful under it
+const MIN_TXPOWER_DBM: u8 = 5;
+/// The signal we'd like the furthest client or mesh peer of a radio to be heard at, any more is
+/// just interference for the routers around us
+const TARGET_SIGNAL_DBM: i32 = -65;
+
+/// Our address on the guest network, guests are handed addresses in the /24 around it
+const GUEST_ROUTER_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 20, 1);
+/// Our address on the sponsored network
+const SPONSORED_ROUTER_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 30, 1);
+
 /// A helper error type for displaying UCI config value validation problems human-readably.
 #[derive(Debug, Fail, Serialize)]
 pub enum ValidationError {
@@ -104,6 +142,19 @@ pub enum ValidationError {
     WrongRadio,
     #[fail(display = "Value too short ({} required)", _0)]
     TooShort(usize),
+    #[fail(display = "A bandwidth limit must be at least 1mbit")]
+    ZeroLimit,
+    #[fail(display = "A daily cap must be more than zero")]
+    ZeroCap,
+    #[fail(
+        display = "Transmit power must be between {} and {} dBm for this channel here",
+        _0, _1
+    )]
+    BadTxPower(u8, u8),
+    #[fail(display = "Channel {} isn't allowed in {}", _0, _1)]
+    ChannelNotAllowed(u16, String),
+    #[fail(display = "{} isn't a two letter country code", _0)]
+    BadCountry(String),
 }
 
 pub fn set_wifi_ssid(wifi_ssid: Json<WifiSSID>) -> Result<HttpResponse, Error> {
@@ -113,15 +164,10 @@ pub fn set_wifi_ssid(wifi_ssid: Json<WifiSSID>) -> Result<HttpResponse, Error> {
     set_ssid(&wifi_ssid)
 }
 
-fn set_ssid(wifi_ssid: &WifiSSID) -> Result<HttpResponse, Error> {
-    let mut ret: HashMap<String, String> = HashMap::new();
-
+pub fn set_ssid(wifi_ssid: &WifiSSID) -> Result<HttpResponse, Error> {
     if let Err(e) = validate_config_value(&wifi_ssid.ssid) {
         info!("Setting of invalid SSID was requested: {}", e);
-        ret.insert("error".to_owned(), format!("{}", e));
-        return Ok(HttpResponse::new(StatusCode::BAD_REQUEST)
-            .into_builder()
-            .json(ret));
+        return Err(DashboardError::invalid_input(e.to_string()).into());
     }
 
     // think radio0, radio1
@@ -136,7 +182,7 @@ fn set_ssid(wifi_ssid: &WifiSSID) -> Result<HttpResponse, Error> {
     // We edited disk contents, force global sync
     KI.fs_sync()?;
 
-    Ok(HttpResponse::Ok().json(ret))
+    Ok(HttpResponse::Ok().json(json!({})))
 }
 
 pub fn set_wifi_pass(wifi_pass: Json<WifiPass>) -> Result<HttpResponse, Error> {
@@ -147,25 +193,17 @@ pub fn set_wifi_pass(wifi_pass: Json<WifiPass>) -> Result<HttpResponse, Error> {
 }
 
 fn set_pass(wifi_pass: &WifiPass) -> Result<HttpResponse, Error> {
-    let mut ret = HashMap::new();
-
     let wifi_pass_len = wifi_pass.pass.len();
     if wifi_pass_len < MINIMUM_PASS_CHARS {
-        ret.insert(
-            "error".to_owned(),
-            format!("{}", ValidationError::TooShort(MINIMUM_PASS_CHARS)),
-        );
-        return Ok(HttpResponse::new(StatusCode::BAD_REQUEST)
-            .into_builder()
-            .json(ret));
+        return Err(DashboardError::invalid_input(
+            ValidationError::TooShort(MINIMUM_PASS_CHARS).to_string(),
+        )
+        .into());
     }
 
     if let Err(e) = validate_config_value(&wifi_pass.pass) {
         info!("Setting of invalid SSID was requested: {}", e);
-        ret.insert("error".to_owned(), format!("{}", e));
-        return Ok(HttpResponse::new(StatusCode::BAD_REQUEST)
-            .into_builder()
-            .json(ret));
+        return Err(DashboardError::invalid_input(e.to_string()).into());
     }
 
     // think radio0, radio1
@@ -196,10 +234,8 @@ fn set_channel(wifi_channel: &WifiChannel) -> Result<HttpResponse, Error> {
     let channel_width = KI.get_uci_var(&format!("wireless.{}.htmode", wifi_channel.radio))?;
 
     if let Err(e) = validate_channel(current_channel, wifi_channel.channel, &channel_width) {
-        info!("Setting of invalid SSID was requested: {}", e);
-        return Ok(HttpResponse::new(StatusCode::BAD_REQUEST)
-            .into_builder()
-            .json("Invalid SSID!"));
+        info!("Setting of invalid channel was requested: {}", e);
+        return Err(DashboardError::invalid_input(e.to_string()).into());
     }
 
     KI.set_uci_var(
@@ -214,6 +250,374 @@ fn set_channel(wifi_channel: &WifiChannel) -> Result<HttpResponse, Error> {
     Ok(HttpResponse::Ok().json(()))
 }
 
+/// The center frequency of a wifi channel in MHz
+fn channel_mhz(channel: u16) -> u32 {
+    match channel {
+        14 => 2484,
+        1..=13 => 2407 + 5 * u32::from(channel),
+        _ => 5000 + 5 * u32::from(channel),
+    }
+}
+
+/// The channel a radio is on, asking the driver when it's set to pick one itself. None if it's
+/// set to 'auto' and none of its interfaces are up to say which it picked
+fn radio_channel(radio: &str) -> Result<Option<u16>, Error> {
+    let channel = KI.get_uci_var(&format!("wireless.{}.channel", radio))?;
+    if channel.trim() != "auto" {
+        return Ok(Some(channel.trim().parse()?));
+    }
+    for iface in radio_ifaces(radio)? {
+        if let Some(channel) = KI.get_wifi_channel(&iface).unwrap_or(None) {
+            return Ok(Some(channel));
+        }
+    }
+    Ok(None)
+}
+
+/// The most power that may be set on a radio for what it radiates to stay under the domain's
+/// limit, the antenna's gain is added on top of what's set
+fn conducted_limit(max_eirp: u8, antenna_gain: u8) -> u8 {
+    max_eirp.saturating_sub(antenna_gain)
+}
+
+/// The most power this radio may be set to on its current channel, by the regulatory domain the
+/// drivers are enforcing and the antenna gain configured for it. When the radio picks its own
+/// channel and hasn't yet it's the lowest limit of any channel it could pick
+fn max_txpower(radio: &str) -> Result<u8, Error> {
+    let domain = KI.get_wifi_regdomain()?;
+    let max_eirp = match radio_channel(radio)? {
+        Some(channel) => match domain.max_eirp(channel_mhz(channel)) {
+            Some(max) => max,
+            None => {
+                return Err(DashboardError::invalid_input(
+                    ValidationError::ChannelNotAllowed(channel, domain.country).to_string(),
+                )
+                .into())
+            }
+        },
+        None => match domain.rules.iter().map(|rule| rule.max_eirp_dbm).min() {
+            Some(max) => max,
+            None => bail!(
+                "The {} regulatory domain allows no channels",
+                domain.country
+            ),
+        },
+    };
+    // OpenWrt's option, in dBi, unset for antennas with no gain to speak of
+    let antenna_gain = KI
+        .get_uci_var(&format!("wireless.{}.antenna_gain", radio))
+        .ok()
+        .and_then(|gain| gain.trim().parse().ok())
+        .unwrap_or(0);
+    Ok(conducted_limit(max_eirp, antenna_gain))
+}
+
+fn validate_txpower(txpower: u8, max: u8) -> Result<(), ValidationError> {
+    if txpower < MIN_TXPOWER_DBM || txpower > max {
+        return Err(ValidationError::BadTxPower(MIN_TXPOWER_DBM, max));
+    }
+    Ok(())
+}
+
+/// Uppercased if it's a valid country code, 00 is the world domain
+fn validate_country(country: &str) -> Result<String, ValidationError> {
+    let country = country.trim().to_uppercase();
+    let valid =
+        country.len() == 2 && (country == "00" || country.chars().all(|c| c.is_ascii_uppercase()));
+    if !valid {
+        return Err(ValidationError::BadCountry(country));
+    }
+    Ok(country)
+}
+
+/// The power that would have the weakest station on a radio heard at about
+/// `TARGET_SIGNAL_DBM`, assuming they hear us about as well as we hear them. Only lowers power
+/// where stations are close, as in dense deployments, and None with no stations to go by
+fn recommend_txpower(current: f32, weakest_signal: Option<i32>, max: u8) -> Option<u8> {
+    let recommended = current.round() as i32 + TARGET_SIGNAL_DBM - weakest_signal?;
+    Some(
+        recommended
+            .max(i32::from(MIN_TXPOWER_DBM))
+            .min(i32::from(max)) as u8,
+    )
+}
+
+/// The wireless interfaces on a radio, iw names radios phy0, phy1 where uci has radio0, radio1
+fn radio_ifaces(radio: &str) -> Result<Vec<String>, Error> {
+    let phy = radio.replace("radio", "phy");
+    Ok(KI
+        .get_wifi_ifaces()?
+        .into_iter()
+        .filter(|(iface_phy, _)| *iface_phy == phy)
+        .map(|(_, iface)| iface)
+        .collect())
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WifiTxPowerStatus {
+    pub radio: String,
+    /// what's set, None for the driver's default
+    pub txpower: Option<u8>,
+    /// what the radio is actually transmitting at, drivers cap what's set to what's legal
+    pub current_dbm: Option<f32>,
+    /// the most that may be set on the radio's channel, the legal limit less the antenna gain
+    pub max_dbm: Option<u8>,
+    /// None if there's nobody connected to go by
+    pub recommended_dbm: Option<u8>,
+}
+
+fn txpower_status(radio: String) -> Result<WifiTxPowerStatus, Error> {
+    let txpower = KI
+        .get_uci_var(&format!("wireless.{}.txpower", radio))
+        .ok()
+        .and_then(|txpower| txpower.parse().ok());
+    let max_dbm = match max_txpower(&radio) {
+        Ok(max) => Some(max),
+        Err(e) => {
+            warn!("Failed to get the power limit of {} {:?}", radio, e);
+            None
+        }
+    };
+    let mut current_dbm = None;
+    let mut weakest_signal: Option<i32> = None;
+    for iface in radio_ifaces(&radio)? {
+        if current_dbm.is_none() {
+            current_dbm = KI.get_wifi_txpower(&iface).unwrap_or(None);
+        }
+        for station in KI.get_wifi_stations(&iface).unwrap_or_default() {
+            if let Some(signal) = station.signal_dbm {
+                weakest_signal = Some(weakest_signal.map_or(signal, |weakest| weakest.min(signal)));
+            }
+        }
+    }
+    let recommended_dbm = match (current_dbm, max_dbm) {
+        (Some(current), Some(max)) => recommend_txpower(current, weakest_signal, max),
+        _ => None,
+    };
+    Ok(WifiTxPowerStatus {
+        radio,
+        txpower,
+        current_dbm,
+        max_dbm,
+        recommended_dbm,
+    })
+}
+
+/// The power of every radio, along with its legal limit and the power we'd recommend
+pub fn get_wifi_txpower(_req: HttpRequest) -> Result<Json<Vec<WifiTxPowerStatus>>, Error> {
+    debug!("Get /wifi_settings/txpower hit");
+    let mut ret = Vec::new();
+    for radio in wifi_radios()? {
+        ret.push(txpower_status(radio)?);
+    }
+    Ok(Json(ret))
+}
+
+pub fn set_wifi_txpower(wifi_txpower: Json<WifiTxPower>) -> Result<HttpResponse, Error> {
+    debug!("/wifi_settings/txpower hit with {:?}", wifi_txpower);
+
+    let wifi_txpower = wifi_txpower.into_inner();
+    set_txpower(&wifi_txpower)
+}
+
+fn set_txpower(wifi_txpower: &WifiTxPower) -> Result<HttpResponse, Error> {
+    let var = format!("wireless.{}.txpower", wifi_txpower.radio);
+    match wifi_txpower.txpower {
+        Some(txpower) => {
+            if let Err(e) = validate_txpower(txpower, max_txpower(&wifi_txpower.radio)?) {
+                info!("Setting of invalid transmit power was requested: {}", e);
+                return Err(DashboardError::invalid_input(e.to_string()).into());
+            }
+            KI.set_uci_var(&var, &txpower.to_string())?;
+        }
+        None => {
+            // not being set already is fine
+            let _ = KI.del_uci_var(&var);
+        }
+    }
+    KI.uci_commit(&"wireless")?;
+    KI.openwrt_reset_wireless()?;
+
+    // We edited disk contents, force global sync
+    KI.fs_sync()?;
+    Ok(HttpResponse::Ok().json(()))
+}
+
+pub fn get_wifi_country(_req: HttpRequest) -> Result<Json<WifiCountry>, Error> {
+    debug!("Get /wifi_settings/country hit");
+    Ok(Json(WifiCountry {
+        country: KI.get_wifi_regdomain()?.country,
+    }))
+}
+
+pub fn set_wifi_country(wifi_country: Json<WifiCountry>) -> Result<HttpResponse, Error> {
+    debug!("/wifi_settings/country hit with {:?}", wifi_country);
+
+    let wifi_country = wifi_country.into_inner();
+    set_country(&wifi_country)
+}
+
+/// Sets the country on every radio, the drivers then cap power and channels to what's legal
+/// there
+fn set_country(wifi_country: &WifiCountry) -> Result<HttpResponse, Error> {
+    let country = match validate_country(&wifi_country.country) {
+        Ok(country) => country,
+        Err(e) => {
+            info!("Setting of invalid country was requested: {}", e);
+            return Err(DashboardError::invalid_input(e.to_string()).into());
+        }
+    };
+    for radio in wifi_radios()? {
+        KI.set_uci_var(&format!("wireless.{}.country", radio), &country)?;
+    }
+    KI.uci_commit(&"wireless")?;
+    KI.openwrt_reset_wireless()?;
+
+    // We edited disk contents, force global sync
+    KI.fs_sync()?;
+    Ok(HttpResponse::Ok().json(()))
+}
+
+/// A wifi network for guests, on every radio and isolated from the LAN
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GuestNetwork {
+    pub enabled: bool,
+    #[serde(default)]
+    pub ssid: String,
+    /// the network is open if there's no key
+    #[serde(default)]
+    pub key: Option<String>,
+    /// in mbit/s
+    #[serde(default)]
+    pub bandwidth_limit: Option<usize>,
+}
+
+pub fn get_guest_network(_req: HttpRequest) -> Result<Json<GuestNetwork>, Error> {
+    debug!("Get /wifi_settings/guest hit");
+    // every radio gets the same guest network, so the first one tells us everything
+    let section = match KI.get_wifi_radios()?.first() {
+        Some(radio) => format!("wireless.guest_{}", radio),
+        None => bail!("No wifi radios found!"),
+    };
+    let ret = match KI.get_uci_var(&format!("{}.ssid", section)) {
+        Ok(ssid) => GuestNetwork {
+            enabled: true,
+            ssid,
+            key: KI.get_uci_var(&format!("{}.key", section)).ok(),
+            bandwidth_limit: KI.get_guest_bandwidth_limit(),
+        },
+        Err(_) => GuestNetwork {
+            enabled: false,
+            ssid: String::new(),
+            key: None,
+            bandwidth_limit: None,
+        },
+    };
+    Ok(Json(ret))
+}
+
+fn validate_guest_network(guest: &GuestNetwork) -> Result<(), ValidationError> {
+    validate_config_value(&guest.ssid)?;
+    if let Some(key) = &guest.key {
+        if key.len() < MINIMUM_PASS_CHARS {
+            return Err(ValidationError::TooShort(MINIMUM_PASS_CHARS));
+        }
+        validate_config_value(key)?;
+    }
+    if guest.bandwidth_limit == Some(0) {
+        return Err(ValidationError::ZeroLimit);
+    }
+    Ok(())
+}
+
+/// Creates, updates or removes the guest network
+pub fn set_guest_network(guest: Json<GuestNetwork>) -> Result<HttpResponse, Error> {
+    debug!("/wifi_settings/guest hit with {:?}", guest);
+    let guest = guest.into_inner();
+
+    if !guest.enabled {
+        KI.remove_guest_network()?;
+        KI.fs_sync()?;
+        return Ok(HttpResponse::Ok().json(()));
+    }
+
+    if let Err(e) = validate_guest_network(&guest) {
+        info!("Setting of invalid guest network was requested: {}", e);
+        return Err(DashboardError::invalid_input(e.to_string()).into());
+    }
+
+    KI.set_guest_network(
+        &guest.ssid,
+        guest.key.as_ref().map(|k| k.as_str()),
+        GUEST_ROUTER_IP,
+    )?;
+    // after the network is up, the bridge is recreated when it's changed
+    KI.set_guest_bandwidth_limit(guest.bandwidth_limit)?;
+
+    // We edited disk contents, force global sync
+    KI.fs_sync()?;
+    Ok(HttpResponse::Ok().json(()))
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SponsoredNetworkStatus {
+    #[serde(flatten)]
+    pub settings: SponsoredNetworkSettings,
+    pub usage: SponsoredUsage,
+}
+
+pub fn get_sponsored_network(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<SponsoredNetworkStatus>, Error = Error>> {
+    debug!("Get /wifi_settings/sponsored hit");
+    TrafficWatcher::from_registry()
+        .send(GetSponsoredUsage)
+        .from_err()
+        .and_then(|reply| {
+            Ok(Json(SponsoredNetworkStatus {
+                settings: SETTING.get_sponsored_network().clone(),
+                usage: reply?,
+            }))
+        })
+        .responder()
+}
+
+/// Creates, updates or removes the sponsored network, a cap change takes effect on the next
+/// client loop tick
+pub fn set_sponsored_network(
+    sponsored: Json<SponsoredNetworkSettings>,
+) -> Result<HttpResponse, Error> {
+    debug!("/wifi_settings/sponsored hit with {:?}", sponsored);
+    let sponsored = sponsored.into_inner();
+
+    let valid = validate_config_value(&sponsored.ssid).and_then(|_| match sponsored.daily_cap {
+        Some(0) => Err(ValidationError::ZeroCap),
+        _ => Ok(()),
+    });
+    if let Err(e) = valid {
+        info!("Setting of invalid sponsored network was requested: {}", e);
+        return Err(DashboardError::invalid_input(e.to_string()).into());
+    }
+
+    let current = SETTING.get_sponsored_network().clone();
+    if !sponsored.enabled {
+        if current.enabled {
+            KI.remove_sponsored_network()?;
+        }
+    } else if !current.enabled || current.ssid != sponsored.ssid {
+        KI.set_sponsored_network(&sponsored.ssid, SPONSORED_ROUTER_IP)?;
+        TrafficWatcher::from_registry().do_send(SponsoredNetworkChanged);
+    }
+    *SETTING.get_sponsored_network_mut() = sponsored;
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+    KI.fs_sync()?;
+    Ok(HttpResponse::Ok().json(()))
+}
+
 /// an endpoint that takes a series of wifi tokens in json format and applies them all at once
 /// the reason for this is that changing any setting while on wifi will disconnect the caller
 /// so in order to have all the changes 'take' we need to have a single endpoint for all changes
@@ -224,6 +628,8 @@ pub fn set_wifi_multi(wifi_changes: Json<Vec<WifiToken>>) -> Result<HttpResponse
             WifiToken::WifiChannel(val) => set_channel(val)?,
             WifiToken::WifiPass(val) => set_pass(val)?,
             WifiToken::WifiSSID(val) => set_ssid(val)?,
+            WifiToken::WifiTxPower(val) => set_txpower(val)?,
+            WifiToken::WifiCountry(val) => set_country(val)?,
         };
     }
     Ok(HttpResponse::Ok().json(()))
@@ -320,9 +726,7 @@ pub fn get_allowed_wifi_channels(radio: Path<String>) -> Result<HttpResponse, Er
     } else if five_channel_width.contains("160") {
         Ok(HttpResponse::Ok().json(ALLOWED_FIVE_160))
     } else {
-        Ok(HttpResponse::new(StatusCode::BAD_REQUEST)
-            .into_builder()
-            .json("Can't identify Radio!"))
+        Err(DashboardError::new(ErrorCode::Internal, "Can't identify Radio!").into())
     }
 }
 
@@ -348,6 +752,21 @@ fn validate_config_value(s: &str) -> Result<(), ValidationError> {
     }
 }
 
+/// The names of our radios, think radio0, radio1
+pub fn wifi_radios() -> Result<Vec<String>, Error> {
+    let config = KI.ubus_call("uci", "get", "{ \"config\": \"wireless\"}")?;
+    let val: Value = serde_json::from_str(&config)?;
+    let items = match val["values"].as_object() {
+        Some(i) => i,
+        None => return Err(format_err!("No \"values\" key parsed wifi config")),
+    };
+    Ok(items
+        .iter()
+        .filter(|(_, v)| v[".type"] == "wifi-device")
+        .map(|(k, _)| k.clone())
+        .collect())
+}
+
 pub fn get_wifi_config(_req: HttpRequest) -> Result<Json<Vec<WifiInterface>>, Error> {
     debug!("Get wificonfig hit!");
     let mut interfaces = Vec::new();
@@ -387,3 +806,88 @@ pub fn get_wifi_config(_req: HttpRequest) -> Result<Json<Vec<WifiInterface>>, Er
     }
     Ok(Json(interfaces))
 }
+
+/// A station associated with one of our wireless interfaces
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WifiClient {
+    pub mac: String,
+    pub signal_dbm: Option<i32>,
+    pub tx_bitrate_mbps: Option<f32>,
+    pub rx_bitrate_mbps: Option<f32>,
+    pub airtime_us: Option<u64>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub connected_time: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WifiRadioClients {
+    pub radio: String,
+    pub iface: String,
+    pub clients: Vec<WifiClient>,
+}
+
+impl From<WifiStation> for WifiClient {
+    fn from(station: WifiStation) -> WifiClient {
+        WifiClient {
+            mac: station.mac,
+            signal_dbm: station.signal_dbm,
+            tx_bitrate_mbps: station.tx_bitrate_mbps,
+            rx_bitrate_mbps: station.rx_bitrate_mbps,
+            airtime_us: station.airtime_us,
+            rx_bytes: station.rx_bytes,
+            tx_bytes: station.tx_bytes,
+            connected_time: station.connected_time,
+        }
+    }
+}
+
+/// Lists the stations associated with each wireless interface, read fresh from the driver
+/// on every request
+pub fn get_wifi_clients(_req: HttpRequest) -> Result<Json<Vec<WifiRadioClients>>, Error> {
+    debug!("/wifi_clients hit");
+    let mut ret = Vec::new();
+    for (radio, iface) in KI.get_wifi_ifaces()? {
+        let clients = match KI.get_wifi_stations(&iface) {
+            Ok(stations) => stations.into_iter().map(WifiClient::from).collect(),
+            Err(e) => {
+                warn!("Failed to get wifi clients for {} {:?}", iface, e);
+                Vec::new()
+            }
+        };
+        ret.push(WifiRadioClients {
+            radio,
+            iface,
+            clients,
+        });
+    }
+    Ok(Json(ret))
+}
+
+#[test]
+fn test_txpower() {
+    assert_eq!(channel_mhz(1), 2412);
+    assert_eq!(channel_mhz(11), 2462);
+    assert_eq!(channel_mhz(14), 2484);
+    assert_eq!(channel_mhz(36), 5180);
+    assert_eq!(channel_mhz(149), 5745);
+
+    assert!(validate_txpower(20, 23).is_ok());
+    assert!(validate_txpower(24, 23).is_err());
+    assert!(validate_txpower(1, 23).is_err());
+    // a 6dBi antenna leaves 24dBm of a 30dBm EIRP limit
+    assert_eq!(conducted_limit(30, 6), 24);
+    assert_eq!(conducted_limit(20, 30), 0);
+
+    assert_eq!(validate_country(" us").unwrap(), "US");
+    assert_eq!(validate_country("00").unwrap(), "00");
+    assert!(validate_country("USA").is_err());
+    assert!(validate_country("0A").is_err());
+
+    // everyone is heard 10dB louder than needed
+    assert_eq!(recommend_txpower(20.0, Some(-55), 30), Some(10));
+    // a far away station needs more, up to the limit
+    assert_eq!(recommend_txpower(20.0, Some(-75), 23), Some(23));
+    assert_eq!(recommend_txpower(6.0, Some(-30), 23), Some(MIN_TXPOWER_DBM));
+    assert_eq!(recommend_txpower(20.0, None, 23), None);
+}
@@ -0,0 +1,181 @@
+//! Announcements from exit operators, like notice of maintenance or a price change. Exits send
+//! their current announcements signed with every state update, we keep each one until it
+//! expires even if the exit stops sending it so the user doesn't miss it, and remember which ones
+//! the user has seen so the dashboard can point out new ones.
+
+use crate::rita_common::exit_terms::verify_announcement;
+use althea_types::{Announcement, ExitState};
+use failure::Error;
+use settings::client::ExitServer;
+use std::collections::HashMap;
+
+/// The most announcements kept per exit, the oldest are dropped first
+const MAX_ANNOUNCEMENTS: usize = 20;
+
+/// An announcement as shown on the dashboard
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExitAnnouncement {
+    /// the nickname of the exit it's from
+    pub exit: String,
+    #[serde(flatten)]
+    pub announcement: Announcement,
+    pub seen: bool,
+}
+
+/// Checks that all the announcements in an exit's response were signed by it, a response with
+/// forged announcements is rejected as a whole like one with forged terms
+pub fn verify_announcements(exit: &ExitServer, state: &ExitState) -> Result<(), Error> {
+    if let Some(details) = state.general_details() {
+        for signed in details.announcements.iter() {
+            verify_announcement(signed, exit.id.eth_address)?;
+        }
+    }
+    Ok(())
+}
+
+/// Adds the announcements in a verified response to the ones we are keeping for the exit and
+/// drops those that have expired
+pub fn store_announcements(exit: &mut ExitServer, state: &ExitState, now: u64) {
+    if let Some(details) = state.general_details() {
+        for signed in details.announcements.iter() {
+            let announcement = &signed.announcement;
+            let kept = exit
+                .announcements
+                .iter()
+                .position(|kept| kept.id == announcement.id);
+            match kept {
+                Some(index) => exit.announcements[index] = announcement.clone(),
+                None => {
+                    if announcement.id > exit.seen_announcement {
+                        info!("New announcement from exit {}", exit.id.mesh_ip);
+                    }
+                    exit.announcements.push(announcement.clone())
+                }
+            }
+        }
+    }
+    exit.announcements
+        .retain(|announcement| !announcement.is_expired(now));
+    exit.announcements
+        .sort_by_key(|announcement| announcement.id);
+    while exit.announcements.len() > MAX_ANNOUNCEMENTS {
+        exit.announcements.remove(0);
+    }
+}
+
+/// Every announcement we have that hasn't expired, newest first
+pub fn list_announcements(exits: &HashMap<String, ExitServer>, now: u64) -> Vec<ExitAnnouncement> {
+    let mut list: Vec<ExitAnnouncement> = exits
+        .iter()
+        .flat_map(|(name, exit)| {
+            exit.announcements
+                .iter()
+                .filter(move |announcement| !announcement.is_expired(now))
+                .map(move |announcement| ExitAnnouncement {
+                    exit: name.clone(),
+                    announcement: announcement.clone(),
+                    seen: announcement.id <= exit.seen_announcement,
+                })
+        })
+        .collect();
+    list.sort_by(|a, b| b.announcement.posted.cmp(&a.announcement.posted));
+    list
+}
+
+/// Marks everything we have from every exit as seen
+pub fn mark_announcements_seen(exits: &mut HashMap<String, ExitServer>) {
+    for exit in exits.values_mut() {
+        if let Some(newest) = exit.announcements.iter().map(|a| a.id).max() {
+            exit.seen_announcement = exit.seen_announcement.max(newest);
+        }
+    }
+}
+
+#[test]
+fn test_announcements() {
+    use crate::rita_common::exit_terms::sign_announcement;
+    use crate::rita_common::utils::test_identity;
+    use althea_types::{ExitDetails, ExitVerifMode, SystemChain};
+    use clarity::PrivateKey;
+
+    let key: PrivateKey = "fe1bfa04d82b1e5a73f3a2d1a6d4b0d0f0c1ef1d1ba3c5d3c0b1e0fa5b1c2d3e"
+        .parse()
+        .unwrap();
+    let announcement = |id: u64, expires: Option<u64>| Announcement {
+        id,
+        posted: id * 100,
+        expires,
+        message: format!("announcement {}", id),
+    };
+    let state = |announcements: Vec<Announcement>| ExitState::GotInfo {
+        general_details: ExitDetails {
+            server_internal_ip: "172.16.255.254".parse().unwrap(),
+            netmask: 12,
+            wg_exit_port: 59999,
+            exit_price: 50,
+            exit_currency: SystemChain::Xdai,
+            description: String::new(),
+            verif_mode: ExitVerifMode::Off,
+            shared_ipv4: false,
+            terms: None,
+            nat64: None,
+            load: None,
+            announcements: announcements
+                .into_iter()
+                .map(|a| sign_announcement(a, &key).unwrap())
+                .collect(),
+            port_blocks: Vec::new(),
+            signup_challenge: None,
+            time: None,
+        },
+        message: String::new(),
+        auto_register: false,
+        retry_after: None,
+    };
+    let mut id = test_identity("fd00::1");
+    id.eth_address = key.to_public_key().unwrap();
+    let mut exit = ExitServer {
+        id,
+        registration_port: 4875,
+        description: String::new(),
+        info: ExitState::New,
+        accepted_terms: None,
+        region: None,
+        priority: 0,
+        announcements: Vec::new(),
+        seen_announcement: 0,
+        signup_passphrase: None,
+        restored: false,
+    };
+
+    let first = state(vec![announcement(1, None), announcement(2, Some(1000))]);
+    assert!(verify_announcements(&exit, &first).is_ok());
+    store_announcements(&mut exit, &first, 500);
+    // the exit stopped sending the first one but we keep it
+    store_announcements(&mut exit, &state(vec![announcement(3, None)]), 600);
+    assert_eq!(exit.announcements.len(), 3);
+
+    let mut exits = HashMap::new();
+    exits.insert("a".to_string(), exit.clone());
+    let list = list_announcements(&exits, 600);
+    let ids: Vec<u64> = list.iter().map(|a| a.announcement.id).collect();
+    assert_eq!(ids, vec![3, 2, 1]);
+    assert!(list.iter().all(|a| !a.seen && a.exit == "a"));
+
+    mark_announcements_seen(&mut exits);
+    assert!(list_announcements(&exits, 600).iter().all(|a| a.seen));
+    // expired announcements are no longer listed or kept
+    assert_eq!(list_announcements(&exits, 1000).len(), 2);
+    store_announcements(&mut exit, &ExitState::New, 1000);
+    assert_eq!(exit.announcements.len(), 2);
+
+    let mut forged = state(vec![announcement(4, None)]);
+    if let ExitState::GotInfo {
+        ref mut general_details,
+        ..
+    } = forged
+    {
+        general_details.announcements[0].announcement.message = "forged".to_string();
+    }
+    assert!(verify_announcements(&exit, &forged).is_err());
+}
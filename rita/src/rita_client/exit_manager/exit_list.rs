@@ -0,0 +1,229 @@
+//! The exit lists that /exits/sync pulls from a url. A list is either the original flat map of
+//! nicknames to exits or a list of regional groups, each exit in a group is tagged with the
+//! group's region so that we can prefer exits close to us. Exits may also carry a priority to
+//! order them within a region. Lists can be served from anywhere, so unless the exit list key
+//! has been cleared only lists with a valid ed25519 signature from it are accepted, otherwise
+//! anyone who can tamper with the list could point us at their own exits. Signed lists carry a
+//! sequence number and one older than the last list we took is rejected, an old list with a
+//! valid signature would otherwise bring back exits that have since been dropped.
+//!
+//! Whatever the source, exits in a list are checked before they're taken and only describe the
+//! exit, our registration with it, the terms we've accepted and its announcements are kept from
+//! the exit we already had by that name, see `merge_exits`.
+
+use althea_types::{ExitState, SigningPubkey};
+use failure::Error;
+use settings::client::ExitServer;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// Longest exit nickname we take, they end up in dashboard urls
+const MAX_NICKNAME_LEN: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExitGroup {
+    /// None for exits that aren't in any particular region
+    #[serde(default)]
+    pub region: Option<String>,
+    pub exits: HashMap<String, ExitServer>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ExitList {
+    Grouped { groups: Vec<ExitGroup> },
+    Flat(HashMap<String, ExitServer>),
+}
+
+/// What a signed list signs, the list along with its place in the publisher's sequence
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SequencedExitList {
+    /// goes up with every list that's published
+    pub sequence: u64,
+    pub exits: ExitList,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedExitList {
+    /// the json encoded SequencedExitList, the signature is over these exact bytes
+    pub list: String,
+    pub signature: Vec<u8>,
+}
+
+impl ExitList {
+    /// All the exits by nickname with their group's region filled in, a nickname can only be
+    /// used once across all groups
+    pub fn into_exits(self) -> Result<HashMap<String, ExitServer>, Error> {
+        let exits = match self {
+            ExitList::Flat(exits) => exits,
+            ExitList::Grouped { groups } => {
+                let mut exits = HashMap::new();
+                for group in groups {
+                    for (nickname, mut exit) in group.exits {
+                        if exit.region.is_none() {
+                            exit.region = group.region.clone();
+                        }
+                        if exits.insert(nickname.clone(), exit).is_some() {
+                            bail!("Exit {} is listed more than once", nickname);
+                        }
+                    }
+                }
+                exits
+            }
+        };
+        for (nickname, exit) in exits.iter() {
+            validate_exit(nickname, exit)?;
+        }
+        Ok(exits)
+    }
+}
+
+fn validate_exit(nickname: &str, exit: &ExitServer) -> Result<(), Error> {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+    if nickname.is_empty() || nickname.len() > MAX_NICKNAME_LEN || !nickname.chars().all(valid_char)
+    {
+        bail!("Exit nickname {:?} isn't valid", nickname);
+    }
+    match exit.id.mesh_ip {
+        IpAddr::V6(ip) if !ip.is_unspecified() && !ip.is_loopback() && !ip.is_multicast() => {}
+        ip => bail!("Exit {} has an invalid mesh ip {}", nickname, ip),
+    }
+    if exit.registration_port == 0 {
+        bail!("Exit {} has no registration port", nickname);
+    }
+    Ok(())
+}
+
+/// Adds exits from a list to ours. Only what describes the exit is taken from the list, our
+/// registration, accepted terms and announcements are kept from the exit we already had by that
+/// name and start out empty otherwise, a list can't claim we're registered or have accepted
+/// anything
+pub fn merge_exits(exits: &mut HashMap<String, ExitServer>, new: HashMap<String, ExitServer>) {
+    for (nickname, mut exit) in new {
+        match exits.get(&nickname) {
+            Some(old) => {
+                exit.info = old.info.clone();
+                exit.accepted_terms = old.accepted_terms.clone();
+                exit.announcements = old.announcements.clone();
+                exit.seen_announcement = old.seen_announcement;
+                if exit.signup_passphrase.is_none() {
+                    exit.signup_passphrase = old.signup_passphrase.clone();
+                }
+            }
+            None => {
+                exit.info = ExitState::default();
+                exit.accepted_terms = None;
+                exit.announcements = Vec::new();
+                exit.seen_announcement = 0;
+            }
+        }
+        exits.insert(nickname, exit);
+    }
+}
+
+fn verify_list(
+    signed: &SignedExitList,
+    pubkey: &SigningPubkey,
+    last_sequence: u64,
+) -> Result<SequencedExitList, Error> {
+    if !pubkey.verify(signed.list.as_bytes(), &signed.signature) {
+        bail!("Exit list signature is invalid");
+    }
+    let list: SequencedExitList = serde_json::from_str(&signed.list)?;
+    // the list we already have can be synced again, it changes nothing
+    if list.sequence < last_sequence {
+        bail!(
+            "Exit list sequence {} is older than the last list we took, {}",
+            list.sequence,
+            last_sequence
+        );
+    }
+    Ok(list)
+}
+
+/// The exits in a list and, if it was signed, its sequence number to keep for the next sync
+pub fn parse_exit_list(
+    body: &[u8],
+    pubkey: Option<SigningPubkey>,
+    last_sequence: u64,
+) -> Result<(HashMap<String, ExitServer>, Option<u64>), Error> {
+    match pubkey {
+        Some(pubkey) => {
+            let list = verify_list(&serde_json::from_slice(body)?, &pubkey, last_sequence)?;
+            Ok((list.exits.into_exits()?, Some(list.sequence)))
+        }
+        None => {
+            let list: ExitList = serde_json::from_slice(body)?;
+            Ok((list.into_exits()?, None))
+        }
+    }
+}
+
+#[test]
+fn test_parse_exit_list() {
+    use crate::rita_common::utils::test_identity;
+    use sodiumoxide::crypto::sign;
+
+    let exit = |region: Option<&str>| {
+        json!({
+            "id": test_identity("fd00::1"),
+            "registration_port": 4875,
+            "state": "New",
+            "region": region,
+        })
+    };
+    let flat = json!({ "a": exit(None) }).to_string();
+    let exits = parse_exit_list(flat.as_bytes(), None, 0).unwrap().0;
+    assert_eq!(exits["a"].region, None);
+    assert_eq!(exits["a"].priority, 0);
+
+    let grouped = json!({
+        "groups": [
+            { "region": "us-west", "exits": { "a": exit(None), "b": exit(Some("us-east")) } },
+            { "exits": { "c": exit(None) } },
+        ]
+    })
+    .to_string();
+    let exits = parse_exit_list(grouped.as_bytes(), None, 0).unwrap().0;
+    assert_eq!(exits["a"].region, Some("us-west".to_string()));
+    assert_eq!(exits["b"].region, Some("us-east".to_string()));
+    assert_eq!(exits["c"].region, None);
+
+    let duplicate = json!({
+        "groups": [{ "exits": { "a": exit(None) } }, { "exits": { "a": exit(None) } }]
+    })
+    .to_string();
+    assert!(parse_exit_list(duplicate.as_bytes(), None, 0).is_err());
+    // nicknames end up in urls
+    let bad_name = json!({ "a/b": exit(None) }).to_string();
+    assert!(parse_exit_list(bad_name.as_bytes(), None, 0).is_err());
+    let mut bad_ip = exit(None);
+    bad_ip["id"]["mesh_ip"] = json!("::");
+    let bad_ip = json!({ "a": bad_ip }).to_string();
+    assert!(parse_exit_list(bad_ip.as_bytes(), None, 0).is_err());
+
+    let (pubkey, secretkey) = sign::gen_keypair();
+    let pubkey = SigningPubkey::from(pubkey);
+    let grouped_value: serde_json::Value = serde_json::from_str(&grouped).unwrap();
+    let list = json!({ "sequence": 5, "exits": grouped_value }).to_string();
+    let sign::Signature(signature) = sign::sign_detached(list.as_bytes(), &secretkey);
+    let signed = SignedExitList {
+        list,
+        signature: signature.to_vec(),
+    };
+    let signed_bytes = serde_json::to_vec(&signed).unwrap();
+    let (exits, sequence) = parse_exit_list(&signed_bytes, Some(pubkey), 0).unwrap();
+    assert_eq!(exits.len(), 3);
+    assert_eq!(sequence, Some(5));
+    // the same list can be synced again but an older one is a replay
+    assert!(parse_exit_list(&signed_bytes, Some(pubkey), 5).is_ok());
+    assert!(parse_exit_list(&signed_bytes, Some(pubkey), 6).is_err());
+    // unsigned lists are rejected when a key is configured
+    assert!(parse_exit_list(grouped.as_bytes(), Some(pubkey), 0).is_err());
+    let (other_pubkey, _) = sign::gen_keypair();
+    assert!(parse_exit_list(&signed_bytes, Some(other_pubkey.into()), 0).is_err());
+    let mut tampered = signed;
+    tampered.list = tampered.list.replace("us-west", "us-wost");
+    let tampered = serde_json::to_vec(&tampered).unwrap();
+    assert!(parse_exit_list(&tampered, Some(pubkey), 0).is_err());
+}
@@ -0,0 +1,413 @@
+//! Active probing of registered exits. The babel metric only tells us about the quality of the
+//! links between us and an exit, not how loaded the exit itself is, so every few minutes we
+//! measure connection latency and jitter to each exit's registration port and time a small
+//! download from it to estimate throughput. These are blended with the route metric into a
+//! single score, lower is better, which is used to pick an exit when automatic selection is on
+//! and is shown on the dashboard so users can see why an exit was chosen. Exits also report their
+//! own load in their status responses, busy exits get a higher score on top of what the probes
+//! find. Scores only decide between equally preferred exits, exits the exit list puts in our
+//! region always win over the rest and then the list's priority is respected, so long as they're
+//! reachable.
+
+use crate::rita_common::utils::now_secs;
+use actix_web::client;
+use actix_web::client::Connection;
+use actix_web::HttpMessage;
+use althea_types::{CapacityClass, ExitLoad};
+use babel_monitor::get_installed_route;
+use babel_monitor::Route;
+use failure::Error;
+use futures01::future;
+use futures01::stream;
+use futures01::{Future, Stream};
+use settings::client::ExitServer;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream as TokioTcpStream;
+use tokio::util::FutureExt;
+
+/// How often we probe all of our registered exits
+pub const EXIT_PROBE_INTERVAL: Duration = Duration::from_secs(300);
+/// How many latency samples to take per exit
+const PROBE_SAMPLES: usize = 5;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long we give the throughput sample to download, the sample is 64KiB so anything slower
+/// than about 100kbps times out
+const SAMPLE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Upper bound on the sample we're willing to read, well above what exits actually send
+const SAMPLE_LIMIT: usize = 256 * 1024;
+/// How much better (as a fraction of its score) another exit has to be before we move off
+/// of the current one, so that we don't flap between two similar exits
+const SWITCH_MARGIN: f32 = 0.2;
+
+/// Score added per millisecond of latency
+const LATENCY_WEIGHT: f32 = 4.0;
+/// Score added per millisecond of jitter
+const JITTER_WEIGHT: f32 = 8.0;
+/// Score added per millisecond it would take to move 64KiB at the measured throughput
+const TRANSFER_WEIGHT: f32 = 2.0;
+/// The size the transfer time is scored against, the same as the sample exits serve
+const TRANSFER_BITS: f32 = 64.0 * 1024.0 * 8.0;
+/// Used in place of the transfer time of an exit whose sample didn't download in time
+const NO_TRANSFER_MS: f32 = 5000.0;
+/// Score added per percent of the exit's capacity in use
+const UTILIZATION_WEIGHT: f32 = 4.0;
+/// Score added for an exit that says it's full, enough to move us off of it when there's
+/// anything reasonable elsewhere
+const FULL_PENALTY: f32 = 1000.0;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ExitProbe {
+    /// the babel metric of the installed route to the exit
+    pub metric: Option<u16>,
+    pub latency_ms: Option<f32>,
+    pub jitter_ms: Option<f32>,
+    /// kilobits per second measured downloading a small sample from the exit
+    pub throughput_kbps: Option<f32>,
+    /// what the exit said about its load in its last status response
+    #[serde(default)]
+    pub load: Option<ExitLoad>,
+    /// lower is better, None if the exit could not be reached
+    pub score: Option<f32>,
+    /// seconds since the unix epoch
+    pub last_probed: u64,
+}
+
+/// Blends the route metric with the probe results, exits we have no route to or that did not
+/// answer any latency probes can't be scored
+pub fn score_exit(probe: &ExitProbe) -> Option<f32> {
+    let metric = f32::from(probe.metric?);
+    let latency = probe.latency_ms?;
+    let jitter = probe.jitter_ms.unwrap_or(0.0);
+    let transfer = probe
+        .throughput_kbps
+        .filter(|kbps| *kbps > 0.0)
+        .map(|kbps| TRANSFER_BITS / kbps)
+        .unwrap_or(NO_TRANSFER_MS);
+    Some(
+        metric
+            + latency * LATENCY_WEIGHT
+            + jitter * JITTER_WEIGHT
+            + transfer * TRANSFER_WEIGHT
+            + load_penalty(probe.load),
+    )
+}
+
+/// Exits that don't report their load aren't penalized, we can't tell if they're busy
+fn load_penalty(load: Option<ExitLoad>) -> f32 {
+    let load = match load {
+        Some(load) => load,
+        None => return 0.0,
+    };
+    let utilization = f32::from(load.utilization.unwrap_or(0)) * UTILIZATION_WEIGHT;
+    match load.capacity {
+        CapacityClass::Full => utilization + FULL_PENALTY,
+        _ => utilization,
+    }
+}
+
+/// Returns the average latency and the average difference between consecutive samples
+fn latency_stats(samples: &[f32]) -> (Option<f32>, Option<f32>) {
+    if samples.is_empty() {
+        return (None, None);
+    }
+    let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+    if samples.len() < 2 {
+        return (Some(mean), None);
+    }
+    let jitter = samples
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).abs())
+        .sum::<f32>()
+        / (samples.len() - 1) as f32;
+    (Some(mean), Some(jitter))
+}
+
+fn elapsed_ms(start: Instant) -> f32 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs() as f32 * 1000.0 + elapsed.subsec_micros() as f32 / 1000.0
+}
+
+/// Time taken to open a tcp connection to the exit, None on failure
+fn timed_connect(addr: SocketAddr) -> impl Future<Item = Option<f32>, Error = Error> {
+    let start = Instant::now();
+    TokioTcpStream::connect(&addr)
+        .timeout(PROBE_TIMEOUT)
+        .then(move |res| Ok(res.ok().map(|_| elapsed_ms(start))))
+}
+
+/// Kilobits per second over the time from a request going out for the exit's throughput sample
+/// until the last byte of it arrives
+fn kbps(bytes: usize, ms: f32) -> Option<f32> {
+    if bytes == 0 || ms <= 0.0 {
+        return None;
+    }
+    // bits per millisecond is kilobits per second
+    Some(bytes as f32 * 8.0 / ms)
+}
+
+/// Downloads the exit's throughput sample and times it, None on failure. The connection is
+/// opened before the clock starts so the handshake, which the latency probes already cover,
+/// isn't counted against the throughput
+fn throughput_sample(addr: SocketAddr) -> impl Future<Item = Option<f32>, Error = Error> {
+    let endpoint = format!("http://[{}]:{}/throughput_sample", addr.ip(), addr.port());
+    TokioTcpStream::connect(&addr)
+        .from_err()
+        .and_then(move |stream| {
+            let start = Instant::now();
+            client::get(&endpoint)
+                .with_connection(Connection::from_stream(stream))
+                .finish()
+                .unwrap()
+                .send()
+                .from_err()
+                .and_then(|response| response.body().limit(SAMPLE_LIMIT).from_err())
+                .map(move |body| kbps(body.len(), elapsed_ms(start)))
+        })
+        .timeout(SAMPLE_TIMEOUT)
+        .then(|res: Result<Option<f32>, _>| Ok(res.ok().and_then(|kbps| kbps)))
+}
+
+pub fn probe_exit(
+    name: String,
+    exit: ExitServer,
+    routes: &[Route],
+) -> impl Future<Item = (String, ExitProbe), Error = Error> {
+    let metric = get_installed_route(&exit.id.mesh_ip, routes)
+        .ok()
+        .map(|r| r.metric);
+    let addr = SocketAddr::new(exit.id.mesh_ip, exit.registration_port);
+    let load = exit.info.general_details().and_then(|details| details.load);
+    let last_probed = now_secs();
+
+    // no point in waiting for timeouts if babel can't reach it
+    let probes: Box<dyn Future<Item = (Vec<Option<f32>>, Option<f32>), Error = Error>> =
+        if metric.is_some() {
+            Box::new(
+                stream::iter_ok(0..PROBE_SAMPLES)
+                    .and_then(move |_| timed_connect(addr))
+                    .collect()
+                    .join(throughput_sample(addr)),
+            )
+        } else {
+            Box::new(future::ok((Vec::new(), None)))
+        };
+
+    probes.and_then(move |(samples, throughput_kbps)| {
+        let samples: Vec<f32> = samples.into_iter().flatten().collect();
+        let (latency_ms, jitter_ms) = latency_stats(&samples);
+        let mut probe = ExitProbe {
+            metric,
+            latency_ms,
+            jitter_ms,
+            throughput_kbps,
+            load,
+            score: None,
+            last_probed,
+        };
+        probe.score = score_exit(&probe);
+        trace!("Probed exit {} {:?}", name, probe);
+        Ok((name, probe))
+    })
+}
+
+/// Lower is preferred, exits in our region come first and then the exit list's priority
+pub fn exit_preference(exit: &ExitServer, region: Option<&str>) -> (bool, u32) {
+    let in_region = region.is_some() && exit.region.as_ref().map(String::as_str) == region;
+    (!in_region, exit.priority)
+}
+
+/// The most preferred of the candidates that answered their last probe, automatic selection
+/// only chooses between these
+pub fn preferred_candidates(
+    exits: &HashMap<String, ExitServer>,
+    probes: &HashMap<String, ExitProbe>,
+    candidates: &[String],
+    region: Option<&str>,
+) -> Vec<String> {
+    let reachable: Vec<(&String, (bool, u32))> = candidates
+        .iter()
+        .filter(|name| probes.get(*name).and_then(|p| p.score).is_some())
+        .filter_map(|name| Some((name, exit_preference(exits.get(name)?, region))))
+        .collect();
+    let best = match reachable.iter().map(|(_, preference)| *preference).min() {
+        Some(best) => best,
+        None => return Vec::new(),
+    };
+    reachable
+        .into_iter()
+        .filter(|(_, preference)| *preference == best)
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// Picks the best scored exit out of the candidates, only returning something other than the
+/// current exit if it's better by at least SWITCH_MARGIN
+pub fn best_exit(
+    probes: &HashMap<String, ExitProbe>,
+    candidates: &[String],
+    current: Option<&String>,
+) -> Option<String> {
+    let (best, best_score) = candidates
+        .iter()
+        .filter_map(|name| Some((name, probes.get(name)?.score?)))
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))?;
+
+    let current_score = current.and_then(|c| probes.get(c)).and_then(|p| p.score);
+    match (current, current_score) {
+        (Some(current), Some(current_score)) => {
+            if best != current && best_score < current_score * (1.0 - SWITCH_MARGIN) {
+                Some(best.clone())
+            } else {
+                Some(current.clone())
+            }
+        }
+        _ => Some(best.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rita_common::utils::test_identity;
+
+    fn probe(metric: u16, latency_ms: f32) -> ExitProbe {
+        let mut probe = ExitProbe {
+            metric: Some(metric),
+            latency_ms: Some(latency_ms),
+            jitter_ms: Some(1.0),
+            throughput_kbps: Some(8192.0),
+            load: None,
+            score: None,
+            last_probed: 0,
+        };
+        probe.score = score_exit(&probe);
+        probe
+    }
+
+    #[test]
+    fn test_latency_stats() {
+        assert_eq!(latency_stats(&[]), (None, None));
+        assert_eq!(latency_stats(&[10.0]), (Some(10.0), None));
+        assert_eq!(
+            latency_stats(&[10.0, 20.0, 10.0]),
+            (Some(40.0 / 3.0), Some(10.0))
+        );
+    }
+
+    #[test]
+    fn test_throughput_score() {
+        assert_eq!(kbps(0, 10.0), None);
+        assert_eq!(kbps(65536, 0.0), None);
+        assert_eq!(kbps(65536, 64.0), Some(8192.0));
+
+        let fast = probe(256, 50.0);
+        let mut slow = fast.clone();
+        slow.throughput_kbps = Some(512.0);
+        let mut failed = fast.clone();
+        failed.throughput_kbps = None;
+
+        let score = |p: &ExitProbe| score_exit(p).unwrap();
+        // 64KiB takes 64ms at 8192kbps and a second at 512kbps
+        assert_eq!(
+            score(&slow) - score(&fast),
+            (1024.0 - 64.0) * TRANSFER_WEIGHT
+        );
+        assert!(score(&failed) > score(&slow));
+        // a failed sample still leaves the exit scored, the latency probes reached it
+        assert_eq!(
+            score(&failed) - score(&fast),
+            (NO_TRANSFER_MS - 64.0) * TRANSFER_WEIGHT
+        );
+    }
+
+    #[test]
+    fn test_best_exit() {
+        let mut probes = HashMap::new();
+        probes.insert("a".to_string(), probe(256, 50.0));
+        probes.insert("b".to_string(), probe(256, 45.0));
+        probes.insert("c".to_string(), probe(256, 10.0));
+        probes.insert("down".to_string(), ExitProbe::default());
+        let a = "a".to_string();
+        let b = "b".to_string();
+
+        let all: Vec<String> = probes.keys().cloned().collect();
+        assert_eq!(best_exit(&probes, &all, None), Some("c".to_string()));
+        // c is much better than a so we move
+        assert_eq!(best_exit(&probes, &all, Some(&a)), Some("c".to_string()));
+        // b is only slightly better than a so we stay put
+        let ab = vec![a.clone(), b.clone()];
+        assert_eq!(best_exit(&probes, &ab, Some(&a)), Some(a.clone()));
+        // if our current exit is down anything reachable is better
+        let down = "down".to_string();
+        assert_eq!(best_exit(&probes, &ab, Some(&down)), Some(b));
+        assert_eq!(best_exit(&probes, &[down.clone()], Some(&down)), None);
+    }
+
+    #[test]
+    fn test_preferred_candidates() {
+        let exit = |region: Option<&str>, priority| {
+            let mut exit: ExitServer = serde_json::from_value(json!({
+                "id": test_identity("fd00::1"),
+                "registration_port": 4875,
+                "state": "New",
+            }))
+            .unwrap();
+            exit.region = region.map(String::from);
+            exit.priority = priority;
+            exit
+        };
+        let mut exits = HashMap::new();
+        exits.insert("near".to_string(), exit(Some("us-west"), 1));
+        exits.insert("near_backup".to_string(), exit(Some("us-west"), 2));
+        exits.insert("far".to_string(), exit(Some("eu"), 0));
+        let mut probes = HashMap::new();
+        probes.insert("near".to_string(), probe(256, 200.0));
+        probes.insert("near_backup".to_string(), probe(256, 10.0));
+        probes.insert("far".to_string(), probe(256, 10.0));
+        let all: Vec<String> = exits.keys().cloned().collect();
+
+        // our region wins over a faster exit elsewhere and priority over a faster exit here
+        let preferred = preferred_candidates(&exits, &probes, &all, Some("us-west"));
+        assert_eq!(preferred, vec!["near".to_string()]);
+        // without a region only priority counts
+        let preferred = preferred_candidates(&exits, &probes, &all, None);
+        assert_eq!(preferred, vec!["far".to_string()]);
+        // unreachable exits are skipped no matter how preferred they are
+        probes.insert("near".to_string(), ExitProbe::default());
+        let preferred = preferred_candidates(&exits, &probes, &all, Some("us-west"));
+        assert_eq!(preferred, vec!["near_backup".to_string()]);
+    }
+
+    #[test]
+    fn test_load_score() {
+        let load = |utilization| ExitLoad {
+            clients: 10,
+            utilization,
+            capacity: CapacityClass::from_utilization(utilization),
+        };
+        let quiet = probe(256, 50.0);
+        let mut busy = quiet.clone();
+        busy.load = Some(load(Some(70)));
+        let mut full = quiet.clone();
+        full.load = Some(load(Some(95)));
+        let mut unknown = quiet.clone();
+        unknown.load = Some(load(None));
+
+        let score = |p: &ExitProbe| score_exit(p).unwrap();
+        assert_eq!(score(&unknown), score(&quiet));
+        assert!(score(&busy) > score(&quiet));
+        assert!(score(&full) > score(&busy) + FULL_PENALTY);
+
+        // a full exit loses to a slower quiet one
+        let mut probes = HashMap::new();
+        probes.insert("full".to_string(), full);
+        probes.insert("slow".to_string(), probe(256, 150.0));
+        let all: Vec<String> = probes.keys().cloned().collect();
+        assert_eq!(
+            best_exit(&probes, &all, Some(&"full".to_string())),
+            Some("slow".to_string())
+        );
+    }
+}
@@ -16,27 +16,41 @@
 //!
 //! Signup is complete and the user may use the connection
 
+pub mod announcements;
+pub mod exit_list;
+pub mod exit_selection;
+pub mod terms;
+
+use self::exit_selection::{
+    best_exit, exit_preference, preferred_candidates, probe_exit, ExitProbe, EXIT_PROBE_INTERVAL,
+};
+use self::terms::{acceptable, pending_terms, price_failover, update_exit_info};
 use crate::rita_client::rita_loop::Tick;
 use crate::rita_client::rita_loop::CLIENT_LOOP_TIMEOUT;
+use crate::rita_client::split_tunnel::{resolve_routes, SplitTunnel};
 use crate::rita_client::traffic_watcher::{QueryExitDebts, TrafficWatcher};
 use crate::rita_common::oracle::low_balance;
+use crate::rita_common::route_cache::get_routes;
+use crate::rita_common::sla_monitor::{ExitToCheck, SlaMonitor};
+use crate::rita_common::time_sanity::record_exit_time;
+use crate::rita_common::utils::now_secs;
 use crate::KI;
 use crate::SETTING;
 use ::actix::registry::SystemService;
-use ::actix::{Actor, Arbiter, Context, Handler, ResponseFuture, Supervised};
+use ::actix::{Actor, Arbiter, Context, Handler, Message, ResponseFuture, Supervised};
 use ::actix_web::client::Connection;
 use ::actix_web::{client, HttpMessage, Result};
 use althea_types::ExitClientDetails;
 use althea_types::ExitDetails;
 use althea_types::WgKey;
+use althea_types::{solve_signup_pow, Identity, SignupChallenge};
 use althea_types::{EncryptedExitClientIdentity, EncryptedExitState};
 use althea_types::{ExitClientIdentity, ExitState, ExitVerifMode};
-use babel_monitor::open_babel_stream;
-use babel_monitor::parse_routes;
-use babel_monitor::start_connection;
 use failure::Error;
 use futures01::future;
 use futures01::future::join_all;
+use futures01::future::Shared;
+use futures01::sync::oneshot;
 use futures01::Future;
 use settings::client::ExitServer;
 use settings::client::RitaClientSettings;
@@ -44,8 +58,12 @@ use settings::RitaCommonSettings;
 use sodiumoxide::crypto::box_;
 use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::Nonce;
 use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::PublicKey;
-use std::net::SocketAddr;
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use tokio::net::TcpStream as TokioTcpStream;
 use tokio::util::FutureExt;
 
@@ -67,6 +85,11 @@ fn linux_setup_exit_tunnel(
         SETTING.get_network().rita_hello_port,
     )?;
     KI.set_route_to_tunnel(&general_details.server_internal_ip)?;
+    if let Some(subnet) = our_details.internet_ipv6_subnet {
+        KI.set_client_exit_ipv6(current_exit.id.wg_public_key, subnet)?;
+    }
+
+    KI.set_lan_ipv6_only(lan_dns64_resolver(general_details, our_details))?;
 
     let lan_nics = &SETTING.get_exit_client().lan_nics;
     for nic in lan_nics {
@@ -76,6 +99,26 @@ fn linux_setup_exit_tunnel(
     Ok(())
 }
 
+/// The DNS64 resolver to give the LAN if it should be ipv6 only, which needs both the user to
+/// ask for it and the exit to give us ipv6 addresses and translate to ipv4 for us
+pub fn lan_dns64_resolver(
+    general_details: &ExitDetails,
+    our_details: &ExitClientDetails,
+) -> Option<IpAddr> {
+    match (
+        SETTING.get_exit_client().ipv6_only_lan,
+        general_details.nat64,
+        our_details.internet_ipv6_subnet,
+    ) {
+        (true, Some(nat64), Some(_)) => Some(nat64.dns64_resolver),
+        (true, _, _) => {
+            warn!("Exit can't support an ipv6 only LAN, leaving it dual stack");
+            None
+        }
+        (false, _, _) => None,
+    }
+}
+
 fn restore_nat() {
     if let Err(e) = KI.restore_client_nat() {
         error!("Failed to restore client nat! {:?}", e);
@@ -196,7 +239,25 @@ fn send_exit_status_request(
     to: &SocketAddr,
     ident: ExitClientIdentity,
 ) -> impl Future<Item = ExitState, Error = Error> {
-    let endpoint = format!("http://[{}]:{}/secure_status", to.ip(), to.port());
+    send_exit_state_request("secure_status", exit_pubkey, to, ident)
+}
+
+fn send_exit_resend_email_request(
+    exit_pubkey: WgKey,
+    to: &SocketAddr,
+    ident: ExitClientIdentity,
+) -> impl Future<Item = ExitState, Error = Error> {
+    send_exit_state_request("secure_resend_email", exit_pubkey, to, ident)
+}
+
+/// Posts our encrypted identity to one of the exit's endpoints that answer with our state
+fn send_exit_state_request(
+    path: &str,
+    exit_pubkey: WgKey,
+    to: &SocketAddr,
+    ident: ExitClientIdentity,
+) -> impl Future<Item = ExitState, Error = Error> {
+    let endpoint = format!("http://[{}]:{}/{}", to.ip(), to.port(), path);
     let ident = encrypt_exit_client_id(&exit_pubkey.into(), ident);
 
     let stream = TokioTcpStream::connect(to);
@@ -217,6 +278,13 @@ fn send_exit_status_request(
                         decrypt_exit_state(value, exit_pubkey.into())
                     })
             })
+            .map(|state| {
+                // only the exit could have put this in there
+                if let Some(time) = state.general_details().and_then(|details| details.time) {
+                    record_exit_time(time);
+                }
+                state
+            })
     })
 }
 
@@ -234,6 +302,7 @@ fn exit_general_details_request(exit: String) -> impl Future<Item = (), Error =
     trace!("sending exit general details request to {}", exit);
 
     let r = get_exit_info(&endpoint).and_then(move |exit_details| {
+        let max_price = SETTING.get_exit_client().max_exit_price;
         let mut exits = SETTING.get_exits_mut();
 
         let current_exit = match exits.get_mut(&exit) {
@@ -248,14 +317,109 @@ fn exit_general_details_request(exit: String) -> impl Future<Item = (), Error =
             _ => bail!("got incorrect state from exit details request"),
         }
 
-        current_exit.info = exit_details;
-
-        Ok(())
+        update_exit_info(current_exit, exit_details, max_price)
     });
 
     Box::new(r)
 }
 
+/// The addresses we have with the exit we're using, when talking to a different exit. They are
+/// passed along so that the other exit can reserve the same ones for us if they are free, then
+/// if we fail over or switch to it our internal ip and ipv6 subnet don't change
+fn migration_preference(exit: &str) -> Option<ExitClientDetails> {
+    let exit_client = SETTING.get_exit_client();
+    if exit_client.current_exit.as_ref()? == exit {
+        return None;
+    }
+    exit_client.get_current_exit()?.info.our_details().cloned()
+}
+
+/// Past this the exit is asking for more work than a router can do in a reasonable time
+const MAX_SIGNUP_POW_DIFFICULTY: u8 = 28;
+
+/// A proof of work being solved for an exit, kept so that setup requests made while it's running,
+/// or after it's done, wait on the same answer instead of starting another solver
+struct SignupSolver {
+    /// what's being solved, our identity, the exit's seed and the difficulty
+    challenge: (Identity, u64, u8),
+    cancel: Arc<AtomicBool>,
+    result: Shared<oneshot::Receiver<Option<u64>>>,
+}
+
+lazy_static! {
+    static ref SIGNUP_SOLVERS: Mutex<HashMap<String, SignupSolver>> = Mutex::new(HashMap::new());
+}
+
+/// The nonce for this exit's proof of work, at most one solver runs per exit, one still working
+/// on a seed the exit has since replaced is stopped
+fn signup_pow(
+    exit: &str,
+    our_id: Identity,
+    seed: u64,
+    difficulty: u8,
+) -> Shared<oneshot::Receiver<Option<u64>>> {
+    let challenge = (our_id, seed, difficulty);
+    let mut solvers = SIGNUP_SOLVERS.lock().unwrap();
+    if let Some(solver) = solvers.get(exit) {
+        if solver.challenge == challenge {
+            return solver.result.clone();
+        }
+        solver.cancel.store(true, Ordering::Relaxed);
+    }
+
+    let cancel = Arc::new(AtomicBool::new(false));
+    let cancelled = cancel.clone();
+    let (sender, receiver) = oneshot::channel();
+    thread::spawn(move || {
+        let nonce = solve_signup_pow(&our_id, seed, difficulty, || {
+            cancelled.load(Ordering::Relaxed)
+        });
+        let _ = sender.send(nonce);
+    });
+    let result = receiver.shared();
+    solvers.insert(
+        exit.to_string(),
+        SignupSolver {
+            challenge,
+            cancel,
+            result: result.clone(),
+        },
+    );
+    result
+}
+
+/// Our answer to the exit's signup challenge. The proof of work can take a router a few seconds
+/// so it's done off the event loop
+fn signup_challenge_response(
+    name: &str,
+    exit: &ExitServer,
+    challenge: Option<SignupChallenge>,
+    our_id: Identity,
+) -> Box<dyn Future<Item = Option<String>, Error = Error>> {
+    match challenge {
+        Some(SignupChallenge::ProofOfWork { difficulty, .. })
+            if difficulty > MAX_SIGNUP_POW_DIFFICULTY =>
+        {
+            Box::new(future::err(format_err!(
+                "Exit wants a proof of work of difficulty {}, more than we can do",
+                difficulty
+            )))
+        }
+        Some(SignupChallenge::ProofOfWork { difficulty, seed }) => Box::new(
+            signup_pow(name, our_id, seed, difficulty)
+                .map_err(|_| format_err!("Signup proof of work was cancelled"))
+                .and_then(|nonce| match *nonce {
+                    Some(nonce) => Ok(Some(nonce.to_string())),
+                    None => Err(format_err!("Signup proof of work was cancelled")),
+                }),
+        ),
+        Some(SignupChallenge::Passphrase { .. }) => {
+            Box::new(future::ok(exit.signup_passphrase.clone()))
+        }
+        None => Box::new(future::ok(None)),
+    }
+}
+
 pub fn exit_setup_request(
     exit: String,
     code: Option<String>,
@@ -264,13 +428,15 @@ pub fn exit_setup_request(
         Some(exit_struct) => exit_struct.clone(),
         None => return Box::new(future::err(format_err!("Could not find exit {:?}", exit))),
     };
-    let exit_auth_type = match current_exit.info.general_details() {
-        Some(details) => details.verif_mode,
+    let (exit_auth_type, challenge) = match current_exit.info.general_details() {
+        Some(details) => (details.verif_mode, details.signup_challenge.clone()),
         None => return Box::new(future::err(format_err!("Exit is not ready to be setup!"))),
     };
     let exit_server = current_exit.id.mesh_ip;
     let exit_pubkey = current_exit.id.wg_public_key;
     let mut reg_details = SETTING.get_exit_client().reg_details.clone().unwrap();
+    // with a code we've already signed up and only have to verify
+    let challenge = if code.is_none() { challenge } else { None };
     match exit_auth_type {
         ExitVerifMode::Email => {
             reg_details.email_code = code;
@@ -280,34 +446,48 @@ pub fn exit_setup_request(
         }
         ExitVerifMode::Off => {}
     }
+    let preferred = migration_preference(&exit);
 
-    let ident = ExitClientIdentity {
-        global: match SETTING.get_identity() {
-            Some(id) => id,
-            None => {
-                return Box::new(future::err(format_err!(
-                    "Identity has no mesh IP ready yet"
-                )));
-            }
-        },
-        wg_port: SETTING.get_exit_client().wg_listen_port,
-        reg_details,
-        low_balance: None,
+    let our_id = match SETTING.get_identity() {
+        Some(id) => id,
+        None => {
+            return Box::new(future::err(format_err!(
+                "Identity has no mesh IP ready yet"
+            )));
+        }
     };
+    let wg_port = SETTING.get_exit_client().wg_listen_port;
+    let voucher = SETTING.get_exit_client().voucher.clone();
 
     let endpoint = SocketAddr::new(exit_server, current_exit.registration_port);
 
-    trace!(
-        "sending exit setup request {:?} to {}, using {:?}",
-        ident,
-        exit,
-        endpoint
-    );
-
     Box::new(
-        send_exit_setup_request(exit_pubkey, &endpoint, ident)
-            .from_err()
-            .and_then(move |exit_response| {
+        signup_challenge_response(&exit, &current_exit, challenge, our_id)
+            .and_then(move |signup_response| {
+                let ident = ExitClientIdentity {
+                    global: our_id,
+                    wg_port,
+                    reg_details,
+                    low_balance: None,
+                    voucher,
+                    preferred_internal_ip: preferred.as_ref().map(|d| d.client_internal_ip),
+                    preferred_ipv6_subnet: preferred.and_then(|d| d.internet_ipv6_subnet),
+                    signup_response,
+                };
+
+                trace!(
+                    "sending exit setup request {:?} to {}, using {:?}",
+                    ident,
+                    exit,
+                    endpoint
+                );
+
+                send_exit_setup_request(exit_pubkey, &endpoint, ident)
+                    .from_err()
+                    .map(move |exit_response| (exit, exit_response))
+            })
+            .and_then(move |(exit, exit_response)| {
+                let max_price = SETTING.get_exit_client().max_exit_price;
                 let mut exits = SETTING.get_exits_mut();
 
                 let current_exit = match exits.get_mut(&exit) {
@@ -315,7 +495,13 @@ pub fn exit_setup_request(
                     None => bail!("Could not find exit {:?}", exit),
                 };
 
-                current_exit.info = exit_response.clone();
+                update_exit_info(current_exit, exit_response.clone(), max_price)?;
+                drop(exits);
+
+                // the exit only redeems vouchers once we're registered
+                if let ExitState::Registered { .. } = exit_response {
+                    SETTING.get_exit_client_mut().voucher = None;
+                }
 
                 trace!("Got exit setup response {:?}", exit_response);
 
@@ -340,6 +526,7 @@ fn exit_status_request(exit: String) -> impl Future<Item = (), Error = Error> {
 
     let exit_server = current_exit.id.mesh_ip;
     let exit_pubkey = current_exit.id.wg_public_key;
+    let preferred = migration_preference(&exit);
     let ident = ExitClientIdentity {
         global: match SETTING.get_identity() {
             Some(id) => id,
@@ -352,6 +539,10 @@ fn exit_status_request(exit: String) -> impl Future<Item = (), Error = Error> {
         wg_port: SETTING.get_exit_client().wg_listen_port,
         reg_details: SETTING.get_exit_client().reg_details.clone().unwrap(),
         low_balance: Some(balance_notification),
+        voucher: None,
+        preferred_internal_ip: preferred.as_ref().map(|d| d.client_internal_ip),
+        preferred_ipv6_subnet: preferred.and_then(|d| d.internet_ipv6_subnet),
+        signup_response: None,
     };
 
     let endpoint = SocketAddr::new(exit_server, current_exit.registration_port);
@@ -364,6 +555,7 @@ fn exit_status_request(exit: String) -> impl Future<Item = (), Error = Error> {
 
     let r =
         send_exit_status_request(exit_pubkey, &endpoint, ident).and_then(move |exit_response| {
+            let max_price = SETTING.get_exit_client().max_exit_price;
             let mut exits = SETTING.get_exits_mut();
 
             let current_exit = match exits.get_mut(&exit) {
@@ -371,7 +563,7 @@ fn exit_status_request(exit: String) -> impl Future<Item = (), Error = Error> {
                 None => bail!("Could not find exit {:?}", exit),
             };
 
-            current_exit.info = exit_response.clone();
+            update_exit_info(current_exit, exit_response.clone(), max_price)?;
 
             trace!("Got exit status response {:?}", exit_response);
 
@@ -380,12 +572,282 @@ fn exit_status_request(exit: String) -> impl Future<Item = (), Error = Error> {
     Box::new(r)
 }
 
+/// Asks the exit to email us our verification code again
+pub fn exit_resend_email_request(exit: String) -> impl Future<Item = (), Error = Error> {
+    let current_exit = match SETTING.get_exits().get(&exit) {
+        Some(current_exit) => current_exit.clone(),
+        None => {
+            return Box::new(future::err(format_err!("No valid exit for {}", exit)))
+                as Box<dyn Future<Item = (), Error = Error>>;
+        }
+    };
+    let reg_details = match SETTING.get_exit_client().reg_details.clone() {
+        Some(reg_details) => reg_details,
+        None => return Box::new(future::err(format_err!("No registration details set"))),
+    };
+
+    let exit_pubkey = current_exit.id.wg_public_key;
+    let ident = ExitClientIdentity {
+        global: match SETTING.get_identity() {
+            Some(id) => id,
+            None => {
+                return Box::new(future::err(format_err!(
+                    "Identity has no mesh IP ready yet"
+                )));
+            }
+        },
+        wg_port: SETTING.get_exit_client().wg_listen_port,
+        reg_details,
+        low_balance: None,
+        voucher: None,
+        preferred_internal_ip: None,
+        preferred_ipv6_subnet: None,
+        signup_response: None,
+    };
+
+    let endpoint = SocketAddr::new(current_exit.id.mesh_ip, current_exit.registration_port);
+
+    trace!("asking {} to resend our email code", exit);
+
+    let r = send_exit_resend_email_request(exit_pubkey, &endpoint, ident).and_then(
+        move |exit_response| {
+            let max_price = SETTING.get_exit_client().max_exit_price;
+            let mut exits = SETTING.get_exits_mut();
+
+            let current_exit = match exits.get_mut(&exit) {
+                Some(exit_struct) => exit_struct,
+                None => bail!("Could not find exit {:?}", exit),
+            };
+
+            update_exit_info(current_exit, exit_response.clone(), max_price)?;
+
+            trace!("Got exit resend email response {:?}", exit_response);
+
+            Ok(())
+        },
+    );
+    Box::new(r)
+}
+
+/// How long before the start of an exit maintenance window we move to another exit
+const MAINTENANCE_LEAD_TIME: u64 = 300;
+
+/// The internal ip of the selected exit for the SLA monitor to check over the exit tunnel, once
+/// we're registered with it
+fn exit_to_check() -> Option<IpAddr> {
+    let exit = SETTING.get_exit_client().get_current_exit().cloned()?;
+    exit.info.our_details()?;
+    Some(exit.info.general_details()?.server_internal_ip)
+}
+
+/// True if the exit has told us it's suspended or is about to go down for maintenance
+fn exit_unavailable(state: &ExitState, now: u64) -> bool {
+    match state {
+        ExitState::Suspended { .. } => true,
+        ExitState::Maintenance { window, .. } => window.is_imminent(now, MAINTENANCE_LEAD_TIME),
+        _ => false,
+    }
+}
+
+/// Moves us off of the selected exit when it becomes unavailable, rather than waiting for the
+/// tunnel to time out, and back again once it reports that it's healthy. Takes the failover
+/// we performed previously as (original exit, replacement exit) and returns the current one.
+fn exit_failover(failover: Option<(String, String)>) -> Option<(String, String)> {
+    let now = now_secs();
+    let mut exit_client = SETTING.get_exit_client_mut();
+    let current = exit_client.current_exit.clone()?;
+
+    if let Some((original, replacement)) = failover {
+        // the user has picked an exit since we failed over, respect that
+        if current != replacement {
+            return None;
+        }
+        if let Some(exit) = exit_client.exits.get(&original) {
+            if let (ExitState::Registered { .. }, None) = (&exit.info, pending_terms(exit)) {
+                info!("Exit {} is available again, switching back", original);
+                exit_client.current_exit = Some(original);
+                return None;
+            }
+        }
+        return Some((original, replacement));
+    }
+
+    let unavailable = match exit_client.exits.get(&current) {
+        Some(exit) => exit_unavailable(&exit.info, now),
+        None => false,
+    };
+    if !unavailable {
+        return None;
+    }
+
+    let max_price = exit_client.max_exit_price;
+    let region = exit_client.region.clone();
+    let alternative = exit_client
+        .exits
+        .iter()
+        .filter(|(name, exit)| {
+            **name != current
+                && match exit.info {
+                    ExitState::Registered { .. } => true,
+                    _ => false,
+                }
+                && acceptable(exit, max_price)
+        })
+        .min_by_key(|(_, exit)| exit_preference(exit, region.as_ref().map(String::as_str)))
+        .map(|(name, _)| name.clone());
+    match alternative {
+        Some(replacement) => {
+            warn!(
+                "Exit {} is unavailable, failing over to {}",
+                current, replacement
+            );
+            exit_client.current_exit = Some(replacement.clone());
+            Some((current, replacement))
+        }
+        None => {
+            warn!("Exit {} is unavailable and we have no other exit!", current);
+            None
+        }
+    }
+}
+
+/// Names of the exits we're registered with and could switch to without accepting new terms
+fn registered_exits() -> Vec<String> {
+    let max_price = SETTING.get_exit_client().max_exit_price;
+    SETTING
+        .get_exits()
+        .iter()
+        .filter(|(_, exit)| match exit.info {
+            ExitState::Registered { .. } => acceptable(exit, max_price),
+            _ => false,
+        })
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// Probes all registered exits in the background, the results are sent back to the
+/// ExitManager once they're all in
+fn probe_exits() {
+    let exits = SETTING.get_exits().clone();
+    let candidates = registered_exits();
+    if candidates.is_empty() {
+        return;
+    }
+
+    Arbiter::spawn(
+        get_routes()
+            .and_then(move |cached| {
+                let mut futs = Vec::new();
+                for name in candidates {
+                    if let Some(exit) = exits.get(&name) {
+                        futs.push(probe_exit(name, exit.clone(), &cached.routes));
+                    }
+                }
+                join_all(futs).and_then(|results| {
+                    ExitManager::from_registry()
+                        .do_send(ExitProbeResults(results.into_iter().collect()));
+                    Ok(())
+                })
+            })
+            .then(|ret| {
+                if let Err(e) = ret {
+                    error!("Failed to probe exits with {:?}", e)
+                }
+                Ok(())
+            }),
+    );
+}
+
+struct ExitProbeResults(HashMap<String, ExitProbe>);
+
+impl Message for ExitProbeResults {
+    type Result = ();
+}
+
+impl Handler<ExitProbeResults> for ExitManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: ExitProbeResults, _ctx: &mut Context<Self>) -> Self::Result {
+        self.probes = msg.0;
+
+        // never undo a failover, exit_failover is responsible for moving us back
+        if !SETTING.get_exit_client().auto_select_exit || self.failover.is_some() {
+            return;
+        }
+        let candidates = registered_exits();
+        let mut exit_client = SETTING.get_exit_client_mut();
+        let candidates = preferred_candidates(
+            &exit_client.exits,
+            &self.probes,
+            &candidates,
+            exit_client.region.as_ref().map(String::as_str),
+        );
+        // moving into a more preferred group doesn't have to beat our current exit's score
+        let current = exit_client
+            .current_exit
+            .as_ref()
+            .filter(|current| candidates.contains(current));
+        let best = best_exit(&self.probes, &candidates, current);
+        if let Some(best) = best {
+            if exit_client.current_exit.as_ref() != Some(&best) {
+                info!(
+                    "Automatically selecting exit {} with probe results {:?}",
+                    best,
+                    self.probes.get(&best)
+                );
+                exit_client.current_exit = Some(best);
+            }
+        }
+    }
+}
+
+/// A setup request made on a tick has been answered or failed
+struct SetupRequestDone(String);
+
+impl Message for SetupRequestDone {
+    type Result = ();
+}
+
+impl Handler<SetupRequestDone> for ExitManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetupRequestDone, _ctx: &mut Context<Self>) -> Self::Result {
+        self.registering.remove(&msg.0);
+        self.retry_at.remove(&msg.0);
+    }
+}
+
+/// Returns the latest probe results for each registered exit
+pub struct GetExitProbes;
+
+impl Message for GetExitProbes {
+    type Result = Result<HashMap<String, ExitProbe>, Error>;
+}
+
+impl Handler<GetExitProbes> for ExitManager {
+    type Result = Result<HashMap<String, ExitProbe>, Error>;
+
+    fn handle(&mut self, _msg: GetExitProbes, _ctx: &mut Context<Self>) -> Self::Result {
+        Ok(self.probes.clone())
+    }
+}
+
 /// An actor which pays the exit
 #[derive(Default)]
 pub struct ExitManager {
     // used to determine if we've changed exits
     last_exit: Option<ExitServer>,
     nat_setup: bool,
+    // the exit we moved away from due to maintenance or suspension and the one we moved to
+    failover: Option<(String, String)>,
+    // latest probe results for each registered exit
+    probes: HashMap<String, ExitProbe>,
+    last_probe: Option<Instant>,
+    split_tunnel: SplitTunnel,
+    // exits we've sent a setup request to that hasn't been answered yet
+    registering: HashSet<String>,
+    // when we may send the setup request again to exits that asked us to wait
+    retry_at: HashMap<String, Instant>,
 }
 
 impl Actor for ExitManager {
@@ -408,6 +870,17 @@ impl Handler<Tick> for ExitManager {
         // holding a readlock while exit tunnel setup requires a write lock
         // roughly the same as a drop(); inline
         let client_can_use_free_tier = { SETTING.get_payment().client_can_use_free_tier };
+        self.failover = exit_failover(self.failover.take());
+        price_failover();
+        let probe_due = match self.last_probe {
+            Some(val) => Instant::now() - val > EXIT_PROBE_INTERVAL,
+            None => true,
+        };
+        if probe_due {
+            self.last_probe = Some(Instant::now());
+            probe_exits();
+        }
+        SlaMonitor::from_registry().do_send(ExitToCheck(exit_to_check()));
         let exit_server = { SETTING.get_exit_client().get_current_exit().cloned() };
 
         // code that connects to the current exit server
@@ -436,6 +909,7 @@ impl Handler<Tick> for ExitManager {
                         .expect("failure setting up exit tunnel");
                         self.nat_setup = true;
                         self.last_exit = Some(exit.clone());
+                        self.split_tunnel.reset();
                     }
                     (true, false, false) => {
                         trace!("DHCP overwrite setup exit tunnel again");
@@ -446,38 +920,36 @@ impl Handler<Tick> for ExitManager {
                         )
                         .expect("failure setting up exit tunnel");
                         self.nat_setup = true;
+                        self.split_tunnel.reset();
                     }
                     _ => {}
                 }
 
                 // Adds and removes the nat rules in low balance situations
                 // this prevents the free tier from being confusing (partially working)
-                // when deployments are not interested in having a sufficiently fast one
+                // when deployments are not interested in having a sufficiently fast one.
+                // The same goes for exit terms the user has yet to accept
                 let low_balance = low_balance();
+                let terms_pending = pending_terms(&exit).is_some();
                 let nat_setup = self.nat_setup;
                 trace!(
-                    "client can use free tier {} low balance {}",
+                    "client can use free tier {} low balance {} terms pending {}",
                     client_can_use_free_tier,
-                    low_balance
+                    low_balance,
+                    terms_pending
                 );
-                match (low_balance, client_can_use_free_tier, nat_setup) {
-                    // remove when we have a low balance, do not have a free tier
-                    // and have a nat setup.
-                    (true, false, true) => {
+                let cut_off = (low_balance && !client_can_use_free_tier) || terms_pending;
+                match (cut_off, nat_setup) {
+                    // remove when we have a low balance and do not have a free tier, or
+                    // haven't accepted the exit's terms, and have a nat setup.
+                    (true, true) => {
                         trace!("removing exit tunnel!");
                         remove_nat();
                         self.nat_setup = false;
                     }
-                    // restore when our balance is not low and our nat is not setup
-                    // regardless of the free tier value
-                    (false, _, false) => {
-                        trace!("restoring exit tunnel!");
-                        restore_nat();
-                        self.nat_setup = true;
-                    }
-                    // restore if the nat is not setup and the free tier is enabled
-                    // this only happens when settings change under the hood
-                    (true, true, false) => {
+                    // restore when the nat is not setup and we can use the exit again, this
+                    // includes settings changing under the hood to enable the free tier
+                    (false, false) => {
                         trace!("restoring exit tunnel!");
                         restore_nat();
                         self.nat_setup = true;
@@ -491,25 +963,20 @@ impl Handler<Tick> for ExitManager {
                     let exit_internal_addr = general_details.server_internal_ip;
                     let exit_port = exit.registration_port;
                     let exit_id = exit.id;
-                    let babel_port = SETTING.get_network().babel_port;
                     trace!("We are signed up for the selected exit!");
 
                     Arbiter::spawn(
-                        open_babel_stream(babel_port)
-                            .from_err()
-                            .and_then(move |stream| {
-                                start_connection(stream).and_then(move |stream| {
-                                    parse_routes(stream).and_then(move |routes| {
-                                        TrafficWatcher::from_registry().do_send(QueryExitDebts {
-                                            exit_id,
-                                            exit_price,
-                                            routes: routes.1,
-                                            exit_internal_addr,
-                                            exit_port,
-                                        });
-                                        Ok(())
-                                    })
-                                })
+                        get_routes()
+                            .and_then(move |cached| {
+                                TrafficWatcher::from_registry().do_send(QueryExitDebts {
+                                    exit_iface: "wg_exit".to_string(),
+                                    exit_id,
+                                    exit_price,
+                                    routes: cached.routes,
+                                    exit_internal_addr,
+                                    exit_port,
+                                });
+                                Ok(())
                             })
                             .timeout(CLIENT_LOOP_TIMEOUT)
                             .then(|ret| {
@@ -523,6 +990,21 @@ impl Handler<Tick> for ExitManager {
             }
         }
 
+        let routes = {
+            let exit_client = SETTING.get_exit_client();
+            let exit_up = match (exit_client.get_current_exit(), &self.last_exit) {
+                (Some(exit), Some(last_exit)) => exit == last_exit,
+                _ => false,
+            };
+            resolve_routes(
+                &exit_client.split_tunnel,
+                exit_client.current_exit.as_ref().map(|name| name.as_str()),
+                exit_up,
+                &SETTING.get_network().default_route,
+            )
+        };
+        self.split_tunnel.update(routes);
+
         // code that manages requesting details to exits
         let servers = { SETTING.get_exits().clone() };
 
@@ -530,12 +1012,45 @@ impl Handler<Tick> for ExitManager {
 
         for (k, s) in servers {
             match s.info {
-                ExitState::Denied { .. }
-                | ExitState::Disabled
-                | ExitState::GotInfo {
-                    auto_register: false,
-                    ..
-                } => {}
+                // we've restored a backup and need to re-register with our preserved identity,
+                // or the exit has told us when to try registering again. Any other GotInfo
+                // waits for the user to register, whatever auto_register says
+                ExitState::GotInfo { retry_after, .. } if s.restored || retry_after.is_some() => {
+                    // the wait starts when we first see the state, a new answer restarts it
+                    if let (false, Some(secs)) = (s.restored, retry_after) {
+                        let retry_at = *self
+                            .retry_at
+                            .entry(k.clone())
+                            .or_insert_with(|| Instant::now() + Duration::from_secs(secs));
+                        if Instant::now() < retry_at {
+                            trace!("Waiting to send our setup request to {} again", k);
+                            continue;
+                        }
+                    }
+                    // a setup request can take far longer than a tick to be answered
+                    if !self.registering.insert(k.clone()) {
+                        trace!("Still waiting on our setup request to {}", k);
+                        continue;
+                    }
+                    futs.push(Box::new(exit_setup_request(k.clone(), None).then(
+                        move |res| {
+                            match res {
+                                Ok(_) => {
+                                    trace!("exit setup request to {} was successful", k);
+                                    // from here on it's up to what the exit answered
+                                    if let Some(exit) = SETTING.get_exits_mut().get_mut(&k) {
+                                        exit.restored = false;
+                                    }
+                                }
+                                Err(e) => {
+                                    trace!("exit setup request to {} failed with {:?}", k, e);
+                                }
+                            };
+                            ExitManager::from_registry().do_send(SetupRequestDone(k));
+                            Ok(())
+                        },
+                    )));
+                }
                 ExitState::New { .. } => {
                     futs.push(Box::new(exit_general_details_request(k.clone()).then(
                         move |res| {
@@ -551,7 +1066,9 @@ impl Handler<Tick> for ExitManager {
                         },
                     )));
                 }
-                ExitState::Registered { .. } => {
+                ExitState::Registered { .. }
+                | ExitState::Suspended { .. }
+                | ExitState::Maintenance { .. } => {
                     futs.push(Box::new(exit_status_request(k.clone()).then(move |res| {
                         match res {
                             Ok(_) => {
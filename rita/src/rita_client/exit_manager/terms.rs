@@ -0,0 +1,233 @@
+//! Exit terms on the client side. Exits send their signed terms, price, free tier and
+//! jurisdiction, with every state update and we keep the last terms we accepted for each exit.
+//! The first terms an exit offers are the ones we sign up under, later changes that can't leave
+//! us worse off, or that keep the jurisdiction and stay within the user's maximum price, are
+//! accepted automatically and anything else waits for the user to accept it on the dashboard. We
+//! won't forward traffic through an exit with terms waiting for acceptance, optionally moving to
+//! another exit instead if the new price is over the user's maximum.
+
+use super::announcements::{store_announcements, verify_announcements};
+use super::exit_selection::exit_preference;
+use crate::rita_common::exit_terms::verify_terms;
+use crate::rita_common::utils::now_secs;
+use crate::SETTING;
+use althea_types::{ExitState, ExitTerms};
+use failure::Error;
+use settings::client::{ExitServer, RitaClientSettings};
+
+/// The terms the exit is currently offering, if any
+pub fn offered_terms(exit: &ExitServer) -> Option<&ExitTerms> {
+    exit.info
+        .general_details()?
+        .terms
+        .as_ref()
+        .map(|signed| &signed.terms)
+}
+
+/// Terms the exit is offering that we haven't accepted
+pub fn pending_terms(exit: &ExitServer) -> Option<&ExitTerms> {
+    let offered = offered_terms(exit)?;
+    if exit.accepted_terms.as_ref() == Some(offered) {
+        None
+    } else {
+        Some(offered)
+    }
+}
+
+/// True if we could use the exit right now without accepting anything or paying more than
+/// `max_price`
+pub fn acceptable(exit: &ExitServer, max_price: Option<u64>) -> bool {
+    let price = match exit.info.general_details() {
+        Some(details) => details.exit_price,
+        None => return false,
+    };
+    pending_terms(exit).is_none() && max_price.map_or(true, |max| price <= max)
+}
+
+/// Updates the state we have for the exit from one of its responses, which is rejected if it
+/// carries terms or announcements the exit didn't sign. `max_price` is the most the user has
+/// said they'll pay for an exit, if they have
+pub fn update_exit_info(
+    exit: &mut ExitServer,
+    state: ExitState,
+    max_price: Option<u64>,
+) -> Result<(), Error> {
+    if let Some(signed) = state.general_details().and_then(|d| d.terms.as_ref()) {
+        verify_terms(signed, exit.id.eth_address)?;
+    }
+    verify_announcements(exit, &state)?;
+    store_announcements(exit, &state, now_secs());
+    exit.info = state;
+
+    let offered = match offered_terms(exit) {
+        Some(offered) => offered.clone(),
+        None => return Ok(()),
+    };
+    match &exit.accepted_terms {
+        None => exit.accepted_terms = Some(offered),
+        Some(accepted) if *accepted != offered && offered.acceptable_under(accepted, max_price) => {
+            info!(
+                "Accepting exit terms {:?} in place of {:?}",
+                offered, accepted
+            );
+            exit.accepted_terms = Some(offered);
+        }
+        Some(accepted) if *accepted != offered => {
+            warn!(
+                "Exit terms changed from {:?} to {:?}, waiting for acceptance",
+                accepted, offered
+            );
+        }
+        Some(_) => {}
+    }
+    Ok(())
+}
+
+/// Moves us off of the current exit if it has pending terms with a price over our maximum and
+/// the user has asked for that, the cheapest acceptable exit is picked
+pub fn price_failover() {
+    let mut exit_client = SETTING.get_exit_client_mut();
+    let max_price = match exit_client.max_exit_price {
+        Some(max_price) if exit_client.price_failover => max_price,
+        _ => return,
+    };
+    let current = match exit_client.current_exit.clone() {
+        Some(current) => current,
+        None => return,
+    };
+    let region = exit_client.region.clone();
+    let over_max = match exit_client.get_current_exit().and_then(pending_terms) {
+        Some(terms) => terms.exit_price > max_price,
+        None => false,
+    };
+    if !over_max {
+        return;
+    }
+
+    let alternative = exit_client
+        .exits
+        .iter()
+        .filter(|(name, exit)| {
+            **name != current
+                && match exit.info {
+                    ExitState::Registered { .. } => true,
+                    _ => false,
+                }
+                && acceptable(exit, Some(max_price))
+        })
+        .filter_map(|(name, exit)| {
+            let price = exit.info.general_details()?.exit_price;
+            Some((
+                name,
+                exit_preference(exit, region.as_ref().map(String::as_str)),
+                price,
+            ))
+        })
+        .min_by_key(|(_, preference, price)| (*preference, *price))
+        .map(|(name, _, _)| name.clone());
+    match alternative {
+        Some(replacement) => {
+            warn!(
+                "Exit {} raised its price over our maximum of {}, switching to {}",
+                current, max_price, replacement
+            );
+            exit_client.current_exit = Some(replacement);
+        }
+        None => warn!(
+            "Exit {} raised its price over our maximum of {} and we have no other exit!",
+            current, max_price
+        ),
+    }
+}
+
+#[test]
+fn test_exit_terms_acceptance() {
+    use crate::rita_common::exit_terms::sign_terms;
+    use crate::rita_common::utils::test_identity;
+    use althea_types::{ExitDetails, SystemChain};
+    use clarity::PrivateKey;
+
+    let key: PrivateKey = "fe1bfa04d82b1e5a73f3a2d1a6d4b0d0f0c1ef1d1ba3c5d3c0b1e0fa5b1c2d3e"
+        .parse()
+        .unwrap();
+    let terms = |exit_price: u64, jurisdiction: &str| ExitTerms {
+        exit_price,
+        free_tier_throughput: 1000,
+        jurisdiction: jurisdiction.to_string(),
+    };
+    let state = |terms: ExitTerms| ExitState::GotInfo {
+        general_details: ExitDetails {
+            server_internal_ip: "172.16.255.254".parse().unwrap(),
+            netmask: 12,
+            wg_exit_port: 59999,
+            exit_price: terms.exit_price,
+            exit_currency: SystemChain::Xdai,
+            description: String::new(),
+            verif_mode: althea_types::ExitVerifMode::Off,
+            shared_ipv4: false,
+            terms: Some(sign_terms(terms, &key).unwrap()),
+            nat64: None,
+            load: None,
+            announcements: Vec::new(),
+            port_blocks: Vec::new(),
+            signup_challenge: None,
+            time: None,
+        },
+        message: String::new(),
+        auto_register: false,
+        retry_after: None,
+    };
+    let mut id = test_identity("fd00::1");
+    id.eth_address = key.to_public_key().unwrap();
+    let mut exit = ExitServer {
+        id,
+        registration_port: 4875,
+        description: String::new(),
+        info: ExitState::New,
+        accepted_terms: None,
+        region: None,
+        priority: 0,
+        announcements: Vec::new(),
+        seen_announcement: 0,
+        signup_passphrase: None,
+        restored: false,
+    };
+
+    // the first terms are the ones we sign up under
+    update_exit_info(&mut exit, state(terms(50, "US")), None).unwrap();
+    assert_eq!(exit.accepted_terms, Some(terms(50, "US")));
+    // price drops are fine
+    update_exit_info(&mut exit, state(terms(40, "US")), None).unwrap();
+    assert!(pending_terms(&exit).is_none());
+    assert!(acceptable(&exit, Some(40)));
+    assert!(!acceptable(&exit, Some(30)));
+    // so are increases up to our maximum
+    update_exit_info(&mut exit, state(terms(60, "US")), Some(60)).unwrap();
+    assert!(pending_terms(&exit).is_none());
+    assert_eq!(exit.accepted_terms, Some(terms(60, "US")));
+    update_exit_info(&mut exit, state(terms(40, "US")), Some(60)).unwrap();
+    // anything else waits
+    update_exit_info(&mut exit, state(terms(40, "CA")), Some(60)).unwrap();
+    assert_eq!(pending_terms(&exit), Some(&terms(40, "CA")));
+    assert!(!acceptable(&exit, None));
+    update_exit_info(&mut exit, state(terms(100, "US")), Some(60)).unwrap();
+    assert_eq!(exit.accepted_terms, Some(terms(40, "US")));
+    assert_eq!(pending_terms(&exit), Some(&terms(100, "US")));
+    // until the user raises their maximum
+    update_exit_info(&mut exit, state(terms(100, "US")), Some(100)).unwrap();
+    assert!(pending_terms(&exit).is_none());
+    update_exit_info(&mut exit, state(terms(100, "CA")), Some(100)).unwrap();
+    assert_eq!(pending_terms(&exit), Some(&terms(100, "CA")));
+
+    // terms somebody else signed are rejected outright
+    let mut forged = state(terms(10, "US"));
+    if let ExitState::GotInfo {
+        ref mut general_details,
+        ..
+    } = forged
+    {
+        general_details.terms.as_mut().unwrap().terms.exit_price = 1;
+    }
+    assert!(update_exit_info(&mut exit, forged, None).is_err());
+    assert_eq!(pending_terms(&exit), Some(&terms(100, "CA")));
+}
@@ -0,0 +1,268 @@
+//! Heartbeats to the monitoring server. Sending one every tick wastes airtime on a congested radio
+//! link and money on a metered uplink, so a beat is taken every client tick but they're only sent
+//! once enough have built up for the link budget we have, all together in one packet. The more
+//! traffic we're relaying the larger the batch, and a metered WAN gets the largest. Each send is
+//! also put off by a random part of a tick, routers in the same neighborhood see the same load and
+//! would otherwise all back off and send in step.
+
+use crate::rita_client::rita_loop::{Tick, CLIENT_LOOP_SPEED};
+use crate::rita_client::wan_manager::{GetWanStatus, Uplink, WanManager};
+use crate::rita_common::usage_tracker::{
+    GetUsage, UsageGranularity, UsageHour, UsageTracker, UsageType,
+};
+use crate::rita_common::utils::now_secs;
+use crate::SETTING;
+use actix::actors::resolver;
+use actix::{Actor, Arbiter, Context, Handler, Message, Supervised, SystemService};
+use althea_types::Identity;
+use failure::Error;
+use futures01::Future;
+use rand::{thread_rng, Rng};
+use settings::RitaCommonSettings;
+use std::collections::VecDeque;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+type Resolver = resolver::Resolver;
+
+pub const HEARBEAT_MESSAGE_PORT: u16 = 33333;
+/// The most beats we'll hold on to before sending, a minute's worth at the client loop speed
+pub const HEARTBEAT_MAX_BATCH: usize = 12;
+/// How far back relay usage is averaged to decide if the link is busy, in seconds
+const HEARTBEAT_USAGE_WINDOW: u64 = 60;
+
+/// What goes over the wire, the identity stays at the top level so the monitoring server can
+/// read a batch the same way it reads a single heartbeat
+#[derive(Serialize)]
+struct HeartbeatMessage {
+    #[serde(flatten)]
+    id: Identity,
+    /// unix time of every beat in the batch
+    beats: Vec<u64>,
+}
+
+#[derive(Default)]
+pub struct Heartbeat {
+    /// unix times of the beats taken since the last send
+    pending: Vec<u64>,
+    /// how many beats to collect before sending, from the last link budget we got
+    batch_size: usize,
+}
+
+impl Actor for Heartbeat {
+    type Context = Context<Self>;
+}
+
+impl Supervised for Heartbeat {}
+impl SystemService for Heartbeat {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("Heartbeat started");
+    }
+}
+
+/// Relay throughput in kbit/s over the last `window` seconds of raw usage samples, newest first
+fn recent_relay_kbps(samples: &VecDeque<UsageHour>, now: u64, window: u64) -> u64 {
+    let bytes: u64 = samples
+        .iter()
+        .take_while(|sample| sample.index + window > now)
+        .map(|sample| sample.up + sample.down)
+        .sum();
+    bytes * 8 / 1000 / window.max(1)
+}
+
+/// How many beats go out together, one more for every multiple of `busy_kbps` we're relaying up
+/// to `HEARTBEAT_MAX_BATCH`, which is also what a metered WAN gets
+pub fn heartbeat_batch_size(relay_kbps: u64, busy_kbps: u32, metered: bool) -> usize {
+    if metered {
+        return HEARTBEAT_MAX_BATCH;
+    }
+    if busy_kbps == 0 {
+        return 1;
+    }
+    let multiple = relay_kbps / u64::from(busy_kbps);
+    (multiple as usize)
+        .saturating_add(1)
+        .min(HEARTBEAT_MAX_BATCH)
+}
+
+/// The link conditions heartbeats are budgeted against
+pub struct LinkBudget {
+    pub relay_kbps: u64,
+    pub metered: bool,
+}
+
+impl Message for LinkBudget {
+    type Result = ();
+}
+
+impl Handler<LinkBudget> for Heartbeat {
+    type Result = ();
+
+    fn handle(&mut self, msg: LinkBudget, _: &mut Context<Self>) -> Self::Result {
+        let busy_kbps = SETTING.get_log().heartbeat_busy_relay_kbps;
+        let batch_size = heartbeat_batch_size(msg.relay_kbps, busy_kbps, msg.metered);
+        if batch_size != self.batch_size {
+            info!(
+                "Sending heartbeats {} at a time, relaying {}kbps metered {}",
+                batch_size, msg.relay_kbps, msg.metered
+            );
+        }
+        self.batch_size = batch_size;
+    }
+}
+
+/// Checks relay usage and the uplink and passes the result on for the next batch
+fn check_link_budget() -> impl Future<Item = (), Error = ()> {
+    let usage = UsageTracker::from_registry().send(GetUsage {
+        kind: UsageType::Relay,
+        granularity: UsageGranularity::Raw,
+    });
+    let wan = WanManager::from_registry().send(GetWanStatus);
+    usage.join(wan).then(|res| {
+        let (usage, wan) = match res {
+            Ok((Ok(usage), Ok(wan))) => (usage, wan),
+            Ok((Err(e), _)) | Ok((_, Err(e))) => {
+                warn!("Failed to check the heartbeat link budget {:?}", e);
+                return Ok(());
+            }
+            Err(e) => {
+                warn!("Actor mailbox failure checking the link budget {:?}", e);
+                return Ok(());
+            }
+        };
+        let now = now_secs();
+        let relay_kbps = recent_relay_kbps(&usage, now, HEARTBEAT_USAGE_WINDOW);
+        let metered = SETTING.get_log().metered_wan || wan.active_uplink == Uplink::Backup;
+        Heartbeat::from_registry().do_send(LinkBudget {
+            relay_kbps,
+            metered,
+        });
+        Ok(())
+    })
+}
+
+impl Handler<Tick> for Heartbeat {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _: Tick, _: &mut Context<Self>) -> Self::Result {
+        if !SETTING.get_log().enabled {
+            self.pending.clear();
+            return Ok(());
+        }
+        let now = now_secs();
+        self.pending.push(now);
+        Arbiter::spawn(check_link_budget());
+
+        if self.pending.len() < self.batch_size.max(1) {
+            return Ok(());
+        }
+        let beats: Vec<u64> = self.pending.drain(..).collect();
+        let jitter = thread_rng().gen_range(0, CLIENT_LOOP_SPEED * 1000);
+        trace!("Sending {} heartbeats in {}ms", beats.len(), jitter);
+        Arbiter::spawn(
+            Delay::new(Instant::now() + Duration::from_millis(jitter)).then(move |_| {
+                send_udp_heartbeat(beats);
+                Ok(())
+            }),
+        );
+        Ok(())
+    }
+}
+
+fn send_udp_heartbeat(beats: Vec<u64>) {
+    let res = Resolver::from_registry()
+        .send(resolver::Resolve::host(
+            SETTING.get_log().heartbeat_url.clone(),
+        ))
+        .timeout(Duration::from_secs(1))
+        .then(move |res| match res {
+            Ok(Ok(dnsresult)) => {
+                if !dnsresult.is_empty() {
+                    for dns_socket in dnsresult {
+                        send_udp_heartbeat_packet(dns_socket, &beats);
+                    }
+                } else {
+                    trace!("Got zero length dns response: {:?}", dnsresult);
+                }
+                Ok(())
+            }
+
+            Err(e) => {
+                warn!("Actor mailbox failure from DNS resolver! {:?}", e);
+                Ok(())
+            }
+
+            Ok(Err(e)) => {
+                warn!("DNS resolution failed with {:?}", e);
+                Ok(())
+            }
+        });
+
+    Arbiter::spawn(res);
+}
+
+fn send_udp_heartbeat_packet(dns_socket: SocketAddr, beats: &[u64]) {
+    let local_socketaddr = SocketAddr::from(([0, 0, 0, 0], HEARBEAT_MESSAGE_PORT));
+    let local_socket = match UdpSocket::bind(&local_socketaddr) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Couldn't bind to UDP heartbeat socket {:?}", e);
+            return;
+        }
+    };
+
+    let remote_ip = dns_socket.ip();
+    let remote = SocketAddr::new(remote_ip, HEARBEAT_MESSAGE_PORT);
+
+    trace!("Sending heartbeat to {:?}", remote_ip);
+
+    let message = match SETTING.get_identity() {
+        Some(id) => HeartbeatMessage {
+            id,
+            beats: beats.to_vec(),
+        },
+        None => return,
+    };
+    let json_message = match serde_json::to_vec(&message) {
+        Ok(m) => m,
+        Err(_) => return,
+    };
+
+    local_socket
+        .set_write_timeout(Some(Duration::new(0, 100)))
+        .expect("Couldn't set socket timeout");
+
+    local_socket
+        .send_to(&json_message, &remote)
+        .expect("Couldn't send heartbeat");
+}
+
+#[test]
+fn test_heartbeat_batch_size() {
+    let sample = |index: u64, bytes: u64| UsageHour {
+        index,
+        up: bytes,
+        down: bytes,
+        price: 0,
+    };
+    // a minute at 1500kbps, the oldest sample is out of the window
+    let samples: VecDeque<UsageHour> = vec![
+        sample(1000, 3_750_000),
+        sample(970, 1_875_000),
+        sample(900, 100_000_000),
+    ]
+    .into_iter()
+    .collect();
+    let relay_kbps = recent_relay_kbps(&samples, 1000, 60);
+    assert_eq!(relay_kbps, 1500);
+
+    assert_eq!(heartbeat_batch_size(relay_kbps, 1000, false), 2);
+    assert_eq!(heartbeat_batch_size(relay_kbps, 500, false), 4);
+    assert_eq!(heartbeat_batch_size(500, 1000, false), 1);
+    assert_eq!(heartbeat_batch_size(relay_kbps, 0, false), 1);
+    assert_eq!(
+        heartbeat_batch_size(1_000_000, 1000, false),
+        HEARTBEAT_MAX_BATCH
+    );
+    assert_eq!(heartbeat_batch_size(0, 1000, true), HEARTBEAT_MAX_BATCH);
+}
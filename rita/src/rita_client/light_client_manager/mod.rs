@@ -9,19 +9,25 @@ use crate::rita_client::traffic_watcher::TrafficWatcher;
 use crate::rita_common::debt_keeper;
 use crate::rita_common::debt_keeper::DebtKeeper;
 use crate::rita_common::debt_keeper::Traffic;
+use crate::rita_common::hello_handler::post_to_peer;
 use crate::rita_common::peer_listener::Peer;
+use crate::rita_common::schedule;
+use crate::rita_common::traffic_watcher::counter_delta::UsageDelta;
+use crate::rita_common::tunnel_manager::handoff::{sign_handoff, verify_roam};
 use crate::rita_common::tunnel_manager::id_callback::IdentityCallback;
 use crate::rita_common::tunnel_manager::Tunnel;
 use crate::rita_common::tunnel_manager::TunnelManager;
 use crate::rita_common::utils::ip_increment::incrementv4;
+use crate::rita_common::utils::now_secs;
 use crate::KI;
 use crate::SETTING;
-use actix::{Actor, Context, Handler, Message, Supervised, SystemService};
+use actix::{Actor, Arbiter, Context, Handler, Message, Supervised, SystemService};
 use actix_web::http::StatusCode;
 use actix_web::{HttpRequest, HttpResponse, Json};
-use althea_kernel_interface::wg_iface_counter::prepare_usage_history;
-use althea_kernel_interface::wg_iface_counter::WgUsage;
-use althea_types::{Identity, LightClientLocalIdentity, LocalIdentity, WgKey};
+use althea_types::{
+    Identity, LightClientHandoff, LightClientLocalIdentity, LightClientRoam, LocalIdentity,
+    SignedLightClientHandoff, WgKey,
+};
 use failure::Error;
 use futures01::future::Either;
 use futures01::{future, Future};
@@ -62,8 +68,99 @@ fn setup_light_client_forwarding(client_addr: Ipv4Addr, nic: &str) -> Result<(),
 pub fn light_client_hello_response(
     req: (Json<LocalIdentity>, HttpRequest),
 ) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
-    let their_id = *req.0;
-    let a = LightClientManager::from_registry().send(GetAddress(their_id));
+    open_light_client_tunnel(*req.0, req.1, None)
+}
+
+/// Response to a light client roaming over from another gateway, it's given the address it had
+/// there if we can and the old gateway is told to tear down its tunnel, see
+/// tunnel_manager::handoff
+pub fn light_client_roam_response(
+    req: (Json<LightClientRoam>, HttpRequest),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let roam = req.0.into_inner();
+    if let Err(e) = check_roam(&roam) {
+        warn!("Rejected light client roam {:?}", e);
+        return Box::new(future::ok(
+            HttpResponse::new(StatusCode::FORBIDDEN)
+                .into_builder()
+                .json(e.to_string()),
+        ));
+    }
+
+    let preferred_address = Some(roam.handoff.handoff.tunnel_address);
+    Box::new(
+        open_light_client_tunnel(roam.id, req.1, preferred_address).and_then(move |response| {
+            if response.status() == StatusCode::OK {
+                notify_old_gateway(roam);
+            }
+            Ok(response)
+        }),
+    )
+}
+
+/// A light client may roam here with a handoff from another gateway that was issued to it, if it
+/// signed the move to us
+fn check_roam(roam: &LightClientRoam) -> Result<(), Error> {
+    verify_roam(roam, now_secs())?;
+    let our_id = SETTING.get_identity();
+    if Some(roam.gateway) != our_id {
+        bail!("Light client is roaming to {} not us", roam.gateway);
+    }
+    if Some(roam.handoff.handoff.gateway) == our_id {
+        bail!("Handoff is from this gateway, light clients should just say hello");
+    }
+    Ok(())
+}
+
+fn notify_old_gateway(roam: LightClientRoam) {
+    let old_gateway = SocketAddr::new(
+        roam.handoff.handoff.gateway.mesh_ip,
+        SETTING.get_network().rita_hello_port,
+    );
+    info!(
+        "Light client {} roamed here from {}",
+        roam.handoff.handoff.client, old_gateway
+    );
+    Arbiter::spawn(
+        post_to_peer(old_gateway, "/light_client/handoff", &roam).then(
+            move |res: Result<(), Error>| {
+                // the old gateway's tunnel will time out on its own if it doesn't hear this
+                if let Err(e) = res {
+                    warn!("Failed to hand off light client to {} {:?}", old_gateway, e);
+                }
+                Ok(())
+            },
+        ),
+    );
+}
+
+/// Our signed statement of the address a light client has with us, for it to take elsewhere
+fn issue_handoff(client: Identity, tunnel_address: Ipv4Addr) -> Option<SignedLightClientHandoff> {
+    let key = SETTING.get_payment().eth_private_key?;
+    let handoff = LightClientHandoff {
+        gateway: SETTING.get_identity()?,
+        client,
+        tunnel_address,
+        issued: now_secs(),
+    };
+    match sign_handoff(handoff, &key) {
+        Ok(signed) => Some(signed),
+        Err(e) => {
+            error!("Failed to sign light client handoff {:?}", e);
+            None
+        }
+    }
+}
+
+fn open_light_client_tunnel(
+    their_id: LocalIdentity,
+    req: HttpRequest,
+    preferred_address: Option<Ipv4Addr>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let a = LightClientManager::from_registry().send(GetAddress {
+        id: their_id,
+        preferred: preferred_address,
+    });
     let b = TrafficWatcher::from_registry().send(GetExitDestPrice);
 
     Box::new(
@@ -71,7 +168,7 @@ pub fn light_client_hello_response(
             .from_err()
             .and_then(move |(light_client_address, exit_dest_price)| {
                 let err_mesg = "Malformed light client hello tcp packet!";
-                let socket = match req.1.connection_info().remote() {
+                let socket = match req.connection_info().remote() {
                     Some(val) => match val.parse::<SocketAddr>() {
                         Ok(val) => val,
                         Err(e) => {
@@ -114,7 +211,7 @@ pub fn light_client_hello_response(
 
                 trace!(
                     "Got light client Hello from {:?}",
-                    req.1.connection_info().remote()
+                    req.connection_info().remote()
                 );
                 trace!(
                     "opening tunnel in light_client_hello_response for {:?}",
@@ -156,7 +253,8 @@ pub fn light_client_hello_response(
                                 wg_port: tunnel.listen_port,
                                 have_tunnel: Some(have_tunnel),
                                 tunnel_address: light_client_address,
-                                price: SETTING.get_payment().local_fee as u128 + exit_dest_price,
+                                price: schedule::local_fee() as u128 + exit_dest_price,
+                                handoff: issue_handoff(their_id.global, light_client_address),
                             };
                             // Two bools -> 4 state truth table, in 3 of
                             // those states we need to re-add these rules
@@ -188,7 +286,7 @@ pub struct LightClientManager {
     start_address: Ipv4Addr,
     prefix: u8,
     assigned_addresses: HashMap<LocalIdentity, Ipv4Addr>,
-    last_seen_bytes: HashMap<WgKey, WgUsage>,
+    last_seen_bytes: HashMap<WgKey, UsageDelta>,
 }
 
 impl Default for LightClientManager {
@@ -215,7 +313,11 @@ impl SystemService for LightClientManager {
     fn service_started(&mut self, _ctx: &mut Context<Self>) {}
 }
 
-pub struct GetAddress(LocalIdentity);
+/// Assigns a light client an address, `preferred` is the address it had on its last gateway
+pub struct GetAddress {
+    id: LocalIdentity,
+    preferred: Option<Ipv4Addr>,
+}
 
 impl Message for GetAddress {
     type Result = Result<Ipv4Addr, Error>;
@@ -225,7 +327,7 @@ impl Handler<GetAddress> for LightClientManager {
     type Result = Result<Ipv4Addr, Error>;
 
     fn handle(&mut self, msg: GetAddress, _: &mut Context<Self>) -> Self::Result {
-        let requester_id = msg.0;
+        let requester_id = msg.id;
         trace!("Assigning light client address");
         // we already have an ip for this id on record, send the same one out
         if let Some(ip) = self.assigned_addresses.get(&requester_id) {
@@ -234,11 +336,21 @@ impl Handler<GetAddress> for LightClientManager {
         let assigned_ips = {
             let mut set = HashSet::new();
             for (_id, ip) in self.assigned_addresses.iter() {
-                set.insert(ip);
+                set.insert(*ip);
             }
             set
         };
 
+        // a roaming client keeps its address if it's in our range and nobody else has it
+        if let Some(preferred) = msg.preferred {
+            if in_pool(self.start_address, self.prefix, preferred)
+                && !assigned_ips.contains(&preferred)
+            {
+                self.assigned_addresses.insert(requester_id, preferred);
+                return Ok(preferred);
+            }
+        }
+
         // get the first unused address this is kinda inefficient, I'm sure we could do this in all O(1) operations
         // but at the cost of more memory usage, which I'd rather avoid. Either way it's trivial
         // both in terms of memory and cpu at the scale of only 16 bits of address space (ipv4 private range size)
@@ -256,6 +368,18 @@ impl Handler<GetAddress> for LightClientManager {
     }
 }
 
+/// If `ip` is an address we could hand out, not the network or broadcast address of the range
+fn in_pool(start_address: Ipv4Addr, prefix: u8, ip: Ipv4Addr) -> bool {
+    let mask = if prefix == 0 {
+        0
+    } else {
+        u32::max_value() << (32 - u32::from(prefix))
+    };
+    let ip = u32::from(ip);
+    let network = u32::from(start_address) & mask;
+    ip & mask == network && ip >= u32::from(start_address) && ip != network | !mask
+}
+
 /// Returns addresses not assigned to tunnels to the pool, this is
 /// inefficient versus having tunnel manager notify us when it deletes
 /// a tunnel but it turns out getting the conditional complication required
@@ -305,23 +429,23 @@ impl Handler<Watch> for LightClientManager {
 
     fn handle(&mut self, msg: Watch, _: &mut Context<Self>) -> Self::Result {
         trace!("Starting light client traffic watcher");
-        let our_price = SETTING.get_payment().local_fee as u128 + msg.exit_dest_price;
+        let our_price = schedule::local_fee() as u128 + msg.exit_dest_price;
         let tunnels = msg.tunnels;
         let mut debts: HashMap<Identity, i128> = HashMap::new();
         for tunnel in tunnels.iter() {
             if let Some(_val) = tunnel.light_client_details {
                 if let Ok(counter) = KI.read_wg_counters(&tunnel.iface_name) {
-                    prepare_usage_history(&counter, &mut self.last_seen_bytes);
                     // there should only be one, more than one client on a single
                     // interface is not supported
                     assert!(counter.len() == 1);
                     // get only the first element
                     let (key, usage) = counter.iter().next().unwrap();
-                    // unwrap is safe before prepare usage history will ensure an entry exits
-                    let last_seen_usage = self.last_seen_bytes.get_mut(&key).unwrap();
-                    let round_upload = usage.upload - last_seen_usage.upload;
-                    let round_download = usage.download - last_seen_usage.download;
-                    *last_seen_usage = *usage;
+                    // clients we haven't seen before are counted from here on
+                    let (round_upload, round_download) = self
+                        .last_seen_bytes
+                        .entry(*key)
+                        .or_insert_with(|| UsageDelta::starting_at(usage.upload, usage.download))
+                        .update(&tunnel.iface_name, usage.upload, usage.download);
                     let debt = ((round_upload + round_download) * our_price as u64) as i128;
                     subtract_or_insert_and_subtract(&mut debts, tunnel.neigh_id.global, debt);
                 }
@@ -353,3 +477,14 @@ fn subtract_or_insert_and_subtract(data: &mut HashMap<Identity, i128>, i: Identi
         data.insert(i, -debt);
     }
 }
+
+#[test]
+fn test_light_client_pool() {
+    let start: Ipv4Addr = "192.168.20.1".parse().unwrap();
+    assert!(in_pool(start, 24, "192.168.20.1".parse().unwrap()));
+    assert!(in_pool(start, 24, "192.168.20.254".parse().unwrap()));
+    assert!(!in_pool(start, 24, "192.168.20.0".parse().unwrap()));
+    assert!(!in_pool(start, 24, "192.168.20.255".parse().unwrap()));
+    assert!(!in_pool(start, 24, "192.168.21.1".parse().unwrap()));
+    assert!(!in_pool(start, 24, "10.0.0.1".parse().unwrap()));
+}
@@ -1,8 +1,16 @@
+pub mod captive_portal;
 pub mod dashboard;
 pub mod exit_manager;
+pub mod heartbeat;
 pub mod light_client_manager;
+pub mod protective_mode;
+pub mod provisioning;
+pub mod remote_management;
 pub mod rita_loop;
+pub mod split_tunnel;
+pub mod topup;
 pub mod traffic_watcher;
+pub mod wan_manager;
 
 use crate::SETTING;
 use compressed_log::builder::LoggerBuilder;
@@ -0,0 +1,120 @@
+//! Protective mode keeps a router with a nearly empty wallet limping along instead of cutting it
+//! off abruptly once the money runs out. Below the configured balance threshold LAN downloads are
+//! limited to a trickle with tc, so there's little background traffic left to pay for, and the
+//! captive portal shows each device the low balance notice now and then. All of it is lifted as
+//! soon as the balance is topped up.
+
+use crate::rita_client::captive_portal::{CaptivePortal, SetProtectiveMode, LAN_NIC};
+use crate::rita_client::rita_loop::Tick;
+use crate::rita_common::utils::now_secs;
+use crate::KI;
+use crate::SETTING;
+use actix::{Actor, Context, Handler, Message, Supervised, SystemService};
+use failure::Error;
+use num256::Uint256;
+use settings::client::RitaClientSettings;
+use settings::protective_mode::ProtectiveModeSettings;
+use settings::RitaCommonSettings;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProtectiveModeStatus {
+    pub active: bool,
+    /// unix time protective mode was last turned on
+    pub since: Option<u64>,
+    pub balance: Uint256,
+    pub settings: ProtectiveModeSettings,
+}
+
+#[derive(Default)]
+pub struct ProtectiveMode {
+    active: bool,
+    since: Option<u64>,
+    /// the LAN limit currently in place, if any
+    trickle_mbps: Option<usize>,
+}
+
+impl Actor for ProtectiveMode {
+    type Context = Context<Self>;
+}
+
+impl Supervised for ProtectiveMode {}
+impl SystemService for ProtectiveMode {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("Protective mode started");
+    }
+}
+
+/// If protective mode should be on for this balance
+fn should_protect(settings: &ProtectiveModeSettings, balance: &Uint256) -> bool {
+    settings.enabled && *balance < settings.threshold
+}
+
+impl Handler<Tick> for ProtectiveMode {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _: Tick, _ctx: &mut Context<Self>) -> Self::Result {
+        let settings = SETTING.get_protective_mode().clone();
+        let balance = SETTING.get_payment().balance.clone();
+        let active = should_protect(&settings, &balance);
+
+        if active != self.active {
+            if active {
+                warn!(
+                    "Balance {} is below {}, entering protective mode",
+                    balance, settings.threshold
+                );
+                self.since = Some(now_secs());
+            } else {
+                info!("Balance {} topped up, leaving protective mode", balance);
+                self.since = None;
+            }
+            self.active = active;
+            CaptivePortal::from_registry().do_send(SetProtectiveMode(active));
+        }
+        let limit = if active {
+            Some(settings.trickle_mbps)
+        } else {
+            None
+        };
+        if limit != self.trickle_mbps {
+            KI.set_codel_shaping(LAN_NIC, limit)?;
+            self.trickle_mbps = limit;
+        }
+        Ok(())
+    }
+}
+
+pub struct GetProtectiveModeStatus;
+
+impl Message for GetProtectiveModeStatus {
+    type Result = Result<ProtectiveModeStatus, Error>;
+}
+
+impl Handler<GetProtectiveModeStatus> for ProtectiveMode {
+    type Result = Result<ProtectiveModeStatus, Error>;
+
+    fn handle(&mut self, _: GetProtectiveModeStatus, _ctx: &mut Context<Self>) -> Self::Result {
+        Ok(ProtectiveModeStatus {
+            active: self.active,
+            since: self.since,
+            balance: SETTING.get_payment().balance.clone(),
+            settings: SETTING.get_protective_mode().clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_protect() {
+        let mut settings = ProtectiveModeSettings::default();
+        let low: Uint256 = 1u32.into();
+        assert!(!should_protect(&settings, &low));
+
+        settings.enabled = true;
+        assert!(should_protect(&settings, &low));
+        assert!(!should_protect(&settings, &settings.threshold.clone()));
+    }
+}
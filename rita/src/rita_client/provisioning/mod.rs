@@ -0,0 +1,358 @@
+//! Zero touch provisioning, so that an operator deploying dozens of routers doesn't have to set
+//! each one up through the dashboard. Images built for the operator ship with the key their
+//! profiles are signed with and a url or file to get the profile from. A router that has never
+//! been set up (no profile applied and no exits) fetches the profile, checks its signature and
+//! applies it: the exit list, bounds on what we charge, the wifi name and how to reach the
+//! operator. Once applied we tell the operator's adoption endpoint, retrying until it hears us,
+//! and never provision again.
+
+use crate::rita_client::dashboard::wifi::{set_ssid, wifi_radios, WifiSSID};
+use crate::rita_client::exit_manager::exit_list::{merge_exits, ExitList};
+use crate::rita_client::rita_loop::Tick;
+use crate::ARGS;
+use crate::SETTING;
+use actix::{Actor, Arbiter, Context, Handler, Message, Supervised, SystemService};
+use actix_web::client;
+use actix_web::HttpMessage;
+use althea_types::{Identity, SigningPubkey};
+use failure::Error;
+use futures01::future;
+use futures01::Future;
+use settings::client::RitaClientSettings;
+use settings::payment::PaymentSettings;
+use settings::provisioning::OperatorContact;
+use settings::FileWrite;
+use settings::RitaCommonSettings;
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// How long to wait between attempts to fetch the profile or report adoption
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const PROVISIONING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Limits on what we charge, local_fee is brought within them when the profile is applied
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PriceBounds {
+    pub min_local_fee: u32,
+    pub max_local_fee: u32,
+    /// replaces max_fee if set
+    #[serde(default)]
+    pub max_fee: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProvisioningProfile {
+    /// identifies the profile in adoption reports
+    pub id: String,
+    #[serde(default)]
+    pub exits: Option<ExitList>,
+    #[serde(default)]
+    pub price_bounds: Option<PriceBounds>,
+    /// the ssid for every radio, `{suffix}` is replaced with the last four hex digits of our mesh
+    /// ip so that neighboring routers can be told apart
+    #[serde(default)]
+    pub ssid_template: Option<String>,
+    #[serde(default)]
+    pub operator_contact: Option<OperatorContact>,
+    /// where to report adoption to, must be https
+    #[serde(default)]
+    pub adoption_url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedProvisioningProfile {
+    /// the json encoded ProvisioningProfile, the signature is over these exact bytes
+    pub profile: String,
+    pub signature: Vec<u8>,
+}
+
+/// What we tell the operator once we've applied their profile
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AdoptionReport {
+    pub profile: String,
+    pub identity: Option<Identity>,
+    pub version: String,
+}
+
+pub fn parse_profile(body: &[u8], pubkey: &SigningPubkey) -> Result<ProvisioningProfile, Error> {
+    let signed: SignedProvisioningProfile = serde_json::from_slice(body)?;
+    if !pubkey.verify(signed.profile.as_bytes(), &signed.signature) {
+        bail!("Provisioning profile signature is invalid");
+    }
+    let profile: ProvisioningProfile = serde_json::from_str(&signed.profile)?;
+    if let Some(ref bounds) = profile.price_bounds {
+        if bounds.min_local_fee > bounds.max_local_fee {
+            bail!("Provisioning profile price bounds are reversed");
+        }
+    }
+    if let Some(ref url) = profile.adoption_url {
+        if !url.starts_with("https://") {
+            bail!("Provisioning profile adoption url isn't https");
+        }
+    }
+    Ok(profile)
+}
+
+pub fn expand_ssid_template(template: &str, mesh_ip: Option<IpAddr>) -> String {
+    let suffix = match mesh_ip {
+        Some(IpAddr::V6(ip)) => format!("{:04x}", ip.segments()[7]),
+        Some(IpAddr::V4(ip)) => format!("{:02x}{:02x}", ip.octets()[2], ip.octets()[3]),
+        None => String::new(),
+    };
+    template.replace("{suffix}", &suffix)
+}
+
+pub fn apply_price_bounds(bounds: &PriceBounds, payment: &mut PaymentSettings) {
+    if let Some(max_fee) = bounds.max_fee {
+        payment.max_fee = max_fee;
+    }
+    payment.local_fee = payment
+        .local_fee
+        .max(bounds.min_local_fee)
+        .min(bounds.max_local_fee);
+}
+
+/// True for a router that has never been set up and knows where to get a profile
+fn needs_provisioning() -> bool {
+    let has_exits = !SETTING.get_exits().is_empty();
+    let provisioning = SETTING.get_provisioning();
+    !has_exits
+        && provisioning.pubkey.is_some()
+        && provisioning.applied_profile.is_none()
+        && (provisioning.profile_url.is_some() || provisioning.profile_file.is_some())
+}
+
+fn needs_adoption_report() -> bool {
+    let provisioning = SETTING.get_provisioning();
+    provisioning.applied_profile.is_some()
+        && provisioning.adoption_url.is_some()
+        && !provisioning.adoption_reported
+}
+
+/// The profile from the file if there is one, otherwise from the url
+fn fetch_profile() -> Box<dyn Future<Item = Vec<u8>, Error = Error>> {
+    let provisioning = SETTING.get_provisioning().clone();
+    if let Some(path) = provisioning.profile_file {
+        match fs::read(&path) {
+            Ok(body) => return Box::new(future::ok(body)),
+            Err(e) => trace!("No provisioning profile at {}: {:?}", path, e),
+        }
+    }
+    let url = match provisioning.profile_url {
+        Some(url) => url,
+        None => return Box::new(future::err(format_err!("No provisioning profile found"))),
+    };
+    if !url.starts_with("https://") {
+        return Box::new(future::err(format_err!(
+            "Provisioning profile url {} isn't https",
+            url
+        )));
+    }
+    let request = match client::get(&url).finish() {
+        Ok(val) => val,
+        Err(e) => return Box::new(future::err(format_err!("{:?}", e))),
+    };
+    Box::new(
+        request
+            .send()
+            .timeout(PROVISIONING_TIMEOUT)
+            .from_err()
+            .and_then(|response| {
+                if !response.status().is_success() {
+                    return future::Either::A(future::err(format_err!(
+                        "Fetching the provisioning profile failed with {}",
+                        response.status()
+                    )));
+                }
+                future::Either::B(response.body().from_err().map(|body| body.to_vec()))
+            }),
+    )
+}
+
+/// Everything in the profile is worked out before any of it is applied, so that a profile that
+/// fails part way leaves the settings as they were and is tried again in full
+fn apply_profile(profile: ProvisioningProfile) -> Result<(), Error> {
+    info!("Applying provisioning profile {}", profile.id);
+    let exits = match profile.exits {
+        Some(exits) => exits.into_exits()?,
+        None => HashMap::new(),
+    };
+    let mut payment = SETTING.get_payment().clone();
+    if let Some(ref bounds) = profile.price_bounds {
+        apply_price_bounds(bounds, &mut payment);
+    }
+    // the ssid is the only part that goes outside of the settings, it's set again if we fail
+    // after this and that's harmless
+    if let Some(ref template) = profile.ssid_template {
+        let ssid = expand_ssid_template(template, SETTING.get_network().mesh_ip);
+        for radio in wifi_radios()? {
+            set_ssid(&WifiSSID {
+                radio,
+                ssid: ssid.clone(),
+            })?;
+        }
+    }
+
+    merge_exits(&mut SETTING.get_exits_mut(), exits);
+    {
+        let mut current = SETTING.get_payment_mut();
+        current.local_fee = payment.local_fee;
+        current.max_fee = payment.max_fee;
+    }
+    {
+        let mut provisioning = SETTING.get_provisioning_mut();
+        provisioning.operator_contact = profile.operator_contact;
+        provisioning.adoption_url = profile.adoption_url;
+        provisioning.adoption_reported = false;
+        provisioning.applied_profile = Some(profile.id);
+    }
+    SETTING.write().unwrap().write(&ARGS.flag_config)
+}
+
+fn report_adoption() -> Box<dyn Future<Item = (), Error = Error>> {
+    let provisioning = SETTING.get_provisioning().clone();
+    let (url, profile) = match (provisioning.adoption_url, provisioning.applied_profile) {
+        (Some(url), Some(profile)) => (url, profile),
+        _ => return Box::new(future::ok(())),
+    };
+    let report = AdoptionReport {
+        profile,
+        identity: SETTING.get_identity(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+    };
+    let request = match client::post(&url).json(report) {
+        Ok(val) => val,
+        Err(e) => return Box::new(future::err(format_err!("{:?}", e))),
+    };
+    Box::new(
+        request
+            .send()
+            .timeout(PROVISIONING_TIMEOUT)
+            .from_err()
+            .and_then(move |response| {
+                if !response.status().is_success() {
+                    bail!("Adoption report failed with {}", response.status());
+                }
+                info!("Reported adoption to {}", url);
+                SETTING.get_provisioning_mut().adoption_reported = true;
+                SETTING.write().unwrap().write(&ARGS.flag_config)
+            }),
+    )
+}
+
+#[derive(Default)]
+pub struct Provisioner {
+    in_progress: bool,
+    last_attempt: Option<Instant>,
+}
+
+impl Actor for Provisioner {
+    type Context = Context<Self>;
+}
+
+impl Supervised for Provisioner {}
+impl SystemService for Provisioner {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("Provisioner started");
+    }
+}
+
+impl Handler<Tick> for Provisioner {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _: Tick, _ctx: &mut Context<Self>) -> Self::Result {
+        let due = self
+            .last_attempt
+            .map_or(true, |last| last.elapsed() > RETRY_INTERVAL);
+        if self.in_progress || !due {
+            return Ok(());
+        }
+        let attempt: Box<dyn Future<Item = (), Error = Error>> = if needs_provisioning() {
+            let pubkey = SETTING.get_provisioning().pubkey.unwrap();
+            Box::new(
+                fetch_profile()
+                    .and_then(move |body| parse_profile(&body, &pubkey))
+                    .and_then(apply_profile)
+                    .and_then(|_| report_adoption()),
+            )
+        } else if needs_adoption_report() {
+            report_adoption()
+        } else {
+            return Ok(());
+        };
+
+        self.in_progress = true;
+        self.last_attempt = Some(Instant::now());
+        Arbiter::spawn(attempt.then(|res| {
+            if let Err(e) = res {
+                warn!("Provisioning failed with {:?}", e);
+            }
+            Provisioner::from_registry().do_send(ProvisioningDone);
+            Ok(())
+        }));
+        Ok(())
+    }
+}
+
+struct ProvisioningDone;
+
+impl Message for ProvisioningDone {
+    type Result = ();
+}
+
+impl Handler<ProvisioningDone> for Provisioner {
+    type Result = ();
+
+    fn handle(&mut self, _: ProvisioningDone, _ctx: &mut Context<Self>) -> Self::Result {
+        self.in_progress = false;
+    }
+}
+
+#[test]
+fn test_provisioning_profile() {
+    let profile = json!({
+        "id": "acme-2020-01",
+        "price_bounds": { "min_local_fee": 100, "max_local_fee": 500, "max_fee": 1000 },
+        "ssid_template": "Acme {suffix}",
+        "operator_contact": { "name": "Acme", "email": "support@acme.example" },
+        "adoption_url": "https://acme.example/adopted",
+    })
+    .to_string();
+    use sodiumoxide::crypto::sign;
+
+    let (pubkey, secretkey) = sign::gen_keypair();
+    let pubkey = SigningPubkey::from(pubkey);
+    let sign::Signature(signature) = sign::sign_detached(profile.as_bytes(), &secretkey);
+    let signed = SignedProvisioningProfile {
+        profile: profile.clone(),
+        signature: signature.to_vec(),
+    };
+    let signed_bytes = serde_json::to_vec(&signed).unwrap();
+
+    let parsed = parse_profile(&signed_bytes, &pubkey).unwrap();
+    assert_eq!(parsed.id, "acme-2020-01");
+    let (other_pubkey, _) = sign::gen_keypair();
+    assert!(parse_profile(&signed_bytes, &other_pubkey.into()).is_err());
+    let mut tampered = signed;
+    tampered.profile = tampered.profile.replace("500", "5000");
+    assert!(parse_profile(&serde_json::to_vec(&tampered).unwrap(), &pubkey).is_err());
+
+    let mut payment = PaymentSettings::default();
+    payment.local_fee = 0;
+    apply_price_bounds(parsed.price_bounds.as_ref().unwrap(), &mut payment);
+    assert_eq!(payment.local_fee, 100);
+    assert_eq!(payment.max_fee, 1000);
+    payment.local_fee = 900;
+    apply_price_bounds(parsed.price_bounds.as_ref().unwrap(), &mut payment);
+    assert_eq!(payment.local_fee, 500);
+
+    assert_eq!(
+        expand_ssid_template(
+            parsed.ssid_template.as_ref().unwrap(),
+            Some("fd00::1337:e2f".parse().unwrap())
+        ),
+        "Acme 0e2f"
+    );
+}
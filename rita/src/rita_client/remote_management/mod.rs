@@ -0,0 +1,435 @@
+//! Remote management for operators running fleets of routers. Every few minutes we check in with
+//! the operator over https, next to the heartbeats which only go one way, and get back whatever
+//! commands are waiting for us: a new price, more exits or a new wifi name. A command is only
+//! applied if it's signed with the operator key in our settings, meant for us or for every
+//! router, not expired and newer than the last one we took, so a command that's been captured
+//! can't be replayed. Everything we're sent is written to an audit log with what came of it,
+//! including commands that were turned down.
+//!
+//! Commands are applied the same way as the dashboard does it, a new price is kept within
+//! max_fee and only taken once babel has it, and exits are checked like any exit list.
+
+use crate::rita_client::dashboard::wifi::{set_ssid, wifi_radios, WifiSSID};
+use crate::rita_client::exit_manager::exit_list::{merge_exits, ExitList};
+use crate::rita_client::rita_loop::Tick;
+use crate::rita_common::dashboard::babel::apply_local_fee;
+use crate::rita_common::utils::now_secs;
+use crate::ARGS;
+use crate::SETTING;
+use actix::{Actor, Arbiter, Context, Handler, Message, Supervised, SystemService};
+use actix_web::client;
+use actix_web::HttpMessage;
+use althea_types::{Identity, SigningPubkey};
+use failure::Error;
+use futures01::future;
+use futures01::stream;
+use futures01::{Future, Stream};
+use settings::client::RitaClientSettings;
+use settings::FileWrite;
+use settings::RitaCommonSettings;
+use std::fs::{metadata, rename, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+const CHECKIN_TIMEOUT: Duration = Duration::from_secs(10);
+/// The audit log is moved aside past this size, so at most twice this is kept
+const AUDIT_LOG_MAX_SIZE: u64 = 64 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RemoteCommand {
+    SetPrice {
+        local_fee: u32,
+    },
+    /// added to our exits, registration with exits we already have is kept
+    AddExits {
+        exits: ExitList,
+    },
+    /// the ssid for every radio
+    SetSsid {
+        ssid: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommandEnvelope {
+    /// goes up with every command the operator sends
+    pub sequence: u64,
+    /// the mesh ip of the router this is for, None for every router on the operator's key
+    #[serde(default)]
+    pub target: Option<IpAddr>,
+    /// unix time after which the command isn't applied
+    pub expires: u64,
+    pub command: RemoteCommand,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedCommand {
+    /// the json encoded CommandEnvelope, the signature is over these exact bytes
+    pub command: String,
+    pub signature: Vec<u8>,
+}
+
+/// What we send when checking in, the operator replies with a list of `SignedCommand`s
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CheckIn {
+    pub identity: Option<Identity>,
+    pub last_command: u64,
+    pub version: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    /// seconds since the unix epoch
+    pub time: u64,
+    /// None if the command couldn't be read at all, for refused commands these are what the
+    /// command claimed to be
+    pub sequence: Option<u64>,
+    pub command: Option<RemoteCommand>,
+    /// None if the command was applied, otherwise why it wasn't
+    pub error: Option<String>,
+}
+
+/// Checks a command was signed by the operator, is for us, still current and newer than the
+/// last one we took
+pub fn verify_command(
+    signed: &SignedCommand,
+    pubkey: &SigningPubkey,
+    mesh_ip: Option<IpAddr>,
+    now: u64,
+    last_command: u64,
+) -> Result<CommandEnvelope, Error> {
+    if !pubkey.verify(signed.command.as_bytes(), &signed.signature) {
+        bail!("Command signature is invalid");
+    }
+    let envelope: CommandEnvelope = serde_json::from_str(&signed.command)?;
+    if let Some(target) = envelope.target {
+        if Some(target) != mesh_ip {
+            bail!("Command {} is for {}", envelope.sequence, target);
+        }
+    }
+    if envelope.sequence <= last_command {
+        bail!(
+            "Command {} is at or before the last one we took, {}",
+            envelope.sequence,
+            last_command
+        );
+    }
+    if envelope.expires < now {
+        bail!(
+            "Command {} expired at {}",
+            envelope.sequence,
+            envelope.expires
+        );
+    }
+    Ok(envelope)
+}
+
+fn apply_command(command: &RemoteCommand) -> Box<dyn Future<Item = (), Error = Error>> {
+    match command {
+        RemoteCommand::SetPrice { local_fee } => {
+            let requested = *local_fee;
+            Box::new(apply_local_fee(requested).map(move |fee| {
+                if fee != requested {
+                    info!(
+                        "Remote price of {} brought down to max_fee {}",
+                        requested, fee
+                    );
+                }
+            }))
+        }
+        RemoteCommand::AddExits { exits } => {
+            Box::new(future::result(exits.clone().into_exits().map(
+                |new_exits| merge_exits(&mut SETTING.get_exits_mut(), new_exits),
+            )))
+        }
+        RemoteCommand::SetSsid { ssid } => Box::new(future::result(apply_ssid(ssid))),
+    }
+}
+
+fn apply_ssid(ssid: &str) -> Result<(), Error> {
+    for radio in wifi_radios()? {
+        set_ssid(&WifiSSID {
+            radio,
+            ssid: ssid.to_string(),
+        })?;
+    }
+    Ok(())
+}
+
+fn rotated_path(path: &str) -> String {
+    format!("{}.1", path)
+}
+
+fn write_audit(path: &str, entry: &AuditEntry) -> Result<(), Error> {
+    if let Ok(meta) = metadata(path) {
+        if meta.len() > AUDIT_LOG_MAX_SIZE {
+            rename(path, rotated_path(path))?;
+        }
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(format!("{}\n", serde_json::to_string(entry)?).as_bytes())?;
+    Ok(())
+}
+
+fn audit(entry: AuditEntry) {
+    let path = SETTING
+        .get_provisioning()
+        .remote_management
+        .audit_log_file
+        .clone();
+    if let Err(e) = write_audit(&path, &entry) {
+        error!("Failed to write remote management audit log {:?}", e);
+    }
+}
+
+/// Every command in the audit log, oldest first
+pub fn audit_log() -> Result<Vec<AuditEntry>, Error> {
+    let path = SETTING
+        .get_provisioning()
+        .remote_management
+        .audit_log_file
+        .clone();
+    let mut ret = Vec::new();
+    for file in [rotated_path(&path), path].iter() {
+        let file = match File::open(file) {
+            Ok(f) => f,
+            Err(_) => continue,
+        };
+        for line in BufReader::new(file).lines() {
+            match serde_json::from_str(&line?) {
+                Ok(entry) => ret.push(entry),
+                Err(e) => warn!("Skipping corrupt audit log line {:?}", e),
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Verifies, applies and logs the commands from a check in, in the order they were sent, one
+/// after the other
+fn process_commands(
+    commands: Vec<SignedCommand>,
+    pubkey: &SigningPubkey,
+) -> impl Future<Item = (), Error = Error> {
+    let mesh_ip = SETTING.get_network().mesh_ip;
+    let last_command = SETTING.get_provisioning().remote_management.last_command;
+    let mut envelopes = Vec::new();
+    for signed in commands {
+        // only trusted once it's verified, this is just for the log
+        let unverified: Option<CommandEnvelope> = serde_json::from_str(&signed.command).ok();
+        if let Some(ref unverified) = unverified {
+            if unverified.sequence <= last_command {
+                trace!(
+                    "Skipping remote command {} we already took",
+                    unverified.sequence
+                );
+                continue;
+            }
+        }
+        match verify_command(&signed, pubkey, mesh_ip, now_secs(), last_command) {
+            Ok(envelope) => envelopes.push(envelope),
+            Err(e) => {
+                warn!("Refusing remote command {:?}", e);
+                audit(AuditEntry {
+                    time: now_secs(),
+                    sequence: unverified.as_ref().map(|envelope| envelope.sequence),
+                    command: unverified.map(|envelope| envelope.command),
+                    error: Some(e.to_string()),
+                });
+            }
+        }
+    }
+    envelopes.sort_by_key(|envelope| envelope.sequence);
+    stream::iter_ok::<_, Error>(envelopes)
+        .fold(last_command, |_, envelope| {
+            apply_command(&envelope.command).then(move |res| -> Result<u64, Error> {
+                // a signed command that fails to apply is still taken, retrying it would fail
+                // the same way
+                let error = match res {
+                    Ok(()) => {
+                        info!(
+                            "Applied remote command {} {:?}",
+                            envelope.sequence, envelope.command
+                        );
+                        None
+                    }
+                    Err(e) => {
+                        error!(
+                            "Failed to apply remote command {} {:?}",
+                            envelope.sequence, e
+                        );
+                        Some(e.to_string())
+                    }
+                };
+                audit(AuditEntry {
+                    time: now_secs(),
+                    sequence: Some(envelope.sequence),
+                    command: Some(envelope.command),
+                    error,
+                });
+                Ok(envelope.sequence)
+            })
+        })
+        .and_then(|last_command| {
+            let mut provisioning = SETTING.get_provisioning_mut();
+            if provisioning.remote_management.last_command == last_command {
+                return Ok(());
+            }
+            provisioning.remote_management.last_command = last_command;
+            drop(provisioning);
+            SETTING.write().unwrap().write(&ARGS.flag_config)
+        })
+}
+
+fn check_in(url: String, pubkey: SigningPubkey) -> Box<dyn Future<Item = (), Error = Error>> {
+    if !url.starts_with("https://") {
+        return Box::new(future::err(format_err!("Check in url {} isn't https", url)));
+    }
+    let checkin = CheckIn {
+        identity: SETTING.get_identity(),
+        last_command: SETTING.get_provisioning().remote_management.last_command,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+    };
+    let request = match client::post(&url).json(checkin) {
+        Ok(val) => val,
+        Err(e) => return Box::new(future::err(format_err!("{:?}", e))),
+    };
+    Box::new(
+        request
+            .send()
+            .timeout(CHECKIN_TIMEOUT)
+            .from_err()
+            .and_then(|response| {
+                if !response.status().is_success() {
+                    return future::Either::A(future::err(format_err!(
+                        "Check in failed with {}",
+                        response.status()
+                    )));
+                }
+                future::Either::B(response.body().from_err())
+            })
+            .and_then(move |body| {
+                let commands: Vec<SignedCommand> = match serde_json::from_slice(&body) {
+                    Ok(commands) => commands,
+                    Err(e) => return future::Either::A(future::err(e.into())),
+                };
+                trace!("Got {} remote commands", commands.len());
+                future::Either::B(process_commands(commands, &pubkey))
+            }),
+    )
+}
+
+#[derive(Default)]
+pub struct RemoteManager {
+    in_progress: bool,
+    last_checkin: Option<Instant>,
+}
+
+impl Actor for RemoteManager {
+    type Context = Context<Self>;
+}
+
+impl Supervised for RemoteManager {}
+impl SystemService for RemoteManager {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("RemoteManager started");
+    }
+}
+
+impl Handler<Tick> for RemoteManager {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _: Tick, _ctx: &mut Context<Self>) -> Self::Result {
+        let settings = SETTING.get_provisioning().remote_management.clone();
+        let (url, pubkey) = match (settings.checkin_url, settings.pubkey) {
+            (Some(url), Some(pubkey)) => (url, pubkey),
+            _ => return Ok(()),
+        };
+        let interval = Duration::from_secs(settings.checkin_interval);
+        let due = self
+            .last_checkin
+            .map_or(true, |last| last.elapsed() > interval);
+        if self.in_progress || !due {
+            return Ok(());
+        }
+        self.in_progress = true;
+        self.last_checkin = Some(Instant::now());
+        Arbiter::spawn(check_in(url, pubkey).then(|res| {
+            if let Err(e) = res {
+                warn!("Remote management check in failed with {:?}", e);
+            }
+            RemoteManager::from_registry().do_send(CheckInDone);
+            Ok(())
+        }));
+        Ok(())
+    }
+}
+
+struct CheckInDone;
+
+impl Message for CheckInDone {
+    type Result = ();
+}
+
+impl Handler<CheckInDone> for RemoteManager {
+    type Result = ();
+
+    fn handle(&mut self, _: CheckInDone, _ctx: &mut Context<Self>) -> Self::Result {
+        self.in_progress = false;
+    }
+}
+
+#[test]
+fn test_verify_command() {
+    use sodiumoxide::crypto::sign;
+
+    let (pubkey, secretkey) = sign::gen_keypair();
+    let pubkey = SigningPubkey::from(pubkey);
+    let us: IpAddr = "fd00::1".parse().unwrap();
+    let signed = |sequence: u64, target: Option<IpAddr>, expires: u64| {
+        let command = serde_json::to_string(&CommandEnvelope {
+            sequence,
+            target,
+            expires,
+            command: RemoteCommand::SetPrice { local_fee: 300 },
+        })
+        .unwrap();
+        let sign::Signature(signature) = sign::sign_detached(command.as_bytes(), &secretkey);
+        SignedCommand {
+            command,
+            signature: signature.to_vec(),
+        }
+    };
+    let now = 1_580_000_000;
+
+    let envelope =
+        verify_command(&signed(5, Some(us), now + 60), &pubkey, Some(us), now, 4).unwrap();
+    match envelope.command {
+        RemoteCommand::SetPrice { local_fee } => assert_eq!(local_fee, 300),
+        _ => panic!("Wrong command"),
+    }
+    // for every router
+    assert!(verify_command(&signed(5, None, now + 60), &pubkey, Some(us), now, 4).is_ok());
+
+    // replayed, expired or for another router
+    assert!(verify_command(&signed(4, None, now + 60), &pubkey, Some(us), now, 4).is_err());
+    assert!(verify_command(&signed(5, None, now - 1), &pubkey, Some(us), now, 4).is_err());
+    let other: IpAddr = "fd00::2".parse().unwrap();
+    assert!(verify_command(&signed(5, Some(other), now + 60), &pubkey, Some(us), now, 4).is_err());
+
+    // signed by someone else or tampered with
+    let other_pubkey = SigningPubkey::from(sign::gen_keypair().0);
+    assert!(verify_command(&signed(5, None, now + 60), &other_pubkey, Some(us), now, 4).is_err());
+    let mut tampered = signed(5, None, now + 60);
+    tampered.command = tampered.command.replace("300", "3000");
+    assert!(verify_command(&tampered, &pubkey, Some(us), now, 4).is_err());
+
+    let parsed: RemoteCommand =
+        serde_json::from_str(r#"{"type": "set_ssid", "ssid": "Acme"}"#).unwrap();
+    match parsed {
+        RemoteCommand::SetSsid { ssid } => assert_eq!(ssid, "Acme"),
+        _ => panic!("Wrong command"),
+    }
+}
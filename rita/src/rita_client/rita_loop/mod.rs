@@ -4,32 +4,39 @@
 //! This loop manages exit signup based on the settings configuration state and deploys an exit vpn
 //! tunnel if the signup was successful on the selected exit.
 
+use crate::middleware;
+use crate::rita_client::captive_portal::CaptivePortal;
 use crate::rita_client::exit_manager::ExitManager;
+use crate::rita_client::heartbeat::Heartbeat;
 use crate::rita_client::light_client_manager::light_client_hello_response;
+use crate::rita_client::light_client_manager::light_client_roam_response;
 use crate::rita_client::light_client_manager::LightClientManager;
 use crate::rita_client::light_client_manager::Watch;
+use crate::rita_client::protective_mode::ProtectiveMode;
+use crate::rita_client::provisioning::Provisioner;
+use crate::rita_client::remote_management::RemoteManager;
+use crate::rita_client::topup::TopUp;
 use crate::rita_client::traffic_watcher::GetExitDestPrice;
 use crate::rita_client::traffic_watcher::TrafficWatcher;
+use crate::rita_client::traffic_watcher::WatchSponsored;
 use crate::rita_client::traffic_watcher::WeAreGatewayClient;
+use crate::rita_client::wan_manager::WanManager;
 use crate::rita_common::tunnel_manager::GetNeighbors;
 use crate::rita_common::tunnel_manager::GetTunnels;
 use crate::rita_common::tunnel_manager::TunnelManager;
+use crate::rita_common::watchdog::{common_watched, LoopTime, Watchdog, Watched};
 use crate::SETTING;
-use actix::actors::resolver;
 use actix::{
     Actor, ActorContext, Addr, Arbiter, AsyncContext, Context, Handler, Message, Supervised,
     SystemService,
 };
 use actix_web::http::Method;
 use actix_web::{server, App};
-use althea_types::ExitState;
 use failure::Error;
 use futures01::future::Future;
 use settings::client::RitaClientSettings;
 use settings::RitaCommonSettings;
-use std::net::{SocketAddr, UdpSocket};
 use std::time::{Duration, Instant};
-type Resolver = resolver::Resolver;
 
 #[derive(Default)]
 pub struct RitaLoop;
@@ -38,8 +45,6 @@ pub struct RitaLoop;
 pub const CLIENT_LOOP_SPEED: u64 = 5;
 pub const CLIENT_LOOP_TIMEOUT: Duration = Duration::from_secs(4);
 
-pub const HEARBEAT_MESSAGE_PORT: u16 = 33333;
-
 impl Actor for RitaLoop {
     type Context = Context<Self>;
 
@@ -85,8 +90,34 @@ impl Handler<Tick> for RitaLoop {
         let start = Instant::now();
         trace!("Client Tick!");
 
+        Provisioner::from_registry().do_send(Tick {});
+
+        RemoteManager::from_registry().do_send(Tick {});
+
         ExitManager::from_registry().do_send(Tick {});
 
+        WanManager::from_registry().do_send(Tick {});
+
+        TopUp::from_registry().do_send(Tick {});
+
+        // before the captive portal so that it sees protective mode changes right away
+        ProtectiveMode::from_registry().do_send(Tick {});
+
+        CaptivePortal::from_registry().do_send(Tick {});
+
+        if SETTING.get_sponsored_network().enabled {
+            Arbiter::spawn(
+                TrafficWatcher::from_registry()
+                    .send(WatchSponsored)
+                    .then(|res| {
+                        if let Ok(Err(e)) = res {
+                            warn!("Failed to watch sponsored traffic with {:?}", e);
+                        }
+                        Ok(())
+                    }),
+            );
+        }
+
         Arbiter::spawn(check_for_gateway_client_billing_corner_case());
 
         let dest_price = TrafficWatcher::from_registry().send(GetExitDestPrice);
@@ -106,87 +137,43 @@ impl Handler<Tick> for RitaLoop {
                 .then(|_res| Ok(()))
         }));
 
-        if SETTING.get_log().enabled {
-            send_udp_heartbeat();
-        }
+        Heartbeat::from_registry().do_send(Tick {});
 
         info!(
             "Rita Client loop completed in {}s {}ms",
             start.elapsed().as_secs(),
             start.elapsed().subsec_millis()
         );
+        Watchdog::from_registry().do_send(LoopTime {
+            name: "RitaClientLoop",
+            elapsed: start.elapsed(),
+            period: Duration::from_secs(CLIENT_LOOP_SPEED),
+        });
         Ok(())
     }
 }
 
-pub fn send_udp_heartbeat() {
-    let res = Resolver::from_registry()
-        .send(resolver::Resolve::host(
-            SETTING.get_log().heartbeat_url.clone(),
-        ))
-        .timeout(Duration::from_secs(1))
-        .then(move |res| match res {
-            Ok(Ok(dnsresult)) => {
-                if !dnsresult.is_empty() {
-                    for dns_socket in dnsresult {
-                        send_udp_heartbeat_packet(dns_socket);
-                    }
-                } else {
-                    trace!("Got zero length dns response: {:?}", dnsresult);
-                }
-                Ok(())
-            }
-
-            Err(e) => {
-                warn!("Actor mailbox failure from DNS resolver! {:?}", e);
-                Ok(())
-            }
-
-            Ok(Err(e)) => {
-                warn!("DNS resolution failed with {:?}", e);
-                Ok(())
-            }
-        });
-
-    Arbiter::spawn(res);
-}
-
-fn send_udp_heartbeat_packet(dns_socket: SocketAddr) {
-    let local_socketaddr = SocketAddr::from(([0, 0, 0, 0], HEARBEAT_MESSAGE_PORT));
-    let local_socket = match UdpSocket::bind(&local_socketaddr) {
-        Ok(s) => s,
-        Err(e) => {
-            error!("Couldn't bind to UDP heartbeat socket {:?}", e);
-            return;
-        }
-    };
-
-    let remote_ip = dns_socket.ip();
-    let remote = SocketAddr::new(remote_ip, HEARBEAT_MESSAGE_PORT);
-
-    trace!("Sending heartbeat to {:?}", remote_ip);
-
-    let message = match SETTING.get_identity() {
-        Some(i) => i,
-        None => return,
-    };
-    let json_message = match serde_json::to_vec(&message) {
-        Ok(m) => m,
-        Err(_) => return,
-    };
+crate::watchdog_handlers!(RitaLoop);
+crate::watchdog_handlers!(TrafficWatcher);
 
-    local_socket
-        .set_write_timeout(Some(Duration::new(0, 100)))
-        .expect("Couldn't set socket timeout");
-
-    local_socket
-        .send_to(&json_message, &remote)
-        .expect("Couldn't send heartbeat");
+/// Has the watchdog keep an eye on the common actors and the client ones
+pub fn start_rita_client_watchdog() {
+    let mut watched = common_watched();
+    watched.push(Watched::new::<RitaLoop>("RitaClientLoop"));
+    watched.push(Watched::new::<TrafficWatcher>("ClientTrafficWatcher"));
+    Watchdog::from_registry().do_send(crate::rita_common::watchdog::Watch(watched));
 }
 
 pub fn check_rita_client_actors() {
     assert!(crate::rita_client::rita_loop::RitaLoop::from_registry().connected());
     assert!(crate::rita_client::exit_manager::ExitManager::from_registry().connected());
+    assert!(crate::rita_client::wan_manager::WanManager::from_registry().connected());
+    assert!(crate::rita_client::provisioning::Provisioner::from_registry().connected());
+    assert!(crate::rita_client::remote_management::RemoteManager::from_registry().connected());
+    assert!(crate::rita_client::captive_portal::CaptivePortal::from_registry().connected());
+    assert!(crate::rita_client::protective_mode::ProtectiveMode::from_registry().connected());
+    assert!(crate::rita_client::topup::TopUp::from_registry().connected());
+    assert!(crate::rita_client::heartbeat::Heartbeat::from_registry().connected());
 }
 
 /// There is a complicated corner case where the gateway is a client and a relay to
@@ -206,7 +193,7 @@ fn check_for_gateway_client_billing_corner_case() -> impl Future<Item = (), Erro
             let neighbors = res.unwrap().unwrap();
 
             if let Some(exit) = exit_server {
-                if let ExitState::Registered { .. } = exit.info {
+                if exit.info.is_registered() {
                     for neigh in neighbors {
                         // we have a neighbor who is also our selected exit!
                         // wg_key exluded due to multihomed exits having a different one
@@ -230,9 +217,14 @@ pub fn start_rita_client_endpoints(workers: usize) {
     if let Some(gateway_ip) = SETTING.get_network().light_client_router_ip {
         trace!("Listening for light client hellos on {}", gateway_ip);
         let unstarted_server = server::new(|| {
-            App::new().resource("/light_client_hello", |r| {
-                r.method(Method::POST).with(light_client_hello_response)
-            })
+            App::new()
+                .middleware(middleware::RateLimit)
+                .resource("/light_client_hello", |r| {
+                    r.method(Method::POST).with(light_client_hello_response)
+                })
+                .resource("/light_client_roam", |r| {
+                    r.method(Method::POST).with(light_client_roam_response)
+                })
         })
         .workers(workers)
         .bind(format!(
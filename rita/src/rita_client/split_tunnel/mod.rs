@@ -0,0 +1,186 @@
+//! Split tunneling lets the user pick destinations that don't simply follow the default route
+//! out the current exit. A destination can bypass the exit and go straight out the uplink, be
+//! blocked outright, or be pinned to one exit so that it's blocked rather than leaking out
+//! another one while that exit isn't selected. Rules are kept in the exit client settings and
+//! turned into policy routes by ExitManager every tick, the kernel is only touched when the
+//! resulting routes change.
+
+use crate::rita_client::captive_portal::LAN_NIC;
+use crate::KI;
+use althea_kernel_interface::PolicyRoute;
+use failure::Error;
+use ipnetwork::IpNetwork;
+use settings::client::{ExitServer, SplitTunnelAction, SplitTunnelRule};
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+/// Checks rules from the dashboard and normalizes destinations like 10.0.0.1/8 to 10.0.0.0/8
+pub fn validate_rules(
+    rules: Vec<SplitTunnelRule>,
+    exits: &HashMap<String, ExitServer>,
+) -> Result<Vec<SplitTunnelRule>, Error> {
+    let mut seen = HashSet::new();
+    let mut valid = Vec::new();
+    for rule in rules {
+        let destination = IpNetwork::new(rule.destination.network(), rule.destination.prefix())?;
+        if !seen.insert(destination) {
+            bail!("More than one rule for {}", destination);
+        }
+        if let SplitTunnelAction::Exit(name) = &rule.action {
+            if !exits.contains_key(name) {
+                bail!("No exit named {}", name);
+            }
+        }
+        valid.push(SplitTunnelRule {
+            destination,
+            action: rule.action,
+        });
+    }
+    Ok(valid)
+}
+
+/// The policy routes for a set of rules, `exit_up` is if the tunnel to `current_exit` is set up
+/// and `uplink` is our default route outside of the exit tunnel, empty if we don't know it.
+/// Bypass rules we can't route are left out and so go through the exit as usual
+pub fn resolve_routes(
+    rules: &[SplitTunnelRule],
+    current_exit: Option<&str>,
+    exit_up: bool,
+    uplink: &[String],
+) -> Vec<(IpNetwork, PolicyRoute)> {
+    let mut routes = Vec::new();
+    for rule in rules {
+        let route = match &rule.action {
+            SplitTunnelAction::Bypass => match rule.destination {
+                // we only keep track of the ipv4 uplink
+                IpNetwork::V4(_) if !uplink.is_empty() => PolicyRoute::Uplink(uplink.to_vec()),
+                _ => {
+                    trace!("Can't bypass the exit for {}", rule.destination);
+                    continue;
+                }
+            },
+            SplitTunnelAction::Deny => PolicyRoute::Blackhole,
+            SplitTunnelAction::Exit(name) => {
+                if exit_up && current_exit == Some(name.as_str()) {
+                    PolicyRoute::ExitTunnel
+                } else {
+                    PolicyRoute::Blackhole
+                }
+            }
+        };
+        routes.push((rule.destination, route));
+    }
+    routes
+}
+
+/// Keeps track of the routes in the kernel
+#[derive(Debug, Default)]
+pub struct SplitTunnel {
+    applied: Option<Vec<(IpNetwork, PolicyRoute)>>,
+}
+
+impl SplitTunnel {
+    /// Applies the routes if they differ from what was applied last, no routes takes down the
+    /// rule and masquerading as well
+    pub fn update(&mut self, routes: Vec<(IpNetwork, PolicyRoute)>) {
+        if self.applied.as_ref() == Some(&routes) {
+            return;
+        }
+        match KI.set_split_tunnel_routes(LAN_NIC, &routes) {
+            Ok(()) => {
+                info!("Applied {} split tunnel routes", routes.len());
+                self.applied = Some(routes);
+            }
+            Err(e) => {
+                error!("Failed to apply split tunnel routes {:?}", e);
+                self.applied = None;
+            }
+        }
+    }
+
+    /// Forgets what was applied so that the next update applies it again, needed when the
+    /// uplink or exit tunnel is set up from scratch
+    pub fn reset(&mut self) {
+        self.applied = None;
+    }
+}
+
+#[test]
+fn test_split_tunnel_rules() {
+    use crate::rita_common::utils::test_identity;
+
+    let rule = |destination: &str, action: SplitTunnelAction| SplitTunnelRule {
+        destination: destination.parse().unwrap(),
+        action,
+    };
+    let uplink: Vec<String> = vec!["default".into(), "via".into(), "192.168.8.1".into()];
+    let rules = vec![
+        rule("10.5.0.0/16", SplitTunnelAction::Bypass),
+        rule("2001:db8::/32", SplitTunnelAction::Bypass),
+        rule("1.1.1.1/32", SplitTunnelAction::Deny),
+        rule("8.8.0.0/16", SplitTunnelAction::Exit("us_west".into())),
+    ];
+
+    let routes = resolve_routes(&rules, Some("us_west"), true, &uplink);
+    assert_eq!(
+        routes,
+        vec![
+            (
+                "10.5.0.0/16".parse().unwrap(),
+                PolicyRoute::Uplink(uplink.clone())
+            ),
+            ("1.1.1.1/32".parse().unwrap(), PolicyRoute::Blackhole),
+            ("8.8.0.0/16".parse().unwrap(), PolicyRoute::ExitTunnel),
+        ]
+    );
+    // pinned traffic is blocked rather than leaking out another exit or a down tunnel
+    for (exit, up) in [
+        (Some("us_east"), true),
+        (Some("us_west"), false),
+        (None, false),
+    ]
+    .iter()
+    {
+        let routes = resolve_routes(&rules, *exit, *up, &[]);
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[1].1, PolicyRoute::Blackhole);
+    }
+
+    let mut exits = HashMap::new();
+    exits.insert(
+        "us_west".to_string(),
+        ExitServer {
+            id: test_identity("fd00::1"),
+            description: String::new(),
+            registration_port: 4875,
+            info: althea_types::ExitState::New,
+            accepted_terms: None,
+            region: None,
+            priority: 0,
+            announcements: Vec::new(),
+            seen_announcement: 0,
+            signup_passphrase: None,
+            restored: false,
+        },
+    );
+    let valid =
+        validate_rules(vec![rule("10.5.3.1/16", SplitTunnelAction::Bypass)], &exits).unwrap();
+    assert_eq!(valid[0].destination, "10.5.0.0/16".parse().unwrap());
+    assert!(validate_rules(rules.clone(), &exits).is_ok());
+    assert!(validate_rules(
+        vec![rule(
+            "8.8.0.0/16",
+            SplitTunnelAction::Exit("nowhere".into())
+        )],
+        &exits
+    )
+    .is_err());
+    assert!(validate_rules(
+        vec![
+            rule("10.5.0.0/16", SplitTunnelAction::Bypass),
+            rule("10.5.1.0/16", SplitTunnelAction::Deny),
+        ],
+        &exits
+    )
+    .is_err());
+}
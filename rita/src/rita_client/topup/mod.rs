@@ -0,0 +1,260 @@
+//! Topping up a router's balance. The dashboard shows the deposit address as a payment uri that a
+//! wallet can scan and then asks us to watch for the money to arrive. The oracle would notice a
+//! deposit eventually, but while someone is waiting on it we check the balance through the payment
+//! backend every tick and pass increases on right away, so protective mode is lifted and the exit
+//! hears that our balance is no longer low, and stops its warnings, without waiting for the oracle.
+
+use crate::rita_client::protective_mode::ProtectiveMode;
+use crate::rita_client::rita_loop::{Tick, CLIENT_LOOP_TIMEOUT};
+use crate::rita_common::oracle::low_balance;
+use crate::rita_common::payment_controller::backend::payment_backend;
+use crate::rita_common::rita_loop::get_web3_server;
+use crate::rita_common::utils::now_secs;
+use crate::SETTING;
+use actix::{Actor, Arbiter, Context, Handler, Message, Supervised, SystemService};
+use althea_types::SystemChain;
+use clarity::Address;
+use failure::Error;
+use futures01::Future;
+use num256::Uint256;
+use settings::RitaCommonSettings;
+use std::time::{Duration, Instant};
+
+/// How long we keep watching for a deposit after the dashboard asks
+const TOPUP_WATCH_TIME: Duration = Duration::from_secs(30 * 60);
+
+/// The EIP-155 chain id of each system chain
+pub fn chain_id(chain: SystemChain) -> u64 {
+    match chain {
+        SystemChain::Ethereum => 1,
+        SystemChain::Rinkeby => 4,
+        SystemChain::Xdai => 100,
+    }
+}
+
+/// An EIP-681 payment uri for our address, what wallets expect to find in a QR code
+pub fn payment_uri(address: &Address, chain: SystemChain) -> String {
+    format!("ethereum:{}@{}", address, chain_id(chain))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopUpAddress {
+    pub address: Address,
+    pub system_chain: SystemChain,
+    pub chain_id: u64,
+    pub uri: String,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Deposit {
+    pub amount: Uint256,
+    /// unix time we saw it
+    pub seen: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopUpStatus {
+    pub watching: bool,
+    /// unix time the current or last watch started
+    pub since: Option<u64>,
+    pub starting_balance: Option<Uint256>,
+    pub balance: Uint256,
+    /// every balance increase since the watch started, payments from neighbors we relay for
+    /// show up here too
+    pub deposits: Vec<Deposit>,
+    pub low_balance: bool,
+}
+
+#[derive(Default)]
+pub struct TopUp {
+    watch_until: Option<Instant>,
+    since: Option<u64>,
+    starting_balance: Option<Uint256>,
+    last_balance: Option<Uint256>,
+    deposits: Vec<Deposit>,
+    /// a balance request is out, so that a slow full node doesn't pile them up
+    in_progress: bool,
+}
+
+impl Actor for TopUp {
+    type Context = Context<Self>;
+}
+
+impl Supervised for TopUp {}
+impl SystemService for TopUp {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("TopUp started");
+    }
+}
+
+impl TopUp {
+    fn watching(&self, now: Instant) -> bool {
+        match self.watch_until {
+            Some(until) => now < until,
+            None => false,
+        }
+    }
+
+    /// Records a balance reading, returning the amount deposited if it went up
+    fn record_balance(&mut self, balance: Uint256, seen: u64) -> Option<Uint256> {
+        let deposit = match &self.last_balance {
+            Some(last) if balance > *last => Some(balance.clone() - last.clone()),
+            _ => None,
+        };
+        if self.starting_balance.is_none() {
+            self.starting_balance = Some(balance.clone());
+        }
+        self.last_balance = Some(balance);
+        if let Some(amount) = &deposit {
+            self.deposits.push(Deposit {
+                amount: amount.clone(),
+                seen,
+            });
+        }
+        deposit
+    }
+
+    fn status(&self) -> TopUpStatus {
+        TopUpStatus {
+            watching: self.watching(Instant::now()),
+            since: self.since,
+            starting_balance: self.starting_balance.clone(),
+            balance: SETTING.get_payment().balance.clone(),
+            deposits: self.deposits.clone(),
+            low_balance: low_balance(),
+        }
+    }
+}
+
+impl Handler<Tick> for TopUp {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _: Tick, _ctx: &mut Context<Self>) -> Self::Result {
+        if !self.watching(Instant::now()) {
+            if self.watch_until.take().is_some() {
+                info!("Stopped watching for a top up");
+            }
+            return Ok(());
+        }
+        if self.in_progress {
+            return Ok(());
+        }
+        let address = match SETTING.get_payment().eth_address {
+            Some(address) => address,
+            None => bail!("No eth address to watch for a top up!"),
+        };
+        self.in_progress = true;
+        let backend = payment_backend(get_web3_server(), CLIENT_LOOP_TIMEOUT);
+        Arbiter::spawn(backend.get_balance(address).then(|res| {
+            let balance = match res {
+                Ok(balance) => Some(balance),
+                Err(e) => {
+                    warn!("Failed to check for a top up {:?}", e);
+                    None
+                }
+            };
+            TopUp::from_registry().do_send(BalanceUpdate(balance));
+            Ok(())
+        }));
+        Ok(())
+    }
+}
+
+struct BalanceUpdate(Option<Uint256>);
+
+impl Message for BalanceUpdate {
+    type Result = ();
+}
+
+impl Handler<BalanceUpdate> for TopUp {
+    type Result = ();
+
+    fn handle(&mut self, msg: BalanceUpdate, _ctx: &mut Context<Self>) -> Self::Result {
+        self.in_progress = false;
+        let balance = match msg.0 {
+            Some(balance) => balance,
+            None => return,
+        };
+        if let Some(amount) = self.record_balance(balance.clone(), now_secs()) {
+            info!("Received a deposit of {} wei", amount);
+            {
+                // only ever raised here, the oracle takes care of everything else
+                let mut payment = SETTING.get_payment_mut();
+                if payment.balance < balance {
+                    payment.balance = balance;
+                }
+            }
+            ProtectiveMode::from_registry().do_send(Tick);
+        }
+    }
+}
+
+/// Starts watching for a deposit, or extends the current watch
+pub struct StartTopUp;
+
+impl Message for StartTopUp {
+    type Result = Result<TopUpStatus, Error>;
+}
+
+impl Handler<StartTopUp> for TopUp {
+    type Result = Result<TopUpStatus, Error>;
+
+    fn handle(&mut self, _: StartTopUp, _ctx: &mut Context<Self>) -> Self::Result {
+        if !self.watching(Instant::now()) {
+            info!("Watching for a top up");
+            self.since = Some(now_secs());
+            self.starting_balance = None;
+            self.last_balance = None;
+            self.deposits.clear();
+        }
+        self.watch_until = Some(Instant::now() + TOPUP_WATCH_TIME);
+        Ok(self.status())
+    }
+}
+
+pub struct GetTopUpStatus;
+
+impl Message for GetTopUpStatus {
+    type Result = Result<TopUpStatus, Error>;
+}
+
+impl Handler<GetTopUpStatus> for TopUp {
+    type Result = Result<TopUpStatus, Error>;
+
+    fn handle(&mut self, _: GetTopUpStatus, _ctx: &mut Context<Self>) -> Self::Result {
+        Ok(self.status())
+    }
+}
+
+#[test]
+fn test_record_balance() {
+    let mut topup = TopUp::default();
+    assert_eq!(topup.record_balance(100u32.into(), 1), None);
+    assert_eq!(topup.starting_balance, Some(100u32.into()));
+    assert_eq!(topup.record_balance(150u32.into(), 2), Some(50u32.into()));
+    // spending doesn't count against later deposits
+    assert_eq!(topup.record_balance(120u32.into(), 3), None);
+    assert_eq!(topup.record_balance(200u32.into(), 4), Some(80u32.into()));
+    assert_eq!(
+        topup.deposits,
+        vec![
+            Deposit {
+                amount: 50u32.into(),
+                seen: 2
+            },
+            Deposit {
+                amount: 80u32.into(),
+                seen: 4
+            },
+        ]
+    );
+    assert_eq!(topup.starting_balance, Some(100u32.into()));
+
+    let address: Address = "0x0101010101010101010101010101010101010101"
+        .parse()
+        .unwrap();
+    assert_eq!(
+        payment_uri(&address, SystemChain::Xdai),
+        format!("ethereum:{}@100", address)
+    );
+}
@@ -13,7 +13,13 @@
 //!
 //! TrafficWatcher monitors system traffic by interfacing with KernelInterface to create and check
 //! iptables and ip counters on each per hop tunnel (the WireGuard tunnel between two devices). These counts
-//! are then stored and used to compute the usage amounts displayed to the user.
+//! are then stored and used to compute the usage amounts displayed to the user. The exit billing state is
+//! keyed by the exit tunnel interface ExitManager names in QueryExitDebts and dropped once that interface
+//! is gone. ExitManager only ever brings up the one exit tunnel, wg_exit, so there's only ever one entry,
+//! billing several exits at once would also need ExitManager to set up a tunnel for each.
+//!
+//! Traffic on the sponsored network is also counted here, it's paid for like any other client traffic but
+//! is tracked as its own usage category and can be capped each day.
 //!
 //! QueryExitDebts asks the exit what it thinks this particular client owes (over the secure channel of the exit tunnel)
 //! validating if this number is correct is difficult, because the exit is serving us with a total debt while our local
@@ -24,39 +30,77 @@
 use crate::rita_common::debt_keeper::{
     DebtKeeper, Traffic, TrafficReplace, TrafficUpdate, WgKeyInsensitiveTrafficUpdate,
 };
+use crate::rita_common::traffic_watcher::counter_delta::UsageDelta;
+use crate::rita_common::tunnel_manager::{RefuseRoutesVia, TunnelManager};
 use crate::rita_common::usage_tracker::UpdateUsage;
 use crate::rita_common::usage_tracker::UsageTracker;
 use crate::rita_common::usage_tracker::UsageType;
+use crate::rita_common::utils::now_secs;
+use crate::rita_common::watchdog::{or_alarm, Journal, Watchdog, WatchdogEventKind};
 use crate::KI;
 use crate::SETTING;
 use actix::{Actor, Arbiter, Context, Handler, Message, Supervised, SystemService};
 use actix_web::client;
 use actix_web::client::Connection;
 use actix_web::HttpMessage;
-use althea_types::Identity;
+use althea_kernel_interface::SponsoredCounters;
+use althea_types::{Identity, Wei};
 use babel_monitor::get_installed_route;
 use babel_monitor::Route;
 use failure::Error;
 use futures01::future::ok as future_ok;
 use futures01::future::Future;
+use ipnetwork::IpNetwork;
 use num256::Int256;
 use num_traits::identities::Zero;
+use settings::client::RitaClientSettings;
+use settings::payment::PriceCapPolicy;
 use settings::RitaCommonSettings;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
 use std::time::Instant;
 use tokio::net::TcpStream as TokioTcpStream;
+use tokio::util::FutureExt;
+
+/// Billing state for a single exit tunnel
+#[derive(Debug, Clone, Default)]
+pub struct ExitTunnelCounters {
+    /// the tunnel's wireguard counters as of the last round
+    usage: UsageDelta,
+    /// cached exit destination price value
+    last_exit_dest_price: u128,
+    /// if the route to the exit is priced over max_fee and we're paying max_fee for it
+    price_capped: bool,
+    /// the neighbors babel is routing around to get to the exit
+    refused: Vec<Refusal>,
+}
 
 pub struct TrafficWatcher {
-    // last read download
-    last_read_input: u64,
-    // last read upload
-    last_read_output: u64,
+    /// counters for each exit tunnel, keyed by interface name
+    exit_tunnels: HashMap<String, ExitTunnelCounters>,
+    /// the tunnel to the exit we're using, the one we last queried for debts
+    current_exit_iface: Option<String>,
     /// handles the gateway exit client corner case where we need to reconcile client
     /// and relay debts
     gateway_exit_client: bool,
-    /// cached exit destination price value
-    last_exit_dest_price: u128,
+    sponsored: SponsoredUsage,
+}
+
+/// Sponsored network usage for the current day
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct SponsoredUsage {
+    /// days since the unix epoch
+    pub day: u64,
+    /// bytes up and down so far today, only counted since Rita started
+    pub today: u64,
+    /// if the daily cap has been hit and the network is cut off
+    pub cut_off: bool,
+    /// the bridge counters as of the last round, None until the first reading so that what was
+    /// counted before we started isn't billed
+    #[serde(skip)]
+    last_read: Option<UsageDelta>,
 }
 
 impl Actor for TrafficWatcher {
@@ -66,19 +110,19 @@ impl Supervised for TrafficWatcher {}
 impl SystemService for TrafficWatcher {
     fn service_started(&mut self, _ctx: &mut Context<Self>) {
         info!("Client traffic watcher started");
-        self.last_read_input = 0;
-        self.last_read_output = 0;
+        self.exit_tunnels = HashMap::new();
+        self.current_exit_iface = None;
         self.gateway_exit_client = false;
-        self.last_exit_dest_price = 0;
+        self.sponsored = SponsoredUsage::default();
     }
 }
 impl Default for TrafficWatcher {
     fn default() -> TrafficWatcher {
         TrafficWatcher {
-            last_read_input: 0,
-            last_read_output: 0,
+            exit_tunnels: HashMap::new(),
+            current_exit_iface: None,
             gateway_exit_client: false,
-            last_exit_dest_price: 0,
+            sponsored: SponsoredUsage::default(),
         }
     }
 }
@@ -123,6 +167,8 @@ impl Handler<WeAreGatewayClient> for TrafficWatcher {
 /// This request is made against the exits internal ip address to ensure that upstream
 /// nodes can't spoof it.
 pub struct QueryExitDebts {
+    /// the exit tunnel interface traffic to this exit flows over
+    pub exit_iface: String,
     pub exit_internal_addr: IpAddr,
     pub exit_port: u16,
     pub exit_id: Identity,
@@ -140,13 +186,36 @@ impl Handler<QueryExitDebts> for TrafficWatcher {
     fn handle(&mut self, msg: QueryExitDebts, _: &mut Context<Self>) -> Self::Result {
         trace!("About to query the exit for client debts");
 
+        // exit tunnels that have been torn down would otherwise keep their last price in
+        // GetExitDestPrice forever
+        match KI.get_interfaces() {
+            Ok(ifaces) => prune_exit_tunnels(&mut self.exit_tunnels, &ifaces),
+            Err(e) => warn!("Failed to list interfaces to prune exit tunnels {:?}", e),
+        }
+
         // we could exit the function if this fails, but doing so would remove the chance
         // that we can get debts from the exit and continue anyways
-        let local_debt =
-            match local_traffic_calculation(self, &msg.exit_id, msg.exit_price, msg.routes) {
-                Ok(val) => Some(Int256::from(val)),
-                Err(_e) => None,
-            };
+        self.current_exit_iface = Some(msg.exit_iface.clone());
+        let counters = self
+            .exit_tunnels
+            .entry(msg.exit_iface.clone())
+            .or_insert_with(ExitTunnelCounters::default);
+        let local_debt = match local_traffic_calculation(
+            counters,
+            &msg.exit_iface,
+            &msg.exit_id,
+            msg.exit_price,
+            msg.routes,
+        ) {
+            Ok(val) => Some(val.into()),
+            Err(_e) => None,
+        };
+        let refused = self
+            .exit_tunnels
+            .values()
+            .flat_map(|counters| counters.refused.iter().map(|r| r.iface.clone()))
+            .collect();
+        TunnelManager::from_registry().do_send(RefuseRoutesVia(refused));
 
         let gateway_exit_client = self.gateway_exit_client;
         let start = Instant::now();
@@ -265,34 +334,172 @@ impl Handler<QueryExitDebts> for TrafficWatcher {
     }
 }
 
-/// Returns the babel route to a given mesh ip with the properly capped price
-fn find_exit_route_capped(exit_mesh_ip: IpAddr, routes: Vec<Route>) -> Result<Route, Error> {
-    let max_fee = SETTING.get_payment().max_fee;
+/// A neighbor we've had babel route around because its route to our exit is over max_fee
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Refusal {
+    neigh: IpAddr,
+    iface: String,
+}
+
+/// How the price of the route to our exit compares to max_fee
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RoutePrice {
+    WithinCap,
+    /// over max_fee, we pay max_fee for it
+    Capped,
+    /// over max_fee, but there's a route within it so babel should route around this neighbor
+    Refuse(Refusal),
+}
+
+fn is_route_to(route: &Route, mesh_ip: IpAddr) -> bool {
+    match route.prefix {
+        IpNetwork::V6(ref ip) => ip.prefix() == 128 && IpAddr::V6(ip.ip()) == mesh_ip,
+        IpNetwork::V4(_) => false,
+    }
+}
+
+/// If there's a route to `mesh_ip` within max_fee other than the one through `neigh`
+fn has_alternative(routes: &[Route], mesh_ip: IpAddr, neigh: IpAddr, max_fee: u32) -> bool {
+    routes.iter().any(|route| {
+        is_route_to(route, mesh_ip) && route.neigh_ip != neigh && route.price <= max_fee
+    })
+}
+
+fn check_route_price(
+    exit_mesh_ip: IpAddr,
+    exit_route: &Route,
+    routes: &[Route],
+    max_fee: u32,
+    policy: PriceCapPolicy,
+) -> RoutePrice {
+    if exit_route.price <= max_fee {
+        return RoutePrice::WithinCap;
+    }
+    match policy {
+        PriceCapPolicy::Refuse
+            if has_alternative(routes, exit_mesh_ip, exit_route.neigh_ip, max_fee) =>
+        {
+            RoutePrice::Refuse(Refusal {
+                neigh: exit_route.neigh_ip,
+                iface: exit_route.iface.clone(),
+            })
+        }
+        _ => RoutePrice::Capped,
+    }
+}
+
+/// If a refusal still stands, the route through the neighbor has to still be over max_fee with
+/// an alternative to it, otherwise babel may as well go back to it
+fn keep_refusal(
+    refusal: &Refusal,
+    exit_mesh_ip: IpAddr,
+    routes: &[Route],
+    max_fee: u32,
+    policy: PriceCapPolicy,
+) -> bool {
+    let over_cap = routes.iter().any(|route| {
+        is_route_to(route, exit_mesh_ip)
+            && route.neigh_ip == refusal.neigh
+            && route.iface == refusal.iface
+            && route.price > max_fee
+    });
+    policy == PriceCapPolicy::Refuse
+        && over_cap
+        && has_alternative(routes, exit_mesh_ip, refusal.neigh, max_fee)
+}
+
+fn journal(kind: WatchdogEventKind) {
+    Watchdog::from_registry().do_send(Journal {
+        source: "TrafficWatcher",
+        kind,
+    });
+}
+
+/// Returns the babel route to a given mesh ip with the price capped at max_fee. Routes priced
+/// over it are journaled and either paid max_fee or refused in favor of another route, depending
+/// on the price cap policy. Refusals are kept in `history` for TrafficWatcher to hand to
+/// TunnelManager, and dropped once the route is back within max_fee, gone or the only one left
+fn find_exit_route_capped(
+    exit_mesh_ip: IpAddr,
+    routes: Vec<Route>,
+    history: &mut ExitTunnelCounters,
+) -> Result<Route, Error> {
+    let payment_settings = SETTING.get_payment();
+    let max_fee = payment_settings.max_fee;
+    let policy = payment_settings.price_cap_policy;
+    drop(payment_settings);
+
+    history.refused.retain(|refusal| {
+        let keep = keep_refusal(refusal, exit_mesh_ip, &routes, max_fee, policy);
+        if !keep {
+            info!(
+                "No longer refusing the route to our exit via {}",
+                refusal.neigh
+            );
+        }
+        keep
+    });
+
     let mut exit_route = get_installed_route(&exit_mesh_ip, &routes)?;
-    if exit_route.price > max_fee {
-        let mut capped_route = exit_route.clone();
-        capped_route.price = max_fee;
-        exit_route = capped_route;
+    let price = exit_route.price;
+    match check_route_price(exit_mesh_ip, &exit_route, &routes, max_fee, policy) {
+        RoutePrice::WithinCap => {
+            if history.price_capped {
+                info!("The route to our exit is back within max_fee at {}", price);
+                journal(WatchdogEventKind::PriceCapLifted { price });
+            }
+            history.price_capped = false;
+            return Ok(exit_route);
+        }
+        // we pay max_fee until babel has moved over
+        RoutePrice::Refuse(refusal) => {
+            if !history.refused.contains(&refusal) {
+                warn!(
+                    "The route to our exit via {} is priced {} over max_fee {}, refusing it",
+                    refusal.neigh, price, max_fee
+                );
+                journal(WatchdogEventKind::RouteRefused {
+                    neighbor: refusal.neigh.to_string(),
+                    price,
+                });
+                history.refused.push(refusal);
+            }
+        }
+        RoutePrice::Capped => {
+            if !history.price_capped {
+                warn!(
+                    "The route to our exit is priced {} over max_fee {}, only paying max_fee",
+                    price, max_fee
+                );
+                journal(WatchdogEventKind::PriceCapped { price, max_fee });
+            }
+            history.price_capped = true;
+        }
     }
+    exit_route.price = max_fee;
     Ok(exit_route)
 }
 
 pub fn local_traffic_calculation(
-    history: &mut TrafficWatcher,
+    history: &mut ExitTunnelCounters,
+    exit_iface: &str,
     exit: &Identity,
     exit_price: u64,
     routes: Vec<Route>,
-) -> Result<i128, Error> {
-    let exit_route = find_exit_route_capped(exit.mesh_ip, routes)?;
+) -> Result<Wei, Error> {
+    let exit_route = find_exit_route_capped(exit.mesh_ip, routes, history)?;
     info!("Exit metric: {}", exit_route.metric);
 
-    let counter = match KI.read_wg_counters("wg_exit") {
+    let counter = match KI.read_wg_counters(exit_iface) {
         Ok(res) => {
             if res.len() > 1 {
-                warn!("wg_exit client tunnel has multiple peers!");
+                warn!("{} client tunnel has multiple peers!", exit_iface);
             } else if res.is_empty() {
-                warn!("No peers on wg_exit why is client traffic watcher running?");
-                return Err(format_err!("No peers on wg_exit"));
+                warn!(
+                    "No peers on {} why is client traffic watcher running?",
+                    exit_iface
+                );
+                return Err(format_err!("No peers on {}", exit_iface));
             }
             // unwrap is safe because we check that len is not equal to zero
             // then we toss the exit's wg key as we don't need it
@@ -309,34 +516,40 @@ pub fn local_traffic_calculation(
         }
     };
 
-    // bandwidth usage should always increase if it doesn't the interface has been
-    // deleted and recreated and we need to reset our usage, also protects from negatives
-    if history.last_read_input > counter.download || history.last_read_output > counter.upload {
-        warn!("Exit tunnel reset resetting counters");
-        history.last_read_input = 0;
-        history.last_read_output = 0;
-    }
-
-    let input = counter.download - history.last_read_input;
-    let output = counter.upload - history.last_read_output;
-
-    history.last_read_input = counter.download;
-    history.last_read_output = counter.upload;
+    let (output, input) = history
+        .usage
+        .update(exit_iface, counter.upload, counter.download);
 
-    info!("{:?} bytes downloaded from exit this round", &input);
-    info!("{:?} bytes uploaded to exit this round", &output);
+    info!(
+        "{:?} bytes downloaded from exit over {} this round",
+        &input, exit_iface
+    );
+    info!(
+        "{:?} bytes uploaded to exit over {} this round",
+        &output, exit_iface
+    );
 
     // the price we pay to send traffic through the exit
     info!("exit price {}", exit_price);
 
-    // price to get traffic to the exit as a u64 to make the type rules for math easy
-    let exit_route_price: i128 = exit_route.price.into();
+    // price to get traffic to the exit
+    let exit_route_price = u64::from(exit_route.price);
     // the total price for the exit returning traffic to us, in the future we should ask
     // the exit for this because TODO assumes symetric route
-    let exit_dest_price: i128 = exit_route_price + i128::from(exit_price);
+    let exit_dest_price = or_alarm(
+        exit_route_price.checked_add(exit_price),
+        u64::max_value(),
+        "TrafficWatcher",
+        || {
+            format!(
+                "route price {} plus exit price {}",
+                exit_route_price, exit_price
+            )
+        },
+    );
 
     // send the exit dest price over to the light client manager for consumption there
-    history.last_exit_dest_price = exit_dest_price as u128;
+    history.last_exit_dest_price = u128::from(exit_dest_price);
 
     info!("Exit destination price {}", exit_dest_price);
     trace!("Exit ip: {:?}", exit.mesh_ip);
@@ -350,38 +563,46 @@ pub fn local_traffic_calculation(
     // fee for traffic we send to it since our neighbors billing should be handled in
     // rita_common but we do pay for return traffic here since it doesn't make sense
     // to handle in the general case
-    let mut owes_exit = 0i128;
-    let value = i128::from(input) * exit_dest_price;
+    let value = Wei::from_price(exit_dest_price, input);
     trace!(
         "We are billing for {} bytes input times a exit dest price of {} for a total of {}",
         input,
         exit_dest_price,
         value
     );
-    owes_exit += value;
-    let value = i128::from(exit_price * (output));
+    let mut owes_exit = value;
+    let value = Wei::from_price(exit_price, output);
     trace!(
         "We are billing for {} bytes output times a exit price of {} for a total of {}",
         output,
         exit_price,
         value
     );
-    owes_exit += value;
+    owes_exit = or_alarm(
+        owes_exit.checked_add(&value),
+        owes_exit.saturating_add(&value),
+        "TrafficWatcher",
+        || format!("exit bill of {} plus {}", owes_exit, value),
+    );
 
-    if owes_exit > 0 {
+    if owes_exit > Wei::zero() {
         info!("Total client debt of {} this round", owes_exit);
         // update the usage tracker with the details of this round's usage
         UsageTracker::from_registry().do_send(UpdateUsage {
             kind: UsageType::Client,
             up: output,
             down: input,
-            price: exit_dest_price as u32,
+            price: or_alarm(
+                u32::try_from(exit_dest_price).ok(),
+                u32::max_value(),
+                "TrafficWatcher",
+                || format!("exit dest price {} as a usage price", exit_dest_price),
+            ),
         });
     } else {
         error!("no Exit bandwidth, no bill!");
     }
 
-    assert!(owes_exit >= 0);
     Ok(owes_exit)
 }
 
@@ -398,6 +619,297 @@ impl Handler<GetExitDestPrice> for TrafficWatcher {
     type Result = Result<u128, Error>;
 
     fn handle(&mut self, _msg: GetExitDestPrice, _: &mut Context<Self>) -> Self::Result {
-        Ok(self.last_exit_dest_price)
+        Ok(self.current_exit_dest_price().unwrap_or(0))
     }
 }
+
+/// Drops the counters of exit tunnels whose interface no longer exists
+fn prune_exit_tunnels(exit_tunnels: &mut HashMap<String, ExitTunnelCounters>, ifaces: &[String]) {
+    exit_tunnels.retain(|iface, _| {
+        let exists = ifaces.contains(iface);
+        if !exists {
+            info!("Exit tunnel {} is gone, dropping its counters", iface);
+        }
+        exists
+    });
+}
+
+impl TrafficWatcher {
+    /// What the route to the exit we're using plus the exit itself cost as of the last round
+    fn current_exit_dest_price(&self) -> Option<u128> {
+        let iface = self.current_exit_iface.as_ref()?;
+        self.exit_tunnels
+            .get(iface)
+            .map(|counters| counters.last_exit_dest_price)
+    }
+}
+
+/// Takes a new reading of the sponsored bridge counters, returns the bytes up and down since the
+/// last one and whether the network should now be cut off. The first reading only sets where
+/// counting starts from
+fn sponsored_round(
+    usage: &mut SponsoredUsage,
+    counters: SponsoredCounters,
+    day: u64,
+    daily_cap: Option<u64>,
+) -> (u64, u64, bool) {
+    let (up, down) = match usage.last_read {
+        Some(ref mut last_read) => {
+            last_read.update("the sponsored bridge", counters.upload, counters.download)
+        }
+        None => {
+            usage.last_read = Some(UsageDelta::starting_at(counters.upload, counters.download));
+            (0, 0)
+        }
+    };
+
+    if usage.day != day {
+        usage.day = day;
+        usage.today = 0;
+    }
+    usage.today += up + down;
+
+    let cut_off = match daily_cap {
+        Some(cap) => usage.today >= cap,
+        None => false,
+    };
+    (up, down, cut_off)
+}
+
+/// Counts sponsored network traffic and enforces the daily cap, sent every client loop round
+/// while the sponsored network is enabled
+pub struct WatchSponsored;
+
+impl Message for WatchSponsored {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<WatchSponsored> for TrafficWatcher {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _msg: WatchSponsored, _: &mut Context<Self>) -> Self::Result {
+        let counters = KI.get_sponsored_counters()?;
+        let day = now_secs() / (60 * 60 * 24);
+        let daily_cap = SETTING.get_sponsored_network().daily_cap;
+        let (up, down, cut_off) = sponsored_round(&mut self.sponsored, counters, day, daily_cap);
+
+        match self.current_exit_dest_price() {
+            Some(price) if up + down > 0 => {
+                UsageTracker::from_registry().do_send(UpdateUsage {
+                    kind: UsageType::Sponsored,
+                    up,
+                    down,
+                    price: or_alarm(
+                        u32::try_from(price).ok(),
+                        u32::max_value(),
+                        "TrafficWatcher",
+                        || format!("exit dest price {} as a usage price", price),
+                    ),
+                });
+            }
+            None if up + down > 0 => {
+                warn!("No route to our exit yet, sponsored usage isn't recorded this round")
+            }
+            _ => {}
+        }
+
+        if cut_off != self.sponsored.cut_off {
+            if cut_off {
+                info!(
+                    "Sponsored network used {} bytes today, cutting it off",
+                    self.sponsored.today
+                );
+            } else {
+                info!("Lifting the sponsored network cut off");
+            }
+            KI.set_sponsored_cutoff(cut_off)?;
+            self.sponsored.cut_off = cut_off;
+        }
+        Ok(())
+    }
+}
+
+/// Sent when the sponsored network has been reconfigured, which recreates the bridge along
+/// with any cut off on it
+pub struct SponsoredNetworkChanged;
+
+impl Message for SponsoredNetworkChanged {
+    type Result = ();
+}
+
+impl Handler<SponsoredNetworkChanged> for TrafficWatcher {
+    type Result = ();
+
+    fn handle(&mut self, _msg: SponsoredNetworkChanged, _: &mut Context<Self>) -> Self::Result {
+        self.sponsored.cut_off = false;
+    }
+}
+
+pub struct GetSponsoredUsage;
+
+impl Message for GetSponsoredUsage {
+    type Result = Result<SponsoredUsage, Error>;
+}
+
+impl Handler<GetSponsoredUsage> for TrafficWatcher {
+    type Result = Result<SponsoredUsage, Error>;
+
+    fn handle(&mut self, _msg: GetSponsoredUsage, _: &mut Context<Self>) -> Self::Result {
+        Ok(self.sponsored)
+    }
+}
+
+#[test]
+fn test_sponsored_round() {
+    let mut usage = SponsoredUsage::default();
+    let counters = |upload, download| SponsoredCounters { upload, download };
+
+    // whatever the bridge counted before we started isn't ours to bill
+    assert_eq!(
+        sponsored_round(&mut usage, counters(5000, 9000), 10, Some(1000)),
+        (0, 0, false)
+    );
+    assert_eq!(usage.today, 0);
+    let mut usage = SponsoredUsage::default();
+    sponsored_round(&mut usage, counters(0, 0), 10, Some(1000));
+    assert_eq!(
+        sponsored_round(&mut usage, counters(100, 400), 10, Some(1000)),
+        (100, 400, false)
+    );
+    assert_eq!(
+        sponsored_round(&mut usage, counters(200, 900), 10, Some(1000)),
+        (100, 500, true)
+    );
+    assert_eq!(usage.today, 1100);
+    // no cap
+    assert_eq!(
+        sponsored_round(&mut usage, counters(200, 900), 10, None),
+        (0, 0, false)
+    );
+    // a new day
+    assert_eq!(
+        sponsored_round(&mut usage, counters(300, 1000), 11, Some(1000)),
+        (100, 100, false)
+    );
+    assert_eq!(usage.today, 200);
+    // the bridge was recreated
+    assert_eq!(
+        sponsored_round(&mut usage, counters(50, 50), 11, Some(1000)),
+        (50, 50, false)
+    );
+    assert_eq!(usage.today, 300);
+}
+
+#[test]
+fn test_prune_exit_tunnels() {
+    let mut exit_tunnels = HashMap::new();
+    exit_tunnels.insert(
+        "wg_exit".to_string(),
+        ExitTunnelCounters {
+            last_exit_dest_price: 10,
+            ..Default::default()
+        },
+    );
+    prune_exit_tunnels(
+        &mut exit_tunnels,
+        &["wg0".to_string(), "wg_exit".to_string()],
+    );
+    assert_eq!(exit_tunnels["wg_exit"].last_exit_dest_price, 10);
+    // once the tunnel is torn down its price no longer counts
+    prune_exit_tunnels(&mut exit_tunnels, &["wg0".to_string()]);
+    assert!(exit_tunnels.is_empty());
+}
+
+#[test]
+fn test_check_route_price() {
+    let route = |neigh: &str, price: u32, installed: bool| Route {
+        id: "id".to_string(),
+        iface: "wg1".to_string(),
+        xroute: false,
+        installed,
+        neigh_ip: neigh.parse().unwrap(),
+        prefix: "fd00::1/128".parse().unwrap(),
+        metric: 100,
+        refmetric: 0,
+        full_path_rtt: 10.0,
+        price,
+        fee: price,
+    };
+    let exit: IpAddr = "fd00::1".parse().unwrap();
+    let installed = route("fe80::1", 500, true);
+    let cheaper = route("fe80::2", 200, false);
+    let dearer = route("fe80::3", 400, false);
+    let refusal = Refusal {
+        neigh: "fe80::1".parse().unwrap(),
+        iface: "wg1".to_string(),
+    };
+
+    let routes = vec![installed.clone(), cheaper.clone()];
+    assert_eq!(
+        check_route_price(exit, &installed, &routes, 500, PriceCapPolicy::Refuse),
+        RoutePrice::WithinCap
+    );
+    assert_eq!(
+        check_route_price(exit, &installed, &routes, 300, PriceCapPolicy::Cap),
+        RoutePrice::Capped
+    );
+    assert_eq!(
+        check_route_price(exit, &installed, &routes, 300, PriceCapPolicy::Refuse),
+        RoutePrice::Refuse(refusal.clone())
+    );
+    // babel has moved over, the refusal stands while the price does
+    let mut moved = vec![installed.clone(), cheaper.clone()];
+    moved[0].installed = false;
+    moved[1].installed = true;
+    assert!(keep_refusal(
+        &refusal,
+        exit,
+        &moved,
+        300,
+        PriceCapPolicy::Refuse
+    ));
+    assert!(!keep_refusal(
+        &refusal,
+        exit,
+        &moved,
+        500,
+        PriceCapPolicy::Refuse
+    ));
+    assert!(!keep_refusal(
+        &refusal,
+        exit,
+        &moved,
+        300,
+        PriceCapPolicy::Cap
+    ));
+    // the route through the neighbor is gone, or is the only one left
+    assert!(!keep_refusal(
+        &refusal,
+        exit,
+        &moved[1..],
+        300,
+        PriceCapPolicy::Refuse
+    ));
+    assert!(!keep_refusal(
+        &refusal,
+        exit,
+        &moved[..1],
+        300,
+        PriceCapPolicy::Refuse
+    ));
+
+    // nothing within max_fee to move to
+    let routes = vec![installed.clone(), dearer];
+    assert_eq!(
+        check_route_price(exit, &installed, &routes, 300, PriceCapPolicy::Refuse),
+        RoutePrice::Capped
+    );
+    // routes to somewhere else don't count
+    let mut elsewhere = cheaper;
+    elsewhere.prefix = "fd00::2/128".parse().unwrap();
+    let routes = vec![installed.clone(), elsewhere];
+    assert_eq!(
+        check_route_price(exit, &installed, &routes, 300, PriceCapPolicy::Refuse),
+        RoutePrice::Capped
+    );
+}
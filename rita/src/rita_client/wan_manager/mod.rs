@@ -0,0 +1,198 @@
+//! The WAN manager handles gateways with more than one uplink, typically a wired backhaul and an
+//! LTE modem. The primary uplink (external_nic) is checked every client tick by pinging a known
+//! internet host out of that interface. After enough consecutive failures the routes to manual
+//! peers and the exit tunnel are moved over to the backup uplink, and unless the exit tunnel is
+//! our default route so is everything else. The primary's own default route is left alone, the
+//! backup's is put in a table of its own that's looked up ahead of it, so that checks out of the
+//! primary keep working and switching back only has to take that table out again. Meanwhile the
+//! primary's route is kept out of the settings route, which manual peers and dns servers are
+//! pinned over, though it's still the default in the main table. Once the primary has been
+//! healthy for a while traffic is moved back, LTE data is usually metered so we don't want to
+//! stay on it any longer than we have to.
+
+use crate::rita_client::rita_loop::Tick;
+use crate::KI;
+use crate::SETTING;
+use actix::{Actor, Context, Handler, Message, Supervised, SystemService};
+use failure::Error;
+use settings::RitaCommonSettings;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// How many failed checks in a row before we switch to the backup uplink
+const FAILOVER_THRESHOLD: u32 = 3;
+/// How many successful checks in a row before we switch back to the primary
+const RECOVERY_THRESHOLD: u32 = 6;
+const WAN_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Uplink {
+    Primary,
+    Backup,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WanStatus {
+    pub active_uplink: Uplink,
+    pub active_iface: Option<String>,
+    pub primary_iface: Option<String>,
+    pub backup_iface: Option<String>,
+    pub primary_failures: u32,
+    pub primary_successes: u32,
+}
+
+pub struct WanManager {
+    active: Uplink,
+    primary_failures: u32,
+    primary_successes: u32,
+    /// the route in the settings before we failed over, put back when we switch back
+    primary_route: Vec<String>,
+    /// the backup's default route while we're failed over to it
+    backup_route: Vec<String>,
+}
+
+impl Default for WanManager {
+    fn default() -> WanManager {
+        WanManager {
+            active: Uplink::Primary,
+            primary_failures: 0,
+            primary_successes: 0,
+            primary_route: Vec::new(),
+            backup_route: Vec::new(),
+        }
+    }
+}
+
+impl Actor for WanManager {
+    type Context = Context<Self>;
+}
+
+impl Supervised for WanManager {}
+impl SystemService for WanManager {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("WanManager started");
+        // left over if we were restarted while failed over, we start out on the primary
+        KI.set_failed_uplink(None);
+        if let Err(e) = KI.clear_failover_route() {
+            warn!("Failed to clear failover route {:?}", e);
+        }
+    }
+}
+
+impl Handler<Tick> for WanManager {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _: Tick, _ctx: &mut Context<Self>) -> Self::Result {
+        let network = SETTING.get_network().clone();
+        let (primary, backup) = match (network.external_nic, network.backup_external_nic) {
+            (Some(primary), Some(backup)) => (primary, backup),
+            // nothing to fail over to
+            _ => return Ok(()),
+        };
+
+        let primary_ok = uplink_works(&primary, &network.wan_check_ip);
+        if primary_ok {
+            self.primary_failures = 0;
+            self.primary_successes += 1;
+        } else {
+            self.primary_successes = 0;
+            self.primary_failures += 1;
+        }
+        trace!(
+            "WAN check on {} passed: {} active uplink {:?}",
+            primary,
+            primary_ok,
+            self.active
+        );
+
+        match self.active {
+            Uplink::Primary if self.primary_failures >= FAILOVER_THRESHOLD => {
+                if !uplink_works(&backup, &network.wan_check_ip) {
+                    warn!("Primary uplink {} is down but so is {}", primary, backup);
+                    return Ok(());
+                }
+                warn!(
+                    "Primary uplink {} is down, failing over to {}",
+                    primary, backup
+                );
+                self.primary_route = network.default_route.clone();
+                let backup_route = KI.get_iface_default_route(&backup)?;
+                SETTING.get_network_mut().default_route = backup_route.clone();
+                // the primary's default route stays in the main table, it mustn't be taken
+                // back into the settings while we're off it
+                KI.set_failed_uplink(Some(&primary));
+                KI.remove_pinned_routes(&primary)?;
+                self.active = Uplink::Backup;
+                set_failover_route(&backup_route)?;
+                self.backup_route = backup_route;
+            }
+            Uplink::Backup if self.primary_successes >= RECOVERY_THRESHOLD => {
+                info!("Primary uplink {} has recovered, switching back", primary);
+                KI.clear_failover_route()?;
+                KI.set_failed_uplink(None);
+                SETTING.get_network_mut().default_route = self.primary_route.clone();
+                KI.remove_pinned_routes(&backup)?;
+                self.active = Uplink::Primary;
+            }
+            // the exit tunnel may have come up or gone down since we failed over
+            Uplink::Backup => set_failover_route(&self.backup_route)?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+pub struct GetWanStatus;
+
+impl Message for GetWanStatus {
+    type Result = Result<WanStatus, Error>;
+}
+
+impl Handler<GetWanStatus> for WanManager {
+    type Result = Result<WanStatus, Error>;
+
+    fn handle(&mut self, _: GetWanStatus, _ctx: &mut Context<Self>) -> Self::Result {
+        let network = SETTING.get_network();
+        let active_iface = match self.active {
+            Uplink::Primary => network.external_nic.clone(),
+            Uplink::Backup => network.backup_external_nic.clone(),
+        };
+        Ok(WanStatus {
+            active_uplink: self.active,
+            active_iface,
+            primary_iface: network.external_nic.clone(),
+            backup_iface: network.backup_external_nic.clone(),
+            primary_failures: self.primary_failures,
+            primary_successes: self.primary_successes,
+        })
+    }
+}
+
+fn uplink_works(iface: &str, check_ip: &IpAddr) -> bool {
+    match KI.is_iface_up(iface) {
+        Some(true) => {}
+        _ => return false,
+    }
+    match KI.ping_check_iface(iface, check_ip, WAN_CHECK_TIMEOUT) {
+        Ok(val) => val,
+        Err(e) => {
+            warn!("Failed to check uplink {} with {:?}", iface, e);
+            false
+        }
+    }
+}
+
+/// Sends everything out of the backup uplink, unless the exit tunnel is our default route. Then
+/// it's only the tunnel itself that has to move and that's carried over the route in the
+/// settings, which manual peers and the exit's endpoint are routed over.
+fn set_failover_route(backup_route: &[String]) -> Result<(), Error> {
+    let on_exit_tunnel = match KI.get_default_route() {
+        Some(route) => route.contains(&"wg_exit".to_string()),
+        None => false,
+    };
+    if on_exit_tunnel {
+        KI.clear_failover_route()
+    } else {
+        KI.set_failover_route(backup_route)
+    }
+}
@@ -0,0 +1,43 @@
+//! Serves the dashboard api on a unix socket. Talking HTTP with JSON from shell scripts on a
+//! router is awkward, this is what `rita-ctl` talks to instead. Only local users can reach the
+//! socket and it's only readable by root, so unlike the dashboard no password is asked for and
+//! none of the browser specific middleware is applied, errors are formatted the same way.
+
+use crate::SETTING;
+use actix_web::{server, App};
+use settings::RitaCommonSettings;
+use std::fs::{remove_file, set_permissions, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use tokio::net::UnixListener;
+
+/// Starts serving the app built by `factory` on the configured control socket, if any
+pub fn start_control_socket<F>(factory: F)
+where
+    F: Fn() -> App + Send + Clone + 'static,
+{
+    let path = match SETTING.get_network().control_socket.clone() {
+        Some(path) => path,
+        None => return,
+    };
+
+    // a socket left behind by a previous run would stop us from binding
+    let _ = remove_file(&path);
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind the control socket {} {:?}", path, e);
+            return;
+        }
+    };
+    if let Err(e) = set_permissions(&path, Permissions::from_mode(0o600)) {
+        error!("Failed to restrict the control socket {} {:?}", path, e);
+        let _ = remove_file(&path);
+        return;
+    }
+    info!("Control socket listening on {}", path);
+
+    server::new(factory)
+        .workers(1)
+        .shutdown_timeout(0)
+        .start_incoming(listener.incoming(), false);
+}
@@ -0,0 +1,343 @@
+//! Conversion of on chain amounts into a fiat currency for display. Everything internal stays in
+//! wei, this only exists so that the dashboard can show users what their balance and debts are
+//! worth in a currency they understand. Prices come from a configured feed which is fetched
+//! periodically and cached, optionally signed with an ed25519 key so that the feed can be served
+//! from anywhere without being trusted. Feeds are dated, one that's too old or no newer than the
+//! one we have is rejected so that an old signed feed can't be replayed at us.
+
+use crate::rita_common::utils::now_secs;
+use crate::SETTING;
+use actix::{Actor, Arbiter, Context, Handler, Message, Supervised, SystemService};
+use actix_web::client;
+use actix_web::HttpMessage;
+use althea_types::{SigningPubkey, SystemChain};
+use failure::Error;
+use futures01::Future;
+use num256::{Int256, Uint256};
+use settings::RitaCommonSettings;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How often the price feed is fetched
+pub const RATE_REFRESH: Duration = Duration::from_secs(600);
+/// Rates older than this, going by the feed's timestamp, are not shown at all rather than being
+/// shown wrong
+const RATE_MAX_AGE: Duration = Duration::from_secs(7200);
+/// How far ahead of our clock a feed can be dated
+const FEED_CLOCK_SKEW: Duration = Duration::from_secs(300);
+const PRICE_FEED_TIMEOUT: Duration = Duration::from_secs(10);
+/// The number of base units in a whole token, for both eth and dai
+const WEI_PER_TOKEN: f64 = 1_000_000_000_000_000_000.0;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PriceFeed {
+    /// seconds since the unix epoch
+    pub timestamp: u64,
+    /// the price of a whole token by token symbol and then currency code
+    pub prices: HashMap<String, HashMap<String, f64>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedPriceFeed {
+    /// the json encoded PriceFeed, the signature is over these exact bytes
+    pub feed: String,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FiatAmount {
+    pub currency: String,
+    pub amount: f64,
+}
+
+/// The value of a whole token of our system chain in the display currency
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExchangeRate {
+    pub currency: String,
+    pub rate: f64,
+}
+
+impl ExchangeRate {
+    fn convert(&self, wei: f64) -> FiatAmount {
+        FiatAmount {
+            currency: self.currency.clone(),
+            amount: wei / WEI_PER_TOKEN * self.rate,
+        }
+    }
+
+    pub fn convert_uint(&self, wei: &Uint256) -> FiatAmount {
+        self.convert(wei.to_string().parse().unwrap_or(0.0))
+    }
+
+    pub fn convert_int(&self, wei: &Int256) -> FiatAmount {
+        self.convert(wei.to_string().parse().unwrap_or(0.0))
+    }
+}
+
+fn token_symbol(chain: SystemChain) -> &'static str {
+    match chain {
+        SystemChain::Xdai => "DAI",
+        SystemChain::Ethereum | SystemChain::Rinkeby => "ETH",
+    }
+}
+
+fn verify_feed(signed: &SignedPriceFeed, pubkey: &SigningPubkey) -> Result<PriceFeed, Error> {
+    if !pubkey.verify(signed.feed.as_bytes(), &signed.signature) {
+        bail!("Price feed signature is invalid");
+    }
+    Ok(serde_json::from_str(&signed.feed)?)
+}
+
+fn parse_feed(body: &[u8], pubkey: Option<SigningPubkey>) -> Result<PriceFeed, Error> {
+    match pubkey {
+        Some(pubkey) => verify_feed(&serde_json::from_slice(body)?, &pubkey),
+        None => Ok(serde_json::from_slice(body)?),
+    }
+}
+
+fn feed_is_stale(feed: &PriceFeed, now: u64) -> bool {
+    now.saturating_sub(feed.timestamp) > RATE_MAX_AGE.as_secs()
+}
+
+/// A feed replaces the one we have only if it's newer and recent, `last` is the timestamp of the
+/// one we have
+fn check_feed(feed: &PriceFeed, last: Option<u64>, now: u64) -> Result<(), Error> {
+    if feed.timestamp > now + FEED_CLOCK_SKEW.as_secs() {
+        bail!("Price feed is dated in the future");
+    }
+    if feed_is_stale(feed, now) {
+        bail!("Price feed from {} is stale", feed.timestamp);
+    }
+    if let Some(last) = last {
+        if feed.timestamp <= last {
+            bail!(
+                "Price feed from {} is no newer than the one we have from {}",
+                feed.timestamp,
+                last
+            );
+        }
+    }
+    Ok(())
+}
+
+pub struct CurrencyConverter {
+    feed: Option<PriceFeed>,
+    fetched: Option<Instant>,
+}
+
+impl Actor for CurrencyConverter {
+    type Context = Context<Self>;
+}
+
+impl Supervised for CurrencyConverter {}
+impl SystemService for CurrencyConverter {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("Currency converter started");
+    }
+}
+
+impl Default for CurrencyConverter {
+    fn default() -> CurrencyConverter {
+        CurrencyConverter {
+            feed: None,
+            fetched: None,
+        }
+    }
+}
+
+/// Fetches the price feed if it's configured and our copy is stale
+#[derive(Message)]
+pub struct UpdateRates;
+
+impl Handler<UpdateRates> for CurrencyConverter {
+    type Result = ();
+
+    fn handle(&mut self, _msg: UpdateRates, _ctx: &mut Context<Self>) -> Self::Result {
+        let localization = SETTING.get_localization();
+        let url = localization.price_feed_url.clone();
+        let pubkey = localization.price_feed_pubkey;
+        let enabled = localization.display_currency.is_some();
+        drop(localization);
+
+        let url = match url {
+            Some(url) if enabled => url,
+            _ => return,
+        };
+        if let Some(fetched) = self.fetched {
+            if fetched.elapsed() < RATE_REFRESH {
+                return;
+            }
+        }
+        // a signed feed can come from anywhere, an unsigned one has to at least be over tls
+        if pubkey.is_none() && !url.starts_with("https://") {
+            error!("Unsafe price feed url, you must use https or a signed feed!");
+            return;
+        }
+
+        let request = match client::get(&url).header("User-Agent", "Actix-web").finish() {
+            Ok(val) => val,
+            Err(e) => {
+                warn!("Failed to build price feed request {:?}", e);
+                return;
+            }
+        };
+        let res = request
+            .send()
+            .timeout(PRICE_FEED_TIMEOUT)
+            .from_err()
+            .and_then(|response| response.body().from_err())
+            .and_then(move |body| {
+                let feed = parse_feed(&body, pubkey)?;
+                CurrencyConverter::from_registry().do_send(NewPriceFeed(feed));
+                Ok(())
+            })
+            .then(|res: Result<(), Error>| {
+                if let Err(e) = res {
+                    warn!("Failed to update price feed {:?}", e);
+                }
+                Ok(())
+            });
+        Arbiter::spawn(res);
+    }
+}
+
+#[derive(Message)]
+struct NewPriceFeed(PriceFeed);
+
+impl Handler<NewPriceFeed> for CurrencyConverter {
+    type Result = ();
+
+    fn handle(&mut self, msg: NewPriceFeed, _ctx: &mut Context<Self>) -> Self::Result {
+        trace!("Got price feed {:?}", msg.0);
+        let last = self.feed.as_ref().map(|feed| feed.timestamp);
+        if let Err(e) = check_feed(&msg.0, last, now_secs()) {
+            warn!("Rejected price feed {:?}", e);
+            return;
+        }
+        self.feed = Some(msg.0);
+        self.fetched = Some(Instant::now());
+    }
+}
+
+/// The exchange rate for our system chain token in the display currency, None if fiat display
+/// is turned off or we don't have a recent enough rate
+pub struct GetExchangeRate;
+
+impl Message for GetExchangeRate {
+    type Result = Result<Option<ExchangeRate>, Error>;
+}
+
+impl Handler<GetExchangeRate> for CurrencyConverter {
+    type Result = Result<Option<ExchangeRate>, Error>;
+
+    fn handle(&mut self, _msg: GetExchangeRate, _ctx: &mut Context<Self>) -> Self::Result {
+        let currency = match SETTING.get_localization().display_currency.clone() {
+            Some(val) => val,
+            None => return Ok(None),
+        };
+        match &self.feed {
+            Some(feed) if !feed_is_stale(feed, now_secs()) => {
+                let token = token_symbol(SETTING.get_payment().system_chain);
+                Ok(lookup_rate(feed, token, &currency))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+fn lookup_rate(feed: &PriceFeed, token: &str, currency: &str) -> Option<ExchangeRate> {
+    let rate = *feed.prices.get(token)?.get(currency)?;
+    Some(ExchangeRate {
+        currency: currency.to_string(),
+        rate,
+    })
+}
+
+/// Convenience for dashboard endpoints that annotate their responses
+pub fn get_exchange_rate() -> impl Future<Item = Option<ExchangeRate>, Error = Error> {
+    CurrencyConverter::from_registry()
+        .send(GetExchangeRate)
+        .from_err()
+        .and_then(|res| res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed() -> PriceFeed {
+        let mut dai = HashMap::new();
+        dai.insert("USD".to_string(), 1.0);
+        dai.insert("EUR".to_string(), 0.9);
+        let mut prices = HashMap::new();
+        prices.insert("DAI".to_string(), dai);
+        PriceFeed {
+            timestamp: 0,
+            prices,
+        }
+    }
+
+    #[test]
+    fn test_convert() {
+        let rate = lookup_rate(&feed(), "DAI", "EUR").unwrap();
+        assert_eq!(
+            rate.convert_uint(&Uint256::from(2_000_000_000_000_000_000u64)),
+            FiatAmount {
+                currency: "EUR".to_string(),
+                amount: 1.8
+            }
+        );
+        assert_eq!(
+            rate.convert_int(&Int256::from(-500_000_000_000_000_000i64))
+                .amount,
+            -0.45
+        );
+        assert!(lookup_rate(&feed(), "ETH", "USD").is_none());
+        assert!(lookup_rate(&feed(), "DAI", "JPY").is_none());
+    }
+
+    #[test]
+    fn test_check_feed() {
+        let now = 1_600_000_000;
+        let mut current = feed();
+        current.timestamp = now - 60;
+        assert!(check_feed(&current, None, now).is_ok());
+        assert!(check_feed(&current, Some(now - 120), now).is_ok());
+        // replays of the feed we have or older ones
+        assert!(check_feed(&current, Some(now - 60), now).is_err());
+        assert!(check_feed(&current, Some(now - 30), now).is_err());
+        let mut old = feed();
+        old.timestamp = now - RATE_MAX_AGE.as_secs() - 1;
+        assert!(check_feed(&old, None, now).is_err());
+        let mut future = feed();
+        future.timestamp = now + FEED_CLOCK_SKEW.as_secs() + 1;
+        assert!(check_feed(&future, None, now).is_err());
+    }
+
+    #[test]
+    fn test_signed_feed() {
+        use sodiumoxide::crypto::sign;
+
+        let (pubkey, secretkey) = sign::gen_keypair();
+        let pubkey = SigningPubkey::from(pubkey);
+        let body = serde_json::to_string(&feed()).unwrap();
+        let sign::Signature(signature) = sign::sign_detached(body.as_bytes(), &secretkey);
+        let signed = SignedPriceFeed {
+            feed: body.clone(),
+            signature: signature.to_vec(),
+        };
+        let signed_bytes = serde_json::to_vec(&signed).unwrap();
+
+        assert!(parse_feed(&signed_bytes, Some(pubkey)).is_ok());
+        // unsigned feeds are rejected when a key is configured
+        assert!(parse_feed(body.as_bytes(), Some(pubkey)).is_err());
+        assert!(parse_feed(body.as_bytes(), None).is_ok());
+
+        let (other_pubkey, _) = sign::gen_keypair();
+        assert!(parse_feed(&signed_bytes, Some(other_pubkey.into())).is_err());
+        let mut tampered = signed;
+        tampered.feed = tampered.feed.replace("0.9", "9.0");
+        let tampered = serde_json::to_vec(&tampered).unwrap();
+        assert!(parse_feed(&tampered, Some(pubkey)).is_err());
+    }
+}
@@ -5,6 +5,7 @@
 //! to compute the amount it should pay at a time, these micropayments have the effect of pro-rating
 //! the DAO fee amount and preventing the router from drastically making a large payment
 
+use crate::rita_common::payment_controller::signer::sign_and_send;
 use crate::rita_common::payment_controller::TRANSACTION_SUBMISSON_TIMEOUT;
 use crate::rita_common::rita_loop::get_web3_server;
 use crate::rita_common::simulated_txfee_manager::AddTxToTotal;
@@ -21,7 +22,6 @@ use num256::Int256;
 use num_traits::Signed;
 use settings::RitaCommonSettings;
 use std::time::Instant;
-use web30::client::Web3;
 
 pub struct DAOManager {
     last_payment_time: Instant,
@@ -76,7 +76,6 @@ impl Handler<Tick> for DAOManager {
     fn handle(&mut self, _msg: Tick, _: &mut Context<Self>) -> Self::Result {
         let dao_settings = SETTING.get_dao();
         let payment_settings = SETTING.get_payment();
-        let eth_private_key = payment_settings.eth_private_key;
         let our_id = match SETTING.get_identity() {
             Some(id) => id,
             None => return,
@@ -92,7 +91,6 @@ impl Handler<Tick> for DAOManager {
         let we_have_a_dao = !dao_addresses.is_empty();
         let should_pay =
             (Int256::from(self.last_payment_time.elapsed().as_secs()) * dao_fee) > pay_threshold;
-        let net_version = payment_settings.net_version;
         drop(payment_settings);
         trace!("We should pay the subnet dao {}", should_pay);
         trace!("We have a dao to pay {}", we_have_a_dao);
@@ -118,8 +116,6 @@ impl Handler<Tick> for DAOManager {
                 };
 
                 let full_node = get_web3_server();
-                let web3 = Web3::new(&full_node, TRANSACTION_SUBMISSON_TIMEOUT);
-
                 let tx = Transaction {
                     nonce: nonce.clone(),
                     gas_price: gas_price.clone(),
@@ -129,20 +125,8 @@ impl Handler<Tick> for DAOManager {
                     data: Vec::new(),
                     signature: None,
                 };
-                let transaction_signed = tx.sign(
-                    &eth_private_key.expect("No private key configured!"),
-                    net_version,
-                );
-
-                let transaction_bytes = match transaction_signed.to_bytes() {
-                    Ok(bytes) => bytes,
-                    Err(e) => {
-                        error!("Failed to generate DAO transaction, {:?}", e);
-                        return;
-                    }
-                };
-
-                let transaction_status = web3.eth_send_raw_transaction(transaction_bytes);
+                let transaction_status =
+                    sign_and_send(tx, full_node, TRANSACTION_SUBMISSON_TIMEOUT);
 
                 // in theory this may fail, for now there is no handler and
                 // we will just underpay when that occurs
@@ -155,6 +139,8 @@ impl Handler<Tick> for DAOManager {
                                 from: our_id,
                                 amount: amount_to_pay.clone(),
                                 txid: Some(txid),
+                                channel: None,
+                                payment_id: None,
                             },
                         });
                         SimulatedTxFeeManager::from_registry().do_send(AddTxToTotal(amount_to_pay));
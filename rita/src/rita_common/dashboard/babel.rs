@@ -1,16 +1,22 @@
+use crate::rita_common::dashboard::error::{DashboardError, ErrorCode};
+use crate::rita_common::tunnel_manager::{interface_params, RefreshBabelInterfaces, TunnelManager};
 use crate::ARGS;
 use crate::SETTING;
-use ::actix_web::http::StatusCode;
-use ::actix_web::Path;
+use ::actix::SystemService;
 use ::actix_web::{HttpRequest, HttpResponse, Result};
+use ::actix_web::{Json, Path};
+use ::settings::network::BabelSettings;
 use ::settings::FileWrite;
 use ::settings::RitaCommonSettings;
 use babel_monitor::open_babel_stream;
+use babel_monitor::set_interface_defaults;
 use babel_monitor::set_local_fee as babel_set_local_fee;
 use babel_monitor::set_metric_factor as babel_set_metric_factor;
 use babel_monitor::start_connection;
 use failure::Error;
+use futures01::future;
 use futures01::future::Future;
+use serde_json::Value;
 use std::collections::HashMap;
 
 pub fn get_local_fee(_req: HttpRequest) -> Result<HttpResponse, Error> {
@@ -29,37 +35,43 @@ pub fn get_metric_factor(_req: HttpRequest) -> Result<HttpResponse, Error> {
     Ok(HttpResponse::Ok().json(ret))
 }
 
-pub fn set_local_fee(path: Path<u32>) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
-    let new_fee = path.into_inner();
-    debug!("/local_fee/{} POST hit", new_fee);
+/// Sets our fee in babel and then in the settings, brought down to max_fee so that we never
+/// charge more than we'd pay ourselves. The settings are only changed once babel has taken the
+/// fee and it's up to the caller to write them out. Resolves to the fee that was set
+pub fn apply_local_fee(new_fee: u32) -> impl Future<Item = u32, Error = Error> {
     let babel_port = SETTING.get_network().babel_port;
     let max_fee = SETTING.get_payment().max_fee;
-    // prevent the user from setting a higher price than they would pay
-    // themselves
-    let new_fee = if new_fee > max_fee { max_fee } else { new_fee };
-
-    Box::new(open_babel_stream(babel_port).then(move |stream| {
-        // if we can't get to babel here we panic
-        let stream = stream.expect("Can't reach Babel!");
-        start_connection(stream).and_then(move |stream| {
-            babel_set_local_fee(stream, new_fee).then(move |res| {
-                if let Err(e) = res {
-                    error!("Failed to set babel fee with {:?}", e);
-                    Ok(HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
-                        .into_builder()
-                        .json("Failed to set babel fee"))
-                } else {
-                    SETTING.get_payment_mut().local_fee = new_fee;
-
-                    // try and save the config and fail if we can't
-                    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
-                        return Err(e);
-                    }
-
-                    Ok(HttpResponse::Ok().json(()))
-                }
-            })
+    let new_fee = new_fee.min(max_fee);
+
+    open_babel_stream(babel_port)
+        .from_err()
+        .and_then(move |stream| {
+            start_connection(stream).and_then(move |stream| babel_set_local_fee(stream, new_fee))
+        })
+        .map(move |_stream| {
+            SETTING.get_payment_mut().local_fee = new_fee;
+            new_fee
         })
+}
+
+pub fn set_local_fee(path: Path<u32>) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let new_fee = path.into_inner();
+    debug!("/local_fee/{} POST hit", new_fee);
+
+    Box::new(apply_local_fee(new_fee).then(|res| {
+        if let Err(e) = res {
+            error!("Failed to set babel fee with {:?}", e);
+            return Err(
+                DashboardError::new(ErrorCode::BabelFailed, "Failed to set babel fee").into(),
+            );
+        }
+
+        // try and save the config and fail if we can't
+        if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+            return Err(e);
+        }
+
+        Ok(HttpResponse::Ok().json(()))
     }))
 }
 
@@ -68,27 +80,169 @@ pub fn set_metric_factor(path: Path<u32>) -> Box<dyn Future<Item = HttpResponse,
     debug!("/metric_factor/{} POST hit", new_factor);
     let babel_port = SETTING.get_network().babel_port;
 
-    Box::new(open_babel_stream(babel_port).then(move |stream| {
-        // if we can't get to babel here we panic
-        let stream = stream.expect("Can't reach Babel!");
-        start_connection(stream).and_then(move |stream| {
-            babel_set_metric_factor(stream, new_factor).then(move |res| {
+    Box::new(
+        open_babel_stream(babel_port)
+            .from_err()
+            .and_then(move |stream| {
+                start_connection(stream)
+                    .and_then(move |stream| babel_set_metric_factor(stream, new_factor))
+            })
+            .then(move |res| {
                 if let Err(e) = res {
                     error!("Failed to set babel metric factor with {:?}", e);
-                    Ok(HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
-                        .into_builder()
-                        .json("Failed to set babel metric factor"))
-                } else {
-                    SETTING.get_network_mut().metric_factor = new_factor;
-
-                    // try and save the config and fail if we can't
-                    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
-                        return Err(e);
-                    }
-
-                    Ok(HttpResponse::Ok().json(()))
+                    return Err(DashboardError::new(
+                        ErrorCode::BabelFailed,
+                        "Failed to set babel metric factor",
+                    )
+                    .into());
                 }
+                SETTING.get_network_mut().metric_factor = new_factor;
+
+                // try and save the config and fail if we can't
+                if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+                    return Err(e);
+                }
+
+                Ok(HttpResponse::Ok().json(()))
+            }),
+    )
+}
+
+/// Everything we tell babel about how to run, interface parameters apply to every mesh tunnel.
+/// An update is any subset of these fields, anything left out is unchanged
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct BabelSettingsView {
+    #[serde(flatten)]
+    pub babel: BabelSettings,
+    pub local_fee: u32,
+    pub metric_factor: u32,
+}
+
+fn babel_settings_view() -> BabelSettingsView {
+    BabelSettingsView {
+        babel: SETTING.get_network().babel,
+        local_fee: SETTING.get_payment().local_fee,
+        metric_factor: SETTING.get_network().metric_factor,
+    }
+}
+
+/// Applies an update to the current settings, the fee is capped at what we would pay ourselves
+fn apply_babel_update(
+    current: BabelSettingsView,
+    update: &Value,
+    max_fee: u32,
+) -> Result<BabelSettingsView, String> {
+    let update = match update.as_object() {
+        Some(update) => update,
+        None => return Err("Babel settings must be a json object".to_string()),
+    };
+    let mut merged = match serde_json::to_value(current) {
+        Ok(Value::Object(merged)) => merged,
+        _ => return Err("Failed to serialize the current babel settings".to_string()),
+    };
+    for (key, value) in update {
+        merged.insert(key.clone(), value.clone());
+    }
+    let mut new: BabelSettingsView = match serde_json::from_value(Value::Object(merged)) {
+        Ok(new) => new,
+        Err(e) => return Err(format!("Invalid babel settings: {}", e)),
+    };
+    new.local_fee = new.local_fee.min(max_fee);
+    if new.babel.hello_interval == 0 {
+        return Err("hello_interval must be greater than zero".to_string());
+    }
+    if new.babel.rtt_min >= new.babel.rtt_max {
+        return Err("rtt_min must be less than rtt_max".to_string());
+    }
+    Ok(new)
+}
+
+pub fn get_babel_settings(_req: HttpRequest) -> Result<Json<BabelSettingsView>, Error> {
+    debug!("/babel/settings GET hit");
+    Ok(Json(babel_settings_view()))
+}
+
+pub fn set_babel_settings(
+    update: Json<Value>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let update = update.into_inner();
+    debug!("/babel/settings POST hit with {:?}", update);
+    let babel_port = SETTING.get_network().babel_port;
+    let max_fee = SETTING.get_payment().max_fee;
+    let new = match apply_babel_update(babel_settings_view(), &update, max_fee) {
+        Ok(new) => new,
+        Err(e) => return Box::new(future::err(DashboardError::invalid_input(e).into())),
+    };
+
+    Box::new(
+        open_babel_stream(babel_port)
+            .from_err()
+            .and_then(move |stream| {
+                start_connection(stream).and_then(move |stream| {
+                    babel_set_local_fee(stream, new.local_fee)
+                        .and_then(move |stream| babel_set_metric_factor(stream, new.metric_factor))
+                        .and_then(move |stream| {
+                            set_interface_defaults(stream, interface_params(&new.babel))
+                        })
+                })
             })
-        })
-    }))
+            .then(move |res| {
+                if let Err(e) = res {
+                    error!("Failed to set babel settings with {:?}", e);
+                    return Err(DashboardError::new(
+                        ErrorCode::BabelFailed,
+                        "Failed to set babel settings",
+                    )
+                    .into());
+                }
+                // only once babel has taken all of it, a partly applied update is put right by
+                // the slow loop from the settings we had
+                {
+                    let mut network = SETTING.get_network_mut();
+                    network.metric_factor = new.metric_factor;
+                    network.babel = new.babel;
+                }
+                SETTING.get_payment_mut().local_fee = new.local_fee;
+                // tunnels babel is already monitoring keep their old parameters otherwise
+                TunnelManager::from_registry().do_send(RefreshBabelInterfaces);
+
+                // try and save the config and fail if we can't
+                if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+                    return Err(e);
+                }
+
+                Ok(HttpResponse::Ok().json(babel_settings_view()))
+            }),
+    )
+}
+
+#[test]
+fn test_apply_babel_update() {
+    let current = BabelSettingsView {
+        babel: BabelSettings {
+            hello_interval: 4000,
+            max_rtt_penalty: 500,
+            rtt_min: 10,
+            rtt_max: 120,
+            max_reputation_penalty: 300,
+            smooth_rtt: false,
+        },
+        local_fee: 100,
+        metric_factor: 1900,
+    };
+    let new = apply_babel_update(
+        current,
+        &json!({"hello_interval": 1000, "local_fee": 5000}),
+        1000,
+    )
+    .unwrap();
+    assert_eq!(new.babel.hello_interval, 1000);
+    assert_eq!(new.local_fee, 1000);
+    assert_eq!(new.babel.rtt_max, 120);
+    assert_eq!(new.metric_factor, 1900);
+
+    assert!(apply_babel_update(current, &json!({"hello_interval": 0}), 1000).is_err());
+    assert!(apply_babel_update(current, &json!({"rtt_min": 200}), 1000).is_err());
+    assert!(apply_babel_update(current, &json!({"rtt_min": "fast"}), 1000).is_err());
+    assert!(apply_babel_update(current, &json!([1000]), 1000).is_err());
 }
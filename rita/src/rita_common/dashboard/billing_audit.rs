@@ -0,0 +1,19 @@
+use crate::rita_common::traffic_watcher::audit::{AuditQuery, AuditSample};
+use crate::rita_common::traffic_watcher::{GetBillingAudit, TrafficWatcher};
+use ::actix::SystemService;
+use ::actix_web::{AsyncResponder, Json, Query};
+use failure::Error;
+use futures01::Future;
+use std::boxed::Box;
+
+/// What each neighbor was billed for each destination, for settling billing disputes
+pub fn get_billing_audit(
+    query: Query<AuditQuery>,
+) -> Box<dyn Future<Item = Json<Vec<AuditSample>>, Error = Error>> {
+    trace!("get_billing_audit: Hit");
+    TrafficWatcher::from_registry()
+        .send(GetBillingAudit(query.into_inner()))
+        .from_err()
+        .and_then(|reply| Ok(Json(reply?)))
+        .responder()
+}
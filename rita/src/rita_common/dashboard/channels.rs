@@ -0,0 +1,40 @@
+//! Management of our Guac payment channels, opening a channel locks up the deposit on chain
+//! so that payments to that neighbor can be made off chain from then on.
+
+use crate::rita_common::guac;
+use crate::rita_common::guac::ChannelState;
+use crate::rita_common::guac::OpenChannelRequest;
+use ::actix_web::{AsyncResponder, HttpRequest, Json, Path};
+use clarity::Address;
+use failure::Error;
+use futures01::Future;
+use std::boxed::Box;
+
+pub fn get_payment_channels(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<Vec<ChannelState>>, Error = Error>> {
+    debug!("/channels GET hit");
+    guac::get_channels()
+        .and_then(|channels| Ok(Json(channels)))
+        .responder()
+}
+
+pub fn open_payment_channel(
+    req: Json<OpenChannelRequest>,
+) -> Box<dyn Future<Item = Json<ChannelState>, Error = Error>> {
+    let req = req.into_inner();
+    debug!("/channels/open hit with {:?}", req);
+    guac::open_channel(req)
+        .and_then(|channel| Ok(Json(channel)))
+        .responder()
+}
+
+pub fn close_payment_channel(
+    path: Path<Address>,
+) -> Box<dyn Future<Item = Json<ChannelState>, Error = Error>> {
+    let counterparty = path.into_inner();
+    debug!("/channels/close/{:#x} hit", counterparty);
+    guac::close_channel(counterparty)
+        .and_then(|channel| Ok(Json(channel)))
+        .responder()
+}
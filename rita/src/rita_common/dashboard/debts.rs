@@ -1,14 +1,29 @@
+use crate::rita_common::currency::get_exchange_rate;
+use crate::rita_common::debt_keeper::ledger::{read_history, LedgerEntry};
+use crate::rita_common::debt_keeper::reconcile::Divergence;
 use crate::rita_common::debt_keeper::DebtKeeper;
+use crate::rita_common::debt_keeper::GetDebtHistory;
+use crate::rita_common::debt_keeper::GetDebtJournal;
 use crate::rita_common::debt_keeper::GetDebtsList;
 use crate::rita_common::debt_keeper::GetDebtsResult;
+use crate::rita_common::debt_keeper::GetDivergence;
+use crate::rita_common::debt_keeper::JournalStatus;
 use crate::rita_common::debt_keeper::Traffic;
 use crate::rita_common::debt_keeper::TrafficReplace;
+use crate::rita_common::debt_keeper::WriteOff;
+use crate::rita_common::debt_keeper::WriteOffResult;
+use crate::rita_common::payment_controller::receipt::verify_receipt;
+use crate::SETTING;
 use ::actix::SystemService;
-use ::actix_web::{AsyncResponder, HttpRequest, HttpResponse, Json};
-use althea_types::Identity;
+use ::actix_web::{AsyncResponder, HttpRequest, HttpResponse, Json, Path};
+use althea_types::{Identity, PaymentReceipt};
 use failure::Error;
+use futures01::future;
 use futures01::Future;
+use num256::Uint256;
+use settings::RitaCommonSettings;
 use std::boxed::Box;
+use std::net::IpAddr;
 
 pub fn get_debts(
     _req: HttpRequest,
@@ -17,10 +32,91 @@ pub fn get_debts(
     DebtKeeper::from_registry()
         .send(GetDebtsList {})
         .from_err()
+        .join(get_exchange_rate())
+        .and_then(move |(reply, rate)| {
+            let mut debts = reply?;
+            if let Some(rate) = rate {
+                for debt in debts.iter_mut() {
+                    debt.debt_fiat = Some(rate.convert_int(&debt.payment_details.debt));
+                }
+            }
+            Ok(Json(debts))
+        })
+        .responder()
+}
+
+/// Returns every traffic update and payment recorded for the counterparty with the
+/// given mesh ip, oldest first
+pub fn get_debt_history(
+    path: Path<IpAddr>,
+) -> Box<dyn Future<Item = Json<Vec<LedgerEntry>>, Error = Error>> {
+    trace!("get_debt_history: Hit");
+    let counterparty = path.into_inner();
+    // the files are read here rather than by DebtKeeper so it isn't held up, anything written
+    // out between this and the request below shows up next time
+    let ledger_file = SETTING.get_payment().debt_ledger_file.clone();
+    let mut history = match read_history(&ledger_file, counterparty) {
+        Ok(history) => history,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    DebtKeeper::from_registry()
+        .send(GetDebtHistory { counterparty })
+        .from_err()
+        .and_then(move |reply| {
+            history.extend(reply?);
+            Ok(Json(history))
+        })
+        .responder()
+}
+
+/// How far our traffic counters are from each neighbor's, for neighbors that reconcile
+pub fn get_counter_divergence(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<Vec<Divergence>>, Error = Error>> {
+    trace!("get_counter_divergence: Hit");
+    DebtKeeper::from_registry()
+        .send(GetDivergence)
+        .from_err()
+        .and_then(move |reply| Ok(Json(reply?)))
+        .responder()
+}
+
+/// The IOUs exchanged with each neighbor while payments couldn't be made
+pub fn get_debt_journal(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<Vec<JournalStatus>>, Error = Error>> {
+    trace!("get_debt_journal: Hit");
+    DebtKeeper::from_registry()
+        .send(GetDebtJournal)
+        .from_err()
         .and_then(move |reply| Ok(Json(reply?)))
         .responder()
 }
 
+#[derive(Serialize)]
+pub struct ReceiptVerification {
+    pub valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Checks a payment receipt, usually one taken from the debt history, was really signed by
+/// the payee it names
+pub fn verify_payment_receipt(receipt: Json<PaymentReceipt>) -> HttpResponse {
+    trace!("verify_payment_receipt: Hit");
+    let res = match verify_receipt(&receipt) {
+        Ok(()) => ReceiptVerification {
+            valid: true,
+            reason: None,
+        },
+        Err(e) => ReceiptVerification {
+            valid: false,
+            reason: Some(e.to_string()),
+        },
+    };
+    HttpResponse::Ok().json(res)
+}
+
 pub fn reset_debt(user_to_forgive: Json<Identity>) -> HttpResponse {
     let forgiven_traffic = TrafficReplace {
         traffic: Traffic {
@@ -31,3 +127,31 @@ pub fn reset_debt(user_to_forgive: Json<Identity>) -> HttpResponse {
     DebtKeeper::from_registry().do_send(forgiven_traffic);
     HttpResponse::Ok().json(())
 }
+
+#[derive(Deserialize, Debug)]
+pub struct WriteOffRequest {
+    identity: Identity,
+    /// in wei, the whole balance if it's left out
+    #[serde(default)]
+    amount: Option<Uint256>,
+    /// pass it on to the counterparty if it's a debt they owed us
+    #[serde(default)]
+    notify: bool,
+}
+
+/// Writes off part or all of the balance with a counterparty and records it in the ledger
+pub fn write_off_debt(
+    req: Json<WriteOffRequest>,
+) -> Box<dyn Future<Item = Json<WriteOffResult>, Error = Error>> {
+    trace!("write_off_debt: Hit");
+    let req = req.into_inner();
+    DebtKeeper::from_registry()
+        .send(WriteOff {
+            counterparty: req.identity,
+            amount: req.amount,
+            notify: req.notify,
+        })
+        .from_err()
+        .and_then(move |reply| Ok(Json(reply?)))
+        .responder()
+}
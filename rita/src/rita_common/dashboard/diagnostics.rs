@@ -0,0 +1,242 @@
+//! Everything support usually asks for when something is wrong with a router, in one download.
+//! The bundle is a tar.gz of json and command output files, settings are included with every
+//! secret redacted so that bundles can be attached to tickets as they are. Parts that can't be
+//! collected (say babel is down) are replaced by the error instead of failing the whole bundle.
+
+use crate::rita_common::dashboard::own_info::READABLE_VERSION;
+use crate::rita_common::debt_keeper::{DebtKeeper, GetDebtsList, GetDivergence};
+use crate::rita_common::route_cache::get_routes;
+use crate::rita_common::traffic_watcher::{GetTrafficAlerts, TrafficWatcher};
+use crate::rita_common::tunnel_manager::{GetNeighbors, TunnelManager};
+use crate::rita_common::utils::now_secs;
+use crate::rita_common::watchdog::{GetWatchdogStatus, Watchdog};
+use crate::KI;
+use crate::SETTING;
+use ::actix::{MailboxError, SystemService};
+use ::actix_web::http::header::CONTENT_DISPOSITION;
+use ::actix_web::{AsyncResponder, HttpRequest, HttpResponse};
+use failure::Error;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures01::Future;
+use serde::Serialize;
+use serde_json::Value;
+use settings::RitaCommonSettings;
+use std::boxed::Box;
+use tar::{Builder, Header};
+
+/// Settings whose names end in one of these are replaced before they go in a bundle
+const SECRET_SUFFIXES: [&str; 6] = [
+    "password",
+    "passphrase",
+    "private_key",
+    "api_key",
+    "auth_token",
+    "db_uri",
+];
+
+const REDACTED: &str = "<redacted>";
+
+/// Commands whose output goes in the bundle as is, `wg show` hides private keys by itself
+const COMMANDS: [(&str, &str, &[&str]); 5] = [
+    ("ip_addr.txt", "ip", &["addr"]),
+    ("ip_route.txt", "ip", &["route"]),
+    ("ip6_route.txt", "ip", &["-6", "route"]),
+    ("ip_link_stats.txt", "ip", &["-s", "link"]),
+    ("wg_show.txt", "wg", &["show", "all"]),
+];
+
+/// Replaces every secret in a settings json in place
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let secret = SECRET_SUFFIXES.iter().any(|suffix| key.ends_with(suffix));
+                if secret && !value.is_null() {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => {
+            for value in values.iter_mut() {
+                redact(value);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn json_file<T: Serialize>(result: Result<T, Error>) -> Vec<u8> {
+    let value = match result.and_then(|value| Ok(serde_json::to_value(value)?)) {
+        Ok(value) => value,
+        Err(e) => json!({ "error": e.to_string() }),
+    };
+    serde_json::to_vec_pretty(&value).unwrap_or_default()
+}
+
+fn reply_file<T: Serialize>(reply: Result<Result<T, Error>, MailboxError>) -> Vec<u8> {
+    json_file(reply.map_err(Error::from).and_then(|reply| reply))
+}
+
+fn command_file(program: &str, args: &[&str]) -> Vec<u8> {
+    match KI.run_command(program, args) {
+        Ok(output) => {
+            let mut contents = output.stdout;
+            contents.extend_from_slice(&output.stderr);
+            contents
+        }
+        Err(e) => format!("{} failed: {}\n", program, e).into_bytes(),
+    }
+}
+
+/// Packs the files into a gzipped tarball under a `diagnostics` directory
+fn build_bundle(files: &[(String, Vec<u8>)], mtime: u64) -> Result<Vec<u8>, Error> {
+    let mut archive = Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for (name, contents) in files {
+        let mut header = Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        archive.append_data(
+            &mut header,
+            format!("diagnostics/{}", name),
+            contents.as_slice(),
+        )?;
+    }
+    Ok(archive.into_inner()?.finish()?)
+}
+
+pub fn get_diagnostics(_req: HttpRequest) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    debug!("Get diagnostics endpoint hit!");
+    let now = now_secs();
+
+    let mut files: Vec<(String, Vec<u8>)> = Vec::new();
+    files.push((
+        "version.json".to_string(),
+        json_file(Ok(json!({
+            "rita_version": env!("CARGO_PKG_VERSION"),
+            "version": READABLE_VERSION,
+            "generated": now,
+        }))),
+    ));
+    let settings = SETTING.get_all().map(|mut settings| {
+        redact(&mut settings);
+        settings
+    });
+    files.push(("settings.json".to_string(), json_file(settings)));
+    for (name, program, args) in COMMANDS.iter() {
+        files.push((name.to_string(), command_file(program, args)));
+    }
+
+    let routes =
+        get_routes().then(|res| Ok::<Vec<u8>, Error>(json_file(res.map(|cached| cached.routes))));
+    let neighbors = TunnelManager::from_registry()
+        .send(GetNeighbors)
+        .then(|reply| Ok(reply_file(reply)));
+    let debts = DebtKeeper::from_registry()
+        .send(GetDebtsList {})
+        .then(|reply| Ok(reply_file(reply)));
+    let divergence = DebtKeeper::from_registry()
+        .send(GetDivergence)
+        .then(|reply| Ok(reply_file(reply)));
+    let watchdog = Watchdog::from_registry()
+        .send(GetWatchdogStatus)
+        .then(|reply| Ok(reply_file(reply)));
+    let alerts = TrafficWatcher::from_registry()
+        .send(GetTrafficAlerts)
+        .then(|reply| Ok(reply_file(reply)));
+
+    routes
+        .join5(neighbors, debts, divergence, watchdog)
+        .join(alerts)
+        .and_then(
+            move |((routes, neighbors, debts, divergence, watchdog), alerts)| {
+                files.push(("babel_routes.json".to_string(), routes));
+                files.push(("neighbors.json".to_string(), neighbors));
+                files.push(("debts.json".to_string(), debts));
+                files.push(("counter_divergence.json".to_string(), divergence));
+                files.push(("watchdog.json".to_string(), watchdog));
+                files.push(("traffic_alerts.json".to_string(), alerts));
+
+                let bundle = build_bundle(&files, now)?;
+                Ok(HttpResponse::Ok()
+                    .content_type("application/gzip")
+                    .header(
+                        CONTENT_DISPOSITION,
+                        format!("attachment; filename=\"diagnostics-{}.tar.gz\"", now),
+                    )
+                    .body(bundle))
+            },
+        )
+        .responder()
+}
+
+#[test]
+fn test_diagnostics_bundle() {
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use tar::Archive;
+
+    let mut settings = json!({
+        "network": {
+            "wg_private_key": "secret",
+            "wg_private_key_path": "/tmp/priv",
+            "wg_public_key": "public",
+            "rita_dashboard_password": null,
+        },
+        "exit_client": {
+            "exits": [{"auth_api_key": "secret", "signup_passphrase": "secret", "nickname": "exit"}],
+        },
+        "exit_network": {
+            "signup_challenge": {"Passphrase": {"prompt": "Ask us", "passphrase": "secret"}},
+        },
+        "payment": {"eth_private_key": "secret"},
+    });
+    redact(&mut settings);
+    assert_eq!(settings["network"]["wg_private_key"], REDACTED);
+    assert_eq!(settings["network"]["wg_private_key_path"], "/tmp/priv");
+    assert_eq!(settings["network"]["wg_public_key"], "public");
+    assert!(settings["network"]["rita_dashboard_password"].is_null());
+    assert_eq!(
+        settings["exit_client"]["exits"][0]["auth_api_key"],
+        REDACTED
+    );
+    assert_eq!(
+        settings["exit_client"]["exits"][0]["signup_passphrase"],
+        REDACTED
+    );
+    assert_eq!(settings["exit_client"]["exits"][0]["nickname"], "exit");
+    assert_eq!(
+        settings["exit_network"]["signup_challenge"]["Passphrase"]["passphrase"],
+        REDACTED
+    );
+    assert_eq!(
+        settings["exit_network"]["signup_challenge"]["Passphrase"]["prompt"],
+        "Ask us"
+    );
+    assert_eq!(settings["payment"]["eth_private_key"], REDACTED);
+
+    let files = vec![
+        ("settings.json".to_string(), json_file(Ok(settings))),
+        ("ip_addr.txt".to_string(), b"1: lo".to_vec()),
+    ];
+    let bundle = build_bundle(&files, 1_000_000).unwrap();
+    let mut archive = Archive::new(GzDecoder::new(bundle.as_slice()));
+    let mut unpacked = Vec::new();
+    for entry in archive.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let path = entry.path().unwrap().to_string_lossy().to_string();
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents).unwrap();
+        unpacked.push((path, contents));
+    }
+    assert_eq!(unpacked.len(), 2);
+    assert_eq!(unpacked[0].0, "diagnostics/settings.json");
+    assert!(!String::from_utf8_lossy(&unpacked[0].1).contains("secret"));
+    assert_eq!(
+        unpacked[1],
+        ("diagnostics/ip_addr.txt".to_string(), b"1: lo".to_vec())
+    );
+}
@@ -0,0 +1,223 @@
+//! The error responses of the dashboard. Every error an endpoint returns, whether it's a
+//! `DashboardError` or any other error bubbling up, is sent by the `ErrorResponses` middleware as
+//! an `ErrorResponse` so that frontends can branch on `code` instead of matching on messages.
+//! Codes are part of the API, once added they must never be renamed or reused.
+
+use actix::MailboxError;
+use actix_web::error::{JsonPayloadError, PayloadError, UrlencodedError};
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+
+/// What the caller can do about an error
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    /// the request itself is wrong and needs to be changed
+    Request,
+    /// the thing the request refers to doesn't exist
+    NotFound,
+    /// the dashboard password is needed
+    Auth,
+    /// this router isn't ready for the request yet
+    Unavailable,
+    /// something this router depends on, like an exit or the blockchain, failed
+    Upstream,
+    /// a bug or a problem on the router itself
+    Internal,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// a value in the request failed validation
+    InvalidInput,
+    /// the body couldn't be parsed
+    MalformedRequest,
+    InsufficientBalance,
+    /// the exit list the request pointed to couldn't be parsed or its signature doesn't verify,
+    /// fetching it again won't change that
+    InvalidExitList,
+    /// not something this device can do, usually because it isn't running OpenWRT
+    Unsupported,
+    Unauthorized,
+    NotFound,
+    UnknownExit,
+    /// the setting or state the request needs isn't set up yet
+    NotReady,
+    /// an internal component is restarting or overloaded
+    ActorUnavailable,
+    /// a request to an exit failed
+    ExitRequestFailed,
+    /// a request to a full node or the token bridge failed
+    BlockchainFailed,
+    /// babel didn't take a change
+    BabelFailed,
+    /// a request to anything else off the router failed
+    UpstreamFailed,
+    /// the router's settings don't allow this request
+    Misconfigured,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn category(self) -> ErrorCategory {
+        match self {
+            ErrorCode::InvalidInput
+            | ErrorCode::MalformedRequest
+            | ErrorCode::InsufficientBalance
+            | ErrorCode::InvalidExitList
+            | ErrorCode::Unsupported => ErrorCategory::Request,
+            ErrorCode::Unauthorized => ErrorCategory::Auth,
+            ErrorCode::NotFound | ErrorCode::UnknownExit => ErrorCategory::NotFound,
+            ErrorCode::NotReady | ErrorCode::ActorUnavailable | ErrorCode::BabelFailed => {
+                ErrorCategory::Unavailable
+            }
+            ErrorCode::ExitRequestFailed
+            | ErrorCode::BlockchainFailed
+            | ErrorCode::UpstreamFailed => ErrorCategory::Upstream,
+            ErrorCode::Misconfigured | ErrorCode::Internal => ErrorCategory::Internal,
+        }
+    }
+
+    /// If the same request might succeed when tried again later
+    pub fn retryable(self) -> bool {
+        match self.category() {
+            ErrorCategory::Unavailable | ErrorCategory::Upstream => true,
+            _ => false,
+        }
+    }
+
+    pub fn status(self) -> StatusCode {
+        match self.category() {
+            ErrorCategory::Request => StatusCode::BAD_REQUEST,
+            ErrorCategory::NotFound => StatusCode::NOT_FOUND,
+            ErrorCategory::Auth => StatusCode::UNAUTHORIZED,
+            ErrorCategory::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCategory::Upstream => StatusCode::BAD_GATEWAY,
+            ErrorCategory::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// For errors that only come with a status, like those of actix's extractors
+    fn from_status(status: StatusCode) -> ErrorCode {
+        match status {
+            StatusCode::BAD_REQUEST | StatusCode::PAYLOAD_TOO_LARGE => ErrorCode::MalformedRequest,
+            StatusCode::UNAUTHORIZED => ErrorCode::Unauthorized,
+            StatusCode::NOT_FOUND => ErrorCode::NotFound,
+            StatusCode::SERVICE_UNAVAILABLE => ErrorCode::ActorUnavailable,
+            StatusCode::BAD_GATEWAY => ErrorCode::UpstreamFailed,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+#[derive(Debug, Fail, Clone, PartialEq)]
+#[fail(display = "{}", message)]
+pub struct DashboardError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl DashboardError {
+    pub fn new<S: Into<String>>(code: ErrorCode, message: S) -> DashboardError {
+        DashboardError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn invalid_input<S: Into<String>>(message: S) -> DashboardError {
+        DashboardError::new(ErrorCode::InvalidInput, message)
+    }
+
+    pub fn not_ready<S: Into<String>>(message: S) -> DashboardError {
+        DashboardError::new(ErrorCode::NotReady, message)
+    }
+
+    pub fn not_openwrt() -> DashboardError {
+        DashboardError::new(ErrorCode::Unsupported, "Not an OpenWRT device")
+    }
+
+    pub fn unknown_exit(exit: &str) -> DashboardError {
+        DashboardError::new(ErrorCode::UnknownExit, format!("Unknown exit {:?}", exit))
+    }
+
+    /// Classifies any error an endpoint returned, `status` is what actix would have sent
+    pub fn from_actix(err: &actix_web::Error, status: StatusCode) -> DashboardError {
+        if let Some(e) = err.downcast_ref::<DashboardError>() {
+            return e.clone();
+        }
+        let code = if err.downcast_ref::<MailboxError>().is_some() {
+            ErrorCode::ActorUnavailable
+        } else if err.downcast_ref::<JsonPayloadError>().is_some()
+            || err.downcast_ref::<UrlencodedError>().is_some()
+            || err.downcast_ref::<PayloadError>().is_some()
+        {
+            ErrorCode::MalformedRequest
+        } else {
+            ErrorCode::from_status(status)
+        };
+        DashboardError::new(code, err.to_string())
+    }
+
+    pub fn to_response(&self) -> ErrorResponse {
+        ErrorResponse {
+            error: self.message.clone(),
+            code: self.code,
+            category: self.code.category(),
+            retryable: self.code.retryable(),
+        }
+    }
+}
+
+impl ResponseError for DashboardError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.code.status()).json(self.to_response())
+    }
+}
+
+/// The body of every error response from the dashboard
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ErrorResponse {
+    /// human readable, not meant to be matched on
+    pub error: String,
+    pub code: ErrorCode,
+    pub category: ErrorCategory,
+    pub retryable: bool,
+}
+
+#[test]
+fn test_error_response() {
+    let err = DashboardError::unknown_exit("exit_a");
+    assert_eq!(err.code.status(), StatusCode::NOT_FOUND);
+    assert_eq!(
+        serde_json::to_value(err.to_response()).unwrap(),
+        json!({
+            "error": "Unknown exit \"exit_a\"",
+            "code": "unknown_exit",
+            "category": "not_found",
+            "retryable": false
+        })
+    );
+
+    // errors from elsewhere keep their code through failure and actix
+    let failure: failure::Error = DashboardError::not_ready("No mesh ip yet").into();
+    let actix: actix_web::Error = failure.into();
+    let err = DashboardError::from_actix(&actix, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(err.code, ErrorCode::NotReady);
+    assert!(err.code.retryable());
+    assert_eq!(err.code.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let failure: failure::Error = MailboxError::Timeout.into();
+    let err = DashboardError::from_actix(&failure.into(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(err.code, ErrorCode::ActorUnavailable);
+
+    let failure = format_err!("Something broke");
+    let err = DashboardError::from_actix(&failure.into(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(err.code, ErrorCode::Internal);
+    assert_eq!(err.message, "Something broke");
+    assert!(!err.code.retryable());
+
+    assert_eq!(ErrorCode::InvalidExitList.status(), StatusCode::BAD_REQUEST);
+    assert!(!ErrorCode::InvalidExitList.retryable());
+}
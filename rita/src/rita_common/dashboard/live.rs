@@ -0,0 +1,40 @@
+//! The websocket end of live updates, each connection is a session that's handed the updates
+//! published through the `LiveUpdates` actor. Sessions don't expect anything from the dashboard
+//! beyond pings and closing.
+
+use crate::rita_common::live_updates::{LiveUpdates, Push, Subscribe};
+use ::actix::{Actor, ActorContext, AsyncContext, Handler, StreamHandler, SystemService};
+use ::actix_web::{ws, Error, HttpRequest, HttpResponse};
+
+pub struct LiveSession;
+
+impl Actor for LiveSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        LiveUpdates::from_registry().do_send(Subscribe(ctx.address().recipient()));
+    }
+}
+
+impl StreamHandler<ws::Message, ws::ProtocolError> for LiveSession {
+    fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
+        match msg {
+            ws::Message::Ping(msg) => ctx.pong(&msg),
+            ws::Message::Close(_) => ctx.stop(),
+            _ => {}
+        }
+    }
+}
+
+impl Handler<Push> for LiveSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Push, ctx: &mut Self::Context) -> Self::Result {
+        ctx.text(msg.0);
+    }
+}
+
+pub fn live_updates(req: HttpRequest) -> Result<HttpResponse, Error> {
+    debug!("/live hit");
+    ws::start(&req, LiveSession)
+}
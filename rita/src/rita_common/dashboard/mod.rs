@@ -7,15 +7,30 @@ use actix::registry::SystemService;
 
 pub mod auth;
 pub mod babel;
+pub mod billing_audit;
+pub mod channels;
 pub mod dao;
 pub mod debts;
 pub mod development;
+pub mod diagnostics;
+pub mod error;
+pub mod live;
 pub mod nickname;
 pub mod own_info;
+pub mod path_trace;
+pub mod price_simulation;
+pub mod schedule;
+pub mod security;
+pub mod services;
 pub mod settings;
+pub mod sla;
+pub mod system_health;
 pub mod token_bridge;
+pub mod traffic_alerts;
+pub mod tunnels;
 pub mod usage;
 pub mod wallet;
+pub mod watchdog;
 pub mod wg_key;
 
 pub struct Dashboard;
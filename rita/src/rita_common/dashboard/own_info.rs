@@ -1,8 +1,13 @@
+use crate::rita_common::currency::get_exchange_rate;
+use crate::rita_common::currency::FiatAmount;
 use crate::rita_common::oracle::low_balance;
+use crate::rita_common::usage_tracker::{GetFees, UsageTracker};
 use crate::SETTING;
-use actix_web::{HttpRequest, Json};
+use actix::SystemService;
+use actix_web::{AsyncResponder, HttpRequest, Json};
 use clarity::Address;
 use failure::Error;
+use futures01::Future;
 use num256::{Int256, Uint256};
 use settings::RitaCommonSettings;
 
@@ -12,6 +17,9 @@ pub static READABLE_VERSION: &str = "Beta 12 RC1";
 pub struct OwnInfo {
     pub address: Address,
     pub balance: Uint256,
+    /// the balance in the display currency, only present if fiat display is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance_fiat: Option<FiatAmount>,
     pub local_fee: u32,
     pub metric_factor: u32,
     pub pay_threshold: Int256,
@@ -21,10 +29,16 @@ pub struct OwnInfo {
     pub rita_version: String,
     pub version: String,
     pub is_gateway: bool,
+    /// our WAN only has IPv6, IPv4 goes through 464XLAT if it's enabled
+    pub ipv6_only_wan: bool,
     pub client_can_use_free_tier: bool,
+    /// wei spent on transaction fees settling payments over the whole usage history
+    pub fees_paid: Uint256,
+    /// the number of on chain payments those fees were for
+    pub settlements: u64,
 }
 
-pub fn get_own_info(_req: HttpRequest) -> Result<Json<OwnInfo>, Error> {
+pub fn get_own_info(_req: HttpRequest) -> Box<dyn Future<Item = Json<OwnInfo>, Error = Error>> {
     debug!("Get own info endpoint hit!");
     let payment_settings = SETTING.get_payment();
     let eth_address = payment_settings.eth_address.unwrap();
@@ -38,9 +52,11 @@ pub fn get_own_info(_req: HttpRequest) -> Result<Json<OwnInfo>, Error> {
     let metric_factor = network_settings.metric_factor;
     let device = network_settings.device.clone();
     let is_gateway = network_settings.is_gateway;
+    let ipv6_only_wan = network_settings.ipv6_only_wan;
 
-    let reply = OwnInfo {
+    let mut reply = OwnInfo {
         address: eth_address,
+        balance_fiat: None,
         balance,
         local_fee,
         metric_factor,
@@ -51,7 +67,23 @@ pub fn get_own_info(_req: HttpRequest) -> Result<Json<OwnInfo>, Error> {
         rita_version: env!("CARGO_PKG_VERSION").to_string(),
         version: READABLE_VERSION.to_string(),
         is_gateway,
+        ipv6_only_wan,
         client_can_use_free_tier,
+        fees_paid: 0u32.into(),
+        settlements: 0,
     };
-    Ok(Json(reply))
+    drop(payment_settings);
+    drop(network_settings);
+
+    let fees = UsageTracker::from_registry().send(GetFees).from_err();
+    get_exchange_rate()
+        .join(fees)
+        .and_then(move |(rate, fees)| {
+            reply.balance_fiat = rate.map(|r| r.convert_uint(&reply.balance));
+            let (totals, _) = fees?;
+            reply.fees_paid = totals.fees;
+            reply.settlements = totals.settlements;
+            Ok(Json(reply))
+        })
+        .responder()
 }
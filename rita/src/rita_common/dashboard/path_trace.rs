@@ -0,0 +1,216 @@
+//! Traces the path our traffic to a mesh destination takes, so users can see which relays it
+//! crosses and what each of them charges. The trace is a traceroute run one hop at a time on a
+//! thread of its own, each hop is enriched with what babel knows about it and streamed back as
+//! a line of json as soon as it's known. The trace stops at the destination or once it leaves
+//! the mesh, hops outside of it have no identity or price to show.
+
+use crate::rita_common::dashboard::error::{DashboardError, ErrorCode};
+use crate::rita_common::route_cache::get_routes;
+use crate::rita_common::tunnel_manager::{GetTunnels, TunnelManager};
+use crate::KI;
+use ::actix::SystemService;
+use ::actix_web::{error, HttpResponse, Path};
+use althea_types::Identity;
+use babel_monitor::{get_installed_route, Route};
+use bytes::Bytes;
+use failure::Error;
+use futures01::sync::mpsc;
+use futures01::{future, Future, Stream};
+use std::boxed::Box;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::thread;
+use std::time::Duration;
+
+/// No mesh path we'd want to trace is longer than this
+const MAX_HOPS: u8 = 16;
+/// How long to wait for each hop to answer
+const HOP_TIMEOUT: Duration = Duration::from_secs(1);
+/// The trace is given up once this many hops in a row don't answer
+const MAX_SILENT_HOPS: u8 = 3;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PathHop {
+    pub ttl: u8,
+    /// None if the hop didn't answer
+    pub ip: Option<IpAddr>,
+    pub rtt_ms: Option<f32>,
+    /// only known for hops that are our neighbors
+    pub identity: Option<Identity>,
+    /// the price babel advertises to reach this hop, what all of the relays before it charge
+    pub route_price: Option<u32>,
+    /// the part of `route_price` charged since the last hop that answered, the fee of the
+    /// relay in between
+    pub hop_price: Option<u32>,
+    pub metric: Option<u16>,
+}
+
+impl PathHop {
+    fn in_mesh(&self) -> bool {
+        self.route_price.is_some() || self.identity.is_some()
+    }
+}
+
+/// What we know about the address that answered the probe with this ttl, `last_price` is the
+/// route price of the last hop that answered
+fn describe_hop(
+    ttl: u8,
+    answer: Option<(IpAddr, f32)>,
+    routes: &[Route],
+    neighbors: &HashMap<IpAddr, Identity>,
+    last_price: &mut u32,
+) -> PathHop {
+    let (ip, rtt_ms) = match answer {
+        Some((ip, rtt_ms)) => (ip, rtt_ms),
+        None => {
+            return PathHop {
+                ttl,
+                ip: None,
+                rtt_ms: None,
+                identity: None,
+                route_price: None,
+                hop_price: None,
+                metric: None,
+            }
+        }
+    };
+    let route = get_installed_route(&ip, routes).ok();
+    let route_price = route.as_ref().map(|route| route.price);
+    let hop_price = route_price.map(|price| price.saturating_sub(*last_price));
+    if let Some(price) = route_price {
+        *last_price = price;
+    }
+    PathHop {
+        ttl,
+        ip: Some(ip),
+        rtt_ms: Some(rtt_ms),
+        identity: neighbors.get(&ip).cloned(),
+        route_price,
+        hop_price,
+        metric: route.map(|route| route.metric),
+    }
+}
+
+/// Runs the trace, sending each hop as it's known until the destination is reached, the path
+/// leaves the mesh or the receiver goes away
+fn run_trace(
+    dest: IpAddr,
+    routes: Vec<Route>,
+    neighbors: HashMap<IpAddr, Identity>,
+    hops: mpsc::UnboundedSender<Bytes>,
+) {
+    let mut last_price = 0;
+    let mut silent = 0;
+    for ttl in 1..=MAX_HOPS {
+        let answer = match KI.trace_hop(&dest, ttl, HOP_TIMEOUT) {
+            Ok(answer) => answer,
+            Err(e) => {
+                warn!("Path trace to {} failed with {:?}", dest, e);
+                return;
+            }
+        };
+        let hop = describe_hop(ttl, answer, &routes, &neighbors, &mut last_price);
+        if hop.ip.is_some() && !hop.in_mesh() {
+            trace!("Path trace to {} left the mesh at {:?}", dest, hop.ip);
+            return;
+        }
+        silent = if hop.ip.is_none() { silent + 1 } else { 0 };
+        let done = hop.ip == Some(dest) || silent >= MAX_SILENT_HOPS;
+
+        let mut line = match serde_json::to_vec(&hop) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize path hop {:?}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        if hops.unbounded_send(Bytes::from(line)).is_err() || done {
+            return;
+        }
+    }
+}
+
+/// Streams the hops to `dest_ip` as newline separated json objects
+pub fn path_trace(path: Path<String>) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let dest_ip = path.into_inner();
+    debug!("/path_trace/{} hit", dest_ip);
+    let dest: IpAddr = match dest_ip.parse() {
+        Ok(dest) => dest,
+        Err(_) => {
+            return Box::new(future::err(
+                DashboardError::invalid_input(format!("{} is not an ip address", dest_ip)).into(),
+            ));
+        }
+    };
+    let routes = get_routes()
+        .map(|cached| cached.routes)
+        .map_err(|e: Error| -> Error {
+            DashboardError::new(ErrorCode::BabelFailed, format!("{}", e)).into()
+        });
+    let tunnels = TunnelManager::from_registry().send(GetTunnels).from_err();
+
+    Box::new(routes.join(tunnels).and_then(move |(routes, tunnels)| {
+        let neighbors: HashMap<IpAddr, Identity> = tunnels?
+            .into_iter()
+            .map(|tunnel| (tunnel.neigh_id.global.mesh_ip, tunnel.neigh_id.global))
+            .collect();
+        if get_installed_route(&dest, &routes).is_err() && !neighbors.contains_key(&dest) {
+            return Err(DashboardError::new(
+                ErrorCode::NotFound,
+                format!("No mesh route to {}", dest),
+            )
+            .into());
+        }
+
+        let (sender, receiver) = mpsc::unbounded();
+        thread::spawn(move || run_trace(dest, routes, neighbors, sender));
+        Ok(HttpResponse::Ok()
+            .content_type("application/x-ndjson")
+            .streaming(receiver.map_err(|_| error::ErrorInternalServerError("Path trace failed"))))
+    }))
+}
+
+#[test]
+fn test_describe_hop() {
+    use crate::rita_common::utils::test_identity;
+    use ipnetwork::IpNetwork;
+
+    let route = |ip: &str, price: u32| Route {
+        id: String::new(),
+        iface: "wg0".to_string(),
+        xroute: false,
+        installed: true,
+        neigh_ip: "fe80::1".parse().unwrap(),
+        prefix: IpNetwork::new(ip.parse().unwrap(), 128).unwrap(),
+        metric: 96,
+        refmetric: 0,
+        full_path_rtt: 0.0,
+        price,
+        fee: 0,
+    };
+    let routes = vec![
+        route("fd00::1", 0),
+        route("fd00::2", 10),
+        route("fd00::3", 25),
+    ];
+    let neighbor = test_identity("fd00::1");
+    let mut neighbors = HashMap::new();
+    neighbors.insert(neighbor.mesh_ip, neighbor);
+    let mut last_price = 0;
+    let mut hop = |ttl, ip: Option<&str>| {
+        let answer = ip.map(|ip| (ip.parse().unwrap(), 1.0));
+        describe_hop(ttl, answer, &routes, &neighbors, &mut last_price)
+    };
+
+    let first = hop(1, Some("fd00::1"));
+    assert_eq!(first.identity, Some(neighbor));
+    assert_eq!(first.hop_price, Some(0));
+    assert_eq!(hop(2, Some("fd00::2")).hop_price, Some(10));
+    // a silent hop's fee shows up in the next one that answers
+    assert!(!hop(3, None).in_mesh());
+    let last = hop(4, Some("fd00::3"));
+    assert_eq!((last.route_price, last.hop_price), (Some(25), Some(15)));
+    // out on the internet
+    assert!(!hop(5, Some("2001:db8::1")).in_mesh());
+}
@@ -0,0 +1,192 @@
+//! What changing `local_fee` or the free tier would have done to the last few days of relay
+//! earnings. Relay usage is replayed hour by hour under both the configuration in effect and the
+//! hypothetical one. The free tier is an allowance per neighbor, so each neighbor's traffic in an
+//! hour up to what the free tier allows is counted as unpaid, since we can't tell after the fact
+//! which neighbors were overdue.
+//!
+//! Only what the same traffic would have paid changes, a higher fee would also send some traffic
+//! around us but that depends on routes our neighbors see and we don't, so no attempt is made to
+//! estimate it.
+
+use crate::rita_common::dashboard::error::DashboardError;
+use crate::rita_common::schedule;
+use crate::rita_common::usage_tracker::{
+    GetNeighborUsage, NeighborUsageHour, UsageTracker, HOURLY_RETENTION,
+};
+use crate::rita_common::utils::now_secs;
+use ::actix::SystemService;
+use ::actix_web::{AsyncResponder, Json, Query};
+use althea_types::Wei;
+use failure::Error;
+use futures01::Future;
+use std::boxed::Box;
+
+const DEFAULT_DAYS: u64 = 7;
+
+#[derive(Deserialize)]
+pub struct PriceSimulationQuery {
+    /// how many days of history to replay, at most the 30 days hourly usage is kept for
+    pub days: Option<u64>,
+    /// the fee to simulate in wei per byte
+    pub local_fee: u32,
+    /// the free tier to simulate in kbit/s, the current one if not given
+    pub free_tier_throughput: Option<u32>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SimulatedEarnings {
+    pub local_fee: u32,
+    pub free_tier_throughput: u32,
+    /// relayed bytes that would have been paid for
+    pub billable_bytes: u64,
+    pub earnings: Wei,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PriceSimulation {
+    pub days: u64,
+    /// hours of relay usage replayed, less than `days` worth if we don't have that much history
+    pub hours: u64,
+    pub relayed_bytes: u64,
+    /// the configuration in effect at the time
+    pub current: SimulatedEarnings,
+    pub simulated: SimulatedEarnings,
+}
+
+/// The bytes the free tier lets through in an hour, `throughput` is in kbit/s
+fn free_tier_bytes_per_hour(throughput: u32) -> u64 {
+    u64::from(throughput) * 1000 / 8 * 60 * 60
+}
+
+/// What's left to bill in an hour once each neighbor's free tier is taken out
+fn billable_bytes(hour: &NeighborUsageHour, free_tier: u32) -> u64 {
+    let free = free_tier_bytes_per_hour(free_tier);
+    hour.down
+        .values()
+        .map(|bytes| bytes.saturating_sub(free))
+        .sum()
+}
+
+/// Replays `usage` (hourly relay usage by neighbor, newest first) from `now_hour` back `days` days
+pub fn simulate_prices(
+    usage: &[NeighborUsageHour],
+    now_hour: u64,
+    days: u64,
+    current_free_tier: u32,
+    fee: u32,
+    free_tier: u32,
+) -> PriceSimulation {
+    let current_fee = usage.first().map_or(0, |hour| hour.price);
+    let mut simulation = PriceSimulation {
+        days,
+        hours: 0,
+        relayed_bytes: 0,
+        current: SimulatedEarnings {
+            local_fee: current_fee,
+            free_tier_throughput: current_free_tier,
+            billable_bytes: 0,
+            earnings: Wei::zero(),
+        },
+        simulated: SimulatedEarnings {
+            local_fee: fee,
+            free_tier_throughput: free_tier,
+            billable_bytes: 0,
+            earnings: Wei::zero(),
+        },
+    };
+    let start = now_hour.saturating_sub(days * 24);
+    for hour in usage.iter().filter(|hour| hour.index > start) {
+        // every relayed byte is counted once coming in and once going out, we're paid for it
+        // on the way in
+        let current = billable_bytes(hour, current_free_tier);
+        let simulated = billable_bytes(hour, free_tier);
+
+        simulation.hours += 1;
+        simulation.relayed_bytes += hour.down.values().sum::<u64>();
+        simulation.current.billable_bytes += current;
+        simulation.current.earnings = simulation
+            .current
+            .earnings
+            .saturating_add(&Wei::from_price(u64::from(hour.price), current));
+        simulation.simulated.billable_bytes += simulated;
+        simulation.simulated.earnings = simulation
+            .simulated
+            .earnings
+            .saturating_add(&Wei::from_price(u64::from(fee), simulated));
+    }
+    simulation
+}
+
+pub fn get_price_simulation(
+    query: Query<PriceSimulationQuery>,
+) -> Box<dyn Future<Item = Json<PriceSimulation>, Error = Error>> {
+    debug!("/price_simulation hit");
+    let days = query.days.unwrap_or(DEFAULT_DAYS);
+    if days == 0 || days > HOURLY_RETENTION / 24 {
+        return Box::new(futures01::future::err(
+            DashboardError::invalid_input(format!(
+                "days must be between 1 and {}",
+                HOURLY_RETENTION / 24
+            ))
+            .into(),
+        ));
+    }
+    let fee = query.local_fee;
+    let current_free_tier = schedule::free_tier_throughput();
+    let free_tier = query.free_tier_throughput.unwrap_or(current_free_tier);
+    let now_hour = now_secs() / (60 * 60);
+
+    UsageTracker::from_registry()
+        .send(GetNeighborUsage)
+        .from_err()
+        .and_then(move |usage| {
+            let usage: Vec<NeighborUsageHour> = usage?.into_iter().collect();
+            Ok(Json(simulate_prices(
+                &usage,
+                now_hour,
+                days,
+                current_free_tier,
+                fee,
+                free_tier,
+            )))
+        })
+        .responder()
+}
+
+#[test]
+fn test_simulate_prices() {
+    let free_tier_bytes = free_tier_bytes_per_hour(1);
+    let a = "fd00::a".parse().unwrap();
+    let b = "fd00::b".parse().unwrap();
+    let hour = |index: u64, down: Vec<(std::net::IpAddr, u64)>| NeighborUsageHour {
+        index,
+        price: 10,
+        down: down.into_iter().collect(),
+    };
+    let usage = vec![
+        // each neighbor gets its own free tier
+        hour(100, vec![(a, free_tier_bytes + 4000), (b, free_tier_bytes)]),
+        hour(99, vec![(a, 4000)]),
+        // too old
+        hour(50, vec![(a, 1_000_000)]),
+    ];
+
+    let simulation = simulate_prices(&usage, 100, 1, 1, 100, 0);
+    assert_eq!(simulation.hours, 2);
+    assert_eq!(simulation.relayed_bytes, 2 * free_tier_bytes + 8000);
+    assert_eq!(simulation.current.billable_bytes, 4000);
+    assert_eq!(simulation.current.earnings, Wei::from_price(10, 4000));
+    assert_eq!(
+        simulation.simulated.billable_bytes,
+        2 * free_tier_bytes + 8000
+    );
+    assert_eq!(
+        simulation.simulated.earnings,
+        Wei::from_price(100, 2 * free_tier_bytes + 8000)
+    );
+
+    // a bigger free tier covers all of it
+    let simulation = simulate_prices(&usage, 100, 1, 1, 100, 2);
+    assert_eq!(simulation.simulated.billable_bytes, 0);
+    assert_eq!(simulation.simulated.earnings, Wei::zero());
+}
@@ -0,0 +1,45 @@
+use crate::rita_common::dashboard::error::DashboardError;
+use crate::rita_common::schedule;
+use crate::ARGS;
+use crate::SETTING;
+use ::actix_web::{HttpRequest, HttpResponse, Json};
+use failure::Error;
+use settings::payment::{ScheduleSettings, ScheduleWindow};
+use settings::FileWrite;
+use settings::RitaCommonSettings;
+
+#[derive(Serialize)]
+pub struct ScheduleStatus {
+    pub schedule: ScheduleSettings,
+    /// the window in effect right now, if any
+    pub active_window: Option<ScheduleWindow>,
+    /// the values in effect right now, taking the active window into account
+    pub local_fee: u32,
+    pub free_tier_throughput: u32,
+}
+
+pub fn get_schedule(_req: HttpRequest) -> Result<Json<ScheduleStatus>, Error> {
+    debug!("Get schedule hit!");
+    Ok(Json(ScheduleStatus {
+        schedule: SETTING.get_payment().schedule.clone(),
+        active_window: schedule::current_window(),
+        local_fee: schedule::local_fee(),
+        free_tier_throughput: schedule::free_tier_throughput(),
+    }))
+}
+
+/// Replaces the schedule, babel and tunnel shaping pick up the change on the next slow loop tick
+pub fn set_schedule(new_schedule: Json<ScheduleSettings>) -> Result<HttpResponse, Error> {
+    debug!("Set schedule hit!");
+    let new_schedule = new_schedule.into_inner();
+    if let Err(e) = schedule::validate_schedule(&new_schedule) {
+        return Err(DashboardError::invalid_input(e.to_string()).into());
+    }
+    SETTING.get_payment_mut().schedule = new_schedule;
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+    Ok(HttpResponse::Ok().json(()))
+}
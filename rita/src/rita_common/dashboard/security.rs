@@ -0,0 +1,153 @@
+//! Whether our traffic is actually protected. WireGuard speaks a single cipher suite so a tunnel
+//! is encrypting as long as it has session keys, which it throws away three minutes after the
+//! handshake that made them. Beyond the tunnels, a mesh port that's also a member of a bridge puts
+//! whatever is on that bridge onto the mesh in the clear, and the dashboard listens on every
+//! address so only the firewall keeps it off the WAN and the mesh.
+
+use crate::rita_common::tunnel_manager::{GetTunnels, TunnelManager};
+use crate::KI;
+use crate::SETTING;
+use ::actix::SystemService;
+use ::actix_web::{AsyncResponder, HttpRequest, Json};
+use althea_types::Identity;
+use failure::Error;
+use futures01::Future;
+use settings::RitaCommonSettings;
+use std::boxed::Box;
+use std::time::{Duration, SystemTime};
+
+/// The only suite WireGuard has
+pub const WG_CIPHER_SUITE: &str = "Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s";
+/// WireGuard stops using session keys this long after the handshake that made them
+const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TunnelSecurity {
+    pub iface: String,
+    pub neighbor: Identity,
+    /// seconds since the last completed handshake, None if there's never been one
+    pub handshake_age: Option<u64>,
+    pub cipher_suite: &'static str,
+    /// if the tunnel has live session keys, it can't carry any traffic without them
+    pub encrypting: bool,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct BridgedMeshPort {
+    pub iface: String,
+    pub bridge: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SecurityStatus {
+    pub tunnels: Vec<TunnelSecurity>,
+    /// mesh ports that are also in a bridge, whatever is on the bridge goes out them unencrypted
+    pub bridged_mesh_ports: Vec<BridgedMeshPort>,
+    /// firewall zones other than the LAN that can reach the dashboard, None where we can't tell
+    pub dashboard_exposed_zones: Option<Vec<String>>,
+}
+
+/// The handshake's age in seconds and whether its keys are still in use
+pub fn handshake_status(handshake: Option<SystemTime>, now: SystemTime) -> (Option<u64>, bool) {
+    match handshake {
+        Some(handshake) => {
+            let age = now.duration_since(handshake).unwrap_or_default();
+            (Some(age.as_secs()), age < REJECT_AFTER_TIME)
+        }
+        None => (None, false),
+    }
+}
+
+fn bridged_mesh_ports() -> Vec<BridgedMeshPort> {
+    let mut ifaces: Vec<String> = SETTING
+        .get_network()
+        .peer_interfaces
+        .iter()
+        .cloned()
+        .collect();
+    ifaces.sort();
+    let mut bridged = Vec::new();
+    for iface in ifaces {
+        match KI.get_bridge_master(&iface) {
+            Ok(Some(bridge)) => bridged.push(BridgedMeshPort { iface, bridge }),
+            Ok(None) => {}
+            Err(e) => trace!("Can't check mesh port {} for a bridge {:?}", iface, e),
+        }
+    }
+    bridged
+}
+
+fn dashboard_exposed_zones() -> Option<Vec<String>> {
+    if !KI.is_openwrt() {
+        return None;
+    }
+    let port = SETTING.get_network().rita_dashboard_port;
+    match KI.get_zones_accepting_port(port) {
+        Ok(zones) => Some(zones.into_iter().filter(|zone| zone != "lan").collect()),
+        Err(e) => {
+            warn!("Failed to read the firewall config {:?}", e);
+            None
+        }
+    }
+}
+
+pub fn get_security_status(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<SecurityStatus>, Error = Error>> {
+    debug!("/security_status hit");
+    TunnelManager::from_registry()
+        .send(GetTunnels)
+        .from_err()
+        .and_then(|tunnels| {
+            let now = SystemTime::now();
+            let mut report = Vec::new();
+            for tunnel in tunnels? {
+                let key = tunnel.neigh_id.global.wg_public_key;
+                let handshake = match KI.get_last_handshakes(&tunnel.iface_name) {
+                    Ok(handshakes) => handshakes
+                        .into_iter()
+                        .find(|(peer, _)| *peer == key)
+                        .and_then(|(_, time)| time),
+                    Err(e) => {
+                        warn!("Failed to get handshakes for {} {:?}", tunnel.iface_name, e);
+                        None
+                    }
+                };
+                let (handshake_age, encrypting) = handshake_status(handshake, now);
+                report.push(TunnelSecurity {
+                    iface: tunnel.iface_name,
+                    neighbor: tunnel.neigh_id.global,
+                    handshake_age,
+                    cipher_suite: WG_CIPHER_SUITE,
+                    encrypting,
+                });
+            }
+            report.sort_by(|a, b| a.iface.cmp(&b.iface));
+
+            Ok(Json(SecurityStatus {
+                tunnels: report,
+                bridged_mesh_ports: bridged_mesh_ports(),
+                dashboard_exposed_zones: dashboard_exposed_zones(),
+            }))
+        })
+        .responder()
+}
+
+#[test]
+fn test_handshake_status() {
+    let now = SystemTime::now();
+    assert_eq!(
+        handshake_status(Some(now - Duration::from_secs(30)), now),
+        (Some(30), true)
+    );
+    assert_eq!(
+        handshake_status(Some(now - Duration::from_secs(180)), now),
+        (Some(180), false)
+    );
+    assert_eq!(handshake_status(None, now), (None, false));
+    // clock skew doesn't make a handshake look older
+    assert_eq!(
+        handshake_status(Some(now + Duration::from_secs(5)), now),
+        (Some(0), true)
+    );
+}
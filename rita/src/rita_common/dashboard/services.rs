@@ -0,0 +1,69 @@
+use crate::rita_common::dashboard::error::DashboardError;
+use crate::rita_common::service_registry::{validate_service, GetServices, ServiceRegistry};
+use crate::ARGS;
+use crate::SETTING;
+use ::actix::SystemService;
+use ::actix_web::{AsyncResponder, HttpRequest, HttpResponse, Json, Path};
+use althea_types::MeshService;
+use failure::Error;
+use futures01::Future;
+use settings::network::{LocalService, ServiceDiscoverySettings};
+use settings::FileWrite;
+use settings::RitaCommonSettings;
+use std::boxed::Box;
+use std::collections::HashSet;
+
+/// Every service on the mesh we know of, our own first
+pub fn get_services(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<Vec<MeshService>>, Error = Error>> {
+    debug!("Get services hit!");
+    ServiceRegistry::from_registry()
+        .send(GetServices)
+        .from_err()
+        .and_then(|reply| Ok(Json(reply?)))
+        .responder()
+}
+
+pub fn get_local_services(_req: HttpRequest) -> Result<Json<ServiceDiscoverySettings>, Error> {
+    debug!("Get local services hit!");
+    Ok(Json(SETTING.get_network().service_discovery.clone()))
+}
+
+/// Replaces the services we offer, the change reaches our neighbors with the next gossip
+pub fn set_local_services(services: Json<Vec<LocalService>>) -> Result<HttpResponse, Error> {
+    debug!("Set local services hit!");
+    let services = services.into_inner();
+    let mut names = HashSet::new();
+    for service in services.iter() {
+        if let Err(e) = validate_service(&service.name, service.port, &service.description) {
+            return Err(DashboardError::invalid_input(e.to_string()).into());
+        }
+        if !names.insert(service.name.clone()) {
+            return Err(DashboardError::invalid_input(format!(
+                "Service {} is listed twice",
+                service.name
+            ))
+            .into());
+        }
+    }
+    SETTING.get_network_mut().service_discovery.services = services;
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+    Ok(HttpResponse::Ok().json(()))
+}
+
+pub fn set_service_discovery(path: Path<bool>) -> Result<HttpResponse, Error> {
+    let value = path.into_inner();
+    debug!("Set service discovery hit!");
+    SETTING.get_network_mut().service_discovery.enabled = value;
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+    Ok(HttpResponse::Ok().json(()))
+}
@@ -1,6 +1,7 @@
 use crate::rita_common::network_endpoints::JsonStatusResponse;
 use crate::SETTING;
 use ::actix_web::{HttpRequest, Json, Result};
+use ::settings::schema::{get_problems, SettingsProblem};
 use ::settings::RitaCommonSettings;
 use failure::Error;
 use serde_json;
@@ -18,3 +19,10 @@ pub fn set_settings(
 
     JsonStatusResponse::new(Ok("New settings applied".to_string()))
 }
+
+/// Values in the settings file that were wrong when it was loaded, and whether the default was
+/// used in their place
+pub fn get_settings_problems(_req: HttpRequest) -> Result<Json<Vec<SettingsProblem>>, Error> {
+    debug!("Get settings problems endpoint hit!");
+    Ok(Json(get_problems()))
+}
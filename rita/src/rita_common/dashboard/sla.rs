@@ -0,0 +1,16 @@
+use crate::rita_common::sla_monitor::{GetSlaReport, SlaMonitor, SlaReport};
+use ::actix::SystemService;
+use ::actix_web::{AsyncResponder, HttpRequest, Json};
+use failure::Error;
+use futures01::Future;
+use std::boxed::Box;
+
+/// Daily and monthly uptime of the exit, uplink and mesh tiers
+pub fn get_sla(_req: HttpRequest) -> Box<dyn Future<Item = Json<SlaReport>, Error = Error>> {
+    debug!("Get SLA hit!");
+    SlaMonitor::from_registry()
+        .send(GetSlaReport)
+        .from_err()
+        .and_then(|reply| Ok(Json(reply?)))
+        .responder()
+}
@@ -0,0 +1,9 @@
+use crate::rita_common::system_health::{get_system_health, SystemHealth};
+use ::actix_web::{HttpRequest, Json};
+use failure::Error;
+
+/// Load, memory, storage, conntrack, temperatures and our own memory, read right now
+pub fn get_system_health_report(_req: HttpRequest) -> Result<Json<SystemHealth>, Error> {
+    trace!("get_system_health_report: Hit");
+    Ok(Json(get_system_health()))
+}
@@ -1,16 +1,22 @@
+use crate::rita_common::token_bridge::bridge_has_key;
 use crate::rita_common::token_bridge::BridgeStatus;
 use crate::rita_common::token_bridge::GetBridgeStatus;
 use crate::rita_common::token_bridge::TokenBridge;
 use ::actix::registry::SystemService;
 use ::actix_web::{AsyncResponder, HttpRequest, Json};
 use failure::Error;
-use futures01::Future;
+use futures01::{future, Future};
 use std::boxed::Box;
 
 pub fn get_bridge_status(
     _req: HttpRequest,
 ) -> Box<dyn Future<Item = Json<BridgeStatus>, Error = Error>> {
     trace!("/token_bridge/status hit");
+    if !bridge_has_key() {
+        return Box::new(future::err(format_err!(
+            "The token bridge needs a local eth key"
+        )));
+    }
     TokenBridge::from_registry()
         .send(GetBridgeStatus)
         .from_err()
@@ -0,0 +1,19 @@
+use crate::rita_common::traffic_watcher::anomaly::TrafficAlert;
+use crate::rita_common::traffic_watcher::{GetTrafficAlerts, TrafficWatcher};
+use ::actix::SystemService;
+use ::actix_web::{AsyncResponder, HttpRequest, Json};
+use failure::Error;
+use futures01::Future;
+use std::boxed::Box;
+
+/// Neighbors whose traffic recently jumped far above their usual amount, oldest first
+pub fn get_traffic_alerts(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<Vec<TrafficAlert>>, Error = Error>> {
+    trace!("get_traffic_alerts: Hit");
+    TrafficWatcher::from_registry()
+        .send(GetTrafficAlerts)
+        .from_err()
+        .and_then(|reply| Ok(Json(reply?)))
+        .responder()
+}
@@ -0,0 +1,127 @@
+use crate::rita_common::dashboard::error::{DashboardError, ErrorCode};
+use crate::rita_common::tunnel_manager::{GetTunnels, PauseNeighbor, ResetTunnel, TunnelManager};
+use crate::ARGS;
+use crate::SETTING;
+use ::actix::SystemService;
+use ::actix_web::{AsyncResponder, HttpRequest, HttpResponse, Json, Path, Query};
+use althea_types::WgKey;
+use failure::Error;
+use futures01::future::{self, join_all};
+use futures01::Future;
+use settings::FileWrite;
+use settings::RitaCommonSettings;
+use std::boxed::Box;
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ResetTunnelQuery {
+    /// only the tunnel over this physical interface, all of the neighbor's tunnels if unset
+    pub ifidx: Option<u32>,
+}
+
+/// Tears down and renegotiates our tunnels to the neighbor with this wireguard key
+pub fn reset_tunnel(
+    req: (Path<String>, Query<ResetTunnelQuery>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let (pubkey, query) = req;
+    debug!("/tunnels/{}/reset hit with {:?}", pubkey, query);
+    let pubkey: WgKey = match pubkey.parse() {
+        Ok(pubkey) => pubkey,
+        Err(e) => {
+            return Box::new(future::err(
+                DashboardError::invalid_input(format!("Invalid wireguard key: {}", e)).into(),
+            ));
+        }
+    };
+    let ifidx = query.ifidx;
+
+    TunnelManager::from_registry()
+        .send(GetTunnels)
+        .from_err()
+        .and_then(move |tunnels| {
+            let mut to_reset = Vec::new();
+            for tunnel in tunnels? {
+                let identity = tunnel.neigh_id.global;
+                if identity.wg_public_key == pubkey
+                    && ifidx.map_or(true, |ifidx| ifidx == tunnel.listen_ifidx)
+                    && !to_reset.contains(&(identity, tunnel.listen_ifidx))
+                {
+                    to_reset.push((identity, tunnel.listen_ifidx));
+                }
+            }
+            if to_reset.is_empty() {
+                return Err(DashboardError::new(
+                    ErrorCode::NotFound,
+                    format!("No tunnel to {} to reset", pubkey),
+                )
+                .into());
+            }
+            Ok(to_reset)
+        })
+        .and_then(|to_reset| {
+            let resets = to_reset.into_iter().map(|(identity, ifidx)| {
+                TunnelManager::from_registry()
+                    .send(ResetTunnel { identity, ifidx })
+                    .from_err()
+                    .and_then(|res| res)
+            });
+            join_all(resets.collect::<Vec<_>>())
+        })
+        .and_then(|_| Ok(HttpResponse::Ok().json(())))
+        .responder()
+}
+
+fn parse_key(pubkey: &str) -> Result<WgKey, DashboardError> {
+    pubkey
+        .parse()
+        .map_err(|e| DashboardError::invalid_input(format!("Invalid wireguard key: {}", e)))
+}
+
+/// The neighbors we've paused, by wg key
+pub fn get_paused_neighbors(_req: HttpRequest) -> Json<Vec<WgKey>> {
+    Json(
+        SETTING
+            .get_network()
+            .paused_neighbors
+            .iter()
+            .cloned()
+            .collect(),
+    )
+}
+
+/// Stops meshing with the neighbor with this wg key until it's resumed, its tunnels are torn
+/// down and its hellos go unanswered
+pub fn pause_neighbor(pubkey: Path<String>) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    debug!("/neighbors/{}/pause hit", pubkey);
+    let pubkey = match parse_key(&pubkey) {
+        Ok(pubkey) => pubkey,
+        Err(e) => return Box::new(future::err(e.into())),
+    };
+    // saved before the tunnels go so that a hello in between can't set them up again
+    SETTING.get_network_mut().paused_neighbors.insert(pubkey);
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Box::new(future::err(e));
+    }
+
+    TunnelManager::from_registry()
+        .send(PauseNeighbor(pubkey))
+        .from_err()
+        .and_then(move |removed| {
+            info!("Paused neighbor {}, removed {} tunnels", pubkey, removed?);
+            Ok(HttpResponse::Ok().json(()))
+        })
+        .responder()
+}
+
+/// Lets a paused neighbor back in, tunnels are set up again with the next hellos
+pub fn resume_neighbor(pubkey: Path<String>) -> Result<HttpResponse, Error> {
+    debug!("/neighbors/{}/resume hit", pubkey);
+    let pubkey = parse_key(&pubkey)?;
+    if !SETTING.get_network_mut().paused_neighbors.remove(&pubkey) {
+        return Err(
+            DashboardError::new(ErrorCode::NotFound, format!("{} is not paused", pubkey)).into(),
+        );
+    }
+    SETTING.write().unwrap().write(&ARGS.flag_config)?;
+    info!("Resumed neighbor {}", pubkey);
+    Ok(HttpResponse::Ok().json(()))
+}
@@ -1,5 +1,7 @@
+use crate::rita_common::currency::get_exchange_rate;
+use crate::rita_common::currency::FiatAmount;
+use crate::rita_common::usage_tracker::FormattedPaymentTx;
 use crate::rita_common::usage_tracker::GetPayments;
-use crate::rita_common::usage_tracker::PaymentHour;
 use crate::rita_common::usage_tracker::UsageTracker;
 use ::actix::registry::SystemService;
 use ::actix_web::{AsyncResponder, HttpRequest, Json};
@@ -8,13 +10,46 @@ use futures01::Future;
 use std::boxed::Box;
 use std::collections::VecDeque;
 
+/// A payment as shown on the dashboard, valued at the current exchange rate if fiat display
+/// is enabled
+#[derive(Serialize)]
+pub struct DisplayPaymentTx {
+    #[serde(flatten)]
+    pub payment: FormattedPaymentTx,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount_fiat: Option<FiatAmount>,
+}
+
+#[derive(Serialize)]
+pub struct DisplayPaymentHour {
+    pub index: u64,
+    pub payments: Vec<DisplayPaymentTx>,
+}
+
 pub fn get_payments(
     _req: HttpRequest,
-) -> Box<dyn Future<Item = Json<VecDeque<PaymentHour>>, Error = Error>> {
+) -> Box<dyn Future<Item = Json<VecDeque<DisplayPaymentHour>>, Error = Error>> {
     trace!("/usage/relay hit");
     UsageTracker::from_registry()
         .send(GetPayments {})
         .from_err()
-        .and_then(|reply| Ok(Json(reply?)))
+        .join(get_exchange_rate())
+        .and_then(|(reply, rate)| {
+            let hours = reply?
+                .into_iter()
+                .map(|hour| DisplayPaymentHour {
+                    index: hour.index,
+                    payments: hour
+                        .payments
+                        .into_iter()
+                        .map(|payment| DisplayPaymentTx {
+                            amount_fiat: rate.as_ref().map(|r| r.convert_uint(&payment.amount)),
+                            payment,
+                        })
+                        .collect(),
+                })
+                .collect();
+            Ok(Json(hours))
+        })
         .responder()
 }
@@ -1,7 +1,10 @@
+use crate::rita_common::dashboard::error::{DashboardError, ErrorCode};
 use crate::rita_common::oracle::trigger_update_nonce;
 use crate::rita_common::oracle::Oracle;
 use crate::rita_common::oracle::ZeroWindowStart;
+use crate::rita_common::payment_controller::signer::sign_and_send;
 use crate::rita_common::rita_loop::get_web3_server;
+use crate::rita_common::token_bridge::bridge_has_key;
 use crate::rita_common::token_bridge::eth_equal;
 use crate::rita_common::token_bridge::GetBridge;
 use crate::rita_common::token_bridge::TokenBridge;
@@ -10,7 +13,6 @@ use crate::rita_common::token_bridge::DAI_WEI_CENT;
 use crate::rita_common::token_bridge::ETH_TRANSFER_TIMEOUT;
 use crate::SETTING;
 use ::actix::SystemService;
-use ::actix_web::http::StatusCode;
 use ::actix_web::HttpResponse;
 use ::actix_web::Path;
 use ::settings::RitaCommonSettings;
@@ -41,13 +43,15 @@ pub fn withdraw(
         (SystemChain::Rinkeby, SystemChain::Rinkeby) => eth_compatable_withdraw(address, amount),
         (SystemChain::Xdai, SystemChain::Xdai) => eth_compatable_withdraw(address, amount),
         (SystemChain::Xdai, SystemChain::Ethereum) => xdai_to_eth_withdraw(address, amount, false),
-        (_, _) => Box::new(future::ok(
-            HttpResponse::new(StatusCode::from_u16(500u16).unwrap())
-                .into_builder()
-                .json(format!(
+        (_, _) => Box::new(future::err(
+            DashboardError::new(
+                ErrorCode::Misconfigured,
+                format!(
                     "System chain is {} but withdraw chain is {}, withdraw impossible!",
                     system_chain, withdraw_chain
-                )),
+                ),
+            )
+            .into(),
         )),
     }
 }
@@ -81,13 +85,15 @@ pub fn withdraw_all(path: Path<Address>) -> Box<dyn Future<Item = HttpResponse,
         (SystemChain::Rinkeby, SystemChain::Rinkeby) => eth_compatable_withdraw(address, amount),
         (SystemChain::Xdai, SystemChain::Xdai) => eth_compatable_withdraw(address, amount),
         (SystemChain::Xdai, SystemChain::Ethereum) => xdai_to_eth_withdraw(address, amount, true),
-        (_, _) => Box::new(future::ok(
-            HttpResponse::new(StatusCode::from_u16(500u16).unwrap())
-                .into_builder()
-                .json(format!(
+        (_, _) => Box::new(future::err(
+            DashboardError::new(
+                ErrorCode::Misconfigured,
+                format!(
                     "System chain is {} but withdraw chain is {}, withdraw impossible!",
                     system_chain, withdraw_chain
-                )),
+                ),
+            )
+            .into(),
         )),
     }
 }
@@ -98,6 +104,15 @@ pub fn withdraw_eth(
     let to = path.0;
     let withdraw_amount = path.1.clone();
     debug!("/withdraw_eth/{:#x}/{} hit", to, withdraw_amount);
+    if !bridge_has_key() {
+        return Box::new(future::err(
+            DashboardError::new(
+                ErrorCode::Misconfigured,
+                "The token bridge needs a local eth key, withdraw impossible!",
+            )
+            .into(),
+        ));
+    }
     let payment_settings = SETTING.get_payment();
     let our_address = payment_settings.eth_address.unwrap();
     drop(payment_settings);
@@ -107,10 +122,12 @@ pub fn withdraw_eth(
             .send(GetBridge())
             .then(move |bridge| {
                 if let Err(e) = bridge {
-                    return Box::new(future::ok(
-                        HttpResponse::new(StatusCode::from_u16(500u16).unwrap())
-                            .into_builder()
-                            .json(format!("Failed to get bridge {:?}", e)),
+                    return Box::new(future::err(
+                        DashboardError::new(
+                            ErrorCode::ActorUnavailable,
+                            format!("Failed to get bridge {:?}", e),
+                        )
+                        .into(),
                     ))
                         as Box<dyn Future<Item = HttpResponse, Error = Error>>;
                 }
@@ -125,10 +142,12 @@ pub fn withdraw_eth(
                         .join(bridge.dai_to_eth_price(DAI_WEI_CENT.into()))
                         .then(move |res| {
                             if let Err(e) = res {
-                                return Box::new(future::ok(
-                                    HttpResponse::new(StatusCode::from_u16(500u16).unwrap())
-                                        .into_builder()
-                                        .json(format!("Failed to get balance or price {:?}", e)),
+                                return Box::new(future::err(
+                                    DashboardError::new(
+                                        ErrorCode::BlockchainFailed,
+                                        format!("Failed to get balance or price {:?}", e),
+                                    )
+                                    .into(),
                                 ))
                                     as Box<dyn Future<Item = HttpResponse, Error = Error>>;
                             }
@@ -140,21 +159,23 @@ pub fn withdraw_eth(
                                         .eth_transfer(to, withdraw_amount, ETH_TRANSFER_TIMEOUT)
                                         .then(|res| {
                                             if let Err(e) = res {
-                                                Ok(HttpResponse::new(
-                                                    StatusCode::from_u16(500u16).unwrap(),
+                                                Err(DashboardError::new(
+                                                    ErrorCode::BlockchainFailed,
+                                                    format!("Transfer error {:?}", e),
                                                 )
-                                                .into_builder()
-                                                .json(format!("Transfer error {:?}", e)))
+                                                .into())
                                             } else {
                                                 Ok(HttpResponse::Ok().json("Success!".to_string()))
                                             }
                                         }),
                                 )
                             } else {
-                                Box::new(future::ok(
-                                    HttpResponse::new(StatusCode::from_u16(400u16).unwrap())
-                                        .into_builder()
-                                        .json("Insufficient balance".to_string()),
+                                Box::new(future::err(
+                                    DashboardError::new(
+                                        ErrorCode::InsufficientBalance,
+                                        "Insufficient balance",
+                                    )
+                                    .into(),
                                 ))
                             }
                         }),
@@ -174,10 +195,8 @@ fn eth_compatable_withdraw(
     let our_address = match payment_settings.eth_address {
         Some(address) => address,
         None => {
-            return Box::new(future::ok(
-                HttpResponse::new(StatusCode::from_u16(504u16).unwrap())
-                    .into_builder()
-                    .json("No Address configured, withdraw impossible!"),
+            return Box::new(future::err(
+                DashboardError::not_ready("No Address configured, withdraw impossible!").into(),
             ))
         }
     };
@@ -191,25 +210,9 @@ fn eth_compatable_withdraw(
         data: Vec::new(),
         signature: None,
     };
-    let transaction_signed = tx.sign(
-        &payment_settings
-            .eth_private_key
-            .expect("No private key configured!"),
-        payment_settings.net_version,
-    );
-
-    let transaction_bytes = match transaction_signed.to_bytes() {
-        Ok(bytes) => bytes,
-        Err(e) => {
-            return Box::new(future::ok(
-                HttpResponse::new(StatusCode::from_u16(500u16).unwrap())
-                    .into_builder()
-                    .json(format!("Transaction to bytes failed! {:?}", e)),
-            ));
-        }
-    };
+    drop(payment_settings);
 
-    let transaction_status = web3.eth_send_raw_transaction(transaction_bytes);
+    let transaction_status = sign_and_send(tx, full_node.clone(), WITHDRAW_TIMEOUT);
 
     Box::new(transaction_status.then(move |result| match result {
         Ok(tx_id) => Box::new(future::ok({
@@ -219,16 +222,20 @@ fn eth_compatable_withdraw(
         Err(e) => {
             trigger_update_nonce(our_address, &web3, full_node);
             if e.to_string().contains("nonce") {
-                Box::new(future::ok(
-                    HttpResponse::new(StatusCode::from_u16(500u16).unwrap())
-                        .into_builder()
-                        .json(format!("The nonce was not updated, try again {:?}", e)),
+                Box::new(future::err(
+                    DashboardError::new(
+                        ErrorCode::BlockchainFailed,
+                        format!("The nonce was not updated, try again {:?}", e),
+                    )
+                    .into(),
                 ))
             } else {
-                Box::new(future::ok(
-                    HttpResponse::new(StatusCode::from_u16(500u16).unwrap())
-                        .into_builder()
-                        .json(format!("Full node failed to send transaction! {:?}", e)),
+                Box::new(future::err(
+                    DashboardError::new(
+                        ErrorCode::BlockchainFailed,
+                        format!("Full node failed to send transaction! {:?}", e),
+                    )
+                    .into(),
                 ))
             }
         }
@@ -241,6 +248,15 @@ fn xdai_to_eth_withdraw(
     amount: Uint256,
     withdraw_all: bool,
 ) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    if !bridge_has_key() {
+        return Box::new(future::err(
+            DashboardError::new(
+                ErrorCode::Misconfigured,
+                "The token bridge needs a local eth key, withdraw impossible!",
+            )
+            .into(),
+        ));
+    }
     Box::new(
         TokenBridge::from_registry()
             .send(Withdraw {
@@ -252,10 +268,8 @@ fn xdai_to_eth_withdraw(
                 Ok(_) => Box::new(future::ok(
                     HttpResponse::Ok().json("View endpoints for progress"),
                 )),
-                Err(e) => Box::new(future::ok(
-                    HttpResponse::new(StatusCode::from_u16(500u16).unwrap())
-                        .into_builder()
-                        .json(format!("{:?}", e)),
+                Err(e) => Box::new(future::err(
+                    DashboardError::new(ErrorCode::ActorUnavailable, format!("{:?}", e)).into(),
                 )),
             }),
     )
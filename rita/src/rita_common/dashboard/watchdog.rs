@@ -0,0 +1,18 @@
+use crate::rita_common::watchdog::{GetWatchdogStatus, Watchdog, WatchdogStatus};
+use ::actix::SystemService;
+use ::actix_web::{AsyncResponder, HttpRequest, Json};
+use failure::Error;
+use futures01::Future;
+use std::boxed::Box;
+
+/// How responsive the core actors are and what the watchdog has had to do about it
+pub fn get_watchdog_status(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<WatchdogStatus>, Error = Error>> {
+    trace!("get_watchdog_status: Hit");
+    Watchdog::from_registry()
+        .send(GetWatchdogStatus)
+        .from_err()
+        .and_then(|reply| Ok(Json(reply?)))
+        .responder()
+}
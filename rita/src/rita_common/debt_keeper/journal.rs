@@ -0,0 +1,422 @@
+//! Routers behind intermittent links often can't reach the blockchain when a payment comes due.
+//! Without the journal the payment just fails, the neighbor's debt limit forgives whatever runs
+//! past its close threshold and a reboot forgets the rest. With the journal turned on a failed
+//! payment gets an IOU instead, a promise for everything we owe signed with our eth key, which is
+//! handed to the neighbor over the hello port as soon as we see it again. A neighbor holding our
+//! IOU lets our debt run past its close threshold by the IOU's amount, up to a cap of its own,
+//! before enforcing and doesn't forgive that part of the debt. Payments settle the IOUs as they
+//! come in. Both sides keep their IOUs in a journal file so that they survive a reboot.
+//!
+//! The journal file also keeps when each neighbor signed the last write off we took from them,
+//! whether or not IOUs are turned on, so that a write off can't be replayed after a restart.
+
+use super::write_off::MAX_WRITE_OFF_AGE;
+use crate::rita_common::utils::save_json;
+use althea_types::{Identity, Iou};
+use clarity::PrivateKey;
+use failure::Error;
+use num256::{Int256, Uint256};
+use num_traits::identities::Zero;
+use sha3::{Digest, Keccak256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+
+const IOU_LABEL: &[u8] = b"althea iou";
+
+fn iou_digest(from: &Identity, to: &Identity, amount: &Uint256, sequence: u64) -> Vec<u8> {
+    let mut hasher = Keccak256::new();
+    hasher.input(IOU_LABEL);
+    hasher.input(
+        format!(
+            "{}|{}|{}|{}|{:#066x}|{}",
+            from.eth_address, from.mesh_ip, to.eth_address, to.mesh_ip, amount, sequence
+        )
+        .as_bytes(),
+    );
+    hasher.result().to_vec()
+}
+
+pub fn sign_iou(
+    from: Identity,
+    to: Identity,
+    amount: Uint256,
+    sequence: u64,
+    key: &PrivateKey,
+) -> Result<Iou, Error> {
+    // with an external signer the local key may not be the one we are paid at
+    if key.to_public_key()? != from.eth_address {
+        bail!("Our eth key is held by the external signer, can't sign IOUs");
+    }
+    let signature = key.sign_hash(&iou_digest(&from, &to, &amount, sequence));
+    Ok(Iou {
+        from,
+        to,
+        amount,
+        sequence,
+        signature,
+    })
+}
+
+/// Checks that an IOU was signed by the node it's from
+pub fn verify_iou(iou: &Iou) -> Result<(), Error> {
+    let digest = iou_digest(&iou.from, &iou.to, &iou.amount, iou.sequence);
+    let signer = match iou.signature.recover(&digest) {
+        Ok(val) => val,
+        Err(e) => bail!("Malformed IOU signature {:?}", e),
+    };
+    if signer != iou.from.eth_address {
+        bail!("IOU was signed by {} not {}", signer, iou.from.eth_address);
+    }
+    Ok(())
+}
+
+fn saturating_sub(a: &Uint256, b: &Uint256) -> Uint256 {
+    if a > b {
+        a.clone() - b.clone()
+    } else {
+        Uint256::zero()
+    }
+}
+
+/// How much of an IOU counts, never more than `cap`
+fn allowance(owed: Uint256, cap: &Uint256) -> Int256 {
+    let allowed = if owed > *cap { cap.clone() } else { owed };
+    allowed.to_int256().unwrap_or_else(Int256::zero)
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// the last IOU we signed for them
+    pub issued: Option<Iou>,
+    /// if they've acknowledged `issued`
+    pub delivered: bool,
+    /// what we've paid them since signing `issued`
+    pub issued_settled: Uint256,
+    /// the last IOU they signed for us
+    pub received: Option<Iou>,
+    /// what they've paid us since signing `received`
+    pub received_settled: Uint256,
+}
+
+impl JournalEntry {
+    /// What they still owe us by their IOU
+    pub fn owed_to_us(&self) -> Uint256 {
+        match &self.received {
+            Some(iou) => saturating_sub(&iou.amount, &self.received_settled),
+            None => Uint256::zero(),
+        }
+    }
+
+    /// What we still owe them by our IOU
+    pub fn owed_by_us(&self) -> Uint256 {
+        match &self.issued {
+            Some(iou) => saturating_sub(&iou.amount, &self.issued_settled),
+            None => Uint256::zero(),
+        }
+    }
+}
+
+/// The journal as it's saved, serde does not support structs as keys in maps
+#[derive(Serialize, Deserialize)]
+struct JournalFile {
+    next_sequence: u64,
+    entries: Vec<(Identity, JournalEntry)>,
+    #[serde(default)]
+    write_offs_taken: Vec<(Identity, u64)>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DebtJournal {
+    entries: HashMap<Identity, JournalEntry>,
+    /// the sequence of the next IOU we sign, shared by all neighbors
+    next_sequence: u64,
+    /// when each neighbor signed the last write off we took from them, only kept for as long
+    /// as the write off would still be taken
+    write_offs_taken: HashMap<Identity, u64>,
+    /// if there are changes that haven't been saved
+    dirty: bool,
+}
+
+impl DebtJournal {
+    pub fn load(path: &str) -> Result<DebtJournal, Error> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        let file: JournalFile = serde_json::from_str(&contents)?;
+        Ok(DebtJournal {
+            entries: file.entries.into_iter().collect(),
+            next_sequence: file.next_sequence,
+            write_offs_taken: file.write_offs_taken.into_iter().collect(),
+            dirty: false,
+        })
+    }
+
+    /// Just the write offs, for when IOUs are turned off
+    pub fn without_ious(self) -> DebtJournal {
+        DebtJournal {
+            write_offs_taken: self.write_offs_taken,
+            ..Default::default()
+        }
+    }
+
+    /// IOUs are rare and losing one costs money so every change is saved, not just every so often
+    pub fn save_if_needed(&mut self, path: &str) {
+        if !self.dirty {
+            return;
+        }
+        match self.save(path) {
+            Ok(()) => self.dirty = false,
+            Err(e) => error!("Failed to save debt journal {:?}", e),
+        }
+    }
+
+    fn save(&self, path: &str) -> Result<(), Error> {
+        let file = JournalFile {
+            next_sequence: self.next_sequence,
+            entries: self.entries.iter().map(|(k, v)| (*k, v.clone())).collect(),
+            write_offs_taken: self
+                .write_offs_taken
+                .iter()
+                .map(|(k, v)| (*k, *v))
+                .collect(),
+        };
+        save_json(path, &file)
+    }
+
+    pub fn entries(&self) -> &HashMap<Identity, JournalEntry> {
+        &self.entries
+    }
+
+    /// Signs an IOU for everything we owe them, unless our last one already says as much
+    pub fn issue(
+        &mut self,
+        us: Identity,
+        them: Identity,
+        amount: Uint256,
+        key: &PrivateKey,
+    ) -> Result<(), Error> {
+        let entry = self.entries.entry(them).or_default();
+        if entry.owed_by_us() == amount {
+            return Ok(());
+        }
+        let iou = sign_iou(us, them, amount, self.next_sequence, key)?;
+        info!(
+            "Signed IOU {} for {} to {}",
+            iou.sequence, iou.amount, them.mesh_ip
+        );
+        self.next_sequence += 1;
+        entry.issued = Some(iou);
+        entry.delivered = false;
+        entry.issued_settled = Uint256::zero();
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Our IOU for them if they haven't acknowledged it yet
+    pub fn undelivered(&self, them: &Identity) -> Option<Iou> {
+        let entry = self.entries.get(them)?;
+        if entry.delivered {
+            None
+        } else {
+            entry.issued.clone()
+        }
+    }
+
+    pub fn delivered(&mut self, them: &Identity, sequence: u64) {
+        if let Some(entry) = self.entries.get_mut(them) {
+            if entry.issued.as_ref().map(|iou| iou.sequence) == Some(sequence) {
+                entry.delivered = true;
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// Takes an IOU from a neighbor in place of any older one
+    pub fn receive(&mut self, us: &Identity, iou: Iou) -> Result<(), Error> {
+        verify_iou(&iou)?;
+        if iou.to != *us {
+            bail!("IOU is for {} not us", iou.to.mesh_ip);
+        }
+        let entry = self.entries.entry(iou.from).or_default();
+        if let Some(current) = &entry.received {
+            if current.sequence == iou.sequence {
+                // they didn't hear back the last time they sent it
+                return Ok(());
+            } else if current.sequence > iou.sequence {
+                bail!(
+                    "IOU {} is older than the one we have, {}",
+                    iou.sequence,
+                    current.sequence
+                );
+            }
+        }
+        entry.received = Some(iou);
+        entry.received_settled = Uint256::zero();
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Counts a payment they made against their IOU
+    pub fn payment_received(&mut self, them: &Identity, amount: &Uint256) {
+        if let Some(entry) = self.entries.get_mut(them) {
+            if entry.received.is_some() {
+                entry.received_settled += amount.clone();
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// Counts a payment we made against our IOU
+    pub fn payment_sent(&mut self, them: &Identity, amount: &Uint256) {
+        if let Some(entry) = self.entries.get_mut(them) {
+            if entry.issued.is_some() {
+                entry.issued_settled += amount.clone();
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// When they signed the last write off we took from them, if it's recent enough to matter
+    pub fn last_write_off(&self, them: &Identity) -> Option<u64> {
+        self.write_offs_taken.get(them).cloned()
+    }
+
+    /// Records a write off we took, dropping any that are too old to be taken again anyway
+    pub fn write_off_taken(&mut self, them: Identity, time: u64, now: u64) {
+        self.write_offs_taken.insert(them, time);
+        self.write_offs_taken
+            .retain(|_, time| *time + MAX_WRITE_OFF_AGE >= now);
+        self.dirty = true;
+    }
+
+    /// How far past the close threshold we let their debt go
+    pub fn their_allowance(&self, them: &Identity, cap: &Uint256) -> Int256 {
+        match self.entries.get(them) {
+            Some(entry) => allowance(entry.owed_to_us(), cap),
+            None => Int256::zero(),
+        }
+    }
+
+    /// How far past the close threshold we expect them to let our debt go
+    pub fn our_allowance(&self, them: &Identity, cap: &Uint256) -> Int256 {
+        match self.entries.get(them) {
+            Some(entry) => allowance(entry.owed_by_us(), cap),
+            None => Int256::zero(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rita_common::utils::test_identity;
+
+    fn identity(ip: &str, key: &PrivateKey) -> Identity {
+        let mut identity = test_identity(ip);
+        identity.eth_address = key.to_public_key().unwrap();
+        identity
+    }
+
+    #[test]
+    fn test_iou_signature() {
+        let key: PrivateKey = "fe1bfa04d82b1e5a73f3a2d1a6d4b0d0f0c1ef1d1ba3c5d3c0b1e0fa5b1c2d3e"
+            .parse()
+            .unwrap();
+        let us = identity("fd00::1", &key);
+        let them = identity("fd00::2", &key);
+        let iou = sign_iou(us, them, 5000u32.into(), 7, &key).unwrap();
+        assert!(verify_iou(&iou).is_ok());
+
+        let mut inflated = iou.clone();
+        inflated.amount = 50_000u32.into();
+        assert!(verify_iou(&inflated).is_err());
+        let mut redirected = iou.clone();
+        redirected.to.mesh_ip = "fd00::3".parse().unwrap();
+        assert!(verify_iou(&redirected).is_err());
+
+        let mut not_ours = us;
+        not_ours.eth_address = "0x0000000000000000000000000000000000000001"
+            .parse()
+            .unwrap();
+        assert!(sign_iou(not_ours, them, 5000u32.into(), 7, &key).is_err());
+    }
+
+    #[test]
+    fn test_debt_journal() {
+        let our_key: PrivateKey =
+            "fe1bfa04d82b1e5a73f3a2d1a6d4b0d0f0c1ef1d1ba3c5d3c0b1e0fa5b1c2d3e"
+                .parse()
+                .unwrap();
+        let their_key: PrivateKey =
+            "1c2d3efe1bfa04d82b1e5a73f3a2d1a6d4b0d0f0c1ef1d1ba3c5d3c0b1e0fa5b"
+                .parse()
+                .unwrap();
+        let us = identity("fd00::1", &our_key);
+        let them = identity("fd00::2", &their_key);
+        let cap: Uint256 = 1000u32.into();
+        let mut journal = DebtJournal::default();
+
+        // our IOUs wait to be delivered and are only replaced when the debt changes
+        journal.issue(us, them, 500u32.into(), &our_key).unwrap();
+        let first = journal.undelivered(&them).unwrap();
+        journal.issue(us, them, 500u32.into(), &our_key).unwrap();
+        assert_eq!(journal.undelivered(&them), Some(first.clone()));
+        journal.delivered(&them, first.sequence);
+        assert!(journal.undelivered(&them).is_none());
+        journal.issue(us, them, 800u32.into(), &our_key).unwrap();
+        let second = journal.undelivered(&them).unwrap();
+        assert!(second.sequence > first.sequence);
+        // an acknowledgement of the old one doesn't count for the new one
+        journal.delivered(&them, first.sequence);
+        assert!(journal.undelivered(&them).is_some());
+        journal.payment_sent(&them, &300u32.into());
+        assert_eq!(journal.our_allowance(&them, &cap), 500.into());
+
+        // theirs must be for us and newer than the last
+        let iou = |amount: u32, sequence| {
+            sign_iou(them, us, amount.into(), sequence, &their_key).unwrap()
+        };
+        assert!(journal.receive(&them, iou(600, 3)).is_err());
+        journal.receive(&us, iou(600, 3)).unwrap();
+        assert_eq!(journal.their_allowance(&them, &cap), 600.into());
+        journal.receive(&us, iou(600, 3)).unwrap();
+        assert!(journal.receive(&us, iou(100, 2)).is_err());
+        // capped, then settled by payments
+        journal.receive(&us, iou(5000, 4)).unwrap();
+        assert_eq!(journal.their_allowance(&them, &cap), 1000.into());
+        journal.payment_received(&them, &4500u32.into());
+        assert_eq!(journal.their_allowance(&them, &cap), 500.into());
+        journal.payment_received(&them, &4500u32.into());
+        assert_eq!(journal.their_allowance(&them, &cap), Int256::zero());
+
+        let mut forged = iou(9000, 5);
+        forged.from = us;
+        assert!(journal.receive(&us, forged).is_err());
+    }
+
+    #[test]
+    fn test_write_offs_taken() {
+        let key: PrivateKey = "fe1bfa04d82b1e5a73f3a2d1a6d4b0d0f0c1ef1d1ba3c5d3c0b1e0fa5b1c2d3e"
+            .parse()
+            .unwrap();
+        let us = identity("fd00::1", &key);
+        let them = identity("fd00::2", &key);
+        let other = identity("fd00::3", &key);
+        let now = 1_580_000_000;
+        let mut journal = DebtJournal::default();
+
+        journal.write_off_taken(them, now, now);
+        journal.issue(us, them, 500u32.into(), &key).unwrap();
+        // kept without the IOUs
+        let journal = journal.without_ious();
+        assert!(journal.undelivered(&them).is_none());
+        assert_eq!(journal.last_write_off(&them), Some(now));
+
+        // until it's too old to be taken again
+        let mut journal = journal;
+        let later = now + MAX_WRITE_OFF_AGE + 1;
+        journal.write_off_taken(other, later, later);
+        assert_eq!(journal.last_write_off(&them), None);
+        assert_eq!(journal.last_write_off(&other), Some(later));
+    }
+}
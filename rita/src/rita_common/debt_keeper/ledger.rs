@@ -0,0 +1,266 @@
+//! An append only record of every change DebtKeeper makes to a counterparty's balance. DebtKeeper
+//! itself only remembers the current state, which makes it impossible to answer questions like
+//! 'why do I owe this node so much?' after the fact. Receipts neighbors sign for our payments are
+//! kept here too, as proof they were paid. Entries are appended to disk as newline delimited json.
+//! Payments, receipts and write offs are rare and are what billing disputes are about, so they're
+//! written out on the next DebtKeeper tick. Traffic updates come every few seconds, so to go easy
+//! on router flash they're summed per counterparty in memory and written as one entry once an
+//! hour, or sooner ahead of another entry with the same counterparty. Once the file grows past
+//! the configured size it's moved aside and a new one started, so at most two files worth of
+//! history is kept.
+//!
+//! Reading the files back is left to the caller, off the DebtKeeper actor, which only hands out
+//! what it hasn't written yet.
+
+use crate::rita_common::utils::now_secs;
+use crate::SETTING;
+use althea_types::PaymentReceipt;
+use failure::Error;
+use num256::Int256;
+use settings::RitaCommonSettings;
+use std::collections::HashMap;
+use std::fs::{metadata, rename, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// How often summed traffic updates are written out
+const LEDGER_FLUSH_FREQUENCY: Duration = Duration::from_secs(3600);
+/// Most entries kept waiting while the ledger can't be written, past this the oldest are dropped
+const MAX_PENDING_ENTRIES: usize = 1000;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum LedgerEntryKind {
+    /// A traffic update, positive means we owe them more
+    Traffic,
+    /// The debt was replaced wholesale, the amount is the new debt
+    TrafficReplace,
+    /// We paid them
+    PaymentSent,
+    /// They paid us
+    PaymentReceived,
+    /// They signed a receipt for a payment we sent, the amount is the amount acknowledged
+    PaymentReceipt,
+    /// Part or all of the debt was written off, the amount is the change to the debt
+    WriteOff,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct LedgerEntry {
+    /// Seconds since the unix epoch
+    #[serde(rename = "t")]
+    pub time: u64,
+    #[serde(rename = "k")]
+    pub kind: LedgerEntryKind,
+    #[serde(rename = "a")]
+    pub amount: Int256,
+    /// Counterparty mesh ip
+    #[serde(rename = "id")]
+    pub counterparty: IpAddr,
+    #[serde(rename = "r", default, skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<PaymentReceipt>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Ledger {
+    pending: Vec<LedgerEntry>,
+    /// traffic updates not in `pending` yet, the time of the first one and their sum
+    traffic: HashMap<IpAddr, (u64, Int256)>,
+    last_flush: Option<Instant>,
+}
+
+impl Ledger {
+    pub fn record(&mut self, counterparty: IpAddr, kind: LedgerEntryKind, amount: Int256) {
+        if kind == LedgerEntryKind::Traffic {
+            let total = self
+                .traffic
+                .entry(counterparty)
+                .or_insert_with(|| (now_secs(), Int256::zero()));
+            total.1 = total.1.clone() + amount;
+        } else {
+            self.push(counterparty, kind, amount, None);
+        }
+    }
+
+    pub fn record_receipt(&mut self, counterparty: IpAddr, receipt: PaymentReceipt) {
+        let amount = match receipt.amount.to_int256() {
+            Some(val) => val,
+            None => {
+                error!(
+                    "Receipt amount does not fit in the ledger {}",
+                    receipt.amount
+                );
+                return;
+            }
+        };
+        self.push(
+            counterparty,
+            LedgerEntryKind::PaymentReceipt,
+            amount,
+            Some(receipt),
+        );
+    }
+
+    fn push(
+        &mut self,
+        counterparty: IpAddr,
+        kind: LedgerEntryKind,
+        amount: Int256,
+        receipt: Option<PaymentReceipt>,
+    ) {
+        // their traffic so far goes first so the history stays in order
+        self.settle_traffic(counterparty);
+        let time = now_secs();
+        self.pending.push(LedgerEntry {
+            time,
+            kind,
+            amount,
+            counterparty,
+            receipt,
+        });
+        self.cap_pending();
+    }
+
+    /// Entries only pile up while writing fails, if that goes on long enough we'd rather lose
+    /// the oldest history than run out of memory
+    fn cap_pending(&mut self) {
+        if self.pending.len() > MAX_PENDING_ENTRIES {
+            let excess = self.pending.len() - MAX_PENDING_ENTRIES;
+            error!("Debt ledger can't be written, dropping {} entries", excess);
+            self.pending.drain(..excess);
+        }
+    }
+
+    /// Moves the summed traffic updates for a counterparty into `pending` as one entry
+    fn settle_traffic(&mut self, counterparty: IpAddr) {
+        if let Some((time, amount)) = self.traffic.remove(&counterparty) {
+            self.pending.push(LedgerEntry {
+                time,
+                kind: LedgerEntryKind::Traffic,
+                amount,
+                counterparty,
+                receipt: None,
+            });
+        }
+    }
+
+    fn settle_all_traffic(&mut self) {
+        let counterparties: Vec<IpAddr> = self.traffic.keys().cloned().collect();
+        for counterparty in counterparties {
+            self.settle_traffic(counterparty);
+        }
+    }
+
+    #[cfg(test)]
+    pub fn pending(&mut self) -> &[LedgerEntry] {
+        self.settle_all_traffic();
+        &self.pending
+    }
+
+    /// Entries with a counterparty that haven't been written out yet, oldest first
+    pub fn unwritten(&self, counterparty: IpAddr) -> Vec<LedgerEntry> {
+        let mut ret: Vec<LedgerEntry> = self
+            .pending
+            .iter()
+            .filter(|entry| entry.counterparty == counterparty)
+            .cloned()
+            .collect();
+        if let Some((time, amount)) = self.traffic.get(&counterparty) {
+            ret.push(LedgerEntry {
+                time: *time,
+                kind: LedgerEntryKind::Traffic,
+                amount: amount.clone(),
+                counterparty,
+                receipt: None,
+            });
+        }
+        ret
+    }
+
+    /// Writes out anything other than traffic right away and the summed traffic once it's due
+    pub fn flush_if_needed(&mut self) {
+        let traffic_due = match self.last_flush {
+            Some(val) => Instant::now() - val > LEDGER_FLUSH_FREQUENCY,
+            None => true,
+        };
+        if traffic_due {
+            self.last_flush = Some(Instant::now());
+            self.settle_all_traffic();
+            self.cap_pending();
+        }
+        if self.pending.is_empty() {
+            return;
+        }
+        if let Err(e) = self.write_pending() {
+            error!("Failed to write debt ledger {:?}", e);
+        }
+    }
+
+    fn write_pending(&mut self) -> Result<(), Error> {
+        let payment = SETTING.get_payment();
+        let path = payment.debt_ledger_file.clone();
+        let max_size = payment.debt_ledger_max_size;
+        drop(payment);
+
+        if let Ok(meta) = metadata(&path) {
+            if meta.len() > max_size {
+                rename(&path, rotated_path(&path))?;
+            }
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let mut out = String::new();
+        for entry in self.pending.iter() {
+            out += &serde_json::to_string(entry)?;
+            out.push('\n');
+        }
+        file.write_all(out.as_bytes())?;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+/// The history with a counterparty that has been written to the ledger files at `path`, oldest
+/// first. This reads both files in full so it's not to be called from an actor
+pub fn read_history(path: &str, counterparty: IpAddr) -> Result<Vec<LedgerEntry>, Error> {
+    let mut ret = Vec::new();
+    for file in [rotated_path(path), path.to_string()].iter() {
+        let file = match File::open(file) {
+            Ok(f) => f,
+            // the rotated file won't exist until the first rotation
+            Err(_) => continue,
+        };
+        for line in BufReader::new(file).lines() {
+            let entry: LedgerEntry = match serde_json::from_str(&line?) {
+                Ok(e) => e,
+                Err(e) => {
+                    warn!("Skipping corrupt debt ledger line {:?}", e);
+                    continue;
+                }
+            };
+            if entry.counterparty == counterparty {
+                ret.push(entry);
+            }
+        }
+    }
+    Ok(ret)
+}
+
+fn rotated_path(path: &str) -> String {
+    format!("{}.1", path)
+}
+
+#[test]
+fn test_pending_cap() {
+    let mut ledger = Ledger::default();
+    let counterparty: IpAddr = "fd00::1".parse().unwrap();
+    ledger.record(counterparty, LedgerEntryKind::Traffic, 5.into());
+    for i in 0..MAX_PENDING_ENTRIES as i64 + 10 {
+        ledger.record(counterparty, LedgerEntryKind::PaymentSent, i.into());
+    }
+    // the oldest are dropped, traffic first since it goes ahead of the payments
+    let pending = ledger.pending();
+    assert_eq!(pending.len(), MAX_PENDING_ENTRIES);
+    assert_eq!(pending[0].kind, LedgerEntryKind::PaymentSent);
+    assert_eq!(pending[0].amount, 10.into());
+}
@@ -9,29 +9,47 @@
 //! Hence we need an incoming paymetns parameter to take money out of. This of course implies half
 //! of the excess complexity you see, managing an incoming payments pool versus a incoming debts pool
 
+pub mod journal;
+pub mod ledger;
+pub mod reconcile;
+pub mod write_off;
+
+use self::journal::{DebtJournal, JournalEntry};
+use self::ledger::{Ledger, LedgerEntry, LedgerEntryKind};
+use self::reconcile::{current_window, Divergence, NeighborBytes, Reconciler};
+use self::write_off::{sign_write_off, verify_write_off, written_off};
+use crate::rita_common::currency::FiatAmount;
+use crate::rita_common::hello_handler::post_to_peer;
+use crate::rita_common::live_updates::{has_subscribers, LiveUpdates, Publish};
 use crate::rita_common::payment_controller;
 use crate::rita_common::payment_controller::PaymentController;
 use crate::rita_common::payment_validator::PAYMENT_TIMEOUT;
 use crate::rita_common::simulated_txfee_manager::AddTxToTotal;
 use crate::rita_common::simulated_txfee_manager::SimulatedTxFeeManager;
+use crate::rita_common::time_sanity::clock_is_sane;
 use crate::rita_common::tunnel_manager::TunnelAction;
 use crate::rita_common::tunnel_manager::TunnelChange;
 use crate::rita_common::tunnel_manager::TunnelManager;
 use crate::rita_common::tunnel_manager::TunnelStateChange;
+use crate::rita_common::utils::now_secs;
+use crate::rita_common::watchdog::or_alarm;
 use crate::SETTING;
-use ::actix::prelude::{Actor, Context, Handler, Message, Supervised, SystemService};
-use althea_types::{Identity, PaymentTx};
+use ::actix::prelude::{Actor, Arbiter, Context, Handler, Message, Supervised, SystemService};
+use althea_types::{DebtWriteOff, Identity, Iou, PaymentReceipt, PaymentTx, TrafficCounts, Wei};
 use failure::Error;
+use futures01::Future;
 use num256::{Int256, Uint256};
 use num_traits::identities::Zero;
 use num_traits::Signed;
 use serde_json::Error as SerdeError;
+use settings::payment::PaymentPurpose;
 use settings::RitaCommonSettings;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::Error as IOError;
 use std::io::Read;
 use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
 use std::time::Instant;
 
@@ -65,6 +83,10 @@ pub struct NodeDebtData {
     /// case, where when we get payments from the exit there is a race condition where the
     /// exit may not update that we have paid it fast enough
     pub last_successful_payment: Option<Instant>,
+    /// Set for exits, which replace our debt to them, payments to them are paid out of the exit
+    /// wallet
+    #[serde(default)]
+    pub exit: bool,
 }
 
 impl NodeDebtData {
@@ -78,6 +100,7 @@ impl NodeDebtData {
             payment_in_flight: false,
             payment_in_flight_start: None,
             last_successful_payment: None,
+            exit: false,
         }
     }
 }
@@ -125,13 +148,13 @@ fn debt_limit(debt: Int256, close_threshold: Int256) -> Int256 {
     if debt < close_threshold {
         info!(
             "Forgiving {} wei to enforce debt limit",
-            debt - close_threshold.clone()
+            Wei::from(debt).saturating_sub(&Wei::from(close_threshold.clone()))
         );
         close_threshold - 1u8.into()
     } else if debt > close_threshold.abs() {
         info!(
             "Not paying {} wei to enforce debt limit",
-            debt - close_threshold.clone()
+            Wei::from(debt).saturating_sub(&Wei::from(close_threshold.clone()))
         );
         close_threshold.abs() + 1u8.into()
     } else {
@@ -139,11 +162,41 @@ fn debt_limit(debt: Int256, close_threshold: Int256) -> Int256 {
     }
 }
 
+/// Adds `amount` to a neighbor's debt, if that overflows the debt is saturated and an alarm is
+/// journaled, debt_limit brings it back into range on the next round
+fn add_to_debt(debt: &mut Int256, amount: &Wei, ident: &Identity) {
+    let current = Wei::from(debt.clone());
+    *debt = or_alarm(
+        current.checked_add(amount),
+        current.saturating_add(amount),
+        "DebtKeeper",
+        || format!("debt of {} plus {}", ident.mesh_ip, amount),
+    )
+    .into();
+}
+
+fn subtract_from_debt(debt: &mut Int256, amount: &Wei, ident: &Identity) {
+    let current = Wei::from(debt.clone());
+    *debt = or_alarm(
+        current.checked_sub(amount),
+        current.saturating_sub(amount),
+        "DebtKeeper",
+        || format!("debt of {} minus {}", ident.mesh_ip, amount),
+    )
+    .into();
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DebtKeeper {
     #[serde(skip_serializing, skip_deserializing)]
     last_save: Option<Instant>,
     debt_data: DebtData,
+    #[serde(skip_serializing, skip_deserializing)]
+    ledger: Ledger,
+    #[serde(skip_serializing, skip_deserializing)]
+    reconciler: Reconciler,
+    #[serde(skip_serializing, skip_deserializing)]
+    journal: DebtJournal,
 }
 
 impl Actor for DebtKeeper {
@@ -224,6 +277,21 @@ impl Handler<PaymentSucceeded> for DebtKeeper {
     }
 }
 
+/// A neighbor we paid signed a receipt for it, the receipt has already been checked
+#[derive(Message)]
+pub struct PaymentReceiptReceived {
+    pub to: Identity,
+    pub receipt: PaymentReceipt,
+}
+
+impl Handler<PaymentReceiptReceived> for DebtKeeper {
+    type Result = ();
+
+    fn handle(&mut self, msg: PaymentReceiptReceived, _: &mut Context<Self>) -> Self::Result {
+        self.ledger.record_receipt(msg.to.mesh_ip, msg.receipt);
+    }
+}
+
 pub struct Traffic {
     pub from: Identity,
     pub amount: Int256,
@@ -284,10 +352,171 @@ impl Handler<TrafficReplace> for DebtKeeper {
     type Result = ();
 
     fn handle(&mut self, msg: TrafficReplace, _: &mut Context<Self>) -> Self::Result {
+        let debt_data = self.get_debt_data_mut(&msg.traffic.from);
+        if !debt_data.exit {
+            debt_data.exit = true;
+            // saved right away so that a restart doesn't have us paying exits out of the wrong
+            // wallet until the next replace
+            if let Err(e) = self.save() {
+                error!("Failed to save debts {:?}", e);
+            }
+        }
         self.traffic_replace(&msg.traffic.from, msg.traffic.amount);
     }
 }
 
+/// Bytes counted for each neighbor since the last traffic update, see `reconcile`
+#[derive(Message)]
+pub struct CountTraffic(pub Vec<NeighborBytes>);
+
+impl Handler<CountTraffic> for DebtKeeper {
+    type Result = ();
+
+    fn handle(&mut self, msg: CountTraffic, _: &mut Context<Self>) -> Self::Result {
+        // a neighbor showing up again is our chance to hand over IOUs
+        for count in msg.0.iter() {
+            if let (Some(socket), Some(iou)) =
+                (count.iou_to, self.journal.undelivered(&count.neighbor))
+            {
+                let to = count.neighbor;
+                let sequence = iou.sequence;
+                Arbiter::spawn(post_to_peer(socket, "/iou", &iou).then(
+                    move |res: Result<(), Error>| {
+                        match res {
+                            Ok(()) => {
+                                DebtKeeper::from_registry().do_send(IouDelivered { to, sequence })
+                            }
+                            Err(e) => trace!("Failed to deliver IOU to {} {:?}", socket, e),
+                        }
+                        Ok(())
+                    },
+                ));
+            }
+        }
+
+        let us = match SETTING.get_identity() {
+            Some(id) => id,
+            None => return,
+        };
+        for (socket, report) in self.reconciler.count(current_window(), us, &msg.0) {
+            Arbiter::spawn(post_to_peer(socket, "/traffic_counts", &report).then(
+                move |res: Result<(), Error>| {
+                    if let Err(e) = res {
+                        trace!("Failed to send traffic counts to {} {:?}", socket, e);
+                    }
+                    Ok(())
+                },
+            ));
+        }
+    }
+}
+
+/// A neighbor's own counts for a finished window, `sender` is the address the request came from
+#[derive(Message)]
+pub struct TheirTrafficCounts {
+    pub sender: IpAddr,
+    pub counts: TrafficCounts,
+}
+
+impl Handler<TheirTrafficCounts> for DebtKeeper {
+    type Result = ();
+
+    fn handle(&mut self, msg: TheirTrafficCounts, _: &mut Context<Self>) -> Self::Result {
+        self.reconciler.neighbor_report(msg.sender, msg.counts);
+    }
+}
+
+/// A neighbor acknowledged our IOU
+#[derive(Message)]
+struct IouDelivered {
+    to: Identity,
+    sequence: u64,
+}
+
+impl Handler<IouDelivered> for DebtKeeper {
+    type Result = ();
+
+    fn handle(&mut self, msg: IouDelivered, _: &mut Context<Self>) -> Self::Result {
+        self.journal.delivered(&msg.to, msg.sequence);
+    }
+}
+
+/// An IOU from a neighbor, see `journal`
+pub struct IouReceived(pub Iou);
+
+impl Message for IouReceived {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<IouReceived> for DebtKeeper {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: IouReceived, _: &mut Context<Self>) -> Self::Result {
+        if !SETTING.get_payment().debt_journal.enabled {
+            bail!("We don't keep a debt journal");
+        }
+        let us = match SETTING.get_identity() {
+            Some(id) => id,
+            None => bail!("Identity has no mesh IP ready yet"),
+        };
+        info!(
+            "Got IOU {} for {} from {}",
+            msg.0.sequence, msg.0.amount, msg.0.from.mesh_ip
+        );
+        self.journal.receive(&us, msg.0)
+    }
+}
+
+#[derive(Serialize)]
+pub struct JournalStatus {
+    pub identity: Identity,
+    /// what they still owe us by their IOU
+    pub owed_to_us: Uint256,
+    /// what we still owe them by ours
+    pub owed_by_us: Uint256,
+    pub journal: JournalEntry,
+}
+
+/// The IOUs we've exchanged with each neighbor
+pub struct GetDebtJournal;
+
+impl Message for GetDebtJournal {
+    type Result = Result<Vec<JournalStatus>, Error>;
+}
+
+impl Handler<GetDebtJournal> for DebtKeeper {
+    type Result = Result<Vec<JournalStatus>, Error>;
+
+    fn handle(&mut self, _msg: GetDebtJournal, _: &mut Context<Self>) -> Self::Result {
+        Ok(self
+            .journal
+            .entries()
+            .iter()
+            .map(|(identity, entry)| JournalStatus {
+                identity: *identity,
+                owed_to_us: entry.owed_to_us(),
+                owed_by_us: entry.owed_by_us(),
+                journal: entry.clone(),
+            })
+            .collect())
+    }
+}
+
+/// How far our counters are from each neighbor's for the last window we both counted
+pub struct GetDivergence;
+
+impl Message for GetDivergence {
+    type Result = Result<Vec<Divergence>, Error>;
+}
+
+impl Handler<GetDivergence> for DebtKeeper {
+    type Result = Result<Vec<Divergence>, Error>;
+
+    fn handle(&mut self, _msg: GetDivergence, _: &mut Context<Self>) -> Self::Result {
+        Ok(self.reconciler.divergence())
+    }
+}
+
 pub struct SendUpdate;
 
 impl Message for SendUpdate {
@@ -309,6 +538,9 @@ impl Handler<SendUpdate> for DebtKeeper {
     fn handle(&mut self, _msg: SendUpdate, _ctx: &mut Context<Self>) -> Self::Result {
         trace!("sending debt keeper update");
         self.save_if_needed();
+        self.ledger.flush_if_needed();
+        let journal_file = SETTING.get_payment().debt_journal.journal_file.clone();
+        self.journal.save_if_needed(&journal_file);
 
         // in order to keep from overloading actix when we have thousands of debts to process
         // (mainly on exits) we batch tunnel change operations before sending them over
@@ -329,21 +561,46 @@ impl Handler<SendUpdate> for DebtKeeper {
                     });
                 }
                 DebtAction::MakePayment { to, amount } => PaymentController::from_registry()
-                    .do_send(payment_controller::MakePayment(PaymentTx {
-                        to,
-                        from: match SETTING.get_identity() {
-                            Some(id) => id,
-                            None => bail!("Identity has no mesh IP ready yet"),
+                    .do_send(payment_controller::MakePayment {
+                        pmt: PaymentTx {
+                            to,
+                            from: match SETTING.get_identity() {
+                                Some(id) => id,
+                                None => bail!("Identity has no mesh IP ready yet"),
+                            },
+                            amount,
+                            txid: None, // not yet published
+                            channel: None,
+                            payment_id: None,
                         },
-                        amount,
-                        txid: None, // not yet published
-                    })),
+                        purpose: if self.debt_data.get(&to).map_or(false, |d| d.exit) {
+                            PaymentPurpose::Exit
+                        } else {
+                            PaymentPurpose::Relay
+                        },
+                    }),
             }
         }
 
+        let divergence = self
+            .reconciler
+            .divergence()
+            .iter()
+            .map(|d| (d.identity, d.percent))
+            .collect();
         TunnelManager::from_registry().do_send(TunnelStateChange {
             tunnels: debts_message,
+            divergence,
         });
+
+        if has_subscribers() {
+            let debts = self
+                .debt_data
+                .iter()
+                .map(|(id, data)| (*id, data.debt.clone()))
+                .collect();
+            LiveUpdates::from_registry().do_send(Publish::Debts(debts));
+        }
         Ok(())
     }
 }
@@ -357,9 +614,12 @@ impl Default for DebtKeeper {
         let blank_debt_keeper = DebtKeeper {
             last_save: None,
             debt_data: HashMap::new(),
+            ledger: Ledger::default(),
+            reconciler: Reconciler::default(),
+            journal: DebtJournal::default(),
         };
 
-        match file {
+        let mut keeper = match file {
             Ok(mut file) => {
                 let mut contents = String::new();
                 match file.read_to_string(&mut contents) {
@@ -371,6 +631,9 @@ impl Default for DebtKeeper {
                             Ok(value) => DebtKeeper {
                                 last_save: None,
                                 debt_data: ser_to_debt_data(value),
+                                ledger: Ledger::default(),
+                                reconciler: Reconciler::default(),
+                                journal: DebtJournal::default(),
                             },
                             Err(e) => {
                                 error!("Failed to deserialize debts file {:?}", e);
@@ -388,6 +651,24 @@ impl Default for DebtKeeper {
                 error!("Failed to open debts file! {:?}", e);
                 blank_debt_keeper
             }
+        };
+        keeper.journal = load_journal();
+        keeper.restore_journaled_debts();
+        keeper
+    }
+}
+
+/// The write offs we've taken are loaded even with IOUs turned off, see write_off
+fn load_journal() -> DebtJournal {
+    let journal_settings = SETTING.get_payment().debt_journal.clone();
+    match DebtJournal::load(&journal_settings.journal_file) {
+        Ok(journal) if journal_settings.enabled => journal,
+        Ok(journal) => journal.without_ious(),
+        Err(e) => {
+            if journal_settings.enabled {
+                error!("Failed to load debt journal! {:?}", e);
+            }
+            DebtJournal::default()
         }
     }
 }
@@ -401,6 +682,69 @@ impl DebtKeeper {
         DebtKeeper {
             last_save: None,
             debt_data: DebtData::new(),
+            ledger: Ledger::default(),
+            reconciler: Reconciler::default(),
+            journal: DebtJournal::default(),
+        }
+    }
+
+    /// Debts neighbors owe us are forgiven when they're loaded, except for what they've promised
+    /// in an IOU
+    fn restore_journaled_debts(&mut self) {
+        let owed: Vec<(Identity, Int256)> = self
+            .journal
+            .entries()
+            .iter()
+            .filter_map(|(id, entry)| Some((*id, entry.owed_to_us().to_int256()?)))
+            .filter(|(_, owed)| *owed > Int256::zero())
+            .collect();
+        for (ident, owed) in owed {
+            let journaled = Int256::zero() - owed;
+            let debt_data = self.get_debt_data_mut(&ident);
+            if debt_data.debt > journaled {
+                info!(
+                    "Restoring debt of {} from {}'s IOU",
+                    journaled, ident.mesh_ip
+                );
+                debt_data.debt = journaled;
+            }
+        }
+    }
+
+    /// How far past the close threshold debts backed by IOUs can go, theirs and then ours
+    fn journal_allowances(&self, ident: &Identity) -> (Int256, Int256) {
+        let payment_settings = SETTING.get_payment();
+        let journal_settings = &payment_settings.debt_journal;
+        if !journal_settings.enabled {
+            return (Int256::zero(), Int256::zero());
+        }
+        (
+            self.journal
+                .their_allowance(ident, &journal_settings.iou_cap),
+            self.journal.our_allowance(ident, &journal_settings.iou_cap),
+        )
+    }
+
+    /// Signs an IOU for what we owe a neighbor we couldn't pay, it's handed over once the
+    /// neighbor can be reached
+    fn issue_iou(&mut self, to: &Identity) {
+        let amount = match self.get_debt_data_mut(to).debt.to_uint256() {
+            Some(amount) if amount > Uint256::zero() => amount,
+            _ => return,
+        };
+        let us = match SETTING.get_identity() {
+            Some(id) => id,
+            None => return,
+        };
+        let key = match SETTING.get_payment().eth_private_key {
+            Some(key) => key,
+            None => {
+                warn!("No eth key configured yet, can't sign an IOU");
+                return;
+            }
+        };
+        if let Err(e) = self.journal.issue(us, *to, amount, &key) {
+            warn!("Failed to sign an IOU for {} {:?}", to.mesh_ip, e);
         }
     }
 
@@ -446,20 +790,31 @@ impl DebtKeeper {
         let peer = self.get_debt_data_mut(to);
         peer.payment_in_flight = false;
         peer.payment_in_flight_start = None;
+        if SETTING.get_payment().debt_journal.enabled {
+            self.issue_iou(to);
+        }
         Ok(())
     }
 
     fn payment_succeeded(&mut self, to: &Identity, amount: Uint256) -> Result<(), Error> {
+        let signed_amount = match amount.to_int256() {
+            Some(val) => val,
+            None => bail!("Failed to convert amount paid to Int256!"),
+        };
+        self.ledger.record(
+            to.mesh_ip,
+            LedgerEntryKind::PaymentSent,
+            signed_amount.clone(),
+        );
+        self.journal.payment_sent(to, &amount);
+
         let peer = self.get_debt_data_mut(to);
         peer.payment_in_flight = false;
         peer.payment_in_flight_start = None;
 
         peer.total_payment_sent += amount.clone();
         peer.last_successful_payment = Some(Instant::now());
-        peer.debt -= match amount.to_int256() {
-            Some(val) => val,
-            None => bail!("Failed to convert amount paid to Int256!"),
-        };
+        subtract_from_debt(&mut peer.debt, &Wei::from(signed_amount), to);
         Ok(())
     }
 
@@ -467,6 +822,15 @@ impl DebtKeeper {
         let signed_zero = Int256::zero();
         let unsigned_zero = Uint256::zero();
 
+        // zero payments are just applications of existing credit
+        if amount > unsigned_zero {
+            if let Some(val) = amount.to_int256() {
+                self.ledger
+                    .record(ident.mesh_ip, LedgerEntryKind::PaymentReceived, val);
+            }
+            self.journal.payment_received(ident, &amount);
+        }
+
         let debt_data = self.get_debt_data_mut(ident);
         info!(
             "payment received: old incoming payments for {:?}: {:?}",
@@ -498,7 +862,7 @@ impl DebtKeeper {
                     Some(val) => val,
                     None => bail!("Unsigned payment int too big! You're super rich now"),
                 };
-                debt_data.debt += signed_incoming;
+                add_to_debt(&mut debt_data.debt, &Wei::from(signed_incoming), ident);
                 debt_data.incoming_payments = unsigned_zero;
             }
             (false, _) => {
@@ -517,11 +881,15 @@ impl DebtKeeper {
 
     fn traffic_update(&mut self, ident: &Identity, amount: Int256) {
         trace!("traffic update for {} is {}", ident.mesh_ip, amount);
+        if amount != Int256::zero() {
+            self.ledger
+                .record(ident.mesh_ip, LedgerEntryKind::Traffic, amount.clone());
+        }
         let debt_data = self.get_debt_data_mut(ident);
 
         // we handle the incoming debit or credit versus our existing debit or credit
         // very simple
-        debt_data.debt += amount;
+        add_to_debt(&mut debt_data.debt, &Wei::from(amount), ident);
 
         trace!("debt data for {} is {:?}", ident.mesh_ip, debt_data);
     }
@@ -534,25 +902,58 @@ impl DebtKeeper {
         // we may end up double paying we also should wait 60 seconds after
         // our last successful payment to make sure that the exit has had time
         // to check the full node, then update it's own debt keeper
-        match (
+        let replaced = match (
             debt_data.payment_in_flight,
             debt_data.last_successful_payment,
         ) {
-            (true, _) => {}
-            (false, Some(val)) => {
-                if Instant::now() - val > Duration::from_secs(15) {
-                    debt_data.debt = amount;
-                }
-            }
-            (false, None) => debt_data.debt = amount,
+            (true, _) => false,
+            (false, Some(val)) => Instant::now() - val > Duration::from_secs(15),
+            (false, None) => true,
+        };
+        if replaced {
+            debt_data.debt = amount.clone();
         }
 
         trace!("debt data for {} is {:?}", ident.mesh_ip, debt_data);
+        if replaced {
+            self.ledger
+                .record(ident.mesh_ip, LedgerEntryKind::TrafficReplace, amount);
+        }
+    }
+
+    /// Writes off `amount` of the debt, all of it for None, returns the change to the debt
+    fn write_off(&mut self, ident: &Identity, amount: Option<Uint256>) -> Result<Int256, Error> {
+        let debt_data = self.get_debt_data_mut(ident);
+        let old = debt_data.debt.clone();
+        if old > Int256::zero() && debt_data.payment_in_flight {
+            bail!("A payment to {} is in flight", ident.mesh_ip);
+        }
+        let new = written_off(&old, amount.as_ref());
+        if new == old {
+            bail!("Nothing to write off for {}", ident.mesh_ip);
+        }
+        debt_data.debt = new.clone();
+        let change = new - old.clone();
+        // the abs of a signed 256 bit int always fits in an unsigned one
+        let settled = change.abs().to_uint256().unwrap();
+        if old < Int256::zero() {
+            self.journal.payment_received(ident, &settled);
+        } else {
+            self.journal.payment_sent(ident, &settled);
+        }
+        info!(
+            "Wrote off {} of the debt of {} with {}",
+            settled, old, ident.mesh_ip
+        );
+        self.ledger
+            .record(ident.mesh_ip, LedgerEntryKind::WriteOff, change.clone());
+        Ok(change)
     }
 
     /// This updates a neighbor's debt and outputs a DebtAction if one is necessary.
     fn send_update(&mut self, ident: &Identity) -> Result<DebtAction, Error> {
         trace!("debt data: {:?}", self.debt_data);
+        let (their_allowance, our_allowance) = self.journal_allowances(ident);
         let debt_data = self.get_debt_data_mut(ident);
         // the debt we started this round with
 
@@ -576,13 +977,19 @@ impl DebtKeeper {
             close_threshold
         );
         // negative debt means they owe us so when the debt is more negative than
-        // the close treshold we should enforce.
-        let should_close = debt_data.debt < close_threshold;
+        // the close treshold we should enforce. Whatever they've promised in an IOU
+        // is held against them later
+        let should_close = debt_data.debt < close_threshold.clone() - their_allowance.clone();
         let should_pay = debt_data.debt > pay_threshold;
         let payment_in_flight = debt_data.payment_in_flight;
 
         if debt_limit_enabled {
-            debt_data.debt = debt_limit(debt_data.debt.clone(), close_threshold.clone());
+            let limit = if debt_data.debt < Int256::zero() {
+                close_threshold.clone() - their_allowance
+            } else {
+                close_threshold.clone() - our_allowance
+            };
+            debt_data.debt = debt_limit(debt_data.debt.clone(), limit);
         }
 
         match (should_close, should_pay, payment_in_flight) {
@@ -596,6 +1003,15 @@ impl DebtKeeper {
                     self.payment_received(ident, zero)?;
                     return Ok(DebtAction::OpenTunnel);
                 }
+                // a bad clock throws off billing, we don't know this debt is real
+                if !clock_is_sane() {
+                    warn!(
+                        "debt {} is below close threshold {} for {} but our clock is off, not suspending",
+                        debt_data.debt, close_threshold, ident.wg_public_key
+                    );
+                    debt_data.action = DebtAction::OpenTunnel;
+                    return Ok(DebtAction::OpenTunnel);
+                }
 
                 info!(
                     "debt {} is below close threshold {} for {}. suspending forwarding",
@@ -678,6 +1094,142 @@ impl DebtKeeper {
     }
 }
 
+/// Returns the ledger entries with the given counterparty that haven't been written out yet, the
+/// rest are read from the ledger files with `ledger::read_history`
+pub struct GetDebtHistory {
+    pub counterparty: IpAddr,
+}
+
+impl Message for GetDebtHistory {
+    type Result = Result<Vec<LedgerEntry>, Error>;
+}
+
+impl Handler<GetDebtHistory> for DebtKeeper {
+    type Result = Result<Vec<LedgerEntry>, Error>;
+
+    fn handle(&mut self, msg: GetDebtHistory, _: &mut Context<Self>) -> Self::Result {
+        Ok(self.ledger.unwritten(msg.counterparty))
+    }
+}
+
+/// Signs a notice for forgiving `change` of what they owed us and sends it off
+fn notify_write_off(them: Identity, change: &Int256) -> Result<(), Error> {
+    let us = match SETTING.get_identity() {
+        Some(id) => id,
+        None => bail!("Identity has no mesh IP ready yet"),
+    };
+    let key = match SETTING.get_payment().eth_private_key {
+        Some(key) => key,
+        None => bail!("No eth key configured yet, can't sign a write off"),
+    };
+    let amount = match change.to_uint256() {
+        Some(amount) => amount,
+        None => bail!("Only forgiven debts can be passed on"),
+    };
+    let now = now_secs();
+    let notice = sign_write_off(us, them, amount, now, &key)?;
+    let socket = SocketAddr::new(them.mesh_ip, SETTING.get_network().rita_hello_port);
+    Arbiter::spawn(post_to_peer(socket, "/debt_write_off", &notice).then(
+        move |res: Result<(), Error>| {
+            if let Err(e) = res {
+                warn!("Failed to tell {} about a write off {:?}", socket, e);
+            }
+            Ok(())
+        },
+    ));
+    Ok(())
+}
+
+/// Writes off part or all of a counterparty's balance, see `write_off`
+pub struct WriteOff {
+    pub counterparty: Identity,
+    /// None for all of it
+    pub amount: Option<Uint256>,
+    /// pass it on to the counterparty if it's a debt they owed us
+    pub notify: bool,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct WriteOffResult {
+    /// the change to the debt
+    pub written_off: Int256,
+    pub debt: Int256,
+    /// if a notice is on its way to the counterparty
+    pub notified: bool,
+}
+
+impl Message for WriteOff {
+    type Result = Result<WriteOffResult, Error>;
+}
+
+impl Handler<WriteOff> for DebtKeeper {
+    type Result = Result<WriteOffResult, Error>;
+
+    fn handle(&mut self, msg: WriteOff, _: &mut Context<Self>) -> Self::Result {
+        let them = msg.counterparty;
+        let change = self.write_off(&them, msg.amount)?;
+        let debt = self.get_debt_data_mut(&them).debt.clone();
+        // only forgiving what they owed us is worth telling them about, the write off stands
+        // even if they can't be told
+        let notified = msg.notify
+            && change > Int256::zero()
+            && match notify_write_off(them, &change) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Can't tell {} about a write off {:?}", them.mesh_ip, e);
+                    false
+                }
+            };
+        Ok(WriteOffResult {
+            written_off: change,
+            debt,
+            notified,
+        })
+    }
+}
+
+/// A neighbor wrote off some of what we owe them
+pub struct WriteOffReceived(pub DebtWriteOff);
+
+impl Message for WriteOffReceived {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<WriteOffReceived> for DebtKeeper {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: WriteOffReceived, _: &mut Context<Self>) -> Self::Result {
+        let us = match SETTING.get_identity() {
+            Some(id) => id,
+            None => bail!("Identity has no mesh IP ready yet"),
+        };
+        let notice = msg.0;
+        let owed = match self.debt_data.get(&notice.from) {
+            Some(data) => data.debt.clone(),
+            None => {
+                info!(
+                    "Ignoring a write off from {}, we have no debts with them",
+                    notice.from.mesh_ip
+                );
+                return Ok(());
+            }
+        };
+        let now = now_secs();
+        let last = self.journal.last_write_off(&notice.from);
+        verify_write_off(&notice, &us, now, last)?;
+        self.journal.write_off_taken(notice.from, notice.time, now);
+        if owed <= Int256::zero() {
+            info!(
+                "{} wrote off {} but we don't owe them anything",
+                notice.from.mesh_ip, notice.amount
+            );
+            return Ok(());
+        }
+        self.write_off(&notice.from, Some(notice.amount))?;
+        Ok(())
+    }
+}
+
 pub struct GetDebtsList;
 
 impl Message for GetDebtsList {
@@ -688,6 +1240,9 @@ impl Message for GetDebtsList {
 pub struct GetDebtsResult {
     pub identity: Identity,
     pub payment_details: NodeDebtData,
+    /// the debt in the display currency, filled in by the dashboard when available
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub debt_fiat: Option<FiatAmount>,
 }
 
 impl GetDebtsResult {
@@ -695,6 +1250,7 @@ impl GetDebtsResult {
         GetDebtsResult {
             identity: *identity,
             payment_details: payment_details.clone(),
+            debt_fiat: None,
         }
     }
 }
@@ -750,6 +1306,69 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_ledger_records_balance_changes() {
+        SETTING.get_payment_mut().pay_threshold = Int256::from(5);
+        SETTING.get_payment_mut().close_threshold = Int256::from(-10);
+
+        let mut d = DebtKeeper::new();
+        let ident = get_test_identity();
+
+        d.traffic_update(&ident, Int256::from(-100));
+        // zero updates are not worth recording
+        d.traffic_update(&ident, Int256::from(0));
+        d.traffic_update(&ident, Int256::from(-20));
+        d.payment_received(&ident, Uint256::from(50u32)).unwrap();
+        d.payment_succeeded(&ident, Uint256::from(10u32)).unwrap();
+        d.traffic_update(&ident, Int256::from(-5));
+
+        let entries = d.ledger.pending();
+        let kinds: Vec<LedgerEntryKind> = entries.iter().map(|e| e.kind).collect();
+        // traffic updates between other entries are summed into one
+        assert_eq!(
+            kinds,
+            vec![
+                LedgerEntryKind::Traffic,
+                LedgerEntryKind::PaymentReceived,
+                LedgerEntryKind::PaymentSent,
+                LedgerEntryKind::Traffic
+            ]
+        );
+        assert_eq!(entries[0].amount, Int256::from(-120));
+        assert_eq!(entries[3].amount, Int256::from(-5));
+    }
+
+    #[test]
+    fn test_write_off() {
+        SETTING.get_payment_mut().pay_threshold = Int256::from(5);
+        SETTING.get_payment_mut().close_threshold = Int256::from(-10);
+
+        let mut d = DebtKeeper::new();
+        let ident = get_test_identity();
+
+        d.traffic_update(&ident, Int256::from(-100));
+        assert_eq!(
+            d.write_off(&ident, Some(Uint256::from(30u32))).unwrap(),
+            Int256::from(30)
+        );
+        assert_eq!(d.get_debt_data_mut(&ident).debt, Int256::from(-70));
+        assert_eq!(d.write_off(&ident, None).unwrap(), Int256::from(70));
+        assert_eq!(d.send_update(&ident).unwrap(), DebtAction::OpenTunnel);
+        // nothing left
+        assert!(d.write_off(&ident, None).is_err());
+
+        // what we owe can't be written off while we're paying it
+        d.traffic_update(&ident, Int256::from(100));
+        d.get_debt_data_mut(&ident).payment_in_flight = true;
+        assert!(d.write_off(&ident, None).is_err());
+        d.get_debt_data_mut(&ident).payment_in_flight = false;
+        assert_eq!(d.write_off(&ident, None).unwrap(), Int256::from(-100));
+
+        let last = d.ledger.pending().last().unwrap();
+        assert_eq!(last.kind, LedgerEntryKind::WriteOff);
+        assert_eq!(last.amount, Int256::from(-100));
+    }
+
     #[test]
     fn test_single_suspend() {
         SETTING.get_payment_mut().pay_threshold = Int256::from(5);
@@ -0,0 +1,276 @@
+//! Both ends of a tunnel bill from their own counters, if those disagree the debts the two sides
+//! keep slowly drift apart and nobody notices until payments start failing to line up. So byte
+//! counts per neighbor are kept over fixed windows of wall clock time, when a window ends what we
+//! counted is sent to the neighbor over the hello port and they do the same. Comparing the two
+//! gives a divergence per neighbor, this never changes what anybody is billed. It's recorded for
+//! diagnosis and counts against the neighbor's reputation, making routes through it less
+//! attractive.
+
+use crate::rita_common::utils::now_secs;
+use althea_types::{Identity, TrafficCounts};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+/// Length of a reconciliation window in seconds, windows are aligned to the unix epoch so that
+/// neighbors agree on where they start without having to coordinate
+pub const RECONCILE_WINDOW: u64 = 300;
+
+/// The window we are in right now
+pub fn current_window() -> u64 {
+    now_secs() / RECONCILE_WINDOW
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, PartialEq, Eq)]
+pub struct ByteCounts {
+    /// bytes received from the neighbor
+    pub received: u64,
+    /// bytes sent to the neighbor
+    pub sent: u64,
+}
+
+/// Bytes counted for a neighbor since the last update, `report_to` is where the neighbor takes
+/// reports, None if it doesn't support reconciliation. `iou_to` is the same for IOUs, see
+/// `journal`, which ride along since this is sent for every neighbor we can currently reach
+#[derive(Clone, Copy, Debug)]
+pub struct NeighborBytes {
+    pub neighbor: Identity,
+    pub counts: ByteCounts,
+    pub report_to: Option<SocketAddr>,
+    pub iou_to: Option<SocketAddr>,
+}
+
+/// How far apart our counts and a neighbor's were for the last window both of us reported
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Divergence {
+    pub identity: Identity,
+    pub window: u64,
+    pub ours: ByteCounts,
+    /// the neighbor's counts from its own side, so its sent is compared against our received
+    pub theirs: ByteCounts,
+    /// the larger difference of the two directions as a percentage of the larger count
+    pub percent: f64,
+}
+
+fn percent_apart(a: u64, b: u64) -> f64 {
+    let larger = a.max(b);
+    if larger == 0 {
+        return 0.0;
+    }
+    let difference = if a > b { a - b } else { b - a };
+    difference as f64 / larger as f64 * 100.0
+}
+
+pub fn divergence(ours: ByteCounts, theirs: &TrafficCounts) -> Divergence {
+    let percent =
+        percent_apart(ours.received, theirs.sent).max(percent_apart(ours.sent, theirs.received));
+    Divergence {
+        identity: theirs.from,
+        window: theirs.window,
+        ours,
+        theirs: ByteCounts {
+            received: theirs.received,
+            sent: theirs.sent,
+        },
+        percent,
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Reconciler {
+    /// the window being counted, None until the first update
+    window: Option<u64>,
+    /// the window we started in is missing whatever came before we did, so it isn't reported
+    partial: bool,
+    current: HashMap<Identity, ByteCounts>,
+    report_to: HashMap<Identity, SocketAddr>,
+    /// what we counted in the last finished window
+    finished: Option<(u64, HashMap<Identity, ByteCounts>)>,
+    /// neighbor reports for windows we haven't finished yet, at most one per neighbor
+    pending: HashMap<Identity, TrafficCounts>,
+    divergence: HashMap<Identity, Divergence>,
+}
+
+impl Reconciler {
+    /// Adds bytes counted since the last update. If that ends a window the reports to send to
+    /// neighbors are returned
+    pub fn count(
+        &mut self,
+        now: u64,
+        us: Identity,
+        counts: &[NeighborBytes],
+    ) -> Vec<(SocketAddr, TrafficCounts)> {
+        for count in counts {
+            let entry = self.current.entry(count.neighbor).or_default();
+            entry.received += count.counts.received;
+            entry.sent += count.counts.sent;
+            match count.report_to {
+                Some(socket) => self.report_to.insert(count.neighbor, socket),
+                None => self.report_to.remove(&count.neighbor),
+            };
+        }
+
+        let window = match self.window {
+            Some(window) => window,
+            None => {
+                self.window = Some(now);
+                self.partial = true;
+                return Vec::new();
+            }
+        };
+        if window == now {
+            return Vec::new();
+        }
+
+        self.window = Some(now);
+        let finished = std::mem::replace(&mut self.current, HashMap::new());
+        if self.partial {
+            self.partial = false;
+            return Vec::new();
+        }
+
+        let mut reports = Vec::new();
+        for (neighbor, counts) in finished.iter() {
+            if let Some(socket) = self.report_to.get(neighbor) {
+                reports.push((
+                    *socket,
+                    TrafficCounts {
+                        from: us,
+                        window,
+                        received: counts.received,
+                        sent: counts.sent,
+                    },
+                ));
+            }
+        }
+        self.finished = Some((window, finished));
+
+        // reports that beat us to the end of the window
+        let pending: Vec<TrafficCounts> = self.pending.drain().map(|(_, v)| v).collect();
+        for report in pending {
+            self.their_report(report);
+        }
+        reports
+    }
+
+    /// Counts sent to us from `sender`. Whoever they claim to be from, they're taken as the
+    /// neighbor at the other end of the tunnel they came in on, the one with that mesh ip
+    pub fn neighbor_report(&mut self, sender: IpAddr, mut report: TrafficCounts) {
+        let neighbor = match self.report_to.keys().find(|id| id.mesh_ip == sender) {
+            Some(id) => *id,
+            None => {
+                trace!("Traffic counts from non neighbor {}", sender);
+                return;
+            }
+        };
+        if neighbor != report.from {
+            warn!(
+                "Traffic counts from {} claim to be from {}",
+                sender, report.from.mesh_ip
+            );
+            report.from = neighbor;
+        }
+        self.their_report(report);
+    }
+
+    /// Records a neighbor's counts, compared against ours as soon as we have the same window
+    fn their_report(&mut self, report: TrafficCounts) {
+        if !self.report_to.contains_key(&report.from) {
+            trace!("Traffic counts from non neighbor {}", report.from.mesh_ip);
+            return;
+        }
+        let last_finished = self.finished.as_ref().map(|(window, _)| *window);
+        match last_finished {
+            Some(window) if report.window == window => {
+                let ours = self
+                    .finished
+                    .as_ref()
+                    .and_then(|(_, counts)| counts.get(&report.from).cloned());
+                match ours {
+                    Some(ours) => {
+                        let divergence = divergence(ours, &report);
+                        if divergence.percent > 0.0 {
+                            trace!("Counters diverge from {:?}", divergence);
+                        }
+                        self.divergence.insert(report.from, divergence);
+                    }
+                    None => trace!("No traffic counted for {}", report.from.mesh_ip),
+                }
+            }
+            Some(window) if report.window < window => {
+                trace!("Dropping stale traffic counts from {}", report.from.mesh_ip)
+            }
+            _ => {
+                self.pending.insert(report.from, report);
+            }
+        }
+    }
+
+    pub fn divergence(&self) -> Vec<Divergence> {
+        self.divergence.values().cloned().collect()
+    }
+}
+
+#[test]
+fn test_reconcile() {
+    use crate::rita_common::utils::test_identity;
+
+    let us = test_identity("fd00::1");
+    let them = test_identity("fd00::2");
+    let socket: SocketAddr = "[fd00::2]:4876".parse().unwrap();
+    let bytes = |received, sent| {
+        vec![NeighborBytes {
+            neighbor: them,
+            counts: ByteCounts { received, sent },
+            report_to: Some(socket),
+            iou_to: None,
+        }]
+    };
+
+    let mut r = Reconciler::default();
+    // the window we started in is partial
+    assert!(r.count(10, us, &bytes(5, 5)).is_empty());
+    assert!(r.count(11, us, &bytes(5, 5)).is_empty());
+    r.count(11, us, &bytes(1000, 500));
+    // counts from anyone but a neighbor are dropped, even claiming to be one
+    r.neighbor_report(
+        "fd00::3".parse().unwrap(),
+        TrafficCounts {
+            from: them,
+            window: 11,
+            received: 1,
+            sent: 1,
+        },
+    );
+    // their report arriving before we finish the window waits for us, it's theirs because of
+    // where it came from whatever it says
+    r.neighbor_report(
+        them.mesh_ip,
+        TrafficCounts {
+            from: test_identity("fd00::3"),
+            window: 11,
+            received: 500,
+            sent: 900,
+        },
+    );
+    assert!(r.divergence().is_empty());
+    let reports = r.count(12, us, &[]);
+    assert_eq!(
+        reports,
+        vec![(
+            socket,
+            TrafficCounts {
+                from: us,
+                window: 11,
+                received: 1000,
+                sent: 500,
+            }
+        )]
+    );
+    let divergence = r.divergence();
+    assert_eq!(divergence.len(), 1);
+    assert_eq!(divergence[0].window, 11);
+    assert!((divergence[0].percent - 10.0).abs() < 0.001);
+
+    assert_eq!(percent_apart(0, 0), 0.0);
+    assert_eq!(percent_apart(50, 100), 50.0);
+}
@@ -0,0 +1,171 @@
+//! Writing off debts that will never be collected. A neighbor that's gone for good, or one we've
+//! settled with some other way, otherwise keeps a debt on the books that trips enforcement every
+//! time it shows up again. A write off moves the balance towards zero by some amount, or all the
+//! way, and is recorded in the ledger like any other change. Whatever part of an IOU it covers is
+//! counted as settled so that the journal doesn't bring the debt back on the next restart.
+//!
+//! Forgiving what a neighbor owes us can also be passed on to it, signed with our eth key, so that
+//! it stops counting that amount as owed to us. A neighbor only takes a write off that's for it,
+//! recent, and newer than the last one it took from us, so an old one can't be replayed. When it
+//! last took one is kept in the debt journal file so that this holds across restarts.
+
+use althea_types::{DebtWriteOff, Identity};
+use clarity::PrivateKey;
+use failure::Error;
+use num256::{Int256, Uint256};
+use num_traits::identities::Zero;
+use num_traits::Signed;
+use sha3::{Digest, Keccak256};
+
+const WRITE_OFF_LABEL: &[u8] = b"althea write off";
+/// How old a write off we'll take, routers' clocks only roughly agree
+pub const MAX_WRITE_OFF_AGE: u64 = 3600;
+
+fn write_off_digest(from: &Identity, to: &Identity, amount: &Uint256, time: u64) -> Vec<u8> {
+    let mut hasher = Keccak256::new();
+    hasher.input(WRITE_OFF_LABEL);
+    hasher.input(
+        format!(
+            "{}|{}|{}|{}|{:#066x}|{}",
+            from.eth_address, from.mesh_ip, to.eth_address, to.mesh_ip, amount, time
+        )
+        .as_bytes(),
+    );
+    hasher.result().to_vec()
+}
+
+pub fn sign_write_off(
+    from: Identity,
+    to: Identity,
+    amount: Uint256,
+    time: u64,
+    key: &PrivateKey,
+) -> Result<DebtWriteOff, Error> {
+    if key.to_public_key()? != from.eth_address {
+        bail!("Our eth key is held by the external signer, can't sign write offs");
+    }
+    let signature = key.sign_hash(&write_off_digest(&from, &to, &amount, time));
+    Ok(DebtWriteOff {
+        from,
+        to,
+        amount,
+        time,
+        signature,
+    })
+}
+
+/// Checks that a write off was signed by the node it's from, is meant for us and isn't one we've
+/// seen before. `last` is the time of the last one we took from the same node
+pub fn verify_write_off(
+    write_off: &DebtWriteOff,
+    us: &Identity,
+    now: u64,
+    last: Option<u64>,
+) -> Result<(), Error> {
+    let digest = write_off_digest(
+        &write_off.from,
+        &write_off.to,
+        &write_off.amount,
+        write_off.time,
+    );
+    let signer = match write_off.signature.recover(&digest) {
+        Ok(val) => val,
+        Err(e) => bail!("Malformed write off signature {:?}", e),
+    };
+    if signer != write_off.from.eth_address {
+        bail!(
+            "Write off was signed by {} not {}",
+            signer,
+            write_off.from.eth_address
+        );
+    }
+    if write_off.to != *us {
+        bail!("Write off is for {} not us", write_off.to.mesh_ip);
+    }
+    if write_off.time + MAX_WRITE_OFF_AGE < now || write_off.time > now + MAX_WRITE_OFF_AGE {
+        bail!("Write off from {} is too old", write_off.time);
+    }
+    if let Some(last) = last {
+        if write_off.time <= last {
+            bail!("Already took a write off from {}", last);
+        }
+    }
+    Ok(())
+}
+
+/// The debt after writing off `amount` of it, or all of it for None. Either way the debt only
+/// moves towards zero
+pub fn written_off(debt: &Int256, amount: Option<&Uint256>) -> Int256 {
+    let amount = match amount {
+        Some(amount) => amount,
+        None => return Int256::zero(),
+    };
+    // the abs of a signed 256 bit int always fits in an unsigned one
+    let owed = debt.abs().to_uint256().unwrap();
+    if *amount >= owed {
+        return Int256::zero();
+    }
+    // less than the debt so it fits
+    let amount = amount.to_int256().unwrap();
+    if *debt < Int256::zero() {
+        debt.clone() + amount
+    } else {
+        debt.clone() - amount
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rita_common::utils::test_identity;
+
+    fn identity(ip: &str, key: &PrivateKey) -> Identity {
+        let mut identity = test_identity(ip);
+        identity.eth_address = key.to_public_key().unwrap();
+        identity
+    }
+
+    #[test]
+    fn test_write_off_signature() {
+        let key: PrivateKey = "fe1bfa04d82b1e5a73f3a2d1a6d4b0d0f0c1ef1d1ba3c5d3c0b1e0fa5b1c2d3e"
+            .parse()
+            .unwrap();
+        let us = identity("fd00::1", &key);
+        let them = identity("fd00::2", &key);
+        let now = 1_580_000_000;
+        let write_off = sign_write_off(them, us, 5000u32.into(), now, &key).unwrap();
+        assert!(verify_write_off(&write_off, &us, now + 60, None).is_ok());
+        assert!(verify_write_off(&write_off, &us, now + 60, Some(now - 600)).is_ok());
+
+        // replayed, stale, or meant for someone else
+        assert!(verify_write_off(&write_off, &us, now + 60, Some(now)).is_err());
+        assert!(verify_write_off(&write_off, &us, now + MAX_WRITE_OFF_AGE + 1, None).is_err());
+        assert!(verify_write_off(&write_off, &them, now, None).is_err());
+        let mut inflated = write_off.clone();
+        inflated.amount = 50_000u32.into();
+        assert!(verify_write_off(&inflated, &us, now, None).is_err());
+    }
+
+    #[test]
+    fn test_written_off() {
+        let amount: Uint256 = 30u32.into();
+        assert_eq!(written_off(&Int256::from(-100), None), Int256::zero());
+        assert_eq!(
+            written_off(&Int256::from(-100), Some(&amount)),
+            Int256::from(-70)
+        );
+        assert_eq!(
+            written_off(&Int256::from(100), Some(&amount)),
+            Int256::from(70)
+        );
+        // never past zero
+        assert_eq!(
+            written_off(&Int256::from(-20), Some(&amount)),
+            Int256::zero()
+        );
+        assert_eq!(
+            written_off(&Int256::from(20), Some(&amount)),
+            Int256::zero()
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//! Signatures over exit terms. Exits sign the price, free tier and jurisdiction they serve clients
+//! under with their eth key and send them along with the rest of their details, clients check the
+//! signature against the eth address they have configured for the exit. That way a client can
+//! show exactly what terms it accepted and an exit can't quietly claim different ones.
+//!
+//! Operator announcements are signed the same way, so that a client only shows its user messages
+//! that really came from the exit and not from anyone who can tamper with the exit's responses.
+
+use althea_types::{Announcement, ExitTerms, SignedAnnouncement, SignedExitTerms};
+use clarity::{Address, PrivateKey};
+use failure::Error;
+use sha3::{Digest, Keccak256};
+
+const TERMS_LABEL: &[u8] = b"althea exit terms";
+const ANNOUNCEMENT_LABEL: &[u8] = b"althea exit announcement";
+
+fn terms_digest(terms: &ExitTerms) -> Vec<u8> {
+    let mut hasher = Keccak256::new();
+    hasher.input(TERMS_LABEL);
+    // the jurisdiction goes last since it's the only field that can contain the separator
+    hasher.input(
+        format!(
+            "{}:{}:{}",
+            terms.exit_price, terms.free_tier_throughput, terms.jurisdiction
+        )
+        .as_bytes(),
+    );
+    hasher.result().to_vec()
+}
+
+pub fn sign_terms(terms: ExitTerms, key: &PrivateKey) -> Result<SignedExitTerms, Error> {
+    let signature = key.sign_hash(&terms_digest(&terms));
+    Ok(SignedExitTerms {
+        terms,
+        signer: key.to_public_key()?,
+        signature,
+    })
+}
+
+/// Checks that the terms were signed by the exit we expect them from
+pub fn verify_terms(signed: &SignedExitTerms, exit: Address) -> Result<(), Error> {
+    if signed.signer != exit {
+        bail!("Exit terms are from {} not {}", signed.signer, exit);
+    }
+    let signer = match signed.signature.recover(&terms_digest(&signed.terms)) {
+        Ok(val) => val,
+        Err(e) => bail!("Malformed exit terms signature {:?}", e),
+    };
+    if signer != exit {
+        bail!("Exit terms were signed by {} not {}", signer, exit);
+    }
+    Ok(())
+}
+
+fn announcement_digest(announcement: &Announcement) -> Vec<u8> {
+    let mut hasher = Keccak256::new();
+    hasher.input(ANNOUNCEMENT_LABEL);
+    // like the jurisdiction in terms, the message goes last
+    hasher.input(
+        format!(
+            "{}:{}:{}:{}",
+            announcement.id,
+            announcement.posted,
+            announcement
+                .expires
+                .map(|expires| expires.to_string())
+                .unwrap_or_default(),
+            announcement.message
+        )
+        .as_bytes(),
+    );
+    hasher.result().to_vec()
+}
+
+pub fn sign_announcement(
+    announcement: Announcement,
+    key: &PrivateKey,
+) -> Result<SignedAnnouncement, Error> {
+    let signature = key.sign_hash(&announcement_digest(&announcement));
+    Ok(SignedAnnouncement {
+        announcement,
+        signer: key.to_public_key()?,
+        signature,
+    })
+}
+
+/// Checks that the announcement was signed by the exit it claims to be from
+pub fn verify_announcement(signed: &SignedAnnouncement, exit: Address) -> Result<(), Error> {
+    if signed.signer != exit {
+        bail!("Announcement is from {} not {}", signed.signer, exit);
+    }
+    let signer = match signed
+        .signature
+        .recover(&announcement_digest(&signed.announcement))
+    {
+        Ok(val) => val,
+        Err(e) => bail!("Malformed announcement signature {:?}", e),
+    };
+    if signer != exit {
+        bail!("Announcement was signed by {} not {}", signer, exit);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_exit_terms_signature() {
+    let key: PrivateKey = "fe1bfa04d82b1e5a73f3a2d1a6d4b0d0f0c1ef1d1ba3c5d3c0b1e0fa5b1c2d3e"
+        .parse()
+        .unwrap();
+    let exit = key.to_public_key().unwrap();
+    let terms = ExitTerms {
+        exit_price: 50,
+        free_tier_throughput: 1000,
+        jurisdiction: "US".to_string(),
+    };
+    let signed = sign_terms(terms, &key).unwrap();
+    assert!(verify_terms(&signed, exit).is_ok());
+
+    let mut raised = signed.clone();
+    raised.terms.exit_price = 500;
+    assert!(verify_terms(&raised, exit).is_err());
+
+    let someone_else: Address = "0x0000000000000000000000000000000000000001"
+        .parse()
+        .unwrap();
+    assert!(verify_terms(&signed, someone_else).is_err());
+    let mut claimed = signed.clone();
+    claimed.signer = someone_else;
+    assert!(verify_terms(&claimed, someone_else).is_err());
+}
+
+#[test]
+fn test_announcement_signature() {
+    let key: PrivateKey = "fe1bfa04d82b1e5a73f3a2d1a6d4b0d0f0c1ef1d1ba3c5d3c0b1e0fa5b1c2d3e"
+        .parse()
+        .unwrap();
+    let exit = key.to_public_key().unwrap();
+    let announcement = Announcement {
+        id: 1,
+        posted: 1_577_836_800,
+        expires: Some(1_578_441_600),
+        message: "Maintenance tonight from 2am to 4am".to_string(),
+    };
+    let signed = sign_announcement(announcement, &key).unwrap();
+    assert!(verify_announcement(&signed, exit).is_ok());
+
+    let mut changed = signed.clone();
+    changed.announcement.message = "Prices are going up tenfold".to_string();
+    assert!(verify_announcement(&changed, exit).is_err());
+    let mut extended = signed.clone();
+    extended.announcement.expires = None;
+    assert!(verify_announcement(&extended, exit).is_err());
+
+    let someone_else: Address = "0x0000000000000000000000000000000000000001"
+        .parse()
+        .unwrap();
+    assert!(verify_announcement(&signed, someone_else).is_err());
+}
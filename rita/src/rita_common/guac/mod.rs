@@ -0,0 +1,474 @@
+//! Integration with Guac, the payment channel light client that runs alongside Rita. A channel
+//! payment is a signed balance update rather than a transaction so it costs nothing and is final
+//! as soon as both sides have it. Guac handles the channel state machine and talking to the
+//! chain, we ask it to pay and forward the resulting channel summary to the neighbor in place of
+//! a txid. On the receiving side the summary is checked against what our own Guac has seen of the
+//! channel before anything is credited, and summaries that contradict our channel state are
+//! disputed.
+//!
+//! Neighbors are credited with how much the channel total went up since we last credited it, not
+//! with the amount in the PaymentTx, so a lost payment notification is made up for by the next one.
+//! What we have credited is kept on disk, otherwise the first update on every channel after a
+//! restart would be credited from scratch.
+
+use crate::rita_common::debt_keeper::DebtKeeper;
+use crate::rita_common::debt_keeper::PaymentReceived;
+use crate::rita_common::debt_keeper::PaymentSucceeded;
+use crate::rita_common::usage_tracker::UpdatePayments;
+use crate::rita_common::usage_tracker::UsageTracker;
+use crate::rita_common::utils::save_json;
+use crate::SETTING;
+use actix::{Actor, Arbiter, Context, Handler, Message, Supervised, SystemService};
+use actix_web::client;
+use actix_web::client::ClientResponse;
+use actix_web::HttpMessage;
+use althea_types::{ChannelSummary, PaymentTx};
+use clarity::Address;
+use failure::Error;
+use futures01::{future, Future};
+use num256::Uint256;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use settings::RitaCommonSettings;
+use std::collections::HashMap;
+use std::fs::File;
+use std::time::Duration;
+
+pub const GUAC_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A channel as reported by Guac
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChannelState {
+    pub channel_id: Uint256,
+    pub counterparty: Address,
+    pub sequence_number: Uint256,
+    /// what we have locked up in the channel
+    pub deposit: Uint256,
+    /// everything we have sent over the channel
+    pub total_paid: Uint256,
+    /// everything the counterparty has sent over the channel
+    pub total_received: Uint256,
+    pub open: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OpenChannelRequest {
+    pub counterparty: Address,
+    pub deposit: Uint256,
+}
+
+#[derive(Serialize)]
+struct PayRequest {
+    amount: Uint256,
+}
+
+#[derive(Debug, Fail)]
+pub enum ChannelError {
+    #[fail(display = "Channel update conflicts with our channel state: {}", _0)]
+    Mismatch(String),
+    #[fail(display = "Channel update has already been credited")]
+    Replay,
+}
+
+fn guac_url(path: &str) -> Result<String, Error> {
+    match SETTING.get_payment().guac_url.clone() {
+        Some(url) => Ok(format!("{}{}", url, path)),
+        None => bail!("Guac is not configured"),
+    }
+}
+
+fn parse_response<T: DeserializeOwned + 'static>(
+    response: ClientResponse,
+) -> Box<dyn Future<Item = T, Error = Error>> {
+    if !response.status().is_success() {
+        return Box::new(future::err(format_err!(
+            "Guac request failed with {}",
+            response.status()
+        )));
+    }
+    Box::new(response.json().from_err())
+}
+
+fn guac_get<T: DeserializeOwned + 'static>(path: &str) -> Box<dyn Future<Item = T, Error = Error>> {
+    let url = match guac_url(path) {
+        Ok(val) => val,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let request = match client::get(&url).finish() {
+        Ok(val) => val,
+        Err(e) => return Box::new(future::err(format_err!("{:?}", e))),
+    };
+    Box::new(
+        request
+            .send()
+            .timeout(GUAC_TIMEOUT)
+            .from_err()
+            .and_then(parse_response),
+    )
+}
+
+fn guac_post<B: Serialize, T: DeserializeOwned + 'static>(
+    path: &str,
+    body: &B,
+) -> Box<dyn Future<Item = T, Error = Error>> {
+    let url = match guac_url(path) {
+        Ok(val) => val,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let request = match client::post(&url).json(body) {
+        Ok(val) => val,
+        Err(e) => return Box::new(future::err(format_err!("{:?}", e))),
+    };
+    Box::new(
+        request
+            .send()
+            .timeout(GUAC_TIMEOUT)
+            .from_err()
+            .and_then(parse_response),
+    )
+}
+
+pub fn get_channels() -> Box<dyn Future<Item = Vec<ChannelState>, Error = Error>> {
+    guac_get("/channels")
+}
+
+pub fn get_channel(counterparty: Address) -> Box<dyn Future<Item = ChannelState, Error = Error>> {
+    guac_get(&format!("/channels/{:#x}", counterparty))
+}
+
+pub fn open_channel(
+    req: OpenChannelRequest,
+) -> Box<dyn Future<Item = ChannelState, Error = Error>> {
+    guac_post("/channels/open", &req)
+}
+
+pub fn close_channel(counterparty: Address) -> Box<dyn Future<Item = ChannelState, Error = Error>> {
+    guac_post(&format!("/channels/{:#x}/close", counterparty), &())
+}
+
+fn dispute_channel(
+    counterparty: Address,
+    summary: &ChannelSummary,
+) -> Box<dyn Future<Item = serde_json::Value, Error = Error>> {
+    guac_post(&format!("/channels/{:#x}/dispute", counterparty), summary)
+}
+
+/// Pays the neighbor over our channel with them, fails without paying if we have no open channel
+/// so that the caller can fall back to paying on chain
+pub fn make_channel_payment(mut pmt: PaymentTx) -> impl Future<Item = (), Error = Error> {
+    let request = PayRequest {
+        amount: pmt.amount.clone(),
+    };
+    guac_post(
+        &format!("/channels/{:#x}/pay", pmt.to.eth_address),
+        &request,
+    )
+    .and_then(move |summary: ChannelSummary| {
+        info!(
+            "Paid {} to {} over channel {:#066x}",
+            pmt.amount, pmt.to.wg_public_key, summary.channel_id
+        );
+        pmt.channel = Some(summary);
+        // unlike a transaction the update is final as soon as Guac has made it
+        DebtKeeper::from_registry().do_send(PaymentSucceeded {
+            to: pmt.to,
+            amount: pmt.amount.clone(),
+        });
+        UsageTracker::from_registry().do_send(UpdatePayments {
+            payment: pmt.clone(),
+        });
+        notify_neighbor(pmt);
+        Ok(())
+    })
+}
+
+/// Sends the channel summary to the neighbor, if this fails they will still be credited for the
+/// payment when they get the next summary so there's no need to retry
+fn notify_neighbor(pmt: PaymentTx) {
+    // testing hack
+    let neighbor_url = if cfg!(not(test)) {
+        format!(
+            "http://[{}]:{}/make_payment",
+            pmt.to.mesh_ip,
+            SETTING.get_network().rita_contact_port
+        )
+    } else {
+        String::from("http://127.0.0.1:1234/make_payment")
+    };
+    let request = match client::post(&neighbor_url).json(&pmt) {
+        Ok(val) => val,
+        Err(e) => {
+            error!("Failed to serialize channel payment {:?}", e);
+            return;
+        }
+    };
+    Arbiter::spawn(request.send().timeout(GUAC_TIMEOUT).then(move |res| {
+        match res {
+            Ok(ref msg) if msg.status().is_success() => {}
+            Ok(msg) => warn!(
+                "Neighbor {} rejected our channel payment with {}",
+                pmt.to.wg_public_key,
+                msg.status()
+            ),
+            Err(e) => warn!(
+                "Failed to notify {} of our channel payment {:?}",
+                pmt.to.wg_public_key, e
+            ),
+        }
+        Ok(())
+    }));
+}
+
+/// Checks a channel summary from a neighbor against our channel state, returning the amount to
+/// credit them with
+pub fn check_channel_payment(
+    state: &ChannelState,
+    summary: &ChannelSummary,
+    claimed: &Uint256,
+    credited: Option<&Uint256>,
+) -> Result<Uint256, ChannelError> {
+    if state.channel_id != summary.channel_id {
+        return Err(ChannelError::Mismatch(
+            "it's for a different channel".to_string(),
+        ));
+    }
+    if summary.sequence_number > state.sequence_number {
+        return Err(ChannelError::Mismatch(
+            "it's newer than any update we have".to_string(),
+        ));
+    }
+    if summary.total_paid > state.total_received {
+        return Err(ChannelError::Mismatch(
+            "it claims more than we have received".to_string(),
+        ));
+    }
+    if summary.sequence_number == state.sequence_number
+        && summary.total_paid != state.total_received
+    {
+        return Err(ChannelError::Mismatch(
+            "it has a different total for the same update".to_string(),
+        ));
+    }
+    match credited {
+        Some(credited) if summary.total_paid <= *credited => Err(ChannelError::Replay),
+        Some(credited) => Ok(summary.total_paid.clone() - credited.clone()),
+        // the first update we have seen since starting up, we don't know what was credited
+        // before so we go with the claimed amount as long as the channel covers it
+        None => {
+            if *claimed > summary.total_paid {
+                Ok(summary.total_paid.clone())
+            } else {
+                Ok(claimed.clone())
+            }
+        }
+    }
+}
+
+pub struct Guac {
+    /// the channel total we have credited so far, by channel id
+    credited: HashMap<Uint256, Uint256>,
+}
+
+impl Actor for Guac {
+    type Context = Context<Self>;
+}
+
+impl Supervised for Guac {}
+impl SystemService for Guac {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("Guac started");
+    }
+}
+
+impl Default for Guac {
+    fn default() -> Guac {
+        Guac {
+            credited: load_credited(),
+        }
+    }
+}
+
+/// The channel totals credited before we last stopped, an unreadable file is logged and treated
+/// as empty
+fn load_credited() -> HashMap<Uint256, Uint256> {
+    let path = SETTING.get_payment().guac_credited_file.clone();
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        // nothing has been credited yet
+        Err(_) => return HashMap::new(),
+    };
+    match serde_json::from_reader::<_, Vec<(Uint256, Uint256)>>(file) {
+        Ok(credited) => credited.into_iter().collect(),
+        Err(e) => {
+            error!("Failed to read credited channel totals {:?}", e);
+            HashMap::new()
+        }
+    }
+}
+
+fn save_credited(credited: &HashMap<Uint256, Uint256>) -> Result<(), Error> {
+    let path = SETTING.get_payment().guac_credited_file.clone();
+    let credited: Vec<(&Uint256, &Uint256)> = credited.iter().collect();
+    save_json(&path, &credited)
+}
+
+/// A PaymentTx with a channel summary from a neighbor, once it checks out against the channel
+/// state Guac has the neighbor is credited
+#[derive(Message)]
+pub struct ChannelPaymentReceived(pub PaymentTx);
+
+impl Handler<ChannelPaymentReceived> for Guac {
+    type Result = ();
+
+    fn handle(&mut self, msg: ChannelPaymentReceived, _ctx: &mut Context<Self>) -> Self::Result {
+        let pmt = msg.0;
+        if pmt.channel.is_none() {
+            error!("Channel payment without a channel summary {:?}", pmt);
+            return;
+        }
+        Arbiter::spawn(get_channel(pmt.from.eth_address).then(move |res| {
+            match res {
+                Ok(state) => Guac::from_registry().do_send(CreditChannelPayment { pmt, state }),
+                Err(e) => warn!(
+                    "Could not get channel state to check payment from {} {:?}",
+                    pmt.from.wg_public_key, e
+                ),
+            }
+            Ok(())
+        }));
+    }
+}
+
+#[derive(Message)]
+struct CreditChannelPayment {
+    pmt: PaymentTx,
+    state: ChannelState,
+}
+
+impl Handler<CreditChannelPayment> for Guac {
+    type Result = ();
+
+    fn handle(&mut self, msg: CreditChannelPayment, _ctx: &mut Context<Self>) -> Self::Result {
+        let mut pmt = msg.pmt;
+        let summary = pmt.channel.clone().unwrap();
+        let from = pmt.from;
+
+        match check_channel_payment(
+            &msg.state,
+            &summary,
+            &pmt.amount,
+            self.credited.get(&summary.channel_id),
+        ) {
+            Ok(amount) => {
+                if amount != pmt.amount {
+                    info!(
+                        "Crediting {} with {} for a channel payment claiming {}",
+                        from.wg_public_key, amount, pmt.amount
+                    );
+                }
+                self.credited
+                    .insert(summary.channel_id.clone(), summary.total_paid.clone());
+                if let Err(e) = save_credited(&self.credited) {
+                    error!("Failed to save credited channel totals {:?}", e);
+                }
+                pmt.amount = amount;
+                DebtKeeper::from_registry().do_send(PaymentReceived {
+                    from,
+                    amount: pmt.amount.clone(),
+                });
+                UsageTracker::from_registry().do_send(UpdatePayments { payment: pmt });
+            }
+            Err(ChannelError::Replay) => {
+                warn!(
+                    "Ignoring replayed channel payment from {}",
+                    from.wg_public_key
+                );
+            }
+            Err(e) => {
+                error!(
+                    "Rejecting channel payment from {}, {}",
+                    from.wg_public_key, e
+                );
+                Arbiter::spawn(
+                    dispute_channel(from.eth_address, &summary).then(move |res| {
+                        if let Err(e) = res {
+                            error!("Failed to raise channel dispute {:?}", e);
+                        }
+                        Ok(())
+                    }),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(sequence_number: u32, total_received: u32) -> ChannelState {
+        ChannelState {
+            channel_id: 1u32.into(),
+            counterparty: "0x0000000000000000000000000000000000000001"
+                .parse()
+                .unwrap(),
+            sequence_number: sequence_number.into(),
+            deposit: 0u32.into(),
+            total_paid: 0u32.into(),
+            total_received: total_received.into(),
+            open: true,
+        }
+    }
+
+    fn summary(sequence_number: u32, total_paid: u32) -> ChannelSummary {
+        ChannelSummary {
+            channel_id: 1u32.into(),
+            sequence_number: sequence_number.into(),
+            total_paid: total_paid.into(),
+        }
+    }
+
+    #[test]
+    fn test_check_channel_payment() {
+        let claimed: Uint256 = 10u32.into();
+        let credited: Uint256 = 90u32.into();
+        let ours = state(5, 100);
+
+        assert_eq!(
+            check_channel_payment(&ours, &summary(5, 100), &claimed, Some(&credited)).unwrap(),
+            10u32.into()
+        );
+        // a missed notification is made up for
+        let credited_earlier: Uint256 = 70u32.into();
+        assert_eq!(
+            check_channel_payment(&ours, &summary(5, 100), &claimed, Some(&credited_earlier))
+                .unwrap(),
+            30u32.into()
+        );
+        // older updates are fine but only credit once
+        assert!(
+            match check_channel_payment(&ours, &summary(4, 90), &claimed, Some(&credited)) {
+                Err(ChannelError::Replay) => true,
+                _ => false,
+            }
+        );
+        // without history the claim is capped by the channel total
+        let big_claim: Uint256 = 1000u32.into();
+        assert_eq!(
+            check_channel_payment(&ours, &summary(5, 100), &big_claim, None).unwrap(),
+            100u32.into()
+        );
+
+        for bad in [summary(6, 110), summary(5, 120), summary(5, 95)].iter() {
+            assert!(
+                match check_channel_payment(&ours, bad, &claimed, Some(&credited)) {
+                    Err(ChannelError::Mismatch(_)) => true,
+                    _ => false,
+                }
+            );
+        }
+        let mut other_channel = summary(5, 100);
+        other_channel.channel_id = 2u32.into();
+        assert!(check_channel_payment(&ours, &other_channel, &claimed, None).is_err());
+    }
+}
@@ -0,0 +1,314 @@
+//! Challenge-response authentication for the hello exchange. Without it anybody who can reach
+//! the hello port can get us to negotiate a tunnel while claiming an arbitrary identity.
+//!
+//! Before saying hello the initiator asks for a challenge, which the responder creates without
+//! keeping any state by including a mac over it under a random key generated at startup. The
+//! initiator answers with an hmac of the challenge keyed with the Diffie-Hellman shared secret
+//! of the two wg keys, which can only be computed by holding one of the private keys. The
+//! responder checks that before opening a tunnel and proves itself in the same way in its
+//! response, using a different label so a proof can't be reflected back at its sender.
+//!
+//! Older nodes that don't support this are still accepted unless require_hello_auth is set, but
+//! a node advertising HELLO_AUTH always authenticates. The flag is only what the sender says, so
+//! the wg keys that have authenticated with us are remembered in the settings as well. Their
+//! owners won't go back to a version without auth, so an unauthenticated hello claiming one of
+//! them is somebody trying to pass themselves off as an old node and is refused.
+//!
+//! That still leaves keys we've never heard from open to impersonation until require_hello_auth
+//! is set. It stays off by default while routers on releases from before hello auth are still in
+//! the field, operators of meshes that have all upgraded can turn it on now. Once hello auth has
+//! been in every supported release for a full release cycle the default is flipped to on, and the
+//! release after that drops the unauthenticated path altogether.
+
+use super::our_features;
+use crate::rita_common::utils::now_secs;
+use crate::SETTING;
+use althea_types::{FeatureFlags, HelloAuth, HelloChallenge, LocalIdentity, WgKey};
+use failure::Error;
+use settings::RitaCommonSettings;
+use sodiumoxide::crypto::auth::hmacsha256;
+use sodiumoxide::crypto::box_;
+use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::{PublicKey, SecretKey};
+use sodiumoxide::randombytes::randombytes_into;
+
+/// How long a challenge may be answered for, hellos are sent right after getting one
+const CHALLENGE_LIFETIME: u64 = 30;
+const HELLO_LABEL: &[u8] = b"althea hello";
+const RESPONSE_LABEL: &[u8] = b"althea hello response";
+
+lazy_static! {
+    static ref CHALLENGE_KEY: hmacsha256::Key = hmacsha256::gen_key();
+}
+
+/// If a hello from the node with this wg key advertising `their_features` must be authenticated
+pub fn auth_required(their_key: &WgKey, their_features: FeatureFlags) -> bool {
+    let network = SETTING.get_network();
+    must_authenticate(
+        our_features(),
+        their_features,
+        network.require_hello_auth,
+        network.authenticated_neighbors.contains(their_key),
+    )
+}
+
+fn must_authenticate(
+    ours: FeatureFlags,
+    theirs: FeatureFlags,
+    require_hello_auth: bool,
+    authenticated_before: bool,
+) -> bool {
+    require_hello_auth
+        || authenticated_before
+        || ours.negotiate(theirs).contains(FeatureFlags::HELLO_AUTH)
+}
+
+/// Remembers that the node with this wg key has authenticated, its hellos always must from now on
+pub fn remember_authenticated(their_key: WgKey) {
+    if !SETTING
+        .get_network()
+        .authenticated_neighbors
+        .contains(&their_key)
+    {
+        info!(
+            "{} has authenticated, its hellos always must now",
+            their_key
+        );
+        SETTING
+            .get_network_mut()
+            .authenticated_neighbors
+            .insert(their_key);
+    }
+}
+
+fn our_pubkey() -> Result<WgKey, Error> {
+    match SETTING.get_network().wg_public_key {
+        Some(key) => Ok(key),
+        None => bail!("No wg key configured yet"),
+    }
+}
+
+fn challenge_data(requester: &WgKey, nonce: &[u8; 32], expires: u64) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(requester.as_ref());
+    data.extend_from_slice(nonce);
+    data.extend_from_slice(&expires.to_be_bytes());
+    data
+}
+
+fn proof_data(label: &[u8], challenge: &HelloChallenge, sender: &WgKey) -> Vec<u8> {
+    let mut data = label.to_vec();
+    data.extend_from_slice(challenge.pubkey.as_ref());
+    data.extend_from_slice(sender.as_ref());
+    data.extend_from_slice(&challenge.nonce);
+    data.extend_from_slice(&challenge.expires.to_be_bytes());
+    data
+}
+
+/// The hmac key both ends of the exchange can derive, and nobody else
+fn shared_key(our_secretkey: WgKey, their_pubkey: WgKey) -> hmacsha256::Key {
+    let our_secretkey: SecretKey = our_secretkey.into();
+    let their_pubkey: PublicKey = their_pubkey.into();
+    let box_::PrecomputedKey(key) = box_::precompute(&their_pubkey, &our_secretkey);
+    hmacsha256::Key(key)
+}
+
+fn our_shared_key(their_pubkey: WgKey) -> Result<hmacsha256::Key, Error> {
+    match SETTING.get_network().wg_private_key {
+        Some(key) => Ok(shared_key(key, their_pubkey)),
+        None => bail!("No wg key configured yet"),
+    }
+}
+
+fn make_proof(
+    label: &[u8],
+    challenge: &HelloChallenge,
+    sender: &WgKey,
+    key: &hmacsha256::Key,
+) -> [u8; 32] {
+    let hmacsha256::Tag(proof) =
+        hmacsha256::authenticate(&proof_data(label, challenge, sender), key);
+    proof
+}
+
+fn check_proof(label: &[u8], auth: &HelloAuth, sender: &WgKey, key: &hmacsha256::Key) -> bool {
+    hmacsha256::verify(
+        &hmacsha256::Tag(auth.proof),
+        &proof_data(label, &auth.challenge, sender),
+        key,
+    )
+}
+
+/// Creates a challenge for the node with the given wg key
+pub fn new_challenge(requester: &WgKey) -> Result<HelloChallenge, Error> {
+    let mut nonce = [0u8; 32];
+    randombytes_into(&mut nonce);
+    let expires = now_secs() + CHALLENGE_LIFETIME;
+    let hmacsha256::Tag(mac) =
+        hmacsha256::authenticate(&challenge_data(requester, &nonce, expires), &CHALLENGE_KEY);
+    Ok(HelloChallenge {
+        pubkey: our_pubkey()?,
+        nonce,
+        expires,
+        mac,
+    })
+}
+
+/// Answers a challenge we got from a neighbor, to be sent along with our hello
+pub fn answer_challenge(challenge: HelloChallenge) -> Result<HelloAuth, Error> {
+    let key = our_shared_key(challenge.pubkey)?;
+    let proof = make_proof(HELLO_LABEL, &challenge, &our_pubkey()?, &key);
+    Ok(HelloAuth { challenge, proof })
+}
+
+/// Checks a hello from a neighbor, which must answer a challenge we handed out to the
+/// wg key it claims
+pub fn verify_hello(their_id: &LocalIdentity) -> Result<(), Error> {
+    let auth = match their_id.auth {
+        Some(auth) => auth,
+        None => bail!("Hello is not authenticated"),
+    };
+    let challenge = auth.challenge;
+    let their_pubkey = their_id.global.wg_public_key;
+
+    if challenge.pubkey != our_pubkey()? {
+        bail!("Hello answers a challenge from somebody else");
+    }
+    if !hmacsha256::verify(
+        &hmacsha256::Tag(challenge.mac),
+        &challenge_data(&their_pubkey, &challenge.nonce, challenge.expires),
+        &CHALLENGE_KEY,
+    ) {
+        bail!(
+            "Hello answers a challenge we did not issue to {}",
+            their_pubkey
+        );
+    }
+    if challenge.expires < now_secs() {
+        bail!("Hello answers an expired challenge");
+    }
+    let key = our_shared_key(their_pubkey)?;
+    if !check_proof(HELLO_LABEL, &auth, &their_pubkey, &key) {
+        bail!("Hello from {} has an invalid proof", their_pubkey);
+    }
+    Ok(())
+}
+
+/// Our proof for a hello response, answering the challenge the neighbor answered
+pub fn respond_to_hello(their_id: &LocalIdentity) -> Result<Option<HelloAuth>, Error> {
+    let challenge = match their_id.auth {
+        Some(auth) => auth.challenge,
+        // an older node that won't check our response
+        None => return Ok(None),
+    };
+    let key = our_shared_key(their_id.global.wg_public_key)?;
+    let proof = make_proof(RESPONSE_LABEL, &challenge, &our_pubkey()?, &key);
+    Ok(Some(HelloAuth { challenge, proof }))
+}
+
+/// Checks a hello response, the neighbor must be the one who handed us the challenge and
+/// must prove it holds that key
+pub fn verify_response(challenge: &HelloChallenge, their_id: &LocalIdentity) -> Result<(), Error> {
+    let auth = match their_id.auth {
+        Some(auth) => auth,
+        None => bail!("Hello response is not authenticated"),
+    };
+    let their_pubkey = their_id.global.wg_public_key;
+    if auth.challenge != *challenge || their_pubkey != challenge.pubkey {
+        bail!("Hello response is for a different challenge");
+    }
+    let key = our_shared_key(their_pubkey)?;
+    if !check_proof(RESPONSE_LABEL, &auth, &their_pubkey, &key) {
+        bail!("Hello response from {} has an invalid proof", their_pubkey);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keypair() -> (WgKey, WgKey) {
+        let (public, secret) = box_::gen_keypair();
+        (WgKey::from(public.0), WgKey::from(secret.0))
+    }
+
+    #[test]
+    fn test_hello_proof() {
+        let (initiator_pub, initiator_secret) = keypair();
+        let (responder_pub, responder_secret) = keypair();
+        let (imposter_pub, imposter_secret) = keypair();
+        let challenge = HelloChallenge {
+            pubkey: responder_pub,
+            nonce: [7; 32],
+            expires: 0,
+            mac: [0; 32],
+        };
+
+        // both sides derive the same key
+        let initiator_key = shared_key(initiator_secret, responder_pub);
+        let responder_key = shared_key(responder_secret, initiator_pub);
+        let auth = HelloAuth {
+            challenge,
+            proof: make_proof(HELLO_LABEL, &challenge, &initiator_pub, &initiator_key),
+        };
+        assert!(check_proof(
+            HELLO_LABEL,
+            &auth,
+            &initiator_pub,
+            &responder_key
+        ));
+        // a proof can't be reflected back as a response
+        assert!(!check_proof(
+            RESPONSE_LABEL,
+            &auth,
+            &initiator_pub,
+            &responder_key
+        ));
+
+        // somebody claiming the initiators key without holding it can't produce a proof
+        let imposter_key = shared_key(imposter_secret, responder_pub);
+        let forged = HelloAuth {
+            challenge,
+            proof: make_proof(HELLO_LABEL, &challenge, &initiator_pub, &imposter_key),
+        };
+        assert!(!check_proof(
+            HELLO_LABEL,
+            &forged,
+            &initiator_pub,
+            &responder_key
+        ));
+        // and a proof for their own key doesn't pass for the initiators
+        let own = HelloAuth {
+            challenge,
+            proof: make_proof(HELLO_LABEL, &challenge, &imposter_pub, &imposter_key),
+        };
+        assert!(!check_proof(
+            HELLO_LABEL,
+            &own,
+            &initiator_pub,
+            &responder_key
+        ));
+    }
+
+    #[test]
+    fn test_must_authenticate() {
+        let ours = FeatureFlags::HELLO_AUTH | FeatureFlags::PAYMENT_RECEIPTS;
+        let old = FeatureFlags::default();
+        assert!(!must_authenticate(ours, old, false, false));
+        assert!(must_authenticate(ours, old, true, false));
+        assert!(must_authenticate(
+            ours,
+            FeatureFlags::HELLO_AUTH,
+            false,
+            false
+        ));
+        assert!(!must_authenticate(
+            ours,
+            FeatureFlags::PAYMENT_RECEIPTS,
+            false,
+            false
+        ));
+        // leaving out the flag doesn't get a key that has authenticated before out of it
+        assert!(must_authenticate(ours, old, false, true));
+    }
+}
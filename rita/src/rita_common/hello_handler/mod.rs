@@ -3,20 +3,45 @@
 //! The call path goes like this
 //!
 //! peer listener gets udp ImHere -> TunnelManager tries to contact peer with hello
-//! -> hello manager actually manages that request -> hello manager calls back to tunnel manager
+//! -> hello manager gets a challenge from the peer and sends the hello with its answer
+//! -> hello manager checks the peer's proof in the response -> hello manager calls back to tunnel manager
 
+pub mod auth;
+
+use self::auth::{answer_challenge, auth_required, remember_authenticated, verify_response};
 use crate::rita_common::peer_listener::Peer;
 use crate::rita_common::tunnel_manager::id_callback::IdentityCallback;
 use crate::rita_common::tunnel_manager::{PortCallback, TunnelManager};
+use crate::SETTING;
 use actix::{Actor, Context, Handler, Message, ResponseFuture, Supervised, SystemService};
 use actix_web::client::Connection;
 use actix_web::{client, HttpMessage, Result};
-use althea_types::LocalIdentity;
+use althea_types::{FeatureFlags, HelloChallenge, LocalIdentity};
 use failure::Error;
-use futures01::future::ok as future_ok;
+use futures01::future;
 use futures01::Future;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use settings::RitaCommonSettings;
+use std::net::SocketAddr;
 use tokio::net::TcpStream as TokioTcpStream;
 
+/// The optional behaviors this build supports, advertised in every hello and hello response.
+/// IPV4_TUNNELS and MULTI_EXIT are left out, this build does neither
+pub fn our_features() -> FeatureFlags {
+    let mut features = FeatureFlags::HELLO_AUTH
+        | FeatureFlags::PAYMENT_RECEIPTS
+        | FeatureFlags::COUNTER_RECONCILIATION
+        | FeatureFlags::PAYMENT_STATUS;
+    if SETTING.get_payment().debt_journal.enabled {
+        features = features | FeatureFlags::DEBT_JOURNAL;
+    }
+    if SETTING.get_network().service_discovery.enabled {
+        features = features | FeatureFlags::SERVICE_DISCOVERY;
+    }
+    features
+}
+
 #[derive(Default)]
 pub struct HelloHandler;
 
@@ -41,6 +66,40 @@ impl Message for Hello {
     type Result = Result<(), Error>;
 }
 
+/// Posts json to a path on the peer's hello port and parses the response
+pub fn post_to_peer<T: Serialize, R: DeserializeOwned + 'static>(
+    to: SocketAddr,
+    path: &str,
+    body: &T,
+) -> Box<dyn Future<Item = R, Error = Error>> {
+    let endpoint = format!("http://[{}]:{}{}", to.ip(), to.port(), path);
+    let body = match serde_json::to_value(body) {
+        Ok(val) => val,
+        Err(e) => return Box::new(future::err(e.into())),
+    };
+
+    Box::new(
+        TokioTcpStream::connect(&to)
+            .from_err()
+            .and_then(move |stream| {
+                let request = client::post(&endpoint)
+                    .with_connection(Connection::from_stream(stream))
+                    .json(body);
+                match request {
+                    Ok(request) => future::ok(request),
+                    Err(e) => future::err(format_err!("{:?}", e)),
+                }
+            })
+            .and_then(|request| {
+                trace!("sending hello request {:?}", request);
+                request
+                    .send()
+                    .from_err()
+                    .and_then(|response| response.json().from_err())
+            }),
+    )
+}
+
 /// Handler for sending hello messages, it's important that any path by which this handler
 /// may crash is handled such that ports are returned to tunnel manager, otherwise we end
 /// up with a port leak which will eventually crash the program
@@ -49,73 +108,76 @@ impl Handler<Hello> for HelloHandler {
     fn handle(&mut self, msg: Hello, _: &mut Self::Context) -> Self::Result {
         trace!("Sending Hello {:?}", msg);
 
-        let stream = TokioTcpStream::connect(&msg.to.contact_socket);
-
-        let endpoint = format!(
-            "http://[{}]:{}/hello",
-            msg.to.contact_socket.ip(),
-            msg.to.contact_socket.port()
-        );
-
-        Box::new(stream.then(move |stream| {
-            trace!("stream status {:?}, to: {:?}", stream, &msg.to);
-            let mut network_request = client::post(&endpoint);
-            let peer = msg.to;
-            let wg_port = msg.my_id.wg_port;
-
-            let stream = match stream {
-                Ok(s) => s,
-                Err(e) => {
-                    trace!("Error getting stream from hello {:?}", e);
-                    TunnelManager::from_registry().do_send(PortCallback(wg_port));
-                    return Box::new(future_ok(())) as Box<dyn Future<Item = (), Error = Error>>;
-                }
-            };
-
-            let network_request = network_request.with_connection(Connection::from_stream(stream));
-
-            let network_json = network_request.json(&msg.my_id);
-
-            let network_json = match network_json {
-                Ok(n) => n,
+        let peer = msg.to;
+        let my_id = msg.my_id;
+        let wg_port = my_id.wg_port;
+        let socket = peer.contact_socket;
+
+        let challenge: Box<dyn Future<Item = HelloChallenge, Error = Error>> =
+            post_to_peer(socket, "/hello/challenge", &my_id.global.wg_public_key);
+
+        let hello = challenge.then(move |challenge| {
+            let mut my_id = my_id;
+            let challenge = match challenge {
+                Ok(challenge) => match answer_challenge(challenge) {
+                    Ok(auth) => {
+                        my_id.auth = Some(auth);
+                        Some(challenge)
+                    }
+                    Err(e) => {
+                        return Box::new(future::err(e))
+                            as Box<dyn Future<Item = LocalIdentity, Error = Error>>
+                    }
+                },
+                // older nodes don't hand out challenges, a node that says it does is checked
+                // once its response tells us what it supports
                 Err(e) => {
-                    trace!("Error serializing our request {:?}", e);
-                    TunnelManager::from_registry().do_send(PortCallback(wg_port));
-                    return Box::new(future_ok(())) as Box<dyn Future<Item = (), Error = Error>>;
+                    if SETTING.get_network().require_hello_auth {
+                        return Box::new(future::err(format_err!(
+                            "Could not get hello challenge {:?}",
+                            e
+                        )))
+                            as Box<dyn Future<Item = LocalIdentity, Error = Error>>;
+                    }
+                    trace!("No hello challenge from {}, {:?}", socket, e);
+                    None
                 }
             };
 
-            trace!("sending hello request {:?}", network_json);
-
-            let http_result = network_json.send().then(move |response| {
-                trace!("got response from Hello {:?}", response);
-                match response {
-                    Ok(response) => Box::new(response.json().then(move |val| match val {
-                        Ok(val) => {
-                            TunnelManager::from_registry().do_send(IdentityCallback::new(
-                                val,
-                                peer,
-                                Some(wg_port),
-                                None,
-                            ));
-                            Ok(())
+            Box::new(post_to_peer(socket, "/hello", &my_id).and_then(
+                move |their_id: LocalIdentity| {
+                    let their_key = their_id.global.wg_public_key;
+                    match challenge {
+                        Some(challenge) => {
+                            verify_response(&challenge, &their_id)?;
+                            remember_authenticated(their_key);
                         }
-                        Err(e) => {
-                            trace!("Got error deserializing Hello {:?}", e);
-                            TunnelManager::from_registry().do_send(PortCallback(wg_port));
-                            Ok(())
+                        None if auth_required(&their_key, their_id.features) => {
+                            bail!("{} supports hello auth but gave us no challenge", socket)
                         }
-                    }))
-                        as Box<dyn Future<Item = (), Error = Error>>,
-                    Err(e) => {
-                        trace!("Got error getting Hello response {:?}", e);
-                        TunnelManager::from_registry().do_send(PortCallback(wg_port));
-                        Box::new(future_ok(())) as Box<dyn Future<Item = (), Error = Error>>
+                        None => {}
                     }
+                    Ok(their_id)
+                },
+            )) as Box<dyn Future<Item = LocalIdentity, Error = Error>>
+        });
+
+        Box::new(hello.then(move |res| {
+            match res {
+                Ok(their_id) => {
+                    TunnelManager::from_registry().do_send(IdentityCallback::new(
+                        their_id,
+                        peer,
+                        Some(wg_port),
+                        None,
+                    ));
                 }
-            });
-
-            Box::new(http_result) as Box<dyn Future<Item = (), Error = Error>>
+                Err(e) => {
+                    trace!("Hello to {:?} failed with {:?}", peer, e);
+                    TunnelManager::from_registry().do_send(PortCallback(wg_port));
+                }
+            }
+            Ok(())
         }))
     }
 }
@@ -0,0 +1,260 @@
+//! Pushes changes to dashboards connected to `/live` instead of having them poll a handful of
+//! endpoints every second. Actors publish what they already have on hand, the current neighbors
+//! and debts every round and usage samples and watchdog events as they happen, and only what's
+//! changed since the last round goes out to each session as json. A new session first gets
+//! everything as it stands. Nothing is published while no one is connected, so the state here
+//! is dropped when the last session leaves and rebuilt from the next round when one connects.
+
+use crate::rita_common::tunnel_manager::Neighbor;
+use crate::rita_common::usage_tracker::UsageType;
+use crate::rita_common::watchdog::WatchdogEvent;
+use ::actix::{Actor, Context, Handler, Message, Recipient, Supervised, SystemService};
+use althea_types::{Identity, LocalIdentity};
+use num256::Int256;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Kept outside the actor so that publishers can skip the work when no one is listening
+static SUBSCRIBERS: AtomicUsize = AtomicUsize::new(0);
+
+/// If any dashboard is connected, publishers should check this before building an update
+pub fn has_subscribers() -> bool {
+    SUBSCRIBERS.load(Ordering::Relaxed) > 0
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct NeighborUpdate {
+    pub identity: LocalIdentity,
+    pub iface_name: String,
+    pub tunnel_ip: IpAddr,
+    pub speed_limit: Option<usize>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DebtUpdate {
+    pub identity: Identity,
+    pub debt: Int256,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct UsageSample {
+    pub kind: UsageType,
+    /// seconds since the unix epoch
+    pub time: u64,
+    pub up: u64,
+    pub down: u64,
+    pub price: u32,
+}
+
+/// What's sent to sessions
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LiveUpdate {
+    Neighbors {
+        updated: Vec<NeighborUpdate>,
+        /// interfaces of tunnels that went away
+        removed: Vec<String>,
+    },
+    Debts {
+        updated: Vec<DebtUpdate>,
+        removed: Vec<Identity>,
+    },
+    Usage(UsageSample),
+    Event(WatchdogEvent),
+}
+
+/// The entries of `new` that are missing from or different in `old`, and the keys of `old` that
+/// are missing from `new`
+fn diff_maps<K, V>(old: &HashMap<K, V>, new: &HashMap<K, V>) -> (Vec<(K, V)>, Vec<K>)
+where
+    K: Eq + Hash + Clone,
+    V: PartialEq + Clone,
+{
+    let updated = new
+        .iter()
+        .filter(|(key, value)| old.get(key) != Some(value))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    let removed = old
+        .keys()
+        .filter(|key| !new.contains_key(key))
+        .cloned()
+        .collect();
+    (updated, removed)
+}
+
+/// A session's outbox, the json of one update
+pub struct Push(pub String);
+
+impl Message for Push {
+    type Result = ();
+}
+
+#[derive(Default)]
+pub struct LiveUpdates {
+    subscribers: Vec<Recipient<Push>>,
+    /// by interface name, as of the last round
+    neighbors: HashMap<String, NeighborUpdate>,
+    debts: HashMap<Identity, Int256>,
+}
+
+impl Actor for LiveUpdates {
+    type Context = Context<Self>;
+}
+
+impl Supervised for LiveUpdates {}
+impl SystemService for LiveUpdates {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("LiveUpdates started");
+    }
+}
+
+impl LiveUpdates {
+    /// Sends to every session, dropping those that have gone away
+    fn send(&mut self, update: &LiveUpdate) {
+        let json = match serde_json::to_string(update) {
+            Ok(json) => json,
+            Err(e) => {
+                error!("Failed to serialize live update {:?}", e);
+                return;
+            }
+        };
+        self.subscribers
+            .retain(|subscriber| subscriber.do_send(Push(json.clone())).is_ok());
+        SUBSCRIBERS.store(self.subscribers.len(), Ordering::Relaxed);
+        if self.subscribers.is_empty() {
+            self.neighbors.clear();
+            self.debts.clear();
+        }
+    }
+
+    fn neighbors_update(&self, old: &HashMap<String, NeighborUpdate>) -> Option<LiveUpdate> {
+        let (updated, removed) = diff_maps(old, &self.neighbors);
+        if updated.is_empty() && removed.is_empty() {
+            return None;
+        }
+        Some(LiveUpdate::Neighbors {
+            updated: updated.into_iter().map(|(_, neighbor)| neighbor).collect(),
+            removed,
+        })
+    }
+
+    fn debts_update(&self, old: &HashMap<Identity, Int256>) -> Option<LiveUpdate> {
+        let (updated, removed) = diff_maps(old, &self.debts);
+        if updated.is_empty() && removed.is_empty() {
+            return None;
+        }
+        Some(LiveUpdate::Debts {
+            updated: updated
+                .into_iter()
+                .map(|(identity, debt)| DebtUpdate { identity, debt })
+                .collect(),
+            removed,
+        })
+    }
+}
+
+/// A new session, it's sent everything we have right away
+pub struct Subscribe(pub Recipient<Push>);
+
+impl Message for Subscribe {
+    type Result = ();
+}
+
+impl Handler<Subscribe> for LiveUpdates {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _: &mut Context<Self>) -> Self::Result {
+        trace!("Live update session connected");
+        let initial = vec![
+            self.neighbors_update(&HashMap::new()),
+            self.debts_update(&HashMap::new()),
+        ];
+        for update in initial.into_iter().filter_map(|update| update) {
+            if let Ok(json) = serde_json::to_string(&update) {
+                let _ = msg.0.do_send(Push(json));
+            }
+        }
+        self.subscribers.push(msg.0);
+        SUBSCRIBERS.store(self.subscribers.len(), Ordering::Relaxed);
+    }
+}
+
+pub enum Publish {
+    /// every neighbor we have a tunnel with
+    Neighbors(Vec<Neighbor>),
+    /// every debt we're keeping
+    Debts(Vec<(Identity, Int256)>),
+    Usage(UsageSample),
+    Event(WatchdogEvent),
+}
+
+impl Message for Publish {
+    type Result = ();
+}
+
+impl Handler<Publish> for LiveUpdates {
+    type Result = ();
+
+    fn handle(&mut self, msg: Publish, _: &mut Context<Self>) -> Self::Result {
+        if self.subscribers.is_empty() {
+            return;
+        }
+        let update = match msg {
+            Publish::Neighbors(neighbors) => {
+                let current = neighbors
+                    .into_iter()
+                    .map(|neighbor| {
+                        (
+                            neighbor.iface_name.clone(),
+                            NeighborUpdate {
+                                identity: neighbor.identity,
+                                iface_name: neighbor.iface_name,
+                                tunnel_ip: neighbor.tunnel_ip,
+                                speed_limit: neighbor.speed_limit,
+                            },
+                        )
+                    })
+                    .collect();
+                let old = std::mem::replace(&mut self.neighbors, current);
+                self.neighbors_update(&old)
+            }
+            Publish::Debts(debts) => {
+                let old = std::mem::replace(&mut self.debts, debts.into_iter().collect());
+                self.debts_update(&old)
+            }
+            Publish::Usage(sample) => Some(LiveUpdate::Usage(sample)),
+            Publish::Event(event) => Some(LiveUpdate::Event(event)),
+        };
+        if let Some(update) = update {
+            self.send(&update);
+        }
+    }
+}
+
+#[test]
+fn test_diff_maps() {
+    let map = |entries: &[(&str, u32)]| -> HashMap<String, u32> {
+        entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), *value))
+            .collect()
+    };
+    let old = map(&[("wg0", 1), ("wg1", 2), ("wg2", 3)]);
+    let new = map(&[("wg0", 1), ("wg1", 5), ("wg3", 4)]);
+    let (mut updated, removed) = diff_maps(&old, &new);
+    updated.sort();
+    assert_eq!(
+        updated,
+        vec![("wg1".to_string(), 5), ("wg3".to_string(), 4)]
+    );
+    assert_eq!(removed, vec!["wg2".to_string()]);
+
+    // a new session gets everything
+    let (updated, removed) = diff_maps(&HashMap::new(), &new);
+    assert_eq!(updated.len(), 3);
+    assert!(removed.is_empty());
+    assert_eq!(diff_maps(&new, &new), (Vec::new(), Vec::new()));
+}
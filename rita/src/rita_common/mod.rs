@@ -1,7 +1,12 @@
+pub mod control_socket;
+pub mod currency;
 pub mod dao_manager;
 pub mod dashboard;
 pub mod debt_keeper;
+pub mod exit_terms;
+pub mod guac;
 pub mod hello_handler;
+pub mod live_updates;
 pub mod network_endpoints;
 pub mod network_monitor;
 pub mod oracle;
@@ -9,9 +14,16 @@ pub mod payment_controller;
 pub mod payment_validator;
 pub mod peer_listener;
 pub mod rita_loop;
+pub mod route_cache;
+pub mod schedule;
+pub mod service_registry;
 pub mod simulated_txfee_manager;
+pub mod sla_monitor;
+pub mod system_health;
+pub mod time_sanity;
 pub mod token_bridge;
 pub mod traffic_watcher;
 pub mod tunnel_manager;
 pub mod usage_tracker;
 pub mod utils;
+pub mod watchdog;
@@ -1,16 +1,33 @@
 //! Network endptoints for common Rita functionality (such as exchanging hello messages)
 
-use crate::rita_common::payment_validator::{PaymentValidator, ToValidate, ValidateLater};
+use crate::rita_common::debt_keeper::{
+    DebtKeeper, IouReceived, TheirTrafficCounts, WriteOffReceived,
+};
+use crate::rita_common::guac::{ChannelPaymentReceived, Guac};
+use crate::rita_common::hello_handler::auth::{
+    auth_required, new_challenge, remember_authenticated, respond_to_hello, verify_hello,
+};
+use crate::rita_common::hello_handler::our_features;
+use crate::rita_common::payment_validator::{
+    GetPaymentStatus, PaymentValidator, Refused, ToValidate, ValidateLater,
+};
 use crate::rita_common::peer_listener::Peer;
+use crate::rita_common::schedule;
+use crate::rita_common::service_registry::{ServiceRegistry, ServicesGossiped};
+use crate::rita_common::tunnel_manager::handoff::ReleaseLightClient;
 use crate::rita_common::tunnel_manager::id_callback::IdentityCallback;
-use crate::rita_common::tunnel_manager::TunnelManager;
+use crate::rita_common::tunnel_manager::{is_paused, TunnelManager};
 use crate::SETTING;
 use actix::registry::SystemService;
 use actix_web::http::StatusCode;
 use actix_web::{AsyncResponder, HttpRequest, HttpResponse, Json, Result};
-use althea_types::{LocalIdentity, PaymentTx};
+use althea_types::{
+    DebtWriteOff, HelloChallenge, Iou, LightClientRoam, LocalIdentity, PaymentStatus, PaymentTx,
+    SignedMeshService, TrafficCounts, WgKey,
+};
 use failure::Error;
 use futures01::{future, Future};
+use num256::Uint256;
 use settings::RitaCommonSettings;
 use std::boxed::Box;
 use std::net::SocketAddr;
@@ -40,6 +57,22 @@ pub fn make_payments(
 ) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
     let txid = pmt.0.txid.clone();
 
+    if pmt.0.channel.is_some() {
+        if SETTING.get_payment().guac_url.is_none() {
+            return Box::new(future::ok(
+                HttpResponse::new(StatusCode::from_u16(400u16).unwrap())
+                    .into_builder()
+                    .json("Channel payments are not supported!"),
+            ));
+        }
+        info!(
+            "Got channel payment from {} for {}",
+            pmt.0.from.wg_public_key, pmt.0.amount
+        );
+        Guac::from_registry().do_send(ChannelPaymentReceived(pmt.0.into_inner()));
+        return Box::new(future::ok(HttpResponse::Ok().json("Payment Received!")));
+    }
+
     // we didn't get a txid, probably an old client.
     // why don't we need an Either up here? Because the types ultimately match?
     if txid.is_none() {
@@ -55,14 +88,54 @@ pub fn make_payments(
         "Got Payment from {} for {} with txid {:#066x}",
         pmt.0.from.wg_public_key, pmt.0.amount, txid,
     );
+    // the validation queue is shared out by where payments come in from, the payer they claim
+    // to be from could be anyone
+    let peer = match pmt.1.connection_info().remote() {
+        Some(val) => match val.parse::<SocketAddr>() {
+            Ok(val) => val.ip(),
+            Err(_e) => return Box::new(future::err(format_err!("Malformed payment request!"))),
+        },
+        None => return Box::new(future::err(format_err!("Malformed payment request!"))),
+    };
+    let payment = pmt.0.into_inner();
     let ts = ToValidate {
-        payment: pmt.0.into_inner(),
+        payment,
         recieved: Instant::now(),
         checked: false,
+        peer: Some(peer),
     };
-    PaymentValidator::from_registry().do_send(ValidateLater(ts));
 
-    Box::new(future::ok(HttpResponse::Ok().json("Payment Received!")))
+    Box::new(
+        PaymentValidator::from_registry()
+            .send(ValidateLater(ts))
+            .from_err()
+            .and_then(move |queued| match queued {
+                // the payer stops resending once it's told the id is taken, any other
+                // refusal is resent until it's queued
+                Err(e @ Refused::ReusedId(..)) => Ok(HttpResponse::new(StatusCode::CONFLICT)
+                    .into_builder()
+                    .json(e.to_string())),
+                Err(e) => Ok(HttpResponse::new(StatusCode::SERVICE_UNAVAILABLE)
+                    .into_builder()
+                    .json(format!("{}, try again later", e))),
+                // a retry of a payment we already have is acknowledged the same way, the
+                // receipt comes from /payment_status once it's validated
+                Ok(()) => Ok(HttpResponse::Ok().json("Payment Received!")),
+            }),
+    )
+}
+
+/// Lets a payer check on a payment after we've acknowledged it
+pub fn payment_status(
+    txid: Json<Uint256>,
+) -> Box<dyn Future<Item = Json<PaymentStatus>, Error = Error>> {
+    trace!("payment_status: Hit");
+    Box::new(
+        PaymentValidator::from_registry()
+            .send(GetPaymentStatus(txid.into_inner()))
+            .from_err()
+            .map(Json),
+    )
 }
 
 pub fn hello_response(
@@ -80,6 +153,32 @@ pub fn hello_response(
     };
 
     trace!("Got Hello from {:?}", req.1.connection_info().remote());
+
+    if is_paused(&their_id.global.wg_public_key) {
+        trace!("Ignoring hello from paused neighbor {}", socket);
+        return Box::new(future::err(format_err!("Neighbor is paused")));
+    }
+
+    match their_id.auth {
+        Some(_) => {
+            if let Err(e) = verify_hello(&their_id) {
+                warn!("Rejecting hello from {} {:?}", socket, e);
+                return Box::new(future::err(e));
+            }
+            remember_authenticated(their_id.global.wg_public_key);
+        }
+        None => {
+            if auth_required(&their_id.global.wg_public_key, their_id.features) {
+                warn!("Rejecting unauthenticated hello from {}", socket);
+                return Box::new(future::err(format_err!("Hello is not authenticated")));
+            }
+        }
+    }
+    let auth = match respond_to_hello(&their_id) {
+        Ok(val) => val,
+        Err(e) => return Box::new(future::err(e)),
+    };
+
     trace!("opening tunnel in hello_response for {:?}", their_id);
 
     let peer = Peer {
@@ -107,12 +206,90 @@ pub fn hello_response(
                     },
                     wg_port: tunnel.0.listen_port,
                     have_tunnel: Some(tunnel.1),
+                    local_fee: Some(schedule::local_fee()),
+                    auth,
+                    features: our_features(),
                 }))
             })
             .responder(),
     )
 }
 
+/// Hands out a challenge that must be answered in the following hello, see hello_handler::auth
+pub fn hello_challenge(their_pubkey: Json<WgKey>) -> Result<Json<HelloChallenge>, Error> {
+    Ok(Json(new_challenge(&their_pubkey)?))
+}
+
+/// A neighbor's counts for the last reconciliation window, see debt_keeper::reconcile. They're
+/// sent to our mesh ip over the tunnel, so the sender's address says which neighbor they're from
+pub fn traffic_counts(req: (Json<TrafficCounts>, HttpRequest)) -> Result<Json<()>, Error> {
+    let sender = match req.1.connection_info().remote() {
+        Some(val) => match val.parse::<SocketAddr>() {
+            Ok(val) => val.ip(),
+            Err(_e) => bail!("Malformed traffic counts request!"),
+        },
+        None => bail!("Malformed traffic counts request!"),
+    };
+    trace!("Got traffic counts from {}", sender);
+    DebtKeeper::from_registry().do_send(TheirTrafficCounts {
+        sender,
+        counts: req.0.into_inner(),
+    });
+    Ok(Json(()))
+}
+
+/// An IOU from a neighbor that couldn't pay us, see debt_keeper::journal. Only acknowledged
+/// once it's in our journal, so the neighbor keeps trying until then
+pub fn iou(iou: Json<Iou>) -> Box<dyn Future<Item = Json<()>, Error = Error>> {
+    trace!("Got IOU from {}", iou.from.mesh_ip);
+    DebtKeeper::from_registry()
+        .send(IouReceived(iou.into_inner()))
+        .from_err()
+        .and_then(|res| {
+            res?;
+            Ok(Json(()))
+        })
+        .responder()
+}
+
+/// A neighbor wrote off some of what we owe them, see debt_keeper::write_off
+pub fn debt_write_off(
+    write_off: Json<DebtWriteOff>,
+) -> Box<dyn Future<Item = Json<()>, Error = Error>> {
+    trace!("Got write off from {}", write_off.from.mesh_ip);
+    DebtKeeper::from_registry()
+        .send(WriteOffReceived(write_off.into_inner()))
+        .from_err()
+        .and_then(|res| {
+            res?;
+            Ok(Json(()))
+        })
+        .responder()
+}
+
+/// A neighbor's service table, see service_registry
+pub fn service_gossip(services: Json<Vec<SignedMeshService>>) -> Result<Json<()>, Error> {
+    trace!("Got {} gossiped services", services.len());
+    ServiceRegistry::from_registry().do_send(ServicesGossiped(services.into_inner()));
+    Ok(Json(()))
+}
+
+/// Another gateway took over a light client of ours, see tunnel_manager::handoff
+pub fn light_client_handoff(
+    roam: Json<LightClientRoam>,
+) -> Box<dyn Future<Item = Json<()>, Error = Error>> {
+    trace!("Got light client handoff for {}", roam.id.global);
+    Box::new(
+        TunnelManager::from_registry()
+            .send(ReleaseLightClient(roam.into_inner()))
+            .from_err()
+            .and_then(|res| {
+                res?;
+                Ok(Json(()))
+            }),
+    )
+}
+
 pub fn version(_req: HttpRequest) -> String {
     format!(
         "crate ver {}\ngit hash {}",
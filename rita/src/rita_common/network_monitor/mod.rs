@@ -9,8 +9,10 @@
 //! on traffic over every interface and base our action off of spikes in throughput as well as spikes in latency.
 
 use crate::rita_common::rita_loop::fast_loop::FAST_LOOP_SPEED;
+use crate::rita_common::tunnel_manager::latency::RttSamples;
 use crate::rita_common::tunnel_manager::GotBloat;
 use crate::rita_common::tunnel_manager::Neighbor as RitaNeighbor;
+use crate::rita_common::tunnel_manager::ReprobeMtu;
 use crate::rita_common::tunnel_manager::TunnelManager;
 use actix::Actor;
 use actix::Context;
@@ -26,6 +28,8 @@ use std::collections::HashMap;
 
 const SAMPLE_PERIOD: u8 = FAST_LOOP_SPEED as u8;
 const SAMPLES_IN_FIVE_MINUTES: usize = 300 / SAMPLE_PERIOD as usize;
+/// Five minute packet loss average above which we suspect an mtu problem on the link
+const MTU_SUSPECT_LOSS: f32 = 0.2;
 
 /// Implements https://en.wikipedia.org/wiki/Algorithms_for_calculating_variance#Welford's_online_algorithm
 /// to keep track of neighbor latency in an online fashion
@@ -289,6 +293,12 @@ impl Handler<NetworkInfo> for NetworkMonitor {
             &mut self.packet_loss_history,
         );
         network_stats(babel_routes, babel_neighbors);
+        TunnelManager::from_registry().do_send(RttSamples {
+            samples: babel_neighbors
+                .iter()
+                .map(|neigh| (neigh.iface.clone(), neigh.rtt))
+                .collect(),
+        });
         self.last_babel_dump = Some(msg);
     }
 }
@@ -355,6 +365,13 @@ fn observe_network(
                     "Lost packets to {} {}% five min average {}% power on average",
                     key, five_min_average, avg
                 );
+                // sustained loss on a link that's otherwise up is what an mtu
+                // blackhole looks like, TunnelManager rate limits the reprobing
+                if five_min_average > MTU_SUSPECT_LOSS {
+                    TunnelManager::from_registry().do_send(ReprobeMtu {
+                        iface: iface.to_string(),
+                    });
+                }
             }
             (true, Some(_avg), None) => {
                 error!(
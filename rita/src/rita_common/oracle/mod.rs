@@ -7,8 +7,12 @@
 //! operates by simply grabbing a text file from a configured server and adjusting prices
 //! to match. More advanced pricing systems may be broken out into their own file some day
 
+use crate::rita_common::payment_controller::backend::payment_backend;
+use crate::rita_common::payment_controller::backend::PaymentBackend;
+use crate::rita_common::payment_controller::wallets::{our_addresses, wallet_state_mut};
 use crate::rita_common::rita_loop::fast_loop::FAST_LOOP_TIMEOUT;
 use crate::rita_common::rita_loop::get_web3_server;
+use crate::rita_common::token_bridge::bridge_has_key;
 use crate::rita_common::token_bridge::ReloadAddresses;
 use crate::rita_common::token_bridge::TokenBridge;
 use crate::SETTING;
@@ -35,9 +39,11 @@ use web30::client::Web3;
 /// Things that you are not allowed to put into the merge json field of the oracle,
 /// this mostly includes dangerous local things like eth private keys (erase money)
 /// ports (destory all networking) etc etc
-const FORBIDDEN_MERGE_VALUES: [&str; 5] = [
+const FORBIDDEN_MERGE_VALUES: [&str; 7] = [
     "eth_private_key",
     "eth_address",
+    "wallets",
+    "daily_spending_cap",
     "mesh_ip",
     "external_nic",
     "peer_interfaces",
@@ -102,12 +108,19 @@ impl Handler<Update> for Oracle {
     fn handle(&mut self, _msg: Update, _ctx: &mut Context<Self>) -> Self::Result {
         let payment_settings = SETTING.get_payment();
         let our_address = payment_settings.eth_address.expect("No address!");
+        let other_wallets: Vec<Address> = our_addresses(&payment_settings)
+            .into_iter()
+            .filter(|address| *address != our_address)
+            .collect();
         drop(payment_settings);
 
         let full_node = get_web3_server();
         let web3 = Web3::new(&full_node, ORACLE_TIMEOUT);
 
         info!("About to make web3 requests to {}", full_node);
+        for address in other_wallets {
+            update_wallet_info(address, &web3, full_node.clone(), Some(Instant::now()));
+        }
         update_blockchain_info(our_address, web3, full_node, Some(Instant::now()));
         update_oracle();
     }
@@ -119,7 +132,8 @@ fn update_blockchain_info(
     full_node: String,
     zero_window: Option<Instant>,
 ) {
-    let balance = web3.eth_get_balance(our_address);
+    let backend = payment_backend(full_node.clone(), ORACLE_TIMEOUT);
+    let balance = backend.get_balance(our_address);
     let nonce = web3.eth_get_transaction_count(our_address);
     let net_version = web3.net_version();
     let gas_price = web3.eth_gas_price();
@@ -133,7 +147,7 @@ fn update_blockchain_info(
                 &mut payment_settings.balance,
                 balance,
             );
-            update_gas_price(&full_node, gas_price, &mut payment_settings);
+            update_gas_price(&full_node, gas_price, &mut payment_settings, &*backend);
             update_nonce(&full_node, nonce, &mut payment_settings.nonce);
             get_net_version(&full_node, &mut payment_settings.net_version, net_version);
             Ok(())
@@ -148,6 +162,39 @@ fn update_blockchain_info(
     Arbiter::spawn(res);
 }
 
+/// Updates the balance and nonce of one of our wallets other than the main one, gas price and
+/// net version are the same for all of them
+fn update_wallet_info(
+    address: Address,
+    web3: &Web3,
+    full_node: String,
+    zero_window: Option<Instant>,
+) {
+    let backend = payment_backend(full_node.clone(), ORACLE_TIMEOUT);
+    let balance = backend.get_balance(address);
+    let nonce = web3.eth_get_transaction_count(address);
+    let res = balance
+        .join(nonce)
+        .and_then(move |(balance, nonce)| {
+            let mut payment_settings = SETTING.get_payment_mut();
+            // the wallet may have been removed in the meantime
+            if let Some((our_balance, our_nonce)) = wallet_state_mut(&mut payment_settings, address)
+            {
+                update_balance(&full_node, zero_window, our_balance, balance);
+                update_nonce(&full_node, nonce, our_nonce);
+            }
+            Ok(())
+        })
+        .then(move |res| {
+            if let Err(e) = res {
+                warn!("Failed to update wallet {} with {:?}", address, e);
+            }
+            Ok(())
+        });
+
+    Arbiter::spawn(res);
+}
+
 /// Gets the balance for the provided eth address and updates it
 /// in the global SETTING variable, do not use this function as a generic
 /// balance getter.
@@ -229,7 +276,9 @@ pub fn trigger_update_nonce(our_address: Address, web3: &Web3, full_node: String
                     full_node, value
                 );
                 let mut payment_settings = SETTING.get_payment_mut();
-                update_nonce(&full_node, value, &mut payment_settings.nonce);
+                if let Some((_, nonce)) = wallet_state_mut(&mut payment_settings, our_address) {
+                    update_nonce(&full_node, value, nonce);
+                }
                 Ok(())
             }
             Err(e) => {
@@ -252,6 +301,7 @@ fn update_gas_price(
     full_node: &str,
     new_gas_price: Uint256,
     payment_settings: &mut PaymentSettings,
+    backend: &dyn PaymentBackend,
 ) {
     let mut value = new_gas_price;
     info!(
@@ -280,12 +330,12 @@ fn update_gas_price(
     };
 
     let dynamic_fee_factor: Int256 = payment_settings.dynamic_fee_multiplier.into();
-    let transaction_gas: Int256 = 21000.into();
     let neg_one = -1i32;
     let sign_flip: Int256 = neg_one.into();
 
-    if let Some(gas_price) = payment_settings.gas_price.to_int256() {
-        payment_settings.pay_threshold = transaction_gas * gas_price * dynamic_fee_factor;
+    let transaction_fee = backend.estimate_fee(payment_settings.gas_price.clone());
+    if let Some(transaction_fee) = transaction_fee.to_int256() {
+        payment_settings.pay_threshold = transaction_fee * dynamic_fee_factor;
     }
     trace!(
         "Dynamically set pay threshold to {:?}",
@@ -398,6 +448,7 @@ fn update_oracle() {
                                             // Sends a message to reload bridge addresses live if needed
                                             if SETTING.get_payment().bridge_addresses
                                                 != starting_token_bridge_core
+                                                && bridge_has_key()
                                             {
                                                 TokenBridge::from_registry()
                                                     .do_send(ReloadAddresses());
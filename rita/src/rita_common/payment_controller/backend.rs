@@ -0,0 +1,238 @@
+//! The chain specific parts of making and checking payments. Everything that talks to a full node
+//! about money goes through a PaymentBackend so that the payment controller, validator and oracle
+//! don't care which chain a community has chosen to settle on. The backend is picked using the
+//! system_chain setting, Ethereum mainnet (and its testnets) or xDai, the xDai backend also covers
+//! other low fee EVM compatible sidechains and L2s with fast blocks by pointing the node_list at
+//! them. The chains differ mostly in how long blocks take and therefore how many confirmations a
+//! payment needs and how old a txid can be before we refuse it.
+
+use super::signer::{sign_and_send, sign_with_key_and_send};
+use super::wallets::Wallet;
+use crate::SETTING;
+use althea_types::{PaymentTx, SystemChain};
+use clarity::{Address, Transaction};
+use failure::Error;
+use futures01::Future;
+use num256::Uint256;
+use settings::payment::XDAI_MIN_GAS;
+use settings::RitaCommonSettings;
+use std::time::Duration;
+use web30::client::Web3;
+use web30::types::TransactionResponse;
+
+/// Gas used by a plain value transfer, the only kind of transaction we send for payments
+const TRANSFER_GAS: u32 = 21000;
+
+/// Chain parameters that determine how payments are checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainParams {
+    /// How many blocks before we assume finality
+    pub blocks_to_confirm: u32,
+    /// How old a txid can be before we don't accept it
+    pub blocks_to_old: u32,
+}
+
+/// Roughly 15 second blocks, so 6 hours of history
+const ETHEREUM_PARAMS: ChainParams = ChainParams {
+    blocks_to_confirm: 4,
+    blocks_to_old: 1440,
+};
+
+/// Roughly 5 second blocks, so also 6 hours of history
+const XDAI_PARAMS: ChainParams = ChainParams {
+    blocks_to_confirm: 4,
+    blocks_to_old: 4320,
+};
+
+/// A transaction as found on chain along with where it stands relative to the chain head
+pub struct VerifiedTx {
+    pub transaction: TransactionResponse,
+    /// if it's deep enough in the chain to be considered final
+    pub confirmed: bool,
+    /// if it's too old to be accepted as a new payment
+    pub too_old: bool,
+}
+
+pub trait PaymentBackend {
+    /// Signs and publishes a payment from the given wallet, returning the txid
+    fn send(
+        &self,
+        pmt: &PaymentTx,
+        wallet: &Wallet,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>>;
+    /// Looks up a txid, None if the full node has not seen it yet
+    fn verify(&self, txid: Uint256) -> Box<dyn Future<Item = Option<VerifiedTx>, Error = Error>>;
+    fn get_balance(&self, address: Address) -> Box<dyn Future<Item = Uint256, Error = Error>>;
+    /// The cost of sending a single payment at the given gas price
+    fn estimate_fee(&self, gas_price: Uint256) -> Uint256;
+}
+
+pub struct EthereumBackend {
+    full_node: String,
+    timeout: Duration,
+}
+
+pub struct XdaiBackend {
+    full_node: String,
+    timeout: Duration,
+}
+
+/// Returns the backend for the configured system chain talking to the given full node
+pub fn payment_backend(full_node: String, timeout: Duration) -> Box<dyn PaymentBackend> {
+    match SETTING.get_payment().system_chain {
+        SystemChain::Ethereum | SystemChain::Rinkeby => {
+            Box::new(EthereumBackend { full_node, timeout })
+        }
+        SystemChain::Xdai => Box::new(XdaiBackend { full_node, timeout }),
+    }
+}
+
+impl PaymentBackend for EthereumBackend {
+    fn send(
+        &self,
+        pmt: &PaymentTx,
+        wallet: &Wallet,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        send_transfer(&self.full_node, self.timeout, pmt, wallet)
+    }
+
+    fn verify(&self, txid: Uint256) -> Box<dyn Future<Item = Option<VerifiedTx>, Error = Error>> {
+        verify_transfer(&self.full_node, self.timeout, txid, ETHEREUM_PARAMS)
+    }
+
+    fn get_balance(&self, address: Address) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        Box::new(Web3::new(&self.full_node, self.timeout).eth_get_balance(address))
+    }
+
+    fn estimate_fee(&self, gas_price: Uint256) -> Uint256 {
+        gas_price * TRANSFER_GAS.into()
+    }
+}
+
+impl PaymentBackend for XdaiBackend {
+    fn send(
+        &self,
+        pmt: &PaymentTx,
+        wallet: &Wallet,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        send_transfer(&self.full_node, self.timeout, pmt, wallet)
+    }
+
+    fn verify(&self, txid: Uint256) -> Box<dyn Future<Item = Option<VerifiedTx>, Error = Error>> {
+        verify_transfer(&self.full_node, self.timeout, txid, XDAI_PARAMS)
+    }
+
+    fn get_balance(&self, address: Address) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        Box::new(Web3::new(&self.full_node, self.timeout).eth_get_balance(address))
+    }
+
+    /// The full node may report a gas price of zero when the chain is idle but transactions
+    /// still need to pay the minimum to be mined
+    fn estimate_fee(&self, gas_price: Uint256) -> Uint256 {
+        let min_gas: Uint256 = XDAI_MIN_GAS.into();
+        let gas_price = if gas_price < min_gas {
+            min_gas
+        } else {
+            gas_price
+        };
+        gas_price * TRANSFER_GAS.into()
+    }
+}
+
+/// Builds, signs and publishes a value transfer using the wallet's nonce and the gas price, both
+/// maintained by the oracle
+fn send_transfer(
+    full_node: &str,
+    timeout: Duration,
+    pmt: &PaymentTx,
+    wallet: &Wallet,
+) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+    let tx = Transaction {
+        nonce: wallet.nonce.clone(),
+        gas_price: SETTING.get_payment().gas_price.clone(),
+        gas_limit: TRANSFER_GAS.into(),
+        to: pmt.to.eth_address,
+        value: pmt.amount.clone(),
+        data: Vec::new(),
+        signature: None,
+    };
+
+    match wallet.key {
+        Some(key) => sign_with_key_and_send(tx, key, full_node.to_string(), timeout),
+        None => sign_and_send(tx, full_node.to_string(), timeout),
+    }
+}
+
+fn verify_transfer(
+    full_node: &str,
+    timeout: Duration,
+    txid: Uint256,
+    params: ChainParams,
+) -> Box<dyn Future<Item = Option<VerifiedTx>, Error = Error>> {
+    let web3 = Web3::new(full_node, timeout);
+    Box::new(
+        web3.eth_block_number()
+            .join(web3.eth_get_transaction_by_hash(txid))
+            .and_then(move |(block_num, tx_status)| {
+                Ok(tx_status.map(|transaction| {
+                    let (confirmed, too_old) =
+                        check_depth(&params, &block_num, &transaction.block_number);
+                    VerifiedTx {
+                        transaction,
+                        confirmed,
+                        too_old,
+                    }
+                }))
+            }),
+    )
+}
+
+/// Determines if a transaction at tx_height is final and if it's too old to accept
+fn check_depth(
+    params: &ChainParams,
+    chain_height: &Uint256,
+    tx_height: &Option<Uint256>,
+) -> (bool, bool) {
+    match tx_height {
+        Some(tx_block) if tx_block <= chain_height => {
+            let depth = chain_height.clone() - tx_block.clone();
+            (
+                depth >= Uint256::from(params.blocks_to_confirm),
+                depth > Uint256::from(params.blocks_to_old),
+            )
+        }
+        // not mined yet or somehow newer than our block height request, wait until later
+        _ => (false, false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_depth() {
+        let head: Uint256 = 10_000u32.into();
+        let at = |block: u32| Some(Uint256::from(block));
+        assert_eq!(check_depth(&ETHEREUM_PARAMS, &head, &None), (false, false));
+        assert_eq!(
+            check_depth(&ETHEREUM_PARAMS, &head, &at(10_001)),
+            (false, false)
+        );
+        assert_eq!(
+            check_depth(&ETHEREUM_PARAMS, &head, &at(9_997)),
+            (false, false)
+        );
+        assert_eq!(
+            check_depth(&ETHEREUM_PARAMS, &head, &at(9_996)),
+            (true, false)
+        );
+        // the same age is too old on mainnet but fine on the faster xdai chain
+        assert_eq!(
+            check_depth(&ETHEREUM_PARAMS, &head, &at(5_000)),
+            (true, true)
+        );
+        assert_eq!(check_depth(&XDAI_PARAMS, &head, &at(8_000)), (true, false));
+        assert_eq!(check_depth(&XDAI_PARAMS, &head, &at(5_000)), (true, true));
+    }
+}
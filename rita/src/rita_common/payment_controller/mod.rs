@@ -2,54 +2,157 @@
 //! managing the retry flow for failed payment attempts. We will retry a payment
 //! so long as we have not published it to a full node, once the payment is on
 //! the blockchain it's up to the reciever to validate that it's correct
-
+//!
+//! Every payment gets a random payment id that it keeps through retries. A payment is kept in
+//! the unacknowledged store from when it's published until our neighbor acknowledges it, turns
+//! it away for good or we give up, so that a restart in between doesn't stop us telling them.
+//!
+//! Payments are made from the wallet configured for their purpose, see the wallets module.
+
+pub mod backend;
+pub mod receipt;
+pub mod signer;
+pub mod unacked;
+pub mod wallets;
+
+use self::backend::payment_backend;
+use self::receipt::check_receipt;
+use self::unacked::{load_unacked, save_unacked};
+use self::wallets::{select_wallet, wallet_state_mut, DailySpending, Reservation, Wallet};
 use crate::rita_common::debt_keeper::DebtKeeper;
 use crate::rita_common::debt_keeper::PaymentFailed;
+use crate::rita_common::debt_keeper::PaymentReceiptReceived;
+use crate::rita_common::guac::make_channel_payment;
 use crate::rita_common::oracle::trigger_update_nonce;
-use crate::rita_common::payment_validator::{PaymentValidator, ToValidate, ValidateLater};
+use crate::rita_common::payment_validator::{
+    PaymentValidator, ToValidate, ValidateLater, NOT_FOUND,
+};
 use crate::rita_common::rita_loop::get_web3_server;
+use crate::rita_common::tunnel_manager::{GetNeighborFeatures, TunnelManager};
+use crate::rita_common::usage_tracker::{UpdateFees, UsageTracker};
+use crate::rita_common::utils::now_secs;
+use crate::rita_common::watchdog::overflow_alarm;
 use crate::SETTING;
 use actix::prelude::{Actor, Arbiter, Context, Handler, Message, Supervised, SystemService};
 use actix_web::client;
 use actix_web::client::Connection;
-use althea_types::PaymentTx;
-use clarity::Transaction;
+use actix_web::http::StatusCode;
+use actix_web::HttpMessage;
+use althea_types::{FeatureFlags, Identity, PaymentReceipt, PaymentStatus, PaymentTx, Wei};
 use failure::Error;
 use futures01::future::Either;
 use futures01::{future, Future};
 use num256::Uint256;
+use settings::payment::PaymentPurpose;
 use settings::RitaCommonSettings;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::time::Duration;
 use std::time::Instant;
 use tokio::net::TcpStream as TokioTcpStream;
+use tokio::timer::Delay;
 use web30::client::Web3;
 
 pub const TRANSACTION_SUBMISSON_TIMEOUT: Duration = Duration::from_secs(15);
 pub const MAX_TXID_RETRIES: u8 = 15u8;
-
-pub struct PaymentController();
+/// How much longer we wait before each retry, neighbors turn txids away while their validation
+/// queue is full so hammering them right away does no good. With MAX_TXID_RETRIES this keeps us
+/// resending for a few minutes, well past the first lookup after which our neighbor drops a txid
+/// its full node hasn't seen, so a payment that's slow to spread is sent again until it has
+const TXID_RETRY_BACKOFF: Duration = Duration::from_secs(2);
+/// How long after our neighbor acknowledges a payment we ask whether it still has it, long
+/// enough for it to have looked the txid up once
+const PAYMENT_STATUS_DELAY: Duration = Duration::from_secs(30);
+/// How many times we ask about a payment while waiting on the receipt for it, payments can take
+/// a few minutes to be validated
+const RECEIPT_CHECKS: u8 = 10;
+/// How often settled payments are dropped from the unacknowledged store on disk
+const UNACKED_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct PaymentController {
+    /// published payments our neighbors haven't acknowledged yet, by payment id
+    unacked: HashMap<u64, PaymentTx>,
+    /// if payments were settled since the unacknowledged store was last written
+    unacked_dirty: bool,
+    /// what each of our wallets has sent today, to hold them to their daily caps
+    spending: DailySpending,
+}
 
 impl Actor for PaymentController {
     type Context = Context<Self>;
 }
 impl Supervised for PaymentController {}
 impl SystemService for PaymentController {
-    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+    fn service_started(&mut self, ctx: &mut Context<Self>) {
         info!("Payment Controller started");
+        self.unacked = load_unacked();
+        self.spending = DailySpending::load(&SETTING.get_payment().daily_spending_file);
+        ctx.run_interval(UNACKED_SAVE_INTERVAL, |act, _ctx| {
+            if act.unacked_dirty {
+                act.save_unacked();
+            }
+        });
+        // we may have restarted between publishing these and telling our neighbor
+        for pmt in self.unacked.values() {
+            match (neighbor_contact(pmt), pmt.txid.clone()) {
+                (Ok((contact_socket, neigh_url)), Some(txid)) => {
+                    info!("Resending unacknowledged payment {:#066x}", txid);
+                    resend_txid(ResendInfo {
+                        txid,
+                        contact_socket,
+                        neigh_url,
+                        pmt: pmt.clone(),
+                        attempt: 0,
+                    });
+                }
+                _ => error!("Can't resend unacknowledged payment {:?}", pmt),
+            }
+        }
     }
 }
 
 #[derive(Message)]
-pub struct MakePayment(pub PaymentTx);
+pub struct MakePayment {
+    pub pmt: PaymentTx,
+    pub purpose: PaymentPurpose,
+}
 
 impl Handler<MakePayment> for PaymentController {
     type Result = ();
 
     fn handle(&mut self, msg: MakePayment, _ctx: &mut Context<Self>) -> Self::Result {
-        let res = make_payment(msg.0.clone());
-        if res.is_err() {
-            DebtKeeper::from_registry().do_send(PaymentFailed { to: msg.0.to });
+        let pmt = msg.pmt;
+        let (wallet, reservation) = match self.reserve_wallet(&pmt, msg.purpose) {
+            Ok(val) => val,
+            Err(e) => {
+                error!("Not paying {} {:?}", pmt.to.mesh_ip, e);
+                DebtKeeper::from_registry().do_send(PaymentFailed { to: pmt.to });
+                return;
+            }
+        };
+
+        if SETTING.get_payment().guac_url.is_some() {
+            Arbiter::spawn(make_channel_payment(pmt.clone()).then(move |res| {
+                match res {
+                    // paid out of the channel's deposit rather than the wallet
+                    Ok(_) => PaymentController::from_registry().do_send(Unreserve(reservation)),
+                    Err(e) => {
+                        info!(
+                            "Could not pay {} over a channel, paying on chain {:?}",
+                            pmt.to.wg_public_key, e
+                        );
+                        if make_payment(pmt.clone(), wallet, reservation.clone()).is_err() {
+                            payment_failed(pmt.to, reservation);
+                        }
+                    }
+                }
+                Ok(())
+            }));
+            return;
+        }
+
+        if make_payment(pmt.clone(), wallet, reservation.clone()).is_err() {
+            payment_failed(pmt.to, reservation);
         }
     }
 }
@@ -62,29 +165,101 @@ impl Default for PaymentController {
 
 impl PaymentController {
     pub fn new() -> Self {
-        PaymentController {}
+        PaymentController {
+            unacked: HashMap::new(),
+            unacked_dirty: false,
+            spending: DailySpending::default(),
+        }
+    }
+
+    /// The wallet to make this payment from, with the payment counted against its daily cap as
+    /// long as it's under it
+    fn reserve_wallet(
+        &mut self,
+        pmt: &PaymentTx,
+        purpose: PaymentPurpose,
+    ) -> Result<(Wallet, Reservation), Error> {
+        let wallet = select_wallet(&SETTING.get_payment(), purpose)?;
+        let reservation = self.spending.reserve(&wallet, pmt, now_secs())?;
+        self.save_spending();
+        Ok((wallet, reservation))
+    }
+
+    fn save_spending(&self) {
+        let path = SETTING.get_payment().daily_spending_file.clone();
+        if let Err(e) = self.spending.save(&path) {
+            error!("Failed to save daily spending {:?}", e);
+        }
+    }
+
+    /// A failed write is tried again on the next interval
+    fn save_unacked(&mut self) {
+        match save_unacked(&self.unacked) {
+            Ok(()) => self.unacked_dirty = false,
+            Err(e) => {
+                error!("Failed to save unacknowledged payments {:?}", e);
+                self.unacked_dirty = true;
+            }
+        }
     }
 }
-/// This is called by debt_keeper to make payments. It sends a
-/// PaymentTx to the `mesh_ip` in its `to` field.
-fn make_payment(mut pmt: PaymentTx) -> Result<(), Error> {
-    let payment_settings = SETTING.get_payment();
-    let balance = payment_settings.balance.clone();
-    let nonce = payment_settings.nonce.clone();
-    let gas_price = payment_settings.gas_price.clone();
-    let our_address = payment_settings.eth_address.unwrap();
-    info!(
-        "current balance: {:?}, payment of {:?}, from address {} to address {} with nonce {}",
-        balance, pmt.amount, our_address, pmt.to.eth_address, nonce
-    );
-    if balance < pmt.amount {
-        warn!("Not enough money to pay debts! Cutoff immenient");
-        bail!("Not enough money!")
-    } else if pmt.amount == 0u32.into() {
-        error!("Trying to pay nothing!");
-        bail!("Zero payment!");
+
+/// A payment that failed before it was published, what was reserved for it is given back to
+/// its wallet's daily cap
+#[derive(Message)]
+struct Unreserve(Reservation);
+
+impl Handler<Unreserve> for PaymentController {
+    type Result = ();
+
+    fn handle(&mut self, msg: Unreserve, _ctx: &mut Context<Self>) -> Self::Result {
+        self.spending.release(&msg.0, now_secs());
+        self.save_spending();
     }
+}
 
+/// Tells DebtKeeper a payment didn't go out so that it's tried again later
+fn payment_failed(to: Identity, reservation: Reservation) {
+    PaymentController::from_registry().do_send(Unreserve(reservation));
+    DebtKeeper::from_registry().do_send(PaymentFailed { to });
+}
+
+/// A payment was published, it's kept until our neighbor acknowledges it
+#[derive(Message)]
+struct Published {
+    pmt: PaymentTx,
+}
+
+impl Handler<Published> for PaymentController {
+    type Result = ();
+
+    fn handle(&mut self, msg: Published, _ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(id) = msg.pmt.payment_id {
+            self.unacked.insert(id, msg.pmt);
+            self.save_unacked();
+        }
+    }
+}
+
+/// Our neighbor acknowledged a payment, or it's no use sending it again
+#[derive(Message)]
+struct Settled(Option<u64>);
+
+impl Handler<Settled> for PaymentController {
+    type Result = ();
+
+    fn handle(&mut self, msg: Settled, _ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(id) = msg.0 {
+            // a payment we forget to drop is only sent again, so this can wait for the interval
+            if self.unacked.remove(&id).is_some() {
+                self.unacked_dirty = true;
+            }
+        }
+    }
+}
+
+/// Where to tell a neighbor about a payment, their contact socket and the url on it
+fn neighbor_contact(pmt: &PaymentTx) -> Result<(SocketAddr, String), Error> {
     let contact_socket: SocketAddr = match format!(
         "[{}]:{}",
         pmt.to.mesh_ip,
@@ -97,7 +272,6 @@ fn make_payment(mut pmt: PaymentTx) -> Result<(), Error> {
             bail!("Failed to make socket for payment message! {:?}", e);
         }
     };
-    let stream = TokioTcpStream::connect(&contact_socket);
 
     // testing hack
     let neighbor_url = if cfg!(not(test)) {
@@ -109,40 +283,58 @@ fn make_payment(mut pmt: PaymentTx) -> Result<(), Error> {
     } else {
         String::from("http://127.0.0.1:1234/make_payment")
     };
-
-    let full_node = get_web3_server();
-    let web3 = Web3::new(&full_node, TRANSACTION_SUBMISSON_TIMEOUT);
-
-    let tx = Transaction {
-        nonce,
-        gas_price,
-        gas_limit: "21000".parse().unwrap(),
-        to: pmt.to.eth_address,
-        value: pmt.amount.clone(),
-        data: Vec::new(),
-        signature: None,
-    };
-    let transaction_signed = tx.sign(
-        &payment_settings
-            .eth_private_key
-            .expect("No private key configured!"),
-        payment_settings.net_version,
+    Ok((contact_socket, neighbor_url))
+}
+/// This is called by debt_keeper to make payments. It sends a
+/// PaymentTx to the `mesh_ip` in its `to` field.
+fn make_payment(mut pmt: PaymentTx, wallet: Wallet, reservation: Reservation) -> Result<(), Error> {
+    let balance = wallet.balance.clone();
+    let nonce = wallet.nonce.clone();
+    let our_address = wallet.address;
+    info!(
+        "current balance: {:?}, payment of {:?}, from address {} to address {} with nonce {}",
+        balance, pmt.amount, our_address, pmt.to.eth_address, nonce
     );
+    if balance < pmt.amount {
+        warn!("Not enough money to pay debts! Cutoff immenient");
+        bail!("Not enough money!")
+    } else if pmt.amount == 0u32.into() {
+        error!("Trying to pay nothing!");
+        bail!("Zero payment!");
+    }
+    // the debt keeper couldn't take a payment this large off of a debt
+    if Wei::from_uint256(&pmt.amount).is_none() {
+        overflow_alarm(
+            "PaymentController",
+            format!("payment of {} to {}", pmt.amount, pmt.to.mesh_ip),
+        );
+        bail!("Payment amount overflows!");
+    }
 
-    let transaction_bytes = match transaction_signed.to_bytes() {
-        Ok(bytes) => bytes,
-        Err(e) => bail!("Failed to generate transaction, {:?}", e),
-    };
+    // kept through every retry so that our neighbor only credits this once
+    if pmt.payment_id.is_none() {
+        pmt.payment_id = Some(rand::random());
+    }
 
-    let transaction_status = web3.eth_send_raw_transaction(transaction_bytes);
+    let (contact_socket, neighbor_url) = neighbor_contact(&pmt)?;
+    let stream = TokioTcpStream::connect(&contact_socket);
+
+    let full_node = get_web3_server();
+    let backend = payment_backend(full_node.clone(), TRANSACTION_SUBMISSON_TIMEOUT);
+    // what we offer for gas on this transaction, plain transfers always use all of it
+    let fee = backend.estimate_fee(SETTING.get_payment().gas_price.clone());
+    let transaction_status = backend.send(&pmt, &wallet);
 
     let futures_chain = Box::new(stream.then(move |open_stream| match open_stream {
             Ok(open_stream) => Either::A(transaction_status.then(move |transaction_outcome| {
                 match transaction_outcome {
                     Ok(tx_id) => {
                         info!("Sending bw payment with txid: {:#066x}", tx_id);
+                        // once published the fee is spent whether or not our neighbor hears of it
+                        UsageTracker::from_registry().do_send(UpdateFees { fee });
                         // add published txid to submission
                         pmt.txid = Some(tx_id.clone());
+                        PaymentController::from_registry().do_send(Published { pmt: pmt.clone() });
                         Either::A(
                             client::post(&neighbor_url)
                                 .with_connection(Connection::from_stream(open_stream))
@@ -157,7 +349,10 @@ fn make_payment(mut pmt: PaymentTx) -> Result<(), Error> {
                                             tx_id, msg.status(), full_node, pmt.amount
                                         );
 
-                                        if !msg.status().is_success() {
+                                        if msg.status() == StatusCode::CONFLICT {
+                                            error!("Our neighbor has already seen payment id {:?} for another txid, not retrying {:#066x}", pmt.payment_id, tx_id);
+                                            PaymentController::from_registry().do_send(Settled(pmt.payment_id));
+                                        } else if !msg.status().is_success() {
                                             error!("We published txid: {:#066x} but failed to notify our neighbor, will retry", tx_id);
                                             PaymentController::from_registry().do_send(ResendTxid(ResendInfo{
                                                 txid: tx_id,
@@ -166,14 +361,25 @@ fn make_payment(mut pmt: PaymentTx) -> Result<(), Error> {
                                                 pmt: pmt.clone(),
                                                 attempt: 0u8,
                                             }));
+                                        } else {
+                                            acknowledged(ResendInfo{
+                                                txid: tx_id.clone(),
+                                                contact_socket,
+                                                neigh_url: neighbor_url,
+                                                pmt: pmt.clone(),
+                                                attempt: 0u8,
+                                            });
+                                        }
+                                        if let Some((_, nonce)) = wallet_state_mut(&mut SETTING.get_payment_mut(), our_address) {
+                                            *nonce += 1u64.into();
                                         }
-                                        SETTING.get_payment_mut().nonce += 1u64.into();
 
 
                                         let ts = ToValidate {
                                             payment: pmt,
                                             recieved: Instant::now(),
-                                            checked: false
+                                            checked: false,
+                                            peer: None,
                                        };
 
                                       PaymentValidator::from_registry().do_send(ValidateLater(ts));
@@ -203,13 +409,12 @@ fn make_payment(mut pmt: PaymentTx) -> Result<(), Error> {
 
                         // triggering a nonce update may help us if the oracle modules updates
                         // are slow for some reason
+                        let web3 = Web3::new(&full_node, TRANSACTION_SUBMISSON_TIMEOUT);
                         trigger_update_nonce(our_address, &web3, full_node);
 
                         // we have not yet published the tx (at least hopefully)
                         // so it's safe to add this debt back to our balances
-                        DebtKeeper::from_registry().do_send(PaymentFailed {
-                            to: pmt.to,
-                        });
+                        payment_failed(pmt.to, reservation);
                         Either::B(future::ok(()))
                     }
                 }
@@ -221,9 +426,7 @@ fn make_payment(mut pmt: PaymentTx) -> Result<(), Error> {
                     "Failed to connect to neighbor for bandwidth payment {:?}",
                     e
                 );
-                DebtKeeper::from_registry().do_send(PaymentFailed {
-                    to: pmt.to,
-                });
+                payment_failed(pmt.to, reservation);
                 Either::B(future::ok(()))
             }
         }));
@@ -232,6 +435,115 @@ fn make_payment(mut pmt: PaymentTx) -> Result<(), Error> {
     Ok(())
 }
 
+/// Our neighbor took a payment. Those that answer `/payment_status` are asked later on whether
+/// they still have it, since they drop txids their full node hasn't seen yet, and for the receipt
+/// once it's validated if they sign them. The rest we're done with
+fn acknowledged(info: ResendInfo) {
+    let features = TunnelManager::from_registry().send(GetNeighborFeatures(info.pmt.to));
+    let res = features.then(move |features| {
+        let features = match features {
+            Ok(Ok(features)) => features,
+            _ => FeatureFlags::default(),
+        };
+        if features.contains(FeatureFlags::PAYMENT_STATUS) {
+            let receipts = features.contains(FeatureFlags::PAYMENT_RECEIPTS);
+            if !receipts {
+                trace!("{} does not sign payment receipts", info.pmt.to.mesh_ip);
+            }
+            confirm_payment(info, receipts, 0);
+        } else {
+            PaymentController::from_registry().do_send(Settled(info.pmt.payment_id));
+        }
+        Ok(()) as Result<(), ()>
+    });
+    Arbiter::spawn(res);
+}
+
+/// Checks the receipt our neighbor signed for a payment and hands it to DebtKeeper for the
+/// ledger
+fn store_receipt(receipt: PaymentReceipt, pmt: &PaymentTx) {
+    match check_receipt(&receipt, pmt) {
+        Ok(()) => DebtKeeper::from_registry().do_send(PaymentReceiptReceived {
+            to: pmt.to,
+            receipt,
+        }),
+        Err(e) => warn!("Invalid payment receipt from {} {:?}", pmt.to.mesh_ip, e),
+    }
+}
+
+/// Asks our neighbor where a payment it acknowledged is at after `PAYMENT_STATUS_DELAY`, if it
+/// dropped the txid because its full node hadn't seen it, or doesn't know of it at all, the txid
+/// is sent again. While it's pending we keep asking for up to `RECEIPT_CHECKS` times if we're
+/// waiting on a receipt, `check` is how many times we've asked
+fn confirm_payment(input: ResendInfo, receipts: bool, check: u8) {
+    let status_url = input.neigh_url.replace("/make_payment", "/payment_status");
+    let contact_socket = input.contact_socket;
+    let txid = input.txid.clone();
+    let res = Delay::new(Instant::now() + PAYMENT_STATUS_DELAY)
+        .then(move |_| TokioTcpStream::connect(&contact_socket))
+        .from_err()
+        .and_then(move |stream| {
+            let request = match client::post(&status_url)
+                .with_connection(Connection::from_stream(stream))
+                .json(&txid)
+            {
+                Ok(val) => val,
+                Err(e) => return Either::A(future::err(format_err!("{:?}", e))),
+            };
+            Either::B(
+                request
+                    .send()
+                    .timeout(TRANSACTION_SUBMISSON_TIMEOUT)
+                    .from_err()
+                    .and_then(|response| response.json::<PaymentStatus>().from_err()),
+            )
+        })
+        .then(move |res: Result<PaymentStatus, Error>| {
+            let check_again = receipts && check + 1 < RECEIPT_CHECKS;
+            match res {
+                Ok(PaymentStatus::Validated { receipt }) => {
+                    PaymentController::from_registry().do_send(Settled(input.pmt.payment_id));
+                    match receipt {
+                        Some(receipt) => store_receipt(receipt, &input.pmt),
+                        // validated a moment ago, the receipt is on its way
+                        None if check_again => confirm_payment(input, receipts, check + 1),
+                        None => {}
+                    }
+                }
+                Ok(PaymentStatus::Pending) => {
+                    PaymentController::from_registry().do_send(Settled(input.pmt.payment_id));
+                    if check_again {
+                        confirm_payment(input, receipts, check + 1);
+                    }
+                }
+                Ok(PaymentStatus::Failed { ref reason }) if reason != NOT_FOUND => {
+                    error!(
+                        "{} failed to validate our payment {:#066x}, {}",
+                        input.pmt.to.mesh_ip, input.txid, reason
+                    );
+                    PaymentController::from_registry().do_send(Settled(input.pmt.payment_id));
+                }
+                Ok(status) => {
+                    info!(
+                        "{} no longer has our payment {:#066x} {:?}, resending",
+                        input.pmt.to.mesh_ip, input.txid, status
+                    );
+                    PaymentController::from_registry().do_send(ResendTxid(input));
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to check on our payment {:#066x} {:?}",
+                        input.txid, e
+                    );
+                    PaymentController::from_registry().do_send(ResendTxid(input));
+                }
+            }
+            Ok(()) as Result<(), ()>
+        });
+    Arbiter::spawn(res);
+}
+
+#[derive(Clone)]
 struct ResendInfo {
     txid: Uint256,
     contact_socket: SocketAddr,
@@ -252,7 +564,7 @@ impl Handler<ResendTxid> for PaymentController {
 }
 
 /// For some reason we have sent a payment and managed not to notify our neighbor, this routine will
-/// retry up to MAX_TXID_RETIRES times
+/// retry up to MAX_TXID_RETIRES times, backing off a little more each time
 fn resend_txid(input: ResendInfo) {
     let txid = input.txid;
     let contact_socket = input.contact_socket;
@@ -262,10 +574,17 @@ fn resend_txid(input: ResendInfo) {
 
     // at this point the chance of success is too tiny to be worth it
     if attempt > MAX_TXID_RETRIES {
+        error!(
+            "Giving up on telling {} about txid {:#066x}",
+            pmt.to.mesh_ip, txid
+        );
+        PaymentController::from_registry().do_send(Settled(pmt.payment_id));
         return;
     }
 
-    let stream = TokioTcpStream::connect(&contact_socket);
+    let backoff = TXID_RETRY_BACKOFF * u32::from(attempt);
+    let stream = Delay::new(Instant::now() + backoff)
+        .then(move |_| TokioTcpStream::connect(&contact_socket));
 
     let futures_chain = Box::new(stream.then(move |open_stream| {
         match open_stream {
@@ -278,7 +597,14 @@ fn resend_txid(input: ResendInfo) {
                     .timeout(TRANSACTION_SUBMISSON_TIMEOUT)
                     .then(move |neigh_ack| match neigh_ack {
                         Ok(msg) => {
-                            if !msg.status().is_success() {
+                            if msg.status() == StatusCode::CONFLICT {
+                                error!(
+                                    "Our neighbor has already seen payment id {:?} for another txid, not retrying {:#066x}",
+                                    pmt.payment_id, txid
+                                );
+                                PaymentController::from_registry()
+                                    .do_send(Settled(pmt.payment_id));
+                            } else if !msg.status().is_success() {
                                 error!("retry failed with published txid: {:#066x}", txid);
                                 PaymentController::from_registry().do_send(ResendTxid(
                                     ResendInfo {
@@ -289,6 +615,16 @@ fn resend_txid(input: ResendInfo) {
                                         attempt,
                                     },
                                 ));
+                            } else {
+                                acknowledged(
+                                    ResendInfo {
+                                        txid,
+                                        contact_socket,
+                                        neigh_url,
+                                        pmt,
+                                        attempt,
+                                    },
+                                );
                             }
 
                             Ok(()) as Result<(), ()>
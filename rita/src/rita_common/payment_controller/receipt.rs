@@ -0,0 +1,112 @@
+//! Signed receipts for on chain payments. When a payment from a neighbor has been validated we
+//! sign the txid, amount and a random nonce with our eth key and hand that out through
+//! `/payment_status`, the payer keeps it in the debt ledger. Should we later claim to never have
+//! been paid the receipt proves otherwise, since only the holder of our eth key could have
+//! produced it. Receipts are never signed for payments that haven't been found in the chain, so
+//! a txid alone gets nobody a receipt.
+
+use crate::SETTING;
+use althea_types::{PaymentReceipt, PaymentTx};
+use clarity::PrivateKey;
+use failure::Error;
+use num256::Uint256;
+use settings::RitaCommonSettings;
+use sha3::{Digest, Keccak256};
+use sodiumoxide::randombytes::randombytes_into;
+
+const RECEIPT_LABEL: &[u8] = b"althea payment receipt";
+
+fn receipt_digest(txid: &Uint256, amount: &Uint256, nonce: &[u8; 32]) -> Vec<u8> {
+    let mut hasher = Keccak256::new();
+    hasher.input(RECEIPT_LABEL);
+    // fixed width so that no two txid and amount pairs encode the same
+    hasher.input(format!("{:#066x}{:#066x}", txid, amount).as_bytes());
+    hasher.input(nonce);
+    hasher.result().to_vec()
+}
+
+fn make_receipt(txid: Uint256, amount: Uint256, key: &PrivateKey) -> Result<PaymentReceipt, Error> {
+    let mut nonce = [0u8; 32];
+    randombytes_into(&mut nonce);
+    let signature = key.sign_hash(&receipt_digest(&txid, &amount, &nonce));
+    Ok(PaymentReceipt {
+        txid,
+        amount,
+        nonce,
+        payee: key.to_public_key()?,
+        signature,
+    })
+}
+
+/// Signs a receipt for a payment we have been sent, only once it's validated
+pub fn sign_receipt(pmt: &PaymentTx) -> Result<PaymentReceipt, Error> {
+    let txid = match pmt.txid.clone() {
+        Some(txid) => txid,
+        None => bail!("Can't sign a receipt for a payment without a txid"),
+    };
+    let payment_settings = SETTING.get_payment();
+    let key = match payment_settings.eth_private_key {
+        Some(key) => key,
+        None => bail!("No eth key configured yet"),
+    };
+    // with an external signer the local key may not be the one we are paid at
+    if Some(key.to_public_key()?) != payment_settings.eth_address {
+        bail!("Our eth key is held by the external signer, can't sign receipts");
+    }
+    drop(payment_settings);
+    make_receipt(txid, pmt.amount.clone(), &key)
+}
+
+/// Checks that a receipt was signed by the payee it names
+pub fn verify_receipt(receipt: &PaymentReceipt) -> Result<(), Error> {
+    let digest = receipt_digest(&receipt.txid, &receipt.amount, &receipt.nonce);
+    let signer = match receipt.signature.recover(&digest) {
+        Ok(val) => val,
+        Err(e) => bail!("Malformed receipt signature {:?}", e),
+    };
+    if signer != receipt.payee {
+        bail!("Receipt was signed by {} not {}", signer, receipt.payee);
+    }
+    Ok(())
+}
+
+/// Checks a receipt we got back from a neighbor we just paid, it must acknowledge the payment
+/// we actually made and come from the neighbor we paid
+pub fn check_receipt(receipt: &PaymentReceipt, pmt: &PaymentTx) -> Result<(), Error> {
+    verify_receipt(receipt)?;
+    if receipt.payee != pmt.to.eth_address {
+        bail!(
+            "Receipt is from {} but we paid {}",
+            receipt.payee,
+            pmt.to.eth_address
+        );
+    }
+    if Some(&receipt.txid) != pmt.txid.as_ref() || receipt.amount != pmt.amount {
+        bail!("Receipt does not match the payment we made");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_receipt_signature() {
+        let key: PrivateKey = "fe1bfa04d82b1e5a73f3a2d1a6d4b0d0f0c1ef1d1ba3c5d3c0b1e0fa5b1c2d3e"
+            .parse()
+            .unwrap();
+        let receipt = make_receipt(1234u32.into(), 5000u32.into(), &key).unwrap();
+        assert!(verify_receipt(&receipt).is_ok());
+
+        let mut inflated = receipt.clone();
+        inflated.amount = 50_000u32.into();
+        assert!(verify_receipt(&inflated).is_err());
+
+        let mut someone_else = receipt.clone();
+        someone_else.payee = "0x0000000000000000000000000000000000000001"
+            .parse()
+            .unwrap();
+        assert!(verify_receipt(&someone_else).is_err());
+    }
+}
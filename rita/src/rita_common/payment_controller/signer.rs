@@ -0,0 +1,241 @@
+//! Signing transactions with a key that isn't stored on the router. When an external signer is
+//! configured every transaction we send is posted unsigned to `<url>/sign` as json
+//!
+//! {"from": "0x..", "nonce": "1", "gas_price": "1000000000", "gas_limit": "21000",
+//!  "to": "0x..", "value": "1000", "data": "0x", "chain_id": 100}
+//!
+//! with numbers as decimal strings, and the signer replies with the signature as
+//! `{"v": "235", "r": "..", "s": ".."}`. The signature is checked against the address the signer
+//! is configured for and the chain before anything is broadcast. If the signer can't be reached
+//! in time or refuses, the configured fallback decides if the transaction fails or is signed
+//! with the local key instead.
+
+use crate::SETTING;
+use actix_web::client;
+use actix_web::HttpMessage;
+use clarity::{Address, PrivateKey, Signature, Transaction};
+use failure::Error;
+use futures01::future::Either;
+use futures01::{future, Future};
+use num256::Uint256;
+use settings::payment::{ExternalSignerSettings, SignerFallback};
+use settings::RitaCommonSettings;
+use std::time::Duration;
+use web30::client::Web3;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SignRequest {
+    pub from: Address,
+    pub nonce: Uint256,
+    pub gas_price: Uint256,
+    pub gas_limit: Uint256,
+    pub to: Address,
+    pub value: Uint256,
+    /// hex with a 0x prefix
+    pub data: String,
+    /// the EIP-155 chain id, None if the signature shouldn't commit to one
+    pub chain_id: Option<u64>,
+}
+
+impl SignRequest {
+    fn new(tx: &Transaction, from: Address, chain_id: Option<u64>) -> SignRequest {
+        let data: String = tx.data.iter().map(|b| format!("{:02x}", b)).collect();
+        SignRequest {
+            from,
+            nonce: tx.nonce.clone(),
+            gas_price: tx.gas_price.clone(),
+            gas_limit: tx.gas_limit.clone(),
+            to: tx.to,
+            value: tx.value.clone(),
+            data: format!("0x{}", data),
+            chain_id,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SignResponse {
+    pub v: Uint256,
+    pub r: Uint256,
+    pub s: Uint256,
+}
+
+/// Puts the signer's signature on the transaction, as long as it's for the right chain and
+/// recovers to the address we expect. Anything else would be rejected by the full node at best
+fn attach_signature(
+    tx: &Transaction,
+    response: SignResponse,
+    from: Address,
+    chain_id: Option<u64>,
+) -> Result<Transaction, Error> {
+    if let Some(id) = chain_id {
+        let base = id * 2 + 35;
+        if response.v != base.into() && response.v != (base + 1).into() {
+            bail!("Signer signed for the wrong chain, v is {}", response.v);
+        }
+    }
+    let mut signed = tx.clone();
+    signed.signature = Some(Signature::new(response.v, response.r, response.s));
+    match signed.sender() {
+        Ok(sender) if sender == from => Ok(signed),
+        Ok(sender) => bail!("Signer signed as {} not {}", sender, from),
+        Err(e) => bail!("Signer returned an invalid signature {:?}", e),
+    }
+}
+
+fn sign_locally(
+    tx: &Transaction,
+    key: Option<PrivateKey>,
+    chain_id: Option<u64>,
+) -> Result<Transaction, Error> {
+    match key {
+        Some(key) => Ok(tx.clone().sign(&key, chain_id)),
+        None => bail!("No private key configured!"),
+    }
+}
+
+fn request_signature(
+    signer: &ExternalSignerSettings,
+    request: &SignRequest,
+) -> Box<dyn Future<Item = SignResponse, Error = Error>> {
+    let url = format!("{}/sign", signer.url);
+    let request = match client::post(&url).json(request) {
+        Ok(val) => val,
+        Err(e) => return Box::new(future::err(format_err!("{:?}", e))),
+    };
+    Box::new(
+        request
+            .send()
+            .timeout(Duration::from_secs(signer.timeout_seconds))
+            .from_err()
+            .and_then(|response| {
+                if !response.status().is_success() {
+                    return Either::A(future::err(format_err!(
+                        "Signer refused with {}",
+                        response.status()
+                    )));
+                }
+                Either::B(response.json().from_err())
+            }),
+    )
+}
+
+/// Signs a transaction with the external signer if there is one and with the local key
+/// otherwise
+pub fn sign_transaction(tx: Transaction) -> Box<dyn Future<Item = Transaction, Error = Error>> {
+    let payment_settings = SETTING.get_payment();
+    let local_key = payment_settings.eth_private_key;
+    let chain_id = payment_settings.net_version;
+    let signer = payment_settings.external_signer.clone();
+    drop(payment_settings);
+
+    let signer = match signer {
+        Some(signer) => signer,
+        None => return Box::new(future::result(sign_locally(&tx, local_key, chain_id))),
+    };
+    let from = signer.address;
+    let fallback = signer.fallback;
+    let request = SignRequest::new(&tx, from, chain_id);
+    Box::new(
+        request_signature(&signer, &request)
+            .and_then({
+                let tx = tx.clone();
+                move |response| attach_signature(&tx, response, from, chain_id)
+            })
+            .or_else(move |e| match fallback {
+                SignerFallback::Fail => Err(e),
+                SignerFallback::LocalKey => {
+                    warn!("External signer failed, signing locally {:?}", e);
+                    // a key for another account would pay from the wrong balance with the
+                    // wrong nonce
+                    match local_key.map(|key| key.to_public_key()) {
+                        Some(Ok(address)) if address == from => {
+                            sign_locally(&tx, local_key, chain_id)
+                        }
+                        _ => bail!("The local key isn't for {}, can't fall back to it", from),
+                    }
+                }
+            }),
+    )
+}
+
+/// Signs a transaction as above and publishes it, returning the txid
+pub fn sign_and_send(
+    tx: Transaction,
+    full_node: String,
+    timeout: Duration,
+) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+    Box::new(
+        sign_transaction(tx)
+            .and_then(move |transaction_signed| publish(&transaction_signed, &full_node, timeout)),
+    )
+}
+
+/// Signs a transaction with one of our other wallets' keys and publishes it, returning the txid
+pub fn sign_with_key_and_send(
+    tx: Transaction,
+    key: PrivateKey,
+    full_node: String,
+    timeout: Duration,
+) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+    let chain_id = SETTING.get_payment().net_version;
+    match sign_locally(&tx, Some(key), chain_id) {
+        Ok(transaction_signed) => publish(&transaction_signed, &full_node, timeout),
+        Err(e) => Box::new(future::err(e)),
+    }
+}
+
+fn publish(
+    transaction_signed: &Transaction,
+    full_node: &str,
+    timeout: Duration,
+) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+    match transaction_signed.to_bytes() {
+        Ok(bytes) => Box::new(Web3::new(full_node, timeout).eth_send_raw_transaction(bytes)),
+        Err(e) => Box::new(future::err(format_err!(
+            "Failed to generate transaction, {:?}",
+            e
+        ))),
+    }
+}
+
+#[test]
+fn test_attach_signature() {
+    let key: PrivateKey = "0xc5e8f61d1ab959b397eecc0a37a6517b8e67a0e7cf1f4bce5591f3ed80199122"
+        .parse()
+        .unwrap();
+    let from = key.to_public_key().unwrap();
+    let tx = Transaction {
+        nonce: 4u32.into(),
+        gas_price: 1_000_000_000u64.into(),
+        gas_limit: 21_000u32.into(),
+        to: "0x0000000000000000000000000000000000000001"
+            .parse()
+            .unwrap(),
+        value: 1000u32.into(),
+        data: Vec::new(),
+        signature: None,
+    };
+    let request = SignRequest::new(&tx, from, Some(100));
+    assert_eq!(request.data, "0x");
+    assert_eq!(request.gas_limit, 21_000u32.into());
+
+    // stands in for the signer
+    let signature = tx.clone().sign(&key, Some(100)).signature.unwrap();
+    let response = SignResponse {
+        v: signature.v.clone(),
+        r: signature.r.clone(),
+        s: signature.s.clone(),
+    };
+
+    let signed = attach_signature(&tx, response.clone(), from, Some(100)).unwrap();
+    assert_eq!(signed, tx.clone().sign(&key, Some(100)));
+    // a signature for another chain or account is refused
+    assert!(attach_signature(&tx, response.clone(), from, Some(1)).is_err());
+    let other = "0x0000000000000000000000000000000000000002"
+        .parse()
+        .unwrap();
+    assert!(attach_signature(&tx, response, other, Some(100)).is_err());
+
+    assert!(sign_locally(&tx, None, Some(100)).is_err());
+}
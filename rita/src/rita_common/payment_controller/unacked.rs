@@ -0,0 +1,43 @@
+//! Payments we've published that our neighbor hasn't acknowledged yet. Once a transaction is
+//! published the money is gone whether or not our neighbor ever hears of it, so these are kept
+//! on disk and sent again after a restart. Every retry carries the payment's id, which our
+//! neighbor uses to tell it apart from a new payment, so that however many times it's sent it's
+//! only credited once.
+//!
+//! A payment that's published is written out right away, payments that are settled are only
+//! dropped from the file every few seconds, at worst a restart sends one of those again and
+//! our neighbor recognizes the payment id.
+
+use crate::rita_common::utils::save_json;
+use crate::SETTING;
+use althea_types::PaymentTx;
+use failure::Error;
+use settings::RitaCommonSettings;
+use std::collections::HashMap;
+use std::fs::File;
+
+/// The unacknowledged payments by payment id, an unreadable file is logged and treated as empty
+pub fn load_unacked() -> HashMap<u64, PaymentTx> {
+    let path = SETTING.get_payment().unacked_payments_file.clone();
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        // nothing was left unacknowledged
+        Err(_) => return HashMap::new(),
+    };
+    match serde_json::from_reader::<_, Vec<PaymentTx>>(file) {
+        Ok(payments) => payments
+            .into_iter()
+            .filter_map(|pmt| Some((pmt.payment_id?, pmt)))
+            .collect(),
+        Err(e) => {
+            error!("Failed to read unacknowledged payments {:?}", e);
+            HashMap::new()
+        }
+    }
+}
+
+pub fn save_unacked(unacked: &HashMap<u64, PaymentTx>) -> Result<(), Error> {
+    let path = SETTING.get_payment().unacked_payments_file.clone();
+    let payments: Vec<&PaymentTx> = unacked.values().collect();
+    save_json(&path, &payments)
+}
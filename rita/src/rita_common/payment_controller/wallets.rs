@@ -0,0 +1,289 @@
+//! Picking the wallet a payment is made from and keeping each wallet under its daily spending
+//! cap. Payments are made from the first of the configured wallets that lists their purpose and
+//! from the main wallet otherwise. The caps are there to stop a bug or a misbehaving neighbor from
+//! draining a wallet with a runaway stream of payments, a payment that would take a wallet past
+//! its cap fails and DebtKeeper tries again later, by which time a new day may have started.
+//! A payment's amount is reserved against the cap before it's made and given back if it fails
+//! before it's published, so payments in flight at the same time can't each squeeze under the
+//! cap. Spending is written to disk as it changes so that a restart doesn't start the day over.
+
+use crate::rita_common::utils::save_json;
+use althea_types::PaymentTx;
+use clarity::{Address, PrivateKey};
+use failure::Error;
+use num256::Uint256;
+use settings::payment::{PaymentPurpose, PaymentSettings, WalletSettings};
+use std::collections::HashMap;
+use std::fs::File;
+
+const SECONDS_PER_DAY: u64 = 86400;
+
+/// The wallet a payment is made from along with the state the oracle keeps for it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Wallet {
+    /// None for the main wallet, which is signed for with the external signer if there is one
+    pub key: Option<PrivateKey>,
+    pub address: Address,
+    pub balance: Uint256,
+    pub nonce: Uint256,
+    pub daily_cap: Option<Uint256>,
+}
+
+fn wallet_address(wallet: &WalletSettings) -> Option<Address> {
+    match wallet.eth_private_key.to_public_key() {
+        Ok(address) => Some(address),
+        Err(e) => {
+            error!("Invalid private key for a wallet {:?}", e);
+            None
+        }
+    }
+}
+
+/// The wallet payments for this purpose are made from
+pub fn select_wallet(settings: &PaymentSettings, purpose: PaymentPurpose) -> Result<Wallet, Error> {
+    for wallet in settings.wallets.iter() {
+        if !wallet.purposes.contains(&purpose) {
+            continue;
+        }
+        if let Some(address) = wallet_address(wallet) {
+            return Ok(Wallet {
+                key: Some(wallet.eth_private_key),
+                address,
+                balance: wallet.balance.clone(),
+                nonce: wallet.nonce.clone(),
+                daily_cap: wallet.daily_cap.clone(),
+            });
+        }
+    }
+    match settings.eth_address {
+        Some(address) => Ok(Wallet {
+            key: None,
+            address,
+            balance: settings.balance.clone(),
+            nonce: settings.nonce.clone(),
+            daily_cap: settings.daily_spending_cap.clone(),
+        }),
+        None => bail!("No eth address to pay from!"),
+    }
+}
+
+/// The addresses of all of our wallets, the main one first
+pub fn our_addresses(settings: &PaymentSettings) -> Vec<Address> {
+    settings
+        .eth_address
+        .into_iter()
+        .chain(settings.wallets.iter().filter_map(wallet_address))
+        .collect()
+}
+
+/// The balance and nonce the oracle keeps for the wallet with this address, None if it isn't
+/// one of ours
+pub fn wallet_state_mut(
+    settings: &mut PaymentSettings,
+    address: Address,
+) -> Option<(&mut Uint256, &mut Uint256)> {
+    if settings.eth_address == Some(address) {
+        return Some((&mut settings.balance, &mut settings.nonce));
+    }
+    settings
+        .wallets
+        .iter_mut()
+        .find(|wallet| wallet_address(wallet) == Some(address))
+        .map(|wallet| (&mut wallet.balance, &mut wallet.nonce))
+}
+
+/// An amount counted against a wallet's cap for a payment that's being made, given back with
+/// `DailySpending::release` if the payment fails before it's published
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reservation {
+    pub address: Address,
+    pub amount: Uint256,
+    /// the day the amount was counted against, days since the unix epoch
+    pub day: u64,
+}
+
+/// What each wallet has sent or is sending since the start of the current day, in UTC
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DailySpending {
+    day: u64,
+    /// a list since json maps only take string keys
+    spent: Vec<(Address, Uint256)>,
+}
+
+impl DailySpending {
+    /// The spending written by `save`, an unreadable file is logged and starts the day over
+    pub fn load(path: &str) -> DailySpending {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            // nothing has been spent yet
+            Err(_) => return DailySpending::default(),
+        };
+        match serde_json::from_reader(file) {
+            Ok(spending) => spending,
+            Err(e) => {
+                error!("Failed to read daily spending {:?}", e);
+                DailySpending::default()
+            }
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        save_json(path, self)
+    }
+
+    fn roll_over(&mut self, now: u64) {
+        let day = now / SECONDS_PER_DAY;
+        if day != self.day {
+            self.day = day;
+            self.spent.clear();
+        }
+    }
+
+    fn spent_mut(&mut self, address: Address) -> &mut Uint256 {
+        let index = match self.spent.iter().position(|(a, _)| *a == address) {
+            Some(index) => index,
+            None => {
+                self.spent.push((address, 0u32.into()));
+                self.spent.len() - 1
+            }
+        };
+        &mut self.spent[index].1
+    }
+
+    /// Counts this payment against the wallet's daily cap, erroring without counting it if it
+    /// would take the wallet past the cap. `now` is in seconds since the unix epoch
+    pub fn reserve(
+        &mut self,
+        wallet: &Wallet,
+        pmt: &PaymentTx,
+        now: u64,
+    ) -> Result<Reservation, Error> {
+        self.roll_over(now);
+        let spent = self.spent_mut(wallet.address);
+        if let Some(ref cap) = wallet.daily_cap {
+            if spent.clone() + pmt.amount.clone() > *cap {
+                bail!(
+                    "Paying {} to {} would take {} past its daily cap of {}, {} spent today",
+                    pmt.amount,
+                    pmt.to.eth_address,
+                    wallet.address,
+                    cap,
+                    spent
+                );
+            }
+        }
+        *spent += pmt.amount.clone();
+        Ok(Reservation {
+            address: wallet.address,
+            amount: pmt.amount.clone(),
+            day: self.day,
+        })
+    }
+
+    /// Gives back the amount reserved for a payment that wasn't made, unless the day it was
+    /// counted against is already over
+    pub fn release(&mut self, reservation: &Reservation, now: u64) {
+        self.roll_over(now);
+        if reservation.day != self.day {
+            return;
+        }
+        let spent = self.spent_mut(reservation.address);
+        if *spent > reservation.amount {
+            *spent -= reservation.amount.clone();
+        } else {
+            *spent = 0u32.into();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rita_common::utils::test_identity;
+
+    fn key(byte: u8) -> PrivateKey {
+        format!("0x{:064x}", byte).parse().unwrap()
+    }
+
+    fn payment(amount: u32) -> PaymentTx {
+        let to = test_identity("fd00::1");
+        PaymentTx {
+            to,
+            from: to,
+            amount: amount.into(),
+            txid: None,
+            channel: None,
+            payment_id: None,
+        }
+    }
+
+    #[test]
+    fn test_select_wallet() {
+        let main = key(1).to_public_key().unwrap();
+        let mut settings = PaymentSettings::default();
+        assert!(select_wallet(&settings, PaymentPurpose::Relay).is_err());
+        settings.eth_address = Some(main);
+        settings.wallets.push(WalletSettings {
+            eth_private_key: key(2),
+            purposes: vec![PaymentPurpose::Exit],
+            daily_cap: Some(1000u32.into()),
+            balance: 5000u32.into(),
+            nonce: 7u32.into(),
+        });
+
+        let relay = select_wallet(&settings, PaymentPurpose::Relay).unwrap();
+        assert_eq!(relay.key, None);
+        assert_eq!(relay.address, main);
+        let exit = select_wallet(&settings, PaymentPurpose::Exit).unwrap();
+        assert_eq!(exit.key, Some(key(2)));
+        assert_eq!(exit.nonce, 7u32.into());
+        assert_eq!(exit.daily_cap, Some(1000u32.into()));
+
+        assert_eq!(our_addresses(&settings), vec![main, exit.address]);
+        *wallet_state_mut(&mut settings, exit.address).unwrap().1 += 1u32.into();
+        assert_eq!(settings.wallets[0].nonce, 8u32.into());
+        assert!(wallet_state_mut(&mut settings, key(3).to_public_key().unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_daily_spending() {
+        let mut wallet = Wallet {
+            key: None,
+            address: key(1).to_public_key().unwrap(),
+            balance: 0u32.into(),
+            nonce: 0u32.into(),
+            daily_cap: Some(1000u32.into()),
+        };
+        let mut spending = DailySpending::default();
+        let today = 10 * SECONDS_PER_DAY + 100;
+
+        let first = spending.reserve(&wallet, &payment(600), today).unwrap();
+        // a payment still in flight counts
+        assert!(spending.reserve(&wallet, &payment(401), today).is_err());
+        let second = spending.reserve(&wallet, &payment(400), today).unwrap();
+        assert!(spending.reserve(&wallet, &payment(1), today).is_err());
+        // one that failed doesn't
+        spending.release(&second, today);
+        assert!(spending.reserve(&wallet, &payment(400), today).is_ok());
+        // the cap is per wallet
+        let mut other = wallet.clone();
+        other.address = key(2).to_public_key().unwrap();
+        assert!(spending.reserve(&other, &payment(400), today).is_ok());
+
+        // and per day, yesterday's payments aren't given back to today
+        let tomorrow = today + SECONDS_PER_DAY;
+        let third = spending.reserve(&wallet, &payment(1000), tomorrow).unwrap();
+        spending.release(&first, tomorrow);
+        assert!(spending.reserve(&wallet, &payment(1), tomorrow).is_err());
+        spending.release(&third, tomorrow);
+        assert!(spending.reserve(&wallet, &payment(1000), tomorrow).is_ok());
+
+        // no cap, no limit
+        wallet.daily_cap = None;
+        assert!(spending.reserve(&wallet, &payment(5000), tomorrow).is_ok());
+
+        let restored: DailySpending =
+            serde_json::from_str(&serde_json::to_string(&spending).unwrap()).unwrap();
+        assert_eq!(restored.spent, spending.spent);
+    }
+}
@@ -6,37 +6,82 @@
 //! attempt to validate these payments every 5 seconds, if successful the payment is sent
 //! off to debt keeper to be removed from the owed balance. Payments may time out after a
 //! configured period.
+//!
+//! Payers are acknowledged as soon as their txid is queued and can ask for the outcome with
+//! `/payment_status`. The queue is bounded, overall and per peer address the payments come in
+//! from rather than the payer they claim to be from, so that a flood of txids can't pile up
+//! checks against the full node or use up someone else's share. A payment to us whose txid the
+//! full node has never heard of is dropped after the first lookup instead of sitting in the
+//! queue until it times out, a payer whose payment is turned away or dropped that way is told
+//! so by `/payment_status` and its payment controller resends the txid.
+//!
+//! Payments carry a payment id the payer keeps through retries, we remember which txid each
+//! payer's ids were used for so that a retry is acknowledged like the first attempt while a
+//! second transaction under an id we've already seen is refused rather than credited twice.
+//! Those ids and the txids we've credited are written to disk as they change so that a restart
+//! doesn't open the door to a replay. Both are forgotten after a day, by then the transaction
+//! is well past the age at which the backend calls it too old to credit.
+//!
+//! Validated payments to us get a signed receipt, see payment_controller::receipt, which the
+//! payer picks up from `/payment_status`.
 
 use crate::rita_common::debt_keeper::DebtKeeper;
 use crate::rita_common::debt_keeper::PaymentReceived;
 use crate::rita_common::debt_keeper::PaymentSucceeded;
+use crate::rita_common::payment_controller::backend::payment_backend;
+use crate::rita_common::payment_controller::backend::VerifiedTx;
+use crate::rita_common::payment_controller::receipt::sign_receipt;
+use crate::rita_common::payment_controller::wallets::our_addresses;
 use crate::rita_common::rita_loop::fast_loop::FAST_LOOP_TIMEOUT;
 use crate::rita_common::rita_loop::get_web3_server;
 use crate::rita_common::usage_tracker::UpdatePayments;
 use crate::rita_common::usage_tracker::UsageTracker;
+use crate::rita_common::utils::{now_secs, save_json};
 use crate::SETTING;
 use actix::{Actor, Arbiter, Context, Handler, Message, Supervised, SystemService};
-use althea_types::PaymentTx;
+use althea_types::{PaymentReceipt, PaymentStatus, PaymentTx};
+use clarity::Address;
 use failure::Error;
 use futures01::Future;
 use num256::Uint256;
 use settings::RitaCommonSettings;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
+use std::fs::File;
+use std::net::IpAddr;
 use std::time::{Duration, Instant};
 use tokio::util::FutureExt;
-use web30::client::Web3;
-use web30::types::TransactionResponse;
 
 pub const TRANSACTION_VERIFICATION_TIMEOUT: Duration = FAST_LOOP_TIMEOUT;
 
 // Discard payments after 15 minutes of failing to find txid
 pub const PAYMENT_TIMEOUT: Duration = Duration::from_secs(900u64);
-// How many blocks before we assume finality
-const BLOCKS_TO_CONFIRM: u32 = 4;
-// How old does a txid need to be before we don't accept it?
-// this is 12 hours
-const BLOCKS_TO_OLD: u32 = 1440;
+
+/// How many payments to us can wait on validation at once
+const MAX_UNVALIDATED: usize = 512;
+/// How many of those can come in from one peer address
+const MAX_UNVALIDATED_PER_PEER: usize = 16;
+/// How many failed payments we remember the reason for
+const FAILED_HISTORY: usize = 256;
+/// How many receipts for validated payments we keep for payers to pick up
+const RECEIPT_HISTORY: usize = 256;
+/// How long we remember payment ids and credited txids in seconds, well past the time a payer
+/// keeps retrying
+const PAYMENT_ID_MEMORY: u64 = 86400;
+/// The reason given for payments dropped because the full node had never heard of them, payers
+/// resend the txid when they see it
+pub const NOT_FOUND: &str = "Not found";
+
+/// Why a payment to us wasn't queued for validation
+#[derive(Debug, Fail)]
+pub enum Refused {
+    #[fail(display = "Unpublished transaction")]
+    Unpublished,
+    #[fail(display = "{}", _0)]
+    QueueFull(&'static str),
+    #[fail(display = "Payment id {} was already used for txid {:#066x}", _0, _1)]
+    ReusedId(u64, Uint256),
+}
 
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
 pub struct ToValidate {
@@ -47,6 +92,8 @@ pub struct ToValidate {
     /// if we have managed to talk to a full node about this
     /// transaction ever
     pub checked: bool,
+    /// the address the payment came in from, None for our own payments
+    pub peer: Option<IpAddr>,
 }
 
 impl fmt::Display for ToValidate {
@@ -64,7 +111,14 @@ impl fmt::Display for ToValidate {
 
 pub struct PaymentValidator {
     unvalidated_transactions: HashSet<ToValidate>,
-    successful_transactions: HashSet<Uint256>,
+    /// the txids we've validated and when
+    successful_transactions: HashMap<Uint256, u64>,
+    /// the latest payments that failed validation, oldest first
+    failed_transactions: VecDeque<(Uint256, String)>,
+    /// the txid each payer's payment ids were used for and when we first saw them
+    payment_ids: HashMap<(Address, u64), (Uint256, u64)>,
+    /// receipts for the latest validated payments to us, oldest first
+    receipts: VecDeque<PaymentReceipt>,
 }
 
 impl Actor for PaymentValidator {
@@ -82,14 +136,69 @@ impl PaymentValidator {
     pub fn new() -> Self {
         PaymentValidator {
             unvalidated_transactions: HashSet::new(),
-            successful_transactions: HashSet::new(),
+            successful_transactions: HashMap::new(),
+            failed_transactions: VecDeque::new(),
+            payment_ids: HashMap::new(),
+            receipts: VecDeque::new(),
+        }
+    }
+
+    fn save_history(&self) {
+        let history = PaymentHistory {
+            successful: self
+                .successful_transactions
+                .iter()
+                .map(|(txid, seen)| (txid.clone(), *seen))
+                .collect(),
+            payment_ids: self
+                .payment_ids
+                .iter()
+                .map(|((payer, id), (txid, seen))| (*payer, *id, txid.clone(), *seen))
+                .collect(),
+        };
+        let path = SETTING.get_payment().payment_history_file.clone();
+        if let Err(e) = save_json(&path, &history) {
+            error!("Failed to save payment history {:?}", e);
         }
     }
 }
 
 impl Default for PaymentValidator {
     fn default() -> PaymentValidator {
-        PaymentValidator::new()
+        let mut validator = PaymentValidator::new();
+        let history = load_history();
+        validator.successful_transactions = history.successful.into_iter().collect();
+        validator.payment_ids = history
+            .payment_ids
+            .into_iter()
+            .map(|(payer, id, txid, seen)| ((payer, id), (txid, seen)))
+            .collect();
+        validator
+    }
+}
+
+/// The txids we've credited and the payment ids we've seen, as they're kept on disk
+#[derive(Serialize, Deserialize, Default)]
+struct PaymentHistory {
+    successful: Vec<(Uint256, u64)>,
+    payment_ids: Vec<(Address, u64, Uint256, u64)>,
+}
+
+/// The payment history from before we last stopped, an unreadable file is logged and treated as
+/// empty
+fn load_history() -> PaymentHistory {
+    let path = SETTING.get_payment().payment_history_file.clone();
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        // we've never been paid
+        Err(_) => return PaymentHistory::default(),
+    };
+    match serde_json::from_reader(file) {
+        Ok(history) => history,
+        Err(e) => {
+            error!("Failed to read payment history {:?}", e);
+            PaymentHistory::default()
+        }
     }
 }
 
@@ -98,26 +207,152 @@ impl Default for PaymentValidator {
 /// into the blockchain. Transactions that are too old are prevented from being played back
 /// by using a history of successful transactions.
 /// This endpoint specifically (and only this one) is fully imdepotent so that we can retry
-/// txid transmissions
-#[derive(Message)]
+/// txid transmissions. Payments to us are turned away while the queue is full, ours never are
 pub struct ValidateLater(pub ToValidate);
 
+impl Message for ValidateLater {
+    type Result = Result<(), Refused>;
+}
+
 impl Handler<ValidateLater> for PaymentValidator {
-    type Result = ();
+    type Result = Result<(), Refused>;
 
     fn handle(&mut self, msg: ValidateLater, _ctx: &mut Context<Self>) -> Self::Result {
         let ts = msg.0;
-        if let Some(txid) = ts.payment.txid.clone() {
-            if !self.successful_transactions.contains(&txid) {
-                // insert is safe to run multiple times just so long as we check successful tx's for duplicates
-                self.unvalidated_transactions.insert(ts);
+        let txid = match ts.payment.txid.clone() {
+            Some(txid) => txid,
+            None => {
+                error!(
+                    "Someone tried to insert an unpublished transaction to validate!? {:?}",
+                    ts
+                );
+                return Err(Refused::Unpublished);
+            }
+        };
+        if let Some(id) = ts.payment.payment_id {
+            let key = (ts.payment.from.eth_address, id);
+            if let Err(e) = check_payment_id(&self.payment_ids, &key, &txid) {
+                warn!("Turning away payment {} {}", ts, e);
+                return Err(e);
+            }
+        }
+        let queued = self
+            .unvalidated_transactions
+            .iter()
+            .any(|queued| queued.payment.txid == ts.payment.txid);
+        if self.successful_transactions.contains_key(&txid) || queued {
+            return Ok(());
+        }
+        if let Some(peer) = ts.peer {
+            if let Some(e) = queue_full(&self.unvalidated_transactions, peer) {
+                warn!("Turning away payment {} from {} {}", ts, peer, e);
+                return Err(Refused::QueueFull(e));
+            }
+        }
+        if let Some(id) = ts.payment.payment_id {
+            self.payment_ids
+                .insert((ts.payment.from.eth_address, id), (txid, now_secs()));
+            self.save_history();
+        }
+        self.unvalidated_transactions.insert(ts);
+        Ok(())
+    }
+}
+
+/// A payment id may only ever be used for one txid, the same txid again is just a retry
+fn check_payment_id(
+    payment_ids: &HashMap<(Address, u64), (Uint256, u64)>,
+    key: &(Address, u64),
+    txid: &Uint256,
+) -> Result<(), Refused> {
+    match payment_ids.get(key) {
+        Some((used_for, _)) if used_for != txid => Err(Refused::ReusedId(key.1, used_for.clone())),
+        _ => Ok(()),
+    }
+}
+
+/// Why a payment coming in from `peer` can't be queued right now, if it can't. Our own
+/// payments don't count against anyone
+fn queue_full(queue: &HashSet<ToValidate>, peer: IpAddr) -> Option<&'static str> {
+    let mut total = 0;
+    let mut theirs = 0;
+    for ts in queue.iter().filter(|ts| ts.peer.is_some()) {
+        total += 1;
+        if ts.peer == Some(peer) {
+            theirs += 1;
+        }
+    }
+    if theirs >= MAX_UNVALIDATED_PER_PEER {
+        Some("Too many of your payments are waiting on validation")
+    } else if total >= MAX_UNVALIDATED {
+        Some("Too many payments are waiting on validation")
+    } else {
+        None
+    }
+}
+
+/// Where a payment to or from us is with validation
+pub struct GetPaymentStatus(pub Uint256);
+
+impl Message for GetPaymentStatus {
+    type Result = PaymentStatus;
+}
+
+impl Handler<GetPaymentStatus> for PaymentValidator {
+    type Result = PaymentStatus;
+
+    fn handle(&mut self, msg: GetPaymentStatus, _ctx: &mut Context<Self>) -> Self::Result {
+        let txid = msg.0;
+        if self.successful_transactions.contains_key(&txid) {
+            PaymentStatus::Validated {
+                receipt: self.receipts.iter().find(|r| r.txid == txid).cloned(),
+            }
+        } else if self
+            .unvalidated_transactions
+            .iter()
+            .any(|ts| ts.payment.txid.as_ref() == Some(&txid))
+        {
+            PaymentStatus::Pending
+        } else if let Some((_, reason)) = self
+            .failed_transactions
+            .iter()
+            .rev()
+            .find(|(failed, _)| *failed == txid)
+        {
+            PaymentStatus::Failed {
+                reason: reason.clone(),
             }
         } else {
-            error!(
-                "Someone tried to insert an unpublished transaction to validate!? {:?}",
-                ts
-            );
+            PaymentStatus::Unknown
+        }
+    }
+}
+
+impl PaymentValidator {
+    fn record_failure(&mut self, txid: Uint256, reason: &str) {
+        if self.failed_transactions.len() >= FAILED_HISTORY {
+            self.failed_transactions.pop_front();
+        }
+        self.failed_transactions
+            .push_back((txid, reason.to_string()));
+    }
+}
+
+/// A receipt for a payment to us that was just validated
+struct StoreReceipt(PaymentReceipt);
+
+impl Message for StoreReceipt {
+    type Result = ();
+}
+
+impl Handler<StoreReceipt> for PaymentValidator {
+    type Result = ();
+
+    fn handle(&mut self, msg: StoreReceipt, _ctx: &mut Context<Self>) -> Self::Result {
+        if self.receipts.len() >= RECEIPT_HISTORY {
+            self.receipts.pop_front();
         }
+        self.receipts.push_back(msg.0);
     }
 }
 
@@ -125,7 +360,8 @@ impl Handler<ValidateLater> for PaymentValidator {
 /// have been discovered to be invalid or have been succesfully accepted
 struct Remove {
     tx: ToValidate,
-    success: bool,
+    /// why it was invalid, None if it was accepted
+    failure: Option<&'static str>,
 }
 
 impl Message for Remove {
@@ -137,11 +373,17 @@ impl Handler<Remove> for PaymentValidator {
 
     fn handle(&mut self, msg: Remove, _ctx: &mut Context<Self>) -> Self::Result {
         let was_present = self.unvalidated_transactions.remove(&msg.tx);
-        // store successful transactions so that they can't be played back to us, at least
-        // during this session
-        if msg.success && was_present {
-            self.successful_transactions
-                .insert(msg.tx.payment.clone().txid.unwrap());
+        // store successful transactions so that they can't be played back to us, on disk so that
+        // a restart doesn't forget them
+        if was_present {
+            let txid = msg.tx.payment.clone().txid.unwrap();
+            match msg.failure {
+                None => {
+                    self.successful_transactions.insert(txid, now_secs());
+                    self.save_history();
+                }
+                Some(reason) => self.record_failure(txid, reason),
+            }
         }
         if was_present {
             info!("Transaction {} was removed", msg.tx);
@@ -210,6 +452,16 @@ impl Handler<Validate> for PaymentValidator {
 
         for item in to_delete.iter() {
             self.unvalidated_transactions.remove(item);
+            self.record_failure(item.payment.txid.clone().unwrap(), "Timed out");
+        }
+        let now = now_secs();
+        let remembered = self.payment_ids.len() + self.successful_transactions.len();
+        self.payment_ids
+            .retain(|_, (_, seen)| now.saturating_sub(*seen) < PAYMENT_ID_MEMORY);
+        self.successful_transactions
+            .retain(|_, seen| now.saturating_sub(*seen) < PAYMENT_ID_MEMORY);
+        if self.payment_ids.len() + self.successful_transactions.len() != remembered {
+            self.save_history();
         }
     }
 }
@@ -222,25 +474,37 @@ pub fn validate_transaction(ts: &ToValidate) {
     let txid = ts.payment.clone().txid.unwrap();
     let pmt = ts.payment.clone();
     let full_node = get_web3_server();
-    let web3 = Web3::new(&full_node, TRANSACTION_VERIFICATION_TIMEOUT);
+    let backend = payment_backend(full_node, TRANSACTION_VERIFICATION_TIMEOUT);
 
     let long_life_ts = ts.clone();
 
-    let res = web3
-        .eth_block_number()
-        .join(web3.eth_get_transaction_by_hash(txid.clone()))
+    let res = backend
+        .verify(txid.clone())
         // even though we sepcify the timeout above don't remove this, we need it to 100% ensure that operations time out
         // for example Actix may run slowly, web3 timeouts only care about actual request time
         .timeout(TRANSACTION_VERIFICATION_TIMEOUT)
-        .and_then(move |(block_num, tx_status)| {
+        .and_then(move |tx_status| {
             if !long_life_ts.checked {
                 PaymentValidator::from_registry().do_send(Checked {
                     tx: long_life_ts.clone(),
                 });
             }
 
-            if let Some(transaction) = tx_status {
-                handle_tx_messaging(txid, transaction, long_life_ts, block_num);
+            match tx_status {
+                Some(verified) => handle_tx_messaging(txid, verified, long_life_ts),
+                // we know ours were published, anyone else's we drop right away rather than let
+                // made up txids fill the queue, the payer sends it again if it was real
+                None if long_life_ts.peer.is_some() => {
+                    info!(
+                        "Dropping payment {} the full node hasn't seen",
+                        long_life_ts
+                    );
+                    PaymentValidator::from_registry().do_send(Remove {
+                        tx: long_life_ts,
+                        failure: Some(NOT_FOUND),
+                    });
+                }
+                None => {}
             }
             Ok(())
         })
@@ -259,28 +523,28 @@ pub fn validate_transaction(ts: &ToValidate) {
 
 /// Handles the tx response from the full node and it's various cases
 /// pulled out of validate_transaction purely for cosmetic reasons
-fn handle_tx_messaging(
-    txid: Uint256,
-    transaction: TransactionResponse,
-    ts: ToValidate,
-    current_block: Uint256,
-) {
+fn handle_tx_messaging(txid: Uint256, verified: VerifiedTx, ts: ToValidate) {
+    let transaction = verified.transaction;
     let from_address = ts.payment.from.eth_address;
     let amount = ts.payment.amount.clone();
     let pmt = ts.payment.clone();
-    let our_address = SETTING.get_payment().eth_address.expect("No Address!");
+    let payment_settings = SETTING.get_payment();
+    let our_address = payment_settings.eth_address.expect("No Address!");
+    // we may have paid out of any of our wallets, but we're only ever paid to the main one
+    let our_wallets = our_addresses(&payment_settings);
+    drop(payment_settings);
 
     let to_us = transaction.to == our_address;
-    let from_us = transaction.from == our_address;
+    let from_us = our_wallets.contains(&transaction.from);
     let value_correct = transaction.value == amount;
-    let is_in_chain = payment_in_chain(current_block.clone(), transaction.block_number.clone());
-    let is_old = payment_is_old(current_block, transaction.block_number);
+    let is_in_chain = verified.confirmed;
+    let is_old = verified.too_old;
 
     if !value_correct {
         error!("Transaction with invalid amount!");
         PaymentValidator::from_registry().do_send(Remove {
             tx: ts,
-            success: false,
+            failure: Some("Wrong amount"),
         });
         return;
     }
@@ -289,7 +553,7 @@ fn handle_tx_messaging(
         error!("Transaction is more than 6 hours old! {:#066x}", txid);
         PaymentValidator::from_registry().do_send(Remove {
             tx: ts,
-            success: false,
+            failure: Some("Too old"),
         });
         return;
     }
@@ -300,7 +564,7 @@ fn handle_tx_messaging(
             let res = PaymentValidator::from_registry()
                 .send(Remove {
                     tx: ts,
-                    success: true,
+                    failure: None,
                 })
                 .and_then(move |res| {
                     if res.is_ok() {
@@ -312,6 +576,12 @@ fn handle_tx_messaging(
                             from: pmt.from,
                             amount: pmt.amount.clone(),
                         });
+                        match sign_receipt(&pmt) {
+                            Ok(receipt) => {
+                                PaymentValidator::from_registry().do_send(StoreReceipt(receipt))
+                            }
+                            Err(e) => warn!("Failed to sign payment receipt {:?}", e),
+                        }
 
                         // update the usage tracker with the details of this payment
                         UsageTracker::from_registry().do_send(UpdatePayments { payment: pmt });
@@ -335,7 +605,7 @@ fn handle_tx_messaging(
             let res = PaymentValidator::from_registry()
                 .send(Remove {
                     tx: ts,
-                    success: true,
+                    failure: None,
                 })
                 .and_then(|res| {
                     if res.is_ok() {
@@ -355,14 +625,14 @@ fn handle_tx_messaging(
             error!("Transaction to ourselves!");
             PaymentValidator::from_registry().do_send(Remove {
                 tx: ts,
-                success: false,
+                failure: Some("Transaction to ourselves"),
             });
         }
         (false, false, _) => {
             error!("Transaction has nothing to do with us?");
             PaymentValidator::from_registry().do_send(Remove {
                 tx: ts,
-                success: false,
+                failure: Some("Not to or from us"),
             });
         }
         (_, _, false) => {
@@ -371,40 +641,88 @@ fn handle_tx_messaging(
     }
 }
 
-/// Determine if a given payment satisfies our criteria for being in the blockchain
-fn payment_in_chain(chain_height: Uint256, tx_height: Option<Uint256>) -> bool {
-    match tx_height {
-        Some(tx_block) => {
-            // somehow the block is newer than our block height request, wait until later
-            if tx_block > chain_height {
-                false
-            } else {
-                chain_height - tx_block >= Uint256::from(BLOCKS_TO_CONFIRM)
-            }
-        }
-        None => false,
+fn print_txids(list: &HashSet<ToValidate>) -> String {
+    let mut output = String::new();
+    for item in list.iter() {
+        output += &format!("{} ,", item);
     }
+    output
 }
 
-/// Determine if a given payment is older than what we shoul accept
-fn payment_is_old(chain_height: Uint256, tx_height: Option<Uint256>) -> bool {
-    match tx_height {
-        Some(tx_block) => {
-            // somehow the block is newer than our block height request, wait until later
-            if tx_block > chain_height {
-                false
-            } else {
-                chain_height - tx_block > Uint256::from(BLOCKS_TO_OLD)
-            }
-        }
-        None => false,
+#[test]
+fn test_queue_full() {
+    use althea_types::Identity;
+
+    let identity = |ip: &str, address: &str| {
+        Identity::new(
+            ip.parse().unwrap(),
+            address.parse().unwrap(),
+            "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+            None,
+        )
+    };
+    let us = identity("fd00::1", "0x0000000000000000000000000000000000000001");
+    let them = identity("fd00::2", "0x0000000000000000000000000000000000000002");
+    let other = identity("fd00::3", "0x0000000000000000000000000000000000000003");
+    let newcomer = identity("fd00::4", "0x0000000000000000000000000000000000000004");
+    let payment = |from: Identity, to: Identity, txid: u32| ToValidate {
+        payment: PaymentTx {
+            to,
+            from,
+            amount: 1000u32.into(),
+            txid: Some(txid.into()),
+            channel: None,
+            payment_id: None,
+        },
+        recieved: Instant::now(),
+        checked: false,
+        peer: if from == us { None } else { Some(from.mesh_ip) },
+    };
+
+    let mut queue = HashSet::new();
+    for txid in 0..MAX_UNVALIDATED_PER_PEER as u32 {
+        queue.insert(payment(them, us, txid));
+        // our own payments don't count against anyone
+        queue.insert(payment(us, them, 10_000 + txid));
+    }
+    assert!(queue_full(&queue, them.mesh_ip).is_some());
+    assert_eq!(queue_full(&queue, other.mesh_ip), None);
+
+    // payments claiming to be from someone else still count against the peer they came from
+    let mut forged = payment(other, us, 15_000);
+    forged.peer = Some(newcomer.mesh_ip);
+    queue.insert(forged);
+    assert_eq!(queue_full(&queue, other.mesh_ip), None);
+
+    for txid in 0..MAX_UNVALIDATED as u32 {
+        queue.insert(payment(other, us, 20_000 + txid));
     }
+    assert_eq!(
+        queue_full(&queue, "fd00::5".parse().unwrap()),
+        Some("Too many payments are waiting on validation")
+    );
 }
 
-fn print_txids(list: &HashSet<ToValidate>) -> String {
-    let mut output = String::new();
-    for item in list.iter() {
-        output += &format!("{} ,", item);
+#[test]
+fn test_check_payment_id() {
+    let payer: Address = "0x0000000000000000000000000000000000000002"
+        .parse()
+        .unwrap();
+    let mut payment_ids = HashMap::new();
+    let key = (payer, 42);
+    assert!(check_payment_id(&payment_ids, &key, &1u32.into()).is_ok());
+    payment_ids.insert(key, (1u32.into(), now_secs()));
+    // a retry of the same transaction
+    assert!(check_payment_id(&payment_ids, &key, &1u32.into()).is_ok());
+    match check_payment_id(&payment_ids, &key, &2u32.into()) {
+        Err(Refused::ReusedId(42, used_for)) => assert_eq!(used_for, 1u32.into()),
+        _ => panic!("Reused payment id was accepted"),
     }
-    output
+    // ids are per payer
+    let other: Address = "0x0000000000000000000000000000000000000003"
+        .parse()
+        .unwrap();
+    assert!(check_payment_id(&payment_ids, &(other, 42), &2u32.into()).is_ok());
 }
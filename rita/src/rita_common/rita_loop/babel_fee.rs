@@ -0,0 +1,81 @@
+//! Keeps the fee babel announces in line with ours. Babel forgets our fee when it's restarted and
+//! can turn down a `fee` command, either way we'd be relaying traffic at a price nobody is paying
+//! us for. The fee babel is announcing is read back at startup and every slow loop tick, set again
+//! if it has drifted and a watchdog event is journaled if that doesn't stick.
+
+use crate::rita_common::watchdog::{Journal, Watchdog, WatchdogEventKind};
+use ::actix::SystemService;
+use babel_monitor::{get_local_fee, set_local_fee, BabelMonitorError};
+use failure::Error;
+use futures01::future::{self, Either};
+use futures01::Future;
+use tokio::net::TcpStream;
+
+fn journal(kind: WatchdogEventKind) {
+    Watchdog::from_registry().do_send(Journal {
+        source: "Babel",
+        kind,
+    });
+}
+
+/// True if babel answered a command with `bad` or `no` rather than the connection failing
+fn is_refusal(e: &Error) -> bool {
+    match e.downcast_ref::<BabelMonitorError>() {
+        Some(BabelMonitorError::ReadFailed(_)) => true,
+        _ => false,
+    }
+}
+
+/// Checks babel is announcing `expected` and sets it again if it isn't, the stream is only
+/// handed back if babel ends up announcing it
+pub fn reconcile_local_fee(
+    stream: TcpStream,
+    expected: u32,
+) -> impl Future<Item = TcpStream, Error = Error> {
+    get_local_fee(stream).and_then(move |(stream, announced)| {
+        if announced == expected {
+            trace!("Babel is announcing our fee of {}", expected);
+            return Either::A(future::ok(stream));
+        }
+        warn!(
+            "Babel is announcing a fee of {} instead of {}, setting it again",
+            announced, expected
+        );
+        journal(WatchdogEventKind::BabelFeeDrift {
+            expected,
+            announced,
+        });
+        Either::B(
+            set_local_fee(stream, expected)
+                .and_then(get_local_fee)
+                .then(move |res| match res {
+                    Ok((stream, announced)) if announced == expected => Ok(stream),
+                    Ok((_stream, announced)) => {
+                        journal(WatchdogEventKind::BabelFeeRefused { fee: expected });
+                        bail!(
+                            "Babel is still announcing a fee of {} instead of {}",
+                            announced,
+                            expected
+                        )
+                    }
+                    Err(e) => {
+                        if is_refusal(&e) {
+                            journal(WatchdogEventKind::BabelFeeRefused { fee: expected });
+                        }
+                        Err(e)
+                    }
+                }),
+        )
+    })
+}
+
+#[test]
+fn test_is_refusal() {
+    assert!(is_refusal(
+        &BabelMonitorError::ReadFailed("bad\n".to_string()).into()
+    ));
+    assert!(!is_refusal(
+        &BabelMonitorError::TokioError("connection reset".to_string()).into()
+    ));
+    assert!(!is_refusal(&format_err!("timed out")));
+}
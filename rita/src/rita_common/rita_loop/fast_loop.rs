@@ -5,9 +5,11 @@ use crate::rita_common::oracle::{Oracle, Update};
 use crate::rita_common::payment_validator::{PaymentValidator, Validate};
 use crate::rita_common::peer_listener::GetPeers;
 use crate::rita_common::peer_listener::PeerListener;
+use crate::rita_common::route_cache::refresh_routes;
 use crate::rita_common::traffic_watcher::{TrafficWatcher, Watch};
 use crate::rita_common::tunnel_manager::PeersToContact;
 use crate::rita_common::tunnel_manager::{GetNeighbors, TunnelManager};
+use crate::rita_common::watchdog::{LoopTime, Watchdog};
 use crate::KI;
 use crate::SETTING;
 use actix::{
@@ -16,11 +18,11 @@ use actix::{
 };
 use babel_monitor::open_babel_stream;
 use babel_monitor::parse_neighs;
-use babel_monitor::parse_routes;
 use babel_monitor::start_connection;
 use failure::Error;
 use futures01::Future;
 use settings::RitaCommonSettings;
+use std::net::IpAddr;
 use std::time::{Duration, Instant};
 
 // the speed in seconds for the common loop
@@ -95,7 +97,8 @@ impl Handler<Tick> for RitaFastLoop {
         // in blowing through the entire grace in less than a minute
         PaymentValidator::from_registry().do_send(Validate());
 
-        // watch neighbors for billing
+        // dump the route table once per tick for billing and the network monitor, everyone
+        // else is served this same copy by the RouteCache
         Arbiter::spawn(
             TunnelManager::from_registry()
                 .send(GetNeighbors)
@@ -111,24 +114,46 @@ impl Handler<Tick> for RitaFastLoop {
                         start.elapsed().subsec_millis()
                     );
 
-                    open_babel_stream(babel_port)
-                        .from_err()
-                        .and_then(move |stream| {
-                            start_connection(stream).and_then(move |stream| {
-                                parse_routes(stream).and_then(move |routes| {
-                                    TrafficWatcher::from_registry()
-                                        .send(Watch::new(neighbors, routes.1))
-                                        .timeout(FAST_LOOP_TIMEOUT)
-                                        .then(move |_res| {
-                                            info!(
-                                                "TrafficWatcher completed in {}s {}ms",
-                                                neigh.elapsed().as_secs(),
-                                                neigh.elapsed().subsec_millis()
-                                            );
-                                            Ok(())
-                                        })
+                    refresh_routes()
+                        .and_then(move |cached| {
+                            // Observe the dataplane for status and problems
+                            let rita_neighbors = neighbors.clone();
+                            let babel_routes = cached.routes.clone();
+                            Arbiter::spawn(
+                                open_babel_stream(babel_port)
+                                    .from_err()
+                                    .and_then(start_connection)
+                                    .and_then(parse_neighs)
+                                    .and_then(move |(_stream, babel_neighbors)| {
+                                        NetworkMonitor::from_registry().do_send(
+                                            NetworkMonitorTick {
+                                                rita_neighbors,
+                                                babel_routes,
+                                                babel_neighbors,
+                                            },
+                                        );
+                                        Ok(())
+                                    })
+                                    .then(|ret| {
+                                        if let Err(e) = ret {
+                                            error!("Failed to watch network latency with {:?}", e)
+                                        }
+                                        Ok(())
+                                    }),
+                            );
+
+                            // watch neighbors for billing
+                            TrafficWatcher::from_registry()
+                                .send(Watch::new(neighbors, cached.routes))
+                                .timeout(FAST_LOOP_TIMEOUT)
+                                .then(move |_res| {
+                                    info!(
+                                        "TrafficWatcher completed in {}s {}ms",
+                                        neigh.elapsed().as_secs(),
+                                        neigh.elapsed().subsec_millis()
+                                    );
+                                    Ok(())
                                 })
-                            })
                         })
                         .then(|ret| {
                             if let Err(e) = ret {
@@ -139,35 +164,6 @@ impl Handler<Tick> for RitaFastLoop {
                 }),
         );
 
-        // Observe the dataplane for status and problems
-        Arbiter::spawn(TunnelManager::from_registry().send(GetNeighbors).then(
-            move |rita_neighbors| {
-                let rita_neighbors = rita_neighbors.unwrap().unwrap();
-                open_babel_stream(babel_port)
-                    .from_err()
-                    .and_then(move |stream| {
-                        start_connection(stream).and_then(move |stream| {
-                            parse_routes(stream).and_then(move |(stream, babel_routes)| {
-                                parse_neighs(stream).and_then(move |(_stream, babel_neighbors)| {
-                                    NetworkMonitor::from_registry().do_send(NetworkMonitorTick {
-                                        rita_neighbors,
-                                        babel_routes,
-                                        babel_neighbors,
-                                    });
-                                    Ok(())
-                                })
-                            })
-                        })
-                    })
-                    .then(|ret| {
-                        if let Err(e) = ret {
-                            error!("Failed to watch network latency with {:?}", e)
-                        }
-                        Ok(())
-                    })
-            },
-        ));
-
         // Update debts
         DebtKeeper::from_registry().do_send(SendUpdate {});
 
@@ -211,6 +207,11 @@ impl Handler<Tick> for RitaFastLoop {
                 .then(|_| Ok(())),
         );
 
+        Watchdog::from_registry().do_send(LoopTime {
+            name: "RitaFastLoop",
+            elapsed: start.elapsed(),
+            period: Duration::from_secs(FAST_LOOP_SPEED),
+        });
         Ok(())
     }
 }
@@ -221,27 +222,104 @@ impl Handler<Tick> for RitaFastLoop {
 fn manage_gateway() {
     // Resolves the gateway client corner case
     // Background info here https://forum.altheamesh.com/t/the-gateway-client-corner-case/35
-    let gateway = match SETTING.get_network().external_nic {
-        Some(ref external_nic) => match KI.is_iface_up(external_nic) {
+    let network = SETTING.get_network().clone();
+    let iface_up = |iface: &Option<String>| match iface {
+        Some(ref nic) => match KI.is_iface_up(nic) {
             Some(val) => val,
             None => false,
         },
         None => false,
     };
+    // a backup uplink such as an LTE modem also makes us a gateway
+    let gateway = iface_up(&network.external_nic) || iface_up(&network.backup_external_nic);
 
     info!("We are a Gateway: {}", gateway);
     SETTING.get_network_mut().is_gateway = gateway;
 
+    let ipv6_only = match network.external_nic {
+        Some(ref nic) if gateway => match KI.is_ipv6_only_uplink(nic) {
+            Ok(val) => val,
+            Err(e) => {
+                warn!("Failed to check {} for IPv4 with {:?}", nic, e);
+                network.ipv6_only_wan
+            }
+        },
+        _ => false,
+    };
+    if ipv6_only != network.ipv6_only_wan {
+        info!("Our WAN is IPv6 only: {}", ipv6_only);
+    }
+    SETTING.get_network_mut().ipv6_only_wan = ipv6_only;
+    if network.auto_clat && KI.is_openwrt() {
+        manage_clat(&network.external_nic, ipv6_only);
+    }
+
     if gateway {
         match KI.get_resolv_servers() {
-            Ok(s) => {
-                for ip in s.iter() {
-                    trace!("Resolv route {:?}", ip);
-                    KI.manual_peers_route(&ip, &mut SETTING.get_network_mut().default_route)
-                        .unwrap();
-                }
-            }
+            Ok(s) => route_dns_servers(&s),
             Err(e) => warn!("Failed to add DNS routes with {:?}", e),
         }
     }
 }
+
+/// Pins routes to our dns servers over the route in the settings, so they're still reachable
+/// when the exit tunnel is our default route
+fn route_dns_servers(servers: &[IpAddr]) {
+    for ip in servers.iter() {
+        trace!("Resolv route {:?}", ip);
+        KI.manual_peers_route(&ip, &mut SETTING.get_network_mut().default_route)
+            .unwrap();
+    }
+}
+
+/// Brings 464XLAT up over the external nic when we need it and takes it down when we don't
+fn manage_clat(external_nic: &Option<String>, wanted: bool) {
+    let enabled = KI.clat_enabled();
+    match external_nic {
+        Some(nic) if wanted && !enabled => {
+            info!("Setting up 464XLAT over {}", nic);
+            if let Err(e) = KI.setup_clat(nic) {
+                error!("Failed to set up 464XLAT with {:?}", e);
+            }
+        }
+        _ if !wanted && enabled => {
+            info!("Removing 464XLAT");
+            if let Err(e) = KI.remove_clat() {
+                error!("Failed to remove 464XLAT with {:?}", e);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[test]
+fn test_dns_routes_while_failed_over() {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{ExitStatus, Output};
+
+    let route = |s: &str| -> Vec<String> { s.split_whitespace().map(|s| s.to_string()).collect() };
+    let backup = route("default via 10.0.0.1 dev lte0");
+    SETTING.get_network_mut().default_route = backup.clone();
+    KI.set_failed_uplink(Some("eth0"));
+    KI.set_mock(Box::new(move |program, args| {
+        assert_eq!(program, "ip");
+        let args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+        let stdout = match args.as_slice() {
+            // the dead primary's route is still the default in the main table
+            ["route", "list", "default"] => {
+                b"default via 192.168.1.1 dev eth0 proto dhcp metric 10".to_vec()
+            }
+            ["route", "add", "1.1.1.1", "via", "10.0.0.1", "dev", "lte0"] => Vec::new(),
+            _ => panic!("Unexpected call {} {:?}", program, args),
+        };
+        Ok(Output {
+            stdout,
+            stderr: Vec::new(),
+            status: ExitStatus::from_raw(0),
+        })
+    }));
+
+    route_dns_servers(&["1.1.1.1".parse().unwrap()]);
+    assert_eq!(SETTING.get_network().default_route, backup);
+    KI.set_failed_uplink(None);
+}
@@ -5,6 +5,7 @@
 //! all system functions. Anything that blocks will eventually filter up to block this loop and
 //! halt essential functions like opening tunnels and managing peers
 
+use crate::middleware;
 use crate::rita_common::network_endpoints::*;
 use crate::SETTING;
 use actix::SystemService;
@@ -14,6 +15,7 @@ use rand::thread_rng;
 use rand::Rng;
 use settings::RitaCommonSettings;
 
+pub mod babel_fee;
 pub mod fast_loop;
 pub mod slow_loop;
 
@@ -33,18 +35,43 @@ pub fn get_web3_server() -> String {
 
 pub fn start_core_rita_endpoints(workers: usize) {
     // Rita hello function
-    server::new(|| App::new().resource("/hello", |r| r.method(Method::POST).with(hello_response)))
-        .workers(workers)
-        .bind(format!("[::0]:{}", SETTING.get_network().rita_hello_port))
-        .unwrap()
-        .shutdown_timeout(0)
-        .start();
+    server::new(|| {
+        App::new()
+            .middleware(middleware::RateLimit)
+            .resource("/hello", |r| r.method(Method::POST).with(hello_response))
+            .resource("/hello/challenge", |r| {
+                r.method(Method::POST).with(hello_challenge)
+            })
+            .resource("/traffic_counts", |r| {
+                r.method(Method::POST).with(traffic_counts)
+            })
+            .resource("/iou", |r| r.method(Method::POST).with(iou))
+            .resource("/debt_write_off", |r| {
+                r.method(Method::POST).with(debt_write_off)
+            })
+            .resource("/services/gossip", |r| {
+                r.method(Method::POST).with(service_gossip)
+            })
+            .resource("/light_client/handoff", |r| {
+                r.method(Method::POST).with(light_client_handoff)
+            })
+    })
+    .workers(workers)
+    .bind(format!("[::0]:{}", SETTING.get_network().rita_hello_port))
+    .unwrap()
+    .shutdown_timeout(0)
+    .start();
 
     // Rita accept payment function, on a different port
     server::new(|| {
-        App::new().resource("/make_payment", |r| {
-            r.method(Method::POST).with(make_payments)
-        })
+        App::new()
+            .middleware(middleware::RateLimit)
+            .resource("/make_payment", |r| {
+                r.method(Method::POST).with(make_payments)
+            })
+            .resource("/payment_status", |r| {
+                r.method(Method::POST).with(payment_status)
+            })
     })
     .workers(workers)
     .bind(format!("[::0]:{}", SETTING.get_network().rita_contact_port))
@@ -63,4 +90,9 @@ pub fn check_rita_common_actors() {
     assert!(crate::rita_common::peer_listener::PeerListener::from_registry().connected());
     assert!(crate::rita_common::rita_loop::fast_loop::RitaFastLoop::from_registry().connected());
     assert!(crate::rita_common::rita_loop::slow_loop::RitaSlowLoop::from_registry().connected());
+    assert!(crate::rita_common::watchdog::Watchdog::from_registry().connected());
+    assert!(crate::rita_common::service_registry::ServiceRegistry::from_registry().connected());
+    assert!(crate::rita_common::sla_monitor::SlaMonitor::from_registry().connected());
+    assert!(crate::rita_common::route_cache::RouteCache::from_registry().connected());
+    assert!(crate::rita_common::live_updates::LiveUpdates::from_registry().connected());
 }
@@ -1,17 +1,24 @@
+use crate::rita_common::currency::CurrencyConverter;
+use crate::rita_common::currency::UpdateRates;
 use crate::rita_common::dao_manager::DAOManager;
 use crate::rita_common::dao_manager::Tick as DAOTick;
+use crate::rita_common::rita_loop::babel_fee::reconcile_local_fee;
+use crate::rita_common::schedule;
 use crate::rita_common::simulated_txfee_manager::SimulatedTxFeeManager;
 use crate::rita_common::simulated_txfee_manager::Tick as TxFeeTick;
+use crate::rita_common::system_health::check_system_health;
+use crate::rita_common::time_sanity::check_clock;
+use crate::rita_common::token_bridge::bridge_has_key;
 use crate::rita_common::token_bridge::Tick as TokenBridgeTick;
 use crate::rita_common::token_bridge::TokenBridge;
-use crate::rita_common::tunnel_manager::{TriggerGC, TunnelManager};
+use crate::rita_common::tunnel_manager::{babel_params, CheckNatPeers, TriggerGC, TunnelManager};
 use crate::SETTING;
 use actix::{
     Actor, ActorContext, Addr, Arbiter, AsyncContext, Context, Handler, Message, Supervised,
     SystemService,
 };
 use babel_monitor::open_babel_stream;
-use babel_monitor::set_local_fee;
+use babel_monitor::set_interface_defaults;
 use babel_monitor::set_metric_factor;
 use babel_monitor::start_connection;
 use failure::Error;
@@ -38,6 +45,9 @@ impl Actor for RitaSlowLoop {
     fn started(&mut self, ctx: &mut Context<Self>) {
         trace!("Common rita loop started!");
 
+        // make sure babel has our fee from the start rather than a minute in
+        set_babel_settings();
+
         ctx.run_interval(Duration::from_secs(SLOW_LOOP_SPEED), |_act, ctx| {
             let addr: Addr<Self> = ctx.address();
             addr.do_send(Tick);
@@ -87,33 +97,49 @@ impl Handler<Tick> for RitaSlowLoop {
             SETTING.get_network().tunnel_timeout_seconds,
         )));
 
-        TokenBridge::from_registry().do_send(TokenBridgeTick());
+        TunnelManager::from_registry().do_send(CheckNatPeers);
+
+        if bridge_has_key() {
+            TokenBridge::from_registry().do_send(TokenBridgeTick());
+        }
+
+        CurrencyConverter::from_registry().do_send(UpdateRates);
+
+        check_system_health();
+        check_clock();
+
+        // before babel is updated so that it gets the fee of any window that just started
+        schedule::apply();
 
-        // we really only need to run this on startup, but doing so periodically
-        // could catch the edge case where babel is restarted under us
-        set_babel_price();
+        // babel forgets all of this if it's restarted under us, the fee is read back to catch
+        // that and babel refusing it
+        set_babel_settings();
 
         Ok(())
     }
 }
 
-fn set_babel_price() {
+/// Applies the babel parameters from the settings and makes sure babel is announcing our price,
+/// the price goes last so that babel refusing it doesn't hold up the rest
+fn set_babel_settings() {
     let babel_port = SETTING.get_network().babel_port;
-    let local_fee = SETTING.get_payment().local_fee;
+    let local_fee = schedule::local_fee();
     let metric_factor = SETTING.get_network().metric_factor;
+    let params = babel_params();
     Arbiter::spawn(
         open_babel_stream(babel_port)
             .from_err()
             .and_then(move |stream| {
                 start_connection(stream).and_then(move |stream| {
-                    set_local_fee(stream, local_fee)
-                        .and_then(move |stream| Ok(set_metric_factor(stream, metric_factor)))
+                    set_metric_factor(stream, metric_factor)
+                        .and_then(move |stream| set_interface_defaults(stream, params))
+                        .and_then(move |stream| reconcile_local_fee(stream, local_fee))
                 })
             })
             .timeout(SLOW_LOOP_TIMEOUT)
             .then(|res| {
                 if let Err(e) = res {
-                    error!("Failed to set babel price {:?}", e);
+                    error!("Failed to set babel settings {:?}", e);
                 }
                 Ok(())
             }),
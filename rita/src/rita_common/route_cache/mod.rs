@@ -0,0 +1,190 @@
+//! A copy of babel's route table shared by everything that needs it. Dumping and parsing the
+//! table is one of the more expensive things we ask of babeld on a large mesh and the fast loop,
+//! the exit loop and most dashboard endpoints all used to do it on their own. Now the fast loop
+//! refreshes the table once per tick and everyone else uses the cached copy, only going to babel
+//! themselves if the fast loop has fallen behind.
+
+use crate::rita_common::rita_loop::fast_loop::FAST_LOOP_SPEED;
+use crate::SETTING;
+use actix::{Actor, Context, Handler, Message, Supervised, SystemService};
+use babel_monitor::{open_babel_stream, parse_routes, start_connection, Route};
+use failure::Error;
+use futures01::future;
+use futures01::Future;
+use settings::RitaCommonSettings;
+use std::time::{Duration, Instant};
+
+/// Routes older than this are fetched again rather than served, long enough that the fast loop
+/// running a little late doesn't send anyone to babel
+pub const ROUTE_CACHE_MAX_AGE: Duration = Duration::from_secs(FAST_LOOP_SPEED * 2);
+
+#[derive(Debug, Clone)]
+pub struct CachedRoutes {
+    pub routes: Vec<Route>,
+    /// when the table was dumped from babel
+    pub fetched: Instant,
+}
+
+#[derive(Default)]
+pub struct RouteCache {
+    routes: Option<CachedRoutes>,
+}
+
+impl Actor for RouteCache {
+    type Context = Context<Self>;
+}
+
+impl Supervised for RouteCache {}
+impl SystemService for RouteCache {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("Route cache started");
+    }
+}
+
+impl RouteCache {
+    fn fresh_routes(&self, max_age: Duration, now: Instant) -> Option<CachedRoutes> {
+        match &self.routes {
+            Some(cached) if now.saturating_duration_since(cached.fetched) <= max_age => {
+                Some(cached.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn update(&mut self, routes: CachedRoutes) {
+        // a slow fetch finishing after a newer one mustn't replace it
+        if let Some(cached) = &self.routes {
+            if cached.fetched > routes.fetched {
+                return;
+            }
+        }
+        self.routes = Some(routes);
+    }
+}
+
+#[derive(Message)]
+pub struct UpdateRoutes(pub CachedRoutes);
+
+impl Handler<UpdateRoutes> for RouteCache {
+    type Result = ();
+
+    fn handle(&mut self, msg: UpdateRoutes, _ctx: &mut Context<Self>) -> Self::Result {
+        self.update(msg.0);
+    }
+}
+
+/// The cached routes if they were fetched no longer than `max_age` ago
+pub struct GetCachedRoutes {
+    pub max_age: Duration,
+}
+
+impl Message for GetCachedRoutes {
+    type Result = Result<Option<CachedRoutes>, Error>;
+}
+
+impl Handler<GetCachedRoutes> for RouteCache {
+    type Result = Result<Option<CachedRoutes>, Error>;
+
+    fn handle(&mut self, msg: GetCachedRoutes, _ctx: &mut Context<Self>) -> Self::Result {
+        Ok(self.fresh_routes(msg.max_age, Instant::now()))
+    }
+}
+
+/// Dumps the route table from babel and caches it, called by the fast loop every tick
+pub fn refresh_routes() -> impl Future<Item = CachedRoutes, Error = Error> {
+    let babel_port = SETTING.get_network().babel_port;
+    let fetched = Instant::now();
+    open_babel_stream(babel_port)
+        .from_err()
+        .and_then(start_connection)
+        .and_then(parse_routes)
+        .map(move |(_stream, routes)| {
+            let cached = CachedRoutes { routes, fetched };
+            RouteCache::from_registry().do_send(UpdateRoutes(cached.clone()));
+            cached
+        })
+}
+
+/// The route table as of at most ROUTE_CACHE_MAX_AGE ago, fetched from babel if the cache is
+/// older than that
+pub fn get_routes() -> Box<dyn Future<Item = CachedRoutes, Error = Error>> {
+    Box::new(
+        RouteCache::from_registry()
+            .send(GetCachedRoutes {
+                max_age: ROUTE_CACHE_MAX_AGE,
+            })
+            .from_err()
+            .and_then(
+                |cached| -> Box<dyn Future<Item = CachedRoutes, Error = Error>> {
+                    match cached {
+                        Ok(Some(cached)) => Box::new(future::ok(cached)),
+                        Ok(None) => {
+                            trace!("Route cache is stale, asking babel");
+                            Box::new(refresh_routes())
+                        }
+                        Err(e) => Box::new(future::err(e)),
+                    }
+                },
+            ),
+    )
+}
+
+#[test]
+fn test_route_cache_age() {
+    let route = Route {
+        id: String::new(),
+        iface: "wg0".to_string(),
+        xroute: false,
+        installed: true,
+        neigh_ip: "fe80::1".parse().unwrap(),
+        prefix: "fd00::1/128".parse().unwrap(),
+        metric: 100,
+        refmetric: 100,
+        full_path_rtt: 0.0,
+        price: 10,
+        fee: 0,
+    };
+    let start = Instant::now();
+    let mut cache = RouteCache::default();
+    assert!(cache.fresh_routes(ROUTE_CACHE_MAX_AGE, start).is_none());
+
+    cache.update(CachedRoutes {
+        routes: vec![route.clone()],
+        fetched: start,
+    });
+    let cached = cache.fresh_routes(ROUTE_CACHE_MAX_AGE, start + ROUTE_CACHE_MAX_AGE);
+    assert_eq!(cached.unwrap().routes.len(), 1);
+    assert!(cache
+        .fresh_routes(
+            ROUTE_CACHE_MAX_AGE,
+            start + ROUTE_CACHE_MAX_AGE + Duration::from_secs(1)
+        )
+        .is_none());
+
+    // an older dump finishing late is ignored
+    cache.update(CachedRoutes {
+        routes: Vec::new(),
+        fetched: start - Duration::from_secs(1),
+    });
+    assert_eq!(
+        cache
+            .fresh_routes(ROUTE_CACHE_MAX_AGE, start)
+            .unwrap()
+            .routes
+            .len(),
+        1
+    );
+
+    cache.update(CachedRoutes {
+        routes: vec![route.clone(), route],
+        fetched: start + Duration::from_secs(1),
+    });
+    assert_eq!(
+        cache
+            .fresh_routes(ROUTE_CACHE_MAX_AGE, start)
+            .unwrap()
+            .routes
+            .len(),
+        2
+    );
+}
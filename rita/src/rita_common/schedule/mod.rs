@@ -0,0 +1,208 @@
+//! Scheduled price and bandwidth windows. Operators can list windows in the payment settings, say
+//! cheaper nights or a bigger free tier on weekends, that replace local_fee, the exit price and
+//! free_tier_throughput while they are active. Everything that uses those values asks this module
+//! for them, babel gets the current fee on the next slow loop tick and `apply` reshapes tunnels
+//! when a window starts or ends.
+
+use crate::rita_common::tunnel_manager::{RefreshBandwidthLimits, TunnelManager};
+use crate::rita_common::utils::now_secs;
+use crate::SETTING;
+use actix::SystemService;
+use failure::Error;
+use settings::payment::{ScheduleSettings, ScheduleWindow};
+use settings::RitaCommonSettings;
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+/// The furthest any timezone is from UTC, in minutes
+pub const MAX_UTC_OFFSET: i16 = 14 * 60;
+
+lazy_static! {
+    /// The name of the window active at the last `apply`, used to spot transitions
+    static ref ACTIVE_WINDOW: Arc<RwLock<Option<String>>> = Arc::new(RwLock::new(None));
+}
+
+/// Checks a schedule before it's saved
+pub fn validate_schedule(schedule: &ScheduleSettings) -> Result<(), Error> {
+    if schedule.utc_offset.abs() > MAX_UTC_OFFSET {
+        bail!(
+            "utc_offset must be within {} minutes of UTC",
+            MAX_UTC_OFFSET
+        );
+    }
+    let mut names = HashSet::new();
+    for window in schedule.windows.iter() {
+        if window.name.is_empty() {
+            bail!("Schedule windows need a name");
+        }
+        if !names.insert(&window.name) {
+            bail!(
+                "There is more than one schedule window named {}",
+                window.name
+            );
+        }
+        if window.start >= MINUTES_PER_DAY || window.end >= MINUTES_PER_DAY {
+            bail!(
+                "Window {} must start and end less than {} minutes after midnight",
+                window.name,
+                MINUTES_PER_DAY
+            );
+        }
+        if window.start == window.end {
+            bail!("Window {} starts and ends at the same time", window.name);
+        }
+        if window.days.iter().any(|day| *day > 6) {
+            bail!("Window {} has a day past 6 (Sunday)", window.name);
+        }
+    }
+    Ok(())
+}
+
+fn on_day(window: &ScheduleWindow, weekday: u8) -> bool {
+    window.days.is_empty() || window.days.contains(&weekday)
+}
+
+/// The window active at `unix_secs`, the first listed wins if more than one is
+pub fn active_window(schedule: &ScheduleSettings, unix_secs: u64) -> Option<&ScheduleWindow> {
+    if !schedule.enabled {
+        return None;
+    }
+    let local = unix_secs as i64 + i64::from(schedule.utc_offset) * 60;
+    let day = local.div_euclid(SECONDS_PER_DAY);
+    let minute = (local.rem_euclid(SECONDS_PER_DAY) / 60) as u16;
+    // the epoch was a Thursday
+    let weekday = (day + 3).rem_euclid(7) as u8;
+    let yesterday = (weekday + 6) % 7;
+
+    schedule.windows.iter().find(|window| {
+        if window.start < window.end {
+            on_day(window, weekday) && minute >= window.start && minute < window.end
+        } else {
+            // runs past midnight, so in the early hours it's yesterday's window
+            (on_day(window, weekday) && minute >= window.start)
+                || (on_day(window, yesterday) && minute < window.end)
+        }
+    })
+}
+
+/// The window active right now, if any
+pub fn current_window() -> Option<ScheduleWindow> {
+    active_window(&SETTING.get_payment().schedule, now_secs()).cloned()
+}
+
+/// The fee we charge to forward traffic right now
+pub fn local_fee() -> u32 {
+    let payment = SETTING.get_payment();
+    active_window(&payment.schedule, now_secs())
+        .and_then(|window| window.local_fee)
+        .unwrap_or(payment.local_fee)
+}
+
+/// The free tier we give right now
+pub fn free_tier_throughput() -> u32 {
+    let payment = SETTING.get_payment();
+    active_window(&payment.schedule, now_secs())
+        .and_then(|window| window.free_tier_throughput)
+        .unwrap_or(payment.free_tier_throughput)
+}
+
+/// The exit price right now, the base price lives in the exit's own settings
+pub fn exit_price(base: u64) -> u64 {
+    active_window(&SETTING.get_payment().schedule, now_secs())
+        .and_then(|window| window.exit_price)
+        .unwrap_or(base)
+}
+
+/// The most we might charge and the least free tier we might give at any time, this is what the
+/// exit signs so that clients don't have to accept new terms every time a window ends
+pub fn worst_case_exit_terms(base_price: u64) -> (u64, u32) {
+    let payment = SETTING.get_payment();
+    let mut price = base_price;
+    let mut free_tier = payment.free_tier_throughput;
+    if payment.schedule.enabled {
+        for window in payment.schedule.windows.iter() {
+            price = price.max(window.exit_price.unwrap_or(base_price));
+            free_tier = free_tier.min(
+                window
+                    .free_tier_throughput
+                    .unwrap_or(payment.free_tier_throughput),
+            );
+        }
+    }
+    (price, free_tier)
+}
+
+/// Called every slow loop tick, reshapes overdue tunnels when a window starts or ends. Babel
+/// picks up the new fee from the same tick
+pub fn apply() {
+    let active = current_window().map(|window| window.name);
+    let mut last = ACTIVE_WINDOW.write().unwrap();
+    if *last == active {
+        return;
+    }
+    match &active {
+        Some(name) => info!("Schedule window {} is now active", name),
+        None => info!("No schedule window is active, using the base prices"),
+    }
+    *last = active;
+    TunnelManager::from_registry().do_send(RefreshBandwidthLimits);
+}
+
+#[test]
+fn test_active_window() {
+    let window = |name: &str, days: Vec<u8>, start: u16, end: u16| ScheduleWindow {
+        name: name.to_string(),
+        days,
+        start,
+        end,
+        local_fee: Some(1),
+        exit_price: None,
+        free_tier_throughput: None,
+    };
+    let mut schedule = ScheduleSettings {
+        enabled: true,
+        utc_offset: 0,
+        windows: vec![
+            window("night", vec![], 22 * 60, 6 * 60),
+            window("friday", vec![4], 12 * 60, 14 * 60),
+        ],
+    };
+    // Thursday 1970-01-01 and Friday 1970-01-02
+    let at = |day: u64, hour: u64, minute: u64| day * 86400 + hour * 3600 + minute * 60;
+    let name = |schedule: &ScheduleSettings, secs: u64| {
+        active_window(schedule, secs).map(|window| window.name.clone())
+    };
+
+    assert_eq!(name(&schedule, at(0, 23, 0)), Some("night".to_string()));
+    assert_eq!(name(&schedule, at(1, 5, 59)), Some("night".to_string()));
+    assert_eq!(name(&schedule, at(1, 6, 0)), None);
+    assert_eq!(name(&schedule, at(0, 13, 0)), None);
+    assert_eq!(name(&schedule, at(1, 13, 0)), Some("friday".to_string()));
+    assert_eq!(name(&schedule, at(1, 14, 0)), None);
+
+    // a window past midnight only carries over from the days it starts on
+    schedule.windows[0].days = vec![3];
+    assert_eq!(name(&schedule, at(1, 1, 0)), Some("night".to_string()));
+    assert_eq!(name(&schedule, at(1, 23, 0)), None);
+    assert_eq!(name(&schedule, at(2, 1, 0)), None);
+
+    // two hours east of UTC Friday lunch starts at 10:00 UTC
+    schedule.utc_offset = 120;
+    assert_eq!(name(&schedule, at(1, 10, 30)), Some("friday".to_string()));
+    assert_eq!(name(&schedule, at(1, 12, 30)), None);
+
+    schedule.enabled = false;
+    assert_eq!(name(&schedule, at(1, 10, 30)), None);
+
+    schedule.enabled = true;
+    assert!(validate_schedule(&schedule).is_ok());
+    schedule.windows[1].name = "night".to_string();
+    assert!(validate_schedule(&schedule).is_err());
+    schedule.windows[1].name = "friday".to_string();
+    schedule.windows[1].end = 24 * 60;
+    assert!(validate_schedule(&schedule).is_err());
+    schedule.windows[1].end = schedule.windows[1].start;
+    assert!(validate_schedule(&schedule).is_err());
+}
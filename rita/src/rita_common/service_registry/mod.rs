@@ -0,0 +1,437 @@
+//! Intra-mesh service discovery. Nodes that opt in register the services they offer (a NAS, a
+//! camera, a game server) on the dashboard and once a minute send every service they know of to
+//! their neighbors that support it. Each neighbor adds a hop and passes the entries on the same
+//! way, so the whole table spreads across the mesh a hop per round.
+//!
+//! Every entry is signed by the provider's eth key along with a sequence number the provider
+//! raises each round, relays can't change anything but the hop count. Only a higher sequence
+//! refreshes an entry, copies of one we already have just offer a shorter path, so entries that
+//! haven't had a new sequence from their provider within `SERVICE_TTL` are dropped. That's also
+//! how a removed service disappears, relays passing old copies back and forth can't keep it
+//! alive. A provider's mesh ip is tied to the first eth address we hear for it until all of its
+//! entries expire.
+
+use crate::rita_common::hello_handler::post_to_peer;
+use crate::rita_common::tunnel_manager::{GetNeighbors, TunnelManager};
+use crate::rita_common::utils::now_secs;
+use crate::SETTING;
+use actix::{Actor, Arbiter, AsyncContext, Context, Handler, Message, Supervised, SystemService};
+use althea_types::{FeatureFlags, MeshService, SignedMeshService};
+use clarity::PrivateKey;
+use failure::Error;
+use futures01::Future;
+use settings::network::LocalService;
+use settings::RitaCommonSettings;
+use sha3::{Digest, Keccak256};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// How often the table is sent to our neighbors
+const GOSSIP_INTERVAL: Duration = Duration::from_secs(60);
+/// How long a learned entry lasts without being heard again
+const SERVICE_TTL: Duration = Duration::from_secs(300);
+/// Entries further away than this aren't passed on
+pub const MAX_SERVICE_HOPS: u8 = 8;
+/// Past this many learned entries new ones are ignored until some expire
+const MAX_LEARNED_SERVICES: usize = 1024;
+pub const MAX_SERVICE_NAME_LEN: usize = 32;
+pub const MAX_SERVICE_DESCRIPTION_LEN: usize = 128;
+
+const SERVICE_LABEL: &[u8] = b"althea mesh service";
+
+/// Checks a service registered on the dashboard or gossiped to us
+pub fn validate_service(name: &str, port: u16, description: &str) -> Result<(), Error> {
+    if name.is_empty() || name.len() > MAX_SERVICE_NAME_LEN {
+        bail!(
+            "Service names must be between 1 and {} bytes",
+            MAX_SERVICE_NAME_LEN
+        );
+    }
+    if port == 0 {
+        bail!("Service {} has no port", name);
+    }
+    if description.len() > MAX_SERVICE_DESCRIPTION_LEN {
+        bail!(
+            "Service descriptions can't be longer than {} bytes",
+            MAX_SERVICE_DESCRIPTION_LEN
+        );
+    }
+    Ok(())
+}
+
+fn service_digest(service: &MeshService, sequence: u64) -> Vec<u8> {
+    let mut hasher = Keccak256::new();
+    hasher.input(SERVICE_LABEL);
+    hasher.input(
+        format!(
+            "{}:{}:{}:{}:{}",
+            service.provider, service.name, service.port, service.description, sequence
+        )
+        .as_bytes(),
+    );
+    hasher.result().to_vec()
+}
+
+pub fn sign_service(
+    service: MeshService,
+    sequence: u64,
+    key: &PrivateKey,
+) -> Result<SignedMeshService, Error> {
+    let signature = key.sign_hash(&service_digest(&service, sequence));
+    Ok(SignedMeshService {
+        eth_address: key.to_public_key()?,
+        service,
+        sequence,
+        signature,
+    })
+}
+
+/// Checks that the entry was signed by the eth address it names
+pub fn verify_service(signed: &SignedMeshService) -> Result<(), Error> {
+    let digest = service_digest(&signed.service, signed.sequence);
+    let signer = match signed.signature.recover(&digest) {
+        Ok(val) => val,
+        Err(e) => bail!("Malformed service signature {:?}", e),
+    };
+    if signer != signed.eth_address {
+        bail!(
+            "Service was signed by {} not {}",
+            signer,
+            signed.eth_address
+        );
+    }
+    Ok(())
+}
+
+/// The services we've heard of, keyed by provider and name, along with when we first heard the
+/// sequence we have
+#[derive(Debug, Default)]
+pub struct ServiceTable {
+    learned: HashMap<(IpAddr, String), (SignedMeshService, Instant)>,
+}
+
+impl ServiceTable {
+    /// Takes in a neighbor's table, entries for our own ip are left out since we know better
+    pub fn merge(&mut self, services: Vec<SignedMeshService>, our_ip: IpAddr, now: Instant) {
+        for mut signed in services {
+            let service = &signed.service;
+            if service.provider == our_ip {
+                continue;
+            }
+            if let Err(e) = validate_service(&service.name, service.port, &service.description)
+                .and_then(|_| verify_service(&signed))
+            {
+                trace!("Ignoring gossiped service {:?}", e);
+                continue;
+            }
+            if self.learned.values().any(|(existing, _)| {
+                existing.service.provider == service.provider
+                    && existing.eth_address != signed.eth_address
+            }) {
+                trace!(
+                    "Ignoring service {} from {} signed by another key",
+                    service.name,
+                    service.provider
+                );
+                continue;
+            }
+            signed.service.hops = signed.service.hops.saturating_add(1);
+            if signed.service.hops > MAX_SERVICE_HOPS {
+                continue;
+            }
+
+            let key = (signed.service.provider, signed.service.name.clone());
+            match self.learned.get_mut(&key) {
+                // only the provider refreshes an entry
+                Some((existing, heard)) if signed.sequence > existing.sequence => {
+                    *existing = signed;
+                    *heard = now;
+                }
+                // the same entry over a shorter path, it lasts no longer for it
+                Some((existing, _))
+                    if signed.sequence == existing.sequence
+                        && signed.service.hops < existing.service.hops =>
+                {
+                    *existing = signed;
+                }
+                Some(_) => {}
+                None if self.learned.len() < MAX_LEARNED_SERVICES => {
+                    self.learned.insert(key, (signed, now));
+                }
+                None => trace!("Service table full, ignoring {}", signed.service.name),
+            }
+        }
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        self.learned
+            .retain(|_, (_, heard)| now - *heard < SERVICE_TTL);
+    }
+
+    /// Our own services followed by everything we've learned
+    pub fn services(&self, ours: &[LocalService], our_ip: IpAddr) -> Vec<MeshService> {
+        let mut services = own_services(ours, our_ip);
+        let mut learned: Vec<MeshService> = self
+            .learned
+            .values()
+            .map(|(signed, _)| signed.service.clone())
+            .collect();
+        learned.sort_by(|a, b| (a.hops, &a.name).cmp(&(b.hops, &b.name)));
+        services.extend(learned);
+        services
+    }
+
+    /// What we pass on to our neighbors, our own services signed with a new sequence and the
+    /// learned ones as we got them
+    pub fn gossip(
+        &self,
+        ours: &[LocalService],
+        our_ip: IpAddr,
+        key: &PrivateKey,
+        sequence: u64,
+    ) -> Result<Vec<SignedMeshService>, Error> {
+        let mut services = Vec::new();
+        for service in own_services(ours, our_ip) {
+            services.push(sign_service(service, sequence, key)?);
+        }
+        services.extend(
+            self.learned
+                .values()
+                .map(|(signed, _)| signed.clone())
+                .filter(|signed| signed.service.hops < MAX_SERVICE_HOPS),
+        );
+        Ok(services)
+    }
+}
+
+fn own_services(ours: &[LocalService], our_ip: IpAddr) -> Vec<MeshService> {
+    ours.iter()
+        .map(|service| MeshService {
+            provider: our_ip,
+            name: service.name.clone(),
+            port: service.port,
+            description: service.description.clone(),
+            hops: 0,
+        })
+        .collect()
+}
+
+#[derive(Default)]
+pub struct ServiceRegistry {
+    table: ServiceTable,
+}
+
+impl Actor for ServiceRegistry {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(GOSSIP_INTERVAL, |act, _ctx| act.gossip());
+    }
+}
+
+impl Supervised for ServiceRegistry {}
+impl SystemService for ServiceRegistry {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("Service registry started");
+    }
+}
+
+impl ServiceRegistry {
+    fn gossip(&mut self) {
+        self.table.expire(Instant::now());
+        let settings = SETTING.get_network().service_discovery.clone();
+        let our_ip = match SETTING.get_network().mesh_ip {
+            Some(ip) => ip,
+            None => return,
+        };
+        if !settings.enabled {
+            return;
+        }
+        let key = match SETTING.get_payment().eth_private_key {
+            Some(key) => key,
+            None => {
+                trace!("No eth key to sign our services with");
+                return;
+            }
+        };
+        // seconds since the epoch only go up, even across restarts
+        let services = match self
+            .table
+            .gossip(&settings.services, our_ip, &key, now_secs())
+        {
+            Ok(services) => services,
+            Err(e) => {
+                warn!("Could not sign our services {:?}", e);
+                return;
+            }
+        };
+        if services.is_empty() {
+            return;
+        }
+
+        let hello_port = SETTING.get_network().rita_hello_port;
+        Arbiter::spawn(
+            TunnelManager::from_registry()
+                .send(GetNeighbors)
+                .then(move |neighbors| {
+                    let neighbors = match neighbors {
+                        Ok(Ok(neighbors)) => neighbors,
+                        Ok(Err(e)) => {
+                            warn!("Could not get neighbors to gossip services {:?}", e);
+                            return Ok(());
+                        }
+                        Err(e) => {
+                            warn!("Could not get neighbors to gossip services {:?}", e);
+                            return Ok(());
+                        }
+                    };
+                    // neighbors show up once per tunnel
+                    let peers: HashSet<IpAddr> = neighbors
+                        .iter()
+                        .filter(|neigh| {
+                            neigh
+                                .identity
+                                .features
+                                .contains(FeatureFlags::SERVICE_DISCOVERY)
+                        })
+                        .map(|neigh| neigh.identity.global.mesh_ip)
+                        .collect();
+                    for ip in peers {
+                        let socket = SocketAddr::new(ip, hello_port);
+                        Arbiter::spawn(post_to_peer(socket, "/services/gossip", &services).then(
+                            move |res: Result<(), Error>| {
+                                if let Err(e) = res {
+                                    trace!("Failed to gossip services to {} {:?}", socket, e);
+                                }
+                                Ok(())
+                            },
+                        ));
+                    }
+                    Ok(())
+                }),
+        );
+    }
+}
+
+/// A neighbor's service table
+pub struct ServicesGossiped(pub Vec<SignedMeshService>);
+
+impl Message for ServicesGossiped {
+    type Result = ();
+}
+
+impl Handler<ServicesGossiped> for ServiceRegistry {
+    type Result = ();
+
+    fn handle(&mut self, msg: ServicesGossiped, _ctx: &mut Context<Self>) -> Self::Result {
+        if !SETTING.get_network().service_discovery.enabled {
+            return;
+        }
+        if let Some(our_ip) = SETTING.get_network().mesh_ip {
+            self.table.merge(msg.0, our_ip, Instant::now());
+        }
+    }
+}
+
+/// Every service we know of, ours first
+pub struct GetServices;
+
+impl Message for GetServices {
+    type Result = Result<Vec<MeshService>, Error>;
+}
+
+impl Handler<GetServices> for ServiceRegistry {
+    type Result = Result<Vec<MeshService>, Error>;
+
+    fn handle(&mut self, _msg: GetServices, _ctx: &mut Context<Self>) -> Self::Result {
+        self.table.expire(Instant::now());
+        let settings = SETTING.get_network().service_discovery.clone();
+        let our_ip = SETTING
+            .get_network()
+            .mesh_ip
+            .ok_or_else(|| format_err!("No mesh ip yet"))?;
+        if !settings.enabled {
+            return Ok(Vec::new());
+        }
+        Ok(self.table.services(&settings.services, our_ip))
+    }
+}
+
+#[test]
+fn test_service_table() {
+    let us: IpAddr = "fd00::1".parse().unwrap();
+    let them: IpAddr = "fd00::2".parse().unwrap();
+    let their_key: PrivateKey = format!("0x{:064x}", 2).parse().unwrap();
+    let other_key: PrivateKey = format!("0x{:064x}", 3).parse().unwrap();
+    let service = |provider: IpAddr, name: &str, hops: u8| MeshService {
+        provider,
+        name: name.to_string(),
+        port: 445,
+        description: String::new(),
+        hops,
+    };
+    let signed = |provider: IpAddr, name: &str, hops: u8, sequence: u64| {
+        let mut signed = sign_service(service(provider, name, 0), sequence, &their_key).unwrap();
+        signed.service.hops = hops;
+        signed
+    };
+    let start = Instant::now();
+    let mut table = ServiceTable::default();
+
+    // our own entries and bad ones are ignored, the rest gain a hop
+    let mut tampered = signed(them, "web", 0, 1);
+    tampered.service.port = 80;
+    table.merge(
+        vec![
+            signed(us, "nas", 1, 1),
+            signed(them, "", 0, 1),
+            signed(them, "far", MAX_SERVICE_HOPS, 1),
+            tampered,
+            signed(them, "nas", 2, 1),
+        ],
+        us,
+        start,
+    );
+    let ours = vec![LocalService {
+        name: "camera".to_string(),
+        port: 8080,
+        description: "front door".to_string(),
+    }];
+    let services = table.services(&ours, us);
+    assert_eq!(services.len(), 2);
+    assert_eq!(services[0].provider, us);
+    assert_eq!(services[0].hops, 0);
+    assert_eq!(services[1], service(them, "nas", 3));
+
+    // another key can't speak for a provider we already know
+    let forged = sign_service(service(them, "ssh", 0), 1, &other_key).unwrap();
+    table.merge(vec![forged], us, start);
+    assert_eq!(table.services(&[], us).len(), 1);
+
+    // a shorter path to the same copy is taken but doesn't refresh it, relayed copies
+    // bouncing around can't keep an entry alive
+    let later = start + SERVICE_TTL / 2;
+    table.merge(vec![signed(them, "nas", 0, 1)], us, later);
+    table.merge(vec![signed(them, "nas", 4, 1)], us, later);
+    assert_eq!(table.services(&[], us), vec![service(them, "nas", 1)]);
+    table.expire(start + SERVICE_TTL);
+    assert!(table.services(&[], us).is_empty());
+
+    // a newer sequence from the provider does, whatever the path
+    table.merge(vec![signed(them, "nas", 0, 1)], us, start);
+    table.merge(vec![signed(them, "nas", 4, 2)], us, later);
+    assert_eq!(table.services(&[], us), vec![service(them, "nas", 5)]);
+    table.expire(start + SERVICE_TTL);
+    assert_eq!(table.services(&[], us).len(), 1);
+    table.expire(later + SERVICE_TTL);
+    assert!(table.services(&[], us).is_empty());
+
+    // what we pass on is our own services signed and what we learned as we got it
+    table.merge(vec![signed(them, "nas", 0, 3)], us, start);
+    let gossip = table.gossip(&ours, us, &other_key, 7).unwrap();
+    assert_eq!(gossip.len(), 2);
+    assert_eq!(gossip[0].sequence, 7);
+    assert!(verify_service(&gossip[0]).is_ok());
+    assert_eq!(gossip[1], signed(them, "nas", 1, 3));
+}
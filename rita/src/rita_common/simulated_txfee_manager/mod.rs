@@ -1,5 +1,6 @@
 //! The maintainer fee is a fraction of all payments that is sent to the firmware maintainer
 
+use crate::rita_common::payment_controller::signer::sign_and_send;
 use crate::rita_common::payment_controller::TRANSACTION_SUBMISSON_TIMEOUT;
 use crate::rita_common::rita_loop::get_web3_server;
 use crate::rita_common::usage_tracker::UpdatePayments;
@@ -14,7 +15,6 @@ use num256::Uint256;
 use num_traits::Signed;
 use num_traits::Zero;
 use settings::RitaCommonSettings;
-use web30::client::Web3;
 
 pub struct SimulatedTxFeeManager {
     amount_owed: Uint256,
@@ -97,7 +97,6 @@ impl Handler<Tick> for SimulatedTxFeeManager {
 
     fn handle(&mut self, _msg: Tick, _: &mut Context<Self>) -> Self::Result {
         let payment_settings = SETTING.get_payment();
-        let eth_private_key = payment_settings.eth_private_key;
         let our_id = match SETTING.get_identity() {
             Some(id) => id,
             None => return,
@@ -109,7 +108,6 @@ impl Handler<Tick> for SimulatedTxFeeManager {
         let simulated_transaction_fee = payment_settings.simulated_transaction_fee;
         let amount_to_pay = self.amount_owed.clone();
         let should_pay = amount_to_pay > pay_threshold.abs().to_uint256().unwrap();
-        let net_version = payment_settings.net_version;
         drop(payment_settings);
         trace!(
             "We should pay the simulated tx fee {} of 1/{} % to {}",
@@ -133,8 +131,6 @@ impl Handler<Tick> for SimulatedTxFeeManager {
         };
 
         let full_node = get_web3_server();
-        let web3 = Web3::new(&full_node, TRANSACTION_SUBMISSON_TIMEOUT);
-
         let tx = Transaction {
             nonce,
             gas_price,
@@ -144,20 +140,7 @@ impl Handler<Tick> for SimulatedTxFeeManager {
             data: Vec::new(),
             signature: None,
         };
-        let transaction_signed = tx.sign(
-            &eth_private_key.expect("No private key configured!"),
-            net_version,
-        );
-
-        let transaction_bytes = match transaction_signed.to_bytes() {
-            Ok(bytes) => bytes,
-            Err(e) => {
-                error!("Failed to generate simulated txfee transaction, {:?}", e);
-                return;
-            }
-        };
-
-        let transaction_status = web3.eth_send_raw_transaction(transaction_bytes);
+        let transaction_status = sign_and_send(tx, full_node, TRANSACTION_SUBMISSON_TIMEOUT);
 
         // in theory this may fail, for now there is no handler and
         // we will just underpay when that occurs
@@ -170,6 +153,8 @@ impl Handler<Tick> for SimulatedTxFeeManager {
                         from: our_id,
                         amount: amount_to_pay.clone(),
                         txid: Some(txid),
+                        channel: None,
+                        payment_id: None,
                     },
                 });
                 SimulatedTxFeeManager::from_registry().do_send(SuccessfulPayment(amount_to_pay));
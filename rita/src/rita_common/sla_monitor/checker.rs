@@ -0,0 +1,77 @@
+//! The pings behind SLA checks. Each one waits up to a second for an answer and an uplink that's
+//! down makes every check wait that long, so they're run here, a sync actor with a thread of its
+//! own, rather than in the monitor's context or the exit manager's.
+
+use crate::KI;
+use crate::SETTING;
+use actix::{Actor, Handler, Message, SyncContext};
+use settings::RitaCommonSettings;
+use std::net::IpAddr;
+use std::time::Duration;
+
+const WAN_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+const EXIT_CHECK_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub struct SlaChecker;
+
+impl Actor for SlaChecker {
+    type Context = SyncContext<Self>;
+}
+
+/// Checks the exit at `exit`, if we have one, and our uplinks. Returns whether the exit and the
+/// internet answered, None for whatever there's nothing to check for
+pub struct RunChecks {
+    pub exit: Option<IpAddr>,
+}
+
+impl Message for RunChecks {
+    type Result = (Option<bool>, Option<bool>);
+}
+
+impl Handler<RunChecks> for SlaChecker {
+    type Result = (Option<bool>, Option<bool>);
+
+    fn handle(&mut self, msg: RunChecks, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        let exit = msg
+            .exit
+            .map(|ip| match KI.ping_check(&ip, EXIT_CHECK_TIMEOUT) {
+                Ok(val) => val,
+                Err(e) => {
+                    warn!("Failed to check exit reachability {:?}", e);
+                    false
+                }
+            });
+        (exit, wan_status())
+    }
+}
+
+/// If the internet answers over any of our uplinks, None if we don't have one
+fn wan_status() -> Option<bool> {
+    let network = SETTING.get_network().clone();
+    let uplinks: Vec<String> = network
+        .external_nic
+        .into_iter()
+        .chain(network.backup_external_nic.into_iter())
+        .collect();
+    if uplinks.is_empty() {
+        return None;
+    }
+    Some(
+        uplinks
+            .iter()
+            .any(|iface| uplink_works(iface, &network.wan_check_ip)),
+    )
+}
+
+fn uplink_works(iface: &str, check_ip: &IpAddr) -> bool {
+    if KI.is_iface_up(iface) != Some(true) {
+        return false;
+    }
+    match KI.ping_check_iface(iface, check_ip, WAN_CHECK_TIMEOUT) {
+        Ok(val) => val,
+        Err(e) => {
+            warn!("Failed to check uplink {} with {:?}", iface, e);
+            false
+        }
+    }
+}
@@ -0,0 +1,530 @@
+//! Uptime monitoring for SLA reports. Every `check_interval` seconds we check three tiers of
+//! connectivity: the selected exit answering over the exit tunnel (clients only, reported by the
+//! exit manager), the internet answering over our own uplink (gateways and exits) and having at
+//! least one live neighbor on the mesh. Each check counts towards a per day tally for each tier,
+//! tiers that don't apply to this device aren't counted. Checks that should have been made but
+//! weren't, because we were down or a check was still running, count as failed for the tiers
+//! the next check covers. The days are kept on disk for a bit over a year and rolled up into
+//! daily and calendar month uptime percentages for `/sla`.
+
+use self::checker::{RunChecks, SlaChecker};
+use crate::rita_common::tunnel_manager::{GetNeighbors, TunnelManager};
+use crate::rita_common::utils::now_secs;
+use crate::SETTING;
+use actix::{
+    Actor, Addr, Arbiter, AsyncContext, Context, Handler, Message, Supervised, SyncArbiter,
+    SystemService,
+};
+use failure::Error;
+use futures01::Future;
+use settings::network::SlaTargets;
+use settings::RitaCommonSettings;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+mod checker;
+
+/// How many days of history are kept, enough for a full year of monthly reports
+const MAX_SLA_DAYS: usize = 400;
+/// How many days are listed individually in reports
+const REPORTED_DAYS: usize = 31;
+/// The history is written to disk every this many checks, to spare the flash
+const SAVE_EVERY: u32 = 15;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// The result of one round of checks, None for tiers that don't apply to this device
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SlaSample {
+    pub exit: Option<bool>,
+    pub wan: Option<bool>,
+    pub mesh: Option<bool>,
+}
+
+/// Checks passed out of checks made
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Uptime {
+    pub up: u32,
+    pub checks: u32,
+}
+
+impl Uptime {
+    fn record(&mut self, result: Option<bool>) {
+        if let Some(up) = result {
+            self.checks += 1;
+            if up {
+                self.up += 1;
+            }
+        }
+    }
+
+    /// Counts `count` checks that weren't made as failed, if the tier applies
+    fn miss(&mut self, result: Option<bool>, count: u32) {
+        if result.is_some() {
+            self.checks += count;
+        }
+    }
+
+    fn add(&mut self, other: Uptime) {
+        self.up += other.up;
+        self.checks += other.checks;
+    }
+
+    pub fn percent(&self) -> Option<f64> {
+        if self.checks == 0 {
+            None
+        } else {
+            Some(f64::from(self.up) * 100.0 / f64::from(self.checks))
+        }
+    }
+
+    /// If the uptime is at least `target` hundredths of a percent
+    pub fn meets(&self, target: u32) -> Option<bool> {
+        if self.checks == 0 {
+            None
+        } else {
+            Some(u64::from(self.up) * 10_000 >= u64::from(target) * u64::from(self.checks))
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SlaDay {
+    /// days since the unix epoch, in UTC
+    pub day: u64,
+    pub exit: Uptime,
+    pub wan: Uptime,
+    pub mesh: Uptime,
+}
+
+impl SlaDay {
+    fn add(&mut self, other: &SlaDay) {
+        self.exit.add(other.exit);
+        self.wan.add(other.wan);
+        self.mesh.add(other.mesh);
+    }
+}
+
+/// The calendar date of a day since the unix epoch, as (year, month, day)
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Howard Hinnant's days_from_civil inverse, shifted so the year starts in March
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TierReport {
+    /// None if this tier was never checked in the period
+    pub uptime_percent: Option<f64>,
+    pub checks: u32,
+    pub failed_checks: u32,
+    /// None without a target or without checks
+    pub met_target: Option<bool>,
+}
+
+impl TierReport {
+    fn new(uptime: Uptime, target: Option<u32>) -> TierReport {
+        TierReport {
+            uptime_percent: uptime.percent(),
+            checks: uptime.checks,
+            failed_checks: uptime.checks - uptime.up,
+            met_target: target.and_then(|target| uptime.meets(target)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PeriodReport {
+    /// `YYYY-MM-DD` for days and `YYYY-MM` for months, in UTC
+    pub period: String,
+    pub exit: TierReport,
+    pub wan: TierReport,
+    pub mesh: TierReport,
+}
+
+impl PeriodReport {
+    fn new(period: String, totals: &SlaDay, targets: &SlaTargets) -> PeriodReport {
+        PeriodReport {
+            period,
+            exit: TierReport::new(totals.exit, targets.exit),
+            wan: TierReport::new(totals.wan, targets.wan),
+            mesh: TierReport::new(totals.mesh, targets.mesh),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SlaReport {
+    pub check_interval: u64,
+    pub targets: SlaTargets,
+    pub latest: Option<SlaSample>,
+    /// newest first
+    pub daily: Vec<PeriodReport>,
+    /// calendar months, newest first
+    pub monthly: Vec<PeriodReport>,
+}
+
+/// Per day tallies, oldest first
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct SlaHistory {
+    days: VecDeque<SlaDay>,
+    /// when the last check was made, in seconds since the unix epoch
+    #[serde(default)]
+    last_check: Option<u64>,
+}
+
+impl SlaHistory {
+    /// The tally for `day`, days only go forward so a check from before the newest day, after
+    /// the clock is set back, goes into the newest one
+    fn day_mut(&mut self, day: u64) -> &mut SlaDay {
+        let is_newer = self.days.back().map_or(true, |newest| newest.day < day);
+        if is_newer {
+            self.days.push_back(SlaDay {
+                day,
+                ..SlaDay::default()
+            });
+        }
+        while self.days.len() > MAX_SLA_DAYS {
+            self.days.pop_front();
+        }
+        self.days.back_mut().unwrap()
+    }
+
+    /// Counts a round of checks made at `now`, in seconds since the unix epoch, along with the
+    /// checks every `interval` seconds since the last one that weren't made
+    pub fn record(&mut self, sample: SlaSample, now: u64, interval: u64) {
+        let interval = interval.max(1);
+        if let Some(last) = self.last_check {
+            // rounded so that a check that's a bit late isn't a miss
+            let missed = ((now.saturating_sub(last) + interval / 2) / interval).saturating_sub(1);
+            // nothing older than the history goes back is kept anyway
+            let oldest = now.saturating_sub(MAX_SLA_DAYS as u64 * SECONDS_PER_DAY);
+            let mut time = (last + interval).max(oldest);
+            let end = last + missed * interval;
+            while missed > 0 && time <= end {
+                let day = time / SECONDS_PER_DAY;
+                let day_end = (day + 1) * SECONDS_PER_DAY - 1;
+                let count = (end.min(day_end) - time) / interval + 1;
+                let tally = self.day_mut(day);
+                tally.exit.miss(sample.exit, count as u32);
+                tally.wan.miss(sample.wan, count as u32);
+                tally.mesh.miss(sample.mesh, count as u32);
+                time += count * interval;
+            }
+        }
+        self.last_check = Some(now);
+
+        let day = self.day_mut(now / SECONDS_PER_DAY);
+        day.exit.record(sample.exit);
+        day.wan.record(sample.wan);
+        day.mesh.record(sample.mesh);
+    }
+
+    pub fn daily(&self, targets: &SlaTargets) -> Vec<PeriodReport> {
+        self.days
+            .iter()
+            .rev()
+            .take(REPORTED_DAYS)
+            .map(|day| {
+                let (year, month, date) = civil_from_days(day.day);
+                PeriodReport::new(
+                    format!("{:04}-{:02}-{:02}", year, month, date),
+                    day,
+                    targets,
+                )
+            })
+            .collect()
+    }
+
+    pub fn monthly(&self, targets: &SlaTargets) -> Vec<PeriodReport> {
+        let mut months: Vec<((u64, u64), SlaDay)> = Vec::new();
+        for day in self.days.iter().rev() {
+            let (year, month, _) = civil_from_days(day.day);
+            match months.last_mut() {
+                Some((key, totals)) if *key == (year, month) => totals.add(day),
+                _ => months.push(((year, month), *day)),
+            }
+        }
+        months
+            .iter()
+            .map(|((year, month), totals)| {
+                PeriodReport::new(format!("{:04}-{:02}", year, month), totals, targets)
+            })
+            .collect()
+    }
+}
+
+fn load_history(path: &str) -> Result<SlaHistory, Error> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    Ok(serde_json::from_str(&contents)?)
+}
+
+fn save_history(history: &SlaHistory, path: &str) -> Result<(), Error> {
+    let serialized = serde_json::to_vec(history)?;
+    File::create(path)?.write_all(&serialized)?;
+    Ok(())
+}
+
+pub struct SlaMonitor {
+    history: SlaHistory,
+    latest: Option<SlaSample>,
+    /// the exit to check from the exit manager and when it was last told to us
+    exit: Option<(Option<IpAddr>, Instant)>,
+    /// if the last round of checks is still running
+    checking: bool,
+    checker: Option<Addr<SlaChecker>>,
+    unsaved: u32,
+}
+
+impl Default for SlaMonitor {
+    fn default() -> SlaMonitor {
+        let path = SETTING.get_network().sla.file.clone();
+        // if the history can't be loaded we just start again
+        let history = match load_history(&path) {
+            Ok(history) => history,
+            Err(e) => {
+                info!(
+                    "Starting a new SLA history, could not load {} {:?}",
+                    path, e
+                );
+                SlaHistory::default()
+            }
+        };
+        SlaMonitor {
+            history,
+            latest: None,
+            exit: None,
+            checking: false,
+            checker: None,
+            unsaved: 0,
+        }
+    }
+}
+
+impl Actor for SlaMonitor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        // changes to the interval take effect on restart
+        let interval = SETTING.get_network().sla.check_interval.max(1);
+        ctx.run_interval(Duration::from_secs(interval), |act, _ctx| act.check());
+    }
+}
+
+impl Supervised for SlaMonitor {}
+impl SystemService for SlaMonitor {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("SLA monitor started");
+    }
+}
+
+impl SlaMonitor {
+    fn check(&mut self) {
+        // a check that runs over isn't doubled up on, the one that's skipped counts as missed
+        if self.checking {
+            trace!("Previous SLA check is still running");
+            return;
+        }
+        let interval = Duration::from_secs(SETTING.get_network().sla.check_interval.max(1));
+        // an exit the exit manager hasn't told us about in a while isn't checked, without an
+        // exit manager that's every time
+        let exit = match self.exit {
+            Some((exit, told)) if told.elapsed() < interval * 2 => exit,
+            _ => None,
+        };
+        self.checking = true;
+        let checker = self
+            .checker
+            .get_or_insert_with(|| SyncArbiter::start(1, || SlaChecker))
+            .clone();
+        Arbiter::spawn(
+            checker
+                .send(RunChecks { exit })
+                .join(TunnelManager::from_registry().send(GetNeighbors).then(Ok))
+                .then(move |res| {
+                    let sample = match res {
+                        Ok(((exit, wan), neighbors)) => Some(SlaSample {
+                            exit,
+                            wan,
+                            mesh: match neighbors {
+                                Ok(Ok(neighbors)) => Some(!neighbors.is_empty()),
+                                _ => None,
+                            },
+                        }),
+                        Err(e) => {
+                            error!("SLA checks failed to run {:?}", e);
+                            None
+                        }
+                    };
+                    SlaMonitor::from_registry().do_send(RecordSample(sample));
+                    Ok(())
+                }),
+        );
+    }
+}
+
+/// The result of a round of checks, None if they couldn't be run
+struct RecordSample(Option<SlaSample>);
+
+impl Message for RecordSample {
+    type Result = ();
+}
+
+impl Handler<RecordSample> for SlaMonitor {
+    type Result = ();
+
+    fn handle(&mut self, msg: RecordSample, _ctx: &mut Context<Self>) -> Self::Result {
+        self.checking = false;
+        let sample = match msg.0 {
+            Some(sample) => sample,
+            None => return,
+        };
+        trace!("SLA check {:?}", sample);
+        let interval = SETTING.get_network().sla.check_interval;
+        self.history.record(sample, now_secs(), interval);
+        self.latest = Some(sample);
+        self.unsaved += 1;
+        if self.unsaved >= SAVE_EVERY {
+            self.unsaved = 0;
+            let path = SETTING.get_network().sla.file.clone();
+            if let Err(e) = save_history(&self.history, &path) {
+                error!("Failed to save SLA history to {} {:?}", path, e);
+            }
+        }
+    }
+}
+
+/// Sent by the client's exit manager every tick with the internal ip of the selected exit to
+/// check over the exit tunnel, None if we have no exit to check
+pub struct ExitToCheck(pub Option<IpAddr>);
+
+impl Message for ExitToCheck {
+    type Result = ();
+}
+
+impl Handler<ExitToCheck> for SlaMonitor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ExitToCheck, _ctx: &mut Context<Self>) -> Self::Result {
+        self.exit = Some((msg.0, Instant::now()));
+    }
+}
+
+pub struct GetSlaReport;
+
+impl Message for GetSlaReport {
+    type Result = Result<SlaReport, Error>;
+}
+
+impl Handler<GetSlaReport> for SlaMonitor {
+    type Result = Result<SlaReport, Error>;
+
+    fn handle(&mut self, _msg: GetSlaReport, _ctx: &mut Context<Self>) -> Self::Result {
+        let sla = SETTING.get_network().sla.clone();
+        Ok(SlaReport {
+            check_interval: sla.check_interval,
+            targets: sla.targets,
+            latest: self.latest,
+            daily: self.history.daily(&sla.targets),
+            monthly: self.history.monthly(&sla.targets),
+        })
+    }
+}
+
+#[test]
+fn test_sla_history() {
+    assert_eq!(civil_from_days(0), (1970, 1, 1));
+    assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+    assert_eq!(civil_from_days(20_742), (2026, 10, 16));
+
+    let up = SlaSample {
+        exit: Some(true),
+        wan: None,
+        mesh: Some(true),
+    };
+    let exit_down = SlaSample {
+        exit: Some(false),
+        ..up
+    };
+    // the last day of september and the first two of october 2026
+    let day = |days: u64| days * SECONDS_PER_DAY;
+    // checked once a day so that nothing is missed in between
+    let interval = SECONDS_PER_DAY;
+    let mut history = SlaHistory::default();
+    for _ in 0..3 {
+        history.record(up, day(20_726), interval);
+    }
+    history.record(exit_down, day(20_726) + 100, interval);
+    for _ in 0..4 {
+        history.record(up, day(20_727), interval);
+    }
+    for _ in 0..4 {
+        history.record(up, day(20_728), interval);
+    }
+
+    let targets = SlaTargets {
+        exit: Some(9_000),
+        wan: Some(9_000),
+        mesh: None,
+    };
+    let daily = history.daily(&targets);
+    assert_eq!(daily.len(), 3);
+    assert_eq!(daily[0].period, "2026-10-02");
+    assert_eq!(daily[2].period, "2026-09-30");
+    assert_eq!(daily[2].exit.uptime_percent, Some(75.0));
+    assert_eq!(daily[2].exit.failed_checks, 1);
+    assert_eq!(daily[2].exit.met_target, Some(false));
+    assert_eq!(daily[0].exit.met_target, Some(true));
+    // tiers that don't apply or have no target aren't judged
+    assert_eq!(daily[0].wan.uptime_percent, None);
+    assert_eq!(daily[0].wan.met_target, None);
+    assert_eq!(daily[0].mesh.met_target, None);
+
+    let monthly = history.monthly(&targets);
+    assert_eq!(monthly.len(), 2);
+    assert_eq!(monthly[0].period, "2026-10");
+    assert_eq!(monthly[0].exit.checks, 8);
+    assert_eq!(monthly[1].period, "2026-09");
+    assert_eq!(monthly[1].mesh.uptime_percent, Some(100.0));
+
+    // only the last MAX_SLA_DAYS are kept
+    for i in 0..MAX_SLA_DAYS as u64 {
+        history.record(up, day(20_729 + i), interval);
+    }
+    assert_eq!(history.days.len(), MAX_SLA_DAYS);
+    assert_eq!(history.days.front().unwrap().day, 20_729);
+
+    // checks that weren't made count against the tiers the next check covers, on the days
+    // they should have been made
+    let mut history = SlaHistory::default();
+    let start = day(20_727) - 180;
+    history.record(up, start, 60);
+    history.record(up, start + 61, 60);
+    // a check running a bit late isn't a miss
+    assert_eq!(
+        history.days.back().unwrap().exit,
+        Uptime { up: 2, checks: 2 }
+    );
+    // down from a minute before midnight to two minutes after
+    history.record(up, start + 61 + 240, 60);
+    let daily = history.daily(&targets);
+    assert_eq!(daily[1].exit.checks, 3);
+    assert_eq!(daily[1].exit.failed_checks, 1);
+    assert_eq!(daily[0].exit.checks, 3);
+    assert_eq!(daily[0].exit.failed_checks, 2);
+    assert_eq!(daily[0].wan.checks, 0);
+    // the clock going back doesn't reopen old days
+    history.record(up, start, 60);
+    assert_eq!(history.days.len(), 2);
+}
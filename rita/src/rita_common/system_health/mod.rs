@@ -0,0 +1,290 @@
+//! How the router itself is holding up, load, memory, storage, the conntrack table, temperature
+//! and Rita's own memory. Everything is read fresh for `/system_health` and once every slow loop
+//! tick to compare against the thresholds in the settings. A resource going past its threshold
+//! is journaled with the watchdog once, along with it coming back under, rather than every tick
+//! it stays there. Whatever can't be read on a device is left out of the report.
+
+use crate::rita_common::watchdog::{Journal, Watchdog, WatchdogEventKind};
+use crate::KI;
+use crate::SETTING;
+use ::actix::SystemService;
+use failure::Error;
+use settings::network::SystemHealthSettings;
+use settings::RitaCommonSettings;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+/// OpenWrt keeps everything that's written in the overlay, the root is read only squashfs
+const OVERLAY: &str = "/overlay";
+
+lazy_static! {
+    /// Resources over their threshold as of the last check
+    static ref OVER_THRESHOLD: Arc<RwLock<HashSet<String>>> =
+        Arc::new(RwLock::new(HashSet::new()));
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Memory {
+    pub total_kb: u64,
+    pub available_kb: u64,
+    pub used_percent: u8,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Storage {
+    pub mount: String,
+    pub total_kb: u64,
+    pub free_kb: u64,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Conntrack {
+    pub count: u64,
+    pub max: u64,
+    pub used_percent: u8,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Temperature {
+    pub sensor: String,
+    pub celsius: f32,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct SystemHealth {
+    /// one, five and fifteen minute averages
+    pub load_average: Option<[f32; 3]>,
+    pub memory: Option<Memory>,
+    pub storage: Option<Storage>,
+    pub conntrack: Option<Conntrack>,
+    pub temperatures: Vec<Temperature>,
+    pub rita_rss_kb: Option<u64>,
+    /// names and values of the resources that are over their thresholds
+    pub warnings: HashMap<String, u64>,
+}
+
+fn percent(part: u64, whole: u64) -> u8 {
+    if whole == 0 {
+        return 0;
+    }
+    (part.min(whole) * 100 / whole) as u8
+}
+
+/// Logs and drops what can't be read
+fn read<T>(what: &str, res: Result<T, Error>) -> Option<T> {
+    match res {
+        Ok(val) => Some(val),
+        Err(e) => {
+            warn!("Failed to read {} {:?}", what, e);
+            None
+        }
+    }
+}
+
+pub fn get_system_health() -> SystemHealth {
+    let mount = if Path::new(OVERLAY).exists() {
+        OVERLAY
+    } else {
+        "/"
+    };
+    let mut health = SystemHealth {
+        load_average: read("load average", KI.get_load_average())
+            .map(|load| [load.one, load.five, load.fifteen]),
+        memory: read("memory", KI.get_memory_info()).map(|mem| Memory {
+            total_kb: mem.total_kb,
+            available_kb: mem.available_kb,
+            used_percent: percent(mem.total_kb.saturating_sub(mem.available_kb), mem.total_kb),
+        }),
+        storage: read("storage", KI.get_storage_info(mount)).map(|storage| Storage {
+            mount: mount.to_string(),
+            total_kb: storage.total_kb,
+            free_kb: storage.free_kb,
+        }),
+        conntrack: read("conntrack usage", KI.get_conntrack_usage())
+            .and_then(|usage| usage)
+            .map(|usage| Conntrack {
+                count: usage.count,
+                max: usage.max,
+                used_percent: percent(usage.count, usage.max),
+            }),
+        temperatures: read("temperatures", KI.get_thermal_zones())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|zone| Temperature {
+                sensor: zone.name,
+                celsius: zone.celsius,
+            })
+            .collect(),
+        rita_rss_kb: read("our rss", KI.get_own_rss()),
+        warnings: HashMap::new(),
+    };
+    let thresholds = SETTING.get_network().system_health.clone();
+    health.warnings = over_threshold(&health, &thresholds)
+        .into_iter()
+        .map(|(resource, value, _)| (resource.to_string(), value))
+        .collect();
+    health
+}
+
+/// Every resource over its threshold, with its value and the threshold
+fn over_threshold(
+    health: &SystemHealth,
+    thresholds: &SystemHealthSettings,
+) -> Vec<(&'static str, u64, u64)> {
+    let mut over = Vec::new();
+    if let Some(memory) = health.memory.as_ref() {
+        if memory.used_percent > thresholds.memory_percent {
+            let threshold = u64::from(thresholds.memory_percent);
+            over.push(("memory_percent", u64::from(memory.used_percent), threshold));
+        }
+    }
+    if let Some(storage) = health.storage.as_ref() {
+        if storage.free_kb < thresholds.storage_free_kb {
+            over.push((
+                "storage_free_kb",
+                storage.free_kb,
+                thresholds.storage_free_kb,
+            ));
+        }
+    }
+    if let Some(conntrack) = health.conntrack.as_ref() {
+        if conntrack.used_percent > thresholds.conntrack_percent {
+            let threshold = u64::from(thresholds.conntrack_percent);
+            over.push((
+                "conntrack_percent",
+                u64::from(conntrack.used_percent),
+                threshold,
+            ));
+        }
+    }
+    let hottest = health
+        .temperatures
+        .iter()
+        .map(|temp| temp.celsius)
+        .fold(std::f32::NEG_INFINITY, f32::max);
+    if hottest > thresholds.temperature as f32 {
+        let threshold = u64::from(thresholds.temperature);
+        over.push(("temperature", hottest as u64, threshold));
+    }
+    if let (Some(rss), Some(threshold)) = (health.rita_rss_kb, thresholds.rita_rss_kb) {
+        if rss > threshold {
+            over.push(("rita_rss_kb", rss, threshold));
+        }
+    }
+    over
+}
+
+fn journal(kind: WatchdogEventKind) {
+    Watchdog::from_registry().do_send(Journal {
+        source: "SystemHealth",
+        kind,
+    });
+}
+
+/// Journals resources that have gone over their thresholds or come back under since the last
+/// check, called from the slow loop
+pub fn check_system_health() {
+    let health = get_system_health();
+    let thresholds = SETTING.get_network().system_health.clone();
+    let over = over_threshold(&health, &thresholds);
+    let mut previous = OVER_THRESHOLD.write().unwrap();
+    for (resource, value, threshold) in over.iter() {
+        if !previous.contains(*resource) {
+            warn!(
+                "{} is at {}, past the threshold of {}",
+                resource, value, threshold
+            );
+            journal(WatchdogEventKind::ResourceWarning {
+                resource: resource.to_string(),
+                value: *value,
+                threshold: *threshold,
+            });
+        }
+    }
+    for resource in previous.iter() {
+        if !over.iter().any(|(over, _, _)| over == resource) {
+            info!("{} is back under its threshold", resource);
+            journal(WatchdogEventKind::ResourceRecovered {
+                resource: resource.clone(),
+                value: current_value(&health, resource),
+            });
+        }
+    }
+    *previous = over
+        .into_iter()
+        .map(|(resource, _, _)| resource.to_string())
+        .collect();
+}
+
+/// The value of a resource named by `over_threshold`, for when it's no longer over
+fn current_value(health: &SystemHealth, resource: &str) -> u64 {
+    let value = match resource {
+        "memory_percent" => health
+            .memory
+            .as_ref()
+            .map(|mem| u64::from(mem.used_percent)),
+        "storage_free_kb" => health.storage.as_ref().map(|storage| storage.free_kb),
+        "conntrack_percent" => health
+            .conntrack
+            .as_ref()
+            .map(|conntrack| u64::from(conntrack.used_percent)),
+        "temperature" => health
+            .temperatures
+            .iter()
+            .map(|temp| temp.celsius.max(0.0) as u64)
+            .max(),
+        "rita_rss_kb" => health.rita_rss_kb,
+        _ => None,
+    };
+    value.unwrap_or(0)
+}
+
+#[test]
+fn test_over_threshold() {
+    let thresholds = SystemHealthSettings::default();
+    let mut health = SystemHealth {
+        memory: Some(Memory {
+            total_kb: 124_720,
+            available_kb: 61236,
+            used_percent: percent(124_720 - 61236, 124_720),
+        }),
+        storage: Some(Storage {
+            mount: OVERLAY.to_string(),
+            total_kb: 4864,
+            free_kb: 4028,
+        }),
+        conntrack: Some(Conntrack {
+            count: 100,
+            max: 16384,
+            used_percent: percent(100, 16384),
+        }),
+        temperatures: vec![Temperature {
+            sensor: "cpu-thermal".to_string(),
+            celsius: 55.5,
+        }],
+        rita_rss_kb: Some(18432),
+        ..Default::default()
+    };
+    assert_eq!(health.memory.as_ref().unwrap().used_percent, 50);
+    assert!(over_threshold(&health, &thresholds).is_empty());
+
+    health.storage.as_mut().unwrap().free_kb = 512;
+    health.temperatures.push(Temperature {
+        sensor: "wlan".to_string(),
+        celsius: 95.2,
+    });
+    assert_eq!(
+        over_threshold(&health, &thresholds),
+        vec![("storage_free_kb", 512, 1024), ("temperature", 95, 90)]
+    );
+    assert_eq!(current_value(&health, "temperature"), 95);
+
+    // no limit on our own memory unless one is set
+    let thresholds = SystemHealthSettings {
+        rita_rss_kb: Some(16384),
+        ..SystemHealthSettings::default()
+    };
+    assert!(over_threshold(&health, &thresholds).contains(&("rita_rss_kb", 18432, 16384)));
+    assert_eq!(percent(5, 0), 0);
+}
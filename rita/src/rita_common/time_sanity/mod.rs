@@ -0,0 +1,213 @@
+//! Keeps an eye on how far our clock has drifted. Routers without a battery backed clock come up
+//! at whatever time they were built and only get the real time once ntp gets through, if it ever
+//! does, and with a clock that's badly off billing rounds and last seen times go wrong.
+//!
+//! Clients compare their clock against the time their exit puts in its encrypted exit state, the
+//! only time a peer tells us that can't be made up by whoever is in between. Neighbors aren't
+//! asked, anyone can claim any time in a hello, and exits have nobody to ask so they're only ever
+//! held back by ntp. When the exit's time says we're off by more than the safe bound ntp is asked
+//! how far off we are before anything is done. If it agrees debts aren't enforced until our clock
+//! is back in line, since we can't tell whether the debt that would be enforced was counted
+//! right, and the clock is resynced. Talking to ntp takes seconds so it's done on its own thread,
+//! one check at a time, and the slow loop picks up what it found on the next tick.
+
+use crate::rita_common::utils::now_secs;
+use crate::rita_common::watchdog::{Journal, Watchdog, WatchdogEventKind};
+use crate::KI;
+use crate::SETTING;
+use actix::SystemService;
+use settings::RitaCommonSettings;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long what the exit told us about the time is used for
+const SAMPLE_MAX_AGE: Duration = Duration::from_secs(900);
+/// How often we ask ntp while the exit says our clock is off
+const NTP_RETRY: Duration = Duration::from_secs(600);
+
+/// What ntp said when we last asked
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NtpCheck {
+    /// it agreed we're off by this many seconds and the clock was resynced, or not
+    Skewed { skew: i64, resynced: bool },
+    /// our clock is fine by ntp, the exit's is the one that's off
+    Fine { skew: i64 },
+    /// ntp couldn't be reached, so we can't tell who's right
+    Unreachable,
+}
+
+#[derive(Debug, Default)]
+struct ClockState {
+    /// the exit's clock minus ours in seconds as of its last answer
+    exit_skew: Option<(i64, Instant)>,
+    /// if the clock was off as of the last check
+    skewed: bool,
+    last_ntp: Option<Instant>,
+    /// if an ntp check is running
+    checking: bool,
+    /// the outcome of an ntp check the slow loop hasn't looked at yet
+    ntp_check: Option<NtpCheck>,
+}
+
+lazy_static! {
+    static ref CLOCK: Arc<RwLock<ClockState>> = Arc::new(RwLock::new(ClockState::default()));
+}
+
+/// Notes the time our exit put in its last answer, in seconds since the unix epoch
+pub fn record_exit_time(theirs: u64) {
+    let skew = theirs as i64 - now_secs() as i64;
+    trace!("Our exit is {}s off from us", skew);
+    CLOCK.write().unwrap().exit_skew = Some((skew, Instant::now()));
+}
+
+/// How far off the exit says our clock is, positive if we're behind, None if it hasn't said
+/// lately
+fn exit_skew(state: &ClockState) -> Option<i64> {
+    match state.exit_skew {
+        Some((skew, at)) if at.elapsed() < SAMPLE_MAX_AGE => Some(skew),
+        _ => None,
+    }
+}
+
+/// If our clock is off given what ntp said at the last check, None if it couldn't tell
+fn ntp_says_skewed(check: NtpCheck) -> Option<bool> {
+    match check {
+        NtpCheck::Skewed { resynced, .. } => Some(!resynced),
+        NtpCheck::Fine { .. } => Some(false),
+        NtpCheck::Unreachable => None,
+    }
+}
+
+/// False while our clock is too far off to make enforcement decisions with
+pub fn clock_is_sane() -> bool {
+    !CLOCK.read().unwrap().skewed
+}
+
+fn journal(kind: WatchdogEventKind) {
+    Watchdog::from_registry().do_send(Journal {
+        source: "TimeSanity",
+        kind,
+    });
+}
+
+/// Asks ntp how far off we are and resyncs if it agrees with the exit, run on its own thread
+fn check_ntp(servers: Vec<String>, max_skew: u64) {
+    let check = match KI.ntp_offset(&servers) {
+        Ok(offset) if offset.abs() as u64 > max_skew => {
+            let resynced = match KI.sync_time_ntp(&servers) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Failed to resync our clock {:?}", e);
+                    false
+                }
+            };
+            NtpCheck::Skewed {
+                skew: offset as i64,
+                resynced,
+            }
+        }
+        Ok(offset) => NtpCheck::Fine {
+            skew: offset as i64,
+        },
+        Err(e) => {
+            warn!("Failed to check our clock over ntp {:?}", e);
+            NtpCheck::Unreachable
+        }
+    };
+    let mut state = CLOCK.write().unwrap();
+    state.checking = false;
+    state.ntp_check = Some(check);
+}
+
+/// Goes over what the exit and ntp have said about our clock, called from the slow loop
+pub fn check_clock() {
+    let settings = SETTING.get_network().time_sanity.clone();
+    let mut state = CLOCK.write().unwrap();
+
+    if let Some(check) = state.ntp_check.take() {
+        let skewed = ntp_says_skewed(check).unwrap_or(state.skewed);
+        match check {
+            NtpCheck::Skewed { skew, resynced } => {
+                if !state.skewed {
+                    error!(
+                        "Our clock is {}s off by ntp, not enforcing debts until it's fixed",
+                        skew
+                    );
+                    journal(WatchdogEventKind::ClockSkew { skew });
+                }
+                if resynced {
+                    info!("Resynced our clock over ntp");
+                    journal(WatchdogEventKind::ClockRecovered { skew: 0 });
+                    // relative to the old clock
+                    state.exit_skew = None;
+                }
+            }
+            NtpCheck::Fine { skew } => {
+                warn!(
+                    "Our exit's clock disagrees with ours but we're only {}s off by ntp",
+                    skew
+                );
+                if state.skewed {
+                    journal(WatchdogEventKind::ClockRecovered { skew });
+                }
+            }
+            NtpCheck::Unreachable => {}
+        }
+        state.skewed = skewed;
+    }
+
+    let suspect = match exit_skew(&state) {
+        Some(skew) => skew.abs() as u64 > settings.max_skew,
+        None => false,
+    };
+    // going back to enforcing is the safe direction, the exit's word is enough for that
+    if state.skewed && exit_skew(&state).is_some() && !suspect {
+        info!("Our clock is back in line with our exit's");
+        journal(WatchdogEventKind::ClockRecovered {
+            skew: exit_skew(&state).unwrap_or(0),
+        });
+        state.skewed = false;
+    }
+    let ntp_due = state
+        .last_ntp
+        .map_or(true, |last_ntp| last_ntp.elapsed() > NTP_RETRY);
+    if !suspect || !ntp_due || state.checking || settings.ntp_servers.is_empty() {
+        return;
+    }
+    info!("Our exit says our clock is off, checking with ntp");
+    state.checking = true;
+    state.last_ntp = Some(Instant::now());
+    drop(state);
+    let max_skew = settings.max_skew;
+    thread::spawn(move || check_ntp(settings.ntp_servers, max_skew));
+}
+
+#[test]
+fn test_exit_skew() {
+    let mut state = ClockState::default();
+    assert_eq!(exit_skew(&state), None);
+    state.exit_skew = Some((-400, Instant::now()));
+    assert_eq!(exit_skew(&state), Some(-400));
+}
+
+#[test]
+fn test_ntp_says_skewed() {
+    assert_eq!(
+        ntp_says_skewed(NtpCheck::Skewed {
+            skew: 3600,
+            resynced: false
+        }),
+        Some(true)
+    );
+    assert_eq!(
+        ntp_says_skewed(NtpCheck::Skewed {
+            skew: 3600,
+            resynced: true
+        }),
+        Some(false)
+    );
+    // the exit alone is never enough to hold back enforcement
+    assert_eq!(ntp_says_skewed(NtpCheck::Fine { skew: 1 }), Some(false));
+    assert_eq!(ntp_says_skewed(NtpCheck::Unreachable), None);
+}
@@ -161,6 +161,12 @@ impl SystemService for TokenBridge {
     }
 }
 
+/// The bridge signs with our private key itself rather than through the payment signer, so it
+/// can't be used when the key is only held by an external signer
+pub fn bridge_has_key() -> bool {
+    SETTING.get_payment().eth_private_key.is_some()
+}
+
 fn token_bridge_core_from_settings() -> TokenBridgeCore {
     let payment_settings = SETTING.get_payment();
     let addresses = payment_settings.bridge_addresses.clone();
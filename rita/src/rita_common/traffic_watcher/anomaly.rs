@@ -0,0 +1,192 @@
+//! Spots neighbors whose traffic suddenly jumps far above what they normally send us. Each
+//! neighbor has a baseline, a moving average of their bytes per round, and a round that exceeds
+//! it by the configured multiple raises an alert and optionally throttles the neighbor for a
+//! while. Without this a misbehaving neighbor pumping garbage traffic only shows up as a
+//! surprise on the bill.
+
+use crate::rita_common::utils::now_secs;
+use althea_types::Identity;
+use settings::network::TrafficAnomalySettings;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// How much a single round moves the baseline
+const BASELINE_WEIGHT: f64 = 0.1;
+/// Rounds we watch a neighbor for before we trust their baseline
+const WARMUP_ROUNDS: u32 = 12;
+/// How many alerts we keep around for the dashboard
+const MAX_ALERTS: usize = 100;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TrafficAlert {
+    pub neighbor: Identity,
+    /// seconds since the unix epoch
+    pub timestamp: u64,
+    /// bytes sent and received in the anomalous round
+    pub bytes: u64,
+    /// the neighbor's usual bytes per round
+    pub baseline: u64,
+    pub throttled: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Baseline {
+    average: f64,
+    rounds: u32,
+    /// if the last round was anomalous, so that sustained anomalies only alert once
+    anomalous: bool,
+}
+
+#[derive(Default)]
+pub struct AnomalyDetector {
+    baselines: HashMap<Identity, Baseline>,
+    throttled: HashMap<Identity, Instant>,
+    alerts: VecDeque<TrafficAlert>,
+}
+
+/// What the traffic watcher should do about this round
+#[derive(Debug, Default, PartialEq)]
+pub struct AnomalyActions {
+    pub throttle: Vec<Identity>,
+    pub unthrottle: Vec<Identity>,
+}
+
+impl AnomalyDetector {
+    /// Folds a round of per neighbor byte counts into the baselines, recording alerts for
+    /// neighbors that have just become anomalous
+    pub fn observe(
+        &mut self,
+        usage: &HashMap<Identity, u64>,
+        settings: &TrafficAnomalySettings,
+        now: Instant,
+    ) -> AnomalyActions {
+        let mut actions = AnomalyActions::default();
+        let throttle_duration = Duration::from_secs(settings.throttle_duration);
+        let expired: Vec<Identity> = self
+            .throttled
+            .iter()
+            .filter(|(_id, since)| now - **since > throttle_duration)
+            .map(|(id, _since)| *id)
+            .collect();
+        for id in expired {
+            info!("Lifting anomalous traffic throttle on {}", id.mesh_ip);
+            self.throttled.remove(&id);
+            actions.unthrottle.push(id);
+        }
+
+        // neighbors we no longer have tunnels with start over if they come back
+        self.baselines.retain(|id, _| usage.contains_key(id));
+
+        for (id, bytes) in usage {
+            let baseline = self.baselines.entry(*id).or_insert_with(Baseline::default);
+            let limit = baseline.average * f64::from(settings.multiple);
+            let anomalous = baseline.rounds >= WARMUP_ROUNDS
+                && *bytes >= settings.min_bytes
+                && *bytes as f64 > limit;
+
+            if anomalous && !baseline.anomalous {
+                let throttled =
+                    settings.throttle_mbps.is_some() && !self.throttled.contains_key(id);
+                warn!(
+                    "Anomalous traffic from {}, {} bytes this round against a baseline of {}",
+                    id.mesh_ip, bytes, baseline.average as u64
+                );
+                if throttled {
+                    self.throttled.insert(*id, now);
+                    actions.throttle.push(*id);
+                }
+                if self.alerts.len() >= MAX_ALERTS {
+                    self.alerts.pop_front();
+                }
+                self.alerts.push_back(TrafficAlert {
+                    neighbor: *id,
+                    timestamp: now_secs(),
+                    bytes: *bytes,
+                    baseline: baseline.average as u64,
+                    throttled,
+                });
+            }
+            baseline.anomalous = anomalous;
+
+            // the first round seeds the average rather than dragging it up from zero
+            if baseline.rounds == 0 {
+                baseline.average = *bytes as f64;
+            } else {
+                baseline.average =
+                    baseline.average * (1.0 - BASELINE_WEIGHT) + *bytes as f64 * BASELINE_WEIGHT;
+            }
+            baseline.rounds = baseline.rounds.saturating_add(1);
+        }
+        actions
+    }
+
+    pub fn alerts(&self) -> Vec<TrafficAlert> {
+        self.alerts.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rita_common::utils::test_identity;
+
+    fn id() -> Identity {
+        test_identity("fd00::2")
+    }
+
+    fn round(bytes: u64) -> HashMap<Identity, u64> {
+        let mut usage = HashMap::new();
+        usage.insert(id(), bytes);
+        usage
+    }
+
+    #[test]
+    fn test_anomaly_detection() {
+        let settings = TrafficAnomalySettings {
+            multiple: 10,
+            min_bytes: 1000,
+            throttle_mbps: Some(1),
+            throttle_duration: 60,
+        };
+        let mut detector = AnomalyDetector::default();
+        let start = Instant::now();
+
+        // a spike during warmup is not trusted
+        detector.observe(&round(500), &settings, start);
+        assert_eq!(
+            detector.observe(&round(100_000), &settings, start),
+            AnomalyActions::default()
+        );
+        for _ in 0..40 {
+            detector.observe(&round(500), &settings, start);
+        }
+        assert!(detector.alerts().is_empty());
+
+        // over the multiple but under min_bytes
+        let mut quiet = TrafficAnomalySettings::default();
+        quiet.min_bytes = 1_000_000;
+        assert_eq!(
+            detector.observe(&round(100_000), &quiet, start),
+            AnomalyActions::default()
+        );
+        for _ in 0..40 {
+            detector.observe(&round(500), &settings, start);
+        }
+
+        let actions = detector.observe(&round(100_000), &settings, start);
+        assert_eq!(actions.throttle, vec![id()]);
+        // a sustained anomaly only alerts once
+        assert_eq!(
+            detector.observe(&round(150_000), &settings, start),
+            AnomalyActions::default()
+        );
+        let alerts = detector.alerts();
+        assert_eq!(alerts.len(), 1);
+        assert!(alerts[0].throttled);
+        assert_eq!(alerts[0].bytes, 100_000);
+
+        let later = start + Duration::from_secs(61);
+        let actions = detector.observe(&round(500), &settings, later);
+        assert_eq!(actions.unthrottle, vec![id()]);
+    }
+}
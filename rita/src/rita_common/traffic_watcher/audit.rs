@@ -0,0 +1,221 @@
+//! An audit trail for billing disputes. When it's enabled the traffic watcher records the bytes
+//! exchanged with each neighbor for each destination, and the route price they were billed at,
+//! summed per minute and kept for the configured number of hours. If a bill looks wrong the
+//! samples show which destination and which price it came from, say a route that briefly went
+//! through an expensive neighbor.
+
+use althea_types::Identity;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+
+/// The most samples kept per neighbor no matter the configured hours, to bound memory on
+/// routers. Per neighbor so that one busy neighbor can't push out everyone else's history
+const MAX_AUDIT_SAMPLES_PER_NEIGHBOR: usize = 5_000;
+const SECONDS_PER_MINUTE: u64 = 60;
+const SECONDS_PER_HOUR: u64 = 3600;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditDirection {
+    /// the neighbor sent us traffic for the destination, they pay us
+    Received,
+    /// we sent the neighbor traffic for the destination, we pay them
+    Sent,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AuditSample {
+    /// the start of the minute, in seconds since the unix epoch
+    pub minute: u64,
+    pub neighbor: Identity,
+    pub destination: IpAddr,
+    pub direction: AuditDirection,
+    /// wei per byte, a destination whose price changed within the minute has a sample per price
+    pub price: u64,
+    pub bytes: u64,
+}
+
+/// Filters for the audit endpoint, all optional
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct AuditQuery {
+    /// a neighbor's mesh ip
+    pub neighbor: Option<IpAddr>,
+    pub destination: Option<IpAddr>,
+    /// seconds since the unix epoch
+    pub since: Option<u64>,
+}
+
+impl AuditQuery {
+    fn matches(&self, sample: &AuditSample) -> bool {
+        self.neighbor
+            .map_or(true, |ip| ip == sample.neighbor.mesh_ip)
+            && self.destination.map_or(true, |ip| ip == sample.destination)
+            && self.since.map_or(true, |since| sample.minute >= since)
+    }
+}
+
+type SampleKey = (Identity, IpAddr, AuditDirection, u64);
+
+#[derive(Default)]
+pub struct BillingAudit {
+    /// finished minutes per neighbor, oldest first
+    samples: HashMap<Identity, VecDeque<AuditSample>>,
+    current_minute: u64,
+    current: HashMap<SampleKey, u64>,
+}
+
+impl BillingAudit {
+    pub fn record(
+        &mut self,
+        now: u64,
+        neighbor: Identity,
+        destination: IpAddr,
+        direction: AuditDirection,
+        price: u64,
+        bytes: u64,
+    ) {
+        if bytes == 0 {
+            return;
+        }
+        let minute = now - now % SECONDS_PER_MINUTE;
+        if minute != self.current_minute {
+            self.finish_minute();
+            self.current_minute = minute;
+        }
+        *self
+            .current
+            .entry((neighbor, destination, direction, price))
+            .or_insert(0) += bytes;
+    }
+
+    fn finish_minute(&mut self) {
+        let minute = self.current_minute;
+        let mut finished: Vec<AuditSample> = self
+            .current
+            .drain()
+            .map(
+                |((neighbor, destination, direction, price), bytes)| AuditSample {
+                    minute,
+                    neighbor,
+                    destination,
+                    direction,
+                    price,
+                    bytes,
+                },
+            )
+            .collect();
+        finished.sort_by_key(|sample| (sample.neighbor.mesh_ip, sample.destination));
+        for sample in finished {
+            let samples = self.samples.entry(sample.neighbor).or_default();
+            samples.push_back(sample);
+            if samples.len() > MAX_AUDIT_SAMPLES_PER_NEIGHBOR {
+                samples.pop_front();
+            }
+        }
+    }
+
+    /// Drops samples older than `hours` before `now`
+    pub fn expire(&mut self, now: u64, hours: u32) {
+        let cutoff = now.saturating_sub(u64::from(hours) * SECONDS_PER_HOUR);
+        for samples in self.samples.values_mut() {
+            while samples
+                .front()
+                .map_or(false, |sample| sample.minute < cutoff)
+            {
+                samples.pop_front();
+            }
+        }
+        self.samples.retain(|_, samples| !samples.is_empty());
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+        self.current.clear();
+    }
+
+    /// Matching samples oldest first, including the minute still being recorded
+    pub fn query(&self, query: &AuditQuery) -> Vec<AuditSample> {
+        let current =
+            self.current
+                .iter()
+                .map(
+                    |((neighbor, destination, direction, price), bytes)| AuditSample {
+                        minute: self.current_minute,
+                        neighbor: *neighbor,
+                        destination: *destination,
+                        direction: *direction,
+                        price: *price,
+                        bytes: *bytes,
+                    },
+                );
+        let mut matching: Vec<AuditSample> = self
+            .samples
+            .values()
+            .flatten()
+            .cloned()
+            .chain(current)
+            .filter(|sample| query.matches(sample))
+            .collect();
+        matching.sort_by_key(|sample| (sample.minute, sample.neighbor.mesh_ip, sample.destination));
+        matching
+    }
+}
+
+#[test]
+fn test_billing_audit() {
+    use crate::rita_common::utils::test_identity;
+
+    let neighbor = test_identity("fd00::1");
+    let a: IpAddr = "fd00::a".parse().unwrap();
+    let b: IpAddr = "fd00::b".parse().unwrap();
+    let mut audit = BillingAudit::default();
+
+    // the same minute and price are summed, a new price gets its own sample
+    audit.record(3600, neighbor, a, AuditDirection::Received, 10, 100);
+    audit.record(3630, neighbor, a, AuditDirection::Received, 10, 50);
+    audit.record(3630, neighbor, a, AuditDirection::Received, 20, 5);
+    audit.record(3630, neighbor, b, AuditDirection::Sent, 10, 0);
+    audit.record(3660, neighbor, b, AuditDirection::Sent, 7, 1);
+
+    let all = audit.query(&AuditQuery::default());
+    assert_eq!(all.len(), 3);
+    assert_eq!(all[2].minute, 3660);
+    let expensive: Vec<(u64, u64)> = all
+        .iter()
+        .filter(|s| s.destination == a)
+        .map(|s| (s.price, s.bytes))
+        .collect();
+    assert!(expensive.contains(&(10, 150)));
+    assert!(expensive.contains(&(20, 5)));
+
+    let query = AuditQuery {
+        destination: Some(b),
+        ..Default::default()
+    };
+    assert_eq!(audit.query(&query).len(), 1);
+    let query = AuditQuery {
+        neighbor: Some(b),
+        ..Default::default()
+    };
+    assert!(audit.query(&query).is_empty());
+
+    audit.expire(3600 + SECONDS_PER_HOUR + 30, 1);
+    assert_eq!(audit.query(&AuditQuery::default()).len(), 1);
+
+    // a busy neighbor only pushes out its own old samples
+    let busy = test_identity("fd00::2");
+    let minute = 2 * SECONDS_PER_HOUR;
+    audit.record(minute, neighbor, a, AuditDirection::Sent, 1, 1);
+    for i in 0..MAX_AUDIT_SAMPLES_PER_NEIGHBOR as u64 + 2 {
+        audit.record(minute + (i + 1) * 60, busy, a, AuditDirection::Sent, 1, 1);
+    }
+    let query = |ip| AuditQuery {
+        neighbor: Some(ip),
+        ..Default::default()
+    };
+    assert_eq!(audit.query(&query(neighbor.mesh_ip)).len(), 2);
+    // the cap applies to finished minutes, the last one is still being recorded
+    let busy_samples = audit.query(&query(busy.mesh_ip));
+    assert_eq!(busy_samples.len(), MAX_AUDIT_SAMPLES_PER_NEIGHBOR + 1);
+    assert_eq!(busy_samples[0].minute, minute + 2 * 60);
+}
@@ -0,0 +1,134 @@
+//! Turns readings of cumulative byte counters, like the wireguard counters of a tunnel or the
+//! counters of an interface, into the bytes moved since the previous reading. These counters only
+//! go down when something happened to them, they wrapped around, the interface was deleted and
+//! recreated between readings, or we read them right as they were being zeroed. Each of those
+//! would otherwise either bill a huge bogus amount or, with unsigned math, panic.
+
+/// A counter that drops from within this far of the top to within this far of the bottom is taken
+/// to have wrapped around rather than to have been reset
+const WRAP_MARGIN: u64 = 1 << 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CounterChange {
+    /// counted up as usual
+    Counted,
+    /// went past u64::MAX and started again from zero
+    Wrapped,
+    /// went down, the counter started over and everything on it is new
+    Reset,
+    /// came back up past where it was before what looked like a reset, so the low reading was
+    /// taken while the counter was being zeroed or the interface recreated and isn't real
+    Recovered,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CounterDelta {
+    last: u64,
+    /// when the last reading looked like a reset, the reading before it and what we counted for it
+    suspect_reset: Option<(u64, u64)>,
+}
+
+impl CounterDelta {
+    /// Only bytes counted after this reading will be returned
+    pub fn starting_at(reading: u64) -> CounterDelta {
+        CounterDelta {
+            last: reading,
+            suspect_reset: None,
+        }
+    }
+
+    pub fn last(&self) -> u64 {
+        self.last
+    }
+
+    /// The bytes moved since the last reading and how the counter got to this one. A reset is
+    /// only certain once the next reading stays below the old value, if it instead comes back past
+    /// it what was counted for the low reading is taken back out. A counter that really was reset
+    /// and grows past its old value within a single reading is undercounted by at most that value,
+    /// which is small for any counter that could
+    pub fn update(&mut self, reading: u64) -> (u64, CounterChange) {
+        let last = self.last;
+        self.last = reading;
+        if let Some((before, counted)) = self.suspect_reset.take() {
+            if reading >= before {
+                let bytes = (reading - before).saturating_sub(counted);
+                return (bytes, CounterChange::Recovered);
+            }
+        }
+        if reading >= last {
+            (reading - last, CounterChange::Counted)
+        } else if last > u64::max_value() - WRAP_MARGIN && reading < WRAP_MARGIN {
+            (reading.wrapping_sub(last), CounterChange::Wrapped)
+        } else {
+            self.suspect_reset = Some((last, reading));
+            (reading, CounterChange::Reset)
+        }
+    }
+}
+
+/// The upload and download counters of a single tunnel or interface
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UsageDelta {
+    pub upload: CounterDelta,
+    pub download: CounterDelta,
+}
+
+impl UsageDelta {
+    pub fn starting_at(upload: u64, download: u64) -> UsageDelta {
+        UsageDelta {
+            upload: CounterDelta::starting_at(upload),
+            download: CounterDelta::starting_at(download),
+        }
+    }
+
+    /// The bytes uploaded and downloaded since the last reading, anything other than the
+    /// counters counting up is logged with `name` to say what they belong to
+    pub fn update(&mut self, name: &str, upload: u64, download: u64) -> (u64, u64) {
+        let (up, up_change) = self.upload.update(upload);
+        let (down, down_change) = self.download.update(download);
+        for (direction, change) in [("upload", up_change), ("download", down_change)].iter() {
+            match change {
+                CounterChange::Counted => {}
+                CounterChange::Wrapped => info!("The {} counter of {} wrapped", direction, name),
+                CounterChange::Reset => warn!("The {} counter of {} was reset", direction, name),
+                CounterChange::Recovered => warn!(
+                    "The {} counter of {} came back after a reset, it was misread",
+                    direction, name
+                ),
+            }
+        }
+        (up, down)
+    }
+}
+
+#[test]
+fn test_counter_delta() {
+    let mut counter = CounterDelta::default();
+    assert_eq!(counter.update(1000), (1000, CounterChange::Counted));
+    assert_eq!(counter.update(1500), (500, CounterChange::Counted));
+    assert_eq!(counter.update(1500), (0, CounterChange::Counted));
+
+    // the interface was recreated, what's on the new counter is all new
+    assert_eq!(counter.update(200), (200, CounterChange::Reset));
+    assert_eq!(counter.update(700), (500, CounterChange::Counted));
+    assert_eq!(counter.last(), 700);
+
+    // read while being zeroed, the counter was never really reset
+    let mut counter = CounterDelta::starting_at(1_000_000);
+    assert_eq!(counter.update(0), (0, CounterChange::Reset));
+    assert_eq!(counter.update(1_000_300), (300, CounterChange::Recovered));
+    assert_eq!(counter.update(1_000_400), (100, CounterChange::Counted));
+    // a partial reading is taken back out again
+    let mut counter = CounterDelta::starting_at(1_000_000);
+    assert_eq!(counter.update(50), (50, CounterChange::Reset));
+    assert_eq!(counter.update(1_000_300), (250, CounterChange::Recovered));
+
+    // wrapping around u64::MAX
+    let mut counter = CounterDelta::starting_at(u64::max_value() - 99);
+    assert_eq!(counter.update(400), (500, CounterChange::Wrapped));
+    assert_eq!(counter.update(900), (500, CounterChange::Counted));
+
+    let mut usage = UsageDelta::starting_at(100, 1000);
+    assert_eq!(usage.update("wg_exit", 150, 1200), (50, 200));
+    assert_eq!(usage.update("wg_exit", 10, 1300), (10, 100));
+}
@@ -2,27 +2,46 @@
 //! iptables and ipset counters on each per hop tunnel (the WireGuard tunnel between two devices). These counts
 //! are then stored and used to compute amounts for bills.
 
+use self::anomaly::{AnomalyDetector, TrafficAlert};
+use self::audit::{AuditDirection, AuditQuery, AuditSample, BillingAudit};
 use crate::rita_common::debt_keeper;
+use crate::rita_common::debt_keeper::reconcile::{ByteCounts, NeighborBytes};
+use crate::rita_common::debt_keeper::CountTraffic;
 use crate::rita_common::debt_keeper::DebtKeeper;
 use crate::rita_common::debt_keeper::Traffic;
+use crate::rita_common::schedule;
 use crate::rita_common::tunnel_manager::Neighbor;
+use crate::rita_common::tunnel_manager::{ThrottleNeighbor, TunnelManager};
+use crate::rita_common::usage_tracker::UpdateNeighborUsage;
 use crate::rita_common::usage_tracker::UpdateUsage;
 use crate::rita_common::usage_tracker::UsageTracker;
 use crate::rita_common::usage_tracker::UsageType;
+use crate::rita_common::utils::now_secs;
+use crate::rita_common::watchdog::or_alarm;
 use crate::KI;
 use crate::SETTING;
 use ::actix::{Actor, Context, Handler, Message, Supervised, SystemService};
 use althea_kernel_interface::open_tunnel::is_link_local;
 use althea_kernel_interface::FilterTarget;
+use althea_types::FeatureFlags;
 use althea_types::Identity;
+use althea_types::Wei;
 use babel_monitor::Route;
 use failure::Error;
 use ipnetwork::IpNetwork;
 use settings::RitaCommonSettings;
 use std::collections::HashMap;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
 
-pub struct TrafficWatcher;
+pub mod anomaly;
+pub mod audit;
+pub mod counter_delta;
+
+pub struct TrafficWatcher {
+    anomaly: AnomalyDetector,
+    audit: BillingAudit,
+}
 
 impl Actor for TrafficWatcher {
     type Context = Context<Self>;
@@ -43,7 +62,10 @@ impl SystemService for TrafficWatcher {
 
 impl Default for TrafficWatcher {
     fn default() -> TrafficWatcher {
-        TrafficWatcher {}
+        TrafficWatcher {
+            anomaly: AnomalyDetector::default(),
+            audit: BillingAudit::default(),
+        }
     }
 }
 
@@ -67,7 +89,47 @@ impl Handler<Watch> for TrafficWatcher {
     type Result = Result<(), Error>;
 
     fn handle(&mut self, msg: Watch, _: &mut Context<Self>) -> Self::Result {
-        watch(msg.routes, &msg.neighbors)
+        let audit_settings = SETTING.get_payment().billing_audit.clone();
+        let audit = if audit_settings.enabled {
+            self.audit.expire(now_secs(), audit_settings.hours);
+            Some(&mut self.audit)
+        } else {
+            self.audit.clear();
+            None
+        };
+        watch(msg.routes, &msg.neighbors, &mut self.anomaly, audit)
+    }
+}
+
+pub struct GetTrafficAlerts;
+
+impl Message for GetTrafficAlerts {
+    type Result = Result<Vec<TrafficAlert>, Error>;
+}
+
+impl Handler<GetTrafficAlerts> for TrafficWatcher {
+    type Result = Result<Vec<TrafficAlert>, Error>;
+
+    fn handle(&mut self, _msg: GetTrafficAlerts, _: &mut Context<Self>) -> Self::Result {
+        Ok(self.anomaly.alerts())
+    }
+}
+
+/// The billing audit samples matching the query, oldest first
+pub struct GetBillingAudit(pub AuditQuery);
+
+impl Message for GetBillingAudit {
+    type Result = Result<Vec<AuditSample>, Error>;
+}
+
+impl Handler<GetBillingAudit> for TrafficWatcher {
+    type Result = Result<Vec<AuditSample>, Error>;
+
+    fn handle(&mut self, msg: GetBillingAudit, _: &mut Context<Self>) -> Self::Result {
+        if !SETTING.get_payment().billing_audit.enabled {
+            bail!("The billing audit is not enabled");
+        }
+        Ok(self.audit.query(&msg.0))
     }
 }
 
@@ -86,12 +148,12 @@ pub fn prepare_helper_maps(
     (identities, if_to_id)
 }
 
-pub fn get_babel_info(routes: Vec<Route>) -> Result<(HashMap<IpAddr, i128>, u32), Error> {
+pub fn get_babel_info(routes: Vec<Route>) -> Result<(HashMap<IpAddr, u64>, u32), Error> {
     trace!("Got {} routes: {:?}", routes.len(), routes);
     let mut destinations = HashMap::new();
     // we assume this matches what is actually set it babel becuase we
     // panic on startup if it does not get set correctly
-    let local_fee = SETTING.get_payment().local_fee;
+    let local_fee = schedule::local_fee();
 
     let max_fee = SETTING.get_payment().max_fee;
     for route in &routes {
@@ -109,7 +171,7 @@ pub fn get_babel_info(routes: Vec<Route>) -> Result<(HashMap<IpAddr, i128>, u32)
                     "Inserting {} into the destinations map",
                     IpAddr::V6(ip.ip())
                 );
-                destinations.insert(IpAddr::V6(ip.ip()), i128::from(price + local_fee));
+                destinations.insert(IpAddr::V6(ip.ip()), u64::from(price) + u64::from(local_fee));
             }
         }
     }
@@ -119,7 +181,7 @@ pub fn get_babel_info(routes: Vec<Route>) -> Result<(HashMap<IpAddr, i128>, u32)
             Some(ip) => ip,
             None => bail!("No mesh IP configured yet"),
         },
-        i128::from(0),
+        0,
     );
 
     trace!("{} destinations setup", destinations.len());
@@ -235,10 +297,12 @@ pub fn get_output_counters() -> Result<HashMap<(IpAddr, String), u64>, Error> {
     Ok(total_output_counters)
 }
 
-/// Takes and sumns the input and output counters for logging
+/// Takes and sumns the input and output counters for logging, relayed input is also recorded
+/// by the neighbor it came from
 fn update_usage(
     input: &HashMap<(IpAddr, String), u64>,
     output: &HashMap<(IpAddr, String), u64>,
+    if_to_id: &HashMap<String, Identity>,
     our_fee: u32,
 ) {
     let mut total_in = 0;
@@ -261,6 +325,92 @@ fn update_usage(
         down: total_in,
         price: our_fee,
     });
+
+    let mut by_neighbor: HashMap<IpAddr, u64> = HashMap::new();
+    for ((_ip, interface), bytes) in input.iter() {
+        if let Some(id) = if_to_id.get(interface) {
+            *by_neighbor.entry(id.mesh_ip).or_insert(0) += bytes;
+        }
+    }
+    UsageTracker::from_registry().do_send(UpdateNeighborUsage {
+        down: by_neighbor,
+        price: our_fee,
+    });
+}
+
+/// Totals this round's traffic in both directions for each neighbor and checks it against
+/// their usual amount
+fn check_anomalies(
+    input: &HashMap<(IpAddr, String), u64>,
+    output: &HashMap<(IpAddr, String), u64>,
+    if_to_id: &HashMap<String, Identity>,
+    detector: &mut AnomalyDetector,
+) {
+    let mut usage: HashMap<Identity, u64> = HashMap::new();
+    for id in if_to_id.values() {
+        usage.insert(*id, 0);
+    }
+    for ((_ip, interface), bytes) in input.iter().chain(output.iter()) {
+        if let Some(id) = if_to_id.get(interface) {
+            *usage.entry(*id).or_insert(0) += bytes;
+        }
+    }
+
+    let settings = SETTING.get_network().traffic_anomaly.clone();
+    let actions = detector.observe(&usage, &settings, Instant::now());
+    for id in actions.throttle {
+        TunnelManager::from_registry().do_send(ThrottleNeighbor {
+            id,
+            limit: settings.throttle_mbps,
+        });
+    }
+    for id in actions.unthrottle {
+        TunnelManager::from_registry().do_send(ThrottleNeighbor { id, limit: None });
+    }
+}
+
+/// The bytes exchanged with each neighbor, for checking our counters against theirs
+fn neighbor_bytes(
+    input: &HashMap<(IpAddr, String), u64>,
+    output: &HashMap<(IpAddr, String), u64>,
+    neighbors: &[Neighbor],
+) -> Vec<NeighborBytes> {
+    let mut by_iface: HashMap<&str, ByteCounts> = HashMap::new();
+    for ((_, iface), bytes) in input.iter() {
+        by_iface.entry(iface.as_str()).or_default().received += bytes;
+    }
+    for ((_, iface), bytes) in output.iter() {
+        by_iface.entry(iface.as_str()).or_default().sent += bytes;
+    }
+
+    let hello_port = SETTING.get_network().rita_hello_port;
+    let journal_enabled = SETTING.get_payment().debt_journal.enabled;
+    neighbors
+        .iter()
+        .map(|neigh| NeighborBytes {
+            neighbor: neigh.identity.global,
+            counts: by_iface
+                .get(neigh.iface_name.as_str())
+                .cloned()
+                .unwrap_or_default(),
+            report_to: if neigh
+                .identity
+                .features
+                .contains(FeatureFlags::COUNTER_RECONCILIATION)
+            {
+                Some(SocketAddr::new(neigh.identity.global.mesh_ip, hello_port))
+            } else {
+                None
+            },
+            iou_to: if journal_enabled
+                && neigh.identity.features.contains(FeatureFlags::DEBT_JOURNAL)
+            {
+                Some(SocketAddr::new(neigh.identity.global.mesh_ip, hello_port))
+            } else {
+                None
+            },
+        })
+        .collect()
 }
 
 /// This traffic watcher watches how much traffic each neighbor sends to each destination
@@ -270,14 +420,38 @@ fn update_usage(
 ///
 /// This first time this is run, it will create the rules and then immediately read and zero them.
 /// (should return 0)
-pub fn watch(routes: Vec<Route>, neighbors: &[Neighbor]) -> Result<(), Error> {
+///
+/// If `audit` is given what each neighbor is billed for each destination is recorded in it.
+pub fn watch(
+    routes: Vec<Route>,
+    neighbors: &[Neighbor],
+    detector: &mut AnomalyDetector,
+    mut audit: Option<&mut BillingAudit>,
+) -> Result<(), Error> {
+    let now = now_secs();
     let (identities, if_to_id) = prepare_helper_maps(neighbors);
 
     let (destinations, local_fee) = get_babel_info(routes)?;
 
     let total_input_counters = get_input_counters()?;
     let total_output_counters = get_output_counters()?;
-    update_usage(&total_input_counters, &total_output_counters, local_fee);
+    update_usage(
+        &total_input_counters,
+        &total_output_counters,
+        &if_to_id,
+        local_fee,
+    );
+    check_anomalies(
+        &total_input_counters,
+        &total_output_counters,
+        &if_to_id,
+        detector,
+    );
+    DebtKeeper::from_registry().do_send(CountTraffic(neighbor_bytes(
+        &total_input_counters,
+        &total_output_counters,
+        neighbors,
+    )));
 
     // Flow counters should debit your neighbor which you received the packet from
     // Destination counters should credit your neighbor which you sent the packet to
@@ -286,7 +460,7 @@ pub fn watch(routes: Vec<Route>, neighbors: &[Neighbor]) -> Result<(), Error> {
 
     // Setup the debts table
     for (_, ident) in identities.clone() {
-        debts.insert(ident, 0i128);
+        debts.insert(ident, Wei::zero());
     }
 
     // We take the destination ip and input interface and then look up what local neighbor
@@ -296,9 +470,18 @@ pub fn watch(routes: Vec<Route>, neighbors: &[Neighbor]) -> Result<(), Error> {
         let state = (destinations.get(&ip), if_to_id.get(&interface));
         match state {
             (Some(dest), Some(id_from_if)) => {
+                if let Some(audit) = audit.as_mut() {
+                    audit.record(now, *id_from_if, ip, AuditDirection::Received, *dest, bytes);
+                }
                 match debts.get_mut(&id_from_if) {
                     Some(debt) => {
-                        *debt -= dest * i128::from(bytes);
+                        let value = Wei::from_price(*dest, bytes);
+                        *debt = or_alarm(
+                            debt.checked_sub(&value),
+                            debt.saturating_sub(&value),
+                            "TrafficWatcher",
+                            || format!("debt of {} minus {}", id_from_if.mesh_ip, value),
+                        );
                     }
                     // debts is generated from identities, this should be impossible
                     None => warn!("No debts entry for input entry id {:?}", id_from_if),
@@ -325,13 +508,26 @@ pub fn watch(routes: Vec<Route>, neighbors: &[Neighbor]) -> Result<(), Error> {
     for ((ip, interface), bytes) in total_output_counters {
         let state = (destinations.get(&ip), if_to_id.get(&interface));
         match state {
-            (Some(dest), Some(id_from_if)) => match debts.get_mut(&id_from_if) {
-                Some(debt) => {
-                    *debt += (dest - i128::from(local_fee)) * i128::from(bytes);
+            (Some(dest), Some(id_from_if)) => {
+                // what our neighbor charges doesn't include our own fee
+                let price = dest.saturating_sub(u64::from(local_fee));
+                if let Some(audit) = audit.as_mut() {
+                    audit.record(now, *id_from_if, ip, AuditDirection::Sent, price, bytes);
                 }
-                // debts is generated from identities, this should be impossible
-                None => warn!("No debts entry for input entry id {:?}", id_from_if),
-            },
+                match debts.get_mut(&id_from_if) {
+                    Some(debt) => {
+                        let value = Wei::from_price(price, bytes);
+                        *debt = or_alarm(
+                            debt.checked_add(&value),
+                            debt.saturating_add(&value),
+                            "TrafficWatcher",
+                            || format!("debt of {} plus {}", id_from_if.mesh_ip, value),
+                        );
+                    }
+                    // debts is generated from identities, this should be impossible
+                    None => warn!("No debts entry for input entry id {:?}", id_from_if),
+                }
+            }
             // this can be caused by a peer that has not yet formed a babel route
             // we use _ because ip_to_if is created from identites, if one fails the other must
             (None, Some(id_from_if)) => warn!(
@@ -347,13 +543,13 @@ pub fn watch(routes: Vec<Route>, neighbors: &[Neighbor]) -> Result<(), Error> {
 
     trace!("Collated total Intermediary debts: {:?}", debts);
     info!("Computed Intermediary debts for {:?} peers", debts.len());
-    let mut total_income = 0i128;
+    let mut total_income = Wei::zero();
     for entry in debts.iter() {
         let income = entry.1;
-        total_income += income;
+        total_income = total_income.saturating_add(income);
     }
     info!(
-        "Total intermediary debts of {:?} Wei this round",
+        "Total intermediary debts of {} Wei this round",
         total_income
     );
 
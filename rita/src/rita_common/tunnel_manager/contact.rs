@@ -0,0 +1,94 @@
+//! Contacts neighbors for TunnelManager. Before saying hello to a neighbor we make sure there's a
+//! route to it, which shells out and can take a good while on a loaded router, and manual peers
+//! may need a DNS lookup first. Done one after another in TunnelManager's own context one slow
+//! peer held up hellos to everyone else, so the contacts are handed to a small pool of sync
+//! actors where blocking is fine and the results are gathered as futures with a deadline. The
+//! deadline only stops the waiting, the contact carries on and gives back its port itself if it
+//! fails.
+
+use super::{HelloHandler, PortCallback, TunnelManager};
+use crate::rita_common::hello_handler::{our_features, Hello};
+use crate::rita_common::peer_listener::Peer;
+use crate::rita_common::schedule;
+use crate::KI;
+use crate::SETTING;
+use actix::{Actor, Handler, Message, SyncContext, SystemService};
+use althea_types::LocalIdentity;
+use failure::Error;
+use settings::RitaCommonSettings;
+use std::sync::Mutex;
+
+/// How many neighbors can be contacted at once
+pub const NEIGHBOR_CONTACT_THREADS: usize = 4;
+
+lazy_static! {
+    /// Held while a contact updates the default route in the settings, see contact_neighbor
+    static ref ROUTE_UPDATE: Mutex<()> = Mutex::new(());
+}
+
+pub struct NeighborContacter;
+
+impl Actor for NeighborContacter {
+    type Context = SyncContext<Self>;
+}
+
+/// Says hello to a neighbor, the port is speculative and only assigned if they respond
+pub struct ContactNeighbor {
+    pub peer: Peer,
+    pub our_port: u16,
+    /// if the port is ours to give back when the contact fails, the addresses of a manual
+    /// peer's hostname share one
+    pub owns_port: bool,
+}
+
+impl Message for ContactNeighbor {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<ContactNeighbor> for NeighborContacter {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: ContactNeighbor, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        let res = contact_neighbor(&msg.peer, msg.our_port);
+        // whoever sent us this may have stopped waiting by now, so it's given back from here
+        if res.is_err() && msg.owns_port {
+            TunnelManager::from_registry().do_send(PortCallback(msg.our_port));
+        }
+        res
+    }
+}
+
+fn contact_neighbor(peer: &Peer, our_port: u16) -> Result<(), Error> {
+    // the other contacts need the settings too, so they aren't locked while we shell out,
+    // contacts take turns instead and the route is only written back if nobody holding the
+    // settings lock has updated it in the meantime, theirs is just as new as ours
+    let res = {
+        let _turn = ROUTE_UPDATE.lock().unwrap_or_else(|e| e.into_inner());
+        let before = SETTING.get_network().default_route.clone();
+        let mut default_route = before.clone();
+        let res = KI.manual_peers_route(&peer.contact_socket.ip(), &mut default_route);
+        let mut network = SETTING.get_network_mut();
+        if network.default_route == before {
+            network.default_route = default_route;
+        }
+        res
+    };
+    res?;
+
+    HelloHandler::from_registry().do_send(Hello {
+        my_id: LocalIdentity {
+            global: SETTING
+                .get_identity()
+                .ok_or_else(|| format_err!("Identity has no mesh IP ready yet"))?,
+            wg_port: our_port,
+            have_tunnel: None,
+            local_fee: Some(schedule::local_fee()),
+            // filled in by the HelloHandler once it has a challenge
+            auth: None,
+            features: our_features(),
+        },
+        to: peer.clone(),
+    });
+
+    Ok(())
+}
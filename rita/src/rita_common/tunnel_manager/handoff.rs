@@ -0,0 +1,284 @@
+//! Handoffs let light clients roam between gateways. Every light client hello response carries a
+//! handoff signed by the gateway saying which address the phone had there. When the phone moves
+//! it presents the handoff to the next gateway along with its own signature naming that gateway,
+//! the next gateway gives it the same address if it's free and passes both back to the old
+//! gateway over the mesh. The old gateway only acts on its own signature over the handoff and the
+//! phone's over the move, and tears down the tunnels to the phone that are older than the handoff
+//! right away rather than waiting for them to time out, a phone that has since come back keeps its
+//! new tunnel. The phone is billed under the same identity on both gateways throughout.
+
+use super::{Tunnel, TunnelManager};
+use crate::rita_common::utils::now_secs;
+use crate::SETTING;
+use actix::{Context, Handler, Message};
+use althea_types::{Identity, LightClientHandoff, LightClientRoam, SignedLightClientHandoff};
+use clarity::PrivateKey;
+use failure::Error;
+use settings::RitaCommonSettings;
+use sha3::{Digest, Keccak256};
+use std::collections::HashMap;
+
+const HANDOFF_LABEL: &[u8] = b"althea light client handoff";
+const ROAM_LABEL: &[u8] = b"althea light client roam";
+/// How long after it's issued a handoff can be presented, phones get a fresh one with every hello
+pub const HANDOFF_TTL_SECS: u64 = 30 * 60;
+/// How far ahead of our clock a handoff can be dated, for gateways with slightly different clocks
+const HANDOFF_CLOCK_SKEW_SECS: u64 = 5 * 60;
+
+fn handoff_digest(handoff: &LightClientHandoff) -> Vec<u8> {
+    let mut hasher = Keccak256::new();
+    hasher.input(HANDOFF_LABEL);
+    hasher.input(
+        format!(
+            "{}:{}:{}:{}:{}:{}:{}",
+            handoff.gateway.mesh_ip,
+            handoff.gateway.eth_address,
+            handoff.client.mesh_ip,
+            handoff.client.eth_address,
+            handoff.client.wg_public_key,
+            handoff.tunnel_address,
+            handoff.issued
+        )
+        .as_bytes(),
+    );
+    hasher.result().to_vec()
+}
+
+pub fn sign_handoff(
+    handoff: LightClientHandoff,
+    key: &PrivateKey,
+) -> Result<SignedLightClientHandoff, Error> {
+    if handoff.gateway.eth_address != key.to_public_key()? {
+        bail!("Can't sign a handoff for another gateway");
+    }
+    let signature = key.sign_hash(&handoff_digest(&handoff));
+    Ok(SignedLightClientHandoff { handoff, signature })
+}
+
+/// Checks that the handoff was signed by the gateway it names and is still current at `now`
+pub fn verify_handoff(signed: &SignedLightClientHandoff, now: u64) -> Result<(), Error> {
+    let handoff = &signed.handoff;
+    let signer = match signed.signature.recover(&handoff_digest(handoff)) {
+        Ok(val) => val,
+        Err(e) => bail!("Malformed handoff signature {:?}", e),
+    };
+    if signer != handoff.gateway.eth_address {
+        bail!(
+            "Handoff was signed by {} not {}",
+            signer,
+            handoff.gateway.eth_address
+        );
+    }
+    if handoff.issued > now + HANDOFF_CLOCK_SKEW_SECS {
+        bail!("Handoff is dated in the future");
+    }
+    if now.saturating_sub(handoff.issued) > HANDOFF_TTL_SECS {
+        bail!("Handoff has expired");
+    }
+    Ok(())
+}
+
+fn roam_digest(gateway: &Identity, issued: u64) -> Vec<u8> {
+    let mut hasher = Keccak256::new();
+    hasher.input(ROAM_LABEL);
+    hasher.input(format!("{}:{}:{}", gateway.mesh_ip, gateway.eth_address, issued).as_bytes());
+    hasher.result().to_vec()
+}
+
+/// What a light client signs to move to `gateway`, phones do the same with their own key
+pub fn sign_roam(gateway: &Identity, issued: u64, key: &PrivateKey) -> clarity::Signature {
+    key.sign_hash(&roam_digest(gateway, issued))
+}
+
+/// Checks the handoff in a roam, and that the light client it was issued to signed the move to the
+/// gateway named recently enough
+pub fn verify_roam(roam: &LightClientRoam, now: u64) -> Result<(), Error> {
+    verify_handoff(&roam.handoff, now)?;
+    let client = &roam.handoff.handoff.client;
+    if *client != roam.id.global {
+        bail!("Handoff was issued to {} not {}", client, roam.id.global);
+    }
+    let signer = match roam
+        .signature
+        .recover(&roam_digest(&roam.gateway, roam.issued))
+    {
+        Ok(val) => val,
+        Err(e) => bail!("Malformed roam signature {:?}", e),
+    };
+    if signer != client.eth_address {
+        bail!("Roam was signed by {} not {}", signer, client.eth_address);
+    }
+    if roam.issued > now + HANDOFF_CLOCK_SKEW_SECS {
+        bail!("Roam is dated in the future");
+    }
+    if now.saturating_sub(roam.issued) > HANDOFF_CLOCK_SKEW_SECS {
+        bail!("Roam is too old");
+    }
+    Ok(())
+}
+
+/// Takes the light client tunnels to `client` made before `before` out of the tunnel map
+fn take_light_client_tunnels(
+    tunnels: &mut HashMap<Identity, Vec<Tunnel>>,
+    client: &Identity,
+    before: u64,
+) -> Vec<Tunnel> {
+    let mut taken = Vec::new();
+    if let Some(id_tunnels) = tunnels.get_mut(client) {
+        let (light, rest): (Vec<Tunnel>, Vec<Tunnel>) = id_tunnels
+            .drain(..)
+            .partition(|t| t.light_client_details.is_some() && t.created < before);
+        *id_tunnels = rest;
+        taken = light;
+    }
+    if tunnels.get(client).map_or(false, |t| t.is_empty()) {
+        tunnels.remove(client);
+    }
+    taken
+}
+
+/// A handoff we issued that another gateway has accepted, the light client has moved on
+pub struct ReleaseLightClient(pub LightClientRoam);
+
+impl Message for ReleaseLightClient {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<ReleaseLightClient> for TunnelManager {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: ReleaseLightClient, _: &mut Context<Self>) -> Self::Result {
+        let roam = msg.0;
+        let handoff = &roam.handoff.handoff;
+        let our_id = SETTING
+            .get_identity()
+            .ok_or_else(|| format_err!("Identity has no mesh IP ready yet"))?;
+        if handoff.gateway != our_id {
+            bail!("Handoff was issued by {} not us", handoff.gateway);
+        }
+        if roam.gateway == our_id {
+            bail!("Light client is roaming to us, not away");
+        }
+        verify_roam(&roam, now_secs())?;
+
+        // the address goes back to the pool once the light client manager sees the tunnel is
+        // gone, debts stay with debt keeper to be paid as usual
+        let released =
+            take_light_client_tunnels(&mut self.tunnels, &handoff.client, handoff.issued);
+        info!(
+            "Light client {} roamed to {}, removing {} tunnels",
+            handoff.client,
+            roam.gateway,
+            released.len()
+        );
+        self.reap(released);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_handoff_signature() {
+    use crate::rita_common::utils::test_identity;
+
+    let key: PrivateKey = "fe1bfa04d82b1e5a73f3a2d1a6d4b0d0f0c1ef1d1ba3c5d3c0b1e0fa5b1c2d3e"
+        .parse()
+        .unwrap();
+    let mut gateway = test_identity("fd00::1");
+    gateway.eth_address = key.to_public_key().unwrap();
+    let client = test_identity("fd00::2");
+    let handoff = LightClientHandoff {
+        gateway,
+        client,
+        tunnel_address: "192.168.20.2".parse().unwrap(),
+        issued: 1000,
+    };
+    let signed = sign_handoff(handoff, &key).unwrap();
+    assert!(verify_handoff(&signed, 1000).is_ok());
+    assert!(verify_handoff(&signed, 1000 + HANDOFF_TTL_SECS).is_ok());
+    assert!(verify_handoff(&signed, 1001 + HANDOFF_TTL_SECS).is_err());
+    assert!(verify_handoff(&signed, 1000 - HANDOFF_CLOCK_SKEW_SECS).is_ok());
+    assert!(verify_handoff(&signed, 999 - HANDOFF_CLOCK_SKEW_SECS).is_err());
+
+    // the address or the client can't be swapped out
+    let mut moved = signed.clone();
+    moved.handoff.tunnel_address = "192.168.20.3".parse().unwrap();
+    assert!(verify_handoff(&moved, 1000).is_err());
+    let mut stolen = signed.clone();
+    stolen.handoff.client.eth_address = key.to_public_key().unwrap();
+    assert!(verify_handoff(&stolen, 1000).is_err());
+
+    // nor can another gateway sign for us
+    let mut claimed = handoff;
+    claimed.gateway.eth_address = "0x0000000000000000000000000000000000000001"
+        .parse()
+        .unwrap();
+    assert!(sign_handoff(claimed, &key).is_err());
+
+    // only the light client can move itself
+    let client_key: PrivateKey = "0b1e0fa5b1c2d3efe1bfa04d82b1e5a73f3a2d1a6d4b0d0f0c1ef1d1ba3c5d3c"
+        .parse()
+        .unwrap();
+    let mut client = client;
+    client.eth_address = client_key.to_public_key().unwrap();
+    let signed = sign_handoff(LightClientHandoff { client, ..handoff }, &key).unwrap();
+    let mut next_gateway = test_identity("fd00::3");
+    next_gateway.eth_address = "0x0000000000000000000000000000000000000003"
+        .parse()
+        .unwrap();
+    let roam = LightClientRoam {
+        id: althea_types::LocalIdentity {
+            wg_port: 60000,
+            have_tunnel: None,
+            global: client,
+            local_fee: None,
+            auth: None,
+            features: Default::default(),
+        },
+        handoff: signed,
+        gateway: next_gateway,
+        issued: 1100,
+        signature: sign_roam(&next_gateway, 1100, &client_key),
+    };
+    assert!(verify_roam(&roam, 1100).is_ok());
+    assert!(verify_roam(&roam, 1101 + HANDOFF_CLOCK_SKEW_SECS).is_err());
+    let mut redirected = roam.clone();
+    redirected.gateway.mesh_ip = "fd00::4".parse().unwrap();
+    assert!(verify_roam(&redirected, 1100).is_err());
+    let mut forged = roam;
+    forged.signature = sign_roam(&next_gateway, 1100, &key);
+    assert!(verify_roam(&forged, 1100).is_err());
+}
+
+#[test]
+fn test_take_light_client_tunnels() {
+    use crate::rita_common::utils::test_identity;
+
+    let client = test_identity("fd00::2");
+    let id = althea_types::LocalIdentity {
+        wg_port: 60000,
+        have_tunnel: None,
+        global: client,
+        local_fee: None,
+        auth: None,
+        features: Default::default(),
+    };
+    let tunnel = |created: u64| {
+        let mut tunnel = Tunnel::new(
+            "fe80::2".parse().unwrap(),
+            format!("wg{}", created),
+            60000,
+            1,
+            id,
+            Some("192.168.20.2".parse().unwrap()),
+        );
+        tunnel.created = created;
+        tunnel
+    };
+    let mut tunnels = HashMap::new();
+    tunnels.insert(client, vec![tunnel(900), tunnel(1100)]);
+    // the phone came back after the handoff was issued, that tunnel stays
+    let taken = take_light_client_tunnels(&mut tunnels, &client, 1000);
+    assert_eq!(taken.len(), 1);
+    assert_eq!(taken[0].created, 900);
+    assert_eq!(tunnels[&client].len(), 1);
+}
@@ -1,4 +1,6 @@
 use crate::rita_common::peer_listener::Peer;
+use crate::rita_common::tunnel_manager::has_tunnel;
+use crate::rita_common::tunnel_manager::is_paused;
 use crate::rita_common::tunnel_manager::Tunnel;
 use crate::rita_common::tunnel_manager::TunnelManager;
 use actix::{Context, Handler, Message};
@@ -42,6 +44,21 @@ impl Handler<IdentityCallback> for TunnelManager {
     type Result = Option<(Tunnel, bool)>;
 
     fn handle(&mut self, msg: IdentityCallback, _: &mut Context<Self>) -> Self::Result {
+        if is_paused(&msg.local_identity.global.wg_public_key) {
+            trace!("Not opening a tunnel to paused neighbor {:?}", msg.peer);
+            return None;
+        }
+
+        // a neighbor asking for a new tunnel counts against this tick's setup budget, if it's
+        // spent they are turned away and will try again with their next hello
+        if msg.our_port.is_none()
+            && !has_tunnel(&self.tunnels, &msg.peer)
+            && !self.setup_queue.reserve()
+        {
+            trace!("Deferring tunnel setup for {:?}", msg.peer);
+            return None;
+        }
+
         let our_port = match msg.our_port {
             Some(port) => port,
             _ => match self.get_port(0) {
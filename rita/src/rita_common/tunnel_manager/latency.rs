@@ -0,0 +1,143 @@
+//! Latency aware tunnel costs. Babel can penalize a link for its rtt on its own, but it goes by
+//! the latest few hellos, so a single spike moves routes around and a link that's slow for good
+//! only costs more as long as the samples agree. With `smooth_rtt` on, babel's own penalty is
+//! turned off and the rtt babel measures for each tunnel is instead smoothed here over a couple of
+//! minutes and added to the tunnel's rxcost hint along the same curve, from nothing at `rtt_min`
+//! to `max_rtt_penalty` at `rtt_max`. High latency links are then deprioritized even when they
+//! lose nothing, without routes flapping on every blip.
+
+use super::reputation::DEFAULT_RXCOST;
+use super::TunnelManager;
+use crate::SETTING;
+use actix::{Context, Handler, Message};
+use settings::network::BabelSettings;
+use settings::RitaCommonSettings;
+use std::collections::{HashMap, HashSet};
+
+/// How much each new sample moves the smoothed rtt, at one sample per fast loop this settles in
+/// about two minutes
+const RTT_SMOOTHING: f32 = 0.05;
+/// Penalties are rounded to this so that babel isn't told about every small change
+const RTT_PENALTY_STEP: u16 = 16;
+
+/// An exponentially weighted moving average of a tunnel's rtt in milliseconds
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SmoothedRtt {
+    rtt: Option<f32>,
+}
+
+impl SmoothedRtt {
+    pub fn add_sample(&mut self, sample: f32) {
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => rtt + RTT_SMOOTHING * (sample - rtt),
+            None => sample,
+        });
+    }
+
+    pub fn get(&self) -> Option<f32> {
+        self.rtt
+    }
+}
+
+/// What babel would add to a link's cost for this rtt, rounded down to a step
+pub fn rtt_penalty(rtt: f32, babel: &BabelSettings) -> u16 {
+    let (min, max) = (f32::from(babel.rtt_min), f32::from(babel.rtt_max));
+    let penalty = if rtt <= min {
+        0.0
+    } else if rtt >= max || max <= min {
+        f32::from(babel.max_rtt_penalty)
+    } else {
+        f32::from(babel.max_rtt_penalty) * (rtt - min) / (max - min)
+    };
+    let penalty = penalty as u16;
+    penalty - penalty % RTT_PENALTY_STEP
+}
+
+/// Raises a tunnel's rxcost hint by its rtt penalty, tunnels without a hint are raised from
+/// babel's default
+pub fn add_rtt_penalty(rxcost: Option<u16>, penalty: u16) -> Option<u16> {
+    if penalty == 0 {
+        return rxcost;
+    }
+    Some(rxcost.unwrap_or(DEFAULT_RXCOST).saturating_add(penalty))
+}
+
+/// The rtt babel has measured to each neighbor, by tunnel interface, sent by NetworkMonitor
+/// every fast loop
+pub struct RttSamples {
+    pub samples: HashMap<String, f32>,
+}
+
+impl Message for RttSamples {
+    type Result = ();
+}
+
+impl Handler<RttSamples> for TunnelManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: RttSamples, _: &mut Context<Self>) -> Self::Result {
+        let babel = SETTING.get_network().babel;
+        let mut changed = Vec::new();
+        let mut ifaces = HashSet::new();
+        for (id, tunnels) in self.tunnels.iter_mut() {
+            for tunnel in tunnels.iter_mut() {
+                if tunnel.light_client_details.is_some() {
+                    continue;
+                }
+                ifaces.insert(tunnel.iface_name.clone());
+                let smoothed = self.latency.entry(tunnel.iface_name.clone()).or_default();
+                // babel reports 0 for neighbors that don't send timestamps
+                match msg.samples.get(&tunnel.iface_name) {
+                    Some(rtt) if *rtt > 0.0 => smoothed.add_sample(*rtt),
+                    _ => {}
+                }
+                let penalty = match (babel.smooth_rtt, smoothed.get()) {
+                    (true, Some(rtt)) => rtt_penalty(rtt, &babel),
+                    _ => 0,
+                };
+                if penalty != tunnel.rtt_penalty {
+                    trace!(
+                        "rtt penalty of {} is now {}, smoothed rtt {:?}",
+                        tunnel.iface_name,
+                        penalty,
+                        smoothed.get()
+                    );
+                    tunnel.rtt_penalty = penalty;
+                    changed.push(*id);
+                }
+            }
+        }
+        self.latency.retain(|iface, _| ifaces.contains(iface));
+        changed.dedup();
+        for id in changed {
+            self.update_rxcost_hints(&id);
+        }
+    }
+}
+
+#[test]
+fn test_rtt_penalty() {
+    let babel = BabelSettings::default();
+    assert_eq!(rtt_penalty(5.0, &babel), 0);
+    assert_eq!(rtt_penalty(10.0, &babel), 0);
+    // halfway between rtt_min and rtt_max is half the penalty, rounded down to a step
+    assert_eq!(rtt_penalty(65.0, &babel), 240);
+    assert_eq!(rtt_penalty(120.0, &babel), 500 - 500 % RTT_PENALTY_STEP);
+    assert_eq!(rtt_penalty(900.0, &babel), 496);
+
+    assert_eq!(add_rtt_penalty(None, 0), None);
+    assert_eq!(add_rtt_penalty(None, 240), Some(DEFAULT_RXCOST + 240));
+    assert_eq!(add_rtt_penalty(Some(192), 240), Some(432));
+
+    let mut smoothed = SmoothedRtt::default();
+    assert_eq!(smoothed.get(), None);
+    smoothed.add_sample(20.0);
+    assert_eq!(smoothed.get(), Some(20.0));
+    // one spike barely moves it
+    smoothed.add_sample(420.0);
+    assert_eq!(smoothed.get(), Some(40.0));
+    for _ in 0..200 {
+        smoothed.add_sample(420.0);
+    }
+    assert!(smoothed.get().unwrap() > 419.0);
+}
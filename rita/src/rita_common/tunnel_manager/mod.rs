@@ -4,35 +4,62 @@
 //! up tunnels if they respond, likewise if someone calls us their hello goes through network_endpoints
 //! then into TunnelManager to open a tunnel for them.
 
+pub mod contact;
+pub mod handoff;
 pub mod id_callback;
-
+pub mod latency;
+pub mod mtu;
+pub mod reaper;
+pub mod reconcile;
+pub mod reputation;
+pub mod roaming;
+pub mod setup_queue;
+
+use self::contact::{ContactNeighbor, NeighborContacter, NEIGHBOR_CONTACT_THREADS};
+use self::latency::{add_rtt_penalty, SmoothedRtt};
+use self::mtu::{MtuProber, ProbeMtu};
+use self::reaper::{DeleteInterfaces, InterfaceReaper};
+use self::reputation::{penalize, Reputation, ReputationSample};
+use self::setup_queue::SetupQueue;
 use crate::rita_common;
-use crate::rita_common::hello_handler::Hello;
+use crate::rita_common::hello_handler::our_features;
+use crate::rita_common::live_updates::{has_subscribers, LiveUpdates, Publish};
 use crate::rita_common::peer_listener::Peer;
+use crate::rita_common::schedule;
+use crate::rita_common::utils::now_secs;
 use crate::KI;
 use crate::SETTING;
 #[cfg(test)]
 use actix::actors::mocker::Mocker;
 use actix::actors::resolver;
-use actix::{Actor, Arbiter, Context, Handler, Message, Supervised, SystemService};
+use actix::{
+    Actor, Addr, Arbiter, AsyncContext, Context, Handler, Message, ResponseFuture, Supervised,
+    SyncArbiter, SystemService,
+};
+use althea_types::FeatureFlags;
 use althea_types::Identity;
 use althea_types::LocalIdentity;
+use althea_types::WgKey;
 use babel_monitor::monitor;
 use babel_monitor::open_babel_stream;
 use babel_monitor::start_connection;
 use babel_monitor::unmonitor;
+use babel_monitor::InterfaceParams;
 use failure::Error;
+use futures01::future::{self, join_all};
 use futures01::Future;
 use rand::thread_rng;
 use rand::Rng;
+use settings::network::{BabelSettings, NetworkSettings};
 use settings::RitaCommonSettings;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fmt::Display;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::Path;
 use std::time::{Duration, Instant};
 use tokio::timer::Delay;
+use tokio::util::FutureExt;
 
 #[cfg(test)]
 type HelloHandler = Mocker<rita_common::hello_handler::HelloHandler>;
@@ -134,7 +161,15 @@ pub struct Tunnel {
     pub neigh_id: LocalIdentity, // the identity of the counterparty tunnel
     pub last_contact: Instant, // When's the last we heard from the other end of this tunnel?
     pub speed_limit: Option<usize>, // banwidth limit in mbps, used for Codel shaping
+    pub throttle: Option<usize>, // limit in mbps while the neighbor is throttled, see ThrottleNeighbor
     pub light_client_details: Option<Ipv4Addr>, // if Some this tunnel is for a light client
+    pub mtu: Option<u16>,        // the interface mtu as determined by path mtu probing
+    pub mtu_probed: Option<Instant>, // when we last probed the path mtu of this tunnel
+    pub rxcost: Option<u16>,     // babel cost hint when we have several tunnels to this neighbor
+    pub last_endpoint: Option<String>, // the endpoint wireguard last reported for the other end
+    pub behind_nat: bool,        // if the other end's endpoint has changed, suggesting a NAT
+    pub rtt_penalty: u16,        // added to the babel cost for high latency, see the latency module
+    pub created: u64,            // unix time in seconds the tunnel was made, see the handoff module
     state: TunnelState,
 }
 
@@ -172,7 +207,15 @@ impl Tunnel {
             neigh_id: their_id,
             last_contact: Instant::now(),
             speed_limit: None,
+            throttle: None,
             light_client_details,
+            mtu: None,
+            mtu_probed: None,
+            rxcost: None,
+            last_endpoint: None,
+            behind_nat: false,
+            rtt_penalty: 0,
+            created: now_secs(),
             // By default new tunnels are in Registered state
             state: TunnelState {
                 payment_state: PaymentState::Paid,
@@ -181,6 +224,19 @@ impl Tunnel {
         }
     }
 
+    /// The limit the tunnel is actually shaped to, the lower of the bloat limit and the throttle
+    pub fn shaping_limit(&self) -> Option<usize> {
+        match (self.speed_limit, self.throttle) {
+            (Some(speed_limit), Some(throttle)) => Some(speed_limit.min(throttle)),
+            (speed_limit, throttle) => speed_limit.or(throttle),
+        }
+    }
+
+    /// The optional behaviors both we and the neighbor on the other end support
+    pub fn features(&self) -> FeatureFlags {
+        our_features().negotiate(self.neigh_id.features)
+    }
+
     /// Open a real tunnel to match the virtual tunnel we store in memory
     pub fn open(&self, light_client_details: Option<Ipv4Addr>) -> Result<(), Error> {
         let network = SETTING.get_network().clone();
@@ -197,22 +253,71 @@ impl Tunnel {
             network.external_nic.clone(),
             &mut SETTING.get_network_mut().default_route,
             light_client_details,
+            network.tunnel_keepalive_interval,
         )?;
-        KI.set_codel_shaping(&self.iface_name, None)
+        set_tunnel_shaping(&self.iface_name, self.shaping_limit())
+    }
+
+    /// Has the path mtu to the other end of this tunnel probed and the interface mtu set so that
+    /// encapsulated packets fit, WireGuard packets that don't are silently dropped by some radios
+    pub fn tune_mtu(&mut self, prober: &Addr<MtuProber>) {
+        self.mtu_probed = Some(Instant::now());
+        prober.do_send(ProbeMtu {
+            iface: self.iface_name.clone(),
+            ip: self.ip,
+            mtu: self.mtu,
+        });
+    }
+
+    /// Compares the endpoint WireGuard reports for the other end of this tunnel with what it
+    /// reported last time, a peer whose address or port changes is behind a NAT that is
+    /// rebinding them and we send keepalives so that the mapping doesn't expire under us.
+    /// `endpoint` is None if we haven't heard from the peer yet
+    pub fn check_nat(&mut self, endpoint: Option<&String>, keepalive_interval: u16) {
+        let endpoint = match endpoint {
+            Some(val) => val.clone(),
+            None => return,
+        };
+        let churned = match self.last_endpoint {
+            Some(ref last) => *last != endpoint,
+            None => false,
+        };
+        self.last_endpoint = Some(endpoint);
+        if !churned || self.behind_nat {
+            return;
+        }
+        info!(
+            "Endpoint of {} changed, assuming NAT and setting keepalive to {}s",
+            self.iface_name, keepalive_interval
+        );
+        match KI.set_wg_keepalive(
+            &self.iface_name,
+            &self.neigh_id.global.wg_public_key,
+            keepalive_interval,
+        ) {
+            Ok(_) => self.behind_nat = true,
+            Err(e) => error!(
+                "Failed to set keepalive for {} with {:?}",
+                self.iface_name, e
+            ),
+        }
     }
 
     /// Register this tunnel into Babel monitor
     pub fn monitor(&self, retry_count: u8) {
         info!("Monitoring tunnel {}", self.iface_name);
         let iface_name = self.iface_name.clone();
+        let rxcost = self.rxcost;
         let babel_port = SETTING.get_network().babel_port;
+        let params = babel_params();
         let tunnel = self.clone();
 
         Arbiter::spawn(
             open_babel_stream(babel_port)
                 .from_err()
                 .and_then(move |stream| {
-                    start_connection(stream).and_then(move |stream| monitor(stream, &iface_name))
+                    start_connection(stream)
+                        .and_then(move |stream| monitor(stream, &iface_name, rxcost, params))
                 })
                 .then(move |res| {
                     // Errors here seem very very rare, I've only ever seen it happen
@@ -270,30 +375,93 @@ impl Tunnel {
                         // We must wait until we have flushed the interface before deleting it
                         // otherwise we will experience this error
                         // https://github.com/sudomesh/bugs/issues/24
-                        if let Err(e) = KI.del_interface(&tunnel.iface_name) {
-                            error!("Failed to delete wg interface! {:?}", e);
-                        }
-                        TunnelManager::from_registry().do_send(PortCallback(tunnel.listen_port));
+                        TunnelManager::from_registry().do_send(ReapTunnels(vec![tunnel]));
                     }
                     Ok(())
                 }),
         )
     }
+}
+
+/// The ports tunnels may listen on, as configured in the network settings
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PortRange {
+    start: u16,
+    /// exclusive
+    end: u16,
+    /// ports in the range used by other services
+    reserved: HashSet<u16>,
+}
 
-    pub fn close_light_client_tunnel(&self) {
-        if let Err(e) = KI.del_interface(&self.iface_name) {
-            error!("Failed to delete wg interface! {:?}", e);
+impl PortRange {
+    fn from_settings(network: &NetworkSettings) -> PortRange {
+        let mut reserved: HashSet<u16> = network.wg_reserved_ports.iter().cloned().collect();
+        // our own services, in case the range has been moved on top of them
+        reserved.insert(network.babel_port);
+        reserved.insert(network.rita_hello_port);
+        reserved.insert(network.light_client_hello_port);
+        reserved.insert(network.rita_contact_port);
+        reserved.insert(network.rita_dashboard_port);
+        reserved.insert(network.bounty_port);
+        PortRange {
+            start: network.wg_start_port,
+            end: network.wg_end_port,
+            reserved,
         }
-        TunnelManager::from_registry().do_send(PortCallback(self.listen_port));
-        // there's a garbage collector function over in light_client_manager
-        // to handle the return of addresses it's less efficient than shooting
-        // off a message here but doesn't require conditional complication
+    }
+
+    fn contains(&self, port: u16) -> bool {
+        port >= self.start && port < self.end && !self.reserved.contains(&port)
+    }
+
+    /// All the ports in the range not reserved or in use by a tunnel
+    fn free_ports(&self, in_use: &HashSet<u16>) -> Vec<u16> {
+        (self.start..self.end)
+            .filter(|port| self.contains(*port) && !in_use.contains(port))
+            .collect()
+    }
+}
+
+/// The babel interface parameters from the settings
+pub fn babel_params() -> InterfaceParams {
+    interface_params(&SETTING.get_network().babel)
+}
+
+/// The babel interface parameters for the given babel settings
+pub fn interface_params(babel: &BabelSettings) -> InterfaceParams {
+    InterfaceParams {
+        hello_interval: babel.hello_interval,
+        // we add the penalty ourselves from the smoothed rtt
+        max_rtt_penalty: if babel.smooth_rtt {
+            0
+        } else {
+            babel.max_rtt_penalty
+        },
+        rtt_min: babel.rtt_min,
+        rtt_max: babel.rtt_max,
     }
 }
 
 pub struct TunnelManager {
     free_ports: Vec<u16>,
+    port_range: PortRange,
     tunnels: HashMap<Identity, Vec<Tunnel>>,
+    /// started the first time there's an interface to delete
+    reaper: Option<Addr<InterfaceReaper>>,
+    /// started the first time there's a neighbor to contact
+    contacter: Option<Addr<NeighborContacter>>,
+    /// started the first time there's a tunnel to probe
+    prober: Option<Addr<MtuProber>>,
+    /// peers waiting for a tunnel to be set up
+    setup_queue: SetupQueue,
+    reputation: Reputation,
+    /// the smoothed rtt of each tunnel by interface name
+    latency: HashMap<String, SmoothedRtt>,
+    /// neighbors whose routes to our exit are priced over max_fee, see RefuseRoutesVia
+    price_refused: HashSet<Identity>,
+    /// neighbors being throttled for anomalous traffic and to how many mbps, tunnels made while
+    /// they are get it too
+    throttled: HashMap<Identity, usize>,
 }
 
 impl Actor for TunnelManager {
@@ -303,6 +471,8 @@ impl Supervised for TunnelManager {}
 impl SystemService for TunnelManager {
     fn service_started(&mut self, _ctx: &mut Context<Self>) {
         info!("Tunnel manager started");
+        // clears out the interfaces of a previous run
+        self.reconcile_interfaces();
     }
 }
 
@@ -335,8 +505,8 @@ impl Handler<GotBloat> for TunnelManager {
             for (_id, tunnel_list) in self.tunnels.iter_mut() {
                 for tunnel in tunnel_list {
                     if tunnel.speed_limit != None {
-                        set_shaping_or_error(&tunnel.iface_name, None);
                         tunnel.speed_limit = None;
+                        set_shaping_or_error(&tunnel.iface_name, tunnel.shaping_limit());
                     }
                 }
             }
@@ -344,14 +514,15 @@ impl Handler<GotBloat> for TunnelManager {
         }
 
         let iface = msg.iface;
-        for (id, tunnel_list) in self.tunnels.iter_mut() {
+        let mut bloated_id = None;
+        'outer: for (id, tunnel_list) in self.tunnels.iter_mut() {
             for tunnel in tunnel_list {
                 if tunnel.iface_name == iface {
                     match tunnel.speed_limit {
                         // start at the startin glimit
                         None => {
                             tunnel.speed_limit = Some(starting_bandwidth_limit);
-                            set_shaping_or_error(&iface, Some(starting_bandwidth_limit))
+                            set_shaping_or_error(&iface, tunnel.shaping_limit())
                         }
                         // after that cut the value by 20% each time
                         Some(val) => {
@@ -363,26 +534,157 @@ impl Handler<GotBloat> for TunnelManager {
                                     "Interface {} for peer {} is showing bloat new speed value {}",
                                     iface, id.wg_public_key, new_val
                                 );
-                                set_shaping_or_error(&iface, Some(new_val));
                                 tunnel.speed_limit = Some(new_val);
+                                set_shaping_or_error(&iface, tunnel.shaping_limit());
                             }
                         }
                     }
 
-                    return;
+                    bloated_id = Some(*id);
+                    break 'outer;
+                }
+            }
+        }
+        match bloated_id {
+            // the slower link should now carry less of the traffic to this neighbor
+            Some(id) => self.update_rxcost_hints(&id),
+            None => error!(
+                "Could not find tunnel for banwdith limit with iface {}",
+                iface
+            ),
+        }
+    }
+}
+
+/// Don't probe the mtu of a tunnel more often than this
+const MTU_REPROBE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Sent by NetworkMonitor when a tunnel is showing sustained packet loss, one cause of
+/// which is a change in the path mtu (for example a radio link being reconfigured) so we
+/// check it again
+pub struct ReprobeMtu {
+    pub iface: String,
+}
+
+impl Message for ReprobeMtu {
+    type Result = ();
+}
+
+impl Handler<ReprobeMtu> for TunnelManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReprobeMtu, _: &mut Context<Self>) -> Self::Result {
+        let prober = self.prober();
+        for (_id, tunnel_list) in self.tunnels.iter_mut() {
+            for tunnel in tunnel_list {
+                let recently_probed = match tunnel.mtu_probed {
+                    Some(val) => Instant::now() - val < MTU_REPROBE_INTERVAL,
+                    None => false,
+                };
+                if tunnel.iface_name == msg.iface && !recently_probed {
+                    info!("Reprobing mtu for {} due to packet loss", tunnel.iface_name);
+                    tunnel.tune_mtu(&prober);
                 }
             }
         }
-        error!(
-            "Could not find tunnel for banwdith limit with iface {}",
-            iface
-        );
+    }
+}
+
+/// Checks every tunnel for endpoint churn and keeps those to peers behind NAT alive, light
+/// client and manual peer tunnels are the ones that usually need this
+pub struct CheckNatPeers;
+
+impl Message for CheckNatPeers {
+    type Result = ();
+}
+
+impl Handler<CheckNatPeers> for TunnelManager {
+    type Result = ();
+
+    fn handle(&mut self, _: CheckNatPeers, _: &mut Context<Self>) -> Self::Result {
+        let keepalive_interval = SETTING.get_network().nat_keepalive_interval;
+        if keepalive_interval == 0 {
+            return;
+        }
+        let endpoints = match KI.get_wg_endpoints() {
+            Ok(val) => val,
+            Err(e) => {
+                warn!("Failed to get wg endpoints with {:?}", e);
+                return;
+            }
+        };
+        for (_id, tunnel_list) in self.tunnels.iter_mut() {
+            for tunnel in tunnel_list {
+                tunnel.check_nat(endpoints.get(&tunnel.iface_name), keepalive_interval);
+            }
+        }
+    }
+}
+
+/// Reapplies the free tier to overdue tunnels, sent when a schedule window changes it
+pub struct RefreshBandwidthLimits;
+
+impl Message for RefreshBandwidthLimits {
+    type Result = ();
+}
+
+impl Handler<RefreshBandwidthLimits> for TunnelManager {
+    type Result = ();
+
+    fn handle(&mut self, _: RefreshBandwidthLimits, _: &mut Context<Self>) -> Self::Result {
+        if let Err(e) = tunnel_bw_limit_update(&self.tunnels) {
+            error!("Bandwidth limiting failed with {:?}", e);
+        }
+    }
+}
+
+/// Sent by TrafficWatcher to temporarily shape every tunnel to a neighbor whose traffic is
+/// anomalous, a limit of None lifts the throttle. The throttle is kept apart from the limit bloat
+/// detection sets and the tunnel is shaped to whichever is lower
+pub struct ThrottleNeighbor {
+    pub id: Identity,
+    pub limit: Option<usize>,
+}
+
+impl Message for ThrottleNeighbor {
+    type Result = ();
+}
+
+impl Handler<ThrottleNeighbor> for TunnelManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: ThrottleNeighbor, _: &mut Context<Self>) -> Self::Result {
+        match msg.limit {
+            Some(limit) => self.throttled.insert(msg.id, limit),
+            None => self.throttled.remove(&msg.id),
+        };
+        if let Some(tunnel_list) = self.tunnels.get_mut(&msg.id) {
+            for tunnel in tunnel_list {
+                tunnel.throttle = msg.limit;
+                set_shaping_or_error(&tunnel.iface_name, tunnel.shaping_limit());
+            }
+        }
+    }
+}
+
+/// Sets up the qdisc on a tunnel, prioritizing latency sensitive traffic if tunnel qos is
+/// enabled. Limit is in mbps
+fn set_tunnel_shaping(iface: &str, limit: Option<usize>) -> Result<(), Error> {
+    let qos = SETTING.get_network().tunnel_qos.clone();
+    if qos.enabled {
+        let max_rate = match limit {
+            Some(mbps) => (mbps as u32).saturating_mul(1000),
+            None => qos.max_rate,
+        };
+        KI.set_qos_shaping(iface, max_rate, qos.priority_rate)
+    } else {
+        KI.set_codel_shaping(iface, limit)
     }
 }
 
 /// tiny little helper function for GotBloat() limit is in mbps
 fn set_shaping_or_error(iface: &str, limit: Option<usize>) {
-    if let Err(e) = KI.set_codel_shaping(iface, limit) {
+    if let Err(e) = set_tunnel_shaping(iface, limit) {
         error!("Failed to shape tunnel for bloat! {}", e);
     }
 }
@@ -456,18 +758,40 @@ impl Handler<PortCallback> for TunnelManager {
 
     fn handle(&mut self, msg: PortCallback, _: &mut Context<Self>) -> Self::Result {
         let port = msg.0;
-        self.free_ports.push(port);
+        self.return_port(port);
+    }
+}
+
+/// The features we and a neighbor both support, none if we don't have a tunnel to them
+pub struct GetNeighborFeatures(pub Identity);
+
+impl Message for GetNeighborFeatures {
+    type Result = Result<FeatureFlags, Error>;
+}
+
+impl Handler<GetNeighborFeatures> for TunnelManager {
+    type Result = Result<FeatureFlags, Error>;
+
+    fn handle(&mut self, msg: GetNeighborFeatures, _: &mut Context<Self>) -> Self::Result {
+        Ok(
+            match self.tunnels.get(&msg.0).and_then(|tunnels| tunnels.first()) {
+                Some(tunnel) => tunnel.features(),
+                None => FeatureFlags::default(),
+            },
+        )
     }
 }
 
 pub struct GetNeighbors;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Neighbor {
     pub identity: LocalIdentity,
     pub iface_name: String,
     pub tunnel_ip: IpAddr,
     pub speed_limit: Option<usize>,
+    /// between 0 and 1, see the reputation module
+    pub reputation: f64,
 }
 
 impl Neighbor {
@@ -476,12 +800,14 @@ impl Neighbor {
         iface_name: String,
         tunnel_ip: IpAddr,
         speed_limit: Option<usize>,
+        reputation: f64,
     ) -> Neighbor {
         Neighbor {
             identity,
             iface_name,
             tunnel_ip,
             speed_limit,
+            reputation,
         }
     }
 }
@@ -493,6 +819,12 @@ impl Handler<GetNeighbors> for TunnelManager {
     type Result = Result<Vec<Neighbor>, Error>;
 
     fn handle(&mut self, _: GetNeighbors, _: &mut Context<Self>) -> Self::Result {
+        Ok(self.neighbors())
+    }
+}
+
+impl TunnelManager {
+    fn neighbors(&self) -> Vec<Neighbor> {
         let mut res = Vec::new();
         for (_, tunnels) in self.tunnels.iter() {
             for tunnel in tunnels.iter() {
@@ -500,11 +832,12 @@ impl Handler<GetNeighbors> for TunnelManager {
                     tunnel.neigh_id,
                     tunnel.iface_name.clone(),
                     tunnel.ip,
-                    tunnel.speed_limit,
+                    tunnel.shaping_limit(),
+                    self.reputation.get(&tunnel.neigh_id.global).score(),
                 ));
             }
         }
-        Ok(res)
+        res
     }
 }
 
@@ -527,6 +860,11 @@ impl Handler<GetTunnels> for TunnelManager {
     }
 }
 
+/// The most tunnels a single TriggerGC will remove, any more are left for a followup
+const GC_MAX_TUNNELS: usize = 32;
+/// How long to wait before collecting the next batch of timed out tunnels
+const GC_CONTINUE_DELAY: Duration = Duration::from_millis(500);
+
 /// A message type for deleting all tunnels we haven't heard from for more than the duration.
 pub struct TriggerGC(pub Duration);
 
@@ -536,31 +874,18 @@ impl Message for TriggerGC {
 
 impl Handler<TriggerGC> for TunnelManager {
     type Result = Result<(), Error>;
-    fn handle(&mut self, msg: TriggerGC, _ctx: &mut Context<Self>) -> Self::Result {
-        let mut good: HashMap<Identity, Vec<Tunnel>> = HashMap::new();
-        let mut timed_out: HashMap<Identity, Vec<Tunnel>> = HashMap::new();
-        // Split entries into good and timed out rebuilding the double hashmap strucutre
-        // as you can tell this is enterly copy based and uses 2n ram to prevent borrow
-        // checker issues, we should consider a method that does modify in place
-        for (identity, tunnels) in self.tunnels.iter() {
-            for tunnel in tunnels.iter() {
-                if tunnel.last_contact.elapsed() < msg.0 {
-                    if good.contains_key(identity) {
-                        good.get_mut(identity).unwrap().push(tunnel.clone());
-                    } else {
-                        good.insert(identity.clone(), Vec::new());
-                        good.get_mut(identity).unwrap().push(tunnel.clone());
-                    }
-                } else if timed_out.contains_key(identity) {
-                    timed_out.get_mut(identity).unwrap().push(tunnel.clone());
-                } else {
-                    timed_out.insert(identity.clone(), Vec::new());
-                    timed_out.get_mut(identity).unwrap().push(tunnel.clone());
-                }
-            }
-        }
+    fn handle(&mut self, msg: TriggerGC, ctx: &mut Context<Self>) -> Self::Result {
+        let timed_out = take_timed_out(&mut self.tunnels, msg.0, GC_MAX_TUNNELS);
 
-        info!("TriggerGC: removing tunnels: {:?}", timed_out);
+        // there's more to collect, come back for it once the hellos that queued up
+        // behind this batch have been handled
+        if timed_out.len() >= GC_MAX_TUNNELS {
+            ctx.notify_later(TriggerGC(msg.0), GC_CONTINUE_DELAY);
+        }
+        if timed_out.is_empty() {
+            return Ok(());
+        }
+        info!("TriggerGC: removing {} tunnels", timed_out.len());
 
         // Please keep in mind it makes more sense to update the tunnel map *before* yielding the
         // actual interfaces and ports from timed_out.
@@ -570,27 +895,196 @@ impl Handler<TriggerGC> for TunnelManager {
         //
         // The former would be a mere performance bug while inconsistent-with-reality Rita state
         // would lead to nasty bugs in case del_interface() goes wrong for whatever reason.
-        self.tunnels = good;
-
-        for (_ident, tunnels) in timed_out {
-            for tunnel in tunnels {
-                match tunnel.light_client_details {
-                    None => {
-                        // In the same spirit, we return the port to the free port pool only after tunnel
-                        // deletion goes well.
-                        tunnel.unmonitor(0);
-                    }
-                    Some(_) => {
-                        tunnel.close_light_client_tunnel();
-                    }
-                }
+        let mut to_reap = Vec::new();
+        let mut affected = HashSet::new();
+        for (ident, tunnel) in timed_out {
+            trace!("TriggerGC: removing tunnel {}", tunnel.iface_name);
+            affected.insert(ident);
+            match tunnel.light_client_details {
+                // the port is returned only after babel has let go of the interface and it
+                // has been deleted
+                None => tunnel.unmonitor(0),
+                // there's a garbage collector function over in light_client_manager
+                // to handle the return of addresses
+                Some(_) => to_reap.push(tunnel),
             }
         }
+        for ident in affected.iter() {
+            self.update_rxcost_hints(ident);
+        }
+        self.reap(to_reap);
 
         Ok(())
     }
 }
 
+/// Removes up to `max` tunnels that haven't been heard from within `timeout` from the tunnel
+/// map, dropping identities that are left with no tunnels at all
+fn take_timed_out(
+    tunnels: &mut HashMap<Identity, Vec<Tunnel>>,
+    timeout: Duration,
+    max: usize,
+) -> Vec<(Identity, Tunnel)> {
+    let mut timed_out = Vec::new();
+    for (identity, id_tunnels) in tunnels.iter_mut() {
+        let mut i = 0;
+        while i < id_tunnels.len() && timed_out.len() < max {
+            if id_tunnels[i].last_contact.elapsed() < timeout {
+                i += 1;
+            } else {
+                timed_out.push((*identity, id_tunnels.remove(i)));
+            }
+        }
+        if timed_out.len() >= max {
+            break;
+        }
+    }
+    tunnels.retain(|_, id_tunnels| !id_tunnels.is_empty());
+    timed_out
+}
+
+/// Hands tunnels to the interface reaper once babel is no longer watching them
+pub struct ReapTunnels(pub Vec<Tunnel>);
+
+impl Message for ReapTunnels {
+    type Result = ();
+}
+
+impl Handler<ReapTunnels> for TunnelManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReapTunnels, _: &mut Context<Self>) -> Self::Result {
+        self.reap(msg.0);
+    }
+}
+
+/// Tears down our tunnels to this neighbor over the physical interface with this index and
+/// contacts the neighbor again to build fresh ones, for tunnels that have wedged with a stale
+/// handshake or an endpoint that's out of date
+pub struct ResetTunnel {
+    pub identity: Identity,
+    pub ifidx: u32,
+}
+
+impl Message for ResetTunnel {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<ResetTunnel> for TunnelManager {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: ResetTunnel, _: &mut Context<Self>) -> Self::Result {
+        let rita_hello_port = SETTING.get_network().rita_hello_port;
+        let reset = take_tunnels(&mut self.tunnels, &msg.identity, msg.ifidx);
+        if reset.is_empty() {
+            bail!(
+                "No tunnel to {} on interface {}",
+                msg.identity.wg_public_key,
+                msg.ifidx
+            );
+        }
+        self.update_rxcost_hints(&msg.identity);
+
+        let mut to_reap = Vec::new();
+        let mut peers = Vec::new();
+        for tunnel in reset {
+            info!("Resetting tunnel {}", tunnel);
+            peers.push(Peer {
+                ifidx: tunnel.listen_ifidx,
+                contact_socket: SocketAddr::new(tunnel.ip, rita_hello_port),
+            });
+            match tunnel.light_client_details {
+                None => tunnel.unmonitor(0),
+                // light clients contact us on their own, their address is returned by the
+                // light client manager's garbage collection
+                Some(_) => to_reap.push(tunnel),
+            }
+        }
+        self.reap(to_reap);
+
+        // the old interfaces are still being deleted, the new tunnels get new ones
+        for peer in peers {
+            let inquiry = self.neighbor_inquiry(&peer)?;
+            Arbiter::spawn(inquiry.then(move |res| {
+                if let Err(e) = res {
+                    warn!(
+                        "Neighbor inquiry for reset tunnel {:?} failed with {:?}",
+                        peer, e
+                    );
+                }
+                Ok(())
+            }));
+        }
+        Ok(())
+    }
+}
+
+/// True if we've stopped meshing with the neighbor with this key until it's resumed
+pub fn is_paused(key: &WgKey) -> bool {
+    SETTING.get_network().paused_neighbors.contains(key)
+}
+
+/// Tears down every tunnel to the neighbor with this wg key, which should already be in
+/// `paused_neighbors` so that they aren't set up again. Returns how many tunnels were removed
+pub struct PauseNeighbor(pub WgKey);
+
+impl Message for PauseNeighbor {
+    type Result = Result<usize, Error>;
+}
+
+impl Handler<PauseNeighbor> for TunnelManager {
+    type Result = Result<usize, Error>;
+
+    fn handle(&mut self, msg: PauseNeighbor, _: &mut Context<Self>) -> Self::Result {
+        let paused = take_neighbor_tunnels(&mut self.tunnels, &msg.0);
+        let count = paused.len();
+        let mut to_reap = Vec::new();
+        for tunnel in paused {
+            info!("Removing tunnel {} to paused neighbor", tunnel);
+            match tunnel.light_client_details {
+                None => tunnel.unmonitor(0),
+                Some(_) => to_reap.push(tunnel),
+            }
+        }
+        self.reap(to_reap);
+        Ok(count)
+    }
+}
+
+/// Removes every tunnel to any identity with this wg key from the tunnel map
+fn take_neighbor_tunnels(tunnels: &mut HashMap<Identity, Vec<Tunnel>>, key: &WgKey) -> Vec<Tunnel> {
+    let identities: Vec<Identity> = tunnels
+        .keys()
+        .filter(|identity| identity.wg_public_key == *key)
+        .cloned()
+        .collect();
+    identities
+        .iter()
+        .filter_map(|identity| tunnels.remove(identity))
+        .flatten()
+        .collect()
+}
+
+/// Removes the tunnels to this identity over the physical interface `ifidx` from the tunnel map
+fn take_tunnels(
+    tunnels: &mut HashMap<Identity, Vec<Tunnel>>,
+    identity: &Identity,
+    ifidx: u32,
+) -> Vec<Tunnel> {
+    let id_tunnels = match tunnels.get_mut(identity) {
+        Some(id_tunnels) => id_tunnels,
+        None => return Vec::new(),
+    };
+    let (taken, kept) = id_tunnels
+        .drain(..)
+        .partition(|tunnel| tunnel.listen_ifidx == ifidx);
+    *id_tunnels = kept;
+    if id_tunnels.is_empty() {
+        tunnels.remove(identity);
+    }
+    taken
+}
+
 pub struct PeersToContact {
     pub peers: HashMap<IpAddr, Peer>,
 }
@@ -602,115 +1096,166 @@ impl PeersToContact {
 }
 
 impl Message for PeersToContact {
-    type Result = ();
+    type Result = Result<(), Error>;
 }
 
+/// How long all of the neighbor inquiries of a tick get, shorter than the fast loop's timeout
+/// so that the results are in before it gives up on us
+const PEER_CONTACT_DEADLINE: Duration = Duration::from_secs(3);
+
+/// An inquiry that's underway and who it's for
+type Inquiry = (String, Box<dyn Future<Item = (), Error = Error>>);
+
 /// Takes a list of peers to contact and dispatches requests if you have a WAN connection
-/// it will also dispatch neighbor requests to manual peers
+/// it will also dispatch neighbor requests to manual peers. The inquiries run concurrently,
+/// the returned future resolves once they are all done or the deadline has passed
 impl Handler<PeersToContact> for TunnelManager {
-    type Result = ();
+    type Result = ResponseFuture<(), Error>;
     fn handle(&mut self, msg: PeersToContact, _ctx: &mut Context<Self>) -> Self::Result {
         let network_settings = SETTING.get_network();
         let manual_peers = network_settings.manual_peers.clone();
         let is_gateway = network_settings.is_gateway;
         let rita_hello_port = network_settings.rita_hello_port;
+        let tunnel_setup_concurrency = network_settings.tunnel_setup_concurrency;
         drop(network_settings);
 
         trace!("TunnelManager contacting peers");
+        if self.setup_queue.refused() > 0 {
+            info!(
+                "Turned away {} tunnel setups last tick",
+                self.setup_queue.refused()
+            );
+        }
+        self.setup_queue.new_tick(tunnel_setup_concurrency);
+        // peers we already have tunnels with are only being kept alive, which is cheap, new
+        // peers wait their turn
+        let mut to_contact = Vec::new();
         for (_, peer) in msg.peers.iter() {
-            let res = self.neighbor_inquiry(&peer);
-            if res.is_err() {
-                warn!("Neighbor inqury for {:?} failed! with {:?}", peer, res);
+            if has_tunnel(&self.tunnels, peer) {
+                to_contact.push(peer.clone());
+            } else {
+                self.setup_queue.push(peer.clone());
             }
         }
-        for manual_peer in manual_peers.iter() {
-            let ip = manual_peer.parse::<IpAddr>();
+        let tunnels = &self.tunnels;
+        let new_peers = self.setup_queue.take(|peer| has_tunnel(tunnels, peer));
+        to_contact.extend(new_peers);
+        if !self.setup_queue.is_empty() {
+            info!("{} peers waiting for tunnel setup", self.setup_queue.len());
+        }
 
-            match ip {
+        let mut inquiries: Vec<Inquiry> = Vec::new();
+        for peer in to_contact.iter() {
+            match self.neighbor_inquiry(&peer) {
+                Ok(inquiry) => inquiries.push((format!("{:?}", peer), inquiry)),
+                Err(e) => warn!("Neighbor inqury for {:?} failed! with {:?}", peer, e),
+            }
+        }
+        for manual_peer in manual_peers.iter() {
+            let inquiry = match manual_peer.parse::<IpAddr>() {
                 Ok(ip) => {
                     let socket = SocketAddr::new(ip, rita_hello_port);
                     let man_peer = Peer {
                         ifidx: 0,
                         contact_socket: socket,
                     };
-                    let res = self.neighbor_inquiry(&man_peer);
-                    if res.is_err() {
-                        warn!(
-                            "Neighbor inqury for {:?} failed with: {:?}",
-                            manual_peer, res
-                        );
-                    }
+                    self.neighbor_inquiry(&man_peer)
                 }
-                Err(_) => {
-                    // Do not contact manual peers on the internet if we are not a gateway
-                    // it will just fill the logs with faild dns resolution attempts or result
-                    // in bad behavior, we do allow the addressing of direct ip address gateways
-                    // for the special case that the user is attempting some special behavior
-                    if is_gateway {
-                        let res = self.neighbor_inquiry_hostname(manual_peer.to_string());
-                        if res.is_err() {
-                            warn!(
-                                "Neighbor inqury for {:?} failed with: {:?}",
-                                manual_peer, res
-                            );
+                // Do not contact manual peers on the internet if we are not a gateway
+                // it will just fill the logs with faild dns resolution attempts or result
+                // in bad behavior, we do allow the addressing of direct ip address gateways
+                // for the special case that the user is attempting some special behavior
+                Err(_) if is_gateway => self.neighbor_inquiry_hostname(manual_peer.to_string()),
+                Err(_) => continue,
+            };
+            match inquiry {
+                Ok(inquiry) => inquiries.push((manual_peer.clone(), inquiry)),
+                Err(e) => warn!("Neighbor inqury for {:?} failed with: {:?}", manual_peer, e),
+            }
+        }
+
+        let inquiries = inquiries.into_iter().map(|(name, inquiry)| {
+            inquiry
+                .timeout(PEER_CONTACT_DEADLINE)
+                .then(move |res| -> Result<bool, Error> {
+                    match res {
+                        Ok(()) => Ok(true),
+                        // the contact may still go through so the port is kept
+                        Err(ref e) if e.is_elapsed() => Ok(false),
+                        Err(e) => {
+                            warn!("Neighbor inqury for {} failed with: {:?}", name, e);
+                            Ok(true)
                         }
                     }
-                }
+                })
+        });
+        Box::new(join_all(inquiries).and_then(|finished| {
+            let late = finished.iter().filter(|done| !**done).count();
+            if late > 0 {
+                warn!(
+                    "{} neighbor inquiries missed the {}s deadline",
+                    late,
+                    PEER_CONTACT_DEADLINE.as_secs()
+                );
             }
-        }
+            Ok(())
+        }))
     }
 }
 
-/// Sets out to contact a neighbor, takes a speculative port (only assigned if the neighbor
-/// responds successfully)
-fn contact_neighbor(peer: &Peer, our_port: u16) -> Result<(), Error> {
-    KI.manual_peers_route(
-        &peer.contact_socket.ip(),
-        &mut SETTING.get_network_mut().default_route,
-    )?;
-
-    HelloHandler::from_registry().do_send(Hello {
-        my_id: LocalIdentity {
-            global: SETTING
-                .get_identity()
-                .ok_or_else(|| format_err!("Identity has no mesh IP ready yet"))?,
-            wg_port: our_port,
-            have_tunnel: None,
-        },
-        to: peer.clone(),
-    });
-
-    Ok(())
+/// gets the tunnel from the list with the given index and target ip, both are needed
+/// to tell apart several tunnels to the same neighbor over the same physical interface
+/// If we have a tunnel with this peer over the interface we heard them on
+pub(crate) fn has_tunnel(tunnels: &HashMap<Identity, Vec<Tunnel>>, peer: &Peer) -> bool {
+    tunnels.values().any(|tunnels| {
+        get_tunnel_by_ifidx_and_ip(peer.ifidx, peer.contact_socket.ip(), tunnels).is_some()
+    })
 }
 
-/// determines if the list contains a tunnel with the given target ip
-fn have_tunnel_by_ip(ip: IpAddr, tunnels: &[Tunnel]) -> bool {
+fn get_tunnel_by_ifidx_and_ip(ifidx: u32, ip: IpAddr, tunnels: &[Tunnel]) -> Option<&Tunnel> {
     for tunnel in tunnels.iter() {
-        if tunnel.ip == ip {
-            return true;
+        if tunnel.listen_ifidx == ifidx && tunnel.ip == ip {
+            return Some(tunnel);
         }
     }
-    false
+    None
 }
 
-/// determines if the list contains a tunnel with the given target ifidx
-fn have_tunnel_by_ifidx(ifidx: u32, tunnels: &[Tunnel]) -> bool {
-    for tunnel in tunnels.iter() {
-        if tunnel.listen_ifidx == ifidx {
-            return true;
-        }
+/// The babel rxcost given to the best of several tunnels to the same neighbor
+const MULTIPATH_BASE_RXCOST: u16 = 96;
+/// No tunnel is hinted worse than this, so that babel still falls back to it
+const MULTIPATH_MAX_RXCOST: u16 = 1024;
+/// The least rxcost the tunnels to a neighbor refused for its price get, well past anything the
+/// other hints give so that babel prefers any other route, but short of infinity so that routes
+/// through the neighbor are still used when there's nothing else
+const PRICE_REFUSAL_RXCOST: u16 = 4096;
+
+/// When we have several tunnels to the same neighbor (for example two routers linked by two
+/// radios) computes a babel rxcost for each so that routes prefer the fastest link, as judged
+/// by the bandwidth limit bloat detection has settled on, and tunnels of equal quality are
+/// weighted equally. Lone tunnels get no hint and are left at the babel default.
+fn multipath_rxcosts(tunnels: &[Tunnel], unshaped_speed: usize) -> Vec<Option<u16>> {
+    let speeds: Vec<usize> = tunnels
+        .iter()
+        .filter(|t| t.light_client_details.is_none())
+        .map(|t| t.speed_limit.unwrap_or(unshaped_speed).max(1))
+        .collect();
+    if speeds.len() < 2 {
+        return tunnels.iter().map(|_| None).collect();
     }
-    false
-}
+    let fastest = *speeds.iter().max().unwrap();
 
-/// gets the tunnel from the list with the given index
-fn get_tunnel_by_ifidx(ifidx: u32, tunnels: &[Tunnel]) -> Option<&Tunnel> {
-    for tunnel in tunnels.iter() {
-        if tunnel.listen_ifidx == ifidx {
-            return Some(tunnel);
-        }
-    }
-    None
+    tunnels
+        .iter()
+        .map(|t| {
+            if t.light_client_details.is_some() {
+                return None;
+            }
+            let speed = t.speed_limit.unwrap_or(unshaped_speed).max(1);
+            let cost = u64::from(MULTIPATH_BASE_RXCOST) * fastest as u64 / speed as u64;
+            Some(cost.min(u64::from(MULTIPATH_MAX_RXCOST)) as u16)
+        })
+        .collect()
 }
 
 /// deletes all instances of a given tunnel from the list
@@ -720,18 +1265,83 @@ fn del_tunnel(to_del: &Tunnel, tunnels: &mut Vec<Tunnel>) {
 
 impl TunnelManager {
     pub fn new() -> Self {
-        let start = SETTING.get_network().wg_start_port;
-        let ports = (start..65535).collect();
+        let network = SETTING.get_network();
+        let port_range = PortRange::from_settings(&network);
+        // peers that contact us before our first tick get the same budget as one
+        let mut setup_queue = SetupQueue::default();
+        setup_queue.new_tick(network.tunnel_setup_concurrency);
+        drop(network);
         TunnelManager {
-            free_ports: ports,
+            free_ports: port_range.free_ports(&HashSet::new()),
+            port_range,
             tunnels: HashMap::new(),
+            reaper: None,
+            contacter: None,
+            prober: None,
+            setup_queue,
+            reputation: Reputation::default(),
+            latency: HashMap::new(),
+            price_refused: HashSet::new(),
+            throttled: HashMap::new(),
         }
     }
 
+    /// Hands tunnels off to have their interfaces deleted and ports returned
+    fn reap(&mut self, tunnels: Vec<Tunnel>) {
+        if tunnels.is_empty() {
+            return;
+        }
+        self.reaper
+            .get_or_insert_with(|| SyncArbiter::start(1, || InterfaceReaper))
+            .do_send(DeleteInterfaces(tunnels));
+    }
+
+    /// Puts a port back in the free list, ports that are no longer in the configured range
+    /// because it changed while they were in use are simply dropped
+    fn return_port(&mut self, port: u16) {
+        if self.port_range.contains(port) && !self.free_ports.contains(&port) {
+            self.free_ports.push(port);
+        }
+    }
+
+    /// Rebuilds the free port list if the port range settings have changed, existing tunnels
+    /// keep their ports even if those are now outside of the range
+    fn refresh_port_range(&mut self) {
+        let port_range = PortRange::from_settings(&SETTING.get_network());
+        if port_range == self.port_range {
+            return;
+        }
+        if port_range.start >= port_range.end {
+            error!(
+                "Invalid tunnel port range {}..{}, keeping the old one",
+                port_range.start, port_range.end
+            );
+            return;
+        }
+        let in_use: HashSet<u16> = self
+            .tunnels
+            .values()
+            .flat_map(|tunnels| tunnels.iter().map(|t| t.listen_port))
+            .collect();
+        info!(
+            "Tunnel port range changed to {}..{}",
+            port_range.start, port_range.end
+        );
+        self.free_ports = port_range.free_ports(&in_use);
+        self.port_range = port_range;
+    }
+
     /// Gets a port off of the internal port list after checking that said port is free
     /// with the operating system, level argument is always zero for callers and is used
     /// interally to prevent unchecked recursion
     fn get_port(&mut self, level: usize) -> Option<u16> {
+        if level == 0 {
+            self.refresh_port_range();
+        }
+        if self.free_ports.is_empty() {
+            error!("No free ports left in the tunnel port range!");
+            return None;
+        }
         let udp_table = KI.used_ports();
         let mut rng = thread_rng();
         let val = rng.gen_range(0, self.free_ports.len());
@@ -767,13 +1377,33 @@ impl TunnelManager {
         }
     }
 
-    /// This function generates a future and hands it off to the Actix arbiter to actually resolve
-    /// in the case that the DNS request is successful the hello handler and eventually the Identity
-    /// callback continue execution flow. But this function itself returns syncronously
-    pub fn neighbor_inquiry_hostname(&mut self, their_hostname: String) -> Result<(), Error> {
+    /// The pool neighbors are contacted from
+    fn contacter(&mut self) -> Addr<NeighborContacter> {
+        self.contacter
+            .get_or_insert_with(|| {
+                SyncArbiter::start(NEIGHBOR_CONTACT_THREADS, || NeighborContacter)
+            })
+            .clone()
+    }
+
+    /// The thread path mtus are probed from
+    fn prober(&mut self) -> Addr<MtuProber> {
+        self.prober
+            .get_or_insert_with(|| SyncArbiter::start(1, || MtuProber))
+            .clone()
+    }
+
+    /// Resolves a manual peer's hostname and contacts every address it resolves to, in the
+    /// case that the DNS request is successful the hello handler and eventually the Identity
+    /// callback continue execution flow
+    pub fn neighbor_inquiry_hostname(
+        &mut self,
+        their_hostname: String,
+    ) -> Result<Box<dyn Future<Item = (), Error = Error>>, Error> {
         trace!("Getting tunnel, inq");
         let network_settings = SETTING.get_network();
         let is_gateway = network_settings.is_gateway;
+        let ipv6_only_wan = network_settings.ipv6_only_wan;
         let rita_hello_port = network_settings.rita_hello_port;
         drop(network_settings);
 
@@ -786,56 +1416,80 @@ impl TunnelManager {
                 );
             }
         };
+        let contacter = self.contacter();
 
         let res = Resolver::from_registry()
             .send(resolver::Resolve::host(their_hostname.clone()))
             .timeout(Duration::from_secs(1))
-            .then(move |res| match res {
-                Ok(Ok(dnsresult)) => {
-                    let url = format!("http://[{}]:{}/hello", their_hostname, rita_hello_port);
-                    trace!("Saying hello to: {:?} at ip {:?}", url, dnsresult);
-                    if !dnsresult.is_empty() && is_gateway {
+            .then(move |res| -> Box<dyn Future<Item = (), Error = Error>> {
+                match res {
+                    Ok(Ok(dnsresult)) => {
+                        let url = format!("http://[{}]:{}/hello", their_hostname, rita_hello_port);
+                        trace!("Saying hello to: {:?} at ip {:?}", url, dnsresult);
+                        if dnsresult.is_empty() || !is_gateway {
+                            trace!(
+                                "We're not a gateway or we got a zero length dns response: {:?}",
+                                dnsresult
+                            );
+                            return Box::new(future::ok(()));
+                        }
                         // dns records may have many ip's if we get multiple it's a load
                         // balanced exit and we need to create tunnels to all of them
-                        for dns_socket in dnsresult {
-                            let their_ip = dns_socket.ip();
-                            let socket = SocketAddr::new(their_ip, rita_hello_port);
+                        let dnsresult = prefer_ipv6(dnsresult, ipv6_only_wan);
+                        let contacts = dnsresult.into_iter().map(move |dns_socket| {
                             let man_peer = Peer {
                                 ifidx: 0,
-                                contact_socket: socket,
+                                contact_socket: SocketAddr::new(dns_socket.ip(), rita_hello_port),
                             };
-                            let res = contact_neighbor(&man_peer, our_port);
-                            if res.is_err() {
-                                warn!("Contact neighbor failed with {:?}", res);
-                            }
-                        }
-                    } else {
-                        trace!(
-                            "We're not a gateway or we got a zero length dns response: {:?}",
-                            dnsresult
-                        );
+                            contacter
+                                .send(ContactNeighbor {
+                                    peer: man_peer,
+                                    our_port,
+                                    owns_port: false,
+                                })
+                                .then(|res| -> Result<bool, ()> {
+                                    match res {
+                                        Ok(Ok(())) => return Ok(true),
+                                        Ok(Err(e)) => warn!("Contact neighbor failed with {:?}", e),
+                                        Err(e) => warn!("Contact neighbor failed with {:?}", e),
+                                    }
+                                    Ok(false)
+                                })
+                        });
+                        // the addresses share the port so it's given back once they've all
+                        // failed, this is spawned on its own so that it happens even if the
+                        // caller's deadline passes first
+                        Arbiter::spawn(join_all(contacts.collect::<Vec<_>>()).map(
+                            move |contacted| {
+                                if !contacted.contains(&true) {
+                                    TunnelManager::from_registry().do_send(PortCallback(our_port));
+                                }
+                            },
+                        ));
+                        Box::new(future::ok(()))
+                    }
+                    Err(e) => {
+                        warn!("Actor mailbox failure from DNS resolver! {:?}", e);
+                        TunnelManager::from_registry().do_send(PortCallback(our_port));
+                        Box::new(future::ok(()))
                     }
-                    Ok(())
-                }
-                Err(e) => {
-                    warn!("Actor mailbox failure from DNS resolver! {:?}", e);
-                    TunnelManager::from_registry().do_send(PortCallback(our_port));
-                    Ok(())
-                }
 
-                Ok(Err(e)) => {
-                    warn!("DNS resolution failed with {:?}", e);
-                    TunnelManager::from_registry().do_send(PortCallback(our_port));
-                    Ok(())
+                    Ok(Err(e)) => {
+                        warn!("DNS resolution failed with {:?}", e);
+                        TunnelManager::from_registry().do_send(PortCallback(our_port));
+                        Box::new(future::ok(()))
+                    }
                 }
             });
-        Arbiter::spawn(res);
-        Ok(())
+        Ok(Box::new(res))
     }
 
-    /// Contacts one neighbor with our LocalIdentity to get their LocalIdentity and wireguard tunnel
-    /// interface name.
-    pub fn neighbor_inquiry(&mut self, peer: &Peer) -> Result<(), Error> {
+    /// Contacts one neighbor, takes a speculative port (only assigned if the neighbor
+    /// responds successfully), which is returned if we couldn't say hello at all
+    pub fn neighbor_inquiry(
+        &mut self,
+        peer: &Peer,
+    ) -> Result<Box<dyn Future<Item = (), Error = Error>>, Error> {
         trace!("TunnelManager neigh inquiry for {:?}", peer);
         let our_port = match self.get_port(0) {
             Some(p) => p,
@@ -847,7 +1501,22 @@ impl TunnelManager {
             }
         };
 
-        contact_neighbor(peer, our_port)
+        Ok(Box::new(
+            self.contacter()
+                .send(ContactNeighbor {
+                    peer: peer.clone(),
+                    our_port,
+                    owns_port: true,
+                })
+                .then(move |res| match res {
+                    // a failed contact gives back its own port
+                    Ok(res) => res,
+                    Err(e) => {
+                        TunnelManager::from_registry().do_send(PortCallback(our_port));
+                        Err(e.into())
+                    }
+                }),
+        ))
     }
 
     /// Given a LocalIdentity, connect to the neighbor over wireguard
@@ -866,11 +1535,12 @@ impl TunnelManager {
 
         let we_have_tunnel = match self.tunnels.get(&key) {
             Some(tunnels) => {
-                have_tunnel_by_ifidx(peer.ifidx, tunnels)
-                    && have_tunnel_by_ip(peer.contact_socket.ip(), tunnels)
+                get_tunnel_by_ifidx_and_ip(peer.ifidx, peer.contact_socket.ip(), tunnels).is_some()
             }
             None => false,
         };
+        // a neighbor whose address changed keeps its tunnel, pointed at the new address
+        let we_have_tunnel = we_have_tunnel || self.repin_roamed_tunnel(&their_localid, &peer);
 
         let they_have_tunnel = match their_localid.have_tunnel {
             Some(v) => v,
@@ -893,13 +1563,17 @@ impl TunnelManager {
                         tunnel.last_contact = Instant::now();
                         // update the nickname in case they changed it live
                         tunnel.neigh_id.global.nickname = their_localid.global.nickname;
+                        // and their advertised price
+                        tunnel.neigh_id.local_fee = their_localid.local_fee;
+                        // and what they support, which changes when they upgrade
+                        tunnel.neigh_id.features = their_localid.features;
                     }
                 }
             }
 
             if they_have_tunnel {
                 // return allocated port as it's not required
-                self.free_ports.push(our_port);
+                self.return_port(our_port);
                 trace!("Looking up for a tunnels by {:?}", key);
                 // Unwrap is safe because we confirm membership
                 let tunnels = &self.tunnels[&key];
@@ -910,8 +1584,9 @@ impl TunnelManager {
                     tunnels,
                     peer.ifidx
                 );
-                let tunnel = get_tunnel_by_ifidx(peer.ifidx, tunnels)
-                    .expect("Unable to find tunnel by ifidx how did this happen?");
+                let tunnel =
+                    get_tunnel_by_ifidx_and_ip(peer.ifidx, peer.contact_socket.ip(), tunnels)
+                        .expect("Unable to find tunnel by ifidx how did this happen?");
 
                 return Ok((tunnel.clone(), true));
             } else {
@@ -927,7 +1602,10 @@ impl TunnelManager {
                     // Find tunnels by identity
                     let tunnels = self.tunnels.get_mut(&key).unwrap();
                     // Find tunnel by interface index
-                    let value = get_tunnel_by_ifidx(peer.ifidx, tunnels).unwrap().clone();
+                    let value =
+                        get_tunnel_by_ifidx_and_ip(peer.ifidx, peer.contact_socket.ip(), tunnels)
+                            .unwrap()
+                            .clone();
                     del_tunnel(&value, tunnels);
                     // Outer HashMap (self.tunnels) can contain empty HashMaps,
                     // so the resulting tuple will consist of the tunnel itself, and
@@ -937,6 +1615,8 @@ impl TunnelManager {
                 if size == 0 {
                     // Remove this identity if there are no tunnels associated with it.
                     self.tunnels.remove(&key);
+                } else {
+                    self.update_rxcost_hints(&key);
                 }
 
                 // Remove interface
@@ -948,7 +1628,7 @@ impl TunnelManager {
                     );
                 }
 
-                self.free_ports.push(tunnel.listen_port);
+                self.return_port(tunnel.listen_port);
                 return_bool = true;
             }
         }
@@ -958,20 +1638,85 @@ impl TunnelManager {
             peer.ifidx,
         );
 
-        let (new_key, tunnel) = create_new_tunnel(
-            peer.contact_socket.ip(),
-            our_port,
-            peer.ifidx,
-            their_localid,
-            light_client_details,
-        )?;
+        let throttle = self.throttled.get(&their_localid.global).cloned();
+        let create = || {
+            create_new_tunnel(
+                peer.contact_socket.ip(),
+                our_port,
+                peer.ifidx,
+                their_localid,
+                light_client_details,
+                throttle,
+            )
+        };
+        let (new_key, mut tunnel) = match create() {
+            Ok(val) => val,
+            Err(e) => {
+                // most likely a stale interface in the way, clear those out and try once more
+                warn!("Failed to create tunnel {:?}, reconciling interfaces", e);
+                self.reconcile_interfaces();
+                create()?
+            }
+        };
+        if tunnel.light_client_details.is_none() {
+            let prober = self.prober();
+            tunnel.tune_mtu(&prober);
+        }
 
         self.tunnels
             .entry(new_key)
             .or_insert_with(Vec::new)
             .push(tunnel.clone());
+        // this attaches babel to the new tunnel if it gets a cost hint, so it's only monitored
+        // below when it doesn't, otherwise babel would be sent the interface twice
+        self.update_rxcost_hints(&new_key);
+        let tunnel = self.tunnels[&new_key].last().cloned().unwrap();
+        if tunnel.light_client_details.is_none() && tunnel.rxcost.is_none() {
+            // the argument indicates that this is attempt zero
+            tunnel.monitor(0);
+        }
         Ok((tunnel, return_bool))
     }
+
+    /// Recomputes the babel cost hints for the tunnels to a neighbor, from multipath, each
+    /// tunnel's latency, the neighbor's reputation and whether its routes are refused for their
+    /// price, and pushes any changes to babel. Traffic accounting needs no
+    /// special handling, it's done per identity so bytes over any member tunnel are billed to
+    /// the same neighbor.
+    fn update_rxcost_hints(&mut self, key: &Identity) {
+        let network = SETTING.get_network();
+        let unshaped_speed = network.starting_bandwidth_limit;
+        let max_penalty = network.babel.max_reputation_penalty;
+        drop(network);
+        let penalty = self.reputation.get(key).penalty(max_penalty);
+        let price_refused = self.price_refused.contains(key);
+        let tunnels = match self.tunnels.get_mut(key) {
+            Some(tunnels) => tunnels,
+            None => return,
+        };
+        let costs = multipath_rxcosts(tunnels, unshaped_speed);
+        for (tunnel, cost) in tunnels.iter_mut().zip(costs) {
+            let cost = match tunnel.light_client_details {
+                Some(_) => cost,
+                None if price_refused => Some(
+                    penalize(add_rtt_penalty(cost, tunnel.rtt_penalty), penalty)
+                        .map_or(PRICE_REFUSAL_RXCOST, |cost| cost.max(PRICE_REFUSAL_RXCOST)),
+                ),
+                None => penalize(add_rtt_penalty(cost, tunnel.rtt_penalty), penalty),
+            };
+            // babel merges interface options, so a tunnel that no longer has siblings
+            // or a penalty has to be explicitly put back to the base cost
+            let cost = cost.or_else(|| tunnel.rxcost.map(|_| MULTIPATH_BASE_RXCOST));
+            if cost != tunnel.rxcost {
+                info!(
+                    "Setting babel rxcost of {} to {:?}, rtt penalty {} reputation penalty {}%",
+                    tunnel.iface_name, cost, tunnel.rtt_penalty, penalty
+                );
+                tunnel.rxcost = cost;
+                tunnel.monitor(0);
+            }
+        }
+    }
 }
 
 fn create_new_tunnel(
@@ -980,16 +1725,18 @@ fn create_new_tunnel(
     ifidx: u32,
     their_localid: LocalIdentity,
     light_client_details: Option<Ipv4Addr>,
+    throttle: Option<usize>,
 ) -> Result<(Identity, Tunnel), Error> {
     // Create new tunnel
-    let tunnel = Tunnel::new(
+    let mut tunnel = Tunnel::new(
         peer_ip,
-        KI.setup_wg_if().unwrap(),
+        KI.setup_wg_if()?,
         our_port,
         ifidx,
         their_localid,
         light_client_details,
     );
+    tunnel.throttle = throttle;
     let new_key = tunnel.neigh_id.global;
 
     // actually create the tunnel
@@ -1000,13 +1747,7 @@ fn create_new_tunnel(
             return Err(e);
         }
     }
-    match light_client_details {
-        None => {
-            // attach babel, the argument indicates that this is attempt zero
-            tunnel.monitor(0);
-        }
-        Some(_) => {}
-    }
+    // babel is attached by the caller once the tunnel's rxcost hint is known
     Ok((new_key, tunnel))
 }
 
@@ -1015,8 +1756,63 @@ pub struct TunnelChange {
     pub action: TunnelAction,
 }
 
+/// The tunnels whose neighbors' routes are made more expensive to babel, all of them each time
+/// so that a tunnel that's been recreated under another name is picked up. A babel filter would
+/// drop the routes outright, but babel has no way to take one back, while the rxcost is put back
+/// like any other hint and re-sent along with the rest when babel restarts. It applies to all of
+/// a neighbor's routes, not just the one to our exit
+pub struct RefuseRoutesVia(pub Vec<String>);
+
+impl Message for RefuseRoutesVia {
+    type Result = ();
+}
+
+impl Handler<RefuseRoutesVia> for TunnelManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: RefuseRoutesVia, _: &mut Context<Self>) -> Self::Result {
+        let refused: HashSet<Identity> = self
+            .tunnels
+            .iter()
+            .filter(|(_, tunnels)| tunnels.iter().any(|t| msg.0.contains(&t.iface_name)))
+            .map(|(id, _)| *id)
+            .collect();
+        let changed: Vec<Identity> = refused
+            .symmetric_difference(&self.price_refused)
+            .cloned()
+            .collect();
+        self.price_refused = refused;
+        for id in changed {
+            self.update_rxcost_hints(&id);
+        }
+    }
+}
+
+/// Has babel pick up changed interface parameters on every tunnel it's monitoring
+pub struct RefreshBabelInterfaces;
+
+impl Message for RefreshBabelInterfaces {
+    type Result = ();
+}
+
+impl Handler<RefreshBabelInterfaces> for TunnelManager {
+    type Result = ();
+
+    fn handle(&mut self, _: RefreshBabelInterfaces, _: &mut Context<Self>) -> Self::Result {
+        for tunnel in self.tunnels.values().flatten() {
+            if tunnel.light_client_details.is_none()
+                && tunnel.state.registration_state == RegistrationState::Registered
+            {
+                tunnel.monitor(0);
+            }
+        }
+    }
+}
+
 pub struct TunnelStateChange {
     pub tunnels: Vec<TunnelChange>,
+    /// the latest counter divergence of each neighbor in percent, for their reputation
+    pub divergence: HashMap<Identity, f64>,
 }
 
 impl Message for TunnelStateChange {
@@ -1028,12 +1824,37 @@ impl Handler<TunnelStateChange> for TunnelManager {
     type Result = Result<(), Error>;
 
     fn handle(&mut self, msg: TunnelStateChange, _: &mut Context<Self>) -> Self::Result {
+        let mut samples = HashMap::new();
+        for change in msg.tunnels.iter() {
+            let paid_on_time = match change.action {
+                TunnelAction::PaymentOverdue => false,
+                _ => true,
+            };
+            samples.insert(
+                change.identity,
+                ReputationSample {
+                    paid_on_time,
+                    divergence: msg.divergence.get(&change.identity).cloned(),
+                    tunnel_up: self.tunnels.contains_key(&change.identity),
+                },
+            );
+        }
+
         for tunnel in msg.tunnels {
             let res = tunnel_state_change(tunnel, &mut self.tunnels);
             if res.is_err() {
                 error!("Tunnel state change failed with {:?}", res);
             }
         }
+
+        let max_penalty = SETTING.get_network().babel.max_reputation_penalty;
+        for id in self.reputation.update(&samples, max_penalty) {
+            self.update_rxcost_hints(&id);
+        }
+
+        if has_subscribers() {
+            LiveUpdates::from_registry().do_send(Publish::Neighbors(self.neighbors()));
+        }
         Ok(())
     }
 }
@@ -1148,6 +1969,19 @@ fn tunnel_state_change(
     Ok(())
 }
 
+/// Without IPv4 on our WAN IPv4 addresses are only reachable through 464XLAT, so if a manual
+/// peer has AAAA records those are the only ones we contact
+fn prefer_ipv6(addresses: Vec<SocketAddr>, ipv6_only_wan: bool) -> Vec<SocketAddr> {
+    if ipv6_only_wan && addresses.iter().any(|address| address.is_ipv6()) {
+        addresses
+            .into_iter()
+            .filter(|address| address.is_ipv6())
+            .collect()
+    } else {
+        addresses
+    }
+}
+
 /// Takes the tunnels list and iterates over it to update all of the traffic control settings
 /// since we can't figure out how to combine interfaces badnwidth budgets we're subdividing it
 /// here with manual terminal commands whenever there is a change
@@ -1162,10 +1996,11 @@ fn tunnel_bw_limit_update(tunnels: &HashMap<Identity, Vec<Tunnel>>) -> Result<()
             }
         }
     }
+    let free_tier_throughput = schedule::free_tier_throughput();
     let bw_per_iface = if limited_interfaces > 0 {
-        SETTING.get_payment().free_tier_throughput / u32::from(limited_interfaces)
+        free_tier_throughput / u32::from(limited_interfaces)
     } else {
-        SETTING.get_payment().free_tier_throughput
+        free_tier_throughput
     };
 
     for sublist in tunnels.iter() {
@@ -1177,7 +2012,7 @@ fn tunnel_bw_limit_update(tunnels: &HashMap<Identity, Vec<Tunnel>>) -> Result<()
             if *payment_state == PaymentState::Overdue {
                 KI.set_classless_limit(iface_name, bw_per_iface)?;
             } else if *payment_state == PaymentState::Paid && has_limit {
-                KI.set_codel_shaping(iface_name, None)?;
+                set_tunnel_shaping(iface_name, tunnel.shaping_limit())?;
             }
         }
     }
@@ -1186,6 +2021,7 @@ fn tunnel_bw_limit_update(tunnels: &HashMap<Identity, Vec<Tunnel>>) -> Result<()
 
 #[cfg(test)]
 mod tests {
+    use crate::rita_common::tunnel_manager::multipath_rxcosts;
     use crate::rita_common::tunnel_manager::RegistrationState;
     use crate::rita_common::tunnel_manager::Tunnel;
     use crate::rita_common::tunnel_manager::TunnelManager;
@@ -1208,6 +2044,39 @@ mod tests {
         assert_eq!(tunnel_manager.free_ports.pop().unwrap(), 65534);
     }
 
+    #[test]
+    pub fn test_prefer_ipv6() {
+        use crate::rita_common::tunnel_manager::prefer_ipv6;
+        use std::net::SocketAddr;
+
+        let v4: SocketAddr = "203.0.113.1:4876".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:4876".parse().unwrap();
+        assert_eq!(prefer_ipv6(vec![v4, v6], true), vec![v6]);
+        assert_eq!(prefer_ipv6(vec![v4, v6], false), vec![v4, v6]);
+        // better than nothing, we may have 464XLAT
+        assert_eq!(prefer_ipv6(vec![v4], true), vec![v4]);
+    }
+
+    #[test]
+    pub fn test_port_range() {
+        use crate::rita_common::tunnel_manager::PortRange;
+        use settings::network::NetworkSettings;
+        use std::collections::HashSet;
+
+        let mut network = NetworkSettings::default();
+        network.wg_start_port = 4870;
+        network.wg_end_port = 4880;
+        network.wg_reserved_ports = vec![4871];
+        let range = PortRange::from_settings(&network);
+        let mut in_use = HashSet::new();
+        in_use.insert(4872);
+        // contact, hello, dashboard and the light client hello port are excluded
+        assert_eq!(range.free_ports(&in_use), vec![4870, 4873, 4875, 4879]);
+        assert!(range.contains(4872));
+        assert!(!range.contains(4877));
+        assert!(!range.contains(4880));
+    }
+
     #[test]
     pub fn test_tunnel_manager_lookup() {
         use clarity::Address;
@@ -1240,6 +2109,9 @@ mod tests {
                     wg_port: 65535,
                     have_tunnel: Some(true),
                     global: id,
+                    local_fee: None,
+                    auth: None,
+                    features: FeatureFlags::default(),
                 },
                 None,
             ));
@@ -1266,4 +2138,223 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    pub fn test_take_timed_out() {
+        use crate::rita_common::tunnel_manager::take_timed_out;
+        use clarity::Address;
+        use std::collections::HashMap;
+        use std::str::FromStr;
+        use std::time::{Duration, Instant};
+
+        let id = Identity::new(
+            "0.0.0.0".parse().unwrap(),
+            Address::from_str("ffffffffffffffffffffffffffffffffffffffff").unwrap(),
+            "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+            None,
+        );
+        let new_tunnel = |ifidx: u32, age: u64| {
+            let mut tunnel = Tunnel::new(
+                "0.0.0.0".parse().unwrap(),
+                format!("wg{}", ifidx),
+                65535,
+                ifidx,
+                LocalIdentity {
+                    wg_port: 65535,
+                    have_tunnel: Some(true),
+                    global: id,
+                    local_fee: None,
+                    auth: None,
+                    features: FeatureFlags::default(),
+                },
+                None,
+            );
+            tunnel.last_contact = Instant::now() - Duration::from_secs(age);
+            tunnel
+        };
+        let timeout = Duration::from_secs(100);
+
+        let mut tunnels = HashMap::new();
+        tunnels.insert(
+            id,
+            vec![new_tunnel(0, 500), new_tunnel(1, 0), new_tunnel(2, 500)],
+        );
+        // capped, the rest is left for next time
+        let removed = take_timed_out(&mut tunnels, timeout, 1);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].1.iface_name, "wg0");
+        assert_eq!(tunnels[&id].len(), 2);
+
+        let removed = take_timed_out(&mut tunnels, timeout, 10);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(tunnels[&id].len(), 1);
+        assert_eq!(tunnels[&id][0].iface_name, "wg1");
+
+        // identities without tunnels are dropped entirely
+        tunnels.get_mut(&id).unwrap()[0].last_contact = Instant::now() - timeout;
+        assert_eq!(take_timed_out(&mut tunnels, timeout, 10).len(), 1);
+        assert!(tunnels.is_empty());
+    }
+
+    #[test]
+    pub fn test_take_tunnels() {
+        use crate::rita_common::tunnel_manager::take_tunnels;
+        use clarity::Address;
+        use std::collections::HashMap;
+        use std::str::FromStr;
+
+        let id = Identity::new(
+            "0.0.0.0".parse().unwrap(),
+            Address::from_str("ffffffffffffffffffffffffffffffffffffffff").unwrap(),
+            "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+            None,
+        );
+        let new_tunnel = |ifidx: u32, ip: &str| {
+            Tunnel::new(
+                ip.parse().unwrap(),
+                format!("wg{}", ifidx),
+                65535,
+                ifidx,
+                LocalIdentity {
+                    wg_port: 65535,
+                    have_tunnel: Some(true),
+                    global: id,
+                    local_fee: None,
+                    auth: None,
+                    features: FeatureFlags::default(),
+                },
+                None,
+            )
+        };
+
+        let mut tunnels = HashMap::new();
+        tunnels.insert(
+            id,
+            vec![
+                new_tunnel(1, "fe80::1"),
+                new_tunnel(2, "fe80::1"),
+                new_tunnel(1, "fe80::2"),
+            ],
+        );
+        assert!(take_tunnels(&mut tunnels, &id, 3).is_empty());
+        // every tunnel over the interface goes, the others are left alone
+        assert_eq!(take_tunnels(&mut tunnels, &id, 1).len(), 2);
+        assert_eq!(tunnels[&id].len(), 1);
+        assert_eq!(tunnels[&id][0].listen_ifidx, 2);
+        assert_eq!(take_tunnels(&mut tunnels, &id, 2).len(), 1);
+        assert!(tunnels.is_empty());
+    }
+
+    #[test]
+    pub fn test_take_neighbor_tunnels() {
+        use crate::rita_common::tunnel_manager::take_neighbor_tunnels;
+        use clarity::Address;
+        use std::collections::HashMap;
+        use std::str::FromStr;
+
+        let identity = |mesh_ip: &str, key: &str| {
+            Identity::new(
+                mesh_ip.parse().unwrap(),
+                Address::from_str("ffffffffffffffffffffffffffffffffffffffff").unwrap(),
+                key.parse().unwrap(),
+                None,
+            )
+        };
+        let paused_key = "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk=";
+        let other_key = "Ha2YlTfDimJNboqxOSCh6M29W/H0jKtB4utitjaTO3A=";
+        let new_tunnel = |ifidx: u32, id: Identity| {
+            Tunnel::new(
+                "fe80::1".parse().unwrap(),
+                format!("wg{}", ifidx),
+                65535,
+                ifidx,
+                LocalIdentity {
+                    wg_port: 65535,
+                    have_tunnel: Some(true),
+                    global: id,
+                    local_fee: None,
+                    auth: None,
+                    features: FeatureFlags::default(),
+                },
+                None,
+            )
+        };
+
+        // the same key under two mesh ips, as when a neighbor changes its mesh ip
+        let paused = identity("fd00::1", paused_key);
+        let paused_moved = identity("fd00::2", paused_key);
+        let other = identity("fd00::3", other_key);
+        let mut tunnels = HashMap::new();
+        tunnels.insert(paused, vec![new_tunnel(1, paused), new_tunnel(2, paused)]);
+        tunnels.insert(paused_moved, vec![new_tunnel(3, paused_moved)]);
+        tunnels.insert(other, vec![new_tunnel(1, other)]);
+
+        let key = paused_key.parse().unwrap();
+        assert_eq!(take_neighbor_tunnels(&mut tunnels, &key).len(), 3);
+        assert_eq!(tunnels.len(), 1);
+        assert!(tunnels.contains_key(&other));
+        assert!(take_neighbor_tunnels(&mut tunnels, &key).is_empty());
+    }
+
+    #[test]
+    pub fn test_multipath_rxcosts() {
+        use clarity::Address;
+        use std::str::FromStr;
+
+        let id = Identity::new(
+            "0.0.0.0".parse().unwrap(),
+            Address::from_str("ffffffffffffffffffffffffffffffffffffffff").unwrap(),
+            "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+            None,
+        );
+        let new_tunnel = |ifidx: u32, speed_limit: Option<usize>| {
+            let mut tunnel = Tunnel::new(
+                "0.0.0.0".parse().unwrap(),
+                format!("wg{}", ifidx),
+                65535,
+                ifidx,
+                LocalIdentity {
+                    wg_port: 65535,
+                    have_tunnel: Some(true),
+                    global: id,
+                    local_fee: None,
+                    auth: None,
+                    features: FeatureFlags::default(),
+                },
+                None,
+            );
+            tunnel.speed_limit = speed_limit;
+            tunnel
+        };
+
+        // a lone tunnel is left alone
+        assert_eq!(
+            multipath_rxcosts(&[new_tunnel(0, None)], 10_000),
+            vec![None]
+        );
+
+        // equal links are weighted equally
+        let tunnels = vec![new_tunnel(0, None), new_tunnel(1, None)];
+        assert_eq!(
+            multipath_rxcosts(&tunnels, 10_000),
+            vec![Some(96), Some(96)]
+        );
+
+        // a link shaped to half the speed costs twice as much, and a badly shaped one is capped
+        let tunnels = vec![
+            new_tunnel(0, None),
+            new_tunnel(1, Some(5_000)),
+            new_tunnel(2, Some(50)),
+        ];
+        assert_eq!(
+            multipath_rxcosts(&tunnels, 10_000),
+            vec![Some(96), Some(192), Some(1024)]
+        );
+    }
 }
@@ -0,0 +1,79 @@
+//! Probes path mtus for TunnelManager. A probe is a ping per candidate size with a second to
+//! answer each, so a link that drops the large ones takes several seconds to find, far too long
+//! to spend in TunnelManager's own context with hellos waiting. Probes are sent here instead, a
+//! sync actor with a thread of its own, and the mtu that was set is reported back.
+
+use super::TunnelManager;
+use crate::KI;
+use actix::{Actor, Context, Handler, Message, SyncContext, SystemService};
+use std::net::IpAddr;
+
+pub struct MtuProber;
+
+impl Actor for MtuProber {
+    type Context = SyncContext<Self>;
+}
+
+/// Probes the path to the other end of a tunnel and sets the interface mtu to fit
+pub struct ProbeMtu {
+    pub iface: String,
+    pub ip: IpAddr,
+    /// what the interface is set to now, so that it's not set again for nothing
+    pub mtu: Option<u16>,
+}
+
+impl Message for ProbeMtu {
+    type Result = ();
+}
+
+impl Handler<ProbeMtu> for MtuProber {
+    type Result = ();
+
+    fn handle(&mut self, msg: ProbeMtu, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        let phy_name = KI.get_device_name(msg.ip).ok();
+        let path_mtu = match KI.probe_path_mtu(&msg.ip, phy_name.as_ref().map(|s| s.as_str())) {
+            Ok(val) => val,
+            Err(e) => {
+                warn!("Failed to probe mtu for {} with {:?}", msg.iface, e);
+                return;
+            }
+        };
+        let mtu = KI.wg_mtu_for_path(path_mtu, &msg.ip);
+        if msg.mtu == Some(mtu) {
+            return;
+        }
+        match KI.set_mtu(&msg.iface, mtu) {
+            Ok(_) => {
+                info!("Set mtu of {} to {}", msg.iface, mtu);
+                TunnelManager::from_registry().do_send(MtuSet {
+                    iface: msg.iface,
+                    mtu,
+                });
+            }
+            Err(e) => error!("Failed to set mtu for {} with {:?}", msg.iface, e),
+        }
+    }
+}
+
+/// Records the mtu the prober set on a tunnel's interface
+pub struct MtuSet {
+    pub iface: String,
+    pub mtu: u16,
+}
+
+impl Message for MtuSet {
+    type Result = ();
+}
+
+impl Handler<MtuSet> for TunnelManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: MtuSet, _ctx: &mut Context<Self>) -> Self::Result {
+        // the tunnel may have been torn down while it was being probed
+        for tunnel in self.tunnels.values_mut().flatten() {
+            if tunnel.iface_name == msg.iface {
+                tunnel.mtu = Some(msg.mtu);
+            }
+        }
+    }
+}
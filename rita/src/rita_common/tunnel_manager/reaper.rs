@@ -0,0 +1,37 @@
+//! Deletes wg interfaces for TunnelManager. Deleting an interface shells out and can take a good
+//! fraction of a second on a busy router, doing that for hundreds of timed out tunnels in
+//! TunnelManager's own context stalls hello processing until it's done. Instead deletions are
+//! batched up and sent here, a sync actor with a thread of its own where blocking is fine.
+
+use super::{PortCallback, Tunnel, TunnelManager};
+use crate::KI;
+use actix::{Actor, Handler, Message, SyncContext, SystemService};
+
+pub struct InterfaceReaper;
+
+impl Actor for InterfaceReaper {
+    type Context = SyncContext<Self>;
+}
+
+/// Deletes the interfaces of these tunnels and returns their ports to TunnelManager
+pub struct DeleteInterfaces(pub Vec<Tunnel>);
+
+impl Message for DeleteInterfaces {
+    type Result = ();
+}
+
+impl Handler<DeleteInterfaces> for InterfaceReaper {
+    type Result = ();
+
+    fn handle(&mut self, msg: DeleteInterfaces, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        trace!("Deleting {} tunnel interfaces", msg.0.len());
+        for tunnel in msg.0 {
+            if let Err(e) = KI.del_interface(&tunnel.iface_name) {
+                error!("Failed to delete wg interface! {:?}", e);
+            }
+            // only once the interface is gone, so that the port isn't handed out while
+            // it's still bound
+            TunnelManager::from_registry().do_send(PortCallback(tunnel.listen_port));
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Brings the wg interfaces on the router in line with the tunnels TunnelManager knows about.
+//! Interfaces outlive Rita, after a crash the old wgN interfaces are still there and interfaces
+//! we failed to configure halfway are left behind, either can make the next tunnel setup fail
+//! over and over. This runs at startup and whenever setting up a tunnel fails: interfaces whose
+//! peer is a neighbor with a tunnel that lost its own interface are adopted by that tunnel, any
+//! other interface we don't know is deleted.
+
+use super::{Tunnel, TunnelManager};
+use crate::KI;
+use althea_types::{Identity, WgKey};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Reconciliation {
+    /// tunnels to move from the missing interface to an existing one, (missing, existing)
+    pub adopt: Vec<(String, String)>,
+    /// interfaces no tunnel accounts for
+    pub delete: Vec<String>,
+}
+
+/// Works out what to do with the existing interfaces, given with the keys of their peers
+pub fn plan_reconciliation(
+    existing: &[(String, Vec<WgKey>)],
+    tunnels: &HashMap<Identity, Vec<Tunnel>>,
+) -> Reconciliation {
+    let existing_names: HashSet<&str> = existing.iter().map(|(name, _)| name.as_str()).collect();
+    // tunnels whose interface is gone, the only ones that can adopt
+    let mut orphans: Vec<&Tunnel> = tunnels
+        .values()
+        .flatten()
+        .filter(|t| !existing_names.contains(t.iface_name.as_str()))
+        .collect();
+    let known: HashSet<&str> = tunnels
+        .values()
+        .flatten()
+        .map(|t| t.iface_name.as_str())
+        .collect();
+
+    let mut plan = Reconciliation::default();
+    for (iface, peers) in existing {
+        if known.contains(iface.as_str()) {
+            continue;
+        }
+        let orphan = orphans
+            .iter()
+            .position(|t| peers.contains(&t.neigh_id.global.wg_public_key));
+        match orphan {
+            Some(i) => {
+                let tunnel = orphans.remove(i);
+                plan.adopt.push((tunnel.iface_name.clone(), iface.clone()));
+            }
+            None => plan.delete.push(iface.clone()),
+        }
+    }
+    plan
+}
+
+impl TunnelManager {
+    pub fn reconcile_interfaces(&mut self) {
+        let existing = match KI.get_wg_tunnels() {
+            Ok(existing) => existing,
+            Err(e) => {
+                error!("Failed to list wg interfaces to reconcile {:?}", e);
+                return;
+            }
+        };
+        let plan = plan_reconciliation(&existing, &self.tunnels);
+        for (missing, iface) in plan.adopt {
+            for tunnel in self.tunnels.values_mut().flatten() {
+                if tunnel.iface_name == missing {
+                    info!("Tunnel {} adopting existing interface {}", missing, iface);
+                    tunnel.iface_name = iface.clone();
+                    if tunnel.light_client_details.is_none() {
+                        tunnel.monitor(0);
+                    }
+                }
+            }
+        }
+        for iface in plan.delete {
+            info!("Deleting stale wg interface {}", iface);
+            if let Err(e) = KI.del_interface(&iface) {
+                error!("Failed to delete stale wg interface {} {:?}", iface, e);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_plan_reconciliation() {
+    use crate::rita_common::utils::test_identity;
+    use althea_types::{FeatureFlags, LocalIdentity};
+
+    let a = test_identity("fd00::1");
+    let mut b = test_identity("fd00::1");
+    b.wg_public_key = "bvM10HW73yePrxdtCQQ4U20W5ogogdiZtUihrPc/oGY="
+        .parse()
+        .unwrap();
+    let tunnel = |id: Identity, iface: &str| {
+        Tunnel::new(
+            "fe80::1".parse().unwrap(),
+            iface.to_string(),
+            65535,
+            1,
+            LocalIdentity {
+                wg_port: 65535,
+                have_tunnel: Some(true),
+                global: id,
+                local_fee: None,
+                auth: None,
+                features: FeatureFlags::default(),
+            },
+            None,
+        )
+    };
+    let mut tunnels = HashMap::new();
+    tunnels.insert(a, vec![tunnel(a, "wg0"), tunnel(a, "wg1")]);
+    tunnels.insert(b, vec![tunnel(b, "wg2")]);
+
+    let existing = vec![
+        ("wg0".to_string(), vec![a.wg_public_key]),
+        ("wg2".to_string(), vec![b.wg_public_key]),
+        // wg1 went missing and its peer now lives on wg5
+        ("wg5".to_string(), vec![a.wg_public_key]),
+        // left over from before a crash
+        ("wg3".to_string(), vec![b.wg_public_key]),
+        // set up but never configured
+        ("wg4".to_string(), vec![]),
+    ];
+    let plan = plan_reconciliation(&existing, &tunnels);
+    // b's tunnel still has its interface and so doesn't adopt wg3
+    assert_eq!(plan.adopt, vec![("wg1".to_string(), "wg5".to_string())]);
+    assert_eq!(plan.delete, vec!["wg3".to_string(), "wg4".to_string()]);
+
+    // nothing is known at startup
+    let plan = plan_reconciliation(&existing, &HashMap::new());
+    assert!(plan.adopt.is_empty());
+    assert_eq!(plan.delete.len(), 5);
+}
@@ -0,0 +1,177 @@
+//! Keeps a reputation score for every neighbor we bill, so that routes can prefer neighbors that
+//! pay on time, count traffic the same way we do and keep their tunnels up. Each of those is
+//! tracked as a moving average fed once per DebtKeeper round, the score combines them into a
+//! number between 0 and 1. Neighbors scoring below a tolerance have the babel cost of their
+//! tunnels raised in proportion, this only ever makes routes through them less attractive and
+//! never cuts a neighbor off.
+
+use althea_types::Identity;
+use std::collections::HashMap;
+
+/// How much one round moves an average, with a round per rita loop tick this reflects about the
+/// last ten minutes
+const SAMPLE_WEIGHT: f64 = 0.01;
+/// Counters this far apart count as fully dishonest, small differences are expected since
+/// neighbors don't sample their counters at exactly the same moment
+const MAX_DIVERGENCE_PERCENT: f64 = 20.0;
+/// At or above this score there's no penalty, so that the odd late payment doesn't move routes
+const PENALTY_FREE_SCORE: f64 = 0.9;
+/// Penalties move in steps of this many percent so that babel isn't told about every change
+const PENALTY_STEP: u16 = 25;
+/// The babel rxcost of a tunnel without a hint of its own, babel's default for wired links
+pub const DEFAULT_RXCOST: u16 = 96;
+
+/// What we learned about a neighbor in a round
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReputationSample {
+    /// false when they are past the close threshold
+    pub paid_on_time: bool,
+    /// how far apart our traffic counts were in the last window we compared, if any
+    pub divergence: Option<f64>,
+    pub tunnel_up: bool,
+}
+
+/// Moving averages between 0 and 1, neighbors start out with a perfect record
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ReputationRecord {
+    pub punctuality: f64,
+    pub honesty: f64,
+    pub uptime: f64,
+}
+
+impl Default for ReputationRecord {
+    fn default() -> Self {
+        ReputationRecord {
+            punctuality: 1.0,
+            honesty: 1.0,
+            uptime: 1.0,
+        }
+    }
+}
+
+fn average(old: f64, sample: f64) -> f64 {
+    old + (sample - old) * SAMPLE_WEIGHT
+}
+
+fn as_sample(good: bool) -> f64 {
+    if good {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+impl ReputationRecord {
+    fn add(&mut self, sample: ReputationSample) {
+        self.punctuality = average(self.punctuality, as_sample(sample.paid_on_time));
+        self.uptime = average(self.uptime, as_sample(sample.tunnel_up));
+        if let Some(percent) = sample.divergence {
+            let honesty = 1.0 - percent.min(MAX_DIVERGENCE_PERCENT) / MAX_DIVERGENCE_PERCENT;
+            self.honesty = average(self.honesty, honesty);
+        }
+    }
+
+    /// Payment weighs the most, it's the one a neighbor has the most control over
+    pub fn score(&self) -> f64 {
+        0.4 * self.punctuality + 0.3 * self.honesty + 0.3 * self.uptime
+    }
+
+    /// The percentage to raise the cost of this neighbor's tunnels by, at most `max_penalty`
+    pub fn penalty(&self, max_penalty: u16) -> u16 {
+        let score = self.score();
+        if score >= PENALTY_FREE_SCORE || max_penalty == 0 {
+            return 0;
+        }
+        let penalty = (1.0 - score) * f64::from(max_penalty);
+        let steps = (penalty / f64::from(PENALTY_STEP)).round() as u16;
+        (steps * PENALTY_STEP).min(max_penalty)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Reputation {
+    records: HashMap<Identity, ReputationRecord>,
+}
+
+impl Reputation {
+    /// Adds a round of samples, neighbors that aren't in it anymore are forgotten. Returns the
+    /// neighbors whose penalty changed
+    pub fn update(
+        &mut self,
+        samples: &HashMap<Identity, ReputationSample>,
+        max_penalty: u16,
+    ) -> Vec<Identity> {
+        self.records.retain(|id, _| samples.contains_key(id));
+        let mut changed = Vec::new();
+        for (id, sample) in samples {
+            let record = self.records.entry(*id).or_default();
+            let before = record.penalty(max_penalty);
+            record.add(*sample);
+            if record.penalty(max_penalty) != before {
+                changed.push(*id);
+            }
+        }
+        changed
+    }
+
+    pub fn get(&self, id: &Identity) -> ReputationRecord {
+        self.records.get(id).cloned().unwrap_or_default()
+    }
+}
+
+/// Raises a tunnel's rxcost hint by a penalty in percent, tunnels without a hint are raised from
+/// babel's default
+pub fn penalize(rxcost: Option<u16>, penalty: u16) -> Option<u16> {
+    if penalty == 0 {
+        return rxcost;
+    }
+    let cost = u32::from(rxcost.unwrap_or(DEFAULT_RXCOST));
+    let cost = cost * (100 + u32::from(penalty)) / 100;
+    Some(cost.min(u32::from(u16::max_value())) as u16)
+}
+
+#[test]
+fn test_reputation() {
+    use crate::rita_common::utils::test_identity;
+
+    let id = test_identity("fd00::1");
+    let good = ReputationSample {
+        paid_on_time: true,
+        divergence: Some(1.0),
+        tunnel_up: true,
+    };
+    let bad = ReputationSample {
+        paid_on_time: false,
+        divergence: Some(50.0),
+        tunnel_up: false,
+    };
+    let mut samples = HashMap::new();
+    let mut reputation = Reputation::default();
+    assert_eq!(reputation.get(&id).score(), 1.0);
+
+    samples.insert(id, good);
+    for _ in 0..100 {
+        assert!(reputation.update(&samples, 300).is_empty());
+    }
+    assert_eq!(reputation.get(&id).penalty(300), 0);
+
+    // a chronically bad neighbor is penalized, in steps
+    samples.insert(id, bad);
+    let mut changes = 0;
+    for _ in 0..500 {
+        changes += reputation.update(&samples, 300).len();
+    }
+    let record = reputation.get(&id);
+    assert!(record.score() < 0.05);
+    assert_eq!(record.penalty(300), 300);
+    assert_eq!(changes, 12);
+    assert_eq!(record.penalty(0), 0);
+
+    // and forgotten once it's gone
+    assert!(reputation.update(&HashMap::new(), 300).is_empty());
+    assert_eq!(reputation.get(&id).score(), 1.0);
+
+    assert_eq!(penalize(None, 0), None);
+    assert_eq!(penalize(None, 50), Some(144));
+    assert_eq!(penalize(Some(200), 300), Some(800));
+}
@@ -0,0 +1,114 @@
+//! Following a neighbor whose address changes under an established tunnel. A radio that
+//! reassociates can come back with a new link local address, hellos then arrive from the new
+//! address but the tunnel's wg peer still points at the old one and no traffic flows until the
+//! tunnel times out and is rebuilt from scratch. When a hello comes in from an address we have no
+//! tunnel for, on an interface where we have exactly one tunnel to the same neighbor, and the
+//! neighbor says it still has its tunnel to us, that tunnel is re-pinned to the new address in
+//! place. Anything else, like a neighbor that has dropped its side, goes through the usual setup.
+
+use super::{Tunnel, TunnelManager};
+use crate::rita_common::peer_listener::Peer;
+use crate::KI;
+use althea_types::LocalIdentity;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+
+/// Which of a neighbor's tunnels has roamed to `ip`, if one has
+pub fn find_roamed(
+    tunnels: &[Tunnel],
+    ifidx: u32,
+    ip: IpAddr,
+    they_have_tunnel: bool,
+) -> Option<usize> {
+    if !they_have_tunnel {
+        return None;
+    }
+    let mut on_iface = tunnels
+        .iter()
+        .enumerate()
+        .filter(|(_, tunnel)| tunnel.listen_ifidx == ifidx);
+    match (on_iface.next(), on_iface.next()) {
+        (Some((i, tunnel)), None) if tunnel.ip != ip => Some(i),
+        // with several tunnels on one interface there's no telling which one moved
+        _ => None,
+    }
+}
+
+impl TunnelManager {
+    /// Points the neighbor's tunnel at the address this hello came from if the tunnel has
+    /// roamed there, true if it was re-pinned
+    pub fn repin_roamed_tunnel(&mut self, their_localid: &LocalIdentity, peer: &Peer) -> bool {
+        let ip = peer.contact_socket.ip();
+        let tunnels = match self.tunnels.get_mut(&their_localid.global) {
+            Some(tunnels) => tunnels,
+            None => return false,
+        };
+        // when we don't know take the more conservative option, same as setup
+        let they_have_tunnel = their_localid.have_tunnel.unwrap_or(true);
+        let tunnel = match find_roamed(tunnels, peer.ifidx, ip, they_have_tunnel) {
+            Some(i) => &mut tunnels[i],
+            None => return false,
+        };
+        let endpoint = SocketAddr::new(ip, their_localid.wg_port);
+        if let Err(e) = KI.set_tunnel_endpoint(
+            &tunnel.iface_name,
+            &their_localid.global.wg_public_key,
+            &endpoint,
+        ) {
+            warn!(
+                "Failed to re-pin {} from {} to {} {:?}",
+                tunnel.iface_name, tunnel.ip, ip, e
+            );
+            return false;
+        }
+        info!(
+            "Neighbor {} roamed from {} to {}, re-pinned {}",
+            their_localid.global.mesh_ip, tunnel.ip, ip, tunnel.iface_name
+        );
+        tunnel.ip = ip;
+        tunnel.last_contact = Instant::now();
+        // whatever wg reported for the old address says nothing about the new one
+        tunnel.last_endpoint = None;
+        true
+    }
+}
+
+#[test]
+fn test_find_roamed() {
+    use crate::rita_common::utils::test_identity;
+    use althea_types::FeatureFlags;
+
+    let id = test_identity("fd00::1");
+    let tunnel = |ip: &str, ifidx: u32| {
+        Tunnel::new(
+            ip.parse().unwrap(),
+            format!("wg{}", ifidx),
+            65535,
+            ifidx,
+            LocalIdentity {
+                wg_port: 65535,
+                have_tunnel: Some(true),
+                global: id,
+                local_fee: None,
+                auth: None,
+                features: FeatureFlags::default(),
+            },
+            None,
+        )
+    };
+    let new_ip: IpAddr = "fe80::2".parse().unwrap();
+    let tunnels = vec![tunnel("fe80::1", 1), tunnel("fe80::3", 2)];
+    assert_eq!(find_roamed(&tunnels, 1, new_ip, true), Some(0));
+    // they dropped their side, a new tunnel is set up instead
+    assert_eq!(find_roamed(&tunnels, 1, new_ip, false), None);
+    // no tunnel on that interface
+    assert_eq!(find_roamed(&tunnels, 5, new_ip, true), None);
+    // not a roam at all
+    assert_eq!(
+        find_roamed(&tunnels, 2, "fe80::3".parse().unwrap(), true),
+        None
+    );
+
+    let multipath = vec![tunnel("fe80::1", 1), tunnel("fe80::4", 1)];
+    assert_eq!(find_roamed(&multipath, 1, new_ip, true), None);
+}
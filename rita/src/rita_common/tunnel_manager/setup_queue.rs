@@ -0,0 +1,115 @@
+//! Limits how many new tunnels TunnelManager sets up per tick. Every tunnel costs a handful of
+//! shell commands, when a whole neighborhood comes back at once (say after a power outage) trying
+//! to set them all up in one go stalls TunnelManager and the router along with it. Peers we don't
+//! have a tunnel with yet wait here and are contacted a few at a time over the following ticks,
+//! peers that contact us first are turned away while the tick's budget is spent and retry with
+//! their next hello.
+
+use crate::rita_common::peer_listener::Peer;
+use std::collections::VecDeque;
+
+/// Past this many waiting peers the oldest are dropped, they are queued again if we keep
+/// hearing from them
+const MAX_QUEUED_PEERS: usize = 512;
+
+#[derive(Debug, Default)]
+pub struct SetupQueue {
+    waiting: VecDeque<Peer>,
+    /// tunnel setups left this tick
+    budget: usize,
+    /// inbound setups turned away this tick
+    refused: usize,
+}
+
+impl SetupQueue {
+    /// Starts a tick allowing `limit` new tunnels, 0 is no limit
+    pub fn new_tick(&mut self, limit: usize) {
+        self.budget = if limit == 0 {
+            usize::max_value()
+        } else {
+            limit
+        };
+        self.refused = 0;
+    }
+
+    /// Queues a peer we don't have a tunnel with yet, unless it's already waiting
+    pub fn push(&mut self, peer: Peer) {
+        if self.waiting.contains(&peer) {
+            return;
+        }
+        if self.waiting.len() >= MAX_QUEUED_PEERS {
+            self.waiting.pop_front();
+        }
+        self.waiting.push_back(peer);
+    }
+
+    /// Takes as many waiting peers as the budget allows, peers `has_tunnel` says we have since
+    /// set up a tunnel with (because they contacted us) are dropped without spending any of it
+    pub fn take<F: Fn(&Peer) -> bool>(&mut self, has_tunnel: F) -> Vec<Peer> {
+        let mut peers = Vec::new();
+        while self.budget > 0 {
+            match self.waiting.pop_front() {
+                Some(peer) => {
+                    if !has_tunnel(&peer) {
+                        self.budget -= 1;
+                        peers.push(peer);
+                    }
+                }
+                None => break,
+            }
+        }
+        peers
+    }
+
+    /// Spends budget on a setup a peer asked us for, false if there's none left
+    pub fn reserve(&mut self) -> bool {
+        if self.budget == 0 {
+            self.refused += 1;
+            false
+        } else {
+            self.budget -= 1;
+            true
+        }
+    }
+
+    /// Peers still waiting for a tunnel
+    pub fn len(&self) -> usize {
+        self.waiting.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiting.is_empty()
+    }
+
+    /// Inbound setups turned away since the tick started
+    pub fn refused(&self) -> usize {
+        self.refused
+    }
+}
+
+#[test]
+fn test_setup_queue() {
+    let peer = |n: u16| Peer {
+        ifidx: 1,
+        contact_socket: format!("[fe80::{}]:4876", n).parse().unwrap(),
+    };
+    let mut queue = SetupQueue::default();
+    queue.new_tick(2);
+    for n in 1..=4 {
+        queue.push(peer(n));
+    }
+    queue.push(peer(1));
+    assert_eq!(queue.len(), 4);
+    // peer 2 set up a tunnel with us in the meantime and doesn't count
+    let taken = queue.take(|p| *p == peer(2));
+    assert_eq!(taken, vec![peer(1), peer(3)]);
+    assert_eq!(queue.len(), 1);
+    assert!(!queue.reserve());
+    assert_eq!(queue.refused(), 1);
+
+    queue.new_tick(0);
+    assert_eq!(queue.refused(), 0);
+    assert!(queue.reserve());
+    assert_eq!(queue.take(|_| false), vec![peer(4)]);
+    assert!(queue.is_empty());
+}
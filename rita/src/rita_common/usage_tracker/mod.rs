@@ -4,10 +4,24 @@
 //! the handler updates the storage to reflect the new total. When a user would like to inspect
 //! or graph usage they query an endpoint which will request the data from this module.
 //!
-//! Persistant storage is planned but not currently implemented.
+//! Usage is kept at three resolutions, raw per round samples for the last day, hourly totals
+//! for the last month and daily totals for the last year. New usage goes into the raw and hourly
+//! tiers as it comes in, a periodic compaction drops expired raw samples and rolls old hours up
+//! into days so that the history stays bounded without throwing away the long term picture.
+//! Raw samples are only kept in memory, everything else is saved to disk periodically.
+//!
+//! Relayed traffic is also kept hourly per neighbor for as long as the hourly totals, for working
+//! out per neighbor allowances like the free tier after the fact.
+//!
+//! The transaction fees paid for each on chain settlement are tracked here as well, hourly and
+//! as a running total, so that operators can see what settling costs them next to what they
+//! pay for bandwidth.
 
+use crate::rita_common::live_updates::{has_subscribers, LiveUpdates, Publish, UsageSample};
+use crate::rita_common::utils::now_secs;
 use crate::SETTING;
 use actix::Actor;
+use actix::AsyncContext;
 use actix::Context;
 use actix::Handler;
 use actix::Message;
@@ -23,7 +37,7 @@ use num256::Uint256;
 use serde::{Deserialize, Serialize};
 use serde_json::Error as SerdeError;
 use settings::RitaCommonSettings;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::fs::File;
 use std::io;
 use std::io::Error as IOError;
@@ -31,13 +45,23 @@ use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
 use std::io::Write;
+use std::net::IpAddr;
+use std::time::Duration;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 
-/// On year worth of usage storage
+/// On year worth of payment storage
 const MAX_ENTRIES: usize = 8760;
 /// Save every 4 hours
 const SAVE_FREQENCY: u64 = 4;
+/// How long raw per round samples are kept, in seconds
+const RAW_RETENTION: u64 = 24 * 60 * 60;
+/// How long hourly totals are kept before being rolled up into days, in hours
+pub const HOURLY_RETENTION: u64 = 30 * 24;
+/// How long daily totals are kept, in days
+const DAILY_RETENTION: u64 = 365;
+/// How often expired samples are dropped and old hours are rolled up
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(600);
 
 /// In an effort to converge this module between the three possible bw tracking
 /// use cases this enum is used to identify which sort of usage we are tracking
@@ -47,16 +71,44 @@ pub enum UsageType {
     Client,
     Relay,
     Exit,
+    /// traffic on the sponsored network, a subset of client traffic
+    Sponsored,
+}
+
+/// The resolution usage history is requested at
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UsageGranularity {
+    Raw,
+    Hour,
+    Day,
+}
+
+impl Default for UsageGranularity {
+    fn default() -> UsageGranularity {
+        UsageGranularity::Hour
+    }
 }
 
 /// A struct for tracking each hour of usage, indexed by time in hours since
-/// the unix epoch
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+/// the unix epoch. Also used for raw samples, indexed in seconds, and daily
+/// rollups, indexed in days.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct UsageHour {
-    index: u64,
-    up: u64,
-    down: u64,
-    price: u32,
+    pub index: u64,
+    pub up: u64,
+    pub down: u64,
+    pub price: u32,
+}
+
+/// Relayed bytes in an hour by the neighbor that sent them to us, indexed in hours since the
+/// unix epoch
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NeighborUsageHour {
+    pub index: u64,
+    pub price: u32,
+    /// keyed by the neighbor's mesh ip
+    pub down: HashMap<IpAddr, u64>,
 }
 
 /// A version of payment tx with a string txid so that the formatting is correct
@@ -90,8 +142,27 @@ fn to_formatted_payment_tx(input: PaymentTx) -> FormattedPaymentTx {
 /// A struct for tracking each hours of paymetns indexed in hours since unix epoch
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PaymentHour {
-    index: u64,
-    payments: Vec<FormattedPaymentTx>,
+    pub index: u64,
+    pub payments: Vec<FormattedPaymentTx>,
+}
+
+/// Transaction fees paid in an hour, indexed in hours since unix epoch
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FeeHour {
+    pub index: u64,
+    /// wei paid in fees
+    pub fees: Uint256,
+    /// on chain payments made
+    pub settlements: u32,
+}
+
+/// Fee spend since we started keeping track
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct FeeTotals {
+    /// wei paid in fees
+    pub fees: Uint256,
+    /// on chain payments made
+    pub settlements: u64,
 }
 
 /// The main actor that holds the usage state for the duration of operations
@@ -103,8 +174,34 @@ pub struct UsageTracker {
     client_bandwith: VecDeque<UsageHour>,
     relay_bandwith: VecDeque<UsageHour>,
     exit_bandwith: VecDeque<UsageHour>,
+    #[serde(skip)]
+    client_raw: VecDeque<UsageHour>,
+    #[serde(skip)]
+    relay_raw: VecDeque<UsageHour>,
+    #[serde(skip)]
+    exit_raw: VecDeque<UsageHour>,
+    #[serde(default)]
+    client_daily: VecDeque<UsageHour>,
+    #[serde(default)]
+    relay_daily: VecDeque<UsageHour>,
+    #[serde(default)]
+    exit_daily: VecDeque<UsageHour>,
+    #[serde(default)]
+    sponsored_bandwith: VecDeque<UsageHour>,
+    #[serde(skip)]
+    sponsored_raw: VecDeque<UsageHour>,
+    #[serde(default)]
+    sponsored_daily: VecDeque<UsageHour>,
+    #[serde(default)]
+    relay_neighbors: VecDeque<NeighborUsageHour>,
     /// A history of payments
     payments: VecDeque<PaymentHour>,
+    /// A history of the fees paid settling payments
+    #[serde(default)]
+    fees: VecDeque<FeeHour>,
+    /// Fees paid over the whole history, unlike `fees` these are never dropped
+    #[serde(default)]
+    fee_totals: FeeTotals,
 }
 
 impl Default for UsageTracker {
@@ -116,7 +213,19 @@ impl Default for UsageTracker {
             client_bandwith: VecDeque::new(),
             relay_bandwith: VecDeque::new(),
             exit_bandwith: VecDeque::new(),
+            client_raw: VecDeque::new(),
+            relay_raw: VecDeque::new(),
+            exit_raw: VecDeque::new(),
+            client_daily: VecDeque::new(),
+            relay_daily: VecDeque::new(),
+            exit_daily: VecDeque::new(),
+            sponsored_bandwith: VecDeque::new(),
+            sponsored_raw: VecDeque::new(),
+            sponsored_daily: VecDeque::new(),
+            relay_neighbors: VecDeque::new(),
             payments: VecDeque::new(),
+            fees: VecDeque::new(),
+            fee_totals: FeeTotals::default(),
         };
 
         match file {
@@ -196,10 +305,85 @@ impl UsageTracker {
         let compressed_bytes = encoder.finish()?;
         file.write_all(&compressed_bytes)
     }
+
+    /// Drops raw samples older than a day, rolls hours older than a month up into days and
+    /// drops days older than a year, `now` is in seconds since the unix epoch
+    fn compact(&mut self, now: u64) {
+        let current_hour = now / (60 * 60);
+        let current_day = current_hour / 24;
+        for (raw, hourly, daily) in vec![
+            (
+                &mut self.client_raw,
+                &mut self.client_bandwith,
+                &mut self.client_daily,
+            ),
+            (
+                &mut self.relay_raw,
+                &mut self.relay_bandwith,
+                &mut self.relay_daily,
+            ),
+            (
+                &mut self.exit_raw,
+                &mut self.exit_bandwith,
+                &mut self.exit_daily,
+            ),
+            (
+                &mut self.sponsored_raw,
+                &mut self.sponsored_bandwith,
+                &mut self.sponsored_daily,
+            ),
+        ] {
+            while let Some(sample) = raw.back() {
+                if sample.index + RAW_RETENTION > now {
+                    break;
+                }
+                raw.pop_back();
+            }
+            // the oldest hours are at the back and are newer than anything already rolled up,
+            // so they either go into the newest day or start a new one
+            while let Some(hour) = hourly.back().cloned() {
+                if hour.index + HOURLY_RETENTION > current_hour {
+                    break;
+                }
+                hourly.pop_back();
+                let day = hour.index / 24;
+                match daily.front_mut() {
+                    Some(entry) if entry.index == day => {
+                        entry.up += hour.up;
+                        entry.down += hour.down;
+                        // like hours, price is a sample rather than an average
+                        entry.price = hour.price;
+                    }
+                    _ => daily.push_front(UsageHour {
+                        index: day,
+                        up: hour.up,
+                        down: hour.down,
+                        price: hour.price,
+                    }),
+                }
+            }
+            while let Some(day) = daily.back() {
+                if day.index + DAILY_RETENTION > current_day {
+                    break;
+                }
+                daily.pop_back();
+            }
+        }
+        while let Some(hour) = self.relay_neighbors.back() {
+            if hour.index + HOURLY_RETENTION > current_hour {
+                break;
+            }
+            self.relay_neighbors.pop_back();
+        }
+    }
 }
 
 impl Actor for UsageTracker {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(COMPACTION_INTERVAL, |act, _ctx| act.compact(now_secs()));
+    }
 }
 
 impl Supervised for UsageTracker {}
@@ -232,26 +416,36 @@ impl Message for UpdateUsage {
 impl Handler<UpdateUsage> for UsageTracker {
     type Result = Result<(), Error>;
     fn handle(&mut self, msg: UpdateUsage, _: &mut Context<Self>) -> Self::Result {
-        let current_hour = match get_current_hour() {
-            Ok(hour) => hour,
-            Err(e) => {
-                error!("System time is set earlier than unix epoch! {:?}", e);
-                return Ok(());
-            }
-        };
-        process_usage_update(current_hour, msg, self);
+        process_usage_update(now_secs(), msg, self);
 
+        if has_subscribers() {
+            LiveUpdates::from_registry().do_send(Publish::Usage(UsageSample {
+                kind: msg.kind,
+                time: now,
+                up: msg.up,
+                down: msg.down,
+                price: msg.price,
+            }));
+        }
         Ok(())
     }
 }
 
-fn process_usage_update(current_hour: u64, msg: UpdateUsage, data: &mut UsageTracker) {
+fn process_usage_update(now: u64, msg: UpdateUsage, data: &mut UsageTracker) {
+    let current_hour = now / (60 * 60);
     // history contains a reference to whatever the correct storage array is
-    let history = match msg.kind {
-        UsageType::Client => &mut data.client_bandwith,
-        UsageType::Relay => &mut data.relay_bandwith,
-        UsageType::Exit => &mut data.exit_bandwith,
+    let (history, raw) = match msg.kind {
+        UsageType::Client => (&mut data.client_bandwith, &mut data.client_raw),
+        UsageType::Relay => (&mut data.relay_bandwith, &mut data.relay_raw),
+        UsageType::Exit => (&mut data.exit_bandwith, &mut data.exit_raw),
+        UsageType::Sponsored => (&mut data.sponsored_bandwith, &mut data.sponsored_raw),
     };
+    raw.push_front(UsageHour {
+        index: now,
+        up: msg.up,
+        down: msg.down,
+        price: msg.price,
+    });
     // we grab the front entry from the VecDeque, if there is an entry one we check if it's
     // up to date, if it is we add to it, if it's not or there is no entry we create one.
     // note that price is only sampled once per hour.
@@ -276,9 +470,6 @@ fn process_usage_update(current_hour: u64, msg: UpdateUsage, data: &mut UsageTra
             }
         }
     }
-    while history.len() > MAX_ENTRIES {
-        let _discarded_entry = history.pop_back();
-    }
     if (current_hour - SAVE_FREQENCY) > data.last_save_hour {
         data.last_save_hour = current_hour;
         let res = data.save();
@@ -286,6 +477,38 @@ fn process_usage_update(current_hour: u64, msg: UpdateUsage, data: &mut UsageTra
     }
 }
 
+/// This round's relayed bytes by the neighbor that sent them, from the common traffic watcher
+pub struct UpdateNeighborUsage {
+    pub down: HashMap<IpAddr, u64>,
+    pub price: u32,
+}
+
+impl Message for UpdateNeighborUsage {
+    type Result = ();
+}
+
+impl Handler<UpdateNeighborUsage> for UsageTracker {
+    type Result = ();
+    fn handle(&mut self, msg: UpdateNeighborUsage, _: &mut Context<Self>) -> Self::Result {
+        process_neighbor_usage(now_secs() / (60 * 60), msg, self);
+    }
+}
+
+fn process_neighbor_usage(current_hour: u64, msg: UpdateNeighborUsage, data: &mut UsageTracker) {
+    if data.relay_neighbors.front().map(|entry| entry.index) != Some(current_hour) {
+        // like the totals, price is only sampled once per hour
+        data.relay_neighbors.push_front(NeighborUsageHour {
+            index: current_hour,
+            price: msg.price,
+            down: HashMap::new(),
+        });
+    }
+    let entry = data.relay_neighbors.front_mut().unwrap();
+    for (neighbor, bytes) in msg.down {
+        *entry.down.entry(neighbor).or_insert(0) += bytes;
+    }
+}
+
 pub struct UpdatePayments {
     pub payment: PaymentTx,
 }
@@ -333,8 +556,70 @@ impl Handler<UpdatePayments> for UsageTracker {
     }
 }
 
+/// The fee paid for one on chain payment, sent once the transaction is published
+pub struct UpdateFees {
+    pub fee: Uint256,
+}
+
+impl Message for UpdateFees {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<UpdateFees> for UsageTracker {
+    type Result = Result<(), Error>;
+    fn handle(&mut self, msg: UpdateFees, _: &mut Context<Self>) -> Self::Result {
+        let current_hour = match get_current_hour() {
+            Ok(hour) => hour,
+            Err(e) => {
+                error!("System time is set earlier than unix epoch! {:?}", e);
+                return Ok(());
+            }
+        };
+        process_fee_update(current_hour, msg.fee, self);
+        if (current_hour - SAVE_FREQENCY) > self.last_save_hour {
+            self.last_save_hour = current_hour;
+            let res = self.save();
+            info!("Saving usage data: {:?}", res);
+        }
+        Ok(())
+    }
+}
+
+fn process_fee_update(current_hour: u64, fee: Uint256, data: &mut UsageTracker) {
+    data.fee_totals.fees += fee.clone();
+    data.fee_totals.settlements += 1;
+    match data.fees.front_mut() {
+        Some(entry) if entry.index == current_hour => {
+            entry.fees += fee;
+            entry.settlements += 1;
+        }
+        _ => data.fees.push_front(FeeHour {
+            index: current_hour,
+            fees: fee,
+            settlements: 1,
+        }),
+    }
+    while data.fees.len() > MAX_ENTRIES {
+        let _discarded_entry = data.fees.pop_back();
+    }
+}
+
+pub struct GetFees;
+
+impl Message for GetFees {
+    type Result = Result<(FeeTotals, VecDeque<FeeHour>), Error>;
+}
+
+impl Handler<GetFees> for UsageTracker {
+    type Result = Result<(FeeTotals, VecDeque<FeeHour>), Error>;
+    fn handle(&mut self, _msg: GetFees, _: &mut Context<Self>) -> Self::Result {
+        Ok((self.fee_totals.clone(), self.fees.clone()))
+    }
+}
+
 pub struct GetUsage {
     pub kind: UsageType,
+    pub granularity: UsageGranularity,
 }
 
 impl Message for GetUsage {
@@ -344,14 +629,38 @@ impl Message for GetUsage {
 impl Handler<GetUsage> for UsageTracker {
     type Result = Result<VecDeque<UsageHour>, Error>;
     fn handle(&mut self, msg: GetUsage, _: &mut Context<Self>) -> Self::Result {
-        match msg.kind {
-            UsageType::Client => Ok(self.client_bandwith.clone()),
-            UsageType::Relay => Ok(self.relay_bandwith.clone()),
-            UsageType::Exit => Ok(self.exit_bandwith.clone()),
+        let (raw, hourly, daily) = match msg.kind {
+            UsageType::Client => (&self.client_raw, &self.client_bandwith, &self.client_daily),
+            UsageType::Relay => (&self.relay_raw, &self.relay_bandwith, &self.relay_daily),
+            UsageType::Exit => (&self.exit_raw, &self.exit_bandwith, &self.exit_daily),
+            UsageType::Sponsored => (
+                &self.sponsored_raw,
+                &self.sponsored_bandwith,
+                &self.sponsored_daily,
+            ),
+        };
+        match msg.granularity {
+            UsageGranularity::Raw => Ok(raw.clone()),
+            UsageGranularity::Hour => Ok(hourly.clone()),
+            UsageGranularity::Day => Ok(daily.clone()),
         }
     }
 }
 
+/// Hourly relay usage by neighbor, newest first
+pub struct GetNeighborUsage;
+
+impl Message for GetNeighborUsage {
+    type Result = Result<VecDeque<NeighborUsageHour>, Error>;
+}
+
+impl Handler<GetNeighborUsage> for UsageTracker {
+    type Result = Result<VecDeque<NeighborUsageHour>, Error>;
+    fn handle(&mut self, _msg: GetNeighborUsage, _: &mut Context<Self>) -> Self::Result {
+        Ok(self.relay_neighbors.clone())
+    }
+}
+
 pub struct GetPayments;
 
 impl Message for GetPayments {
@@ -364,3 +673,170 @@ impl Handler<GetPayments> for UsageTracker {
         Ok(self.payments.clone())
     }
 }
+
+/// Returns the full usage history, used for backups
+pub struct GetUsageHistory;
+
+impl Message for GetUsageHistory {
+    type Result = Result<UsageTracker, Error>;
+}
+
+impl Handler<GetUsageHistory> for UsageTracker {
+    type Result = Result<UsageTracker, Error>;
+    fn handle(&mut self, _msg: GetUsageHistory, _: &mut Context<Self>) -> Self::Result {
+        Ok(self.clone())
+    }
+}
+
+/// Replaces the usage history wholesale, used when restoring a backup
+pub struct RestoreUsageHistory(pub UsageTracker);
+
+impl Message for RestoreUsageHistory {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<RestoreUsageHistory> for UsageTracker {
+    type Result = Result<(), Error>;
+    fn handle(&mut self, msg: RestoreUsageHistory, _: &mut Context<Self>) -> Self::Result {
+        *self = msg.0;
+        self.save()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blank() -> UsageTracker {
+        UsageTracker {
+            last_save_hour: 0,
+            client_bandwith: VecDeque::new(),
+            relay_bandwith: VecDeque::new(),
+            exit_bandwith: VecDeque::new(),
+            client_raw: VecDeque::new(),
+            relay_raw: VecDeque::new(),
+            exit_raw: VecDeque::new(),
+            client_daily: VecDeque::new(),
+            relay_daily: VecDeque::new(),
+            exit_daily: VecDeque::new(),
+            sponsored_bandwith: VecDeque::new(),
+            sponsored_raw: VecDeque::new(),
+            sponsored_daily: VecDeque::new(),
+            relay_neighbors: VecDeque::new(),
+            payments: VecDeque::new(),
+            fees: VecDeque::new(),
+            fee_totals: FeeTotals::default(),
+        }
+    }
+
+    fn usage(up: u64, price: u32) -> UsageHour {
+        UsageHour {
+            index: 0,
+            up,
+            down: 0,
+            price,
+        }
+    }
+
+    #[test]
+    fn test_compaction() {
+        let mut tracker = blank();
+        let now_hour = 500_000;
+        let now = now_hour * 60 * 60;
+        // hours from two days past the hourly retention, oldest at the back
+        for hours_ago in 0..(HOURLY_RETENTION + 48) {
+            let mut hour = usage(1, (hours_ago % 1000) as u32);
+            hour.index = now_hour - hours_ago;
+            tracker.client_bandwith.push_back(hour);
+        }
+        let mut expired = usage(5, 1);
+        expired.index = now - RAW_RETENTION;
+        tracker.client_raw.push_front(expired);
+        let mut fresh = usage(7, 1);
+        fresh.index = now - 60;
+        tracker.client_raw.push_front(fresh);
+        let mut ancient = usage(9, 1);
+        ancient.index = now_hour / 24 - DAILY_RETENTION;
+        tracker.client_daily.push_back(ancient);
+
+        tracker.compact(now);
+
+        assert_eq!(tracker.client_raw, vec![fresh]);
+        assert_eq!(tracker.client_bandwith.len() as u64, HOURLY_RETENTION);
+        let days: Vec<u64> = tracker.client_daily.iter().map(|d| d.index).collect();
+        let first_day = (now_hour - HOURLY_RETENTION - 47) / 24;
+        let last_day = (now_hour - HOURLY_RETENTION) / 24;
+        assert_eq!(days, (first_day..=last_day).rev().collect::<Vec<u64>>());
+        let total: u64 = tracker.client_daily.iter().map(|d| d.up).sum();
+        assert_eq!(total, 48);
+        // the rolled up day keeps the price of its newest hour
+        assert_eq!(tracker.client_daily[0].price, HOURLY_RETENTION as u32);
+
+        // compacting again changes nothing
+        let before = tracker.client_daily.clone();
+        tracker.compact(now);
+        assert_eq!(tracker.client_daily, before);
+    }
+
+    #[test]
+    fn test_fee_tracking() {
+        let mut tracker = blank();
+        process_fee_update(10, 100u32.into(), &mut tracker);
+        process_fee_update(10, 50u32.into(), &mut tracker);
+        process_fee_update(11, 25u32.into(), &mut tracker);
+
+        assert_eq!(
+            tracker.fee_totals,
+            FeeTotals {
+                fees: 175u32.into(),
+                settlements: 3,
+            }
+        );
+        assert_eq!(
+            tracker.fees,
+            vec![
+                FeeHour {
+                    index: 11,
+                    fees: 25u32.into(),
+                    settlements: 1,
+                },
+                FeeHour {
+                    index: 10,
+                    fees: 150u32.into(),
+                    settlements: 2,
+                },
+            ]
+        );
+
+        // the history is bounded but the totals keep counting
+        for hour in 12..(MAX_ENTRIES as u64 + 12) {
+            process_fee_update(hour, 1u32.into(), &mut tracker);
+        }
+        assert_eq!(tracker.fees.len(), MAX_ENTRIES);
+        assert_eq!(tracker.fee_totals.settlements, MAX_ENTRIES as u64 + 3);
+    }
+
+    #[test]
+    fn test_neighbor_usage() {
+        let mut tracker = blank();
+        let a: IpAddr = "fd00::a".parse().unwrap();
+        let b: IpAddr = "fd00::b".parse().unwrap();
+        let update = |down: Vec<(IpAddr, u64)>, price| UpdateNeighborUsage {
+            down: down.into_iter().collect(),
+            price,
+        };
+        process_neighbor_usage(10, update(vec![(a, 5), (b, 1)], 7), &mut tracker);
+        process_neighbor_usage(10, update(vec![(a, 5)], 9), &mut tracker);
+        process_neighbor_usage(11, update(vec![(b, 3)], 9), &mut tracker);
+
+        assert_eq!(tracker.relay_neighbors.len(), 2);
+        assert_eq!(tracker.relay_neighbors[0].down[&b], 3);
+        let hour = &tracker.relay_neighbors[1];
+        assert_eq!((hour.index, hour.price), (10, 7));
+        assert_eq!((hour.down[&a], hour.down[&b]), (10, 1));
+
+        tracker.compact((11 + HOURLY_RETENTION) * 60 * 60);
+        assert_eq!(tracker.relay_neighbors.len(), 0);
+    }
+}
@@ -1 +1,39 @@
+use failure::Error;
+use serde::Serialize;
+use std::fs::{rename, File};
+use std::time::{SystemTime, UNIX_EPOCH};
+
 pub mod ip_increment;
+
+/// Seconds since the unix epoch, 0 if the clock is set before it
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Writes `value` to `path` as json, written to the side and moved into place so that a crash
+/// mid write leaves what was there before rather than half a file
+pub fn save_json<T: Serialize>(path: &str, value: &T) -> Result<(), Error> {
+    let tmp = format!("{}.tmp", path);
+    serde_json::to_writer(File::create(&tmp)?, value)?;
+    rename(tmp, path)?;
+    Ok(())
+}
+
+/// The identity tests use for neighbors and exits, the mesh ip tells them apart and everything
+/// else is a dummy that tests set themselves where it matters
+#[cfg(test)]
+pub fn test_identity(mesh_ip: &str) -> althea_types::Identity {
+    althea_types::Identity::new(
+        mesh_ip.parse().unwrap(),
+        "0x0000000000000000000000000000000000000001"
+            .parse()
+            .unwrap(),
+        "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+            .parse()
+            .unwrap(),
+        None,
+    )
+}
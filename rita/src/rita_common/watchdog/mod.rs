@@ -0,0 +1,561 @@
+//! The watchdog notices when Rita has wedged, for example because a handler blocked on something
+//! that never returned. Every few seconds it pings the core actors, which answer by writing a
+//! heartbeat from their own context, and records how long the heartbeat took to show up. The main
+//! loops also report how long each of their ticks took. An actor whose heartbeat stops is
+//! restarted by its supervisor, one slow tick only gets journaled, and only if it stays stuck
+//! through several restarts for minutes on end does the process exit so that the init system can
+//! start Rita fresh. The watchdog runs in an arbiter of its own so it keeps going when the main
+//! one is stuck, everything it sees is kept in a journal served on the dashboard.
+
+use crate::rita_common::debt_keeper::DebtKeeper;
+use crate::rita_common::live_updates::{has_subscribers, LiveUpdates, Publish};
+use crate::rita_common::rita_loop::fast_loop::RitaFastLoop;
+use crate::rita_common::rita_loop::slow_loop::RitaSlowLoop;
+use crate::rita_common::traffic_watcher::TrafficWatcher;
+use crate::rita_common::tunnel_manager::TunnelManager;
+use crate::rita_common::utils::now_secs;
+use actix::dev::ToEnvelope;
+use actix::{
+    Actor, Addr, Arbiter, AsyncContext, Context, Handler, Message, Supervised, Supervisor,
+    SystemService,
+};
+use failure::Error;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often the watched actors are pinged
+pub const WATCHDOG_INTERVAL: Duration = Duration::from_secs(10);
+/// An actor that takes longer than this to answer a ping is counted as unresponsive
+pub const PING_TIMEOUT: Duration = Duration::from_secs(5);
+/// Answers slower than this are journaled but otherwise fine
+const SLOW_PING: Duration = Duration::from_secs(1);
+/// Consecutive checks a ping goes unanswered before an actor is restarted, and again every this
+/// many after
+const RESTART_AFTER: u32 = 2;
+/// Consecutive checks a ping goes unanswered before we give up and exit, five minutes of being
+/// stuck, long past anything a slow tick could explain
+const EXIT_AFTER: u32 = 30;
+const JOURNAL_SIZE: usize = 200;
+
+/// When a watched actor last answered a ping, written by the actor itself
+#[derive(Clone, Default)]
+pub struct Heartbeat(Arc<Mutex<Option<Instant>>>);
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        *self.0.lock().unwrap() = Some(Instant::now());
+    }
+
+    fn last(&self) -> Option<Instant> {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Answered by every watched actor by beating the heartbeat, doing so proves its mailbox is
+/// moving
+pub struct Ping(pub Heartbeat);
+
+impl Message for Ping {
+    type Result = ();
+}
+
+/// Stops a watched actor so that its supervisor restarts it
+pub struct Restart;
+
+impl Message for Restart {
+    type Result = ();
+}
+
+/// Implements the watchdog messages for a supervised actor
+#[macro_export]
+macro_rules! watchdog_handlers {
+    ($actor:ty) => {
+        impl actix::Handler<$crate::rita_common::watchdog::Ping> for $actor {
+            type Result = ();
+
+            fn handle(
+                &mut self,
+                msg: $crate::rita_common::watchdog::Ping,
+                _: &mut actix::Context<Self>,
+            ) -> Self::Result {
+                msg.0.beat();
+            }
+        }
+
+        impl actix::Handler<$crate::rita_common::watchdog::Restart> for $actor {
+            type Result = ();
+
+            fn handle(
+                &mut self,
+                _: $crate::rita_common::watchdog::Restart,
+                ctx: &mut actix::Context<Self>,
+            ) -> Self::Result {
+                actix::ActorContext::stop(ctx);
+            }
+        }
+    };
+}
+
+watchdog_handlers!(TunnelManager);
+watchdog_handlers!(DebtKeeper);
+watchdog_handlers!(TrafficWatcher);
+watchdog_handlers!(RitaFastLoop);
+watchdog_handlers!(RitaSlowLoop);
+
+/// An actor for the watchdog to keep an eye on
+pub struct Watched {
+    name: &'static str,
+    heartbeat: Heartbeat,
+    ping: Box<dyn Fn(Heartbeat) + Send>,
+    restart: Box<dyn Fn() + Send>,
+}
+
+impl Watched {
+    pub fn new<A>(name: &'static str) -> Watched
+    where
+        A: SystemService + Handler<Ping> + Handler<Restart>,
+        A::Context: ToEnvelope<A, Ping> + ToEnvelope<A, Restart>,
+    {
+        Watched {
+            name,
+            heartbeat: Heartbeat::default(),
+            ping: Box::new(|heartbeat| A::from_registry().do_send(Ping(heartbeat))),
+            restart: Box::new(|| A::from_registry().do_send(Restart)),
+        }
+    }
+}
+
+/// The actors shared by clients and exits
+pub fn common_watched() -> Vec<Watched> {
+    vec![
+        Watched::new::<TunnelManager>("TunnelManager"),
+        Watched::new::<DebtKeeper>("DebtKeeper"),
+        Watched::new::<TrafficWatcher>("TrafficWatcher"),
+        Watched::new::<RitaFastLoop>("RitaFastLoop"),
+        Watched::new::<RitaSlowLoop>("RitaSlowLoop"),
+    ]
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WatchState {
+    pub name: String,
+    pub last_latency_ms: Option<u64>,
+    pub max_latency_ms: u64,
+    /// consecutive unanswered pings
+    pub failures: u32,
+    pub restarts: u32,
+    /// when the ping we're waiting on was sent
+    #[serde(skip)]
+    pinged: Option<Instant>,
+}
+
+/// What came of the last ping when it's checked on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PingOutcome {
+    Answered(Duration),
+    Waiting(Duration),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum WatchdogEventKind {
+    Slow {
+        latency_ms: u64,
+    },
+    Unresponsive {
+        failures: u32,
+    },
+    Restarted,
+    Recovered {
+        latency_ms: u64,
+    },
+    LoopOverrun {
+        elapsed_ms: u64,
+        period_ms: u64,
+    },
+    Exiting,
+    /// billing math that would have overflowed or truncated, the result was saturated instead
+    Overflow {
+        operation: String,
+    },
+    /// babel was announcing a different fee than ours so it was set again
+    BabelFeeDrift {
+        expected: u32,
+        announced: u32,
+    },
+    /// babel turned down our fee or kept announcing another one after it was set
+    BabelFeeRefused {
+        fee: u32,
+    },
+    /// one of the router's resources went past its warning threshold, see `/system_health`
+    ResourceWarning {
+        resource: String,
+        value: u64,
+        threshold: u64,
+    },
+    /// and came back under it
+    ResourceRecovered {
+        resource: String,
+        value: u64,
+    },
+    /// Our clock is off from our neighbors' and exit's by more than the safe bound, in seconds
+    /// positive if we're behind
+    ClockSkew {
+        skew: i64,
+    },
+    /// and is back within it
+    ClockRecovered {
+        skew: i64,
+    },
+    /// the route to our exit is priced over max_fee and we're only paying max_fee for it
+    PriceCapped {
+        price: u32,
+        max_fee: u32,
+    },
+    /// and is back within it
+    PriceCapLifted {
+        price: u32,
+    },
+    /// babel was told to stop using the route to our exit through this neighbor, it's priced
+    /// over max_fee and there's another one that isn't
+    RouteRefused {
+        neighbor: String,
+        price: u32,
+    },
+    /// a source kept making requests to one of our mesh facing endpoints after being rate
+    /// limited and is banned for this many seconds
+    SourceBanned {
+        source: String,
+        path: String,
+        duration: u64,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WatchdogEvent {
+    /// seconds since the unix epoch
+    pub time: u64,
+    pub actor: String,
+    pub kind: WatchdogEventKind,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WatchdogStatus {
+    pub actors: Vec<WatchState>,
+    pub journal: Vec<WatchdogEvent>,
+}
+
+fn as_millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + u64::from(d.subsec_millis())
+}
+
+/// Updates an actor's state with what came of a ping and works out what if anything is worth
+/// journaling. Restarted and Exiting are also what the watchdog should do about it
+fn judge(state: &mut WatchState, outcome: PingOutcome) -> Option<WatchdogEventKind> {
+    match outcome {
+        PingOutcome::Answered(latency) => {
+            let latency_ms = as_millis(latency);
+            state.last_latency_ms = Some(latency_ms);
+            state.max_latency_ms = state.max_latency_ms.max(latency_ms);
+            let was_failing = state.failures > 0;
+            state.failures = 0;
+            if was_failing {
+                Some(WatchdogEventKind::Recovered { latency_ms })
+            } else if latency > SLOW_PING {
+                Some(WatchdogEventKind::Slow { latency_ms })
+            } else {
+                None
+            }
+        }
+        PingOutcome::Waiting(waited) if waited < PING_TIMEOUT => None,
+        PingOutcome::Waiting(_) => {
+            state.last_latency_ms = None;
+            state.failures += 1;
+            if state.failures >= EXIT_AFTER {
+                Some(WatchdogEventKind::Exiting)
+            } else if state.failures % RESTART_AFTER == 0 {
+                state.restarts += 1;
+                Some(WatchdogEventKind::Restarted)
+            } else {
+                Some(WatchdogEventKind::Unresponsive {
+                    failures: state.failures,
+                })
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Watchdog {
+    watched: Vec<(Watched, WatchState)>,
+    journal: VecDeque<WatchdogEvent>,
+}
+
+impl Actor for Watchdog {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(WATCHDOG_INTERVAL, |act, ctx| act.ping_all(ctx));
+    }
+}
+
+impl Supervised for Watchdog {}
+impl SystemService for Watchdog {
+    /// Started in an arbiter of its own rather than the system one, watching the system arbiter
+    /// from inside it would be pointless
+    fn start_service(_sys: &Addr<Arbiter>) -> Addr<Self> {
+        Supervisor::start_in_arbiter(&Arbiter::new("watchdog"), |ctx| {
+            let mut act = Watchdog::default();
+            act.service_started(ctx);
+            act
+        })
+    }
+
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("Watchdog started");
+    }
+}
+
+impl Watchdog {
+    /// Checks on the ping each actor was last sent and sends a new one once it's answered
+    fn ping_all(&mut self, _ctx: &mut Context<Self>) {
+        let mut events = Vec::new();
+        for (watched, state) in self.watched.iter_mut() {
+            let outcome = match (state.pinged, watched.heartbeat.last()) {
+                (None, _) => None,
+                (Some(pinged), Some(beat)) if beat >= pinged => {
+                    Some(PingOutcome::Answered(beat - pinged))
+                }
+                (Some(pinged), _) => Some(PingOutcome::Waiting(pinged.elapsed())),
+            };
+            let event = outcome.and_then(|outcome| judge(state, outcome));
+            match event {
+                Some(WatchdogEventKind::Restarted) => {
+                    error!("{} is unresponsive, restarting it", watched.name);
+                    (watched.restart)();
+                }
+                Some(WatchdogEventKind::Exiting) => {
+                    error!(
+                        "{} is still unresponsive after {} restarts, exiting",
+                        watched.name, state.restarts
+                    );
+                }
+                Some(ref event) => warn!("Watchdog: {} {:?}", watched.name, event),
+                None => {}
+            }
+            if let Some(event) = event {
+                events.push((watched.name, event));
+            }
+            // a ping that's still waiting is already counted
+            match outcome {
+                Some(PingOutcome::Waiting(_)) => {}
+                _ => {
+                    state.pinged = Some(Instant::now());
+                    (watched.ping)(watched.heartbeat.clone());
+                }
+            }
+        }
+        for (name, event) in events {
+            let exiting = event == WatchdogEventKind::Exiting;
+            self.record(name, event);
+            if exiting {
+                std::process::exit(1);
+            }
+        }
+    }
+
+    fn record(&mut self, actor: &str, kind: WatchdogEventKind) {
+        let time = now_secs();
+        if self.journal.len() >= JOURNAL_SIZE {
+            self.journal.pop_front();
+        }
+        let event = WatchdogEvent {
+            time,
+            actor: actor.to_string(),
+            kind,
+        };
+        if has_subscribers() {
+            LiveUpdates::from_registry().do_send(Publish::Event(event.clone()));
+        }
+        self.journal.push_back(event);
+    }
+}
+
+/// Starts watching these actors, in addition to any already watched
+pub struct Watch(pub Vec<Watched>);
+
+impl Message for Watch {
+    type Result = ();
+}
+
+impl Handler<Watch> for Watchdog {
+    type Result = ();
+
+    fn handle(&mut self, msg: Watch, _ctx: &mut Context<Self>) -> Self::Result {
+        for watched in msg.0 {
+            let state = WatchState {
+                name: watched.name.to_string(),
+                ..Default::default()
+            };
+            self.watched.push((watched, state));
+        }
+    }
+}
+
+/// Sent by the main loops with how long their tick handler took
+pub struct LoopTime {
+    pub name: &'static str,
+    pub elapsed: Duration,
+    /// how often the loop ticks, taking longer than this is an overrun
+    pub period: Duration,
+}
+
+impl Message for LoopTime {
+    type Result = ();
+}
+
+impl Handler<LoopTime> for Watchdog {
+    type Result = ();
+
+    fn handle(&mut self, msg: LoopTime, _ctx: &mut Context<Self>) -> Self::Result {
+        if msg.elapsed > msg.period {
+            warn!(
+                "{} tick took {}ms, longer than its {}ms period",
+                msg.name,
+                as_millis(msg.elapsed),
+                as_millis(msg.period)
+            );
+            self.record(
+                msg.name,
+                WatchdogEventKind::LoopOverrun {
+                    elapsed_ms: as_millis(msg.elapsed),
+                    period_ms: as_millis(msg.period),
+                },
+            );
+        }
+    }
+}
+
+/// Journals billing math that overflowed or would have truncated, `source` is the component
+/// doing the math
+pub struct OverflowAlarm {
+    pub source: &'static str,
+    pub operation: String,
+}
+
+impl Message for OverflowAlarm {
+    type Result = ();
+}
+
+impl Handler<OverflowAlarm> for Watchdog {
+    type Result = ();
+
+    fn handle(&mut self, msg: OverflowAlarm, _ctx: &mut Context<Self>) -> Self::Result {
+        self.record(
+            msg.source,
+            WatchdogEventKind::Overflow {
+                operation: msg.operation,
+            },
+        );
+    }
+}
+
+/// Journals something a component noticed that isn't about an actor's health
+pub struct Journal {
+    pub source: &'static str,
+    pub kind: WatchdogEventKind,
+}
+
+impl Message for Journal {
+    type Result = ();
+}
+
+impl Handler<Journal> for Watchdog {
+    type Result = ();
+
+    fn handle(&mut self, msg: Journal, _ctx: &mut Context<Self>) -> Self::Result {
+        self.record(msg.source, msg.kind);
+    }
+}
+
+pub fn overflow_alarm(source: &'static str, operation: String) {
+    error!("{}: {} overflowed", source, operation);
+    Watchdog::from_registry().do_send(OverflowAlarm { source, operation });
+}
+
+/// The result of a checked operation, or `fallback` and an alarm if there isn't one
+pub fn or_alarm<T>(
+    checked: Option<T>,
+    fallback: T,
+    source: &'static str,
+    operation: impl FnOnce() -> String,
+) -> T {
+    match checked {
+        Some(val) => val,
+        None => {
+            overflow_alarm(source, operation());
+            fallback
+        }
+    }
+}
+
+pub struct GetWatchdogStatus;
+
+impl Message for GetWatchdogStatus {
+    type Result = Result<WatchdogStatus, Error>;
+}
+
+impl Handler<GetWatchdogStatus> for Watchdog {
+    type Result = Result<WatchdogStatus, Error>;
+
+    fn handle(&mut self, _: GetWatchdogStatus, _ctx: &mut Context<Self>) -> Self::Result {
+        Ok(WatchdogStatus {
+            actors: self
+                .watched
+                .iter()
+                .map(|(_, state)| state.clone())
+                .collect(),
+            journal: self.journal.iter().cloned().collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_judge() {
+        let answered = |ms| PingOutcome::Answered(Duration::from_millis(ms));
+        let stuck = PingOutcome::Waiting(WATCHDOG_INTERVAL);
+        let mut state = WatchState::default();
+        assert_eq!(judge(&mut state, answered(10)), None);
+        assert_eq!(
+            judge(&mut state, answered(2000)),
+            Some(WatchdogEventKind::Slow { latency_ms: 2000 })
+        );
+        assert_eq!(state.max_latency_ms, 2000);
+
+        // not overdue yet
+        assert_eq!(
+            judge(&mut state, PingOutcome::Waiting(Duration::from_secs(1))),
+            None
+        );
+        assert_eq!(
+            judge(&mut state, stuck),
+            Some(WatchdogEventKind::Unresponsive { failures: 1 })
+        );
+        assert_eq!(judge(&mut state, stuck), Some(WatchdogEventKind::Restarted));
+        // one slow tick that ends is journaled and forgotten
+        assert_eq!(
+            judge(&mut state, answered(25_000)),
+            Some(WatchdogEventKind::Recovered { latency_ms: 25_000 })
+        );
+        assert_eq!(state.restarts, 1);
+
+        for _ in 1..EXIT_AFTER {
+            assert_ne!(judge(&mut state, stuck), Some(WatchdogEventKind::Exiting));
+        }
+        assert_eq!(judge(&mut state, stuck), Some(WatchdogEventKind::Exiting));
+    }
+}
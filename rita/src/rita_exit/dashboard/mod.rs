@@ -0,0 +1,442 @@
+//! A read only admin dashboard for the exit, which otherwise has no way to see how it's doing
+//! short of reading logs and the database. It's served on its own port bound to localhost, so it
+//! has to be reached over ssh, and only reports client counts, how full the address pools are,
+//! a summary of debts, the database's health and the throughput of each interface.
+
+use crate::rita_common::debt_keeper::{DebtAction, DebtKeeper, GetDebtsList, GetDebtsResult};
+use crate::rita_exit::database::database_tools::get_database_connection;
+use crate::rita_exit::database::secs_since_unix_epoch;
+use crate::rita_exit::database::struct_tools::{is_archived, parse_ipv6_subnet};
+use crate::rita_exit::network_endpoints::get_db_status;
+use crate::KI;
+use crate::SETTING;
+use actix::prelude::*;
+use actix::registry::SystemService;
+use actix_web::http::Method;
+use actix_web::{server, App, AsyncResponder, HttpRequest, Json};
+use althea_kernel_interface::InterfaceCounters;
+use exit_db::models::Client;
+use failure::Error;
+use futures01::Future;
+use ipnetwork::{IpNetwork, Ipv4Network};
+use num256::{Int256, Uint256};
+use num_traits::identities::Zero;
+use settings::exit::{ExitNetworkSettings, RitaExitSettings};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How often the interface counters are read
+const INTERFACE_SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+/// Clients that checked in within this many seconds count as online
+const ONLINE_WINDOW: i64 = 600;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct InterfaceThroughput {
+    pub name: String,
+    pub rx_bytes_per_sec: u64,
+    pub tx_bytes_per_sec: u64,
+    /// totals since the interface was created
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+/// The rate between two readings of an interface's counters, a counter that went backwards means
+/// the interface was recreated in between and is counted from zero
+fn throughput(
+    name: &str,
+    old: InterfaceCounters,
+    new: InterfaceCounters,
+    elapsed: Duration,
+) -> InterfaceThroughput {
+    let millis = (elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis())).max(1);
+    let rate = |old: u64, new: u64| {
+        let bytes = if new >= old { new - old } else { new };
+        bytes.saturating_mul(1000) / millis
+    };
+    InterfaceThroughput {
+        name: name.to_string(),
+        rx_bytes_per_sec: rate(old.rx_bytes, new.rx_bytes),
+        tx_bytes_per_sec: rate(old.tx_bytes, new.tx_bytes),
+        rx_bytes: new.rx_bytes,
+        tx_bytes: new.tx_bytes,
+    }
+}
+
+pub struct ExitDashboard {
+    /// the last reading of each interface's counters and when it was taken
+    last_counters: HashMap<String, (InterfaceCounters, Instant)>,
+    throughput: Vec<InterfaceThroughput>,
+}
+
+impl Actor for ExitDashboard {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(INTERFACE_SAMPLE_INTERVAL, |act, _ctx| {
+            act.sample_interfaces()
+        });
+    }
+}
+
+impl Supervised for ExitDashboard {}
+impl SystemService for ExitDashboard {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("Exit dashboard started");
+    }
+}
+
+impl Default for ExitDashboard {
+    fn default() -> ExitDashboard {
+        ExitDashboard {
+            last_counters: HashMap::new(),
+            throughput: Vec::new(),
+        }
+    }
+}
+
+impl ExitDashboard {
+    fn sample_interfaces(&mut self) {
+        let counters = match KI.get_interface_counters() {
+            Ok(counters) => counters,
+            Err(e) => {
+                warn!("Failed to read interface counters {:?}", e);
+                return;
+            }
+        };
+        let now = Instant::now();
+        let mut rates = Vec::new();
+        for (name, new) in counters.iter() {
+            if name == "lo" {
+                continue;
+            }
+            if let Some((old, at)) = self.last_counters.get(name) {
+                rates.push(throughput(name, *old, *new, now - *at));
+            }
+        }
+        rates.sort_by(|a, b| a.name.cmp(&b.name));
+        self.throughput = rates;
+        // interfaces that are gone are dropped here, client tunnels come and go
+        self.last_counters = counters
+            .into_iter()
+            .map(|(name, counters)| (name, (counters, now)))
+            .collect();
+    }
+}
+
+pub struct GetThroughput;
+
+impl Message for GetThroughput {
+    type Result = Result<Vec<InterfaceThroughput>, Error>;
+}
+
+impl Handler<GetThroughput> for ExitDashboard {
+    type Result = Result<Vec<InterfaceThroughput>, Error>;
+
+    fn handle(&mut self, _msg: GetThroughput, _ctx: &mut Context<Self>) -> Self::Result {
+        Ok(self.throughput.clone())
+    }
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct ClientCounts {
+    pub total: usize,
+    pub verified: usize,
+    /// signed up but not verified yet
+    pub pending: usize,
+    /// checked in within the last ten minutes
+    pub online: usize,
+    /// archived by the retention policy, they are counted in the total
+    pub archived: usize,
+}
+
+fn count_clients(clients: &[Client], now: i64) -> ClientCounts {
+    let mut counts = ClientCounts {
+        total: clients.len(),
+        ..ClientCounts::default()
+    };
+    for client in clients {
+        if is_archived(client) {
+            counts.archived += 1;
+        } else if client.verified {
+            counts.verified += 1;
+        } else {
+            counts.pending += 1;
+        }
+        if now - client.last_seen < ONLINE_WINDOW {
+            counts.online += 1;
+        }
+    }
+    counts
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolUsage {
+    pub used: u64,
+    pub capacity: u64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct IpPoolUsage {
+    /// internal addresses in the exit tunnel
+    pub ipv4: PoolUsage,
+    /// /64s delegated out of the ipv6 pool, none without one
+    pub ipv6: Option<PoolUsage>,
+    /// public address and port range pairs, none unless cgnat is on
+    pub cgnat: Option<PoolUsage>,
+    /// none when there's no static ip pool
+    pub static_ips: Option<PoolUsage>,
+}
+
+/// How much of each pool the clients are holding, archived clients have given their addresses
+/// back except for static ones, which are only released by hand
+fn ip_pool_usage(settings: &ExitNetworkSettings, clients: &[Client]) -> IpPoolUsage {
+    let current: Vec<&Client> = clients.iter().filter(|c| !is_archived(c)).collect();
+
+    // the same bounds client addresses are handed out in, from the start ip up to the broadcast
+    // address skipping our own
+    let ipv4_capacity = match Ipv4Network::new(settings.own_internal_ip, settings.netmask) {
+        Ok(subnet) if subnet.contains(settings.exit_start_ip) => {
+            let start = u32::from(settings.exit_start_ip);
+            let end = u32::from(subnet.broadcast());
+            let gateway = u32::from(settings.own_internal_ip);
+            let own = (start <= gateway && gateway < end) as u32;
+            u64::from(end.saturating_sub(start).saturating_sub(own))
+        }
+        _ => 0,
+    };
+    let ipv4 = PoolUsage {
+        used: current.iter().filter(|c| !c.internal_ip.is_empty()).count() as u64,
+        capacity: ipv4_capacity,
+    };
+
+    let ipv6 = match settings.ipv6_pool {
+        // the first /64 is kept for the exit
+        Some(IpNetwork::V6(pool)) if pool.prefix() <= 64 => Some(PoolUsage {
+            used: current
+                .iter()
+                .filter(|c| parse_ipv6_subnet(c).is_some())
+                .count() as u64,
+            capacity: 1u64
+                .checked_shl(u32::from(64 - pool.prefix()))
+                .map_or(u64::max_value(), |count| count - 1),
+        }),
+        _ => None,
+    };
+
+    let cgnat = settings.cgnat.as_ref().map(|cgnat| {
+        let ranges_per_ip = match cgnat.ports_per_client {
+            0 => 0,
+            ports => (65536 - u64::from(cgnat.first_port)) / u64::from(ports),
+        };
+        PoolUsage {
+            used: current
+                .iter()
+                .filter(|c| !c.nat_port_range.is_empty())
+                .count() as u64,
+            capacity: cgnat.public_ips.len() as u64 * ranges_per_ip,
+        }
+    });
+
+    let static_ips = if settings.static_ip_pool.is_empty() {
+        None
+    } else {
+        Some(PoolUsage {
+            used: clients.iter().filter(|c| !c.public_ipv4.is_empty()).count() as u64,
+            capacity: settings.static_ip_pool.len() as u64,
+        })
+    };
+
+    IpPoolUsage {
+        ipv4,
+        ipv6,
+        cgnat,
+        static_ips,
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DebtSummary {
+    /// nodes DebtKeeper is tracking, clients and neighbors alike
+    pub nodes: usize,
+    /// nodes that owe us anything
+    pub in_debt: usize,
+    /// nodes whose tunnels are suspended for not paying
+    pub suspended: usize,
+    /// in wei
+    pub owed_to_us: Uint256,
+    pub owed_by_us: Uint256,
+    pub total_received: Uint256,
+}
+
+fn summarize_debts(debts: &[GetDebtsResult]) -> DebtSummary {
+    let mut summary = DebtSummary {
+        nodes: debts.len(),
+        in_debt: 0,
+        suspended: 0,
+        owed_to_us: Uint256::zero(),
+        owed_by_us: Uint256::zero(),
+        total_received: Uint256::zero(),
+    };
+    for debt in debts.iter().map(|debt| &debt.payment_details) {
+        // sign checked first so the conversions can't fail
+        if debt.debt < Int256::zero() {
+            summary.in_debt += 1;
+            summary.owed_to_us += (Int256::zero() - debt.debt.clone()).to_uint256().unwrap();
+        } else {
+            summary.owed_by_us += debt.debt.to_uint256().unwrap();
+        }
+        if debt.action == DebtAction::SuspendTunnel {
+            summary.suspended += 1;
+        }
+        summary.total_received += debt.total_payment_received.clone();
+    }
+    summary
+}
+
+pub fn get_client_counts(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<ClientCounts>, Error = Error>> {
+    get_database_connection()
+        .and_then(|conn| {
+            let clients_list = conn.load_clients()?;
+            Ok(Json(count_clients(&clients_list, secs_since_unix_epoch())))
+        })
+        .responder()
+}
+
+pub fn get_ip_pool_usage(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<IpPoolUsage>, Error = Error>> {
+    get_database_connection()
+        .and_then(|conn| {
+            let clients_list = conn.load_clients()?;
+            Ok(Json(ip_pool_usage(
+                &SETTING.get_exit_network(),
+                &clients_list,
+            )))
+        })
+        .responder()
+}
+
+pub fn get_debt_summary(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<DebtSummary>, Error = Error>> {
+    DebtKeeper::from_registry()
+        .send(GetDebtsList)
+        .from_err()
+        .and_then(|debts| Ok(Json(summarize_debts(&debts?))))
+        .responder()
+}
+
+/// Throughput of every interface over the last few seconds, empty until the counters have been
+/// read twice
+pub fn get_interface_throughput(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<Vec<InterfaceThroughput>>, Error = Error>> {
+    ExitDashboard::from_registry()
+        .send(GetThroughput)
+        .from_err()
+        .and_then(|throughput| Ok(Json(throughput?)))
+        .responder()
+}
+
+/// Serves the admin dashboard on localhost, unless its port is set to 0
+pub fn start_exit_admin_dashboard() {
+    let port = SETTING.get_exit_network().admin_dashboard_port;
+    if port == 0 {
+        return;
+    }
+    server::new(|| {
+        App::new()
+            .route("/exit/clients", Method::GET, get_client_counts)
+            .route("/exit/ip_pool", Method::GET, get_ip_pool_usage)
+            .route("/exit/debts", Method::GET, get_debt_summary)
+            .route("/exit/database", Method::GET, get_db_status)
+            .route("/exit/interfaces", Method::GET, get_interface_throughput)
+    })
+    .bind(format!("127.0.0.1:{}", port))
+    .unwrap()
+    .workers(1)
+    .shutdown_timeout(0)
+    .start();
+}
+
+#[test]
+fn test_throughput() {
+    let counters = |rx_bytes: u64, tx_bytes: u64| InterfaceCounters {
+        rx_bytes,
+        tx_bytes,
+        ..InterfaceCounters::default()
+    };
+    let rate = throughput(
+        "wg_exit",
+        counters(1000, 5000),
+        counters(11_000, 7500),
+        Duration::from_secs(5),
+    );
+    assert_eq!(rate.rx_bytes_per_sec, 2000);
+    assert_eq!(rate.tx_bytes_per_sec, 500);
+    assert_eq!(rate.rx_bytes, 11_000);
+    // the interface was recreated between readings
+    let rate = throughput(
+        "wg_exit",
+        counters(1_000_000, 0),
+        counters(4000, 0),
+        Duration::from_secs(2),
+    );
+    assert_eq!(rate.rx_bytes_per_sec, 2000);
+}
+
+#[test]
+fn test_ip_pool_usage() {
+    use settings::exit::CgnatSettings;
+
+    let mut settings = ExitNetworkSettings::test_default();
+    settings.own_internal_ip = "172.16.0.1".parse().unwrap();
+    settings.exit_start_ip = "172.16.0.2".parse().unwrap();
+    settings.netmask = 24;
+    settings.ipv6_pool = Some("2602:fbad:10::/60".parse().unwrap());
+    settings.cgnat = Some(CgnatSettings {
+        public_ips: vec!["203.0.113.1".parse().unwrap()],
+        ports_per_client: 1000,
+        first_port: 1536,
+    });
+
+    let client = |internal_ip: &str, ipv6: &str, archived_time: i64| Client {
+        internal_ip: internal_ip.to_string(),
+        internet_ipv6: ipv6.to_string(),
+        nat_port_range: "203.0.113.1:1536-2535".to_string(),
+        archived_time,
+        ..Client::default()
+    };
+    let clients = vec![
+        client("172.16.0.2", "2602:fbad:10:1::/64", 0),
+        client("172.16.0.3", "", 0),
+        // archived clients don't hold their addresses
+        client("172.16.0.4", "2602:fbad:10:2::/64", 100),
+    ];
+    let usage = ip_pool_usage(&settings, &clients);
+    // .2 up to .254
+    assert_eq!(
+        usage.ipv4,
+        PoolUsage {
+            used: 2,
+            capacity: 253
+        }
+    );
+    assert_eq!(
+        usage.ipv6,
+        Some(PoolUsage {
+            used: 1,
+            capacity: 15
+        })
+    );
+    assert_eq!(
+        usage.cgnat,
+        Some(PoolUsage {
+            used: 2,
+            capacity: 64
+        })
+    );
+    assert_eq!(usage.static_ips, None);
+}
@@ -1,25 +1,28 @@
 use crate::rita_common::utils::ip_increment::increment;
+use crate::rita_exit::database::db_health::{DbFailure, DbHealth, DbSuccess};
 use crate::rita_exit::database::secs_since_unix_epoch;
+use crate::rita_exit::database::store::{ExitStore, StoreConnection};
 use crate::rita_exit::database::struct_tools::client_to_new_db_client;
+use crate::rita_exit::database::struct_tools::generate_email_code;
+use crate::rita_exit::database::struct_tools::is_archived;
+use crate::rita_exit::database::struct_tools::parse_ipv6_subnet;
 use crate::rita_exit::database::ONE_DAY;
 use crate::DB_POOL;
 use crate::SETTING;
+use actix::SystemService;
 use actix_web::Result;
-use althea_kernel_interface::ExitClient;
 use althea_types::ExitClientIdentity;
-use diesel;
-use diesel::dsl::{delete, exists};
-use diesel::prelude::{ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
-use diesel::r2d2::ConnectionManager;
-use diesel::r2d2::PooledConnection;
-use diesel::select;
-use exit_db::{models, schema};
+use althea_types::NatPortRange;
+use exit_db::models;
 use failure::Error;
 use futures01::future;
 use futures01::future::Future;
+use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
+use settings::exit::CgnatSettings;
 use settings::exit::RitaExitSettings;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
 use std::time::Duration;
 use std::time::Instant;
 use tokio::timer::Delay;
@@ -30,6 +33,10 @@ use tokio::util::FutureExt;
 fn get_internal_ips(clients: &[exit_db::models::Client]) -> Vec<Ipv4Addr> {
     let mut list = Vec::with_capacity(clients.len());
     for client in clients {
+        // archived clients have given up their address
+        if is_archived(client) {
+            continue;
+        }
         let client_internal_ip = client.internal_ip.parse();
         match client_internal_ip {
             Ok(address) => list.push(address),
@@ -43,8 +50,7 @@ fn get_internal_ips(clients: &[exit_db::models::Client]) -> Vec<Ipv4Addr> {
 
 /// Gets the next available client ip, takes about O(n) time, we could make it faster by
 /// sorting on the database side but I've left that optimization on the vine for now
-pub fn get_next_client_ip(conn: &PgConnection) -> Result<IpAddr, Error> {
-    use self::schema::clients::dsl::clients;
+pub fn get_next_client_ip(conn: &dyn ExitStore) -> Result<IpAddr, Error> {
     let exit_settings = SETTING.get_exit_network();
     let netmask = exit_settings.netmask as u8;
     let start_ip = exit_settings.exit_start_ip;
@@ -52,7 +58,7 @@ pub fn get_next_client_ip(conn: &PgConnection) -> Result<IpAddr, Error> {
     // drop here to free up the settings lock, this codepath runs in parallel
     drop(exit_settings);
 
-    let clients_list = clients.load::<models::Client>(conn)?;
+    let clients_list = conn.load_clients()?;
     let ips_list = get_internal_ips(&clients_list);
     let mut new_ip: IpAddr = start_ip.into();
 
@@ -72,88 +78,351 @@ pub fn get_next_client_ip(conn: &PgConnection) -> Result<IpAddr, Error> {
     Ok(new_ip)
 }
 
+/// True if a client can be given this address, it has to be in the exit's range past the start
+/// ip and not the exit's own, the broadcast address or another client's
+fn ip_available(
+    ip: Ipv4Addr,
+    start_ip: Ipv4Addr,
+    gateway_ip: Ipv4Addr,
+    netmask: u8,
+    taken: &[Ipv4Addr],
+) -> bool {
+    let subnet = match Ipv4Network::new(gateway_ip, netmask) {
+        Ok(subnet) => subnet,
+        Err(_) => return false,
+    };
+    subnet.contains(ip)
+        && ip >= start_ip
+        && ip != gateway_ip
+        && ip != subnet.broadcast()
+        && !taken.contains(&ip)
+}
+
+/// The address a client asked for if it's free, an exit migration where the client had this
+/// address with its previous exit, otherwise the next available one
+pub fn get_preferred_client_ip(
+    preferred: Option<IpAddr>,
+    conn: &dyn ExitStore,
+) -> Result<IpAddr, Error> {
+    if let Some(IpAddr::V4(preferred)) = preferred {
+        let exit_settings = SETTING.get_exit_network();
+        let netmask = exit_settings.netmask as u8;
+        let start_ip = exit_settings.exit_start_ip;
+        let gateway_ip = exit_settings.own_internal_ip;
+        drop(exit_settings);
+
+        let clients_list = conn.load_clients()?;
+        let ips_list = get_internal_ips(&clients_list);
+        if ip_available(preferred, start_ip, gateway_ip, netmask, &ips_list) {
+            return Ok(preferred.into());
+        }
+        info!("Preferred client ip {} is not available", preferred);
+    }
+    get_next_client_ip(conn)
+}
+
+/// The size of the subnet delegated to each client, anything smaller breaks SLAAC
+const CLIENT_IPV6_PREFIX: u8 = 64;
+
+/// Finds the first /64 in the pool that isn't in the taken list. The first subnet in the pool is
+/// never handed out, it's left for the exit's own addresses
+fn next_free_subnet(pool: Ipv6Network, taken: &[IpNetwork]) -> Result<Option<IpNetwork>, Error> {
+    if pool.prefix() > CLIENT_IPV6_PREFIX {
+        bail!(
+            "ipv6 pool {} is too small to delegate /{} subnets",
+            pool,
+            CLIENT_IPV6_PREFIX
+        );
+    }
+    let base = u128::from(pool.network());
+    let step = 1u128 << (128 - u32::from(CLIENT_IPV6_PREFIX));
+    let count = 1u128 << u32::from(CLIENT_IPV6_PREFIX - pool.prefix());
+    for index in 1..count {
+        let addr = Ipv6Addr::from(base + index * step);
+        let subnet = IpNetwork::V6(Ipv6Network::new(addr, CLIENT_IPV6_PREFIX)?);
+        if !taken.contains(&subnet) {
+            return Ok(Some(subnet));
+        }
+    }
+    Ok(None)
+}
+
+/// Gets the next available client ipv6 subnet, returns None if this exit has no ipv6 pool
+/// configured or the pool is exhausted
+pub fn get_next_client_ipv6(conn: &dyn ExitStore) -> Result<Option<IpNetwork>, Error> {
+    let pool = match SETTING.get_exit_network().ipv6_pool {
+        Some(IpNetwork::V6(pool)) => pool,
+        Some(IpNetwork::V4(pool)) => bail!("ipv6 pool {} is not an ipv6 subnet", pool),
+        None => return Ok(None),
+    };
+
+    let clients_list = conn.load_clients()?;
+    let taken: Vec<IpNetwork> = clients_list.iter().filter_map(parse_ipv6_subnet).collect();
+    let subnet = next_free_subnet(pool, &taken)?;
+    if subnet.is_none() {
+        error!("ipv6 pool {} is exhausted!", pool);
+    }
+    Ok(subnet)
+}
+
+/// True if a client can be delegated this subnet, the same rules as `next_free_subnet`
+fn subnet_available(pool: Ipv6Network, subnet: IpNetwork, taken: &[IpNetwork]) -> bool {
+    match subnet {
+        IpNetwork::V6(subnet) => {
+            subnet.prefix() == CLIENT_IPV6_PREFIX
+                && pool.contains(subnet.network())
+                && subnet.network() != pool.network()
+                && !taken.contains(&IpNetwork::V6(subnet))
+        }
+        IpNetwork::V4(_) => false,
+    }
+}
+
+/// The ipv6 subnet a client asked for if it's free, otherwise the next available one
+pub fn get_preferred_client_ipv6(
+    preferred: Option<IpNetwork>,
+    conn: &dyn ExitStore,
+) -> Result<Option<IpNetwork>, Error> {
+    if let (Some(preferred), Some(IpNetwork::V6(pool))) =
+        (preferred, SETTING.get_exit_network().ipv6_pool)
+    {
+        let clients_list = conn.load_clients()?;
+        let taken: Vec<IpNetwork> = clients_list.iter().filter_map(parse_ipv6_subnet).collect();
+        if subnet_available(pool, preferred, &taken) {
+            return Ok(Some(preferred));
+        }
+        info!("Preferred client subnet {} is not available", preferred);
+    }
+    get_next_client_ipv6(conn)
+}
+
+/// Runs `allocate` under the store's address lock and returns what it did, anything that picks
+/// free addresses and saves them has to be in here or two clients can end up with the same ones
+pub(crate) fn with_address_lock<T>(
+    conn: &dyn ExitStore,
+    allocate: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error> {
+    let mut allocate = Some(allocate);
+    let mut ret = None;
+    conn.with_address_lock(&mut || {
+        if let Some(allocate) = allocate.take() {
+            ret = Some(allocate()?);
+        }
+        Ok(())
+    })?;
+    ret.ok_or_else(|| format_err!("Address allocation did not run"))
+}
+
+/// Moves a registered client to the addresses it has with the exit it's currently using, if
+/// they are free here, so that it keeps them if it fails over to us. Returns the updated record
+pub fn migrate_client_addresses(
+    client: &ExitClientIdentity,
+    their_record: &models::Client,
+    conn: &dyn ExitStore,
+) -> Result<models::Client, Error> {
+    with_address_lock(conn, || migrate_addresses(client, their_record, conn))
+}
+
+fn migrate_addresses(
+    client: &ExitClientIdentity,
+    their_record: &models::Client,
+    conn: &dyn ExitStore,
+) -> Result<models::Client, Error> {
+    let mut migrated = their_record.clone();
+    let mut changed = false;
+    if let Some(preferred) = client.preferred_internal_ip {
+        if preferred.to_string() != their_record.internal_ip
+            && get_preferred_client_ip(Some(preferred), conn)? == preferred
+        {
+            migrated.internal_ip = preferred.to_string();
+            migrated.nat_port_range = get_client_nat_port_range(preferred)
+                .map(|r| r.to_string())
+                .unwrap_or_default();
+            changed = true;
+        }
+    }
+    if let Some(preferred) = client.preferred_ipv6_subnet {
+        if Some(preferred) != parse_ipv6_subnet(their_record)
+            && get_preferred_client_ipv6(Some(preferred), conn)? == Some(preferred)
+        {
+            migrated.internet_ipv6 = preferred.to_string();
+            changed = true;
+        }
+    }
+    if !changed {
+        return Ok(migrated);
+    }
+
+    info!(
+        "Migrating client {} from {} {} to {} {}",
+        their_record.wg_pubkey,
+        their_record.internal_ip,
+        their_record.internet_ipv6,
+        migrated.internal_ip,
+        migrated.internet_ipv6
+    );
+    conn.update_client(&their_record.mesh_ip, &mut |record| {
+        record.internal_ip = migrated.internal_ip.clone();
+        record.internet_ipv6 = migrated.internet_ipv6.clone();
+        record.nat_port_range = migrated.nat_port_range.clone();
+    })?;
+    Ok(migrated)
+}
+
+/// Gives an existing client an ipv6 subnet, for clients that registered before the exit had
+/// an ipv6 pool configured
+pub fn assign_client_ipv6(
+    client: &models::Client,
+    conn: &dyn ExitStore,
+) -> Result<Option<IpNetwork>, Error> {
+    with_address_lock(conn, || assign_ipv6(client, conn))
+}
+
+fn assign_ipv6(client: &models::Client, conn: &dyn ExitStore) -> Result<Option<IpNetwork>, Error> {
+    let subnet = match get_next_client_ipv6(conn)? {
+        Some(subnet) => subnet,
+        None => return Ok(None),
+    };
+    info!("Assigning {} to client {}", subnet, client.wg_pubkey);
+    conn.update_client(&client.mesh_ip, &mut |record| {
+        record.internet_ipv6 = subnet.to_string()
+    })?;
+    Ok(Some(subnet))
+}
+
+/// The public address and port range a client with the given internal ip is translated to in
+/// cgnat mode. Clients are numbered by their offset from the start of the internal range so the
+/// mapping stays the same as long as the settings do. None if the public addresses run out
+pub fn nat_port_range(
+    cgnat: &CgnatSettings,
+    start_ip: Ipv4Addr,
+    client_ip: Ipv4Addr,
+) -> Option<NatPortRange> {
+    let ports_per_client = u32::from(cgnat.ports_per_client);
+    if ports_per_client == 0 {
+        return None;
+    }
+    let index = u32::from(client_ip).checked_sub(u32::from(start_ip))?;
+    let ranges_per_ip = (65536 - u32::from(cgnat.first_port)) / ports_per_client;
+    if ranges_per_ip == 0 {
+        return None;
+    }
+    let public_ip = *cgnat.public_ips.get((index / ranges_per_ip) as usize)?;
+    let start = u32::from(cgnat.first_port) + (index % ranges_per_ip) * ports_per_client;
+    Some(NatPortRange {
+        public_ip,
+        start: start as u16,
+        end: (start + ports_per_client - 1) as u16,
+    })
+}
+
+/// The port range a client should have under the current settings, None if cgnat is off
+pub fn get_client_nat_port_range(client_ip: IpAddr) -> Option<NatPortRange> {
+    let exit_settings = SETTING.get_exit_network();
+    let cgnat = exit_settings.cgnat.as_ref()?;
+    match client_ip {
+        IpAddr::V4(client_ip) => nat_port_range(cgnat, exit_settings.exit_start_ip, client_ip),
+        IpAddr::V6(_) => None,
+    }
+}
+
+/// Stores the port range a client is translated to, called whenever it differs from what the
+/// settings say it should be
+pub fn set_client_nat_port_range(
+    client: &models::Client,
+    range: Option<NatPortRange>,
+    conn: &dyn ExitStore,
+) -> Result<(), Error> {
+    let range = range.map(|r| r.to_string()).unwrap_or_default();
+    info!("Setting nat port range {} for {}", range, client.wg_pubkey);
+    conn.update_client(&client.mesh_ip, &mut |record| {
+        record.nat_port_range = range.clone()
+    })?;
+    Ok(())
+}
+
+/// True if the stored record belongs to exactly this client
+fn is_same_client(record: &models::Client, client: &ExitClientIdentity) -> bool {
+    record.mesh_ip == client.global.mesh_ip.to_string()
+        && record.wg_pubkey == client.global.wg_public_key.to_string()
+        && record.eth_address == client.global.eth_address.to_string()
+}
+
+/// Runs `update` on the record of exactly this client, if there is one
+fn update_exact_client(
+    client: &ExitClientIdentity,
+    conn: &dyn ExitStore,
+    update: &mut dyn FnMut(&mut models::Client),
+) -> Result<(), Error> {
+    conn.update_client(&client.global.mesh_ip.to_string(), &mut |record| {
+        if is_same_client(record, client) {
+            update(record)
+        }
+    })?;
+    Ok(())
+}
+
 /// updates the last seen time
 pub fn update_client(
     client: &ExitClientIdentity,
     their_record: &models::Client,
-    conn: &PgConnection,
+    conn: &dyn ExitStore,
 ) -> Result<(), Error> {
-    use self::schema::clients::dsl::{
-        clients, email, eth_address, last_seen, mesh_ip, phone, wg_pubkey,
-    };
-    let ip = client.global.mesh_ip;
-    let wg = client.global.wg_public_key;
-    let key = client.global.eth_address;
-    let filtered_list = clients
-        .filter(mesh_ip.eq(ip.to_string()))
-        .filter(wg_pubkey.eq(wg.to_string()))
-        .filter(eth_address.eq(key.to_string()));
-
+    let mut new_email = None;
     if let Some(mail) = client.reg_details.email.clone() {
         if their_record.email != mail {
             info!(
                 "Client {} email has changed from {} to {} updating",
                 their_record.wg_pubkey, their_record.email, mail
             );
-            diesel::update(filtered_list.clone())
-                .set(email.eq(mail))
-                .execute(&*conn)?;
+            new_email = Some(mail);
         }
     }
 
+    let mut new_phone = None;
     if let Some(number) = client.reg_details.phone.clone() {
         if their_record.phone != number {
             info!(
                 "Client {} phonenumber has changed from {} to {} updating",
                 their_record.wg_pubkey, their_record.phone, number
             );
-            diesel::update(filtered_list.clone())
-                .set(phone.eq(number))
-                .execute(&*conn)?;
+            new_phone = Some(number);
         }
     }
 
     let current_time = secs_since_unix_epoch();
     let time_since_last_update = current_time - their_record.last_seen;
     // update every 12 hours, no entry timeouts less than a day allowed
-    if time_since_last_update > ONE_DAY / 2 {
+    let bump_last_seen = time_since_last_update > ONE_DAY / 2;
+    if bump_last_seen {
         info!("Bumping client timestamp for {}", their_record.wg_pubkey);
-        diesel::update(filtered_list)
-            .set(last_seen.eq(secs_since_unix_epoch() as i64))
-            .execute(&*conn)?;
     }
 
-    Ok(())
+    if new_email.is_none() && new_phone.is_none() && !bump_last_seen {
+        return Ok(());
+    }
+    update_exact_client(client, conn, &mut |record| {
+        if let Some(mail) = new_email.clone() {
+            record.email = mail;
+        }
+        if let Some(number) = new_phone.clone() {
+            record.phone = number;
+        }
+        if bump_last_seen {
+            record.last_seen = current_time;
+        }
+    })
 }
 
 pub fn get_client(
     client: &ExitClientIdentity,
-    conn: &PgConnection,
+    conn: &dyn ExitStore,
 ) -> Result<Option<models::Client>, Error> {
-    use self::schema::clients::dsl::{clients, eth_address, mesh_ip, wg_pubkey};
     let ip = client.global.mesh_ip;
-    let wg = client.global.wg_public_key;
-    let key = client.global.eth_address;
-    let filtered_list = clients
-        .filter(mesh_ip.eq(ip.to_string()))
-        .filter(wg_pubkey.eq(wg.to_string()))
-        .filter(eth_address.eq(key.to_string()));
-    match filtered_list.load::<models::Client>(conn) {
-        Ok(entry) => {
-            if entry.len() > 1 {
-                let err_msg = format!(
-                    "More than one exact match with wg: {} eth: {} ip: {}",
-                    wg, key, ip
-                );
-                error!("{}", err_msg);
-                panic!(err_msg);
-            } else if entry.is_empty() {
-                return Ok(None);
-            }
-            Ok(Some(entry[0].clone()))
-        }
+    match conn.get_client(&ip.to_string()) {
+        Ok(entry) => Ok(entry.filter(|record| is_same_client(record, client))),
         Err(e) => {
-            error!("We failed to lookup the client {:?} with{:?}", mesh_ip, e);
+            error!("We failed to lookup the client {:?} with{:?}", ip, e);
             bail!("We failed to lookup the client!")
         }
     }
@@ -163,80 +432,39 @@ pub fn get_client(
 pub fn verify_client(
     client: &ExitClientIdentity,
     client_verified: bool,
-    conn: &PgConnection,
+    conn: &dyn ExitStore,
 ) -> Result<(), Error> {
-    use self::schema::clients::dsl::*;
-    let ip = client.global.mesh_ip;
-    let wg = client.global.wg_public_key;
-    let key = client.global.eth_address;
-    let filtered_list = clients
-        .filter(mesh_ip.eq(ip.to_string()))
-        .filter(wg_pubkey.eq(wg.to_string()))
-        .filter(eth_address.eq(key.to_string()));
-
-    diesel::update(filtered_list)
-        .set(verified.eq(client_verified))
-        .execute(&*conn)?;
-
-    Ok(())
+    update_exact_client(client, conn, &mut |record| {
+        record.verified = client_verified
+    })
 }
 
 /// Marks a client as verified in the database
 pub fn verify_db_client(
     client: &models::Client,
     client_verified: bool,
-    conn: &PgConnection,
+    conn: &dyn ExitStore,
 ) -> Result<(), Error> {
-    use self::schema::clients::dsl::*;
-    let ip = &client.mesh_ip;
-    let wg = &client.wg_pubkey;
-    let key = &client.eth_address;
-    let filtered_list = clients
-        .filter(mesh_ip.eq(ip.to_string()))
-        .filter(wg_pubkey.eq(wg.to_string()))
-        .filter(eth_address.eq(key.to_string()));
-
-    diesel::update(filtered_list)
-        .set(verified.eq(client_verified))
-        .execute(&*conn)?;
-
+    conn.update_client(&client.mesh_ip, &mut |record| {
+        if record.wg_pubkey == client.wg_pubkey && record.eth_address == client.eth_address {
+            record.verified = client_verified
+        }
+    })?;
     Ok(())
 }
 
 /// Increments the text message sent count in the database
-pub fn text_sent(client: &ExitClientIdentity, conn: &PgConnection, val: i32) -> Result<(), Error> {
-    use self::schema::clients::dsl::*;
-    let ip = client.global.mesh_ip;
-    let wg = client.global.wg_public_key;
-    let key = client.global.eth_address;
-    let filtered_list = clients
-        .filter(mesh_ip.eq(ip.to_string()))
-        .filter(wg_pubkey.eq(wg.to_string()))
-        .filter(eth_address.eq(key.to_string()));
-
-    diesel::update(filtered_list)
-        .set(text_sent.eq(val + 1))
-        .execute(&*conn)?;
-
-    Ok(())
+pub fn text_sent(client: &ExitClientIdentity, conn: &dyn ExitStore, val: i32) -> Result<(), Error> {
+    update_exact_client(client, conn, &mut |record| record.text_sent = val + 1)
 }
 
-fn client_exists(client: &ExitClientIdentity, conn: &PgConnection) -> Result<bool, Error> {
-    use self::schema::clients::dsl::*;
+fn client_exists(client: &ExitClientIdentity, conn: &dyn ExitStore) -> Result<bool, Error> {
     trace!("Checking if client exists");
-    let ip = client.global.mesh_ip;
-    let wg = client.global.wg_public_key;
-    let key = client.global.eth_address;
-    let filtered_list = clients
-        .filter(mesh_ip.eq(ip.to_string()))
-        .filter(wg_pubkey.eq(wg.to_string()))
-        .filter(eth_address.eq(key.to_string()));
-    Ok(select(exists(filtered_list)).get_result(&*conn)?)
+    Ok(get_client(client, conn)?.is_some())
 }
 
 /// True if there is any client with the same eth address, wg key, or ip address already registered
-pub fn client_conflict(client: &ExitClientIdentity, conn: &PgConnection) -> Result<bool, Error> {
-    use self::schema::clients::dsl::*;
+pub fn client_conflict(client: &ExitClientIdentity, conn: &dyn ExitStore) -> Result<bool, Error> {
     // we can't possibly have a conflict if we have exactly this client already
     // since client exists checks all major details this is safe and will return false
     // if it's not exactly the same client
@@ -244,15 +472,13 @@ pub fn client_conflict(client: &ExitClientIdentity, conn: &PgConnection) -> Resu
         return Ok(false);
     }
     trace!("Checking if client exists");
-    let ip = client.global.mesh_ip;
-    let wg = client.global.wg_public_key;
-    let key = client.global.eth_address;
-    let ip_match = clients.filter(mesh_ip.eq(ip.to_string()));
-    let wg_key_match = clients.filter(wg_pubkey.eq(wg.to_string()));
-    let eth_address_match = clients.filter(eth_address.eq(key.to_string()));
-    let ip_exists = select(exists(ip_match)).get_result(&*conn)?;
-    let wg_exists = select(exists(wg_key_match)).get_result(&*conn)?;
-    let eth_exists = select(exists(eth_address_match)).get_result(&*conn)?;
+    let ip = client.global.mesh_ip.to_string();
+    let wg = client.global.wg_public_key.to_string();
+    let key = client.global.eth_address.to_string();
+    let clients_list = conn.load_clients()?;
+    let ip_exists = clients_list.iter().any(|c| c.mesh_ip == ip);
+    let wg_exists = clients_list.iter().any(|c| c.wg_pubkey == wg);
+    let eth_exists = clients_list.iter().any(|c| c.eth_address == key);
     info!(
         "Signup conflict ip {} eth {} wg {}",
         ip_exists, eth_exists, wg_exists
@@ -260,25 +486,67 @@ pub fn client_conflict(client: &ExitClientIdentity, conn: &PgConnection) -> Resu
     Ok(ip_exists || eth_exists || wg_exists)
 }
 
-pub fn delete_client(client: ExitClient, connection: &PgConnection) -> Result<(), Error> {
-    use self::schema::clients::dsl::*;
+pub fn delete_client(client: &models::Client, conn: &dyn ExitStore) -> Result<(), Error> {
     info!("Deleting clients {:?} in database", client);
+    conn.delete_client(&client.mesh_ip)
+}
 
-    let mesh_ip_string = client.mesh_ip.to_string();
-    let statement = clients.find(&mesh_ip_string);
-    delete(statement).execute(connection)?;
+/// Archives an inactive client, their addresses go back to the pool and since setup_clients
+/// skips archived clients their wg peer is removed on the next exit loop. The internal ip is
+/// left in the record, it can't be empty, but archived clients don't count as holding it
+pub fn archive_client(client: &models::Client, conn: &dyn ExitStore) -> Result<(), Error> {
+    info!("Archiving client {} {}", client.wg_pubkey, client.mesh_ip);
+
+    let now = secs_since_unix_epoch();
+    conn.update_client(&client.mesh_ip, &mut |record| {
+        record.internet_ipv6 = String::new();
+        record.nat_port_range = String::new();
+        record.archived_time = now;
+    })?;
     Ok(())
 }
 
+/// Brings an archived client back when they sign up again, they keep their verification but
+/// get freshly allocated addresses since their old ones may have been handed out since, the
+/// ones they asked for if those are free. Only called with the address lock held
+fn restore_client(
+    client: &models::Client,
+    preferred_ip: Option<IpAddr>,
+    preferred_ipv6: Option<IpNetwork>,
+    conn: &dyn ExitStore,
+) -> Result<models::Client, Error> {
+    info!("Restoring archived client {}", client.wg_pubkey);
+
+    let new_ip = get_preferred_client_ip(preferred_ip, conn)?;
+    let new_ipv6 = get_preferred_client_ipv6(preferred_ipv6, conn)?;
+    let new_nat_port_range = get_client_nat_port_range(new_ip);
+
+    let mut restored = client.clone();
+    restored.internal_ip = new_ip.to_string();
+    restored.internet_ipv6 = new_ipv6.map(|s| s.to_string()).unwrap_or_default();
+    restored.nat_port_range = new_nat_port_range
+        .map(|r| r.to_string())
+        .unwrap_or_default();
+    restored.archived_time = 0;
+    restored.last_seen = secs_since_unix_epoch();
+
+    conn.update_client(&client.mesh_ip, &mut |record| {
+        record.internal_ip = restored.internal_ip.clone();
+        record.internet_ipv6 = restored.internet_ipv6.clone();
+        record.nat_port_range = restored.nat_port_range.clone();
+        record.archived_time = 0;
+        record.last_seen = restored.last_seen;
+    })?;
+    Ok(restored)
+}
+
 // for backwards compatibility with entires that do not have a timestamp
 // new entires will be initialized and updated as part of the normal flow
-pub fn set_client_timestamp(client: ExitClient, connection: &PgConnection) -> Result<(), Error> {
-    use self::schema::clients::dsl::*;
+pub fn set_client_timestamp(client: &models::Client, conn: &dyn ExitStore) -> Result<(), Error> {
     info!("Setting timestamp for client {:?}", client);
 
-    diesel::update(clients.find(&client.mesh_ip.to_string()))
-        .set(last_seen.eq(secs_since_unix_epoch()))
-        .execute(connection)?;
+    let now = secs_since_unix_epoch();
+    conn.update_client(&client.mesh_ip, &mut |record| record.last_seen = now)?;
     Ok(())
 }
 
@@ -286,47 +554,95 @@ pub fn set_client_timestamp(client: ExitClient, connection: &PgConnection) -> Re
 // shared emails
 pub fn update_mail_sent_time(
     client: &ExitClientIdentity,
-    conn: &PgConnection,
+    conn: &dyn ExitStore,
 ) -> Result<(), Error> {
-    use self::schema::clients::dsl::{clients, email, email_sent_time};
     let mail_addr = match client.clone().reg_details.email {
         Some(mail) => mail,
         None => bail!("Cloud not find email for {:?}", client.clone()),
     };
 
-    diesel::update(clients.filter(email.eq(mail_addr)))
-        .set(email_sent_time.eq(secs_since_unix_epoch()))
-        .execute(&*conn)?;
+    let now = secs_since_unix_epoch();
+    for record in conn.load_clients()?.iter().filter(|c| c.email == mail_addr) {
+        conn.update_client(&record.mesh_ip, &mut |record| record.email_sent_time = now)?;
+    }
 
     Ok(())
 }
 
+/// Replaces a client's email code with a fresh one and resets their attempts, returns the
+/// updated record
+pub fn replace_email_code(
+    record: &models::Client,
+    conn: &dyn ExitStore,
+) -> Result<models::Client, Error> {
+    let mut updated = record.clone();
+    updated.email_code = generate_email_code();
+    updated.email_code_time = secs_since_unix_epoch();
+    updated.email_attempts = 0;
+    conn.update_client(&record.mesh_ip, &mut |record| {
+        record.email_code = updated.email_code.clone();
+        record.email_code_time = updated.email_code_time;
+        record.email_attempts = 0;
+    })?;
+    Ok(updated)
+}
+
+/// Counts a wrong email code against a client
+pub fn email_code_failed(record: &models::Client, conn: &dyn ExitStore) -> Result<(), Error> {
+    let attempts = record.email_attempts + 1;
+    conn.update_client(&record.mesh_ip, &mut |record| {
+        record.email_attempts = attempts
+    })?;
+    Ok(())
+}
+
 pub fn update_low_balance_notification_time(
     client: &ExitClientIdentity,
-    conn: &PgConnection,
+    conn: &dyn ExitStore,
 ) -> Result<(), Error> {
-    use self::schema::clients::dsl::{clients, last_balance_warning_time, wg_pubkey};
     info!(
         "Updating low balance notification time for {} {:?}",
         client.global.wg_public_key, client
     );
+    set_low_balance_notification_time(client, secs_since_unix_epoch(), conn)
+}
 
-    diesel::update(clients.filter(wg_pubkey.eq(client.global.wg_public_key.to_string())))
-        .set(last_balance_warning_time.eq(secs_since_unix_epoch()))
-        .execute(&*conn)?;
+/// Forgets that a client was warned about their balance, once they've topped up
+pub fn clear_low_balance_notification_time(
+    client: &ExitClientIdentity,
+    conn: &dyn ExitStore,
+) -> Result<(), Error> {
+    info!(
+        "Clearing low balance notification time for {}",
+        client.global.wg_public_key
+    );
+    set_low_balance_notification_time(client, 0, conn)
+}
+
+fn set_low_balance_notification_time(
+    client: &ExitClientIdentity,
+    time: i64,
+    conn: &dyn ExitStore,
+) -> Result<(), Error> {
+    let wg = client.global.wg_public_key.to_string();
+    for record in conn.load_clients()?.iter().filter(|c| c.wg_pubkey == wg) {
+        conn.update_client(&record.mesh_ip, &mut |record| {
+            record.last_balance_warning_time = time
+        })?;
+    }
 
     Ok(())
 }
 
-/// Gets the Postgres database connection from the threadpool, gracefully waiting using futures delay if there
-/// is no connection available.
-pub fn get_database_connection(
-) -> impl Future<Item = PooledConnection<ConnectionManager<PgConnection>>, Error = Error> {
+/// Gets a connection to the store, for Postgres one from the threadpool, gracefully waiting using
+/// futures delay if there is no connection available.
+pub fn get_database_connection() -> impl Future<Item = StoreConnection, Error = Error> {
     match DB_POOL.read().unwrap().try_get() {
-        Some(connection) => Box::new(future::ok(connection))
-            as Box<
-                dyn Future<Item = PooledConnection<ConnectionManager<PgConnection>>, Error = Error>,
-            >,
+        Some(connection) => {
+            DbHealth::from_registry().do_send(DbSuccess);
+            Box::new(future::ok(connection))
+                as Box<dyn Future<Item = StoreConnection, Error = Error>>
+        }
         None => {
             trace!("No available db connection sleeping!");
             let when = Instant::now() + Duration::from_millis(100);
@@ -339,6 +655,12 @@ pub fn get_database_connection(
                         Ok(v) => Ok(v),
                         Err(e) => {
                             error!("Failed to get DB connection with {:?}", e);
+                            // a pool that's busy serving other requests says nothing about
+                            // the database, only count it if there's no connection at all
+                            if !DB_POOL.read().unwrap().connected() {
+                                DbHealth::from_registry()
+                                    .do_send(DbFailure(format!("No database connection {:?}", e)));
+                            }
                             Err(format_err!("{:?}", e))
                         }
                     }),
@@ -348,27 +670,127 @@ pub fn get_database_connection(
 }
 
 pub fn create_or_update_user_record(
-    conn: &PgConnection,
+    conn: &dyn ExitStore,
+    client: &ExitClientIdentity,
+    user_country: String,
+) -> Result<models::Client, Error> {
+    with_address_lock(conn, || create_or_update(conn, client, user_country))
+}
+
+fn create_or_update(
+    conn: &dyn ExitStore,
     client: &ExitClientIdentity,
     user_country: String,
 ) -> Result<models::Client, Error> {
-    use self::schema::clients::dsl::clients;
     if let Some(val) = get_client(&client, conn)? {
+        if is_archived(&val) {
+            return restore_client(
+                &val,
+                client.preferred_internal_ip,
+                client.preferred_ipv6_subnet,
+                conn,
+            );
+        }
         update_client(&client, &val, conn)?;
-        Ok(val)
+        migrate_addresses(&client, &val, conn)
     } else {
         info!(
             "record for {} does not exist, creating",
             client.global.wg_public_key
         );
 
-        let new_ip = get_next_client_ip(conn)?;
+        let new_ip = get_preferred_client_ip(client.preferred_internal_ip, conn)?;
+        let new_ipv6 = get_preferred_client_ipv6(client.preferred_ipv6_subnet, conn)?;
+        let new_nat_port_range = get_client_nat_port_range(new_ip);
 
-        let c = client_to_new_db_client(&client, new_ip, user_country);
+        let c =
+            client_to_new_db_client(&client, new_ip, new_ipv6, new_nat_port_range, user_country);
 
         info!("Inserting new client {}", client.global.wg_public_key);
-        diesel::insert_into(clients).values(&c).execute(conn)?;
+        conn.insert_client(&c)?;
 
         Ok(c)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_free_subnet() {
+        let pool: Ipv6Network = "2001:db8::/62".parse().unwrap();
+        let first: IpNetwork = "2001:db8:0:1::/64".parse().unwrap();
+        let second: IpNetwork = "2001:db8:0:2::/64".parse().unwrap();
+        let third: IpNetwork = "2001:db8:0:3::/64".parse().unwrap();
+
+        assert_eq!(next_free_subnet(pool, &[]).unwrap(), Some(first));
+        assert_eq!(next_free_subnet(pool, &[first]).unwrap(), Some(second));
+        assert_eq!(next_free_subnet(pool, &[second]).unwrap(), Some(first));
+        assert_eq!(
+            next_free_subnet(pool, &[first, second, third]).unwrap(),
+            None
+        );
+        assert!(next_free_subnet("2001:db8::/80".parse().unwrap(), &[]).is_err());
+    }
+
+    #[test]
+    fn test_preferred_addresses() {
+        let start: Ipv4Addr = "172.16.0.2".parse().unwrap();
+        let gateway: Ipv4Addr = "172.16.0.1".parse().unwrap();
+        let taken: Vec<Ipv4Addr> = vec!["172.16.0.5".parse().unwrap()];
+        let available = |ip: &str| ip_available(ip.parse().unwrap(), start, gateway, 16, &taken);
+
+        assert!(available("172.16.0.6"));
+        assert!(available("172.16.200.6"));
+        assert!(!available("172.16.0.5"));
+        assert!(!available("172.16.0.1"));
+        assert!(!available("172.16.0.0"));
+        assert!(!available("172.16.255.255"));
+        assert!(!available("172.17.0.6"));
+
+        let pool: Ipv6Network = "2001:db8::/62".parse().unwrap();
+        let taken: Vec<IpNetwork> = vec!["2001:db8:0:1::/64".parse().unwrap()];
+        let available = |s: &str| subnet_available(pool, s.parse().unwrap(), &taken);
+        assert!(available("2001:db8:0:2::/64"));
+        assert!(!available("2001:db8:0:1::/64"));
+        // reserved for the exit
+        assert!(!available("2001:db8::/64"));
+        assert!(!available("2001:db8:0:2::/56"));
+        assert!(!available("2001:db8:1::/64"));
+    }
+
+    #[test]
+    fn test_nat_port_range() {
+        let cgnat = CgnatSettings {
+            public_ips: vec![
+                "203.0.113.5".parse().unwrap(),
+                "203.0.113.6".parse().unwrap(),
+            ],
+            ports_per_client: 16128,
+            first_port: 1024,
+        };
+        let start: Ipv4Addr = "172.16.0.0".parse().unwrap();
+        let range = |ip: &str| nat_port_range(&cgnat, start, ip.parse().unwrap());
+
+        assert_eq!(
+            range("172.16.0.0").unwrap().to_string(),
+            "203.0.113.5:1024-17151"
+        );
+        assert_eq!(
+            range("172.16.0.3").unwrap().to_string(),
+            "203.0.113.5:49408-65535"
+        );
+        assert_eq!(
+            range("172.16.0.4").unwrap().to_string(),
+            "203.0.113.6:1024-17151"
+        );
+        // out of public addresses
+        assert_eq!(range("172.16.0.8"), None);
+        // below the start of the range
+        assert_eq!(
+            nat_port_range(&cgnat, start, "172.15.255.255".parse().unwrap()),
+            None
+        );
+    }
+}
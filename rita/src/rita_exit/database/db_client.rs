@@ -7,10 +7,6 @@ use actix::Message;
 use actix::Supervised;
 use actix::SystemService;
 use actix_web::Result;
-use diesel;
-use diesel::dsl::delete;
-use diesel::*;
-use exit_db::schema;
 use failure::Error;
 use futures01::future::Future;
 
@@ -37,12 +33,11 @@ impl Handler<TruncateTables> for DbClient {
     type Result = Result<(), Error>;
 
     fn handle(&mut self, _: TruncateTables, _: &mut Self::Context) -> Self::Result {
-        use self::schema::clients::dsl::*;
         info!("Deleting all clients in database");
         Arbiter::spawn(
             get_database_connection()
                 .and_then(|connection| {
-                    (delete(clients).execute(&connection).unwrap());
+                    connection.delete_all_clients().unwrap();
                     Ok(())
                 })
                 .then(|_| Ok(())),
@@ -0,0 +1,186 @@
+//! Tracks whether the exit can reach its database. Without it the exit can't sign up new clients
+//! or check the status of existing ones, but it can keep the tunnels it already knows about up.
+//! So once several connection attempts in a row have failed the exit goes into a degraded mode
+//! where existing clients are served from the last client list we successfully loaded and new
+//! signups are told to try again rather than left hanging. Failures are queries that error out
+//! and connection checkouts that time out with no connection to the database open at all, a
+//! pool that's merely busy doesn't count.
+
+use actix::{Actor, Context, Handler, Message, Supervised, SystemService};
+use exit_db::models;
+use failure::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How many database failures in a row before we consider the database down
+pub const DEGRADED_AFTER_FAILURES: u32 = 3;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DbStatus {
+    pub degraded: bool,
+    pub consecutive_failures: u32,
+    pub total_failures: u64,
+    /// seconds since the unix epoch
+    pub last_success: Option<u64>,
+    pub last_error: Option<String>,
+    /// the number of clients we would be serving from cache if degraded
+    pub cached_clients: usize,
+}
+
+#[derive(Default)]
+pub struct DbHealth {
+    consecutive_failures: u32,
+    total_failures: u64,
+    last_success: Option<SystemTime>,
+    last_error: Option<String>,
+    cached_clients: Vec<models::Client>,
+}
+
+impl Actor for DbHealth {
+    type Context = Context<Self>;
+}
+
+impl Supervised for DbHealth {}
+impl SystemService for DbHealth {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("Database health monitor started");
+    }
+}
+
+impl DbHealth {
+    fn degraded(&self) -> bool {
+        self.consecutive_failures >= DEGRADED_AFTER_FAILURES
+    }
+
+    fn success(&mut self) {
+        if self.degraded() {
+            info!(
+                "Database connection restored after {} failures, leaving degraded mode",
+                self.consecutive_failures
+            );
+        }
+        self.consecutive_failures = 0;
+        self.last_success = Some(SystemTime::now());
+    }
+
+    fn failure(&mut self, error: String) {
+        self.consecutive_failures += 1;
+        self.total_failures += 1;
+        if self.consecutive_failures == DEGRADED_AFTER_FAILURES {
+            error!(
+                "Database unreachable, entering degraded mode with {} cached clients",
+                self.cached_clients.len()
+            );
+        }
+        self.last_error = Some(error);
+    }
+
+    fn status(&self) -> DbStatus {
+        DbStatus {
+            degraded: self.degraded(),
+            consecutive_failures: self.consecutive_failures,
+            total_failures: self.total_failures,
+            last_success: self
+                .last_success
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+            last_error: self.last_error.clone(),
+            cached_clients: self.cached_clients.len(),
+        }
+    }
+}
+
+/// Reported whenever we get a working database connection
+#[derive(Message)]
+pub struct DbSuccess;
+
+impl Handler<DbSuccess> for DbHealth {
+    type Result = ();
+
+    fn handle(&mut self, _msg: DbSuccess, _ctx: &mut Context<Self>) -> Self::Result {
+        self.success();
+    }
+}
+
+/// Reported whenever getting a connection or running a query fails
+#[derive(Message)]
+pub struct DbFailure(pub String);
+
+impl Handler<DbFailure> for DbHealth {
+    type Result = ();
+
+    fn handle(&mut self, msg: DbFailure, _ctx: &mut Context<Self>) -> Self::Result {
+        self.failure(msg.0);
+    }
+}
+
+/// The full client list as loaded by the exit loop, kept to serve clients while degraded
+#[derive(Message)]
+pub struct UpdateClientCache(pub Vec<models::Client>);
+
+impl Handler<UpdateClientCache> for DbHealth {
+    type Result = ();
+
+    fn handle(&mut self, msg: UpdateClientCache, _ctx: &mut Context<Self>) -> Self::Result {
+        self.cached_clients = msg.0;
+    }
+}
+
+pub struct GetDbStatus;
+
+impl Message for GetDbStatus {
+    type Result = Result<DbStatus, Error>;
+}
+
+impl Handler<GetDbStatus> for DbHealth {
+    type Result = Result<DbStatus, Error>;
+
+    fn handle(&mut self, _msg: GetDbStatus, _ctx: &mut Context<Self>) -> Self::Result {
+        Ok(self.status())
+    }
+}
+
+/// Returns the cached client list if we are in degraded mode, None otherwise
+pub struct GetCachedClients;
+
+impl Message for GetCachedClients {
+    type Result = Result<Option<Vec<models::Client>>, Error>;
+}
+
+impl Handler<GetCachedClients> for DbHealth {
+    type Result = Result<Option<Vec<models::Client>>, Error>;
+
+    fn handle(&mut self, _msg: GetCachedClients, _ctx: &mut Context<Self>) -> Self::Result {
+        if self.degraded() {
+            Ok(Some(self.cached_clients.clone()))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_degraded_mode() {
+        let mut health = DbHealth::default();
+        assert!(!health.status().degraded);
+        for _ in 0..DEGRADED_AFTER_FAILURES - 1 {
+            health.failure("timeout".to_string());
+        }
+        assert!(!health.status().degraded);
+        health.failure("connection refused".to_string());
+        let status = health.status();
+        assert!(status.degraded);
+        assert_eq!(status.consecutive_failures, DEGRADED_AFTER_FAILURES);
+        assert_eq!(status.last_error, Some("connection refused".to_string()));
+
+        health.success();
+        let status = health.status();
+        assert!(!status.degraded);
+        assert_eq!(status.consecutive_failures, 0);
+        assert_eq!(status.total_failures, u64::from(DEGRADED_AFTER_FAILURES));
+        assert!(status.last_success.is_some());
+    }
+}
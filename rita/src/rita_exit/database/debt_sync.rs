@@ -0,0 +1,188 @@
+//! Debts across a cluster of exit instances sharing a database. Each instance's DebtKeeper only
+//! sees the traffic and payments that went through it, so a client asking one instance for its
+//! debt would get a different answer from each, and a client spreading its traffic across them
+//! could stay under the close threshold on every one. Instances with a `cluster_node_id`
+//! periodically write their debts to the database and read back everyone else's, a client is
+//! then told, and enforced on, its debt with this instance as it is right now plus its debts with
+//! the others as of their last sync. Instances that haven't written anything in a day are assumed
+//! to be gone.
+
+use crate::rita_common::debt_keeper::{DebtKeeper, GetDebtsList, GetDebtsResult};
+use crate::rita_common::watchdog::or_alarm;
+use crate::rita_exit::database::database_tools::get_database_connection;
+use crate::rita_exit::database::secs_since_unix_epoch;
+use crate::SETTING;
+use actix::SystemService;
+use althea_types::Wei;
+use exit_db::models::NodeDebt;
+use futures01::Future;
+use num256::Int256;
+use settings::exit::RitaExitSettings;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// How often each instance writes its debts and reads the others'
+pub const DEBT_SYNC_INTERVAL: Duration = Duration::from_secs(30);
+/// Debts written longer ago than this are left out
+const STALE_AFTER: i64 = 24 * 60 * 60;
+
+lazy_static! {
+    /// Each client's summed debt with the other instances by mesh ip, as of the last sync
+    static ref OTHER_NODE_DEBTS: Arc<RwLock<HashMap<String, Wei>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+}
+
+pub fn to_node_debts(node_id: &str, debts: &[GetDebtsResult], now: i64) -> Vec<NodeDebt> {
+    debts
+        .iter()
+        .map(|debt| NodeDebt {
+            node_id: node_id.to_string(),
+            mesh_ip: debt.identity.mesh_ip.to_string(),
+            debt: debt.payment_details.debt.to_string(),
+            updated: now,
+        })
+        .collect()
+}
+
+/// Sums the debts every other instance has written for each client
+pub fn sum_other_nodes(rows: &[NodeDebt], node_id: &str, now: i64) -> HashMap<String, Wei> {
+    let mut sums: HashMap<String, Wei> = HashMap::new();
+    for row in rows {
+        if row.node_id == node_id || row.updated + STALE_AFTER < now {
+            continue;
+        }
+        let debt: Int256 = match row.debt.parse() {
+            Ok(debt) => debt,
+            Err(_) => {
+                error!(
+                    "Node {} wrote an invalid debt for {}!",
+                    row.node_id, row.mesh_ip
+                );
+                continue;
+            }
+        };
+        let debt = Wei::from(debt);
+        let sum = sums.entry(row.mesh_ip.clone()).or_insert_with(Wei::zero);
+        *sum = or_alarm(
+            sum.checked_add(&debt),
+            sum.saturating_add(&debt),
+            "DebtSync",
+            || format!("cluster debt of {} plus {}", row.mesh_ip, debt),
+        );
+    }
+    sums
+}
+
+/// A client's debts with the other instances, None if none of them have any
+pub fn other_nodes_debt(mesh_ip: &IpAddr) -> Option<Wei> {
+    OTHER_NODE_DEBTS
+        .read()
+        .unwrap()
+        .get(&mesh_ip.to_string())
+        .cloned()
+}
+
+/// Adds a client's debts with the other instances to its debt with us, None if it has neither
+fn add_other_nodes(local: Option<Wei>, others: Option<Wei>, mesh_ip: &IpAddr) -> Option<Wei> {
+    match (local, others) {
+        (None, None) => None,
+        (Some(debt), None) | (None, Some(debt)) => Some(debt),
+        (Some(local), Some(others)) => Some(or_alarm(
+            local.checked_add(&others),
+            local.saturating_add(&others),
+            "DebtSync",
+            || format!("debt of {} plus {}", mesh_ip, others),
+        )),
+    }
+}
+
+/// A client's debt across the whole cluster, or just its debt with us if we aren't in one
+pub fn cluster_debt(mesh_ip: &IpAddr, local: Option<Wei>) -> Option<Wei> {
+    if SETTING.get_exit_network().cluster_node_id.is_none() {
+        return local;
+    }
+    add_other_nodes(local, other_nodes_debt(mesh_ip), mesh_ip)
+}
+
+/// Whether a client's debt across the cluster is past the close threshold. DebtKeeper only
+/// suspends on the debt with us, so this catches clients that are under the threshold with every
+/// instance but not with all of them together
+pub fn over_cluster_threshold(debt: &GetDebtsResult, close_threshold: &Int256) -> bool {
+    let local = Wei::from(debt.payment_details.debt.clone());
+    match cluster_debt(&debt.identity.mesh_ip, Some(local)) {
+        Some(total) => total < Wei::from(close_threshold.clone()),
+        None => false,
+    }
+}
+
+/// Writes our debts to the database and reads the others' back
+pub fn sync_cluster_debts(node_id: String) -> impl Future<Item = (), Error = ()> {
+    DebtKeeper::from_registry()
+        .send(GetDebtsList)
+        .from_err()
+        .and_then(|debts| debts)
+        .join(get_database_connection())
+        .and_then(move |(debts, conn)| {
+            let now = secs_since_unix_epoch();
+            conn.save_node_debts(&to_node_debts(&node_id, &debts, now))?;
+            let others = sum_other_nodes(&conn.load_node_debts()?, &node_id, now);
+            trace!("Synced debts with {} clients of other nodes", others.len());
+            *OTHER_NODE_DEBTS.write().unwrap() = others;
+            Ok(())
+        })
+        .then(|res| {
+            if let Err(e) = res {
+                warn!("Failed to sync debts with the cluster {:?}", e);
+            }
+            Ok(())
+        })
+}
+
+#[test]
+fn test_sum_other_nodes() {
+    let row = |node_id: &str, mesh_ip: &str, debt: &str, updated: i64| NodeDebt {
+        node_id: node_id.to_string(),
+        mesh_ip: mesh_ip.to_string(),
+        debt: debt.to_string(),
+        updated,
+    };
+    let now = 100_000;
+    let rows = vec![
+        row("a", "fd00::1", "-100", now),
+        row("b", "fd00::1", "-50", now - 10),
+        row("c", "fd00::1", "25", now),
+        row("b", "fd00::2", "-7", now),
+        // gone for too long
+        row("d", "fd00::1", "-1000", now - STALE_AFTER - 1),
+        row("c", "fd00::2", "garbage", now),
+    ];
+    let sums = sum_other_nodes(&rows, "a", now);
+    assert_eq!(sums.len(), 2);
+    assert_eq!(sums["fd00::1"], Wei::from(-25i128));
+    assert_eq!(sums["fd00::2"], Wei::from(-7i128));
+    // our own debts are never counted twice
+    assert_eq!(
+        sum_other_nodes(&rows, "b", now)["fd00::1"],
+        Wei::from(-75i128)
+    );
+}
+
+#[test]
+fn test_add_other_nodes() {
+    let ip: IpAddr = "fd00::1".parse().unwrap();
+    assert_eq!(add_other_nodes(None, None, &ip), None);
+    assert_eq!(
+        add_other_nodes(Some(Wei::from(-10i128)), None, &ip),
+        Some(Wei::from(-10i128))
+    );
+    assert_eq!(
+        add_other_nodes(None, Some(Wei::from(-7i128)), &ip),
+        Some(Wei::from(-7i128))
+    );
+    // under a close threshold of -15 with each instance, but not with both
+    let total = add_other_nodes(Some(Wei::from(-10i128)), Some(Wei::from(-7i128)), &ip).unwrap();
+    assert_eq!(total, Wei::from(-17i128));
+    assert!(total < Wei::from(-15i128));
+}
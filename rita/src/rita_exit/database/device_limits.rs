@@ -0,0 +1,310 @@
+//! Counting the devices behind each client for plans that only allow so many. Client routers NAT
+//! their ipv4 traffic, so only the addresses in a client's delegated ipv6 subnet tell its devices
+//! apart. Those are sampled from the connection tracking table every minute, off the exit loop.
+//! Devices with privacy extensions use a new temporary address every so often and keep the old
+//! ones around while their connections last, so counting addresses counts most devices several
+//! times over. Only EUI-64 interface identifiers, the ones derived from a device's mac address,
+//! stay put, so a client's device count is the most distinct EUI-64 identifiers seen in any of the
+//! recent samples. The address count is reported alongside it for operators but never enforced.
+//! Devices that only use temporary addresses, aren't online at sample time or are behind clients
+//! without an ipv6 subnet go unseen, so the device count errs low. A client over its limit is
+//! warned in its status responses and, if the exit enforces limits, held to the free tier until
+//! it's back under.
+
+use crate::rita_common::dashboard::error::{DashboardError, ErrorCode};
+use crate::rita_exit::database::store::ExitStore;
+use crate::rita_exit::database::struct_tools::parse_ipv6_subnet;
+use crate::KI;
+use crate::SETTING;
+use exit_db::models::Client;
+use failure::Error;
+use ipnetwork::IpNetwork;
+use settings::exit::{DeviceLimitSettings, RitaExitSettings};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// How often the connection tracking table is sampled
+pub const DEVICE_SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+/// How many samples a client's count is taken over
+const DEVICE_SAMPLES_KEPT: usize = 15;
+
+lazy_static! {
+    /// The last few samples by client mesh ip, newest first
+    static ref DEVICE_SAMPLES: Arc<RwLock<HashMap<String, VecDeque<DeviceSample>>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+    /// Set while a sample is being taken so that a slow conntrack doesn't pile up threads
+    static ref SAMPLING: AtomicBool = AtomicBool::new(false);
+}
+
+/// What one sample saw behind a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DeviceSample {
+    /// Distinct EUI-64 interface identifiers
+    pub devices: usize,
+    /// Every address, temporary ones included
+    pub addresses: usize,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ClientDevices {
+    pub mesh_ip: String,
+    /// None for clients that haven't been sampled, like those without an ipv6 subnet
+    pub devices: Option<usize>,
+    /// The most addresses seen in a sample, this overcounts devices with privacy extensions so
+    /// it's only informational
+    pub addresses: Option<usize>,
+    /// None if there's no limit for this client
+    pub limit: Option<u32>,
+    pub over_limit: bool,
+}
+
+/// The mesh ip and ipv6 subnet of every client that has one
+fn client_subnets(clients: &[Client]) -> Vec<(String, IpNetwork)> {
+    clients
+        .iter()
+        .filter_map(|client| Some((client.mesh_ip.clone(), parse_ipv6_subnet(client)?)))
+        .collect()
+}
+
+/// If the interface identifier of this address was made from a mac address, those have ff:fe
+/// in the middle
+fn is_eui64(ip: &Ipv6Addr) -> bool {
+    let octets = ip.octets();
+    octets[11] == 0xff && octets[12] == 0xfe
+}
+
+/// What of `sources` is in each client's ipv6 subnet
+pub fn count_devices(
+    subnets: &[(String, IpNetwork)],
+    sources: &HashSet<IpAddr>,
+) -> HashMap<String, DeviceSample> {
+    let mut counts = HashMap::new();
+    for (mesh_ip, subnet) in subnets {
+        let mut sample = DeviceSample::default();
+        let mut identifiers = HashSet::new();
+        for ip in sources.iter().filter(|ip| subnet.contains(**ip)) {
+            sample.addresses += 1;
+            if let IpAddr::V6(ip) = ip {
+                if is_eui64(ip) {
+                    identifiers.insert(ip.segments()[4..].to_vec());
+                }
+            }
+        }
+        sample.devices = identifiers.len();
+        counts.insert(mesh_ip.clone(), sample);
+    }
+    counts
+}
+
+/// Adds a sample to the history, clients that are no longer in `counts` are forgotten
+fn record_samples(
+    history: &mut HashMap<String, VecDeque<DeviceSample>>,
+    counts: HashMap<String, DeviceSample>,
+) {
+    history.retain(|mesh_ip, _| counts.contains_key(mesh_ip));
+    for (mesh_ip, devices) in counts {
+        let samples = history.entry(mesh_ip).or_insert_with(VecDeque::new);
+        samples.push_front(devices);
+        samples.truncate(DEVICE_SAMPLES_KEPT);
+    }
+}
+
+/// Samples the connection tracking table on its own thread, if device limits are turned on and
+/// the last sample is done, listing a busy exit's connections can take a while
+pub fn sample_devices(clients: &[Client]) {
+    if SETTING.get_exit_network().device_limits.is_none() {
+        DEVICE_SAMPLES.write().unwrap().clear();
+        return;
+    }
+    if SAMPLING.swap(true, Ordering::SeqCst) {
+        warn!("The last device sample is still running, skipping this one");
+        return;
+    }
+    let subnets = client_subnets(clients);
+    thread::spawn(move || {
+        match KI.get_conntrack_sources() {
+            Ok(sources) => {
+                let counts = count_devices(&subnets, &sources);
+                trace!("Sampled devices for {} clients", counts.len());
+                record_samples(&mut DEVICE_SAMPLES.write().unwrap(), counts);
+            }
+            Err(e) => warn!("Failed to sample connections to count devices {:?}", e),
+        }
+        SAMPLING.store(false, Ordering::SeqCst);
+    });
+}
+
+/// The most devices and addresses seen behind this client in the recent samples
+pub fn device_count(mesh_ip: &str) -> Option<DeviceSample> {
+    let samples = DEVICE_SAMPLES.read().unwrap();
+    let samples = samples.get(mesh_ip)?;
+    Some(DeviceSample {
+        devices: samples.iter().map(|sample| sample.devices).max()?,
+        addresses: samples.iter().map(|sample| sample.addresses).max()?,
+    })
+}
+
+/// The client's own limit if it has one, otherwise the default
+pub fn device_limit(client: &Client, settings: &DeviceLimitSettings) -> Option<u32> {
+    if client.device_limit > 0 {
+        Some(client.device_limit as u32)
+    } else if settings.default_limit > 0 {
+        Some(settings.default_limit)
+    } else {
+        None
+    }
+}
+
+fn client_devices(client: &Client, settings: Option<&DeviceLimitSettings>) -> ClientDevices {
+    let sample = device_count(&client.mesh_ip);
+    let devices = sample.map(|sample| sample.devices);
+    let limit = settings.and_then(|settings| device_limit(client, settings));
+    ClientDevices {
+        mesh_ip: client.mesh_ip.clone(),
+        devices,
+        addresses: sample.map(|sample| sample.addresses),
+        limit,
+        over_limit: match (devices, limit) {
+            (Some(devices), Some(limit)) => devices > limit as usize,
+            _ => false,
+        },
+    }
+}
+
+/// Every client's device count and limit
+pub fn device_report(clients: &[Client]) -> Vec<ClientDevices> {
+    let settings = SETTING.get_exit_network().device_limits.clone();
+    clients
+        .iter()
+        .map(|client| client_devices(client, settings.as_ref()))
+        .collect()
+}
+
+/// What to tell a client that's over its limit, read from the live settings so that limits can
+/// be changed without a restart
+pub fn device_limit_warning(client: &Client) -> Option<String> {
+    let settings = SETTING.get_exit_network().device_limits.clone()?;
+    let report = client_devices(client, Some(&settings));
+    if !report.over_limit {
+        return None;
+    }
+    let (devices, limit) = (report.devices?, report.limit?);
+    Some(if settings.enforce {
+        format!(
+            "{} devices are online but your plan allows {}, you're limited to the free tier until some go offline",
+            devices, limit
+        )
+    } else {
+        format!(
+            "{} devices are online but your plan allows {}, please upgrade your plan or disconnect some",
+            devices, limit
+        )
+    })
+}
+
+/// If this client should be held to the free tier for having too many devices
+pub fn enforce_device_limit(client: &Client) -> bool {
+    match SETTING.get_exit_network().device_limits.clone() {
+        Some(settings) => settings.enforce && client_devices(client, Some(&settings)).over_limit,
+        None => false,
+    }
+}
+
+/// Sets a client's limit, 0 to go back to the default
+pub fn set_device_limit(mesh_ip: &str, limit: u32, conn: &dyn ExitStore) -> Result<(), Error> {
+    if limit > i32::max_value() as u32 {
+        return Err(DashboardError::invalid_input(format!("{} is too many devices", limit)).into());
+    }
+    if !conn.update_client(mesh_ip, &mut |record| record.device_limit = limit as i32)? {
+        return Err(DashboardError::new(
+            ErrorCode::NotFound,
+            format!("No client with mesh ip {}", mesh_ip),
+        )
+        .into());
+    }
+    info!("Set the device limit of {} to {}", mesh_ip, limit);
+    Ok(())
+}
+
+#[test]
+fn test_device_limits() {
+    let client = |mesh_ip: &str, subnet: &str, device_limit: i32| Client {
+        mesh_ip: mesh_ip.to_string(),
+        internet_ipv6: subnet.to_string(),
+        device_limit,
+        ..Default::default()
+    };
+    let clients = vec![
+        client("fd00::1", "2001:db8:1::/64", 0),
+        client("fd00::2", "2001:db8:2::/64", 5),
+        client("fd00::3", "", 0),
+    ];
+    let subnets = client_subnets(&clients);
+    assert_eq!(subnets.len(), 2);
+    let sources: HashSet<IpAddr> = vec![
+        // one device with its stable address and two temporary ones
+        "2001:db8:1::211:22ff:fe33:4455",
+        "2001:db8:1::1c2a:9e4f:7b31:d0a2",
+        "2001:db8:1::5e11:2b7c:8d40:91f3",
+        // another device with only its stable address
+        "2001:db8:1::211:22ff:fe33:6677",
+        "2001:db8:2::10",
+        // ipv4 is behind the client's NAT
+        "172.168.1.2",
+    ]
+    .into_iter()
+    .map(|ip| ip.parse().unwrap())
+    .collect();
+    let counts = count_devices(&subnets, &sources);
+    assert_eq!(counts.len(), 2);
+    assert_eq!(
+        counts["fd00::1"],
+        DeviceSample {
+            devices: 2,
+            addresses: 4
+        }
+    );
+    assert_eq!(
+        counts["fd00::2"],
+        DeviceSample {
+            devices: 0,
+            addresses: 1
+        }
+    );
+
+    let sample = |devices| DeviceSample {
+        devices,
+        addresses: devices,
+    };
+    let mut history = HashMap::new();
+    record_samples(&mut history, counts);
+    let mut quieter = HashMap::new();
+    quieter.insert("fd00::1".to_string(), sample(1));
+    record_samples(&mut history, quieter);
+    assert_eq!(history["fd00::1"][0], sample(1));
+    assert_eq!(history["fd00::1"].len(), 2);
+    // fd00::2 wasn't in the last sample
+    assert!(!history.contains_key("fd00::2"));
+    for _ in 0..DEVICE_SAMPLES_KEPT {
+        let mut counts = HashMap::new();
+        counts.insert("fd00::1".to_string(), sample(3));
+        record_samples(&mut history, counts);
+    }
+    assert!(history["fd00::1"].iter().all(|kept| *kept == sample(3)));
+
+    let settings = DeviceLimitSettings {
+        default_limit: 2,
+        enforce: false,
+    };
+    assert_eq!(device_limit(&clients[0], &settings), Some(2));
+    assert_eq!(device_limit(&clients[1], &settings), Some(5));
+    let unlimited = DeviceLimitSettings {
+        default_limit: 0,
+        enforce: false,
+    };
+    assert_eq!(device_limit(&clients[0], &unlimited), None);
+}
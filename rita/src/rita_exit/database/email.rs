@@ -1,83 +1,206 @@
+use crate::rita_exit::database::client_status;
+use crate::rita_exit::database::database_tools::email_code_failed;
+use crate::rita_exit::database::database_tools::get_client;
+use crate::rita_exit::database::database_tools::get_database_connection;
+use crate::rita_exit::database::database_tools::replace_email_code;
 use crate::rita_exit::database::database_tools::update_mail_sent_time;
 use crate::rita_exit::database::database_tools::verify_client;
 use crate::rita_exit::database::get_exit_info;
+use crate::rita_exit::database::plans::client_plan;
+use crate::rita_exit::database::port_policy::client_port_blocks;
 use crate::rita_exit::database::secs_since_unix_epoch;
+use crate::rita_exit::database::store::ExitStore;
+use crate::rita_exit::database::struct_tools::parse_ipv6_subnet;
+use crate::rita_exit::database::struct_tools::parse_nat_port_range;
+use crate::rita_exit::database::struct_tools::parse_public_ipv4;
 use crate::rita_exit::database::struct_tools::verif_done;
+use crate::rita_exit::database::SIGNUP_RETRY_SECS;
+use crate::rita_exit::notifications::{render, Deliver, Notification, Notifier, Notify, Recipient};
+use crate::EXIT_VERIF_SETTINGS;
 use crate::SETTING;
+use actix::SystemService;
 use althea_types::{ExitClientDetails, ExitClientIdentity, ExitState};
-use diesel;
-use diesel::prelude::PgConnection;
 use exit_db::models;
 use failure::Error;
 use futures01::future;
+use futures01::future::Either;
 use futures01::future::Future;
-use handlebars::Handlebars;
-use lettre::file::FileTransport;
-use lettre::smtp::authentication::{Credentials, Mechanism};
-use lettre::smtp::extension::ClientId;
-use lettre::smtp::ConnectionReuseParameters;
-use lettre::{SmtpClient, Transport};
-use lettre_email::EmailBuilder;
 use settings::exit::EmailVerifSettings;
 use settings::exit::ExitVerifSettings;
 use settings::exit::RitaExitSettings;
 
-pub fn send_mail(client: &models::Client) -> Result<(), Error> {
+/// The signup email with the client's verification code
+fn code_mail(client: &models::Client) -> Result<Notification, Error> {
     let mailer = match SETTING.get_verif_settings() {
         Some(ExitVerifSettings::Email(mailer)) => mailer,
         Some(_) => bail!("Verification mode is not email!"),
         None => bail!("No verification mode configured!"),
     };
 
+    let context = json!({
+        "email": client.email,
+        "email_code": client.email_code.to_string(),
+    });
+    Ok(Notification {
+        to: Recipient::Email(client.email.clone()),
+        subject: render(&mailer.signup_subject, &context)?,
+        body: render(&mailer.signup_body, &context)?,
+    })
+}
+
+/// Where an email signup got to, the code may still have to be sent
+pub enum EmailStep {
+    Done(ExitState),
+    /// the code email, and what to tell the client once it's sent
+    SendCode(Notification, ExitState),
+}
+
+/// Sends the code email if there is one, the cooldown only starts once it's out. If it can't be
+/// sent the client is told to try again rather than waiting on a code that never comes
+pub fn send_email_step(
+    client: ExitClientIdentity,
+    step: EmailStep,
+) -> impl Future<Item = ExitState, Error = Error> {
+    let (mail, state) = match step {
+        EmailStep::Done(state) => return Either::A(future::ok(state)),
+        EmailStep::SendCode(mail, state) => (mail, state),
+    };
     info!("Sending exit signup email for client");
+    Either::B(
+        Notifier::from_registry()
+            .send(Deliver(mail))
+            .from_err()
+            .and_then(|res| res)
+            .then(move |res| match res {
+                Ok(()) => Either::A(get_database_connection().and_then(move |conn| {
+                    update_mail_sent_time(&client, &conn)?;
+                    Ok(state)
+                })),
+                Err(e) => {
+                    error!("Failed to send the email code to {:?} with {:?}", client, e);
+                    Either::B(future::ok(ExitState::GotInfo {
+                        general_details: get_exit_info(),
+                        message: "Failed to send the verification email, try again shortly"
+                            .to_string(),
+                        auto_register: true,
+                        retry_after: Some(SIGNUP_RETRY_SECS),
+                    }))
+                }
+            }),
+    )
+}
+
+/// What a code a client submitted amounts to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodeCheck {
+    Correct,
+    /// with the attempts they have left, None if there's no limit
+    Wrong(Option<u32>),
+    /// the code is too old to be used, they need a new one
+    Expired,
+    /// they've used up their attempts on this code, they need a new one
+    Exhausted,
+}
 
-    let reg = Handlebars::new();
-
-    let email = EmailBuilder::new()
-        .to(client.email.clone())
-        .from(mailer.from_address)
-        .subject(mailer.signup_subject)
-        // TODO: maybe have a proper templating engine
-        .text(reg.render_template(
-            &mailer.signup_body,
-            &json!({"email_code": client.email_code.to_string()}),
-        )?)
-        .build()?;
-
-    if mailer.test {
-        let mut mailer = FileTransport::new(&mailer.test_dir);
-        mailer.send(email.into())?;
+/// When the client's current code was made, codes from before codes expired count from when
+/// they were last sent
+fn code_time(record: &models::Client) -> i64 {
+    if record.email_code_time != 0 {
+        record.email_code_time
     } else {
-        // TODO add serde to lettre
-        let mut mailer = SmtpClient::new_simple(&mailer.smtp_url)?
-            .hello_name(ClientId::Domain(mailer.smtp_domain))
-            .credentials(Credentials::new(mailer.smtp_username, mailer.smtp_password))
-            .smtp_utf8(true)
-            .authentication_mechanism(Mechanism::Plain)
-            .connection_reuse(ConnectionReuseParameters::ReuseUnlimited)
-            .transport();
-        mailer.send(email.into())?;
+        record.email_sent_time
     }
+}
 
-    Ok(())
+fn code_expired(record: &models::Client, now: i64, mailer: &EmailVerifSettings) -> bool {
+    mailer.code_expiry != 0 && now - code_time(record) > mailer.code_expiry as i64
+}
+
+/// Wrong codes the client can still submit, None if there's no limit
+pub fn attempts_left(record: &models::Client, mailer: &EmailVerifSettings) -> Option<u32> {
+    if mailer.code_attempts == 0 {
+        return None;
+    }
+    Some(
+        mailer
+            .code_attempts
+            .saturating_sub(record.email_attempts.max(0) as u32),
+    )
+}
+
+pub fn check_email_code(
+    record: &models::Client,
+    code: &str,
+    now: i64,
+    mailer: &EmailVerifSettings,
+) -> CodeCheck {
+    if code_expired(record, now, mailer) {
+        return CodeCheck::Expired;
+    }
+    let left = attempts_left(record, mailer);
+    if left == Some(0) {
+        return CodeCheck::Exhausted;
+    }
+    if code == record.email_code {
+        CodeCheck::Correct
+    } else {
+        CodeCheck::Wrong(left.map(|left| left - 1))
+    }
+}
+
+/// If the client needs a new code before one is sent, codes from before codes expired are
+/// always replaced since there's no telling how old they are
+fn needs_new_code(record: &models::Client, now: i64, mailer: &EmailVerifSettings) -> bool {
+    record.email_code_time == 0
+        || code_expired(record, now, mailer)
+        || attempts_left(record, mailer) == Some(0)
 }
 
 /// handles the minutia of emails and cooldowns
 pub fn handle_email_registration(
     client: &ExitClientIdentity,
     their_record: &exit_db::models::Client,
-    conn: &PgConnection,
-    cooldown: i64,
+    conn: &dyn ExitStore,
+    mailer: &EmailVerifSettings,
 ) -> impl Future<Item = ExitState, Error = Error> {
-    let mut their_record = their_record.clone();
-    if client.reg_details.email_code == Some(their_record.email_code.clone()) {
-        info!("email verification complete for {:?}", client);
+    let client = client.clone();
+    future::result(email_registration(&client, their_record, conn, mailer))
+        .and_then(move |step| send_email_step(client, step))
+}
 
-        match verify_client(&client, true, &conn) {
-            Ok(_) => (),
-            Err(e) => return future::err(e),
+fn email_registration(
+    client: &ExitClientIdentity,
+    their_record: &exit_db::models::Client,
+    conn: &dyn ExitStore,
+    mailer: &EmailVerifSettings,
+) -> Result<EmailStep, Error> {
+    let now = secs_since_unix_epoch();
+    let mut their_record = their_record.clone();
+    let mut problem = None;
+    if let Some(code) = client.reg_details.email_code.as_ref() {
+        match check_email_code(&their_record, code, now, mailer) {
+            CodeCheck::Correct => {
+                info!("email verification complete for {:?}", client);
+                verify_client(&client, true, &conn)?;
+                their_record.verified = true;
+            }
+            CodeCheck::Wrong(remaining_attempts) => {
+                info!("Wrong email code from {:?}", client);
+                email_code_failed(&their_record, conn)?;
+                return Ok(EmailStep::Done(ExitState::Pending {
+                    general_details: get_exit_info(),
+                    message: match remaining_attempts {
+                        Some(left) => format!("Wrong email code, {} attempts left", left),
+                        None => "Wrong email code".to_string(),
+                    },
+                    email_code: None,
+                    phone_code: None,
+                    remaining_attempts,
+                }));
+            }
+            CodeCheck::Expired => problem = Some("Email code expired"),
+            CodeCheck::Exhausted => problem = Some("Too many wrong email codes"),
         }
-        their_record.verified = true;
     }
 
     if verif_done(&their_record) {
@@ -85,67 +208,189 @@ pub fn handle_email_registration(
 
         let client_internal_ip = match their_record.internal_ip.parse() {
             Ok(ip) => ip,
-            Err(e) => return future::err(format_err!("{:?}", e)),
+            Err(e) => bail!("{:?}", e),
         };
-        future::ok(ExitState::Registered {
-            our_details: ExitClientDetails { client_internal_ip },
+        Ok(EmailStep::Done(ExitState::Registered {
+            our_details: ExitClientDetails {
+                client_internal_ip,
+                internet_ipv6_subnet: parse_ipv6_subnet(&their_record),
+                nat_port_range: parse_nat_port_range(&their_record),
+                public_ipv4: parse_public_ipv4(&their_record),
+                port_blocks: client_port_blocks(&their_record.mesh_ip),
+                plan: client_plan(&their_record),
+            },
             general_details: get_exit_info(),
             message: "Registration OK".to_string(),
-        })
+        }))
+    } else {
+        send_email_code(client, their_record, conn, mailer, now, problem)
+    }
+}
+
+/// Gets the client's code ready to email unless they're still in the cooldown, it's replaced first
+/// if it can't be used anymore. `problem` is why they need it, if it's not their first
+fn send_email_code(
+    client: &ExitClientIdentity,
+    their_record: models::Client,
+    conn: &dyn ExitStore,
+    mailer: &EmailVerifSettings,
+    now: i64,
+    problem: Option<&str>,
+) -> Result<EmailStep, Error> {
+    let cooldown = mailer.email_cooldown as i64;
+    let time_since_last_email = now - their_record.email_sent_time;
+    if time_since_last_email < cooldown {
+        let wait = cooldown - time_since_last_email;
+        return Ok(EmailStep::Done(ExitState::GotInfo {
+            general_details: get_exit_info(),
+            message: match problem {
+                Some(problem) => format!("{}, wait {} more seconds for a new one", problem, wait),
+                None => format!("Wait {} more seconds for verification cooldown", wait),
+            },
+            auto_register: true,
+            retry_after: None,
+        }));
+    }
+
+    let their_record = if needs_new_code(&their_record, now, mailer) {
+        replace_email_code(&their_record, conn)?
     } else {
-        let time_since_last_email = secs_since_unix_epoch() - their_record.email_sent_time;
+        their_record
+    };
+    Ok(EmailStep::SendCode(
+        code_mail(&their_record)?,
+        ExitState::Pending {
+            general_details: get_exit_info(),
+            message: match problem {
+                Some(problem) => format!("{}, a new one has been sent", problem),
+                None => "awaiting email verification".to_string(),
+            },
+            email_code: None,
+            phone_code: None,
+            remaining_attempts: attempts_left(&their_record, mailer),
+        },
+    ))
+}
 
-        if time_since_last_email < cooldown {
-            future::ok(ExitState::GotInfo {
+/// Sends a client their email code again, or a new one if it can't be used anymore, as long as
+/// they're not in the cooldown, the step is finished with `send_email_step`
+pub fn resend_email_code(
+    client: &ExitClientIdentity,
+    conn: &dyn ExitStore,
+) -> Result<EmailStep, Error> {
+    let mailer = match EXIT_VERIF_SETTINGS.clone() {
+        Some(ExitVerifSettings::Email(mailer)) => mailer,
+        _ => {
+            return Ok(EmailStep::Done(ExitState::Denied {
+                message: "This exit doesn't verify by email".to_string(),
+            }))
+        }
+    };
+    let their_record = match get_client(client, conn)? {
+        Some(record) => record,
+        None => {
+            return Ok(EmailStep::Done(ExitState::GotInfo {
                 general_details: get_exit_info(),
-                message: format!(
-                    "Wait {} more seconds for verification cooldown",
-                    cooldown - time_since_last_email
-                ),
+                message: "Sign up before asking for a new code".to_string(),
                 auto_register: true,
-            })
-        } else {
-            match update_mail_sent_time(&client, &conn) {
-                Ok(_) => (),
-                Err(e) => return future::err(e),
-            }
-            match send_mail(&their_record) {
-                Ok(_) => (),
-                Err(e) => return future::err(e),
-            }
-            future::ok(ExitState::Pending {
-                general_details: get_exit_info(),
-                message: "awaiting email verification".to_string(),
-                email_code: None,
-                phone_code: None,
-            })
+                retry_after: None,
+            }))
         }
+    };
+    if verif_done(&their_record) {
+        return Ok(EmailStep::Done(client_status(client.clone(), conn)?));
     }
+    info!("Resending the email code for {:?}", client);
+    send_email_code(
+        client,
+        their_record,
+        conn,
+        &mailer,
+        secs_since_unix_epoch(),
+        None,
+    )
 }
 
 pub fn send_low_balance_email(email: &str, mailer: EmailVerifSettings) -> Result<(), Error> {
     info!("Sending low balance email to {}", email);
 
-    let email = EmailBuilder::new()
-        .to(email)
-        .from(mailer.from_address)
-        .subject(mailer.balance_notification_subject)
-        .text(mailer.balance_notification_body)
-        .build()?;
+    let context = json!({ "email": email });
+    Notifier::from_registry().do_send(Notify(Notification {
+        to: Recipient::Email(email.to_string()),
+        subject: render(&mailer.balance_notification_subject, &context)?,
+        body: render(&mailer.balance_notification_body, &context)?,
+    }));
 
-    if mailer.test {
-        let mut mailer = FileTransport::new(&mailer.test_dir);
-        mailer.send(email.into())?;
-    } else {
-        let mut mailer = SmtpClient::new_simple(&mailer.smtp_url)?
-            .hello_name(ClientId::Domain(mailer.smtp_domain))
-            .credentials(Credentials::new(mailer.smtp_username, mailer.smtp_password))
-            .smtp_utf8(true)
-            .authentication_mechanism(Mechanism::Plain)
-            .connection_reuse(ConnectionReuseParameters::ReuseUnlimited)
-            .transport();
-        mailer.send(email.into())?;
-    }
+    Ok(())
+}
+
+pub fn send_balance_restored_email(email: &str, mailer: EmailVerifSettings) -> Result<(), Error> {
+    info!("Sending balance restored email to {}", email);
+
+    let context = json!({ "email": email });
+    Notifier::from_registry().do_send(Notify(Notification {
+        to: Recipient::Email(email.to_string()),
+        subject: render(&mailer.balance_restored_subject, &context)?,
+        body: render(&mailer.balance_restored_body, &context)?,
+    }));
 
     Ok(())
 }
+
+#[test]
+fn test_check_email_code() {
+    let mailer = EmailVerifSettings {
+        code_expiry: 3600,
+        code_attempts: 3,
+        ..Default::default()
+    };
+    let now = 1_580_000_000;
+    let record = models::Client {
+        email_code: "012345".to_string(),
+        email_code_time: now - 60,
+        email_sent_time: now - 60,
+        ..Default::default()
+    };
+    assert_eq!(
+        check_email_code(&record, "012345", now, &mailer),
+        CodeCheck::Correct
+    );
+    assert_eq!(
+        check_email_code(&record, "999999", now, &mailer),
+        CodeCheck::Wrong(Some(2))
+    );
+    assert!(!needs_new_code(&record, now, &mailer));
+
+    let tried = models::Client {
+        email_attempts: 3,
+        ..record.clone()
+    };
+    // even the right code is no good once they're out of attempts
+    assert_eq!(
+        check_email_code(&tried, "012345", now, &mailer),
+        CodeCheck::Exhausted
+    );
+    assert!(needs_new_code(&tried, now, &mailer));
+    assert_eq!(
+        check_email_code(&record, "012345", now + 3600, &mailer),
+        CodeCheck::Expired
+    );
+
+    // codes from before expiry count from when they were sent, and are replaced on the next send
+    let legacy = models::Client {
+        email_code_time: 0,
+        ..record.clone()
+    };
+    assert_eq!(
+        check_email_code(&legacy, "012345", now, &mailer),
+        CodeCheck::Correct
+    );
+    assert!(needs_new_code(&legacy, now, &mailer));
+
+    let unlimited = EmailVerifSettings::default();
+    assert_eq!(attempts_left(&tried, &unlimited), None);
+    assert_eq!(
+        check_email_code(&tried, "999999", now + 1_000_000, &unlimited),
+        CodeCheck::Wrong(None)
+    );
+}
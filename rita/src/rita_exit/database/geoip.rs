@@ -1,53 +1,37 @@
+use crate::rita_common::route_cache::get_routes;
 use crate::GEOIP_CACHE;
 use crate::KI;
 use crate::SETTING;
 use actix_web::client as actix_client;
 use actix_web::HttpMessage;
-use babel_monitor::open_babel_stream;
-use babel_monitor::parse_routes;
-use babel_monitor::start_connection;
 use failure::Error;
 use future::Either;
 use futures01::future;
 use futures01::future::Future;
 use ipnetwork::IpNetwork;
 use settings::exit::RitaExitSettings;
-use settings::RitaCommonSettings;
 use std::collections::HashMap;
 use std::net::IpAddr;
 
 /// gets the gateway ip for a given mesh IP
 pub fn get_gateway_ip_single(mesh_ip: IpAddr) -> Box<dyn Future<Item = IpAddr, Error = Error>> {
-    let babel_port = SETTING.get_network().babel_port;
-
-    Box::new(
-        open_babel_stream(babel_port)
-            .from_err()
-            .and_then(move |stream| {
-                start_connection(stream).and_then(move |stream| {
-                    parse_routes(stream).and_then(move |routes| {
-                        let mut route_to_des = None;
-                        for route in routes.1.iter() {
-                            // Only ip6
-                            if let IpNetwork::V6(ref ip) = route.prefix {
-                                // Only host addresses and installed routes
-                                if ip.prefix() == 128
-                                    && route.installed
-                                    && IpAddr::V6(ip.ip()) == mesh_ip
-                                {
-                                    route_to_des = Some(route.clone());
-                                }
-                            }
-                        }
+    Box::new(get_routes().and_then(move |cached| {
+        let mut route_to_des = None;
+        for route in cached.routes.iter() {
+            // Only ip6
+            if let IpNetwork::V6(ref ip) = route.prefix {
+                // Only host addresses and installed routes
+                if ip.prefix() == 128 && route.installed && IpAddr::V6(ip.ip()) == mesh_ip {
+                    route_to_des = Some(route.clone());
+                }
+            }
+        }
 
-                        match route_to_des {
-                            Some(route) => Ok(KI.get_wg_remote_ip(&route.iface)?),
-                            None => bail!("No route found for mesh ip: {:?}", mesh_ip),
-                        }
-                    })
-                })
-            }),
-    )
+        match route_to_des {
+            Some(route) => Ok(KI.get_wg_remote_ip(&route.iface)?),
+            None => bail!("No route found for mesh ip: {:?}", mesh_ip),
+        }
+    }))
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -61,51 +45,43 @@ pub struct IpPair {
 pub fn get_gateway_ip_bulk(
     mesh_ip_list: Vec<IpAddr>,
 ) -> Box<dyn Future<Item = Vec<IpPair>, Error = Error>> {
-    let babel_port = SETTING.get_network().babel_port;
     trace!("getting gateway ip bulk");
 
-    Box::new(open_babel_stream(babel_port).from_err().and_then(|stream| {
-        start_connection(stream).and_then(|stream| {
-            parse_routes(stream).and_then(|routes| {
-                trace!("done talking to babel for gateway ip bulk");
-                let mut remote_ip_cache: HashMap<String, IpAddr> = HashMap::new();
-                let mut results = Vec::new();
-                for mesh_ip in mesh_ip_list {
-                    for route in routes.1.iter() {
-                        // Only ip6
-                        if let IpNetwork::V6(ref ip) = route.prefix {
-                            // Only host addresses and installed routes
-                            if ip.prefix() == 128
-                                && route.installed
-                                && IpAddr::V6(ip.ip()) == mesh_ip
-                            {
-                                // check if we've already looked up this interface this round, since gateways
-                                // have many clients this will often be the case
-                                if let Some(remote_ip) = remote_ip_cache.get(&route.iface) {
+    Box::new(get_routes().and_then(|cached| {
+        trace!("got routes for gateway ip bulk");
+        let mut remote_ip_cache: HashMap<String, IpAddr> = HashMap::new();
+        let mut results = Vec::new();
+        for mesh_ip in mesh_ip_list {
+            for route in cached.routes.iter() {
+                // Only ip6
+                if let IpNetwork::V6(ref ip) = route.prefix {
+                    // Only host addresses and installed routes
+                    if ip.prefix() == 128 && route.installed && IpAddr::V6(ip.ip()) == mesh_ip {
+                        // check if we've already looked up this interface this round, since gateways
+                        // have many clients this will often be the case
+                        if let Some(remote_ip) = remote_ip_cache.get(&route.iface) {
+                            results.push(IpPair {
+                                mesh_ip,
+                                gateway_ip: *remote_ip,
+                            });
+                        } else {
+                            match KI.get_wg_remote_ip(&route.iface) {
+                                Ok(remote_ip) => {
+                                    remote_ip_cache.insert(route.iface.clone(), remote_ip);
                                     results.push(IpPair {
                                         mesh_ip,
-                                        gateway_ip: *remote_ip,
-                                    });
-                                } else {
-                                    match KI.get_wg_remote_ip(&route.iface) {
-                                        Ok(remote_ip) => {
-                                            remote_ip_cache.insert(route.iface.clone(), remote_ip);
-                                            results.push(IpPair {
-                                                mesh_ip,
-                                                gateway_ip: remote_ip,
-                                            })
-                                        }
-                                        Err(e) => error!("Failure looking up remote ip {:?}", e),
-                                    }
+                                        gateway_ip: remote_ip,
+                                    })
                                 }
+                                Err(e) => error!("Failure looking up remote ip {:?}", e),
                             }
                         }
                     }
                 }
+            }
+        }
 
-                Ok(results)
-            })
-        })
+        Ok(results)
     }))
 }
 
@@ -5,31 +5,52 @@
 use crate::rita_common::debt_keeper::DebtAction;
 use crate::rita_common::debt_keeper::DebtKeeper;
 use crate::rita_common::debt_keeper::GetDebtsList;
+use crate::rita_common::exit_terms::{sign_announcement, sign_terms};
+use crate::rita_common::schedule;
+use crate::rita_common::utils::now_secs;
+use crate::rita_exit::database::database_tools::assign_client_ipv6;
+use crate::rita_exit::database::database_tools::clear_low_balance_notification_time;
 use crate::rita_exit::database::database_tools::client_conflict;
 use crate::rita_exit::database::database_tools::create_or_update_user_record;
-use crate::rita_exit::database::database_tools::delete_client;
 use crate::rita_exit::database::database_tools::get_client;
+use crate::rita_exit::database::database_tools::get_client_nat_port_range;
 use crate::rita_exit::database::database_tools::get_database_connection;
-use crate::rita_exit::database::database_tools::set_client_timestamp;
+use crate::rita_exit::database::database_tools::migrate_client_addresses;
+use crate::rita_exit::database::database_tools::set_client_nat_port_range;
 use crate::rita_exit::database::database_tools::update_client;
 use crate::rita_exit::database::database_tools::update_low_balance_notification_time;
 use crate::rita_exit::database::database_tools::verify_client;
 use crate::rita_exit::database::database_tools::verify_db_client;
+use crate::rita_exit::database::db_health::{DbHealth, GetDbStatus};
+use crate::rita_exit::database::debt_sync::over_cluster_threshold;
+use crate::rita_exit::database::device_limits::{device_limit_warning, enforce_device_limit};
+use crate::rita_exit::database::email::attempts_left;
 use crate::rita_exit::database::email::handle_email_registration;
+use crate::rita_exit::database::email::send_balance_restored_email;
 use crate::rita_exit::database::email::send_low_balance_email;
 use crate::rita_exit::database::geoip::get_country;
 use crate::rita_exit::database::geoip::get_gateway_ip_bulk;
 use crate::rita_exit::database::geoip::get_gateway_ip_single;
 use crate::rita_exit::database::geoip::verify_ip;
+use crate::rita_exit::database::plans::{client_plan, plan_class_limit};
+use crate::rita_exit::database::port_policy::client_port_blocks;
+use crate::rita_exit::database::signup_gate::{advertised_challenge, check_signup_gate};
 use crate::rita_exit::database::sms::handle_sms_registration;
+use crate::rita_exit::database::sms::send_balance_restored_sms;
 use crate::rita_exit::database::sms::send_low_balance_sms;
+use crate::rita_exit::database::store::ExitStore;
 use crate::rita_exit::database::struct_tools::display_hashset;
+use crate::rita_exit::database::struct_tools::is_archived;
+use crate::rita_exit::database::struct_tools::parse_ipv6_subnet;
+use crate::rita_exit::database::struct_tools::parse_nat_port_range;
+use crate::rita_exit::database::struct_tools::parse_public_ipv4;
 use crate::rita_exit::database::struct_tools::to_exit_client;
 use crate::rita_exit::database::struct_tools::to_identity;
 use crate::rita_exit::database::struct_tools::verif_done;
 use crate::rita_exit::rita_loop::EXIT_LOOP_TIMEOUT;
 use crate::EXIT_ALLOWED_COUNTRIES;
 use crate::EXIT_DESCRIPTION;
+use crate::EXIT_LOAD;
 use crate::EXIT_NETWORK_SETTINGS;
 use crate::EXIT_PRICE;
 use crate::EXIT_SYSTEM_CHAIN;
@@ -38,10 +59,11 @@ use crate::KI;
 use crate::SETTING;
 use ::actix::SystemService;
 use althea_kernel_interface::ExitClient;
-use althea_types::{ExitClientDetails, ExitClientIdentity, ExitDetails, ExitState, ExitVerifMode};
-use diesel;
-use diesel::prelude::PgConnection;
-use exit_db::schema;
+use althea_types::{
+    ExitClientDetails, ExitClientIdentity, ExitDetails, ExitState, ExitTerms, ExitVerifMode,
+    Nat64Details, SignedAnnouncement, SignedExitTerms,
+};
+use clarity::PrivateKey;
 use failure::Error;
 use futures01::future;
 use futures01::future::join_all;
@@ -53,20 +75,90 @@ use settings::RitaCommonSettings;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::net::IpAddr;
+use std::sync::RwLock;
+use std::time::Duration;
 use std::time::Instant;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::util::FutureExt;
 
 pub mod database_tools;
 pub mod db_client;
-mod email;
+pub mod db_health;
+pub mod debt_sync;
+pub mod device_limits;
+pub mod email;
 mod geoip;
+pub mod plans;
+pub mod port_policy;
+pub mod retention;
+pub mod signup_gate;
 mod sms;
+pub mod static_ips;
+pub mod store;
 pub mod struct_tools;
+pub mod usage_records;
+pub mod vouchers;
 
 /// one day in seconds
 pub const ONE_DAY: i64 = 86400;
 
+lazy_static! {
+    /// The last terms we signed, they only change with the price so there's no need to sign them
+    /// again for every client that asks
+    static ref SIGNED_TERMS: RwLock<Option<(PrivateKey, SignedExitTerms)>> = RwLock::new(None);
+}
+
+/// Our current terms signed with our eth key, none if we don't have one to sign with. With a
+/// schedule these are the worst terms any window offers, so clients accept them once
+fn signed_exit_terms(base_price: u64) -> Option<SignedExitTerms> {
+    let key = SETTING.get_payment().eth_private_key?;
+    let (exit_price, free_tier_throughput) = schedule::worst_case_exit_terms(base_price);
+    let terms = ExitTerms {
+        exit_price,
+        free_tier_throughput,
+        jurisdiction: EXIT_NETWORK_SETTINGS.jurisdiction.clone(),
+    };
+    if let Some((signed_with, signed)) = SIGNED_TERMS.read().unwrap().as_ref() {
+        if *signed_with == key && signed.terms == terms {
+            return Some(signed.clone());
+        }
+    }
+    match sign_terms(terms, &key) {
+        Ok(signed) => {
+            *SIGNED_TERMS.write().unwrap() = Some((key, signed.clone()));
+            Some(signed)
+        }
+        Err(e) => {
+            error!("Failed to sign exit terms {:?}", e);
+            None
+        }
+    }
+}
+
+/// Our announcements that haven't expired signed with our eth key, read from the live settings
+/// so that operators can post them without a restart
+fn signed_announcements() -> Vec<SignedAnnouncement> {
+    let key = match SETTING.get_payment().eth_private_key {
+        Some(key) => key,
+        None => return Vec::new(),
+    };
+    let now = secs_since_unix_epoch() as u64;
+    SETTING
+        .get_exit_network()
+        .announcements
+        .iter()
+        .filter(|announcement| !announcement.is_expired(now))
+        .filter_map(
+            |announcement| match sign_announcement(announcement.clone(), &key) {
+                Ok(signed) => Some(signed),
+                Err(e) => {
+                    error!("Failed to sign announcement {:?}", e);
+                    None
+                }
+            },
+        )
+        .collect()
+}
+
 pub fn get_exit_info() -> ExitDetails {
     const UPDATE_INTERVAL: Duration = Duration::from_secs(60);
     let last_update = EXIT_PRICE.read().unwrap().1;
@@ -83,10 +175,11 @@ pub fn get_exit_info() -> ExitDetails {
 
     let exit_network = &EXIT_NETWORK_SETTINGS;
     let payment = *EXIT_SYSTEM_CHAIN;
+    let base_price = EXIT_PRICE.read().unwrap().0;
     ExitDetails {
         server_internal_ip: exit_network.own_internal_ip.into(),
         wg_exit_port: exit_network.wg_tunnel_port,
-        exit_price: EXIT_PRICE.read().unwrap().0,
+        exit_price: schedule::exit_price(base_price),
         exit_currency: payment,
         netmask: exit_network.netmask,
         description: EXIT_DESCRIPTION.clone(),
@@ -95,16 +188,35 @@ pub fn get_exit_info() -> ExitDetails {
             Some(ExitVerifSettings::Phone(_phone_settings)) => ExitVerifMode::Phone,
             None => ExitVerifMode::Off,
         },
+        shared_ipv4: exit_network.cgnat.is_some(),
+        terms: signed_exit_terms(base_price),
+        nat64: nat64_details(),
+        load: *EXIT_LOAD.read().unwrap(),
+        announcements: signed_announcements(),
+        port_blocks: SETTING.get_exit_network().port_blocks.clone(),
+        signup_challenge: advertised_challenge(),
+        time: Some(secs_since_unix_epoch() as u64),
+    }
+}
+
+/// Translation is only advertised if clients can get the ipv6 addresses it needs
+pub fn nat64_details() -> Option<Nat64Details> {
+    let exit_network = &EXIT_NETWORK_SETTINGS;
+    match (&exit_network.nat64, exit_network.ipv6_pool) {
+        (Some(nat64), Some(_)) => Some(Nat64Details {
+            prefix: nat64.prefix,
+            dns64_resolver: nat64.dns64_resolver,
+        }),
+        _ => None,
     }
 }
 
 // lossy conversion, but it won't matter until 2.9 * 10^8 millenia from now
+/// How long clients turned away because of a problem on our end wait before trying again
+pub const SIGNUP_RETRY_SECS: u64 = 60;
+
 pub fn secs_since_unix_epoch() -> i64 {
-    let start = SystemTime::now();
-    let since_the_epoch = start
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards");
-    since_the_epoch.as_secs() as i64
+    now_secs() as i64
 }
 
 /// Handles a new client registration api call. Performs a geoip lookup
@@ -112,6 +224,26 @@ pub fn secs_since_unix_epoch() -> i64 {
 /// ip and then sends out an email of phone message
 pub fn signup_client(client: ExitClientIdentity) -> impl Future<Item = ExitState, Error = Error> {
     trace!("got setup request {:?}", client);
+    DbHealth::from_registry()
+        .send(GetDbStatus)
+        .from_err()
+        .and_then(move |status| {
+            if status?.degraded {
+                warn!("Turning away signup from {:?}, database is down", client);
+                // not denied, the client signs up again on its own once we're back
+                return Ok(Box::new(future::ok(ExitState::GotInfo {
+                    general_details: get_exit_info(),
+                    message: "This exit is having database trouble and can't accept new signups right now, retrying".to_string(),
+                    auto_register: true,
+                    retry_after: Some(SIGNUP_RETRY_SECS),
+                })) as Box<dyn Future<Item = ExitState, Error = Error>>);
+            }
+            Ok(Box::new(signup_client_inner(client)))
+        })
+        .flatten()
+}
+
+fn signup_client_inner(client: ExitClientIdentity) -> impl Future<Item = ExitState, Error = Error> {
     get_gateway_ip_single(client.global.mesh_ip).and_then(move |gateway_ip| {
         verify_ip(gateway_ip).and_then(move |verify_status| {
             get_country(gateway_ip).and_then(move |user_country| {
@@ -131,6 +263,31 @@ pub fn signup_client(client: ExitClientIdentity) -> impl Future<Item = ExitState
                         Err(e) => return Box::new(future::err(e)),
                     }
 
+                    // clients we don't know yet have to get past the signup gate, those that
+                    // don't are sent our details again, with a fresh challenge, and told when
+                    // to retry if they can on their own
+                    match get_client(&client, &conn) {
+                        Ok(Some(_)) => {}
+                        Ok(None) => match check_signup_gate(
+                            &conn,
+                            &client,
+                            gateway_ip,
+                            secs_since_unix_epoch(),
+                        ) {
+                            Ok(Some(refusal)) => {
+                                return Box::new(future::ok(ExitState::GotInfo {
+                                    general_details: get_exit_info(),
+                                    message: refusal.message,
+                                    auto_register: refusal.retry_after.is_some(),
+                                    retry_after: refusal.retry_after,
+                                }))
+                            }
+                            Ok(None) => {}
+                            Err(e) => return Box::new(future::err(e)),
+                        },
+                        Err(e) => return Box::new(future::err(e)),
+                    }
+
                     let their_record =
                         match create_or_update_user_record(&conn, &client, user_country) {
                             Ok(record) => record,
@@ -144,7 +301,7 @@ pub fn signup_client(client: ExitClientIdentity) -> impl Future<Item = ExitState
                                 &client,
                                 &their_record,
                                 &conn,
-                                mailer.email_cooldown as i64,
+                                &mailer,
                             ))
                         }
                         (true, Some(ExitVerifSettings::Phone(phone))) => Box::new(
@@ -161,7 +318,14 @@ pub fn signup_client(client: ExitClientIdentity) -> impl Future<Item = ExitState
                             };
 
                             Box::new(future::ok(ExitState::Registered {
-                                our_details: ExitClientDetails { client_internal_ip },
+                                our_details: ExitClientDetails {
+                                    client_internal_ip,
+                                    internet_ipv6_subnet: parse_ipv6_subnet(&their_record),
+                                    nat_port_range: parse_nat_port_range(&their_record),
+                                    public_ipv4: parse_public_ipv4(&their_record),
+                                    port_blocks: client_port_blocks(&their_record.mesh_ip),
+                                    plan: client_plan(&their_record),
+                                },
                                 general_details: get_exit_info(),
                                 message: "Registration OK".to_string(),
                             }))
@@ -179,19 +343,63 @@ pub fn signup_client(client: ExitClientIdentity) -> impl Future<Item = ExitState
     })
 }
 
+/// The status of a client as best we can tell from the cached client list, used while the
+/// database is unreachable. Only fully registered clients are served, anyone else will have to
+/// wait until the database is back
+pub fn cached_client_status(
+    client: &ExitClientIdentity,
+    clients_list: &[exit_db::models::Client],
+) -> Option<ExitState> {
+    let their_record = clients_list.iter().find(|record| {
+        record.mesh_ip == client.global.mesh_ip.to_string()
+            && record.wg_pubkey == client.global.wg_public_key.to_string()
+            && record.eth_address == client.global.eth_address.to_string()
+    })?;
+    if is_archived(their_record) || !verif_done(their_record) {
+        return None;
+    }
+    let current_ip = their_record.internal_ip.parse().ok()?;
+    Some(ExitState::Registered {
+        our_details: ExitClientDetails {
+            client_internal_ip: current_ip,
+            internet_ipv6_subnet: parse_ipv6_subnet(their_record),
+            nat_port_range: parse_nat_port_range(their_record),
+            public_ipv4: parse_public_ipv4(their_record),
+            port_blocks: client_port_blocks(&their_record.mesh_ip),
+            plan: client_plan(their_record),
+        },
+        general_details: get_exit_info(),
+        message: "Registration OK".to_string(),
+    })
+}
+
 /// Gets the status of a client and updates it in the database
-pub fn client_status(client: ExitClientIdentity, conn: &PgConnection) -> Result<ExitState, Error> {
+pub fn client_status(client: ExitClientIdentity, conn: &dyn ExitStore) -> Result<ExitState, Error> {
     trace!("Checking if record exists for {:?}", client.global.mesh_ip);
 
     if let Some(their_record) = get_client(&client, &conn)? {
         trace!("record exists, updating");
 
+        // signing up again restores the client
+        if is_archived(&their_record) {
+            return Ok(ExitState::GotInfo {
+                general_details: get_exit_info(),
+                message: "Registration archived after a long absence, signing up again".to_string(),
+                auto_register: true,
+                retry_after: Some(0),
+            });
+        }
+
         if !verif_done(&their_record) {
             return Ok(ExitState::Pending {
                 general_details: get_exit_info(),
                 message: "awaiting email verification".to_string(),
                 email_code: None,
                 phone_code: None,
+                remaining_attempts: match EXIT_VERIF_SETTINGS.clone() {
+                    Some(ExitVerifSettings::Email(mailer)) => attempts_left(&their_record, &mailer),
+                    _ => None,
+                },
             });
         }
 
@@ -209,15 +417,70 @@ pub fn client_status(client: ExitClientIdentity, conn: &PgConnection) -> Result<
         }
 
         update_client(&client, &their_record, &conn)?;
+        let their_record = migrate_client_addresses(&client, &their_record, &conn)?;
+        let current_ip = their_record.internal_ip.parse()?;
+        // clients registered before ipv6 was turned on get a subnet the next time they check in
+        let internet_ipv6_subnet = match parse_ipv6_subnet(&their_record) {
+            Some(subnet) => Some(subnet),
+            None => assign_client_ipv6(&their_record, &conn)?,
+        };
+        // likewise for cgnat being turned on or its settings changing
+        let nat_port_range = get_client_nat_port_range(current_ip);
+        if nat_port_range != parse_nat_port_range(&their_record) {
+            set_client_nat_port_range(&their_record, nat_port_range, &conn)?;
+        }
+
+        let public_ipv4 = parse_public_ipv4(&their_record);
 
         low_balance_notification(client, &their_record, EXIT_VERIF_SETTINGS.clone(), &conn);
 
+        // read from the live settings so that operators can announce maintenance
+        // or suspend service without a restart
+        let (suspended_message, maintenance_window) = {
+            let exit_network = SETTING.get_exit_network();
+            (
+                exit_network.suspended_message.clone(),
+                exit_network.maintenance_window.clone(),
+            )
+        };
+        if let Some(message) = suspended_message {
+            return Ok(ExitState::Suspended {
+                general_details: get_exit_info(),
+                message,
+            });
+        }
+        let now = secs_since_unix_epoch() as u64;
+        if let Some(window) = maintenance_window {
+            // windows that have already ended are simply ignored
+            if now < window.end {
+                return Ok(ExitState::Maintenance {
+                    our_details: ExitClientDetails {
+                        client_internal_ip: current_ip,
+                        internet_ipv6_subnet,
+                        nat_port_range,
+                        public_ipv4,
+                        port_blocks: client_port_blocks(&their_record.mesh_ip),
+                        plan: client_plan(&their_record),
+                    },
+                    general_details: get_exit_info(),
+                    message: window.message.clone(),
+                    window,
+                });
+            }
+        }
+
         Ok(ExitState::Registered {
             our_details: ExitClientDetails {
                 client_internal_ip: current_ip,
+                internet_ipv6_subnet,
+                nat_port_range,
+                public_ipv4,
+                port_blocks: client_port_blocks(&their_record.mesh_ip),
+                plan: client_plan(&their_record),
             },
             general_details: get_exit_info(),
-            message: "Registration OK".to_string(),
+            message: device_limit_warning(&their_record)
+                .unwrap_or_else(|| "Registration OK".to_string()),
         })
     } else {
         Ok(ExitState::New)
@@ -232,7 +495,7 @@ fn low_balance_notification(
     client: ExitClientIdentity,
     their_record: &exit_db::models::Client,
     config: Option<ExitVerifSettings>,
-    conn: &PgConnection,
+    conn: &dyn ExitStore,
 ) {
     trace!("Checking low balance nofication");
 
@@ -252,6 +515,13 @@ fn low_balance_notification(
         }
     }
 
+    // clients we warned who have since topped up are told once, after which they can be warned
+    // again as soon as they run low
+    if client.low_balance == Some(false) && their_record.last_balance_warning_time != 0 {
+        balance_restored_notification(&client, config, conn);
+        return;
+    }
+
     let time_since_last_notification =
         secs_since_unix_epoch() - their_record.last_balance_warning_time;
 
@@ -305,6 +575,34 @@ fn low_balance_notification(
     }
 }
 
+/// Tells a client we warned about their low balance that it's been topped up
+fn balance_restored_notification(
+    client: &ExitClientIdentity,
+    config: Option<ExitVerifSettings>,
+    conn: &dyn ExitStore,
+) {
+    let res = match (config, &client.reg_details.phone, &client.reg_details.email) {
+        (Some(ExitVerifSettings::Phone(val)), Some(number), _) => {
+            send_balance_restored_sms(number, val)
+        }
+        (Some(ExitVerifSettings::Email(val)), _, Some(email)) => {
+            send_balance_restored_email(email, val)
+        }
+        (_, _, _) => Ok(()),
+    };
+    if let Err(e) = res {
+        warn!(
+            "Failed to notify {} of their restored balance with {:?}",
+            client.global.wg_public_key, e
+        );
+    } else if let Err(e) = clear_low_balance_notification_time(client, conn) {
+        error!(
+            "Failed to find {:?} in the database to clear notified time! {:?}",
+            client, e
+        );
+    }
+}
+
 /// Every 5 seconds we vlaidate all online clients to make sure that they are in the right region
 /// we also do this in the client status requests but we want to handle the edge case of a modified
 /// client that doesn't make status requests
@@ -366,64 +664,6 @@ pub fn validate_clients_region(
         })
 }
 
-/// Iterates over the the database of clients, if a client's last_seen value
-/// is zero it is set to now if a clients last_seen value is older than
-/// the client timeout it is deleted
-pub fn cleanup_exit_clients(
-    clients_list: &[exit_db::models::Client],
-    conn: &PgConnection,
-) -> Result<(), Error> {
-    trace!("Running exit client cleanup");
-    let start = Instant::now();
-
-    for client in clients_list.iter() {
-        trace!("Checking client {:?}", client);
-        match to_exit_client(client.clone()) {
-            Ok(client_id) => {
-                let time_delta = secs_since_unix_epoch() - client.last_seen;
-                let entry_timeout = i64::from(SETTING.get_exit_network().entry_timeout);
-                // entry timeout can be disabled, or longer than a day, but not shorter
-                assert!(entry_timeout == 0 || entry_timeout >= ONE_DAY);
-                if client.last_seen == 0 {
-                    info!(
-                        "{} does not have a last seen timestamp, adding one now ",
-                        client.mesh_ip
-                    );
-                    let res = set_client_timestamp(client_id, conn);
-                    if res.is_err() {
-                        warn!(
-                            "Unable to update the client timestamp for {:?} with {:?}",
-                            client, res
-                        );
-                    }
-                }
-                // a entry_timeout value of 0 means the feature is disabled
-                else if entry_timeout != 0 && time_delta > entry_timeout {
-                    warn!(
-                        "{} has been inactive for too long, deleting! ",
-                        client.mesh_ip
-                    );
-                    let res = delete_client(client_id, conn);
-                    if res.is_err() {
-                        error!(
-                            "Unable to remove inactive client {:?} with {:?}",
-                            client, res
-                        )
-                    }
-                }
-            }
-            Err(e) => error!("Invalid database entry! {:?}", e),
-        }
-    }
-
-    info!(
-        "Exit cleanup completed in {}s {}ms",
-        start.elapsed().as_secs(),
-        start.elapsed().subsec_millis(),
-    );
-    Ok(())
-}
-
 /// Gets a complete list of clients from the database and transforms that list
 /// into a single very long wg tunnel setup command which is then applied to the
 /// wg_exit tunnel (or created if it's the first run). This is the offically supported
@@ -432,16 +672,18 @@ pub fn setup_clients(
     clients_list: &[exit_db::models::Client],
     old_clients: &HashSet<ExitClient>,
 ) -> Result<HashSet<ExitClient>, Error> {
-    use self::schema::clients::dsl::clients;
-
     let start = Instant::now();
 
     // use hashset to ensure uniqueness and check for duplicate db entries
     let mut wg_clients = HashSet::new();
 
-    trace!("got clients from db {:?}", clients);
+    trace!("got clients from db {:?}", clients_list);
 
     for c in clients_list.iter() {
+        if is_archived(c) {
+            trace!("{} is archived, not adding to wg_exit", c.wg_pubkey);
+            continue;
+        }
         match (c.verified, to_exit_client(c.clone())) {
             (true, Ok(exit_client_c)) => {
                 if !wg_clients.insert(exit_client_c) {
@@ -469,6 +711,33 @@ pub fn setup_clients(
         &SETTING.get_exit_network().wg_private_key_path,
     );
 
+    if SETTING.get_exit_network().cgnat.is_some() {
+        let external_nic = SETTING.get_network().external_nic.clone();
+        match external_nic {
+            Some(nic) => {
+                if let Err(e) = KI.set_cgnat_rules(&nic, &wg_clients) {
+                    error!("Failed to set cgnat rules {:?}", e);
+                }
+            }
+            None => error!("cgnat is enabled but there is no external_nic configured!"),
+        }
+    }
+
+    // also run when the pool is emptied while clients still have addresses, so that their
+    // rules are cleared once they are released
+    let static_ips_in_use = wg_clients.iter().any(|c| c.public_ipv4.is_some());
+    if !SETTING.get_exit_network().static_ip_pool.is_empty() || static_ips_in_use {
+        let external_nic = SETTING.get_network().external_nic.clone();
+        match external_nic {
+            Some(nic) => {
+                if let Err(e) = KI.set_static_nat_rules(&nic, &wg_clients) {
+                    error!("Failed to set static nat rules {:?}", e);
+                }
+            }
+            None => error!("Static ips are in use but there is no external_nic configured!"),
+        }
+    }
+
     match exit_status {
         Ok(_) => trace!("Successfully setup Exit WG!"),
         Err(e) => warn!(
@@ -492,7 +761,9 @@ pub fn setup_clients(
 /// if they are also a exit client they are limited to the free tier level of bandwidth by
 /// setting the htb class they are assigned to to a maximum speed of the free tier value.
 /// Unlike intermediary enforcement we do not need to subdivide the free tier to prevent
-/// ourselves from exceeding the upstream free tier. As an exit we are the upstream.
+/// ourselves from exceeding the upstream free tier. As an exit we are the upstream. Clients
+/// with too many devices online are held to the free tier the same way if limits are enforced.
+/// In a cluster the debt that counts is the client's debt with all of the instances.
 pub fn enforce_exit_clients(
     clients_list: Vec<exit_db::models::Client>,
 ) -> Box<dyn Future<Item = (), Error = ()>> {
@@ -504,9 +775,10 @@ pub fn enforce_exit_clients(
             .and_then(move |debts_list| match debts_list {
                 Ok(list) => {
                     let mut clients_by_id = HashMap::new();
-                    let free_tier_limit = SETTING.get_payment().free_tier_throughput;
+                    let free_tier_limit = schedule::free_tier_throughput();
                     let close_threshold = SETTING.get_payment().close_threshold.clone();
-                    for client in clients_list.iter() {
+                    // archived clients have no tunnel and their old address may be someone else's
+                    for client in clients_list.iter().filter(|c| !is_archived(c)) {
                         if let Ok(id) = to_identity(client) {
                             clients_by_id.insert(id, client);
                         }
@@ -519,6 +791,7 @@ pub fn enforce_exit_clients(
                                     Ok(IpAddr::V4(ip)) => {
                                         let res = if debt_entry.payment_details.action
                                             == DebtAction::SuspendTunnel
+                                            || over_cluster_threshold(debt_entry, &close_threshold)
                                         {
                                             info!("Exit is enforcing on {} because their debt of {} is greater than the limit of {}", client.wg_pubkey, debt_entry.payment_details.debt, close_threshold);
                                             KI.set_class_limit(
@@ -527,10 +800,26 @@ pub fn enforce_exit_clients(
                                                 free_tier_limit,
                                                 &ip,
                                             )
+                                        } else if enforce_device_limit(client) {
+                                            info!("Exit is enforcing on {} because they have too many devices online", client.wg_pubkey);
+                                            KI.set_class_limit(
+                                                "wg_exit",
+                                                free_tier_limit,
+                                                free_tier_limit,
+                                                &ip,
+                                            )
+                                        } else if let Some((guaranteed, max)) =
+                                            plan_class_limit(client)
+                                        {
+                                            KI.set_class_limit("wg_exit", guaranteed, max, &ip)
                                         } else {
-                                            // set to 500mbps garunteed bandwidth and 1gbps
-                                            // absolute max
-                                            KI.set_class_limit("wg_exit", 500_000, 1_000_000, &ip)
+                                            info!("Exit is enforcing on {} because they've used up their monthly quota", client.wg_pubkey);
+                                            KI.set_class_limit(
+                                                "wg_exit",
+                                                free_tier_limit,
+                                                free_tier_limit,
+                                                &ip,
+                                            )
                                         };
                                         if res.is_err() {
                                             panic!("Failed to limit {} with {:?}", ip, res);
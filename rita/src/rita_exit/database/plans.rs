@@ -0,0 +1,243 @@
+//! Plans let an exit treat clients differently, each client can have its own rate limit and a
+//! monthly quota stored with its record. Rate limits are applied to the client's traffic class
+//! on wg_exit when the exit enforces on its clients every round. A client that has used up its
+//! quota is held to the free tier until the calendar month is over in UTC. Usage is counted from
+//! the hourly usage records, so the count lags by however long usage takes to be written out and
+//! an exit that keeps records for less than a month can't enforce quotas. Since the records are
+//! shared, a client's usage through every instance of a cluster counts towards its quota.
+
+use crate::rita_common::dashboard::error::{DashboardError, ErrorCode};
+use crate::rita_exit::database::secs_since_unix_epoch;
+use crate::rita_exit::database::store::ExitStore;
+use crate::rita_exit::database::usage_records::usage_totals;
+use crate::rita_exit::database::ONE_DAY;
+use althea_types::ExitPlan;
+use exit_db::models::Client;
+use failure::Error;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// How often usage this month is recounted, usage records are only written every few minutes
+pub const PLAN_USAGE_INTERVAL: Duration = Duration::from_secs(300);
+/// What clients without a rate limit get, guaranteed and at most, in kbit/s
+pub const DEFAULT_CLASS_LIMIT: (u32, u32) = (500_000, 1_000_000);
+/// Plan names are stored in a varchar(64)
+const MAX_PLAN_NAME: usize = 64;
+
+lazy_static! {
+    /// Bytes up and down this month by client mesh ip, as of the last count
+    static ref MONTHLY_USAGE: Arc<RwLock<HashMap<String, u64>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+}
+
+/// The start of the calendar month `now` falls in, both in seconds since the unix epoch in UTC
+pub fn month_start(now: i64) -> i64 {
+    let days = now.div_euclid(ONE_DAY);
+    // the day of the month from Howard Hinnant's civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day_of_month = doy - (153 * mp + 2) / 5 + 1;
+    (days - day_of_month + 1) * ONE_DAY
+}
+
+/// Recounts each client's usage this month, skipped if no one has a quota
+pub fn count_monthly_usage(clients: &[Client], conn: &dyn ExitStore) -> Result<(), Error> {
+    if clients.iter().all(|client| client.monthly_quota <= 0) {
+        MONTHLY_USAGE.write().unwrap().clear();
+        return Ok(());
+    }
+    let start = month_start(secs_since_unix_epoch());
+    let usage = usage_totals(start, i64::max_value(), conn)?
+        .into_iter()
+        .map(|total| {
+            let used = total.upload.max(0) as u64 + total.download.max(0) as u64;
+            (total.mesh_ip, used)
+        })
+        .collect();
+    *MONTHLY_USAGE.write().unwrap() = usage;
+    Ok(())
+}
+
+fn used_this_month(mesh_ip: &str) -> u64 {
+    MONTHLY_USAGE
+        .read()
+        .unwrap()
+        .get(mesh_ip)
+        .cloned()
+        .unwrap_or(0)
+}
+
+fn to_exit_plan(client: &Client, used: u64) -> Option<ExitPlan> {
+    if client.plan_name.is_empty() && client.rate_limit <= 0 && client.monthly_quota <= 0 {
+        return None;
+    }
+    let monthly_quota = if client.monthly_quota > 0 {
+        Some(client.monthly_quota as u64)
+    } else {
+        None
+    };
+    Some(ExitPlan {
+        name: client.plan_name.clone(),
+        rate_limit: if client.rate_limit > 0 {
+            Some(client.rate_limit as u32)
+        } else {
+            None
+        },
+        monthly_quota,
+        used_this_month: used,
+        quota_exceeded: monthly_quota.map_or(false, |quota| used >= quota),
+    })
+}
+
+/// The client's plan and how much of it they've used, None if they aren't on one
+pub fn client_plan(client: &Client) -> Option<ExitPlan> {
+    to_exit_plan(client, used_this_month(&client.mesh_ip))
+}
+
+/// The guaranteed and maximum rate for a client's traffic class, kbit/s
+fn class_limit(plan: Option<&ExitPlan>) -> (u32, u32) {
+    match plan.and_then(|plan| plan.rate_limit) {
+        Some(rate) => (rate.min(DEFAULT_CLASS_LIMIT.0), rate),
+        None => DEFAULT_CLASS_LIMIT,
+    }
+}
+
+/// How the client's traffic class should be limited, None if it's used up its quota and should
+/// be held to the free tier
+pub fn plan_class_limit(client: &Client) -> Option<(u32, u32)> {
+    let plan = client_plan(client);
+    if plan.as_ref().map_or(false, |plan| plan.quota_exceeded) {
+        return None;
+    }
+    Some(class_limit(plan.as_ref()))
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NewPlan {
+    pub name: String,
+    /// kbit/s, None for no limit
+    #[serde(default)]
+    pub rate_limit: Option<u32>,
+    /// bytes per month, None for no quota
+    #[serde(default)]
+    pub monthly_quota: Option<u64>,
+}
+
+fn validate_plan(plan: &NewPlan) -> Result<(), DashboardError> {
+    if plan.name.len() > MAX_PLAN_NAME {
+        return Err(DashboardError::invalid_input(format!(
+            "Plan names can be at most {} bytes",
+            MAX_PLAN_NAME
+        )));
+    }
+    match plan.rate_limit {
+        Some(0) => Err(DashboardError::invalid_input(
+            "A rate limit of 0 would cut the client off, leave it out for no limit".to_string(),
+        )),
+        Some(rate) if rate > i32::max_value() as u32 => Err(DashboardError::invalid_input(
+            format!("{}kbit/s is too fast", rate),
+        )),
+        _ => match plan.monthly_quota {
+            Some(0) => Err(DashboardError::invalid_input(
+                "A quota of 0 would cut the client off, leave it out for no quota".to_string(),
+            )),
+            Some(quota) if quota > i64::max_value() as u64 => Err(DashboardError::invalid_input(
+                format!("{} bytes is too large a quota", quota),
+            )),
+            _ => Ok(()),
+        },
+    }
+}
+
+/// Puts a client on a plan, None takes them off theirs
+pub fn set_client_plan(
+    mesh_ip: &str,
+    plan: Option<NewPlan>,
+    conn: &dyn ExitStore,
+) -> Result<(), Error> {
+    if let Some(plan) = plan.as_ref() {
+        validate_plan(plan)?;
+    }
+    let updated = conn.update_client(mesh_ip, &mut |record| match plan.as_ref() {
+        Some(plan) => {
+            record.plan_name = plan.name.clone();
+            record.rate_limit = plan.rate_limit.unwrap_or(0) as i32;
+            record.monthly_quota = plan.monthly_quota.unwrap_or(0) as i64;
+        }
+        None => {
+            record.plan_name = String::new();
+            record.rate_limit = 0;
+            record.monthly_quota = 0;
+        }
+    })?;
+    if !updated {
+        return Err(DashboardError::new(
+            ErrorCode::NotFound,
+            format!("No client with mesh ip {}", mesh_ip),
+        )
+        .into());
+    }
+    info!("Set the plan of {} to {:?}", mesh_ip, plan);
+    Ok(())
+}
+
+#[test]
+fn test_month_start() {
+    // 2020-02-05 12:00 UTC
+    assert_eq!(month_start(1_580_904_000), 1_580_515_200);
+    assert_eq!(month_start(1_580_515_200), 1_580_515_200);
+    // the last second of a leap year February
+    assert_eq!(month_start(1_583_020_799), 1_580_515_200);
+    assert_eq!(month_start(1_583_020_800), 1_583_020_800);
+    // 2019-12-31 and the new year
+    assert_eq!(month_start(1_577_836_799), 1_575_158_400);
+    assert_eq!(month_start(1_577_836_800), 1_577_836_800);
+}
+
+#[test]
+fn test_plan_limits() {
+    let client = |plan_name: &str, rate_limit: i32, monthly_quota: i64| Client {
+        mesh_ip: "fd00::1".to_string(),
+        plan_name: plan_name.to_string(),
+        rate_limit,
+        monthly_quota,
+        ..Default::default()
+    };
+    assert_eq!(to_exit_plan(&client("", 0, 0), 1000), None);
+    assert_eq!(class_limit(None), DEFAULT_CLASS_LIMIT);
+
+    let basic = to_exit_plan(&client("basic", 10_000, 1_000_000), 999_999).unwrap();
+    assert_eq!(basic.rate_limit, Some(10_000));
+    assert!(!basic.quota_exceeded);
+    assert_eq!(class_limit(Some(&basic)), (10_000, 10_000));
+    assert!(
+        to_exit_plan(&client("basic", 10_000, 1_000_000), 1_000_000)
+            .unwrap()
+            .quota_exceeded
+    );
+
+    // a quota without a rate limit is limited like everyone else until it's used up
+    let capped = to_exit_plan(&client("", 0, 1_000_000), 0).unwrap();
+    assert_eq!(capped.rate_limit, None);
+    assert_eq!(class_limit(Some(&capped)), DEFAULT_CLASS_LIMIT);
+    // only the guarantee is capped for plans faster than the default
+    let fast = to_exit_plan(&client("fast", 2_000_000, 0), 0).unwrap();
+    assert_eq!(fast.monthly_quota, None);
+    assert_eq!(class_limit(Some(&fast)), (500_000, 2_000_000));
+
+    let plan = |rate_limit, monthly_quota| NewPlan {
+        name: "basic".to_string(),
+        rate_limit,
+        monthly_quota,
+    };
+    assert!(validate_plan(&plan(Some(10_000), Some(1_000_000))).is_ok());
+    assert!(validate_plan(&plan(None, None)).is_ok());
+    assert!(validate_plan(&plan(Some(0), None)).is_err());
+    assert!(validate_plan(&plan(None, Some(0))).is_err());
+    assert!(validate_plan(&plan(Some(u32::max_value()), None)).is_err());
+}
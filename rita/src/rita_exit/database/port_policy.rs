@@ -0,0 +1,168 @@
+//! Outbound ports the exit refuses to forward, for when the exit's jurisdiction or upstream
+//! requires blocking things like SMTP. Blocks are set for everyone or per client in the exit
+//! settings, either directly or through the admin api, and clients are told which apply to them.
+//! The nft rules are rebuilt whenever the blocks or the clients they apply to change.
+
+use crate::KI;
+use crate::SETTING;
+use althea_kernel_interface::{ClientPortBlocks, ExitClient, PortPolicy};
+use althea_types::PortBlock;
+use failure::Error;
+use settings::exit::RitaExitSettings;
+use settings::RitaCommonSettings;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+
+/// Errors out on the first block that can't be turned into a rule
+pub fn validate_port_blocks(blocks: &[PortBlock]) -> Result<(), Error> {
+    for block in blocks {
+        if !block.is_valid() {
+            bail!("Invalid port block {:?}", block);
+        }
+    }
+    Ok(())
+}
+
+/// The ports blocked for this client in particular, read from the live settings so that changes
+/// are disclosed to clients the next time they check in
+pub fn client_port_blocks(mesh_ip: &str) -> Vec<PortBlock> {
+    let mesh_ip: IpAddr = match mesh_ip.parse() {
+        Ok(ip) => ip,
+        Err(_) => return Vec::new(),
+    };
+    SETTING
+        .get_exit_network()
+        .client_port_blocks
+        .iter()
+        .find(|(ip, _)| ip.parse::<IpAddr>().ok() == Some(mesh_ip))
+        .map(|(_, blocks)| blocks.clone())
+        .unwrap_or_default()
+}
+
+/// Matches the per client blocks to the clients we have tunnels for, blocks for mesh ips that
+/// aren't clients of ours are left out until they show up
+pub fn build_port_policy(
+    global: &[PortBlock],
+    client_blocks: &HashMap<String, Vec<PortBlock>>,
+    wg_clients: &HashSet<ExitClient>,
+) -> PortPolicy {
+    let mut clients = Vec::new();
+    for (mesh_ip, blocks) in client_blocks.iter() {
+        let mesh_ip: IpAddr = match mesh_ip.parse() {
+            Ok(ip) => ip,
+            Err(_) => {
+                error!("Invalid mesh ip {} in client_port_blocks", mesh_ip);
+                continue;
+            }
+        };
+        if blocks.is_empty() {
+            continue;
+        }
+        if let Some(c) = wg_clients.iter().find(|c| c.mesh_ip == mesh_ip) {
+            clients.push(ClientPortBlocks {
+                internal_ip: c.internal_ip,
+                internet_ipv6: c.internet_ipv6,
+                blocks: blocks.clone(),
+            });
+        }
+    }
+    PortPolicy {
+        global: global.to_vec(),
+        clients,
+    }
+}
+
+/// Loads the port policy for the current clients if it's changed since `last`, returns the
+/// policy now in effect
+pub fn apply_port_policy(
+    wg_clients: &HashSet<ExitClient>,
+    last: Option<PortPolicy>,
+) -> Option<PortPolicy> {
+    let policy = {
+        let exit_network = SETTING.get_exit_network();
+        build_port_policy(
+            &exit_network.port_blocks,
+            &exit_network.client_port_blocks,
+            wg_clients,
+        )
+    };
+    // nothing has ever been blocked, there's no table to clear
+    if last.is_none() && policy == PortPolicy::default() {
+        return None;
+    }
+    if last.as_ref() == Some(&policy) {
+        return last;
+    }
+    let external_nic = match SETTING.get_network().external_nic.clone() {
+        Some(nic) => nic,
+        None => {
+            error!("Ports are blocked but there is no external_nic configured!");
+            return last;
+        }
+    };
+    match KI.set_port_policy_rules(&external_nic, &policy) {
+        Ok(()) => {
+            info!(
+                "Blocking {} ports for everyone and more for {} clients",
+                policy.global.len(),
+                policy.clients.len()
+            );
+            Some(policy)
+        }
+        Err(e) => {
+            error!("Failed to set port policy rules {:?}", e);
+            last
+        }
+    }
+}
+
+#[test]
+fn test_build_port_policy() {
+    use crate::rita_common::utils::test_identity;
+    use althea_types::PortProtocol;
+
+    let client = |mesh_ip: &str, internal_ip: &str| ExitClient {
+        internal_ip: internal_ip.parse().unwrap(),
+        public_key: test_identity(mesh_ip).wg_public_key,
+        mesh_ip: mesh_ip.parse().unwrap(),
+        port: 59999,
+        internet_ipv6: None,
+        nat_port_range: None,
+        public_ipv4: None,
+    };
+    let smtp = PortBlock {
+        protocol: PortProtocol::Tcp,
+        port: 25,
+        port_end: None,
+    };
+    let mut wg_clients = HashSet::new();
+    wg_clients.insert(client("fd00::1", "172.16.0.1"));
+    wg_clients.insert(client("fd00::2", "172.16.0.2"));
+
+    let mut client_blocks = HashMap::new();
+    // written differently than the client's address
+    client_blocks.insert("fd00:0::1".to_string(), vec![smtp]);
+    client_blocks.insert("fd00::2".to_string(), Vec::new());
+    // not one of our clients
+    client_blocks.insert("fd00::3".to_string(), vec![smtp]);
+    client_blocks.insert("garbage".to_string(), vec![smtp]);
+
+    let policy = build_port_policy(&[], &client_blocks, &wg_clients);
+    assert!(policy.global.is_empty());
+    assert_eq!(
+        policy.clients,
+        vec![ClientPortBlocks {
+            internal_ip: "172.16.0.1".parse().unwrap(),
+            internet_ipv6: None,
+            blocks: vec![smtp],
+        }]
+    );
+
+    assert!(validate_port_blocks(&[smtp]).is_ok());
+    let backwards = PortBlock {
+        protocol: PortProtocol::Udp,
+        port: 2000,
+        port_end: Some(1000),
+    };
+    assert!(validate_port_blocks(&[smtp, backwards]).is_err());
+}
@@ -0,0 +1,222 @@
+//! The exit's client retention policy. Clients that haven't been seen in `archive_timeout`
+//! seconds are archived, their wg peer and addresses are freed up but their db entry stays
+//! around so that they don't have to verify again if they come back. The entry keeps the
+//! internal ip they last had, which may be handed to someone else in the meantime. Clients that haven't been
+//! seen in `entry_timeout` seconds are deleted outright, archived or not. Signups that never
+//! verified their email are deleted after `unverified_timeout` seconds so that abandoned or
+//! mistyped addresses don't hold on to an internal ip forever.
+
+use crate::rita_common::time_sanity::clock_is_sane;
+use crate::rita_exit::database::database_tools::{
+    archive_client, delete_client, set_client_timestamp,
+};
+use crate::rita_exit::database::secs_since_unix_epoch;
+use crate::rita_exit::database::store::ExitStore;
+use crate::rita_exit::database::struct_tools::is_archived;
+use crate::rita_exit::database::ONE_DAY;
+use crate::SETTING;
+use exit_db::models::Client;
+use failure::Error;
+use settings::exit::ExitVerifSettings;
+use settings::exit::RitaExitSettings;
+use std::time::Instant;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetentionAction {
+    /// entries from before last_seen was tracked get stamped with the current time
+    Timestamp,
+    Archive,
+    Delete,
+    /// signed up but never verified
+    DeleteUnverified,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PlannedRetention {
+    pub mesh_ip: String,
+    pub wg_pubkey: String,
+    pub nickname: String,
+    pub last_seen: i64,
+    pub action: RetentionAction,
+}
+
+/// What the retention policy wants done with a client, if anything. Timeouts are in seconds and
+/// 0 disables them
+pub fn retention_action(
+    client: &Client,
+    now: i64,
+    archive_timeout: i64,
+    entry_timeout: i64,
+    unverified_timeout: i64,
+) -> Option<RetentionAction> {
+    if client.last_seen == 0 {
+        return Some(RetentionAction::Timestamp);
+    }
+    let time_delta = now - client.last_seen;
+    if entry_timeout != 0 && time_delta > entry_timeout {
+        Some(RetentionAction::Delete)
+    } else if unverified_timeout != 0 && time_delta > unverified_timeout && !client.verified {
+        Some(RetentionAction::DeleteUnverified)
+    } else if archive_timeout != 0 && time_delta > archive_timeout && !is_archived(client) {
+        Some(RetentionAction::Archive)
+    } else {
+        None
+    }
+}
+
+/// The archive, entry and unverified timeouts from the settings, checked when the settings are
+/// loaded so that a bad value stops the exit on start rather than the retention pass later
+pub fn timeouts() -> Result<(i64, i64, i64), Error> {
+    let exit_network = SETTING.get_exit_network();
+    let archive_timeout = i64::from(exit_network.archive_timeout);
+    let entry_timeout = i64::from(exit_network.entry_timeout);
+    drop(exit_network);
+    // timeouts can be disabled, or longer than a day, but not shorter
+    if entry_timeout != 0 && entry_timeout < ONE_DAY {
+        bail!(
+            "entry_timeout of {}s is shorter than a day, set it to 0 to disable it",
+            entry_timeout
+        );
+    }
+    if archive_timeout != 0 && archive_timeout < ONE_DAY {
+        bail!(
+            "archive_timeout of {}s is shorter than a day, set it to 0 to disable it",
+            archive_timeout
+        );
+    }
+    // only email verification has a window to verify in
+    let unverified_timeout = match SETTING.get_verif_settings() {
+        Some(ExitVerifSettings::Email(mailer)) => mailer.unverified_timeout as i64,
+        _ => 0,
+    };
+    Ok((archive_timeout, entry_timeout, unverified_timeout))
+}
+
+/// Everything the retention policy would do to these clients right now
+pub fn plan_retention(clients_list: &[Client]) -> Result<Vec<PlannedRetention>, Error> {
+    let (archive_timeout, entry_timeout, unverified_timeout) = timeouts()?;
+    let now = secs_since_unix_epoch();
+    let mut plan = Vec::new();
+    for client in clients_list.iter() {
+        if let Some(action) = retention_action(
+            client,
+            now,
+            archive_timeout,
+            entry_timeout,
+            unverified_timeout,
+        ) {
+            plan.push(PlannedRetention {
+                mesh_ip: client.mesh_ip.clone(),
+                wg_pubkey: client.wg_pubkey.clone(),
+                nickname: client.nickname.clone(),
+                last_seen: client.last_seen,
+                action,
+            });
+        }
+    }
+    Ok(plan)
+}
+
+/// Applies the retention policy to the database of clients
+pub fn cleanup_exit_clients(clients_list: &[Client], conn: &dyn ExitStore) -> Result<(), Error> {
+    trace!("Running exit client cleanup");
+    if !clock_is_sane() {
+        warn!("Our clock is off, not expiring any clients until it's fixed");
+        return Ok(());
+    }
+    let start = Instant::now();
+
+    let (archive_timeout, entry_timeout, unverified_timeout) = timeouts()?;
+    let now = secs_since_unix_epoch();
+    for client in clients_list.iter() {
+        trace!("Checking client {:?}", client);
+        let action = match retention_action(
+            client,
+            now,
+            archive_timeout,
+            entry_timeout,
+            unverified_timeout,
+        ) {
+            Some(action) => action,
+            None => continue,
+        };
+        let res = match action {
+            RetentionAction::Timestamp => {
+                info!(
+                    "{} does not have a last seen timestamp, adding one now ",
+                    client.mesh_ip
+                );
+                set_client_timestamp(client, conn)
+            }
+            RetentionAction::Archive => {
+                info!(
+                    "{} has been inactive for a while, archiving",
+                    client.mesh_ip
+                );
+                archive_client(client, conn)
+            }
+            RetentionAction::Delete => {
+                warn!(
+                    "{} has been inactive for too long, deleting! ",
+                    client.mesh_ip
+                );
+                delete_client(client, conn)
+            }
+            RetentionAction::DeleteUnverified => {
+                info!(
+                    "{} never verified their email, deleting the signup",
+                    client.mesh_ip
+                );
+                delete_client(client, conn)
+            }
+        };
+        if let Err(e) = res {
+            error!(
+                "Unable to {:?} inactive client {:?} with {:?}",
+                action, client, e
+            );
+        }
+    }
+
+    info!(
+        "Exit cleanup completed in {}s {}ms",
+        start.elapsed().as_secs(),
+        start.elapsed().subsec_millis(),
+    );
+    Ok(())
+}
+
+#[test]
+fn test_retention_action() {
+    let now = 100 * ONE_DAY;
+    let client = |days_ago: i64, archived: bool| Client {
+        last_seen: now - days_ago * ONE_DAY,
+        archived_time: if archived { 1 } else { 0 },
+        ..Default::default()
+    };
+    let action = |c: &Client| retention_action(c, now, 30 * ONE_DAY, 90 * ONE_DAY, 0);
+
+    assert_eq!(action(&client(1, false)), None);
+    assert_eq!(action(&client(31, false)), Some(RetentionAction::Archive));
+    assert_eq!(action(&client(31, true)), None);
+    assert_eq!(action(&client(91, true)), Some(RetentionAction::Delete));
+    assert_eq!(action(&Client::default()), Some(RetentionAction::Timestamp));
+    // both disabled
+    assert_eq!(retention_action(&client(91, false), now, 0, 0, 0), None);
+
+    let unverified = |c: &Client| retention_action(c, now, 30 * ONE_DAY, 90 * ONE_DAY, 7 * ONE_DAY);
+    assert_eq!(unverified(&client(1, false)), None);
+    assert_eq!(
+        unverified(&client(8, false)),
+        Some(RetentionAction::DeleteUnverified)
+    );
+    let verified = Client {
+        verified: true,
+        ..client(8, false)
+    };
+    assert_eq!(unverified(&verified), None);
+    assert_eq!(
+        unverified(&client(91, false)),
+        Some(RetentionAction::Delete)
+    );
+}
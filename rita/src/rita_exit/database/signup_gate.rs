@@ -0,0 +1,268 @@
+//! Keeps bots from flooding the exit with signups and running through its address pools. New
+//! clients can be made to solve a challenge first, either a proof of work tied to their identity
+//! or a passphrase the operator hands out, and their attempts are counted per gateway address and
+//! per wireguard key in fixed windows. The counts live in the database so every instance of a
+//! clustered exit shares them. Attempts made once a limit is reached aren't counted, so a client
+//! that keeps retrying doesn't push the window's count up for everyone else behind its gateway.
+//! Refused clients are told when to come back, or that they shouldn't until their user has done
+//! something, as with a wrong passphrase. Clients that already have a record skip all of this.
+//!
+//! The proof of work is done over a seed we change every `SEED_LIFETIME` seconds, derived from
+//! our wireguard private key so that every instance of a clustered exit hands out the same one
+//! and nobody else can work out the next one. Solutions for the current and the previous seed
+//! are accepted so that a client that started solving just before the change isn't turned away.
+
+use crate::rita_exit::database::secs_since_unix_epoch;
+use crate::rita_exit::database::store::ExitStore;
+use crate::EXIT_WG_PRIVATE_KEY;
+use crate::SETTING;
+use althea_types::{check_signup_pow, ExitClientIdentity, SignupChallenge};
+use failure::Error;
+use settings::exit::{RitaExitSettings, SignupChallengeSettings, SignupRateLimits};
+use sodiumoxide::crypto::hash::sha256;
+use std::net::IpAddr;
+
+/// How long a proof of work seed is handed out for, a router has this long and then some to solve
+/// the challenge
+const SEED_LIFETIME: u64 = 30 * 60;
+
+/// The proof of work seed for the period starting at `period * SEED_LIFETIME`
+fn pow_seed(secret: &[u8], period: u64) -> u64 {
+    let mut data = Vec::new();
+    data.extend_from_slice(secret);
+    data.extend_from_slice(&period.to_be_bytes());
+    let hash = sha256::hash(&data).0;
+    let mut seed = [0u8; 8];
+    seed.copy_from_slice(&hash[..8]);
+    u64::from_be_bytes(seed)
+}
+
+/// The seed we hand out now followed by the previous one, which we still accept
+fn current_pow_seeds(secret: &[u8], now: u64) -> [u64; 2] {
+    let period = now / SEED_LIFETIME;
+    [
+        pow_seed(secret, period),
+        pow_seed(secret, period.saturating_sub(1)),
+    ]
+}
+
+fn our_pow_seeds() -> [u64; 2] {
+    current_pow_seeds(EXIT_WG_PRIVATE_KEY.as_ref(), secs_since_unix_epoch() as u64)
+}
+
+/// The challenge as we advertise it to clients, without the passphrase
+pub fn advertised_challenge() -> Option<SignupChallenge> {
+    match SETTING.get_exit_network().signup_challenge.clone()? {
+        SignupChallengeSettings::ProofOfWork { difficulty } => Some(SignupChallenge::ProofOfWork {
+            difficulty,
+            seed: our_pow_seeds()[0],
+        }),
+        SignupChallengeSettings::Passphrase { prompt, .. } => {
+            Some(SignupChallenge::Passphrase { prompt })
+        }
+    }
+}
+
+pub fn challenge_solved(
+    challenge: &SignupChallengeSettings,
+    client: &ExitClientIdentity,
+    seeds: &[u64],
+) -> bool {
+    let response = match &client.signup_response {
+        Some(response) => response,
+        None => return false,
+    };
+    match challenge {
+        SignupChallengeSettings::ProofOfWork { difficulty } => match response.parse() {
+            Ok(nonce) => seeds
+                .iter()
+                .any(|seed| check_signup_pow(&client.global, *seed, nonce, *difficulty)),
+            Err(_) => false,
+        },
+        SignupChallengeSettings::Passphrase { passphrase, .. } => response.trim() == passphrase,
+    }
+}
+
+/// Why a new client was turned away
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignupRefusal {
+    pub message: String,
+    /// seconds until the client may try again on its own, None if its user has to act first
+    pub retry_after: Option<u64>,
+}
+
+/// Counts an attempt against both limits unless it's already over either, in which case it's
+/// refused until the window is over
+pub fn count_signup_attempt(
+    conn: &dyn ExitStore,
+    limits: &SignupRateLimits,
+    gateway_ip: IpAddr,
+    client: &ExitClientIdentity,
+    now: i64,
+) -> Result<Option<SignupRefusal>, Error> {
+    let window = limits.window.max(1) as i64;
+    let window_start = now - now % window;
+    let ip_key = format!("ip/{}", gateway_ip);
+    let id_key = format!("id/{}", client.global.wg_public_key);
+    let ip_attempts = conn.get_signup_attempts(&ip_key, window_start)?;
+    let id_attempts = conn.get_signup_attempts(&id_key, window_start)?;
+    let over_ip = limits.per_ip != 0 && ip_attempts >= i64::from(limits.per_ip);
+    let over_id = limits.per_identity != 0 && id_attempts >= i64::from(limits.per_identity);
+    if over_ip || over_id {
+        warn!(
+            "Rate limiting signup from {} behind {}, {} attempts from the address and {} with the key",
+            client.global.wg_public_key, gateway_ip, ip_attempts, id_attempts
+        );
+        let retry_after = window_start + window - now;
+        return Ok(Some(SignupRefusal {
+            message: format!(
+                "Too many signup attempts, please try again in {} minutes",
+                (retry_after + 59) / 60
+            ),
+            retry_after: Some(retry_after as u64),
+        }));
+    }
+    conn.prune_signup_attempts(window_start)?;
+    conn.add_signup_attempt(&ip_key, window_start)?;
+    conn.add_signup_attempt(&id_key, window_start)?;
+    Ok(None)
+}
+
+/// Why a new client can't sign up yet, None if it can go ahead
+pub fn check_signup_gate(
+    conn: &dyn ExitStore,
+    client: &ExitClientIdentity,
+    gateway_ip: IpAddr,
+    now: i64,
+) -> Result<Option<SignupRefusal>, Error> {
+    let exit_network = SETTING.get_exit_network();
+    let limits = exit_network.signup_rate_limits.clone();
+    let challenge = exit_network.signup_challenge.clone();
+    drop(exit_network);
+
+    if let Some(limits) = limits {
+        if let Some(refusal) = count_signup_attempt(conn, &limits, gateway_ip, client, now)? {
+            return Ok(Some(refusal));
+        }
+    }
+    match challenge {
+        Some(ref challenge) if !challenge_solved(challenge, client, &our_pow_seeds()) => {
+            info!(
+                "Signup from {} didn't solve our challenge",
+                client.global.wg_public_key
+            );
+            Ok(Some(match challenge {
+                // the router solves a new one on its own
+                SignupChallengeSettings::ProofOfWork { .. } => SignupRefusal {
+                    message: "This exit requires a proof of work to sign up, your router will retry with a new one"
+                        .to_string(),
+                    retry_after: Some(0),
+                },
+                // but only its user can get the passphrase right
+                SignupChallengeSettings::Passphrase { prompt, .. } => SignupRefusal {
+                    message: format!("Wrong or missing signup passphrase. {}", prompt),
+                    retry_after: None,
+                },
+            }))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[test]
+fn test_signup_gate() {
+    use crate::rita_common::utils::test_identity;
+    use crate::rita_exit::database::store::EmbeddedStore;
+    use althea_types::{solve_signup_pow, ExitRegistrationDetails};
+
+    let mut global = test_identity("fd00::1");
+    global.eth_address = "0x0101010101010101010101010101010101010101"
+        .parse()
+        .unwrap();
+    let mut client = ExitClientIdentity {
+        wg_port: 60000,
+        global,
+        reg_details: ExitRegistrationDetails {
+            email: None,
+            email_code: None,
+            phone: None,
+            phone_code: None,
+        },
+        low_balance: None,
+        voucher: None,
+        preferred_internal_ip: None,
+        preferred_ipv6_subnet: None,
+        signup_response: None,
+    };
+
+    let pow = SignupChallengeSettings::ProofOfWork { difficulty: 16 };
+    let passphrase = SignupChallengeSettings::Passphrase {
+        prompt: "Ask at the co-op office".to_string(),
+        passphrase: "open sesame".to_string(),
+    };
+    let secret = [7u8; 32];
+    let seeds = current_pow_seeds(&secret, 10 * SEED_LIFETIME + 5);
+    assert!(!challenge_solved(&pow, &client, &seeds));
+    client.signup_response = Some(
+        solve_signup_pow(&global, seeds[0], 16, || false)
+            .unwrap()
+            .to_string(),
+    );
+    assert!(challenge_solved(&pow, &client, &seeds));
+    // still good for the next period, but not the one after that
+    let next = current_pow_seeds(&secret, 11 * SEED_LIFETIME + 5);
+    assert_eq!(next[1], seeds[0]);
+    assert!(challenge_solved(&pow, &client, &next));
+    let later = current_pow_seeds(&secret, 12 * SEED_LIFETIME + 5);
+    assert!(!challenge_solved(&pow, &client, &later));
+    // and the seeds can't be worked out without our key
+    assert_ne!(current_pow_seeds(&[8u8; 32], 10 * SEED_LIFETIME + 5), seeds);
+    assert!(!challenge_solved(&passphrase, &client, &seeds));
+    client.signup_response = Some("open sesame ".to_string());
+    assert!(challenge_solved(&passphrase, &client, &seeds));
+
+    let store = EmbeddedStore::temporary().unwrap();
+    let limits = SignupRateLimits {
+        per_ip: 2,
+        per_identity: 0,
+        window: 3600,
+    };
+    let gateway = "203.0.113.1".parse().unwrap();
+    let other_gateway = "203.0.113.2".parse().unwrap();
+    let attempt = |gateway, now| count_signup_attempt(&store, &limits, gateway, &client, now);
+    assert_eq!(attempt(gateway, 3600).unwrap(), None);
+    assert_eq!(attempt(gateway, 3700).unwrap(), None);
+    assert_eq!(
+        attempt(gateway, 3800).unwrap(),
+        Some(SignupRefusal {
+            message: "Too many signup attempts, please try again in 57 minutes".to_string(),
+            retry_after: Some(3400),
+        })
+    );
+    // refused attempts aren't counted
+    assert!(attempt(gateway, 3900).unwrap().is_some());
+    assert_eq!(
+        store
+            .get_signup_attempts(&format!("ip/{}", gateway), 3600)
+            .unwrap(),
+        2
+    );
+    assert_eq!(attempt(other_gateway, 3800).unwrap(), None);
+    // a new window starts over
+    assert_eq!(attempt(gateway, 7200).unwrap(), None);
+
+    let limits = SignupRateLimits {
+        per_ip: 0,
+        per_identity: 1,
+        window: 3600,
+    };
+    assert_eq!(
+        count_signup_attempt(&store, &limits, other_gateway, &client, 10800).unwrap(),
+        None
+    );
+    assert!(
+        count_signup_attempt(&store, &limits, gateway, &client, 10900)
+            .unwrap()
+            .is_some()
+    );
+}
@@ -2,8 +2,14 @@ use crate::rita_exit::database::database_tools::text_sent;
 use crate::rita_exit::database::database_tools::verify_client;
 use crate::rita_exit::database::get_database_connection;
 use crate::rita_exit::database::get_exit_info;
+use crate::rita_exit::database::plans::client_plan;
+use crate::rita_exit::database::port_policy::client_port_blocks;
+use crate::rita_exit::database::struct_tools::parse_ipv6_subnet;
+use crate::rita_exit::database::struct_tools::parse_nat_port_range;
+use crate::rita_exit::database::struct_tools::parse_public_ipv4;
 use crate::rita_exit::database::struct_tools::texts_sent;
-use actix::Arbiter;
+use crate::rita_exit::notifications::{render, Notification, Notifier, Notify, Recipient};
+use actix::SystemService;
 use actix_web::client as actix_client;
 use actix_web::client::ClientResponse;
 use althea_types::{ExitClientDetails, ExitClientIdentity, ExitState};
@@ -114,6 +120,11 @@ pub fn handle_sms_registration(
                         Ok(ExitState::Registered {
                             our_details: ExitClientDetails {
                                 client_internal_ip: their_record.internal_ip.parse()?,
+                                internet_ipv6_subnet: parse_ipv6_subnet(&their_record),
+                                nat_port_range: parse_nat_port_range(&their_record),
+                                public_ipv4: parse_public_ipv4(&their_record),
+                                port_blocks: client_port_blocks(&their_record.mesh_ip),
+                                plan: client_plan(&their_record),
                             },
                             general_details: get_exit_info(),
                             message: "Registration OK".to_string(),
@@ -124,6 +135,7 @@ pub fn handle_sms_registration(
                             message: "awaiting phone verification".to_string(),
                             email_code: None,
                             phone_code: None,
+                            remaining_attempts: None,
                         })
                     }
                 })
@@ -135,6 +147,7 @@ pub fn handle_sms_registration(
             message: "awaiting phone verification".to_string(),
             email_code: None,
             phone_code: None,
+            remaining_attempts: None,
         })),
         // user has attempts remaining and is requesting the code be resent
         (Some(number), None, false) => {
@@ -146,6 +159,7 @@ pub fn handle_sms_registration(
                         message: "awaiting phone verification".to_string(),
                         email_code: None,
                         phone_code: None,
+                        remaining_attempts: None,
                     })
                 })
             })) as Box<dyn Future<Item = ExitState, Error = Error>>
@@ -165,6 +179,11 @@ pub fn handle_sms_registration(
                         Ok(ExitState::Registered {
                             our_details: ExitClientDetails {
                                 client_internal_ip: their_record.internal_ip.parse()?,
+                                internet_ipv6_subnet: parse_ipv6_subnet(&their_record),
+                                nat_port_range: parse_nat_port_range(&their_record),
+                                public_ipv4: parse_public_ipv4(&their_record),
+                                port_blocks: client_port_blocks(&their_record.mesh_ip),
+                                plan: client_plan(&their_record),
                             },
                             general_details: get_exit_info(),
                             message: "Registration OK".to_string(),
@@ -175,6 +194,7 @@ pub fn handle_sms_registration(
                             message: "awaiting phone verification".to_string(),
                             email_code: None,
                             phone_code: None,
+                            remaining_attempts: None,
                         })
                     }
                 })
@@ -187,43 +207,27 @@ pub fn handle_sms_registration(
     }
 }
 
-#[derive(Serialize)]
-pub struct SmsNotification {
-    #[serde(rename = "To")]
-    to: String,
-    #[serde(rename = "From")]
-    from: String,
-    #[serde(rename = "Body")]
-    body: String,
-}
-
 pub fn send_low_balance_sms(number: &str, phone: PhoneVerifSettings) -> Result<(), Error> {
     info!("Sending low balance message for {}", number);
 
-    let url = format!(
-        "https://api.twilio.com/2010-04-01/Accounts/{}/Messages.json",
-        phone.twillio_account_id
-    );
-    let number: PhoneNumber = number.parse()?;
-    let res = actix_client::post(&url)
-        .basic_auth(phone.twillio_account_id, Some(phone.twillio_auth_token))
-        .form(&SmsNotification {
-            to: number.to_string(),
-            from: phone.notification_number,
-            body: phone.balance_notification_body,
-        })
-        .unwrap()
-        .send()
-        .then(move |result| {
-            if result.is_err() {
-                warn!(
-                    "Low balance text to {} failed with {:?}",
-                    number.to_string(),
-                    result
-                );
-            }
-            Ok(())
-        });
-    Arbiter::spawn(res);
+    Notifier::from_registry().do_send(Notify(Notification {
+        to: Recipient::Phone(number.to_string()),
+        subject: String::new(),
+        body: render(
+            &phone.balance_notification_body,
+            &json!({ "phone": number }),
+        )?,
+    }));
+    Ok(())
+}
+
+pub fn send_balance_restored_sms(number: &str, phone: PhoneVerifSettings) -> Result<(), Error> {
+    info!("Sending balance restored message for {}", number);
+
+    Notifier::from_registry().do_send(Notify(Notification {
+        to: Recipient::Phone(number.to_string()),
+        subject: String::new(),
+        body: render(&phone.balance_restored_body, &json!({ "phone": number }))?,
+    }));
     Ok(())
 }
@@ -0,0 +1,215 @@
+//! Static public ipv4 addresses for premium clients. The operator lists addresses routed to the
+//! exit in `static_ip_pool` and assigns them to clients through the admin api, a client with an
+//! address is translated to it 1:1 instead of sharing the exit's address, so it can host
+//! services reachable from the internet. Assignments are only ever made and released by the
+//! operator, archiving a client doesn't give its address back to the pool.
+
+use crate::rita_common::dashboard::error::{DashboardError, ErrorCode};
+use crate::rita_exit::database::database_tools::with_address_lock;
+use crate::rita_exit::database::store::ExitStore;
+use crate::rita_exit::database::struct_tools::parse_public_ipv4;
+use exit_db::models::Client;
+use failure::Error;
+use std::net::Ipv4Addr;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct StaticIpAssignment {
+    pub ip: Ipv4Addr,
+    /// the client the address is assigned to, if any
+    pub mesh_ip: Option<String>,
+    /// false for addresses that were removed from the pool while still assigned, they stay
+    /// assigned until they're released
+    pub in_pool: bool,
+}
+
+/// Every address in the pool and who has it, followed by any assigned addresses no longer in it
+pub fn static_ip_assignments(pool: &[Ipv4Addr], clients: &[Client]) -> Vec<StaticIpAssignment> {
+    let mut assigned: Vec<(Ipv4Addr, &Client)> = clients
+        .iter()
+        .filter_map(|client| parse_public_ipv4(client).map(|ip| (ip, client)))
+        .collect();
+    assigned.sort_by_key(|(ip, _)| *ip);
+    let owner = |ip: Ipv4Addr| {
+        assigned
+            .iter()
+            .find(|(assigned_ip, _)| *assigned_ip == ip)
+            .map(|(_, client)| client.mesh_ip.clone())
+    };
+
+    let mut assignments: Vec<StaticIpAssignment> = pool
+        .iter()
+        .map(|ip| StaticIpAssignment {
+            ip: *ip,
+            mesh_ip: owner(*ip),
+            in_pool: true,
+        })
+        .collect();
+    for (ip, client) in assigned.iter() {
+        if !pool.contains(ip) {
+            assignments.push(StaticIpAssignment {
+                ip: *ip,
+                mesh_ip: Some(client.mesh_ip.clone()),
+                in_pool: false,
+            });
+        }
+    }
+    assignments
+}
+
+/// The address `mesh_ip` should get, the requested one if it's free or else the first free one
+fn pick_static_ip(
+    pool: &[Ipv4Addr],
+    clients: &[Client],
+    mesh_ip: &str,
+    requested: Option<Ipv4Addr>,
+) -> Result<Ipv4Addr, Error> {
+    let assignments = static_ip_assignments(pool, clients);
+    let free = |assignment: &StaticIpAssignment| {
+        assignment.in_pool
+            && assignment
+                .mesh_ip
+                .as_ref()
+                .map_or(true, |owner| owner == mesh_ip)
+    };
+    match requested {
+        Some(ip) => match assignments.iter().find(|a| a.ip == ip) {
+            Some(assignment) if free(assignment) => Ok(ip),
+            Some(_) => Err(DashboardError::invalid_input(format!(
+                "{} is already assigned to another client",
+                ip
+            ))
+            .into()),
+            None => Err(DashboardError::invalid_input(format!(
+                "{} is not in the static ip pool",
+                ip
+            ))
+            .into()),
+        },
+        None => match assignments.iter().find(|a| free(a)) {
+            Some(assignment) => Ok(assignment.ip),
+            None => Err(DashboardError::not_ready(
+                "There are no free addresses in the static ip pool",
+            )
+            .into()),
+        },
+    }
+}
+
+/// Assigns `mesh_ip` a static address, `requested` or any free one. A client that already has
+/// an address keeps it unless another one is requested. The address is in use from the next
+/// exit loop on
+pub fn assign_static_ip(
+    mesh_ip: &str,
+    requested: Option<Ipv4Addr>,
+    pool: &[Ipv4Addr],
+    conn: &dyn ExitStore,
+) -> Result<Ipv4Addr, Error> {
+    // picking a free address and saving it happen under the address lock, otherwise two
+    // assignments at once could both see the same address as free
+    with_address_lock(conn, || assign(mesh_ip, requested, pool, conn))
+}
+
+fn assign(
+    mesh_ip: &str,
+    requested: Option<Ipv4Addr>,
+    pool: &[Ipv4Addr],
+    conn: &dyn ExitStore,
+) -> Result<Ipv4Addr, Error> {
+    let clients = conn.load_clients()?;
+    let client = match clients.iter().find(|client| client.mesh_ip == mesh_ip) {
+        Some(client) => client,
+        None => return Err(unknown_client(mesh_ip)),
+    };
+    if let (Some(current), None) = (parse_public_ipv4(client), requested) {
+        return Ok(current);
+    }
+    let ip = pick_static_ip(pool, &clients, mesh_ip, requested)?;
+    if !conn.update_client(mesh_ip, &mut |record| record.public_ipv4 = ip.to_string())? {
+        return Err(unknown_client(mesh_ip));
+    }
+    info!("Assigned static ip {} to {}", ip, mesh_ip);
+    Ok(ip)
+}
+
+/// Takes `mesh_ip`'s static address back, returns the address if it had one
+pub fn release_static_ip(mesh_ip: &str, conn: &dyn ExitStore) -> Result<Option<Ipv4Addr>, Error> {
+    let mut released = None;
+    let found = conn.update_client(mesh_ip, &mut |record| {
+        released = parse_public_ipv4(record);
+        record.public_ipv4 = String::new();
+    })?;
+    if !found {
+        return Err(unknown_client(mesh_ip));
+    }
+    if let Some(ip) = released {
+        info!("Released static ip {} from {}", ip, mesh_ip);
+    }
+    Ok(released)
+}
+
+fn unknown_client(mesh_ip: &str) -> Error {
+    DashboardError::new(
+        ErrorCode::NotFound,
+        format!("No client with mesh ip {}", mesh_ip),
+    )
+    .into()
+}
+
+#[test]
+fn test_static_ip_assignment() {
+    use crate::rita_exit::database::store::EmbeddedStore;
+
+    let store = EmbeddedStore::temporary().unwrap();
+    for mesh_ip in &["fd00::1", "fd00::2", "fd00::3"] {
+        store
+            .insert_client(&Client {
+                mesh_ip: mesh_ip.to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+    }
+    let a: Ipv4Addr = "203.0.113.1".parse().unwrap();
+    let b: Ipv4Addr = "203.0.113.2".parse().unwrap();
+    let pool = vec![a, b];
+
+    assert_eq!(assign_static_ip("fd00::1", None, &pool, &store).unwrap(), a);
+    // assigning again is a no op
+    assert_eq!(assign_static_ip("fd00::1", None, &pool, &store).unwrap(), a);
+    assert!(assign_static_ip("fd00::2", Some(a), &pool, &store).is_err());
+    assert!(assign_static_ip(
+        "fd00::2",
+        Some("198.51.100.1".parse().unwrap()),
+        &pool,
+        &store
+    )
+    .is_err());
+    assert_eq!(assign_static_ip("fd00::2", None, &pool, &store).unwrap(), b);
+    // the pool is used up
+    assert!(assign_static_ip("fd00::3", None, &pool, &store).is_err());
+    assert!(assign_static_ip("fd00::4", None, &pool, &store).is_err());
+
+    assert_eq!(release_static_ip("fd00::1", &store).unwrap(), Some(a));
+    assert_eq!(release_static_ip("fd00::1", &store).unwrap(), None);
+    assert_eq!(
+        assign_static_ip("fd00::3", Some(a), &pool, &store).unwrap(),
+        a
+    );
+
+    // shrinking the pool leaves existing assignments alone
+    let assignments = static_ip_assignments(&[a], &store.load_clients().unwrap());
+    assert_eq!(
+        assignments,
+        vec![
+            StaticIpAssignment {
+                ip: a,
+                mesh_ip: Some("fd00::3".to_string()),
+                in_pool: true,
+            },
+            StaticIpAssignment {
+                ip: b,
+                mesh_ip: Some("fd00::2".to_string()),
+                in_pool: false,
+            },
+        ]
+    );
+}
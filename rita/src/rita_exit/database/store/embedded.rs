@@ -0,0 +1,329 @@
+//! The embedded store, a sled database in a local directory with a tree per table. Rows are
+//! stored as json keyed by their primary key. Only one exit process uses the store, so writes
+//! that read a row first just hold a lock rather than using sled's transactions.
+
+use super::ExitStore;
+use exit_db::models::{Client, NodeDebt, SignupAttempt, UsageRecord, Voucher};
+use failure::Error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone)]
+pub struct EmbeddedStore {
+    db: sled::Db,
+    clients: sled::Tree,
+    vouchers: sled::Tree,
+    usage_records: sled::Tree,
+    node_debts: sled::Tree,
+    signup_attempts: sled::Tree,
+    write_lock: Arc<Mutex<()>>,
+    /// held across a whole address allocation, which takes `write_lock` for each write
+    address_lock: Arc<Mutex<()>>,
+}
+
+fn usage_key(mesh_ip: &str, hour: i64) -> String {
+    format!("{}/{}", mesh_ip, hour)
+}
+
+fn get<T: DeserializeOwned>(tree: &sled::Tree, key: &str) -> Result<Option<T>, Error> {
+    match tree.get(key)? {
+        Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+fn put<T: Serialize>(tree: &sled::Tree, key: &str, value: &T) -> Result<(), Error> {
+    tree.insert(key, serde_json::to_vec(value)?)?;
+    Ok(())
+}
+
+fn load_all<T: DeserializeOwned>(tree: &sled::Tree) -> Result<Vec<T>, Error> {
+    let mut rows = Vec::new();
+    for entry in tree.iter() {
+        let (_, bytes) = entry?;
+        rows.push(serde_json::from_slice(&bytes)?);
+    }
+    Ok(rows)
+}
+
+impl EmbeddedStore {
+    pub fn open(path: &Path) -> Result<EmbeddedStore, Error> {
+        EmbeddedStore::new(sled::open(path)?)
+    }
+
+    /// A store that's deleted once it's dropped, for tests
+    pub fn temporary() -> Result<EmbeddedStore, Error> {
+        EmbeddedStore::new(sled::Config::new().temporary(true).open()?)
+    }
+
+    fn new(db: sled::Db) -> Result<EmbeddedStore, Error> {
+        Ok(EmbeddedStore {
+            clients: db.open_tree("clients")?,
+            vouchers: db.open_tree("vouchers")?,
+            usage_records: db.open_tree("usage_records")?,
+            node_debts: db.open_tree("node_debts")?,
+            signup_attempts: db.open_tree("signup_attempts")?,
+            db,
+            write_lock: Arc::new(Mutex::new(())),
+            address_lock: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// Makes sure a write is on disk before we tell anyone it happened
+    fn flush(&self) -> Result<(), Error> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+impl ExitStore for EmbeddedStore {
+    fn load_clients(&self) -> Result<Vec<Client>, Error> {
+        load_all(&self.clients)
+    }
+
+    fn get_client(&self, mesh_ip: &str) -> Result<Option<Client>, Error> {
+        get(&self.clients, mesh_ip)
+    }
+
+    fn insert_client(&self, client: &Client) -> Result<(), Error> {
+        let _lock = self.write_lock.lock().unwrap();
+        if self.clients.contains_key(&client.mesh_ip)? {
+            bail!("There is already a client with mesh ip {}", client.mesh_ip);
+        }
+        put(&self.clients, &client.mesh_ip, client)?;
+        self.flush()
+    }
+
+    fn update_client(
+        &self,
+        mesh_ip: &str,
+        update: &mut dyn FnMut(&mut Client),
+    ) -> Result<bool, Error> {
+        let _lock = self.write_lock.lock().unwrap();
+        let mut client: Client = match get(&self.clients, mesh_ip)? {
+            Some(client) => client,
+            None => return Ok(false),
+        };
+        update(&mut client);
+        put(&self.clients, mesh_ip, &client)?;
+        self.flush()?;
+        Ok(true)
+    }
+
+    fn delete_client(&self, mesh_ip: &str) -> Result<(), Error> {
+        let _lock = self.write_lock.lock().unwrap();
+        self.clients.remove(mesh_ip)?;
+        self.flush()
+    }
+
+    fn delete_all_clients(&self) -> Result<(), Error> {
+        let _lock = self.write_lock.lock().unwrap();
+        self.clients.clear()?;
+        self.flush()
+    }
+
+    fn with_address_lock(
+        &self,
+        allocate: &mut dyn FnMut() -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let _lock = self.address_lock.lock().unwrap();
+        allocate()
+    }
+
+    fn load_vouchers(&self) -> Result<Vec<Voucher>, Error> {
+        load_all(&self.vouchers)
+    }
+
+    fn insert_voucher(&self, voucher: &Voucher) -> Result<(), Error> {
+        let _lock = self.write_lock.lock().unwrap();
+        if self.vouchers.contains_key(&voucher.code)? {
+            bail!("There is already a voucher with code {}", voucher.code);
+        }
+        put(&self.vouchers, &voucher.code, voucher)?;
+        self.flush()
+    }
+
+    fn update_voucher(
+        &self,
+        code: &str,
+        update: &mut dyn FnMut(Option<&mut Voucher>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let _lock = self.write_lock.lock().unwrap();
+        let voucher: Option<Voucher> = get(&self.vouchers, code)?;
+        match voucher {
+            Some(mut voucher) => {
+                update(Some(&mut voucher))?;
+                put(&self.vouchers, code, &voucher)?;
+                self.flush()
+            }
+            None => update(None),
+        }
+    }
+
+    fn save_usage_records(&self, records: &[UsageRecord]) -> Result<(), Error> {
+        let _lock = self.write_lock.lock().unwrap();
+        for record in records {
+            let key = usage_key(&record.mesh_ip, record.hour);
+            let saved = match get::<UsageRecord>(&self.usage_records, &key)? {
+                Some(existing) => UsageRecord {
+                    upload: existing.upload + record.upload,
+                    download: existing.download + record.download,
+                    ..record.clone()
+                },
+                None => record.clone(),
+            };
+            put(&self.usage_records, &key, &saved)?;
+        }
+        self.flush()
+    }
+
+    fn load_usage_records(
+        &self,
+        mesh_ip: Option<&str>,
+        start: i64,
+        end: i64,
+    ) -> Result<Vec<UsageRecord>, Error> {
+        let mut records: Vec<UsageRecord> = match mesh_ip {
+            Some(mesh_ip) => {
+                let mut records = Vec::new();
+                for entry in self.usage_records.scan_prefix(format!("{}/", mesh_ip)) {
+                    let (_, bytes) = entry?;
+                    records.push(serde_json::from_slice(&bytes)?);
+                }
+                records
+            }
+            None => load_all(&self.usage_records)?,
+        };
+        records.retain(|record| record.hour >= start && record.hour < end);
+        records.sort_by_key(|record| record.hour);
+        Ok(records)
+    }
+
+    fn prune_usage_records(&self, cutoff: i64) -> Result<usize, Error> {
+        let _lock = self.write_lock.lock().unwrap();
+        let records: Vec<UsageRecord> = load_all(&self.usage_records)?;
+        let mut pruned = 0;
+        for record in records.iter().filter(|record| record.hour < cutoff) {
+            self.usage_records
+                .remove(usage_key(&record.mesh_ip, record.hour))?;
+            pruned += 1;
+        }
+        self.flush()?;
+        Ok(pruned)
+    }
+
+    fn save_node_debts(&self, debts: &[NodeDebt]) -> Result<(), Error> {
+        let _lock = self.write_lock.lock().unwrap();
+        for debt in debts {
+            put(
+                &self.node_debts,
+                &format!("{}/{}", debt.node_id, debt.mesh_ip),
+                debt,
+            )?;
+        }
+        self.flush()
+    }
+
+    fn load_node_debts(&self) -> Result<Vec<NodeDebt>, Error> {
+        load_all(&self.node_debts)
+    }
+
+    fn get_signup_attempts(&self, key: &str, window_start: i64) -> Result<i64, Error> {
+        let tree_key = format!("{}/{}", key, window_start);
+        Ok(get::<SignupAttempt>(&self.signup_attempts, &tree_key)?.map_or(0, |a| a.attempts))
+    }
+
+    fn add_signup_attempt(&self, key: &str, window_start: i64) -> Result<i64, Error> {
+        let _lock = self.write_lock.lock().unwrap();
+        let tree_key = format!("{}/{}", key, window_start);
+        let attempt = match get::<SignupAttempt>(&self.signup_attempts, &tree_key)? {
+            Some(existing) => SignupAttempt {
+                attempts: existing.attempts + 1,
+                ..existing
+            },
+            None => SignupAttempt {
+                key: key.to_string(),
+                window_start,
+                attempts: 1,
+            },
+        };
+        put(&self.signup_attempts, &tree_key, &attempt)?;
+        self.flush()?;
+        Ok(attempt.attempts)
+    }
+
+    fn prune_signup_attempts(&self, cutoff: i64) -> Result<usize, Error> {
+        let _lock = self.write_lock.lock().unwrap();
+        let attempts: Vec<SignupAttempt> = load_all(&self.signup_attempts)?;
+        let mut pruned = 0;
+        for attempt in attempts.iter().filter(|a| a.window_start < cutoff) {
+            self.signup_attempts
+                .remove(format!("{}/{}", attempt.key, attempt.window_start))?;
+            pruned += 1;
+        }
+        self.flush()?;
+        Ok(pruned)
+    }
+}
+
+#[test]
+fn test_embedded_store() {
+    let store = EmbeddedStore::temporary().unwrap();
+    let client = Client {
+        mesh_ip: "fd00::1".to_string(),
+        wg_pubkey: "key".to_string(),
+        ..Default::default()
+    };
+    store.insert_client(&client).unwrap();
+    assert!(store.insert_client(&client).is_err());
+    assert!(store
+        .update_client("fd00::1", &mut |c| c.verified = true)
+        .unwrap());
+    assert!(!store.update_client("fd00::2", &mut |_| {}).unwrap());
+    assert!(store.get_client("fd00::1").unwrap().unwrap().verified);
+    store.delete_client("fd00::1").unwrap();
+    assert!(store.load_clients().unwrap().is_empty());
+
+    let mut seen = None;
+    store
+        .update_voucher("COOP-1", &mut |v| {
+            seen = Some(v.is_some());
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(seen, Some(false));
+
+    let record = |mesh_ip: &str, hour: i64| UsageRecord {
+        mesh_ip: mesh_ip.to_string(),
+        hour,
+        wg_pubkey: "key".to_string(),
+        upload: 10,
+        download: 5,
+    };
+    // fd00::10 mustn't show up in fd00::1's records
+    store
+        .save_usage_records(&[record("fd00::1", 7200), record("fd00::10", 3600)])
+        .unwrap();
+    store
+        .save_usage_records(&[record("fd00::1", 7200), record("fd00::1", 3600)])
+        .unwrap();
+    let records = store
+        .load_usage_records(Some("fd00::1"), 0, i64::max_value())
+        .unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].hour, 3600);
+    assert_eq!((records[1].upload, records[1].download), (20, 10));
+    assert_eq!(store.prune_usage_records(7200).unwrap(), 2);
+    assert_eq!(store.load_usage_records(None, 0, 7200).unwrap().len(), 0);
+
+    assert_eq!(store.add_signup_attempt("ip/10.0.0.1", 0).unwrap(), 1);
+    assert_eq!(store.add_signup_attempt("ip/10.0.0.1", 0).unwrap(), 2);
+    assert_eq!(store.get_signup_attempts("ip/10.0.0.1", 0).unwrap(), 2);
+    assert_eq!(store.get_signup_attempts("ip/10.0.0.3", 0).unwrap(), 0);
+    assert_eq!(store.add_signup_attempt("ip/10.0.0.1", 3600).unwrap(), 1);
+    assert_eq!(store.add_signup_attempt("ip/10.0.0.2", 0).unwrap(), 1);
+    assert_eq!(store.prune_signup_attempts(3600).unwrap(), 2);
+    assert_eq!(store.add_signup_attempt("ip/10.0.0.1", 0).unwrap(), 1);
+}
@@ -0,0 +1,283 @@
+//! Where the exit keeps its clients, vouchers and usage records. Big exits use Postgres, small
+//! community exits that don't want to run a database server can keep everything in an embedded
+//! store in a local directory instead, which one is picked by the scheme of `db_uri`. The rest of
+//! the database module only talks to the `ExitStore` trait so it doesn't care which it's using.
+//! A store can be dumped to json and loaded into another one, which is how an exit that outgrows
+//! the embedded store moves to Postgres.
+
+mod embedded;
+mod postgres;
+
+pub use self::embedded::EmbeddedStore;
+pub use self::postgres::PgStore;
+
+use diesel::r2d2::ConnectionManager;
+use diesel::{Connection, PgConnection};
+use exit_db::migrations::{pending_migrations, run_pending_migrations};
+use exit_db::models::{Client, NodeDebt, UsageRecord, Voucher};
+use failure::Error;
+use r2d2::Pool;
+use std::fs::File;
+use std::path::PathBuf;
+
+pub trait ExitStore: Send {
+    fn load_clients(&self) -> Result<Vec<Client>, Error>;
+    /// The client with this mesh ip, mesh ips are unique
+    fn get_client(&self, mesh_ip: &str) -> Result<Option<Client>, Error>;
+    /// Fails if there's already a client with this mesh ip
+    fn insert_client(&self, client: &Client) -> Result<(), Error>;
+    /// Changes the client with this mesh ip in place. The record is locked while `update` runs
+    /// so changes made to other fields at the same time aren't lost. False if there's no client
+    fn update_client(
+        &self,
+        mesh_ip: &str,
+        update: &mut dyn FnMut(&mut Client),
+    ) -> Result<bool, Error>;
+    fn delete_client(&self, mesh_ip: &str) -> Result<(), Error>;
+    fn delete_all_clients(&self) -> Result<(), Error>;
+    /// Runs `allocate` holding a lock every exit instance sharing the store takes before picking
+    /// addresses for a client and saving them, so two signups can't be handed the same one. If
+    /// `allocate` fails what it wrote is undone where the store can
+    fn with_address_lock(
+        &self,
+        allocate: &mut dyn FnMut() -> Result<(), Error>,
+    ) -> Result<(), Error>;
+
+    fn load_vouchers(&self) -> Result<Vec<Voucher>, Error>;
+    fn insert_voucher(&self, voucher: &Voucher) -> Result<(), Error>;
+    /// Like `update_client`, `update` gets None if there's no voucher with this code. If it
+    /// fails nothing is written and the error is returned
+    fn update_voucher(
+        &self,
+        code: &str,
+        update: &mut dyn FnMut(Option<&mut Voucher>) -> Result<(), Error>,
+    ) -> Result<(), Error>;
+
+    /// Adds the records to the hourly rows, creating them as needed
+    fn save_usage_records(&self, records: &[UsageRecord]) -> Result<(), Error>;
+    /// Every record with an hour in [start, end), only those of one client if it's given, oldest
+    /// first
+    fn load_usage_records(
+        &self,
+        mesh_ip: Option<&str>,
+        start: i64,
+        end: i64,
+    ) -> Result<Vec<UsageRecord>, Error>;
+    /// Deletes records with an hour before `cutoff`, returns how many were deleted
+    fn prune_usage_records(&self, cutoff: i64) -> Result<usize, Error>;
+
+    /// Replaces the debts one exit instance has written for these clients
+    fn save_node_debts(&self, debts: &[NodeDebt]) -> Result<(), Error>;
+    /// The debts every instance has written
+    fn load_node_debts(&self) -> Result<Vec<NodeDebt>, Error>;
+
+    /// The signup attempts counted for `key` in the window starting at `window_start`
+    fn get_signup_attempts(&self, key: &str, window_start: i64) -> Result<i64, Error>;
+    /// Counts one more signup attempt for `key` in the window starting at `window_start`,
+    /// returns the attempts in that window including this one
+    fn add_signup_attempt(&self, key: &str, window_start: i64) -> Result<i64, Error>;
+    /// Deletes windows starting before `cutoff`
+    fn prune_signup_attempts(&self, cutoff: i64) -> Result<usize, Error>;
+}
+
+/// What `get_database_connection` hands out, a pooled Postgres connection or the embedded store
+pub type StoreConnection = Box<dyn ExitStore>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreBackend {
+    Postgres(String),
+    /// the directory the embedded store lives in
+    Embedded(PathBuf),
+}
+
+impl StoreBackend {
+    pub fn from_uri(uri: &str) -> Result<StoreBackend, Error> {
+        if uri.contains("postgres://") || uri.contains("postgresql://") || uri.contains("psql://") {
+            Ok(StoreBackend::Postgres(uri.to_string()))
+        } else if uri.starts_with("file://") && uri.len() > "file://".len() {
+            Ok(StoreBackend::Embedded(PathBuf::from(
+                &uri["file://".len()..],
+            )))
+        } else {
+            bail!(
+                "db_uri must be a postgres:// uri or a file:// path for the embedded store, not {}",
+                uri
+            )
+        }
+    }
+}
+
+pub enum StorePool {
+    Postgres(Pool<ConnectionManager<PgConnection>>),
+    Embedded(EmbeddedStore),
+}
+
+impl StorePool {
+    /// `size` is the number of Postgres connections, the embedded store doesn't need any
+    pub fn open(uri: &str, size: u32) -> Result<StorePool, Error> {
+        match StoreBackend::from_uri(uri)? {
+            StoreBackend::Postgres(uri) => {
+                let manager = ConnectionManager::new(uri);
+                Ok(StorePool::Postgres(
+                    Pool::builder().max_size(size).build(manager)?,
+                ))
+            }
+            StoreBackend::Embedded(path) => {
+                info!("Using the embedded store at {}", path.display());
+                Ok(StorePool::Embedded(EmbeddedStore::open(&path)?))
+            }
+        }
+    }
+
+    /// A connection if one is free right now
+    pub fn try_get(&self) -> Option<StoreConnection> {
+        match self {
+            StorePool::Postgres(pool) => pool
+                .try_get()
+                .map(|conn| Box::new(PgStore(conn)) as StoreConnection),
+            StorePool::Embedded(store) => Some(Box::new(store.clone())),
+        }
+    }
+
+    /// If the pool has any connection to the database open, when there's none free but some are
+    /// open the pool is just busy rather than unable to reach the database
+    pub fn connected(&self) -> bool {
+        match self {
+            StorePool::Postgres(pool) => pool.state().connections > 0,
+            StorePool::Embedded(_) => true,
+        }
+    }
+
+    /// Waits for a connection, only for use outside of the actix system
+    pub fn get(&self) -> Result<StoreConnection, Error> {
+        match self {
+            StorePool::Postgres(pool) => Ok(Box::new(PgStore(pool.get()?))),
+            StorePool::Embedded(store) => Ok(Box::new(store.clone())),
+        }
+    }
+}
+
+/// Everything in a store, in a form any other store can load
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct StoreDump {
+    pub clients: Vec<Client>,
+    pub vouchers: Vec<Voucher>,
+    pub usage_records: Vec<UsageRecord>,
+}
+
+pub fn export_store(store: &dyn ExitStore) -> Result<StoreDump, Error> {
+    Ok(StoreDump {
+        clients: store.load_clients()?,
+        vouchers: store.load_vouchers()?,
+        usage_records: store.load_usage_records(None, i64::min_value(), i64::max_value())?,
+    })
+}
+
+/// Loads a dump into a store, which has to be empty so that nothing is merged by accident
+pub fn import_store(dump: &StoreDump, store: &dyn ExitStore) -> Result<(), Error> {
+    if !store.load_clients()?.is_empty() || !store.load_vouchers()?.is_empty() {
+        bail!("Refusing to import into a store that already has clients or vouchers");
+    }
+    for client in dump.clients.iter() {
+        store.insert_client(client)?;
+    }
+    for voucher in dump.vouchers.iter() {
+        store.insert_voucher(voucher)?;
+    }
+    if !dump.usage_records.is_empty() {
+        store.save_usage_records(&dump.usage_records)?;
+    }
+    Ok(())
+}
+
+/// Writes the store at `db_uri` out to a json file, for `rita_exit --export-db`
+pub fn export_to_file(db_uri: &str, file: &str) -> Result<StoreDump, Error> {
+    let store = StorePool::open(db_uri, 1)?.get()?;
+    let dump = export_store(&*store)?;
+    serde_json::to_writer(File::create(file)?, &dump)?;
+    Ok(dump)
+}
+
+/// Loads a json file written by `export_to_file` into the store at `db_uri`, for
+/// `rita_exit --import-db`
+pub fn import_from_file(db_uri: &str, file: &str) -> Result<StoreDump, Error> {
+    let store = StorePool::open(db_uri, 1)?.get()?;
+    let dump: StoreDump = serde_json::from_reader(File::open(file)?)?;
+    import_store(&dump, &*store)?;
+    Ok(dump)
+}
+
+/// The names of the migrations Postgres at `db_uri` is missing, for `rita_exit --check-migrations`.
+/// The embedded store has no schema so it's never missing any
+pub fn check_migrations(db_uri: &str) -> Result<Vec<&'static str>, Error> {
+    match StoreBackend::from_uri(db_uri)? {
+        StoreBackend::Postgres(uri) => Ok(pending_migrations(&PgConnection::establish(&uri)?)?
+            .into_iter()
+            .map(|migration| migration.name)
+            .collect()),
+        StoreBackend::Embedded(_) => Ok(Vec::new()),
+    }
+}
+
+/// Brings Postgres at `db_uri` up to date before anything else uses it, returns the names of
+/// the migrations that were run
+pub fn run_migrations(db_uri: &str) -> Result<Vec<&'static str>, Error> {
+    match StoreBackend::from_uri(db_uri)? {
+        StoreBackend::Postgres(uri) => {
+            let ran = run_pending_migrations(&PgConnection::establish(&uri)?)?;
+            for migration in ran.iter() {
+                info!("Ran database migration {}", migration.name);
+            }
+            Ok(ran.into_iter().map(|migration| migration.name).collect())
+        }
+        StoreBackend::Embedded(_) => Ok(Vec::new()),
+    }
+}
+
+#[test]
+fn test_store_dump() {
+    assert_eq!(
+        StoreBackend::from_uri("postgres://rita@localhost/exit").unwrap(),
+        StoreBackend::Postgres("postgres://rita@localhost/exit".to_string())
+    );
+    assert_eq!(
+        StoreBackend::from_uri("file:///var/lib/rita_exit").unwrap(),
+        StoreBackend::Embedded(PathBuf::from("/var/lib/rita_exit"))
+    );
+    assert!(StoreBackend::from_uri("file://").is_err());
+    assert!(StoreBackend::from_uri("mysql://localhost").is_err());
+
+    let from = EmbeddedStore::temporary().unwrap();
+    from.insert_client(&Client {
+        mesh_ip: "fd00::1".to_string(),
+        internal_ip: "172.16.0.2".to_string(),
+        ..Default::default()
+    })
+    .unwrap();
+    from.insert_voucher(&Voucher {
+        code: "COOP-1234".to_string(),
+        amount: "1000".to_string(),
+        ..Default::default()
+    })
+    .unwrap();
+    from.save_usage_records(&[UsageRecord {
+        mesh_ip: "fd00::1".to_string(),
+        hour: 3600,
+        wg_pubkey: String::new(),
+        upload: 1,
+        download: 2,
+    }])
+    .unwrap();
+
+    let dump = export_store(&from).unwrap();
+    let to = EmbeddedStore::temporary().unwrap();
+    import_store(&dump, &to).unwrap();
+    assert_eq!(to.load_clients().unwrap()[0].internal_ip, "172.16.0.2");
+    assert_eq!(to.load_vouchers().unwrap()[0].code, "COOP-1234");
+    assert_eq!(
+        to.load_usage_records(None, 0, 7200).unwrap(),
+        dump.usage_records
+    );
+    // a second import would double count usage
+    assert!(import_store(&dump, &to).is_err());
+}
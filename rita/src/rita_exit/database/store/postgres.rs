@@ -0,0 +1,218 @@
+use super::ExitStore;
+use diesel::connection::SimpleConnection;
+use diesel::dsl::delete;
+use diesel::pg::upsert::excluded;
+use diesel::prelude::{
+    Connection, ExpressionMethods, OptionalExtension, PgConnection, QueryDsl, RunQueryDsl,
+};
+use diesel::r2d2::{ConnectionManager, PooledConnection};
+use exit_db::models::{Client, NodeDebt, SignupAttempt, UsageRecord, Voucher};
+use exit_db::schema;
+use failure::Error;
+
+/// Postgres advisory lock taken for the duration of an address allocation transaction
+const ADDRESS_LOCK: i64 = 0x616c_7468_6562;
+/// Rows per insert when saving node debts, Postgres takes at most 65535 parameters per statement
+/// and each row is four
+const NODE_DEBTS_PER_INSERT: usize = 10_000;
+
+/// A pooled connection to the Postgres database
+pub struct PgStore(pub PooledConnection<ConnectionManager<PgConnection>>);
+
+impl PgStore {
+    fn conn(&self) -> &PgConnection {
+        &*self.0
+    }
+}
+
+impl ExitStore for PgStore {
+    fn load_clients(&self) -> Result<Vec<Client>, Error> {
+        use self::schema::clients::dsl::clients;
+        Ok(clients.load::<Client>(self.conn())?)
+    }
+
+    fn get_client(&self, ip: &str) -> Result<Option<Client>, Error> {
+        use self::schema::clients::dsl::clients;
+        Ok(clients.find(ip).first::<Client>(self.conn()).optional()?)
+    }
+
+    fn insert_client(&self, client: &Client) -> Result<(), Error> {
+        use self::schema::clients::dsl::clients;
+        diesel::insert_into(clients)
+            .values(client)
+            .execute(self.conn())?;
+        Ok(())
+    }
+
+    fn update_client(&self, ip: &str, update: &mut dyn FnMut(&mut Client)) -> Result<bool, Error> {
+        use self::schema::clients::dsl::clients;
+        let conn = self.conn();
+        conn.transaction::<_, Error, _>(|| {
+            let client: Option<Client> = clients.find(ip).for_update().first(conn).optional()?;
+            let mut client = match client {
+                Some(client) => client,
+                None => return Ok(false),
+            };
+            update(&mut client);
+            diesel::update(clients.find(ip))
+                .set(&client)
+                .execute(conn)?;
+            Ok(true)
+        })
+    }
+
+    fn delete_client(&self, ip: &str) -> Result<(), Error> {
+        use self::schema::clients::dsl::clients;
+        delete(clients.find(ip)).execute(self.conn())?;
+        Ok(())
+    }
+
+    fn delete_all_clients(&self) -> Result<(), Error> {
+        use self::schema::clients::dsl::clients;
+        delete(clients).execute(self.conn())?;
+        Ok(())
+    }
+
+    fn with_address_lock(
+        &self,
+        allocate: &mut dyn FnMut() -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let conn = self.conn();
+        // the lock is released when the transaction ends, the other store methods called by
+        // `allocate` run in it too and their own transactions become savepoints
+        conn.transaction::<_, Error, _>(|| {
+            conn.batch_execute(&format!("SELECT pg_advisory_xact_lock({});", ADDRESS_LOCK))?;
+            allocate()
+        })
+    }
+
+    fn load_vouchers(&self) -> Result<Vec<Voucher>, Error> {
+        use self::schema::vouchers::dsl::vouchers;
+        Ok(vouchers.load::<Voucher>(self.conn())?)
+    }
+
+    fn insert_voucher(&self, voucher: &Voucher) -> Result<(), Error> {
+        use self::schema::vouchers::dsl::vouchers;
+        diesel::insert_into(vouchers)
+            .values(voucher)
+            .execute(self.conn())?;
+        Ok(())
+    }
+
+    fn update_voucher(
+        &self,
+        voucher_code: &str,
+        update: &mut dyn FnMut(Option<&mut Voucher>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        use self::schema::vouchers::dsl::vouchers;
+        let conn = self.conn();
+        conn.transaction::<_, Error, _>(|| {
+            let voucher: Option<Voucher> = vouchers
+                .find(voucher_code)
+                .for_update()
+                .first(conn)
+                .optional()?;
+            match voucher {
+                Some(mut voucher) => {
+                    update(Some(&mut voucher))?;
+                    diesel::update(vouchers.find(voucher_code))
+                        .set(&voucher)
+                        .execute(conn)?;
+                    Ok(())
+                }
+                None => update(None),
+            }
+        })
+    }
+
+    fn save_usage_records(&self, records: &[UsageRecord]) -> Result<(), Error> {
+        use self::schema::usage_records::dsl::{
+            download, hour, mesh_ip, upload, usage_records, wg_pubkey,
+        };
+        diesel::insert_into(usage_records)
+            .values(records)
+            .on_conflict((mesh_ip, hour))
+            .do_update()
+            .set((
+                wg_pubkey.eq(excluded(wg_pubkey)),
+                upload.eq(upload + excluded(upload)),
+                download.eq(download + excluded(download)),
+            ))
+            .execute(self.conn())?;
+        Ok(())
+    }
+
+    fn load_usage_records(
+        &self,
+        client: Option<&str>,
+        start: i64,
+        end: i64,
+    ) -> Result<Vec<UsageRecord>, Error> {
+        use self::schema::usage_records::dsl::{hour, mesh_ip, usage_records};
+        let query = usage_records
+            .filter(hour.ge(start))
+            .filter(hour.lt(end))
+            .order(hour.asc())
+            .into_boxed();
+        let query = match client {
+            Some(client) => query.filter(mesh_ip.eq(client)),
+            None => query,
+        };
+        Ok(query.load(self.conn())?)
+    }
+
+    fn prune_usage_records(&self, cutoff: i64) -> Result<usize, Error> {
+        use self::schema::usage_records::dsl::{hour, usage_records};
+        Ok(delete(usage_records.filter(hour.lt(cutoff))).execute(self.conn())?)
+    }
+
+    fn save_node_debts(&self, debts: &[NodeDebt]) -> Result<(), Error> {
+        use self::schema::node_debts::dsl::{debt, mesh_ip, node_debts, node_id, updated};
+        self.conn().transaction::<_, Error, _>(|| {
+            for chunk in debts.chunks(NODE_DEBTS_PER_INSERT) {
+                diesel::insert_into(node_debts)
+                    .values(chunk)
+                    .on_conflict((node_id, mesh_ip))
+                    .do_update()
+                    .set((debt.eq(excluded(debt)), updated.eq(excluded(updated))))
+                    .execute(self.conn())?;
+            }
+            Ok(())
+        })
+    }
+
+    fn load_node_debts(&self) -> Result<Vec<NodeDebt>, Error> {
+        use self::schema::node_debts::dsl::node_debts;
+        Ok(node_debts.load(self.conn())?)
+    }
+
+    fn get_signup_attempts(&self, attempt_key: &str, start: i64) -> Result<i64, Error> {
+        use self::schema::signup_attempts::dsl::{attempts, signup_attempts};
+        Ok(signup_attempts
+            .find((attempt_key, start))
+            .select(attempts)
+            .first(self.conn())
+            .optional()?
+            .unwrap_or(0))
+    }
+
+    fn add_signup_attempt(&self, attempt_key: &str, start: i64) -> Result<i64, Error> {
+        use self::schema::signup_attempts::dsl::{attempts, key, signup_attempts, window_start};
+        let row: SignupAttempt = diesel::insert_into(signup_attempts)
+            .values(&SignupAttempt {
+                key: attempt_key.to_string(),
+                window_start: start,
+                attempts: 1,
+            })
+            .on_conflict((key, window_start))
+            .do_update()
+            .set(attempts.eq(attempts + 1))
+            .get_result(self.conn())?;
+        Ok(row.attempts)
+    }
+
+    fn prune_signup_attempts(&self, cutoff: i64) -> Result<usize, Error> {
+        use self::schema::signup_attempts::dsl::{signup_attempts, window_start};
+        Ok(delete(signup_attempts.filter(window_start.lt(cutoff))).execute(self.conn())?)
+    }
+}
@@ -1,13 +1,16 @@
+use crate::rita_exit::database::secs_since_unix_epoch;
 use althea_kernel_interface::ExitClient;
 use althea_types::ExitClientIdentity;
 use althea_types::Identity;
+use althea_types::NatPortRange;
 use arrayvec::ArrayString;
 use exit_db::models;
 use exit_db::models::Client;
 use failure::Error;
+use ipnetwork::IpNetwork;
 use rand::Rng;
 use std::collections::HashSet;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr};
 
 pub fn to_identity(client: &Client) -> Result<Identity, Error> {
     trace!("Converting client {:?}", client);
@@ -25,9 +28,57 @@ pub fn to_exit_client(client: Client) -> Result<ExitClient, Error> {
         internal_ip: client.internal_ip.parse()?,
         port: client.wg_port as u16,
         public_key: client.wg_pubkey.parse()?,
+        internet_ipv6: parse_ipv6_subnet(&client),
+        nat_port_range: parse_nat_port_range(&client),
+        public_ipv4: parse_public_ipv4(&client),
     })
 }
 
+/// The share of a public ipv4 address this client is translated to, if the exit is in cgnat mode.
+/// Stored as an empty string for clients that don't have one
+pub fn parse_nat_port_range(client: &Client) -> Option<NatPortRange> {
+    if client.nat_port_range.is_empty() {
+        return None;
+    }
+    match client.nat_port_range.parse() {
+        Ok(range) => Some(range),
+        Err(e) => {
+            error!("Bad nat port range in database entry {:?} {:?}", client, e);
+            None
+        }
+    }
+}
+
+/// The ipv6 subnet delegated to this client, if any. Stored as an empty string for clients
+/// that don't have one
+pub fn parse_ipv6_subnet(client: &Client) -> Option<IpNetwork> {
+    if client.internet_ipv6.is_empty() {
+        return None;
+    }
+    match client.internet_ipv6.parse() {
+        Ok(subnet) => Some(subnet),
+        Err(e) => {
+            error!("Bad ipv6 subnet in database entry {:?} {:?}", client, e);
+            None
+        }
+    }
+}
+
+/// The static public ipv4 address assigned to this client, if any. Stored as an empty string
+/// for clients that don't have one
+pub fn parse_public_ipv4(client: &Client) -> Option<Ipv4Addr> {
+    if client.public_ipv4.is_empty() {
+        return None;
+    }
+    match client.public_ipv4.parse() {
+        Ok(ip) => Some(ip),
+        Err(e) => {
+            error!("Bad public ipv4 in database entry {:?} {:?}", client, e);
+            None
+        }
+    }
+}
+
 pub fn clients_to_ids(clients: Vec<Client>) -> Vec<Identity> {
     let mut ids: Vec<Identity> = Vec::new();
     for client in clients.iter() {
@@ -45,6 +96,12 @@ pub fn verif_done(client: &models::Client) -> bool {
     client.verified
 }
 
+/// returns true if the client has been archived for inactivity, archived clients have no
+/// addresses and no wg peer until they sign up again
+pub fn is_archived(client: &models::Client) -> bool {
+    client.archived_time != 0
+}
+
 /// returns the number of text messages this entry has requested
 /// and recieved so far
 pub fn texts_sent(client: &models::Client) -> i32 {
@@ -60,13 +117,20 @@ pub fn display_hashset(input: &HashSet<String>) -> String {
     out
 }
 
+/// A fresh six digit email verification code
+pub fn generate_email_code() -> String {
+    let mut rng = rand::thread_rng();
+    let rand_code: u64 = rng.gen_range(0, 999_999);
+    format!("{:06}", rand_code)
+}
+
 pub fn client_to_new_db_client(
     client: &ExitClientIdentity,
     new_ip: IpAddr,
+    new_ipv6: Option<IpNetwork>,
+    new_nat_port_range: Option<NatPortRange>,
     country: String,
 ) -> models::Client {
-    let mut rng = rand::thread_rng();
-    let rand_code: u64 = rng.gen_range(0, 999_999);
     models::Client {
         wg_port: i32::from(client.wg_port),
         mesh_ip: client.global.mesh_ip.to_string(),
@@ -77,11 +141,23 @@ pub fn client_to_new_db_client(
         email: client.reg_details.email.clone().unwrap_or_default(),
         phone: client.reg_details.phone.clone().unwrap_or_default(),
         country,
-        email_code: format!("{:06}", rand_code),
+        email_code: generate_email_code(),
         text_sent: 0,
         verified: false,
         email_sent_time: 0,
         last_seen: 0,
         last_balance_warning_time: 0,
+        internet_ipv6: new_ipv6.map(|s| s.to_string()).unwrap_or_default(),
+        nat_port_range: new_nat_port_range
+            .map(|r| r.to_string())
+            .unwrap_or_default(),
+        archived_time: 0,
+        public_ipv4: String::new(),
+        device_limit: 0,
+        plan_name: String::new(),
+        rate_limit: 0,
+        monthly_quota: 0,
+        email_code_time: secs_since_unix_epoch(),
+        email_attempts: 0,
     }
 }
@@ -0,0 +1,212 @@
+//! Hourly usage per client, kept in the database so that operators can answer questions like
+//! 'how much did this client use in March' long after DebtKeeper has forgotten. The exit traffic
+//! watcher adds what it bills for each round to a buffer here, which is written out every few
+//! minutes as increments to the hourly rows so that a restart loses at most one buffer's worth.
+//! Records older than the configured retention are pruned as part of writing them out.
+
+use crate::rita_exit::database::database_tools::get_database_connection;
+use crate::rita_exit::database::store::ExitStore;
+use crate::rita_exit::database::{secs_since_unix_epoch, ONE_DAY};
+use crate::rita_exit::traffic_watcher::{ReturnUsage, TrafficWatcher};
+use crate::SETTING;
+use actix::SystemService;
+use althea_types::Identity;
+use exit_db::models;
+use failure::Error;
+use futures01::Future;
+use settings::exit::RitaExitSettings;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+pub const SECONDS_PER_HOUR: i64 = 3600;
+
+/// How often buffered usage is written to the database
+const USAGE_FLUSH_FREQUENCY: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Default)]
+pub struct UsageBuffer {
+    pending: HashMap<(String, i64), models::UsageRecord>,
+    last_flush: Option<Instant>,
+}
+
+impl UsageBuffer {
+    /// Adds usage for a client at `now` seconds since the unix epoch, upload and download are
+    /// from the client's point of view
+    pub fn add(&mut self, client: &Identity, now: i64, upload: u64, download: u64) {
+        if upload == 0 && download == 0 {
+            return;
+        }
+        let hour = now - now % SECONDS_PER_HOUR;
+        let mesh_ip = client.mesh_ip.to_string();
+        let record = self
+            .pending
+            .entry((mesh_ip.clone(), hour))
+            .or_insert_with(|| models::UsageRecord {
+                mesh_ip,
+                hour,
+                wg_pubkey: client.wg_public_key.to_string(),
+                upload: 0,
+                download: 0,
+            });
+        record.upload += upload as i64;
+        record.download += download as i64;
+    }
+
+    /// Puts back records that failed to be written so that they are tried again
+    pub fn restore(&mut self, records: Vec<models::UsageRecord>) {
+        for record in records {
+            let key = (record.mesh_ip.clone(), record.hour);
+            match self.pending.get_mut(&key) {
+                Some(pending) => {
+                    pending.upload += record.upload;
+                    pending.download += record.download;
+                }
+                None => {
+                    self.pending.insert(key, record);
+                }
+            }
+        }
+    }
+
+    /// The buffered records if it's time they were written out
+    pub fn take_if_due(&mut self, now: Instant) -> Option<Vec<models::UsageRecord>> {
+        match self.last_flush {
+            Some(last) if now - last >= USAGE_FLUSH_FREQUENCY => {
+                self.last_flush = Some(now);
+                if self.pending.is_empty() {
+                    None
+                } else {
+                    Some(self.pending.drain().map(|(_, record)| record).collect())
+                }
+            }
+            Some(_) => None,
+            None => {
+                self.last_flush = Some(now);
+                None
+            }
+        }
+    }
+}
+
+/// Deletes records past the retention period, returns how many were deleted
+pub fn prune_usage_records(conn: &dyn ExitStore, now: i64) -> Result<usize, Error> {
+    let retention = SETTING.get_exit_network().usage_record_retention;
+    if retention == 0 {
+        return Ok(0);
+    }
+    let cutoff = now - i64::from(retention) * ONE_DAY;
+    conn.prune_usage_records(cutoff)
+}
+
+/// Writes out buffered usage and prunes old records, usage that can't be written is handed back
+/// to the traffic watcher for next time
+pub fn flush_usage_records(
+    records: Vec<models::UsageRecord>,
+) -> impl Future<Item = (), Error = ()> {
+    let unsaved = records.clone();
+    get_database_connection()
+        .and_then(move |conn| {
+            conn.save_usage_records(&records)?;
+            // the records are saved at this point, failing to prune mustn't hand them back
+            match prune_usage_records(&conn, secs_since_unix_epoch()) {
+                Ok(0) => {}
+                Ok(pruned) => info!("Pruned {} old usage records", pruned),
+                Err(e) => warn!("Failed to prune usage records {:?}", e),
+            }
+            Ok(())
+        })
+        .then(move |res| {
+            if let Err(e) = res {
+                warn!("Failed to save {} usage records {:?}", unsaved.len(), e);
+                TrafficWatcher::from_registry().do_send(ReturnUsage(unsaved));
+            }
+            Ok(())
+        })
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ClientUsageTotal {
+    pub mesh_ip: String,
+    pub wg_pubkey: String,
+    pub upload: i64,
+    pub download: i64,
+}
+
+/// Totals per client, a client's most recent wg key is the one reported
+pub fn sum_usage(records: &[models::UsageRecord]) -> Vec<ClientUsageTotal> {
+    let mut totals: HashMap<&str, (i64, ClientUsageTotal)> = HashMap::new();
+    for record in records {
+        let (last_hour, total) = totals.entry(record.mesh_ip.as_str()).or_insert_with(|| {
+            (
+                record.hour,
+                ClientUsageTotal {
+                    mesh_ip: record.mesh_ip.clone(),
+                    wg_pubkey: record.wg_pubkey.clone(),
+                    upload: 0,
+                    download: 0,
+                },
+            )
+        });
+        if record.hour > *last_hour {
+            *last_hour = record.hour;
+            total.wg_pubkey = record.wg_pubkey.clone();
+        }
+        total.upload += record.upload;
+        total.download += record.download;
+    }
+    let mut totals: Vec<ClientUsageTotal> = totals.into_iter().map(|(_, (_, t))| t).collect();
+    totals.sort_by(|a, b| a.mesh_ip.cmp(&b.mesh_ip));
+    totals
+}
+
+/// Every record for a client with an hour in [start, end)
+pub fn client_usage_records(
+    client: &str,
+    start: i64,
+    end: i64,
+    conn: &dyn ExitStore,
+) -> Result<Vec<models::UsageRecord>, Error> {
+    conn.load_usage_records(Some(client), start, end)
+}
+
+/// Usage totals for every client with an hour in [start, end)
+pub fn usage_totals(
+    start: i64,
+    end: i64,
+    conn: &dyn ExitStore,
+) -> Result<Vec<ClientUsageTotal>, Error> {
+    let records = conn.load_usage_records(None, start, end)?;
+    Ok(sum_usage(&records))
+}
+
+#[test]
+fn test_usage_buffer() {
+    use crate::rita_common::utils::test_identity;
+
+    let client = test_identity("fd00::1");
+    let start = Instant::now();
+    let mut buffer = UsageBuffer::default();
+    assert!(buffer.take_if_due(start).is_none());
+
+    buffer.add(&client, 7200, 100, 0);
+    buffer.add(&client, 7300, 0, 50);
+    // the next hour gets its own record
+    buffer.add(&client, 10800, 1, 1);
+    buffer.add(&client, 10801, 0, 0);
+    assert!(buffer.take_if_due(start).is_none());
+
+    let mut records = buffer.take_if_due(start + USAGE_FLUSH_FREQUENCY).unwrap();
+    records.sort_by_key(|r| r.hour);
+    assert_eq!(records.len(), 2);
+    assert_eq!(
+        (records[0].hour, records[0].upload, records[0].download),
+        (7200, 100, 50)
+    );
+    assert_eq!(records[1].hour, 10800);
+
+    buffer.restore(records.clone());
+    buffer.add(&client, 7200, 1, 0);
+    let totals = sum_usage(&buffer.pending.values().cloned().collect::<Vec<_>>());
+    assert_eq!(totals.len(), 1);
+    assert_eq!((totals[0].upload, totals[0].download), (102, 51));
+}
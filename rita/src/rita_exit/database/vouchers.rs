@@ -0,0 +1,203 @@
+//! Prepaid vouchers, communities sell these so that subscribers can pay for service without
+//! buying crypto themselves. The operator loads codes into the vouchers table, a client presents
+//! one in its setup request and once it's registered the voucher's amount is credited to it in
+//! DebtKeeper as if it had paid that much. Redemption is idempotent, the same client presenting
+//! the same voucher again is not credited twice.
+//!
+//! Codes are bearer tokens, so they have to be long enough that guessing one isn't practical and
+//! each client only gets a few tries per window. They're never logged.
+
+use crate::rita_common::debt_keeper::{DebtKeeper, PaymentReceived};
+use crate::rita_exit::database::database_tools::get_database_connection;
+use crate::rita_exit::database::secs_since_unix_epoch;
+use crate::rita_exit::database::store::ExitStore;
+use crate::SETTING;
+use actix::SystemService;
+use althea_types::{ExitState, Identity};
+use exit_db::models;
+use failure::Error;
+use futures01::future;
+use futures01::Future;
+use num256::Uint256;
+use settings::exit::RitaExitSettings;
+
+/// Shorter codes are refused outright, a code made of this many random letters and digits can't
+/// be guessed at a few tries an hour
+pub const MIN_VOUCHER_CODE_LEN: usize = 16;
+/// Redemption attempts a client gets per window, counted whether they succeed or not
+const VOUCHER_ATTEMPTS_PER_WINDOW: i64 = 5;
+/// The window voucher attempts are counted in when signup rate limits aren't set, otherwise
+/// theirs is used so that both prune the attempt counts the same way
+const DEFAULT_VOUCHER_WINDOW: u64 = 60 * 60;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Redemption {
+    /// newly redeemed by this client for this amount
+    Credited(Uint256),
+    /// this client already redeemed it
+    AlreadyCredited,
+    /// why the voucher can't be used
+    Rejected(String),
+}
+
+/// What presenting this voucher gets a client, `voucher` is None if the code doesn't exist
+fn check_voucher(voucher: Option<&models::Voucher>, wg_pubkey: &str) -> Redemption {
+    let voucher = match voucher {
+        Some(voucher) => voucher,
+        None => return Redemption::Rejected("Unknown voucher code".to_string()),
+    };
+    if voucher.redeemed_by == wg_pubkey {
+        return Redemption::AlreadyCredited;
+    } else if !voucher.redeemed_by.is_empty() {
+        return Redemption::Rejected("This voucher has already been used".to_string());
+    }
+    match voucher.amount.parse() {
+        Ok(amount) => Redemption::Credited(amount),
+        Err(_) => {
+            error!("Voucher with amount {} is not valid!", voucher.amount);
+            Redemption::Rejected("This voucher is not valid".to_string())
+        }
+    }
+}
+
+/// Counts a redemption attempt by this client, true if it's over the limit for this window
+fn count_voucher_attempt(
+    conn: &dyn ExitStore,
+    wg_pubkey: &str,
+    window: u64,
+    now: i64,
+) -> Result<bool, Error> {
+    let window = window.max(1) as i64;
+    let window_start = now - now % window;
+    conn.prune_signup_attempts(window_start)?;
+    let attempts = conn.add_signup_attempt(&format!("voucher/{}", wg_pubkey), window_start)?;
+    Ok(attempts > VOUCHER_ATTEMPTS_PER_WINDOW)
+}
+
+/// Marks the voucher as used by this client and credits them for it. The voucher is locked for
+/// the duration so two clients can't both redeem it, and it's only marked as used once
+/// DebtKeeper has applied the credit, if that fails the voucher is left as it was
+pub fn redeem_voucher(
+    voucher_code: &str,
+    client: Identity,
+    conn: &dyn ExitStore,
+) -> Result<Redemption, Error> {
+    let wg_pubkey = client.wg_public_key.to_string();
+
+    let window = match SETTING.get_exit_network().signup_rate_limits {
+        Some(ref limits) => limits.window,
+        None => DEFAULT_VOUCHER_WINDOW,
+    };
+    if count_voucher_attempt(conn, &wg_pubkey, window, secs_since_unix_epoch())? {
+        warn!("Rate limiting voucher redemption by {}", wg_pubkey);
+        return Ok(Redemption::Rejected(
+            "Too many voucher attempts, please try again later".to_string(),
+        ));
+    }
+    if voucher_code.len() < MIN_VOUCHER_CODE_LEN {
+        info!("Rejected a voucher from {}: too short", wg_pubkey);
+        return Ok(Redemption::Rejected("Unknown voucher code".to_string()));
+    }
+
+    let mut redemption = Redemption::Rejected("Unknown voucher code".to_string());
+    conn.update_voucher(voucher_code, &mut |voucher| {
+        redemption = check_voucher(voucher.as_ref().map(|v| &**v), &wg_pubkey);
+        if let (Redemption::Credited(amount), Some(voucher)) = (&redemption, voucher) {
+            // DebtKeeper lives on another arbiter, waiting on it here holds the voucher until
+            // the credit is applied
+            DebtKeeper::from_registry()
+                .send(PaymentReceived {
+                    from: client,
+                    amount: amount.clone(),
+                })
+                .wait()??;
+            voucher.redeemed_by = wg_pubkey.clone();
+            voucher.redeemed_time = secs_since_unix_epoch();
+        }
+        Ok(())
+    })?;
+
+    match redemption {
+        Redemption::Credited(ref amount) => {
+            info!("Client {} redeemed a voucher for {} wei", wg_pubkey, amount);
+        }
+        Redemption::AlreadyCredited => {}
+        Redemption::Rejected(ref reason) => {
+            info!("Rejected a voucher from {}: {}", wg_pubkey, reason);
+        }
+    }
+    Ok(redemption)
+}
+
+/// Redeems the voucher a client presented in its setup request, but only once it's registered.
+/// A bad voucher doesn't fail the signup, the reason is added to the registration message
+pub fn redeem_signup_voucher(
+    state: ExitState,
+    client: Identity,
+    voucher: Option<String>,
+) -> Box<dyn Future<Item = ExitState, Error = Error>> {
+    let voucher = match (&state, voucher) {
+        (ExitState::Registered { .. }, Some(voucher)) => voucher,
+        _ => return Box::new(future::ok(state)),
+    };
+    Box::new(get_database_connection().and_then(move |conn| {
+        let redemption = redeem_voucher(&voucher, client, &conn)?;
+        Ok(match (state, redemption) {
+            (
+                ExitState::Registered {
+                    our_details,
+                    general_details,
+                    message,
+                },
+                Redemption::Rejected(reason),
+            ) => ExitState::Registered {
+                our_details,
+                general_details,
+                message: format!("{}, voucher not redeemed: {}", message, reason),
+            },
+            (state, _) => state,
+        })
+    }))
+}
+
+#[test]
+fn test_check_voucher() {
+    let voucher = |redeemed_by: &str| models::Voucher {
+        code: "COOP-7QX2M9KD4HWA".to_string(),
+        amount: "1000000000000000000".to_string(),
+        redeemed_by: redeemed_by.to_string(),
+        redeemed_time: 0,
+    };
+
+    assert_eq!(
+        check_voucher(Some(&voucher("")), "key"),
+        Redemption::Credited(1_000_000_000_000_000_000u64.into())
+    );
+    assert_eq!(
+        check_voucher(Some(&voucher("key")), "key"),
+        Redemption::AlreadyCredited
+    );
+    match check_voucher(Some(&voucher("other")), "key") {
+        Redemption::Rejected(_) => {}
+        r => panic!("Voucher used by someone else gave {:?}", r),
+    }
+    match check_voucher(None, "key") {
+        Redemption::Rejected(_) => {}
+        r => panic!("Unknown voucher gave {:?}", r),
+    }
+}
+
+#[test]
+fn test_voucher_attempts() {
+    use crate::rita_exit::database::store::EmbeddedStore;
+
+    let store = EmbeddedStore::temporary().unwrap();
+    let now = 10 * 3600 + 5;
+    for _ in 0..VOUCHER_ATTEMPTS_PER_WINDOW {
+        assert!(!count_voucher_attempt(&store, "key", 3600, now).unwrap());
+    }
+    assert!(count_voucher_attempt(&store, "key", 3600, now).unwrap());
+    // other clients have their own count and the next window starts over
+    assert!(!count_voucher_attempt(&store, "other", 3600, now).unwrap());
+    assert!(!count_voucher_attempt(&store, "key", 3600, now + 3600).unwrap());
+}
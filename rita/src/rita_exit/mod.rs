@@ -1,4 +1,7 @@
+pub mod dashboard;
 pub mod database;
 pub mod network_endpoints;
+pub mod notifications;
+pub mod organizer;
 pub mod rita_loop;
 pub mod traffic_watcher;
@@ -1,6 +1,7 @@
 //! Network endpoints for rita-exit that are not dashboard or local infromational endpoints
 //! these are called by rita instances to operate the mesh
 
+use crate::rita_common::dashboard::error::{DashboardError, ErrorCode};
 use crate::rita_common::debt_keeper::DebtKeeper;
 use crate::rita_common::debt_keeper::GetDebtsList;
 use crate::rita_exit::database::database_tools::get_database_connection;
@@ -8,28 +9,57 @@ use crate::rita_exit::database::database_tools::get_database_connection;
 use crate::rita_exit::database::db_client::DbClient;
 #[cfg(feature = "development")]
 use crate::rita_exit::database::db_client::TruncateTables;
-use crate::rita_exit::database::{client_status, get_exit_info, signup_client};
+use crate::rita_exit::database::db_health::{DbHealth, DbStatus, GetCachedClients, GetDbStatus};
+use crate::rita_exit::database::debt_sync::cluster_debt;
+use crate::rita_exit::database::device_limits::{device_report, set_device_limit, ClientDevices};
+use crate::rita_exit::database::email::{resend_email_code, send_email_step};
+use crate::rita_exit::database::plans::{client_plan, set_client_plan, NewPlan};
+use crate::rita_exit::database::port_policy::validate_port_blocks;
+use crate::rita_exit::database::retention::{plan_retention, PlannedRetention};
+use crate::rita_exit::database::static_ips::{
+    assign_static_ip, release_static_ip, static_ip_assignments, StaticIpAssignment,
+};
+use crate::rita_exit::database::usage_records::{
+    client_usage_records, usage_totals, ClientUsageTotal,
+};
+use crate::rita_exit::database::vouchers::redeem_signup_voucher;
+use crate::rita_exit::database::{
+    cached_client_status, client_status, get_exit_info, nat64_details, secs_since_unix_epoch,
+    signup_client,
+};
+use crate::ARGS;
 use crate::EXIT_WG_PRIVATE_KEY;
-use ::actix_web::{AsyncResponder, HttpRequest, HttpResponse, Json, Result};
+use crate::KI;
+use crate::SETTING;
+use ::actix_web::http::ContentEncoding;
+use ::actix_web::{AsyncResponder, HttpRequest, HttpResponse, Json, Path, Query, Result};
 #[cfg(feature = "development")]
 use actix::SystemService;
 use actix::SystemService;
 #[cfg(feature = "development")]
 use actix_web::AsyncResponder;
 use althea_types::Identity;
+use althea_types::Wei;
 use althea_types::WgKey;
 use althea_types::{
-    EncryptedExitClientIdentity, EncryptedExitState, ExitClientIdentity, ExitState,
+    Announcement, EncryptedExitClientIdentity, EncryptedExitState, ExitClientIdentity, ExitPlan,
+    ExitState, Nat64Details, PortBlock,
 };
+use exit_db::models::UsageRecord;
 use failure::Error;
 use futures01::future;
 use futures01::Future;
+use ipnetwork::IpNetwork;
 use num256::Int256;
+use settings::exit::{Nat64Settings, RitaExitSettings};
+use settings::FileWrite;
 use sodiumoxide::crypto::box_;
 use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::Nonce;
 use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::PublicKey;
 use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::SecretKey;
-use std::net::SocketAddr;
+use sodiumoxide::randombytes::randombytes;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
 /// helper function for returning from secure_setup_request()
 fn secure_setup_return(
@@ -160,10 +190,14 @@ pub fn secure_setup_request(
 
     let client_mesh_ip = decrypted_id.global.mesh_ip;
     let client = decrypted_id;
+    let client_identity = client.global;
+    let voucher = client.voucher.clone();
 
     let remote_mesh_ip = remote_mesh_socket.ip();
     if remote_mesh_ip == client_mesh_ip {
-        Box::new(signup_client(client).then(move |result| match result {
+        let signup = signup_client(client)
+            .and_then(move |state| redeem_signup_voucher(state, client_identity, voucher));
+        Box::new(signup.then(move |result| match result {
             Ok(exit_state) => Ok(secure_setup_return(
                 exit_state,
                 &our_secretkey,
@@ -202,33 +236,432 @@ pub fn secure_status_request(
     };
     trace!("got status request from {}", their_wg_pubkey);
 
-    Box::new(get_database_connection().and_then(move |conn| {
-        let state = match client_status(decrypted_id, &conn) {
-            Ok(state) => state,
-            Err(e) => {
-                error!(
-                    "Internal error in client status for {} with {:?}",
-                    their_wg_pubkey, e
-                );
-                return Err(format_err!("There was an internal error!"));
-            }
-        };
-        Ok(secure_setup_return(
-            state,
-            &our_secretkey,
-            their_nacl_pubkey,
-        ))
+    Box::new(
+        get_database_connection()
+            .then(move |conn| match conn {
+                Ok(conn) => Box::new(future::result(client_status(decrypted_id, &conn)))
+                    as Box<dyn Future<Item = ExitState, Error = Error>>,
+                Err(e) => Box::new(degraded_client_status(decrypted_id, e)),
+            })
+            .then(move |state| match state {
+                Ok(state) => Ok(secure_setup_return(
+                    state,
+                    &our_secretkey,
+                    their_nacl_pubkey,
+                )),
+                Err(e) => {
+                    error!(
+                        "Internal error in client status for {} with {:?}",
+                        their_wg_pubkey, e
+                    );
+                    Err(format_err!("There was an internal error!"))
+                }
+            }),
+    )
+}
+
+/// Sends the client their email code again, there's no cached fallback like for the status
+/// since a new code has to be written down
+pub fn secure_resend_email_request(
+    request: Json<EncryptedExitClientIdentity>,
+) -> Box<dyn Future<Item = Json<EncryptedExitState>, Error = Error>> {
+    let our_secretkey: WgKey = *EXIT_WG_PRIVATE_KEY;
+    let our_secretkey = our_secretkey.into();
+
+    let their_wg_pubkey = request.pubkey;
+    let their_nacl_pubkey = request.pubkey.into();
+    let decrypted_id = match decrypt_exit_client_id(request.into_inner(), &our_secretkey) {
+        DecryptResult::Success(val) => val,
+        DecryptResult::Failure(val) => {
+            return val;
+        }
+    };
+    trace!("got email resend request from {}", their_wg_pubkey);
+
+    let client = decrypted_id.clone();
+    Box::new(
+        get_database_connection()
+            .and_then(move |conn| resend_email_code(&decrypted_id, &conn))
+            .and_then(move |step| send_email_step(client, step))
+            .then(move |state| match state {
+                Ok(state) => Ok(secure_setup_return(
+                    state,
+                    &our_secretkey,
+                    their_nacl_pubkey,
+                )),
+                Err(e) => {
+                    error!(
+                        "Internal error resending the email code for {} with {:?}",
+                        their_wg_pubkey, e
+                    );
+                    Err(format_err!("There was an internal error!"))
+                }
+            }),
+    )
+}
+
+/// If the database is down, registered clients get their status from the cached client list
+/// so that they keep using the exit until it's back
+fn degraded_client_status(
+    client: ExitClientIdentity,
+    db_error: Error,
+) -> impl Future<Item = ExitState, Error = Error> {
+    DbHealth::from_registry()
+        .send(GetCachedClients)
+        .from_err()
+        .and_then(move |cached| match cached? {
+            Some(clients_list) => match cached_client_status(&client, &clients_list) {
+                Some(state) => Ok(state),
+                None => Err(db_error),
+            },
+            None => Err(db_error),
+        })
+}
+
+/// Reports if the exit can reach its database, and if not how long it has been down
+pub fn get_db_status(_req: HttpRequest) -> Box<dyn Future<Item = Json<DbStatus>, Error = Error>> {
+    DbHealth::from_registry()
+        .send(GetDbStatus)
+        .from_err()
+        .and_then(|status| Ok(Json(status?)))
+        .responder()
+}
+
+#[derive(Serialize, Debug)]
+pub struct Nat64Status {
+    /// the configured settings, none if NAT64 is disabled
+    pub configured: Option<Nat64Settings>,
+    /// the prefix the translator is actually running with
+    pub active_prefix: Option<IpNetwork>,
+    /// what we tell clients, none unless we can give them ipv6 addresses
+    pub advertised: Option<Nat64Details>,
+}
+
+/// Reports how NAT64 for clients with ipv6 only LANs is configured and if it's running
+pub fn get_nat64_status(_req: HttpRequest) -> Result<Json<Nat64Status>, Error> {
+    Ok(Json(Nat64Status {
+        configured: SETTING.get_exit_network().nat64.clone(),
+        active_prefix: KI.get_nat64_prefix()?,
+        advertised: nat64_details(),
+    }))
+}
+
+/// A dry run of the client retention policy, lists the clients that would be archived or
+/// deleted by the next exit loop without touching them
+pub fn get_retention_plan(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<Vec<PlannedRetention>>, Error = Error>> {
+    get_database_connection()
+        .and_then(|conn| {
+            let clients_list = conn.load_clients()?;
+            Ok(Json(plan_retention(&clients_list)?))
+        })
+        .responder()
+}
+
+/// A range of hours for usage queries, in seconds since the unix epoch. Hours starting at or
+/// after `start` and before `end` are included, either can be left out to leave that side open
+#[derive(Deserialize, Debug)]
+pub struct UsageRange {
+    #[serde(default)]
+    start: i64,
+    #[serde(default = "open_range_end")]
+    end: i64,
+}
+
+fn open_range_end() -> i64 {
+    i64::max_value()
+}
+
+/// Usage totals for every client over a range of hours
+pub fn get_usage_totals(
+    range: Query<UsageRange>,
+) -> Box<dyn Future<Item = Json<Vec<ClientUsageTotal>>, Error = Error>> {
+    get_database_connection()
+        .and_then(move |conn| Ok(Json(usage_totals(range.start, range.end, &conn)?)))
+        .responder()
+}
+
+/// The hourly usage records for the client with the given mesh ip over a range of hours
+pub fn get_client_usage_records(
+    req: (Path<String>, Query<UsageRange>),
+) -> Box<dyn Future<Item = Json<Vec<UsageRecord>>, Error = Error>> {
+    let (client, range) = req;
+    get_database_connection()
+        .and_then(move |conn| {
+            Ok(Json(client_usage_records(
+                &client,
+                range.start,
+                range.end,
+                &conn,
+            )?))
+        })
+        .responder()
+}
+
+/// The static ip pool and which client has each address
+pub fn get_static_ips(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<Vec<StaticIpAssignment>>, Error = Error>> {
+    get_database_connection()
+        .and_then(|conn| {
+            let pool = SETTING.get_exit_network().static_ip_pool.clone();
+            Ok(Json(static_ip_assignments(&pool, &conn.load_clients()?)))
+        })
+        .responder()
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StaticIpQuery {
+    /// a specific address from the pool, otherwise the first free one is used
+    ip: Option<Ipv4Addr>,
+}
+
+/// Gives the client with the given mesh ip a static public ipv4 address
+pub fn set_client_static_ip(
+    req: (Path<String>, Query<StaticIpQuery>),
+) -> Box<dyn Future<Item = Json<StaticIpAssignment>, Error = Error>> {
+    let (mesh_ip, query) = req;
+    get_database_connection()
+        .and_then(move |conn| {
+            let pool = SETTING.get_exit_network().static_ip_pool.clone();
+            let ip = assign_static_ip(&mesh_ip, query.ip, &pool, &conn)?;
+            Ok(Json(StaticIpAssignment {
+                ip,
+                mesh_ip: Some(mesh_ip.into_inner()),
+                in_pool: true,
+            }))
+        })
+        .responder()
+}
+
+/// Takes the static public ipv4 address of the client with the given mesh ip back
+pub fn remove_client_static_ip(
+    mesh_ip: Path<String>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    get_database_connection()
+        .and_then(move |conn| {
+            release_static_ip(&mesh_ip, &conn)?;
+            Ok(HttpResponse::Ok().json(()))
+        })
+        .responder()
+}
+
+/// How many devices each client has online and their limits
+pub fn get_client_devices(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = Json<Vec<ClientDevices>>, Error = Error>> {
+    get_database_connection()
+        .and_then(|conn| Ok(Json(device_report(&conn.load_clients()?))))
+        .responder()
+}
+
+/// Sets how many devices the client with the given mesh ip may have online, 0 for the default
+pub fn set_client_device_limit(
+    path: Path<(String, u32)>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let (mesh_ip, limit) = path.into_inner();
+    get_database_connection()
+        .and_then(move |conn| {
+            set_device_limit(&mesh_ip, limit, &conn)?;
+            Ok(HttpResponse::Ok().json(()))
+        })
+        .responder()
+}
+
+/// The plan of the client with the given mesh ip and how much of it they've used, null if they
+/// aren't on one
+pub fn get_client_plan(
+    mesh_ip: Path<String>,
+) -> Box<dyn Future<Item = Json<Option<ExitPlan>>, Error = Error>> {
+    get_database_connection()
+        .and_then(move |conn| match conn.get_client(&mesh_ip)? {
+            Some(client) => Ok(Json(client_plan(&client))),
+            None => Err(DashboardError::new(
+                ErrorCode::NotFound,
+                format!("No client with mesh ip {}", mesh_ip),
+            )
+            .into()),
+        })
+        .responder()
+}
+
+/// Puts the client with the given mesh ip on a plan
+pub fn set_client_plan_endpoint(
+    req: (Path<String>, Json<NewPlan>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let (mesh_ip, plan) = req;
+    get_database_connection()
+        .and_then(move |conn| {
+            set_client_plan(&mesh_ip, Some(plan.into_inner()), &conn)?;
+            Ok(HttpResponse::Ok().json(()))
+        })
+        .responder()
+}
+
+/// Takes the client with the given mesh ip off its plan
+pub fn remove_client_plan(
+    mesh_ip: Path<String>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    get_database_connection()
+        .and_then(move |conn| {
+            set_client_plan(&mesh_ip, None, &conn)?;
+            Ok(HttpResponse::Ok().json(()))
+        })
+        .responder()
+}
+
+/// Our announcements, including expired ones that are no longer sent to clients
+pub fn get_exit_announcements(_req: HttpRequest) -> Result<Json<Vec<Announcement>>, Error> {
+    Ok(Json(SETTING.get_exit_network().announcements.clone()))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewAnnouncement {
+    message: String,
+    /// seconds since the unix epoch, None to show it until it's deleted
+    #[serde(default)]
+    expires: Option<u64>,
+}
+
+/// Posts an announcement to our clients, they get it with their next status update
+pub fn post_exit_announcement(new: Json<NewAnnouncement>) -> Result<Json<Announcement>, Error> {
+    let new = new.into_inner();
+    if new.message.trim().is_empty() {
+        return Err(DashboardError::invalid_input("Announcements can't be empty").into());
+    }
+    let mut exit_network = SETTING.get_exit_network_mut();
+    // settings from before the counter was kept start it after the announcements they have
+    let id = exit_network
+        .announcements
+        .iter()
+        .map(|announcement| announcement.id + 1)
+        .fold(exit_network.next_announcement_id.max(1), u64::max);
+    exit_network.next_announcement_id = id + 1;
+    let announcement = Announcement {
+        id,
+        posted: secs_since_unix_epoch() as u64,
+        expires: new.expires,
+        message: new.message,
+    };
+    info!("Posting announcement {:?}", announcement);
+    exit_network.announcements.push(announcement.clone());
+    drop(exit_network);
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+    Ok(Json(announcement))
+}
+
+/// Stops sending an announcement, clients that already have it keep it until it expires
+pub fn delete_exit_announcement(id: Path<u64>) -> Result<HttpResponse, Error> {
+    let id = id.into_inner();
+    let mut exit_network = SETTING.get_exit_network_mut();
+    let before = exit_network.announcements.len();
+    exit_network
+        .announcements
+        .retain(|announcement| announcement.id != id);
+    if exit_network.announcements.len() == before {
+        return Err(DashboardError::new(
+            ErrorCode::NotFound,
+            format!("No announcement with id {}", id),
+        )
+        .into());
+    }
+    drop(exit_network);
+
+    // try and save the config and fail if we can't
+    if let Err(e) = SETTING.write().unwrap().write(&ARGS.flag_config) {
+        return Err(e);
+    }
+    Ok(HttpResponse::Ok().json(()))
+}
+
+#[derive(Serialize, Debug)]
+pub struct PortPolicyStatus {
+    port_blocks: Vec<PortBlock>,
+    client_port_blocks: HashMap<String, Vec<PortBlock>>,
+}
+
+/// The ports we block for everyone and for particular clients
+pub fn get_port_policy(_req: HttpRequest) -> Result<Json<PortPolicyStatus>, Error> {
+    let exit_network = SETTING.get_exit_network();
+    Ok(Json(PortPolicyStatus {
+        port_blocks: exit_network.port_blocks.clone(),
+        client_port_blocks: exit_network.client_port_blocks.clone(),
     }))
 }
 
+/// Replaces the ports blocked for everyone, clients are told with their next status update
+pub fn set_port_policy(blocks: Json<Vec<PortBlock>>) -> Result<HttpResponse, Error> {
+    let blocks = blocks.into_inner();
+    if let Err(e) = validate_port_blocks(&blocks) {
+        return Err(DashboardError::invalid_input(e.to_string()).into());
+    }
+    info!("Blocking ports {:?} for all clients", blocks);
+    SETTING.get_exit_network_mut().port_blocks = blocks;
+    Ok(HttpResponse::Ok().json(()))
+}
+
+/// Replaces the ports blocked for the client with the given mesh ip, an empty list removes them
+pub fn set_client_port_policy(
+    req: (Path<String>, Json<Vec<PortBlock>>),
+) -> Result<HttpResponse, Error> {
+    let (mesh_ip, blocks) = req;
+    let blocks = blocks.into_inner();
+    let mesh_ip: IpAddr = match mesh_ip.parse() {
+        Ok(ip) => ip,
+        Err(_) => {
+            return Err(
+                DashboardError::invalid_input(format!("Invalid mesh ip {}", mesh_ip)).into(),
+            )
+        }
+    };
+    if let Err(e) = validate_port_blocks(&blocks) {
+        return Err(DashboardError::invalid_input(e.to_string()).into());
+    }
+    info!("Blocking ports {:?} for {}", blocks, mesh_ip);
+    let mut exit_network = SETTING.get_exit_network_mut();
+    // however else the address was written before
+    exit_network
+        .client_port_blocks
+        .retain(|ip, _| ip.parse::<IpAddr>().ok() != Some(mesh_ip));
+    if !blocks.is_empty() {
+        exit_network
+            .client_port_blocks
+            .insert(mesh_ip.to_string(), blocks);
+    }
+    Ok(HttpResponse::Ok().json(()))
+}
+
 pub fn get_exit_info_http(_req: HttpRequest) -> Result<Json<ExitState>, Error> {
     Ok(Json(ExitState::GotInfo {
         general_details: get_exit_info(),
         message: "Got info successfully".to_string(),
         auto_register: false,
+        retry_after: None,
     }))
 }
 
+/// Size of the body served by /throughput_sample, small enough that probing every exit every
+/// few minutes costs next to nothing but large enough to take a few round trips
+pub const THROUGHPUT_SAMPLE_BYTES: usize = 64 * 1024;
+
+lazy_static! {
+    /// Random so that nothing along the way can compress it
+    static ref THROUGHPUT_SAMPLE: Vec<u8> = randombytes(THROUGHPUT_SAMPLE_BYTES);
+}
+
+/// A fixed size blob clients download and time to estimate the throughput to this exit
+pub fn throughput_sample(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .content_encoding(ContentEncoding::Identity)
+        .body(THROUGHPUT_SAMPLE.as_slice())
+}
+
 /// Used by clients to get their debt from the exits. While it is in theory possible for the
 /// client to totally compute their own bill it's not possible for the exit and the client
 /// to agree on the billed amount in the presence of packet loss. Normally Althea is pay per forward
@@ -244,14 +677,18 @@ pub fn get_client_debt(
         .from_err()
         .and_then(move |reply| match reply {
             Ok(debts) => {
-                for debt in debts {
-                    if debt.identity == client {
-                        return Ok(
-                            HttpResponse::Ok().json(debt.payment_details.debt * Int256::from(-1))
-                        );
-                    }
-                }
-                Ok(HttpResponse::NotFound().json("No client by that ID"))
+                let local = debts
+                    .into_iter()
+                    .find(|debt| debt.identity == client)
+                    .map(|debt| Wei::from(debt.payment_details.debt));
+                // in a cluster the client's debts with the other instances count too
+                let debt = match cluster_debt(&client.mesh_ip, local) {
+                    Some(debt) => debt,
+                    None => return Ok(HttpResponse::NotFound().json("No client by that ID")),
+                };
+                // the client wants to know what it owes us, not what we're owed
+                let owed: Int256 = Wei::zero().saturating_sub(&debt).into();
+                Ok(HttpResponse::Ok().json(owed))
             }
             Err(e) => {
                 error!("Failed to contact debt keeper {:?}", e);
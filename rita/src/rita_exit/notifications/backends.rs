@@ -0,0 +1,319 @@
+//! The services notifications can be sent through
+
+use super::{Notification, NotificationBackend, Recipient};
+use actix::{Actor, Addr, Handler, Message, SyncContext};
+use actix_web::client;
+use failure::Error;
+use futures01::{future, Future};
+use lettre::file::FileTransport;
+use lettre::smtp::authentication::{Credentials, Mechanism};
+use lettre::smtp::extension::ClientId;
+use lettre::smtp::ConnectionReuseParameters;
+use lettre::{SmtpClient, Transport};
+use lettre_email::{Email, EmailBuilder};
+use phonenumber::PhoneNumber;
+use std::time::Duration;
+
+/// How long an HTTP backend gets to accept a message
+const HTTP_BACKEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn email_address(notification: &Notification) -> Result<&str, Error> {
+    match &notification.to {
+        Recipient::Email(address) => Ok(address),
+        Recipient::Phone(_) => bail!("Can't email a phone number"),
+    }
+}
+
+fn build_email(notification: &Notification, from: &str) -> Result<Email, Error> {
+    Ok(EmailBuilder::new()
+        .to(email_address(notification)?)
+        .from(from)
+        .subject(notification.subject.clone())
+        .text(notification.body.clone())
+        .build()?)
+}
+
+/// A backend that blocks until the message is sent, SMTP and writing files, these are run on the
+/// BlockingSender's threads rather than holding up the Notifier and everything else on its arbiter
+pub trait BlockingBackend: Send {
+    fn name(&self) -> &'static str;
+    fn send_blocking(&self, notification: &Notification) -> Result<(), Error>;
+}
+
+pub struct BlockingSender;
+
+impl Actor for BlockingSender {
+    type Context = SyncContext<Self>;
+}
+
+pub struct SendBlocking {
+    pub backend: Box<dyn BlockingBackend>,
+    pub notification: Notification,
+}
+
+impl Message for SendBlocking {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<SendBlocking> for BlockingSender {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: SendBlocking, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        msg.backend.send_blocking(&msg.notification)
+    }
+}
+
+/// Sends through a blocking backend on the BlockingSender
+pub struct OnSender<B> {
+    pub backend: B,
+    pub sender: Addr<BlockingSender>,
+}
+
+impl<B: BlockingBackend + Clone + 'static> NotificationBackend for OnSender<B> {
+    fn name(&self) -> &'static str {
+        self.backend.name()
+    }
+
+    fn send(&self, notification: &Notification) -> Box<dyn Future<Item = (), Error = Error>> {
+        Box::new(
+            self.sender
+                .send(SendBlocking {
+                    backend: Box::new(self.backend.clone()),
+                    notification: notification.clone(),
+                })
+                .from_err()
+                .and_then(|res| res),
+        )
+    }
+}
+
+#[derive(Clone)]
+pub struct SmtpBackend {
+    pub url: String,
+    pub domain: String,
+    pub username: String,
+    pub password: String,
+    pub from_address: String,
+}
+
+impl BlockingBackend for SmtpBackend {
+    fn name(&self) -> &'static str {
+        "smtp"
+    }
+
+    fn send_blocking(&self, notification: &Notification) -> Result<(), Error> {
+        let email = build_email(notification, &self.from_address)?;
+        // TODO add serde to lettre
+        let mut mailer = SmtpClient::new_simple(&self.url)?
+            .hello_name(ClientId::Domain(self.domain.clone()))
+            .credentials(Credentials::new(
+                self.username.clone(),
+                self.password.clone(),
+            ))
+            .smtp_utf8(true)
+            .authentication_mechanism(Mechanism::Plain)
+            .connection_reuse(ConnectionReuseParameters::ReuseUnlimited)
+            .transport();
+        mailer.send(email.into())?;
+        Ok(())
+    }
+}
+
+/// Writes emails to a directory instead of sending them, for testing
+#[derive(Clone)]
+pub struct FileBackend {
+    pub dir: String,
+    pub from_address: String,
+}
+
+impl BlockingBackend for FileBackend {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn send_blocking(&self, notification: &Notification) -> Result<(), Error> {
+        let email = build_email(notification, &self.from_address)?;
+        FileTransport::new(&self.dir).send(email.into())?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct HttpApiAddress {
+    email: String,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct HttpApiPersonalization {
+    to: Vec<HttpApiAddress>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct HttpApiContent {
+    #[serde(rename = "type")]
+    content_type: String,
+    value: String,
+}
+
+/// The body of a SendGrid v3 mail send request
+#[derive(Serialize, Debug, PartialEq)]
+struct HttpApiMail {
+    personalizations: Vec<HttpApiPersonalization>,
+    from: HttpApiAddress,
+    subject: String,
+    content: Vec<HttpApiContent>,
+}
+
+fn http_api_mail(notification: &Notification, from: &str) -> Result<HttpApiMail, Error> {
+    Ok(HttpApiMail {
+        personalizations: vec![HttpApiPersonalization {
+            to: vec![HttpApiAddress {
+                email: email_address(notification)?.to_string(),
+            }],
+        }],
+        from: HttpApiAddress {
+            email: from.to_string(),
+        },
+        subject: notification.subject.clone(),
+        content: vec![HttpApiContent {
+            content_type: "text/plain".to_string(),
+            value: notification.body.clone(),
+        }],
+    })
+}
+
+pub struct HttpApiBackend {
+    pub url: String,
+    pub api_key: String,
+    pub from_address: String,
+}
+
+impl NotificationBackend for HttpApiBackend {
+    fn name(&self) -> &'static str {
+        "http api"
+    }
+
+    fn send(&self, notification: &Notification) -> Box<dyn Future<Item = (), Error = Error>> {
+        let mail = match http_api_mail(notification, &self.from_address) {
+            Ok(mail) => mail,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let request = match client::post(&self.url)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .json(mail)
+        {
+            Ok(request) => request,
+            Err(e) => return Box::new(future::err(format_err!("{:?}", e))),
+        };
+        Box::new(
+            request
+                .send()
+                .timeout(HTTP_BACKEND_TIMEOUT)
+                .from_err()
+                .and_then(|response| {
+                    if response.status().is_success() {
+                        Ok(())
+                    } else {
+                        bail!("Email API responded with {}", response.status())
+                    }
+                }),
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct SmsNotification {
+    #[serde(rename = "To")]
+    to: String,
+    #[serde(rename = "From")]
+    from: String,
+    #[serde(rename = "Body")]
+    body: String,
+}
+
+pub struct SmsGatewayBackend {
+    pub url: String,
+    pub account_id: String,
+    pub auth_token: String,
+    pub from_number: String,
+}
+
+impl SmsGatewayBackend {
+    /// The gateway for a Twilio account
+    pub fn twilio(account_id: String, auth_token: String, from_number: String) -> Self {
+        SmsGatewayBackend {
+            url: format!(
+                "https://api.twilio.com/2010-04-01/Accounts/{}/Messages.json",
+                account_id
+            ),
+            account_id,
+            auth_token,
+            from_number,
+        }
+    }
+}
+
+impl NotificationBackend for SmsGatewayBackend {
+    fn name(&self) -> &'static str {
+        "sms gateway"
+    }
+
+    fn send(&self, notification: &Notification) -> Box<dyn Future<Item = (), Error = Error>> {
+        let number: PhoneNumber = match &notification.to {
+            Recipient::Phone(number) => match number.parse() {
+                Ok(number) => number,
+                Err(e) => return Box::new(future::err(e.into())),
+            },
+            Recipient::Email(_) => {
+                return Box::new(future::err(format_err!("Can't text an email")))
+            }
+        };
+        let request = match client::post(&self.url)
+            .basic_auth(self.account_id.clone(), Some(self.auth_token.clone()))
+            .form(&SmsNotification {
+                to: number.to_string(),
+                from: self.from_number.clone(),
+                body: notification.body.clone(),
+            }) {
+            Ok(request) => request,
+            Err(e) => return Box::new(future::err(format_err!("{:?}", e))),
+        };
+        Box::new(
+            request
+                .send()
+                .timeout(HTTP_BACKEND_TIMEOUT)
+                .from_err()
+                .and_then(|response| {
+                    if response.status().is_success() {
+                        Ok(())
+                    } else {
+                        bail!("SMS gateway responded with {}", response.status())
+                    }
+                }),
+        )
+    }
+}
+
+#[test]
+fn test_http_api_mail() {
+    let notification = Notification {
+        to: Recipient::Email("client@example.com".to_string()),
+        subject: "Verify".to_string(),
+        body: "Your code is 123456".to_string(),
+    };
+    let mail = http_api_mail(&notification, "exit@example.com").unwrap();
+    assert_eq!(
+        serde_json::to_value(&mail).unwrap(),
+        json!({
+            "personalizations": [{"to": [{"email": "client@example.com"}]}],
+            "from": {"email": "exit@example.com"},
+            "subject": "Verify",
+            "content": [{"type": "text/plain", "value": "Your code is 123456"}],
+        })
+    );
+    let text = Notification {
+        to: Recipient::Phone("+15555555555".to_string()),
+        ..notification
+    };
+    assert!(http_api_mail(&text, "exit@example.com").is_err());
+}
@@ -0,0 +1,314 @@
+//! Sends emails and texts to clients: signup verification emails and low balance warnings. Each
+//! channel has a backend chosen in the notification settings (an SMTP server, a SendGrid style
+//! HTTP API or a Twilio style SMS gateway) and a queue of its own that enforces the backend's
+//! rate limit and retries failed messages. Messages are rendered from the handlebars templates in
+//! the verification settings before they are queued. Messages someone is waiting on, like signup
+//! codes, can be delivered right away instead so that a failure gets back to them to retry.
+//!
+//! Phone verification codes are the exception, Authy both sends and checks those so they don't
+//! go through here.
+
+pub mod backends;
+pub mod queue;
+
+use self::backends::{
+    BlockingSender, FileBackend, HttpApiBackend, OnSender, SmsGatewayBackend, SmtpBackend,
+};
+use self::queue::{NotificationQueue, QueuedNotification};
+use crate::SETTING;
+use actix::{
+    Actor, Addr, Arbiter, AsyncContext, Context, Handler, Message, ResponseFuture, Supervised,
+    SyncArbiter, SystemService,
+};
+use failure::Error;
+use futures01::{future, Future};
+use handlebars::Handlebars;
+use serde_json::Value;
+use settings::exit::{ExitVerifSettings, NotificationBackendSettings, RitaExitSettings};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How often queued messages are looked at
+const NOTIFIER_INTERVAL: Duration = Duration::from_secs(5);
+/// Threads for backends that block while sending, so one slow SMTP server doesn't hold up
+/// every message behind it
+const BLOCKING_SENDERS: usize = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Recipient {
+    Email(String),
+    Phone(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    pub to: Recipient,
+    /// unused for texts
+    pub subject: String,
+    pub body: String,
+}
+
+impl fmt::Display for Notification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.to {
+            Recipient::Email(address) => write!(f, "email '{}' to {}", self.subject, address),
+            Recipient::Phone(number) => write!(f, "text to {}", number),
+        }
+    }
+}
+
+pub trait NotificationBackend {
+    /// for the logs
+    fn name(&self) -> &'static str;
+    fn send(&self, notification: &Notification) -> Box<dyn Future<Item = (), Error = Error>>;
+}
+
+/// Renders a message template, the context's fields are available as `{{field}}`
+pub fn render(template: &str, context: &Value) -> Result<String, Error> {
+    Ok(Handlebars::new().render_template(template, context)?)
+}
+
+fn backend_from_settings(
+    settings: NotificationBackendSettings,
+    sender: Addr<BlockingSender>,
+) -> Box<dyn NotificationBackend> {
+    match settings {
+        NotificationBackendSettings::Smtp {
+            url,
+            domain,
+            username,
+            password,
+            from_address,
+        } => Box::new(OnSender {
+            backend: SmtpBackend {
+                url,
+                domain,
+                username,
+                password,
+                from_address,
+            },
+            sender,
+        }),
+        NotificationBackendSettings::HttpApi {
+            url,
+            api_key,
+            from_address,
+        } => Box::new(HttpApiBackend {
+            url,
+            api_key,
+            from_address,
+        }),
+        NotificationBackendSettings::SmsGateway {
+            url,
+            account_id,
+            auth_token,
+            from_number,
+        } => Box::new(SmsGatewayBackend {
+            url,
+            account_id,
+            auth_token,
+            from_number,
+        }),
+    }
+}
+
+/// The configured email backend, falling back to the verification settings' smtp server
+fn email_backend(sender: Addr<BlockingSender>) -> Option<Box<dyn NotificationBackend>> {
+    if let Some(settings) = SETTING.get_notifications().email {
+        return Some(backend_from_settings(settings, sender));
+    }
+    match SETTING.get_verif_settings() {
+        Some(ExitVerifSettings::Email(mailer)) => {
+            if mailer.test {
+                Some(Box::new(OnSender {
+                    backend: FileBackend {
+                        dir: mailer.test_dir,
+                        from_address: mailer.from_address,
+                    },
+                    sender,
+                }))
+            } else {
+                Some(Box::new(OnSender {
+                    backend: SmtpBackend {
+                        url: mailer.smtp_url,
+                        domain: mailer.smtp_domain,
+                        username: mailer.smtp_username,
+                        password: mailer.smtp_password,
+                        from_address: mailer.from_address,
+                    },
+                    sender,
+                }))
+            }
+        }
+        _ => None,
+    }
+}
+
+/// The configured sms backend, falling back to Twilio with the verification settings' account
+fn sms_backend(sender: Addr<BlockingSender>) -> Option<Box<dyn NotificationBackend>> {
+    if let Some(settings) = SETTING.get_notifications().sms {
+        return Some(backend_from_settings(settings, sender));
+    }
+    match SETTING.get_verif_settings() {
+        Some(ExitVerifSettings::Phone(phone)) => Some(Box::new(SmsGatewayBackend::twilio(
+            phone.twillio_account_id,
+            phone.twillio_auth_token,
+            phone.notification_number,
+        ))),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+pub struct Notifier {
+    email: NotificationQueue,
+    sms: NotificationQueue,
+    sender: Option<Addr<BlockingSender>>,
+}
+
+impl Actor for Notifier {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(NOTIFIER_INTERVAL, |act, _ctx| act.send_due());
+    }
+}
+
+impl Supervised for Notifier {}
+impl SystemService for Notifier {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("Notifier started");
+    }
+}
+
+fn send_all(backend: Option<Box<dyn NotificationBackend>>, due: Vec<QueuedNotification>) {
+    let backend = match backend {
+        Some(backend) => backend,
+        None => {
+            error!(
+                "No backend configured, dropping {} notifications",
+                due.len()
+            );
+            return;
+        }
+    };
+    for queued in due {
+        trace!("Sending {} with {}", queued.notification, backend.name());
+        let name = backend.name();
+        Arbiter::spawn(backend.send(&queued.notification).then(move |res| {
+            if let Err(e) = res {
+                warn!(
+                    "Failed to send {} with {} {:?}",
+                    queued.notification, name, e
+                );
+                Notifier::from_registry().do_send(NotificationFailed(queued));
+            }
+            Ok(())
+        }));
+    }
+}
+
+impl Notifier {
+    fn sender(&mut self) -> Addr<BlockingSender> {
+        self.sender
+            .get_or_insert_with(|| SyncArbiter::start(BLOCKING_SENDERS, || BlockingSender))
+            .clone()
+    }
+
+    fn send_due(&mut self) {
+        let settings = SETTING.get_notifications();
+        let now = Instant::now();
+        let email = self.email.take_due(now, settings.email_rate_limit);
+        if !email.is_empty() {
+            send_all(email_backend(self.sender()), email);
+        }
+        let sms = self.sms.take_due(now, settings.sms_rate_limit);
+        if !sms.is_empty() {
+            send_all(sms_backend(self.sender()), sms);
+        }
+    }
+}
+
+/// Queues a message, it's sent right away unless the rate limit is used up
+pub struct Notify(pub Notification);
+
+impl Message for Notify {
+    type Result = ();
+}
+
+impl Handler<Notify> for Notifier {
+    type Result = ();
+
+    fn handle(&mut self, msg: Notify, _ctx: &mut Context<Self>) -> Self::Result {
+        let now = Instant::now();
+        match msg.0.to {
+            Recipient::Email(_) => self.email.push(msg.0, now),
+            Recipient::Phone(_) => self.sms.push(msg.0, now),
+        }
+        self.send_due();
+    }
+}
+
+/// Sends a message right away and reports how that went, for messages someone is waiting on. It's
+/// not queued or retried, if the rate limit is used up or the backend fails it's up to them to ask
+/// again
+pub struct Deliver(pub Notification);
+
+impl Message for Deliver {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<Deliver> for Notifier {
+    type Result = ResponseFuture<(), Error>;
+
+    fn handle(&mut self, msg: Deliver, _ctx: &mut Context<Self>) -> Self::Result {
+        let settings = SETTING.get_notifications();
+        let sender = self.sender();
+        let (queue, rate_limit, backend) = match msg.0.to {
+            Recipient::Email(_) => (
+                &mut self.email,
+                settings.email_rate_limit,
+                email_backend(sender),
+            ),
+            Recipient::Phone(_) => (&mut self.sms, settings.sms_rate_limit, sms_backend(sender)),
+        };
+        let backend = match backend {
+            Some(backend) => backend,
+            None => return Box::new(future::err(format_err!("No backend configured"))),
+        };
+        if !queue.take_slot(Instant::now(), rate_limit) {
+            return Box::new(future::err(format_err!(
+                "{} rate limit reached",
+                backend.name()
+            )));
+        }
+        trace!("Delivering {} with {}", msg.0, backend.name());
+        backend.send(&msg.0)
+    }
+}
+
+struct NotificationFailed(QueuedNotification);
+
+impl Message for NotificationFailed {
+    type Result = ();
+}
+
+impl Handler<NotificationFailed> for Notifier {
+    type Result = ();
+
+    fn handle(&mut self, msg: NotificationFailed, _ctx: &mut Context<Self>) -> Self::Result {
+        let max_attempts = SETTING.get_notifications().max_attempts;
+        let queued = msg.0;
+        let description = queued.notification.to_string();
+        let queue = match queued.notification.to {
+            Recipient::Email(_) => &mut self.email,
+            Recipient::Phone(_) => &mut self.sms,
+        };
+        if !queue.failed(queued, Instant::now(), max_attempts) {
+            error!(
+                "Giving up on {} after {} attempts",
+                description, max_attempts
+            );
+        }
+    }
+}
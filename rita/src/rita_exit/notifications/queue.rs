@@ -0,0 +1,164 @@
+//! The queue in front of each notification backend. It holds messages back once the backend's
+//! rate limit for the last minute is used up and puts failed messages back with a growing delay
+//! until they run out of attempts.
+
+use super::Notification;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Past this many waiting messages the oldest are dropped, a backend that's been down for a
+/// long while shouldn't take the exit's memory with it
+const MAX_QUEUED_NOTIFICATIONS: usize = 10_000;
+/// The delay before the first retry, doubled for every one after
+const RETRY_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(3600);
+const RATE_LIMIT_PERIOD: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedNotification {
+    pub notification: Notification,
+    /// failed attempts so far
+    pub attempts: u32,
+    not_before: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct NotificationQueue {
+    waiting: VecDeque<QueuedNotification>,
+    /// when each message in the last rate limit period was handed out
+    sent: VecDeque<Instant>,
+}
+
+impl NotificationQueue {
+    pub fn push(&mut self, notification: Notification, now: Instant) {
+        self.requeue(QueuedNotification {
+            notification,
+            attempts: 0,
+            not_before: now,
+        });
+    }
+
+    fn requeue(&mut self, queued: QueuedNotification) {
+        if self.waiting.len() >= MAX_QUEUED_NOTIFICATIONS {
+            if let Some(dropped) = self.waiting.pop_front() {
+                warn!("Notification queue full, dropping {}", dropped.notification);
+            }
+        }
+        self.waiting.push_back(queued);
+    }
+
+    /// How many more messages `rate_limit` per minute allows right now, 0 is no limit
+    fn allowed(&mut self, now: Instant, rate_limit: u32) -> usize {
+        while let Some(sent) = self.sent.front() {
+            if now - *sent < RATE_LIMIT_PERIOD {
+                break;
+            }
+            self.sent.pop_front();
+        }
+        if rate_limit == 0 {
+            usize::max_value()
+        } else {
+            (rate_limit as usize).saturating_sub(self.sent.len())
+        }
+    }
+
+    /// Counts a message sent past the queue against the rate limit, false if it's used up
+    pub fn take_slot(&mut self, now: Instant, rate_limit: u32) -> bool {
+        if self.allowed(now, rate_limit) == 0 {
+            return false;
+        }
+        if rate_limit != 0 {
+            self.sent.push_back(now);
+        }
+        true
+    }
+
+    /// Takes the messages that are due, as many as `rate_limit` per minute allows, 0 is no limit
+    pub fn take_due(&mut self, now: Instant, rate_limit: u32) -> Vec<QueuedNotification> {
+        let mut allowed = self.allowed(now, rate_limit);
+
+        let mut due = Vec::new();
+        let mut later = VecDeque::new();
+        while let Some(queued) = self.waiting.pop_front() {
+            if allowed > 0 && queued.not_before <= now {
+                allowed -= 1;
+                if rate_limit != 0 {
+                    self.sent.push_back(now);
+                }
+                due.push(queued);
+            } else {
+                later.push_back(queued);
+            }
+        }
+        self.waiting = later;
+        due
+    }
+
+    /// Puts a message that failed to send back for a retry, false if it's out of attempts
+    pub fn failed(
+        &mut self,
+        mut queued: QueuedNotification,
+        now: Instant,
+        max_attempts: u32,
+    ) -> bool {
+        queued.attempts += 1;
+        if queued.attempts >= max_attempts {
+            return false;
+        }
+        let backoff = RETRY_BACKOFF * 2u32.pow((queued.attempts - 1).min(16));
+        queued.not_before = now + backoff.min(MAX_RETRY_BACKOFF);
+        self.requeue(queued);
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiting.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiting.is_empty()
+    }
+}
+
+#[test]
+fn test_notification_queue() {
+    use super::Recipient;
+
+    let message = |n: u32| Notification {
+        to: Recipient::Email(format!("{}@example.com", n)),
+        subject: "subject".to_string(),
+        body: "body".to_string(),
+    };
+    let start = Instant::now();
+    let mut queue = NotificationQueue::default();
+    for n in 0..3 {
+        queue.push(message(n), start);
+    }
+
+    // rate limited to two a minute
+    let due = queue.take_due(start, 2);
+    assert_eq!(due.len(), 2);
+    assert_eq!(due[0].notification, message(0));
+    assert!(queue
+        .take_due(start + Duration::from_secs(59), 2)
+        .is_empty());
+    let later = start + RATE_LIMIT_PERIOD;
+    assert_eq!(queue.take_due(later, 2)[0].notification, message(2));
+    assert!(queue.is_empty());
+    // messages sent past the queue share the limit
+    assert!(queue.take_slot(later, 2));
+    assert!(!queue.take_slot(later, 2));
+    assert!(queue.take_slot(later, 0));
+
+    // failures come back after a growing delay until they run out of attempts
+    let mut failed = due[0].clone();
+    assert!(queue.failed(failed.clone(), later, 3));
+    assert!(queue.take_due(later, 0).is_empty());
+    failed = queue.take_due(later + RETRY_BACKOFF, 0).pop().unwrap();
+    assert_eq!(failed.attempts, 1);
+    assert!(queue.failed(failed, later, 3));
+    assert!(queue.take_due(later + RETRY_BACKOFF, 0).is_empty());
+    failed = queue.take_due(later + RETRY_BACKOFF * 2, 0).pop().unwrap();
+    assert!(!queue.failed(failed, later, 3));
+    assert!(queue.is_empty());
+}
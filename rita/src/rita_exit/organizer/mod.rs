@@ -0,0 +1,328 @@
+//! Aggregate views of the exit's clients for the community organizers who run it. Everything here
+//! is assembled on request from the client database, DebtKeeper and the exit traffic watcher, so
+//! byte counts are totals since each client's tunnel was last created rather than all time.
+
+use crate::rita_common::debt_keeper::{DebtKeeper, GetDebtsList, GetDebtsResult};
+use crate::rita_exit::database::database_tools::get_database_connection;
+use crate::rita_exit::database::secs_since_unix_epoch;
+use crate::rita_exit::database::struct_tools::is_archived;
+use crate::rita_exit::traffic_watcher::{GetClientUsage, TrafficWatcher};
+use ::actix::SystemService;
+use ::actix_web::{AsyncResponder, HttpRequest, HttpResponse, Query};
+use althea_kernel_interface::wg_iface_counter::WgUsage;
+use althea_types::WgKey;
+use exit_db::models::Client;
+use failure::Error;
+use futures01::Future;
+use num256::{Int256, Uint256};
+use num_traits::identities::Zero;
+use std::collections::HashMap;
+
+/// Clients seen within this many seconds count as active
+const ACTIVE_WINDOW: i64 = 86400;
+/// Clients archived within this many seconds count as recently churned
+const CHURN_WINDOW: i64 = 30 * 86400;
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct OrganizerStats {
+    pub total_clients: usize,
+    pub verified_clients: usize,
+    /// clients seen in the last day
+    pub active_clients: usize,
+    /// clients archived by the retention policy, they are counted in the total
+    pub archived_clients: usize,
+    /// clients archived in the last 30 days
+    pub recently_churned: usize,
+    /// bytes sent by clients
+    pub bytes_up: u64,
+    /// bytes sent to clients
+    pub bytes_down: u64,
+    /// payments received from clients in wei
+    pub revenue_received: Uint256,
+    /// what clients currently owe in wei, the revenue to expect if everyone pays up
+    pub revenue_owed: Uint256,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ClientSummary {
+    pub nickname: String,
+    pub mesh_ip: String,
+    pub wg_pubkey: String,
+    pub eth_address: String,
+    pub country: String,
+    pub verified: bool,
+    /// seconds since the unix epoch
+    pub last_seen: i64,
+    pub active: bool,
+    pub archived: bool,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    /// what the client owes us in wei, negative if we owe them
+    pub owes: Int256,
+    pub total_paid: Uint256,
+}
+
+#[derive(Serialize)]
+pub struct ClientPage {
+    pub total: usize,
+    pub page: usize,
+    pub per_page: usize,
+    pub clients: Vec<ClientSummary>,
+}
+
+#[derive(Deserialize)]
+pub struct ClientsQuery {
+    #[serde(default)]
+    page: usize,
+    per_page: Option<usize>,
+    /// `csv` for a csv export, anything else is json
+    format: Option<String>,
+}
+
+fn summarize(
+    clients: &[Client],
+    debts: &[GetDebtsResult],
+    usage: &HashMap<WgKey, WgUsage>,
+    now: i64,
+) -> (OrganizerStats, Vec<ClientSummary>) {
+    let mut debts_by_key = HashMap::new();
+    for debt in debts {
+        debts_by_key.insert(debt.identity.wg_public_key, &debt.payment_details);
+    }
+
+    let mut stats = OrganizerStats {
+        total_clients: clients.len(),
+        verified_clients: 0,
+        active_clients: 0,
+        archived_clients: 0,
+        recently_churned: 0,
+        bytes_up: 0,
+        bytes_down: 0,
+        revenue_received: Uint256::zero(),
+        revenue_owed: Uint256::zero(),
+    };
+    let mut summaries = Vec::new();
+    for client in clients {
+        let key: Option<WgKey> = client.wg_pubkey.parse().ok();
+        // the exit's upload is the client's download
+        let (bytes_up, bytes_down) = match key.and_then(|k| usage.get(&k)) {
+            Some(usage) => (usage.download, usage.upload),
+            None => (0, 0),
+        };
+        let (owes, total_paid) = match key.and_then(|k| debts_by_key.get(&k)) {
+            Some(debt) => (
+                Int256::zero() - debt.debt.clone(),
+                debt.total_payment_received.clone(),
+            ),
+            None => (Int256::zero(), Uint256::zero()),
+        };
+        let active = now - client.last_seen < ACTIVE_WINDOW;
+        let archived = is_archived(client);
+
+        if client.verified {
+            stats.verified_clients += 1;
+        }
+        if active {
+            stats.active_clients += 1;
+        }
+        if archived {
+            stats.archived_clients += 1;
+            if now - client.archived_time < CHURN_WINDOW {
+                stats.recently_churned += 1;
+            }
+        }
+        stats.bytes_up += bytes_up;
+        stats.bytes_down += bytes_down;
+        stats.revenue_received += total_paid.clone();
+        if owes > Int256::zero() {
+            // positive so the conversion can't fail
+            stats.revenue_owed += owes.to_uint256().unwrap();
+        }
+
+        summaries.push(ClientSummary {
+            nickname: client.nickname.clone(),
+            mesh_ip: client.mesh_ip.clone(),
+            wg_pubkey: client.wg_pubkey.clone(),
+            eth_address: client.eth_address.clone(),
+            country: client.country.clone(),
+            verified: client.verified,
+            last_seen: client.last_seen,
+            active,
+            archived,
+            bytes_up,
+            bytes_down,
+            owes,
+            total_paid,
+        });
+    }
+    // a stable order so that pages don't shuffle between requests
+    summaries.sort_by(|a, b| a.mesh_ip.cmp(&b.mesh_ip));
+    (stats, summaries)
+}
+
+/// Quotes a csv field if it needs it, nicknames are user supplied and may contain anything
+fn csv_field(field: &str) -> String {
+    if field.contains(',') || field.contains('"') || field.contains('\n') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn to_csv(clients: &[ClientSummary]) -> String {
+    let mut out = String::from(
+        "nickname,mesh_ip,wg_pubkey,eth_address,country,verified,last_seen,active,archived,bytes_up,bytes_down,owes,total_paid\n",
+    );
+    for c in clients {
+        let row = [
+            csv_field(&c.nickname),
+            csv_field(&c.mesh_ip),
+            csv_field(&c.wg_pubkey),
+            csv_field(&c.eth_address),
+            csv_field(&c.country),
+            c.verified.to_string(),
+            c.last_seen.to_string(),
+            c.active.to_string(),
+            c.archived.to_string(),
+            c.bytes_up.to_string(),
+            c.bytes_down.to_string(),
+            c.owes.to_string(),
+            c.total_paid.to_string(),
+        ];
+        out += &row.join(",");
+        out.push('\n');
+    }
+    out
+}
+
+fn gather() -> impl Future<Item = (OrganizerStats, Vec<ClientSummary>), Error = Error> {
+    let clients_list = get_database_connection().and_then(|conn| conn.load_clients());
+    let debts = DebtKeeper::from_registry()
+        .send(GetDebtsList)
+        .from_err()
+        .and_then(|res| res);
+    let usage = TrafficWatcher::from_registry()
+        .send(GetClientUsage)
+        .from_err()
+        .and_then(|res| res);
+    clients_list
+        .join3(debts, usage)
+        .and_then(|(clients_list, debts, usage)| {
+            Ok(summarize(
+                &clients_list,
+                &debts,
+                &usage,
+                secs_since_unix_epoch(),
+            ))
+        })
+}
+
+pub fn get_organizer_stats(
+    _req: HttpRequest,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    trace!("get_organizer_stats: Hit");
+    gather()
+        .and_then(|(stats, _)| Ok(HttpResponse::Ok().json(stats)))
+        .responder()
+}
+
+/// Per client summaries, a page at a time. Csv exports contain every client unless a page
+/// size is given
+pub fn get_organizer_clients(
+    query: Query<ClientsQuery>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    trace!("get_organizer_clients: Hit");
+    gather()
+        .and_then(move |(_, summaries)| {
+            let csv = query.format.as_ref().map(|f| f == "csv").unwrap_or(false);
+            let total = summaries.len();
+            let per_page = match (query.per_page, csv) {
+                (Some(val), _) => val.min(MAX_PAGE_SIZE).max(1),
+                (None, true) => total.max(1),
+                (None, false) => DEFAULT_PAGE_SIZE,
+            };
+            let page: Vec<ClientSummary> = summaries
+                .into_iter()
+                .skip(query.page.saturating_mul(per_page))
+                .take(per_page)
+                .collect();
+
+            if csv {
+                Ok(HttpResponse::Ok()
+                    .content_type("text/csv")
+                    .header(
+                        "Content-Disposition",
+                        "attachment; filename=\"clients.csv\"",
+                    )
+                    .body(to_csv(&page)))
+            } else {
+                Ok(HttpResponse::Ok().json(ClientPage {
+                    total,
+                    page: query.page,
+                    per_page,
+                    clients: page,
+                }))
+            }
+        })
+        .responder()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rita_common::debt_keeper::NodeDebtData;
+    use crate::rita_common::utils::test_identity;
+
+    #[test]
+    fn test_summarize() {
+        let now = 1_000_000;
+        let id = test_identity("fd00::2");
+        let seen = Client {
+            mesh_ip: "fd00::2".to_string(),
+            wg_pubkey: id.wg_public_key.to_string(),
+            nickname: "Bob's, \"place\"".to_string(),
+            verified: true,
+            last_seen: now - 60,
+            ..Default::default()
+        };
+        let stale = Client {
+            mesh_ip: "fd00::1".to_string(),
+            wg_pubkey: "bad key".to_string(),
+            last_seen: now - ACTIVE_WINDOW * 2,
+            archived_time: now - ACTIVE_WINDOW,
+            ..Default::default()
+        };
+
+        let mut usage = HashMap::new();
+        usage.insert(
+            id.wg_public_key,
+            WgUsage {
+                upload: 500,
+                download: 20,
+            },
+        );
+        let mut debt = NodeDebtData::new();
+        debt.debt = Int256::from(-300);
+        debt.total_payment_received = 1000u32.into();
+        let debts = vec![GetDebtsResult::new(&id, &debt)];
+
+        let (stats, summaries) = summarize(&[seen, stale], &debts, &usage, now);
+        assert_eq!(stats.total_clients, 2);
+        assert_eq!(stats.verified_clients, 1);
+        assert_eq!(stats.active_clients, 1);
+        assert_eq!(stats.archived_clients, 1);
+        assert_eq!(stats.recently_churned, 1);
+        assert_eq!(stats.bytes_up, 20);
+        assert_eq!(stats.bytes_down, 500);
+        assert_eq!(stats.revenue_received, 1000u32.into());
+        assert_eq!(stats.revenue_owed, 300u32.into());
+
+        assert_eq!(summaries[0].mesh_ip, "fd00::1");
+        assert_eq!(summaries[1].owes, Int256::from(300));
+        let csv = to_csv(&summaries);
+        assert!(csv.contains("\"Bob's, \"\"place\"\"\",fd00::2"));
+        assert_eq!(csv.lines().count(), 3);
+    }
+}
@@ -19,11 +19,21 @@
 //! actix work together on this on properly, not that I've every seen simple actors like the loop crash
 //! very often.
 
+use crate::middleware;
+use crate::rita_common::route_cache::get_routes;
+use crate::rita_common::watchdog::{common_watched, LoopTime, Watchdog, Watched};
 use crate::rita_exit::database::database_tools::get_database_connection;
-use crate::rita_exit::database::struct_tools::clients_to_ids;
-use crate::rita_exit::database::{
-    cleanup_exit_clients, enforce_exit_clients, setup_clients, validate_clients_region,
+use crate::rita_exit::database::db_health::{
+    DbFailure, DbHealth, GetCachedClients, UpdateClientCache,
 };
+use crate::rita_exit::database::debt_sync::{sync_cluster_debts, DEBT_SYNC_INTERVAL};
+use crate::rita_exit::database::device_limits::{sample_devices, DEVICE_SAMPLE_INTERVAL};
+use crate::rita_exit::database::plans::{count_monthly_usage, PLAN_USAGE_INTERVAL};
+use crate::rita_exit::database::port_policy::apply_port_policy;
+use crate::rita_exit::database::retention::cleanup_exit_clients;
+use crate::rita_exit::database::store::StoreConnection;
+use crate::rita_exit::database::struct_tools::clients_to_ids;
+use crate::rita_exit::database::{enforce_exit_clients, setup_clients, validate_clients_region};
 use crate::rita_exit::network_endpoints::*;
 use crate::rita_exit::traffic_watcher::{TrafficWatcher, Watch};
 use crate::KI;
@@ -34,14 +44,7 @@ use actix::{
 };
 use actix_web::http::Method;
 use actix_web::{server, App};
-use althea_kernel_interface::ExitClient;
-use babel_monitor::open_babel_stream;
-use babel_monitor::parse_routes;
-use babel_monitor::start_connection;
-use diesel::query_dsl::RunQueryDsl;
-use diesel::r2d2::ConnectionManager;
-use diesel::r2d2::PooledConnection;
-use diesel::PgConnection;
+use althea_kernel_interface::{ExitClient, PortPolicy};
 use exit_db::models;
 use failure::Error;
 use futures01::future::Future;
@@ -64,6 +67,14 @@ pub struct RitaLoop {
     pub geoip_cache: HashMap<IpAddr, String>,
     /// a cache of what tunnels we had setup last round, used to prevent extra setup ops
     pub wg_clients: HashSet<ExitClient>,
+    /// when we last synced debts with the rest of the cluster
+    pub last_debt_sync: Option<Instant>,
+    /// when we last counted the devices behind each client
+    pub last_device_sample: Option<Instant>,
+    /// when we last counted usage this month for clients with a quota
+    pub last_plan_usage_count: Option<Instant>,
+    /// the ports we blocked last round, the rules are only reloaded when this changes
+    pub port_policy: Option<PortPolicy>,
 }
 
 impl Actor for RitaLoop {
@@ -77,7 +88,17 @@ impl Actor for RitaLoop {
             Arbiter::spawn(get_database_connection().then(move |database| {
                 match database {
                     Ok(database) => addr.do_send(Tick(database)),
-                    Err(e) => error!("Could not reach database for Rita sync loop! {:?}", e),
+                    Err(e) => {
+                        error!("Could not reach database for Rita sync loop! {:?}", e);
+                        Arbiter::spawn(DbHealth::from_registry().send(GetCachedClients).then(
+                            move |res| {
+                                if let Ok(Ok(Some(clients_list))) = res {
+                                    addr.do_send(DegradedTick(clients_list));
+                                }
+                                Ok(())
+                            },
+                        ));
+                    }
                 }
                 Ok(())
             }));
@@ -107,7 +128,7 @@ impl Handler<Crash> for RitaLoop {
     }
 }
 
-pub struct Tick(PooledConnection<ConnectionManager<PgConnection>>);
+pub struct Tick(StoreConnection);
 
 impl Message for Tick {
     type Result = Result<(), Error>;
@@ -117,8 +138,6 @@ impl Handler<Tick> for RitaLoop {
     type Result = Result<(), Error>;
     fn handle(&mut self, msg: Tick, _ctx: &mut Context<Self>) -> Self::Result {
         let start = Instant::now();
-        use exit_db::schema::clients::dsl::clients;
-        let babel_port = SETTING.get_network().babel_port;
         info!("Exit tick!");
 
         // opening a database connection takes at least several milliseconds, as the database server
@@ -126,38 +145,16 @@ impl Handler<Tick> for RitaLoop {
         // as possible
         let conn = msg.0;
 
-        let clients_list = clients.load::<models::Client>(&conn)?;
-        let ids = clients_to_ids(clients_list.clone());
-
-        // watch and bill for traffic
-        Arbiter::spawn(
-            open_babel_stream(babel_port)
-                .from_err()
-                .and_then(|stream| {
-                    start_connection(stream).and_then(|stream| {
-                        parse_routes(stream).and_then(|routes| {
-                            TrafficWatcher::from_registry().do_send(Watch {
-                                users: ids,
-                                routes: routes.1,
-                            });
-                            Ok(())
-                        })
-                    })
-                })
-                .timeout(EXIT_LOOP_TIMEOUT)
-                .then(|ret| {
-                    if let Err(e) = ret {
-                        error!("Failed to watch Exit traffic with {:?}", e)
-                    }
-                    Ok(())
-                }),
-        );
+        let clients_list = match conn.load_clients() {
+            Ok(list) => list,
+            Err(e) => {
+                DbHealth::from_registry().do_send(DbFailure(format!("{:?}", e)));
+                return Err(e);
+            }
+        };
+        DbHealth::from_registry().do_send(UpdateClientCache(clients_list.clone()));
 
-        // Create and update client tunnels
-        match setup_clients(&clients_list, &self.wg_clients) {
-            Ok(wg_clients) => self.wg_clients = wg_clients,
-            Err(e) => error!("Setup clients failed with {:?}", e),
-        }
+        self.serve_clients(&clients_list);
 
         // find users that have not been active within the configured time period
         // and remove them from the db
@@ -171,19 +168,118 @@ impl Handler<Tick> for RitaLoop {
             Arbiter::spawn(validate_clients_region(clients_list.clone()));
         }
 
+        // before enforcement so that clients over their device or usage limits are caught right away
+        let due = self
+            .last_device_sample
+            .map_or(true, |last| last.elapsed() >= DEVICE_SAMPLE_INTERVAL);
+        if due {
+            self.last_device_sample = Some(Instant::now());
+            sample_devices(&clients_list);
+        }
+        let due = self
+            .last_plan_usage_count
+            .map_or(true, |last| last.elapsed() >= PLAN_USAGE_INTERVAL);
+        if due {
+            self.last_plan_usage_count = Some(Instant::now());
+            if let Err(e) = count_monthly_usage(&clients_list, &conn) {
+                warn!("Failed to count usage towards monthly quotas {:?}", e);
+            }
+        }
+
         // handle enforcement on client tunnels by querying debt keeper
         // this consumes client list, you can move it up in exchange for a clone
         Arbiter::spawn(enforce_exit_clients(clients_list));
 
+        let node_id = SETTING.get_exit_network().cluster_node_id.clone();
+        if let Some(node_id) = node_id {
+            let due = self
+                .last_debt_sync
+                .map_or(true, |last| last.elapsed() >= DEBT_SYNC_INTERVAL);
+            if due {
+                self.last_debt_sync = Some(Instant::now());
+                Arbiter::spawn(sync_cluster_debts(node_id));
+            }
+        }
+
         info!(
             "Completed Rita sync loop in {}s {}ms, all vars should be dropped",
             start.elapsed().as_secs(),
             start.elapsed().subsec_millis(),
         );
+        Watchdog::from_registry().do_send(LoopTime {
+            name: "RitaExitLoop",
+            elapsed: start.elapsed(),
+            period: Duration::from_secs(EXIT_LOOP_SPEED),
+        });
         Ok(())
     }
 }
 
+crate::watchdog_handlers!(RitaLoop);
+crate::watchdog_handlers!(TrafficWatcher);
+
+/// Has the watchdog keep an eye on the common actors and the exit ones
+pub fn start_rita_exit_watchdog() {
+    let mut watched = common_watched();
+    watched.push(Watched::new::<RitaLoop>("RitaExitLoop"));
+    watched.push(Watched::new::<TrafficWatcher>("ExitTrafficWatcher"));
+    Watchdog::from_registry().do_send(crate::rita_common::watchdog::Watch(watched));
+}
+
+/// Run in place of Tick while the database is down, keeps billing and tunnels going for the
+/// clients we already know about but doesn't touch anything that needs the database
+pub struct DegradedTick(Vec<models::Client>);
+
+impl Message for DegradedTick {
+    type Result = ();
+}
+
+impl Handler<DegradedTick> for RitaLoop {
+    type Result = ();
+    fn handle(&mut self, msg: DegradedTick, _ctx: &mut Context<Self>) -> Self::Result {
+        warn!(
+            "Exit tick in degraded mode with {} cached clients",
+            msg.0.len()
+        );
+        self.serve_clients(&msg.0);
+        Arbiter::spawn(enforce_exit_clients(msg.0));
+    }
+}
+
+impl RitaLoop {
+    /// Bills client traffic and sets up their tunnels
+    fn serve_clients(&mut self, clients_list: &[models::Client]) {
+        let ids = clients_to_ids(clients_list.to_vec());
+
+        // watch and bill for traffic
+        Arbiter::spawn(
+            get_routes()
+                .and_then(|cached| {
+                    TrafficWatcher::from_registry().do_send(Watch {
+                        users: ids,
+                        routes: cached.routes,
+                    });
+                    Ok(())
+                })
+                .timeout(EXIT_LOOP_TIMEOUT)
+                .then(|ret| {
+                    if let Err(e) = ret {
+                        error!("Failed to watch Exit traffic with {:?}", e)
+                    }
+                    Ok(())
+                }),
+        );
+
+        // Create and update client tunnels
+        match setup_clients(clients_list, &self.wg_clients) {
+            Ok(wg_clients) => self.wg_clients = wg_clients,
+            Err(e) => error!("Setup clients failed with {:?}", e),
+        }
+        // checked every round since blocks can be changed through the admin api at any time
+        self.port_policy = apply_port_policy(&self.wg_clients, self.port_policy.take());
+    }
+}
+
 fn setup_exit_wg_tunnel() {
     if let Err(e) = KI.setup_wg_if_named("wg_exit") {
         warn!("exit setup returned {}", e)
@@ -193,29 +289,60 @@ fn setup_exit_wg_tunnel() {
         SETTING.get_exit_network().netmask,
     )
     .expect("Failed to setup wg_exit!");
-    KI.setup_nat(&SETTING.get_network().external_nic.clone().unwrap())
-        .unwrap();
+    // in cgnat mode address translation is set up along with the clients
+    let masquerade = SETTING.get_exit_network().cgnat.is_none();
+    KI.setup_nat(
+        &SETTING.get_network().external_nic.clone().unwrap(),
+        masquerade,
+    )
+    .unwrap();
+
+    let exit_network = SETTING.get_exit_network();
+    match (&exit_network.nat64, exit_network.ipv6_pool) {
+        (Some(nat64), Some(_)) => {
+            if let Err(e) = KI.set_nat64(nat64.prefix) {
+                error!("Failed to set up NAT64 {:?}", e);
+            }
+        }
+        (nat64, _) => {
+            if nat64.is_some() {
+                error!("NAT64 needs an ipv6_pool to give clients addresses from!");
+            }
+            if let Err(e) = KI.remove_nat64() {
+                warn!("Failed to remove NAT64 {:?}", e);
+            }
+        }
+    }
 }
 
 pub fn check_rita_exit_actors() {
     assert!(crate::rita_exit::rita_loop::RitaLoop::from_registry().connected());
     assert!(crate::rita_exit::traffic_watcher::TrafficWatcher::from_registry().connected());
     assert!(crate::rita_exit::database::db_client::DbClient::from_registry().connected());
+    assert!(crate::rita_exit::database::db_health::DbHealth::from_registry().connected());
+    assert!(crate::rita_exit::dashboard::ExitDashboard::from_registry().connected());
 }
 
 pub fn start_rita_exit_endpoints(workers: usize) {
     // Exit stuff, huge threadpool to offset Pgsql blocking
     server::new(|| {
         App::new()
+            .middleware(middleware::RateLimit)
             .resource("/secure_setup", |r| {
                 r.method(Method::POST).with(secure_setup_request)
             })
             .resource("/secure_status", |r| {
                 r.method(Method::POST).with(secure_status_request)
             })
+            .resource("/secure_resend_email", |r| {
+                r.method(Method::POST).with(secure_resend_email_request)
+            })
             .resource("/exit_info", |r| {
                 r.method(Method::GET).with(get_exit_info_http)
             })
+            .resource("/throughput_sample", |r| {
+                r.method(Method::GET).with(throughput_sample)
+            })
             .resource("/client_debt", |r| {
                 r.method(Method::POST).with(get_client_debt)
             })
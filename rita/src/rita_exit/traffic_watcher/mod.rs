@@ -11,26 +11,38 @@
 use crate::rita_common::debt_keeper;
 use crate::rita_common::debt_keeper::DebtKeeper;
 use crate::rita_common::debt_keeper::Traffic;
+use crate::rita_common::schedule;
 use crate::rita_common::usage_tracker::UpdateUsage;
 use crate::rita_common::usage_tracker::UsageTracker;
 use crate::rita_common::usage_tracker::UsageType;
+use crate::rita_common::watchdog::or_alarm;
+use crate::rita_exit::database::secs_since_unix_epoch;
+use crate::rita_exit::database::usage_records::{flush_usage_records, UsageBuffer};
+use crate::EXIT_LOAD;
 use crate::SETTING;
-use ::actix::{Actor, Context, Handler, Message, Supervised, SystemService};
+use ::actix::{Actor, Arbiter, Context, Handler, Message, Supervised, SystemService};
 use althea_kernel_interface::wg_iface_counter::prepare_usage_history;
 use althea_kernel_interface::wg_iface_counter::WgUsage;
 use althea_kernel_interface::KI;
-use althea_types::Identity;
 use althea_types::WgKey;
+use althea_types::{CapacityClass, ExitLoad, Identity, Wei};
 use babel_monitor::Route;
+use exit_db::models::UsageRecord;
 use failure::Error;
 use ipnetwork::IpNetwork;
 use settings::exit::RitaExitSettings;
 use settings::RitaCommonSettings;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::net::IpAddr;
+use std::time::{Duration, Instant};
 
 pub struct TrafficWatcher {
     last_seen_bytes: HashMap<WgKey, WgUsage>,
+    /// billed usage waiting to be written to the usage_records table
+    usage: UsageBuffer,
+    /// when the last round was counted, for working out our load
+    last_watch: Option<Instant>,
 }
 
 impl Actor for TrafficWatcher {
@@ -47,6 +59,8 @@ impl Default for TrafficWatcher {
     fn default() -> TrafficWatcher {
         TrafficWatcher {
             last_seen_bytes: HashMap::new(),
+            usage: UsageBuffer::default(),
+            last_watch: None,
         }
     }
 }
@@ -64,7 +78,56 @@ impl Handler<Watch> for TrafficWatcher {
     type Result = Result<(), Error>;
 
     fn handle(&mut self, msg: Watch, _: &mut Context<Self>) -> Self::Result {
-        watch(&mut self.last_seen_bytes, &msg.routes, &msg.users)
+        let res = watch(
+            &mut self.last_seen_bytes,
+            &mut self.usage,
+            &msg.routes,
+            &msg.users,
+        );
+        let now = Instant::now();
+        if let (Ok(moved), Some(last_watch)) = (&res, self.last_watch) {
+            let load = exit_load(
+                msg.users.len() as u32,
+                *moved,
+                now - last_watch,
+                SETTING.get_exit_network().capacity_mbps,
+            );
+            trace!("Exit load is {:?}", load);
+            *EXIT_LOAD.write().unwrap() = Some(load);
+        }
+        self.last_watch = Some(now);
+        if let Some(records) = self.usage.take_if_due(now) {
+            Arbiter::spawn(flush_usage_records(records));
+        }
+        res.map(|_| ())
+    }
+}
+
+/// Usage records that couldn't be written to the database, they go back in the buffer
+#[derive(Message)]
+pub struct ReturnUsage(pub Vec<UsageRecord>);
+
+impl Handler<ReturnUsage> for TrafficWatcher {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReturnUsage, _: &mut Context<Self>) -> Self::Result {
+        self.usage.restore(msg.0);
+    }
+}
+
+/// Returns the wg counters for each client as of the last round, these are totals since the
+/// client's tunnel was created
+pub struct GetClientUsage;
+
+impl Message for GetClientUsage {
+    type Result = Result<HashMap<WgKey, WgUsage>, Error>;
+}
+
+impl Handler<GetClientUsage> for TrafficWatcher {
+    type Result = Result<HashMap<WgKey, WgUsage>, Error>;
+
+    fn handle(&mut self, _msg: GetClientUsage, _: &mut Context<Self>) -> Self::Result {
+        Ok(self.last_seen_bytes.clone())
     }
 }
 
@@ -127,11 +190,34 @@ fn generate_helper_maps(our_id: &Identity, clients: &[Identity]) -> Result<Helpe
     })
 }
 
+/// Works out our load from the bytes clients sent and received over the last round
+fn exit_load(
+    clients: u32,
+    (total_in, total_out): (u64, u64),
+    elapsed: Duration,
+    capacity_mbps: Option<u32>,
+) -> ExitLoad {
+    let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_millis()) / 1000.0;
+    let utilization = match capacity_mbps {
+        Some(capacity) if capacity > 0 && secs > 0.0 => {
+            let mbps = total_in.max(total_out) as f64 * 8.0 / secs / 1_000_000.0;
+            Some((mbps / f64::from(capacity) * 100.0).min(100.0) as u8)
+        }
+        _ => None,
+    };
+    ExitLoad {
+        clients,
+        utilization,
+        capacity: CapacityClass::from_utilization(utilization),
+    }
+}
+
+/// Logs and records the round's totals, which are returned as bytes in from and out to clients
 fn counters_logging(
     counters: &HashMap<WgKey, WgUsage>,
     history: &HashMap<WgKey, WgUsage>,
     exit_fee: u32,
-) {
+) -> (u64, u64) {
     trace!("exit counters: {:?}", counters);
 
     let mut total_in: u64 = 0;
@@ -167,17 +253,18 @@ fn counters_logging(
     });
 
     info!("Total Exit output of {} bytes this round", total_out);
+    (total_in, total_out)
 }
 
-fn debts_logging(debts: &HashMap<Identity, i128>) {
+fn debts_logging(debts: &HashMap<Identity, Wei>) {
     trace!("Collated total exit debts: {:?}", debts);
 
     info!("Computed exit debts for {:?} clients", debts.len());
-    let mut total_income = 0i128;
+    let mut total_income = Wei::zero();
     for (_identity, income) in debts.iter() {
-        total_income += income;
+        total_income = total_income.saturating_add(income);
     }
-    info!("Total exit income of {:?} Wei this round", total_income);
+    info!("Total exit income of {} Wei this round", total_income);
 
     match KI.get_wg_exit_clients_online() {
         Ok(users) => info!("Total of {} users online", users),
@@ -186,12 +273,14 @@ fn debts_logging(debts: &HashMap<Identity, i128>) {
 }
 
 /// This traffic watcher watches how much traffic each we send and receive from each client.
+/// Returns the total bytes from and to clients this round
 pub fn watch(
     usage_history: &mut HashMap<WgKey, WgUsage>,
+    usage: &mut UsageBuffer,
     routes: &[Route],
     clients: &[Identity],
-) -> Result<(), Error> {
-    let our_price = SETTING.get_exit_network().exit_price;
+) -> Result<(u64, u64), Error> {
+    let our_price = schedule::exit_price(SETTING.get_exit_network().exit_price);
     let our_id = match SETTING.get_identity() {
         Some(id) => id,
         None => {
@@ -219,13 +308,20 @@ pub fn watch(
     // creates new usage entires does not actualy update the values
     prepare_usage_history(&counters, usage_history);
 
-    counters_logging(&counters, &usage_history, our_price as u32);
+    let usage_price = or_alarm(
+        u32::try_from(our_price).ok(),
+        u32::max_value(),
+        "TrafficWatcher",
+        || format!("exit price {} as a usage price", our_price),
+    );
+    let moved = counters_logging(&counters, &usage_history, usage_price);
 
     let mut debts = HashMap::new();
+    let now = secs_since_unix_epoch();
 
     // Setup the debts table
     for (_, ident) in identities.clone() {
-        debts.insert(ident, 0 as i128);
+        debts.insert(ident, Wei::zero());
     }
 
     // accounting for 'input'
@@ -239,9 +335,15 @@ pub fn watch(
             (Some(id), Some(_dest), Some(history)) => match debts.get_mut(&id) {
                 Some(debt) => {
                     let used = bytes.download - history.download;
-                    let value = i128::from(our_price) * i128::from(used);
+                    let value = Wei::from_price(our_price, used);
                     trace!("We are billing for {} bytes input (client output) times a exit price of {} for a total of -{}", used, our_price, value);
-                    *debt -= value;
+                    *debt = or_alarm(
+                        debt.checked_sub(&value),
+                        debt.saturating_sub(&value),
+                        "TrafficWatcher",
+                        || format!("debt of {} minus {}", id.mesh_ip, value),
+                    );
+                    usage.add(id, now, used, 0);
                     // update history so that we know what was used from previous cycles
                     history.download = bytes.download;
                 }
@@ -269,9 +371,21 @@ pub fn watch(
             (Some(id), Some(dest), Some(history)) => match debts.get_mut(&id) {
                 Some(debt) => {
                     let used = bytes.upload - history.upload;
-                    let value = i128::from(dest + our_price) * i128::from(used);
-                    trace!("We are billing for {} bytes output (client input) times a exit dest price of {} for a total of -{}", used, dest + our_price, value);
-                    *debt -= value;
+                    let price = or_alarm(
+                        dest.checked_add(our_price),
+                        u64::max_value(),
+                        "TrafficWatcher",
+                        || format!("route price {} plus exit price {}", dest, our_price),
+                    );
+                    let value = Wei::from_price(price, used);
+                    trace!("We are billing for {} bytes output (client input) times a exit dest price of {} for a total of -{}", used, price, value);
+                    *debt = or_alarm(
+                        debt.checked_sub(&value),
+                        debt.saturating_sub(&value),
+                        "TrafficWatcher",
+                        || format!("debt of {} minus {}", id.mesh_ip, value),
+                    );
+                    usage.add(id, now, 0, used);
                     history.upload = bytes.upload;
                 }
                 // debts is generated from identities, this should be impossible
@@ -301,5 +415,22 @@ pub fn watch(
     };
     DebtKeeper::from_registry().do_send(update);
 
-    Ok(())
+    Ok(moved)
+}
+
+#[test]
+fn test_exit_load() {
+    let second = Duration::from_secs(1);
+    // 50 mbps out of 100
+    let load = exit_load(3, (1_000_000, 6_250_000), second, Some(100));
+    assert_eq!(load.clients, 3);
+    assert_eq!(load.utilization, Some(50));
+    assert_eq!(load.capacity, CapacityClass::Limited);
+    // over capacity is full, not over 100%
+    let load = exit_load(3, (0, 100_000_000), second, Some(100));
+    assert_eq!(load.utilization, Some(100));
+    assert_eq!(load.capacity, CapacityClass::Full);
+    let load = exit_load(3, (0, 100_000_000), second, None);
+    assert_eq!(load.utilization, None);
+    assert_eq!(load.capacity, CapacityClass::Unknown);
 }
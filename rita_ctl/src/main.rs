@@ -0,0 +1,283 @@
+//! rita-ctl talks to a running Rita over its control socket, which serves the same api as the
+//! dashboard. It covers the operations that come up in scripts and field debugging, anything
+//! else can be reached with `get` and `post`.
+
+#[macro_use]
+extern crate failure;
+#[macro_use]
+extern crate serde_derive;
+
+use docopt::Docopt;
+use failure::Error;
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::process::exit;
+
+const USAGE: &str = "
+Usage:
+    rita-ctl [options] status
+    rita-ctl [options] neighbors
+    rita-ctl [options] debts
+    rita-ctl [options] exits
+    rita-ctl [options] exit select <exit>
+    rita-ctl [options] wifi ssid <radio> <ssid>
+    rita-ctl [options] wifi pass <radio> <pass>
+    rita-ctl [options] get <path>
+    rita-ctl [options] post <path> [<json>]
+    rita-ctl (-h | --help)
+
+Options:
+    -s, --socket=<path>   Rita's control socket [default: /var/run/rita.sock]
+    -r, --raw             Print json responses as they came instead of pretty printing them
+    -h, --help            Show this message
+";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    cmd_status: bool,
+    cmd_neighbors: bool,
+    cmd_debts: bool,
+    cmd_exits: bool,
+    cmd_exit: bool,
+    cmd_wifi: bool,
+    cmd_ssid: bool,
+    cmd_pass: bool,
+    cmd_get: bool,
+    cmd_post: bool,
+    arg_exit: String,
+    arg_radio: String,
+    arg_ssid: String,
+    arg_pass: String,
+    arg_path: String,
+    arg_json: Option<String>,
+    flag_socket: String,
+    flag_raw: bool,
+}
+
+#[derive(Debug, PartialEq)]
+struct Request {
+    method: &'static str,
+    path: String,
+    body: Option<String>,
+}
+
+impl Request {
+    fn get(path: &str) -> Request {
+        Request {
+            method: "GET",
+            path: path.to_string(),
+            body: None,
+        }
+    }
+
+    fn post(path: &str, body: Option<Value>) -> Request {
+        Request {
+            method: "POST",
+            path: path.to_string(),
+            body: body.map(|body| body.to_string()),
+        }
+    }
+}
+
+/// The dashboard request for a command
+fn request_for(args: &Args) -> Result<Request, Error> {
+    Ok(if args.cmd_status {
+        Request::get("/info")
+    } else if args.cmd_neighbors {
+        Request::get("/neighbors")
+    } else if args.cmd_debts {
+        Request::get("/debts")
+    } else if args.cmd_exits {
+        Request::get("/exits")
+    } else if args.cmd_exit {
+        Request::post(&format!("/exits/{}/select", args.arg_exit), None)
+    } else if args.cmd_wifi && args.cmd_ssid {
+        Request::post(
+            "/wifi_settings/ssid",
+            Some(json!({"radio": args.arg_radio, "ssid": args.arg_ssid})),
+        )
+    } else if args.cmd_wifi && args.cmd_pass {
+        Request::post(
+            "/wifi_settings/pass",
+            Some(json!({"radio": args.arg_radio, "pass": args.arg_pass})),
+        )
+    } else if args.cmd_get {
+        Request::get(&args.arg_path)
+    } else if args.cmd_post {
+        let body = match args.arg_json {
+            Some(ref json) => match serde_json::from_str(json) {
+                Ok(val) => Some(val),
+                Err(e) => bail!("Not valid json {}", e),
+            },
+            None => None,
+        };
+        Request::post(&args.arg_path, body)
+    } else {
+        bail!("No command given")
+    })
+}
+
+/// Decodes a chunked transfer encoded body
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::new();
+    loop {
+        let line_end = match body.windows(2).position(|w| w == b"\r\n") {
+            Some(pos) => pos,
+            None => bail!("Truncated chunk"),
+        };
+        let size = std::str::from_utf8(&body[..line_end])?;
+        // chunk extensions come after a semicolon
+        let size = size.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16)?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(out);
+        }
+        if body.len() < size {
+            bail!("Truncated chunk");
+        }
+        out.extend_from_slice(&body[..size]);
+        body = &body[size..];
+        if body.starts_with(b"\r\n") {
+            body = &body[2..];
+        }
+    }
+}
+
+/// Splits a raw HTTP response into its status code and body
+fn parse_response(raw: &[u8]) -> Result<(u16, String), Error> {
+    let header_end = match raw.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => pos,
+        None => bail!("Malformed response from Rita"),
+    };
+    let head = std::str::from_utf8(&raw[..header_end])?;
+    let mut lines = head.split("\r\n");
+    let status = match lines.next().and_then(|line| line.split_whitespace().nth(1)) {
+        Some(status) => status.parse()?,
+        None => bail!("Malformed status line from Rita"),
+    };
+    let chunked = lines.any(|line| {
+        let line = line.to_lowercase();
+        line.starts_with("transfer-encoding:") && line.contains("chunked")
+    });
+
+    let body = &raw[header_end + 4..];
+    let body = if chunked {
+        dechunk(body)?
+    } else {
+        body.to_vec()
+    };
+    Ok((status, String::from_utf8(body)?))
+}
+
+fn send(socket: &str, request: &Request) -> Result<(u16, String), Error> {
+    let mut stream = match UnixStream::connect(socket) {
+        Ok(stream) => stream,
+        Err(e) => bail!("Can't reach Rita on {}, is it running? {}", socket, e),
+    };
+    let body = request.body.clone().unwrap_or_default();
+    let mut message = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n",
+        request.method,
+        request.path,
+        body.len()
+    );
+    if request.body.is_some() {
+        message += "Content-Type: application/json\r\n";
+    }
+    message += "\r\n";
+    message += &body;
+    stream.write_all(message.as_bytes())?;
+
+    let mut raw = Vec::new();
+    stream.read_to_end(&mut raw)?;
+    parse_response(&raw)
+}
+
+fn run(args: &Args) -> Result<bool, Error> {
+    let request = request_for(args)?;
+    let (status, body) = send(&args.flag_socket, &request)?;
+    let output = match serde_json::from_str::<Value>(&body) {
+        Ok(ref json) if !args.flag_raw => serde_json::to_string_pretty(json)?,
+        _ => body,
+    };
+    let ok = status >= 200 && status < 300;
+    if ok {
+        println!("{}", output);
+    } else {
+        eprintln!("Rita returned {}: {}", status, output);
+    }
+    Ok(ok)
+}
+
+fn main() {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+
+    match run(&args) {
+        Ok(true) => {}
+        Ok(false) => exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(argv: &[&str]) -> Args {
+        Docopt::new(USAGE)
+            .and_then(|d| d.argv(argv.iter()).deserialize())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_request_for() {
+        let args = parse(&["rita-ctl", "status"]);
+        assert_eq!(args.flag_socket, "/var/run/rita.sock");
+        assert_eq!(request_for(&args).unwrap(), Request::get("/info"));
+
+        let args = parse(&["rita-ctl", "exit", "select", "borked"]);
+        assert_eq!(
+            request_for(&args).unwrap(),
+            Request::post("/exits/borked/select", None)
+        );
+
+        let args = parse(&[
+            "rita-ctl",
+            "-s",
+            "/tmp/r.sock",
+            "wifi",
+            "ssid",
+            "radio0",
+            "home",
+        ]);
+        assert_eq!(args.flag_socket, "/tmp/r.sock");
+        let request = request_for(&args).unwrap();
+        assert_eq!(request.path, "/wifi_settings/ssid");
+        let body: Value = serde_json::from_str(&request.body.unwrap()).unwrap();
+        assert_eq!(body, json!({"radio": "radio0", "ssid": "home"}));
+
+        let args = parse(&["rita-ctl", "post", "/local_fee/5", "{oops"]);
+        assert!(request_for(&args).is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let raw = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n{}";
+        assert_eq!(parse_response(raw).unwrap(), (200, "{}".to_string()));
+
+        let raw = b"HTTP/1.1 400 Bad Request\r\nTransfer-Encoding: chunked\r\n\r\n4\r\n\"bad\r\n5\r\n fee\"\r\n0\r\n\r\n";
+        assert_eq!(
+            parse_response(raw).unwrap(),
+            (400, "\"bad fee\"".to_string())
+        );
+
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+    }
+}
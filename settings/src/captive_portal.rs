@@ -0,0 +1,52 @@
+fn default_captive_portal_port() -> u16 {
+    4880
+}
+
+fn default_title() -> String {
+    "Welcome to the Althea network".to_string()
+}
+
+fn default_unregistered_message() -> String {
+    "This router is not yet connected to the internet. Open the router dashboard to finish setting it up.".to_string()
+}
+
+fn default_low_balance_message() -> String {
+    "This router is out of funds, internet access will resume once the balance is topped up."
+        .to_string()
+}
+
+/// Settings for the captive portal, when enabled plain http requests from the LAN are redirected
+/// to a page explaining why the internet isn't working whenever the router is unregistered or
+/// out of money. Operators can customize the text shown to their subscribers
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct CaptivePortalSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    /// The local port the portal page is served on, LAN http traffic is redirected here
+    #[serde(default = "default_captive_portal_port")]
+    pub port: u16,
+    #[serde(default = "default_title")]
+    pub title: String,
+    /// Shown when the router has not yet registered with an exit
+    #[serde(default = "default_unregistered_message")]
+    pub unregistered_message: String,
+    /// Shown when the router has run out of funds
+    #[serde(default = "default_low_balance_message")]
+    pub low_balance_message: String,
+    /// Also show the current exit's announcements on the portal page
+    #[serde(default)]
+    pub show_announcements: bool,
+}
+
+impl Default for CaptivePortalSettings {
+    fn default() -> Self {
+        CaptivePortalSettings {
+            enabled: false,
+            port: default_captive_portal_port(),
+            title: default_title(),
+            unregistered_message: default_unregistered_message(),
+            low_balance_message: default_low_balance_message(),
+            show_announcements: false,
+        }
+    }
+}
@@ -1,5 +1,3 @@
-use config;
-
 use serde_json;
 
 use owning_ref::{RwLockReadGuardRef, RwLockWriteGuardRefMut};
@@ -7,19 +5,26 @@ use owning_ref::{RwLockReadGuardRef, RwLockWriteGuardRefMut};
 use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, RwLock};
 
-use config::Config;
-
-use althea_types::{ExitRegistrationDetails, ExitState, Identity};
+use althea_types::{
+    Announcement, ExitRegistrationDetails, ExitState, ExitTerms, Identity, SigningPubkey,
+};
 
 use failure::Error;
 
+use ipnetwork::IpNetwork;
+
+use crate::captive_portal::CaptivePortalSettings;
 use crate::dao::SubnetDAOSettings;
 use crate::json_merge;
 use crate::localization::LocalizationSettings;
 use crate::logging::LoggingSettings;
 use crate::network::NetworkSettings;
 use crate::payment::PaymentSettings;
+use crate::protective_mode::ProtectiveModeSettings;
+use crate::provisioning::ProvisioningSettings;
+use crate::schema;
 use crate::spawn_watch_thread;
+use crate::sponsored_network::SponsoredNetworkSettings;
 use crate::RitaCommonSettings;
 
 /// This struct is used by rita to store exit specific information
@@ -34,6 +39,46 @@ pub struct ExitServer {
     /// The state and data about the exit
     #[serde(default, flatten)]
     pub info: ExitState,
+    /// The last terms of this exit's we accepted, changes that leave us worse off have to be
+    /// accepted again before we use the exit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accepted_terms: Option<ExitTerms>,
+    /// The region the exit list put this exit in, exits in our own region are preferred
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    /// From the exit list, lower is preferred when choosing between exits in the same region
+    #[serde(default)]
+    pub priority: u32,
+    /// The exit's announcements that haven't expired yet, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub announcements: Vec<Announcement>,
+    /// The id of the newest announcement from this exit the user has seen
+    #[serde(default)]
+    pub seen_announcement: u64,
+    /// The passphrase to answer this exit's signup challenge with, if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signup_passphrase: Option<String>,
+    /// Set when we were registered with this exit in a backup we've restored, we send it our
+    /// setup request on our own until it answers so that it hands over our details again
+    #[serde(default)]
+    pub restored: bool,
+}
+
+/// Where LAN traffic to a destination goes instead of following the default route out the exit
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub enum SplitTunnelAction {
+    /// Straight out our uplink, skipping the exit
+    Bypass,
+    /// Dropped
+    Deny,
+    /// Only through the exit with this name, dropped while another exit is selected
+    Exit(String),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct SplitTunnelRule {
+    pub destination: IpNetwork,
+    pub action: SplitTunnelAction,
 }
 
 fn default_balance_notification() -> bool {
@@ -59,6 +104,45 @@ pub struct ExitClientSettings {
     /// Specifies if the user would like to receive low balance messages from the exit
     #[serde(default = "default_balance_notification")]
     pub low_balance_notification: bool,
+    /// Automatically switch between registered exits based on their probed latency,
+    /// jitter and throughput as well as the route metric
+    #[serde(default)]
+    pub auto_select_exit: bool,
+    /// A prepaid voucher to redeem with the next exit we register with, cleared once we have
+    #[serde(default)]
+    pub voucher: Option<String>,
+    /// Destinations that don't simply go out the current exit, the most specific rule wins
+    #[serde(default)]
+    pub split_tunnel: Vec<SplitTunnelRule>,
+    /// The most we're willing to pay an exit in wei per byte, none for no limit
+    #[serde(default)]
+    pub max_exit_price: Option<u64>,
+    /// Move to another registered exit when our exit raises its price over `max_exit_price`
+    /// rather than waiting for the new terms to be accepted
+    #[serde(default)]
+    pub price_failover: bool,
+    /// Only hand out ipv6 addresses on the LAN and reach ipv4 through the exit's NAT64, if the
+    /// exit doesn't offer it the LAN stays dual stack
+    #[serde(default)]
+    pub ipv6_only_lan: bool,
+    /// The region we're in, exits the exit list puts in the same region are preferred
+    #[serde(default)]
+    pub region: Option<String>,
+    /// Ed25519 public key exit lists are signed with, unsigned lists are rejected unless this is
+    /// explicitly cleared. Defaults to the key Althea's published lists are signed with
+    #[serde(default = "default_exit_list_pubkey")]
+    pub exit_list_pubkey: Option<SigningPubkey>,
+    /// Sequence number of the last signed exit list we took, older lists are rejected so that
+    /// an old list can't be replayed to bring back exits that have since been dropped
+    #[serde(default)]
+    pub exit_list_sequence: u64,
+}
+
+/// The key Althea signs the exit lists it publishes with
+pub const DEFAULT_EXIT_LIST_PUBKEY: &str = "zXmQLGH7hND5sepDl/fvERiOSXxTUDuQX5zMB2pW+rs=";
+
+fn default_exit_list_pubkey() -> Option<SigningPubkey> {
+    Some(DEFAULT_EXIT_LIST_PUBKEY.parse().unwrap())
 }
 
 impl Default for ExitClientSettings {
@@ -75,6 +159,15 @@ impl Default for ExitClientSettings {
             }),
             lan_nics: HashSet::new(),
             low_balance_notification: true,
+            auto_select_exit: false,
+            voucher: None,
+            split_tunnel: Vec::new(),
+            max_exit_price: None,
+            price_failover: false,
+            ipv6_only_lan: false,
+            region: None,
+            exit_list_pubkey: default_exit_list_pubkey(),
+            exit_list_sequence: 0,
         }
     }
 }
@@ -104,6 +197,30 @@ pub trait RitaClientSettings {
     fn get_log_mut<'ret, 'me: 'ret>(
         &'me self,
     ) -> RwLockWriteGuardRefMut<'ret, RitaSettingsStruct, LoggingSettings>;
+    fn get_captive_portal<'ret, 'me: 'ret>(
+        &'me self,
+    ) -> RwLockReadGuardRef<'ret, RitaSettingsStruct, CaptivePortalSettings>;
+    fn get_captive_portal_mut<'ret, 'me: 'ret>(
+        &'me self,
+    ) -> RwLockWriteGuardRefMut<'ret, RitaSettingsStruct, CaptivePortalSettings>;
+    fn get_protective_mode<'ret, 'me: 'ret>(
+        &'me self,
+    ) -> RwLockReadGuardRef<'ret, RitaSettingsStruct, ProtectiveModeSettings>;
+    fn get_protective_mode_mut<'ret, 'me: 'ret>(
+        &'me self,
+    ) -> RwLockWriteGuardRefMut<'ret, RitaSettingsStruct, ProtectiveModeSettings>;
+    fn get_sponsored_network<'ret, 'me: 'ret>(
+        &'me self,
+    ) -> RwLockReadGuardRef<'ret, RitaSettingsStruct, SponsoredNetworkSettings>;
+    fn get_sponsored_network_mut<'ret, 'me: 'ret>(
+        &'me self,
+    ) -> RwLockWriteGuardRefMut<'ret, RitaSettingsStruct, SponsoredNetworkSettings>;
+    fn get_provisioning<'ret, 'me: 'ret>(
+        &'me self,
+    ) -> RwLockReadGuardRef<'ret, RitaSettingsStruct, ProvisioningSettings>;
+    fn get_provisioning_mut<'ret, 'me: 'ret>(
+        &'me self,
+    ) -> RwLockWriteGuardRefMut<'ret, RitaSettingsStruct, ProvisioningSettings>;
 }
 
 impl RitaClientSettings for Arc<RwLock<RitaSettingsStruct>> {
@@ -141,21 +258,65 @@ impl RitaClientSettings for Arc<RwLock<RitaSettingsStruct>> {
     ) -> RwLockWriteGuardRefMut<'ret, RitaSettingsStruct, LoggingSettings> {
         RwLockWriteGuardRefMut::new(self.write().unwrap()).map_mut(|g| &mut g.log)
     }
+
+    fn get_captive_portal<'ret, 'me: 'ret>(
+        &'me self,
+    ) -> RwLockReadGuardRef<'ret, RitaSettingsStruct, CaptivePortalSettings> {
+        RwLockReadGuardRef::new(self.read().unwrap()).map(|g| &g.captive_portal)
+    }
+
+    fn get_captive_portal_mut<'ret, 'me: 'ret>(
+        &'me self,
+    ) -> RwLockWriteGuardRefMut<'ret, RitaSettingsStruct, CaptivePortalSettings> {
+        RwLockWriteGuardRefMut::new(self.write().unwrap()).map_mut(|g| &mut g.captive_portal)
+    }
+
+    fn get_protective_mode<'ret, 'me: 'ret>(
+        &'me self,
+    ) -> RwLockReadGuardRef<'ret, RitaSettingsStruct, ProtectiveModeSettings> {
+        RwLockReadGuardRef::new(self.read().unwrap()).map(|g| &g.protective_mode)
+    }
+
+    fn get_protective_mode_mut<'ret, 'me: 'ret>(
+        &'me self,
+    ) -> RwLockWriteGuardRefMut<'ret, RitaSettingsStruct, ProtectiveModeSettings> {
+        RwLockWriteGuardRefMut::new(self.write().unwrap()).map_mut(|g| &mut g.protective_mode)
+    }
+
+    fn get_sponsored_network<'ret, 'me: 'ret>(
+        &'me self,
+    ) -> RwLockReadGuardRef<'ret, RitaSettingsStruct, SponsoredNetworkSettings> {
+        RwLockReadGuardRef::new(self.read().unwrap()).map(|g| &g.sponsored_network)
+    }
+
+    fn get_sponsored_network_mut<'ret, 'me: 'ret>(
+        &'me self,
+    ) -> RwLockWriteGuardRefMut<'ret, RitaSettingsStruct, SponsoredNetworkSettings> {
+        RwLockWriteGuardRefMut::new(self.write().unwrap()).map_mut(|g| &mut g.sponsored_network)
+    }
+
+    fn get_provisioning<'ret, 'me: 'ret>(
+        &'me self,
+    ) -> RwLockReadGuardRef<'ret, RitaSettingsStruct, ProvisioningSettings> {
+        RwLockReadGuardRef::new(self.read().unwrap()).map(|g| &g.provisioning)
+    }
+
+    fn get_provisioning_mut<'ret, 'me: 'ret>(
+        &'me self,
+    ) -> RwLockWriteGuardRefMut<'ret, RitaSettingsStruct, ProvisioningSettings> {
+        RwLockWriteGuardRefMut::new(self.write().unwrap()).map_mut(|g| &mut g.provisioning)
+    }
 }
 
 impl RitaSettingsStruct {
     pub fn new(file_name: &str) -> Result<Self, Error> {
-        let mut s = Config::new();
-        s.merge(config::File::with_name(file_name).required(false))?;
-        let settings: Self = s.try_into()?;
+        let settings: Self = schema::load(file_name)?;
 
         Ok(settings)
     }
 
     pub fn new_watched(file_name: &str) -> Result<Arc<RwLock<Self>>, Error> {
-        let mut s = Config::new();
-        s.merge(config::File::with_name(file_name).required(false))?;
-        let settings: Self = s.clone().try_into()?;
+        let settings: Self = schema::load(file_name)?;
 
         let settings = Arc::new(RwLock::new(settings));
 
@@ -174,6 +335,9 @@ impl RitaSettingsStruct {
 /// This is the main struct for rita
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Default)]
 pub struct RitaSettingsStruct {
+    /// see the schema module, set on load
+    #[serde(default)]
+    settings_version: u32,
     payment: PaymentSettings,
     #[serde(default)]
     dao: SubnetDAOSettings,
@@ -183,6 +347,14 @@ pub struct RitaSettingsStruct {
     localization: LocalizationSettings,
     network: NetworkSettings,
     exit_client: ExitClientSettings,
+    #[serde(default)]
+    captive_portal: CaptivePortalSettings,
+    #[serde(default)]
+    protective_mode: ProtectiveModeSettings,
+    #[serde(default)]
+    sponsored_network: SponsoredNetworkSettings,
+    #[serde(default)]
+    provisioning: ProvisioningSettings,
     #[serde(skip)]
     future: bool,
 }
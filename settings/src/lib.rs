@@ -48,6 +48,7 @@ use serde_json::Value;
 
 use failure::Error;
 
+pub mod captive_portal;
 pub mod client;
 pub mod dao;
 pub mod exit;
@@ -55,6 +56,10 @@ pub mod localization;
 pub mod logging;
 pub mod network;
 pub mod payment;
+pub mod protective_mode;
+pub mod provisioning;
+pub mod schema;
+pub mod sponsored_network;
 
 use crate::dao::SubnetDAOSettings;
 use crate::localization::LocalizationSettings;
@@ -162,7 +167,11 @@ where
     T: Serialize,
 {
     fn write(&self, file_name: &str) -> Result<(), Error> {
-        let ser = toml::Value::try_from(self.clone())?;
+        // through json so that what the settings couldn't hold when loaded goes back in
+        let mut ser = serde_json::to_value(self)?;
+        schema::restore_kept(&mut ser);
+        schema::remove_nulls(&mut ser);
+        let ser = toml::Value::try_from(ser)?;
         let ser = toml::to_string(&ser)?;
         let mut file = File::create(file_name)?;
         file.write_all(ser.as_bytes())?;
@@ -178,6 +187,7 @@ where
 mod tests {
     use crate::client::RitaSettingsStruct;
     use crate::exit::RitaExitSettingsStruct;
+    use crate::schema::{migrate, validate, KeptValue, SETTINGS_VERSION};
 
     #[test]
     fn test_settings_test() {
@@ -198,4 +208,42 @@ mod tests {
     fn test_exit_settings_example() {
         RitaExitSettingsStruct::new("example_exit.toml").unwrap();
     }
+
+    #[test]
+    fn test_settings_repair() {
+        let mut value = serde_json::to_value(RitaSettingsStruct::default()).unwrap();
+        value.as_object_mut().unwrap().remove("settings_version");
+        value["network"].as_object_mut().unwrap().remove("mesh_ip");
+        value["network"]["own_ip"] = "fd00::1".into();
+        let metric_factor = value["network"]["metric_factor"].clone();
+        value["network"]["metric_factor"] = "high".into();
+        migrate(&mut value).unwrap();
+        assert_eq!(value["settings_version"], SETTINGS_VERSION);
+        assert_eq!(value["network"]["mesh_ip"], "fd00::1");
+
+        value["network"]["from_the_future"] = true.into();
+
+        let mut problems = Vec::new();
+        let mut kept: Vec<KeptValue> = Vec::new();
+        let settings: RitaSettingsStruct =
+            validate(value.clone(), &mut problems, &mut kept).unwrap();
+        assert_eq!(problems.len(), 2);
+        assert_eq!(problems[0].path, "network.metric_factor");
+        assert!(problems[0].defaulted && problems[0].kept);
+        assert_eq!(problems[1].path, "network.from_the_future");
+        assert!(!problems[1].defaulted && problems[1].kept);
+        let parsed = serde_json::to_value(settings).unwrap();
+        assert_eq!(parsed["network"]["metric_factor"], metric_factor);
+        // the rest of the section is untouched
+        assert_eq!(parsed["network"]["mesh_ip"], "fd00::1");
+        assert_eq!(kept.len(), 2);
+
+        // a required value can't fall back to anything
+        value["network"]["rita_dashboard_port"] = "4877".into();
+        let mut problems = Vec::new();
+        assert!(validate::<RitaSettingsStruct>(value, &mut problems, &mut Vec::new()).is_err());
+        let fatal = problems.last().unwrap();
+        assert!(!fatal.defaulted && !fatal.kept);
+        assert_eq!(fatal.path, "network.rita_dashboard_port");
+    }
 }
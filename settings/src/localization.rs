@@ -1,3 +1,5 @@
+use althea_types::SigningPubkey;
+
 fn default_wyre_enabled() -> bool {
     true
 }
@@ -15,6 +17,16 @@ pub struct LocalizationSettings {
     // Wyre account_id used to associate transactions with a specific Wyre account
     #[serde(default = "default_wyre_account_id")]
     pub wyre_account_id: String,
+    // Currency code (such as USD) to show fiat equivalents of amounts in, none disables
+    // fiat display
+    #[serde(default)]
+    pub display_currency: Option<String>,
+    // Url of the price feed used to convert amounts to the display currency
+    #[serde(default)]
+    pub price_feed_url: Option<String>,
+    // Ed25519 public key the price feed is signed with, if set unsigned feeds are rejected
+    #[serde(default)]
+    pub price_feed_pubkey: Option<SigningPubkey>,
 }
 
 impl Default for LocalizationSettings {
@@ -22,6 +34,9 @@ impl Default for LocalizationSettings {
         LocalizationSettings {
             wyre_enabled: default_wyre_enabled(),
             wyre_account_id: default_wyre_account_id(),
+            display_currency: None,
+            price_feed_url: None,
+            price_feed_pubkey: None,
         }
     }
 }
@@ -14,6 +14,10 @@ fn default_heartbeat_url() -> String {
     "stats.altheamesh.com:33333".to_string()
 }
 
+fn default_heartbeat_busy_relay_kbps() -> u32 {
+    1000
+}
+
 /// Remote logging settings. Used to control remote logs being
 /// forwarded to the dest_url address, https is used to encrypt
 /// the logs as they travel over the internet so don't use non-https
@@ -29,6 +33,14 @@ pub struct LoggingSettings {
     /// Address and port of UDP heartbeat monitoring server
     #[serde(default = "default_heartbeat_url")]
     pub heartbeat_url: String,
+    /// Relay throughput in kbit/s that counts as a busy link, heartbeats are sent less often for
+    /// every multiple of it we're relaying. Zero sends them every tick no matter the load
+    #[serde(default = "default_heartbeat_busy_relay_kbps")]
+    pub heartbeat_busy_relay_kbps: u32,
+    /// If the WAN is metered heartbeats are sent as rarely as they can be, a backup uplink being
+    /// active is treated as metered regardless
+    #[serde(default)]
+    pub metered_wan: bool,
 }
 
 impl Default for LoggingSettings {
@@ -38,6 +50,8 @@ impl Default for LoggingSettings {
             level: default_logging_level(),
             dest_url: default_logging_dest_url(),
             heartbeat_url: default_heartbeat_url(),
+            heartbeat_busy_relay_kbps: default_heartbeat_busy_relay_kbps(),
+            metered_wan: false,
         }
     }
 }
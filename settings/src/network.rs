@@ -21,6 +21,10 @@ fn default_usage_tracker_file() -> String {
     "/etc/rita-usage-tracker.json".to_string()
 }
 
+fn default_control_socket() -> Option<String> {
+    Some("/var/run/rita.sock".to_string())
+}
+
 fn default_bandwidth_limit_enabled() -> bool {
     true
 }
@@ -33,10 +37,362 @@ fn default_starting_bandwidth_limit() -> usize {
     10_000
 }
 
+fn default_wg_end_port() -> u16 {
+    65535
+}
+
 fn default_light_client_hello_port() -> u16 {
     4878
 }
 
+fn default_nat_keepalive_interval() -> u16 {
+    5
+}
+
+fn default_tunnel_keepalive_interval() -> u16 {
+    5
+}
+
+fn default_tunnel_setup_concurrency() -> usize {
+    8
+}
+
+fn default_wan_check_ip() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1))
+}
+
+fn default_auto_clat() -> bool {
+    true
+}
+
+fn default_rate_limit_per_second() -> u32 {
+    5
+}
+
+fn default_rate_limit_burst() -> u32 {
+    20
+}
+
+fn default_rate_limit_ban_threshold() -> u32 {
+    100
+}
+
+fn default_rate_limit_ban_duration() -> u64 {
+    600 // 10 minutes
+}
+
+/// Per source token bucket limits for the endpoints we expose to the mesh, these are shared
+/// between every server so a flood of hellos also counts against signups. Routable ipv6 sources
+/// are limited per /64 since a peer can pick any address in its prefix
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct RateLimitSettings {
+    /// Tokens refilled per second, one request costs one token
+    #[serde(default = "default_rate_limit_per_second")]
+    pub requests_per_second: u32,
+    /// Size of the bucket, the number of requests a source can make in a burst
+    #[serde(default = "default_rate_limit_burst")]
+    pub burst: u32,
+    /// How many rejected requests before a source is banned outright
+    #[serde(default = "default_rate_limit_ban_threshold")]
+    pub ban_threshold: u32,
+    /// How long a ban lasts in seconds
+    #[serde(default = "default_rate_limit_ban_duration")]
+    pub ban_duration: u64,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        RateLimitSettings {
+            requests_per_second: default_rate_limit_per_second(),
+            burst: default_rate_limit_burst(),
+            ban_threshold: default_rate_limit_ban_threshold(),
+            ban_duration: default_rate_limit_ban_duration(),
+        }
+    }
+}
+
+fn default_anomaly_multiple() -> u32 {
+    10
+}
+
+fn default_anomaly_min_bytes() -> u64 {
+    10_000_000
+}
+
+fn default_anomaly_throttle_duration() -> u64 {
+    600 // 10 minutes
+}
+
+/// Detection of neighbors whose traffic suddenly jumps far above their usual amount, which
+/// otherwise only shows up as a surprise on the bill
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct TrafficAnomalySettings {
+    /// A neighbor's traffic in one round is anomalous when it exceeds their baseline by this
+    /// multiple
+    #[serde(default = "default_anomaly_multiple")]
+    pub multiple: u32,
+    /// Rounds with less traffic than this in bytes are never anomalous, so that a neighbor
+    /// who is usually idle doesn't raise alerts by browsing
+    #[serde(default = "default_anomaly_min_bytes")]
+    pub min_bytes: u64,
+    /// If set anomalous neighbors are temporarily throttled to this many mbps
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub throttle_mbps: Option<usize>,
+    /// How long a throttle lasts in seconds
+    #[serde(default = "default_anomaly_throttle_duration")]
+    pub throttle_duration: u64,
+}
+
+impl Default for TrafficAnomalySettings {
+    fn default() -> Self {
+        TrafficAnomalySettings {
+            multiple: default_anomaly_multiple(),
+            min_bytes: default_anomaly_min_bytes(),
+            throttle_mbps: None,
+            throttle_duration: default_anomaly_throttle_duration(),
+        }
+    }
+}
+
+fn default_qos_priority_rate() -> u32 {
+    2_000
+}
+
+fn default_qos_max_rate() -> u32 {
+    1_000_000
+}
+
+/// Prioritization of latency sensitive traffic on the wg tunnels to our neighbors. Packets
+/// marked EF, CS5 or CS6 and small packets (voip, games, dns, acks) go to a priority class
+/// that is served first, everything else shares what's left
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct TunnelQosSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Rate in kbit/s guaranteed to the priority class, it can borrow more when the tunnel is
+    /// idle
+    #[serde(default = "default_qos_priority_rate")]
+    pub priority_rate: u32,
+    /// Rate in kbit/s of the whole tunnel when it isn't being shaped for bloat or payment
+    #[serde(default = "default_qos_max_rate")]
+    pub max_rate: u32,
+}
+
+impl Default for TunnelQosSettings {
+    fn default() -> Self {
+        TunnelQosSettings {
+            enabled: false,
+            priority_rate: default_qos_priority_rate(),
+            max_rate: default_qos_max_rate(),
+        }
+    }
+}
+
+/// A service on this node or its LAN registered with the dashboard
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct LocalService {
+    pub name: String,
+    pub port: u16,
+    #[serde(default)]
+    pub description: String,
+}
+
+/// Opt in service discovery, the services registered here are gossiped to neighbors along with
+/// everything we've heard from them and the whole table is listed on the dashboard
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Default)]
+pub struct ServiceDiscoverySettings {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub services: Vec<LocalService>,
+}
+
+fn default_sla_check_interval() -> u64 {
+    60
+}
+
+fn default_sla_file() -> String {
+    "/etc/rita-sla.json".to_string()
+}
+
+/// Uptime targets in hundredths of a percent, 9990 is 99.9%. Reports show whether each period
+/// met its target, tiers without one are only measured
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Default)]
+pub struct SlaTargets {
+    /// the selected exit answering over the exit tunnel, clients only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit: Option<u32>,
+    /// the internet answering over our own uplink, gateways and exits only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wan: Option<u32>,
+    /// having at least one live neighbor on the mesh
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mesh: Option<u32>,
+}
+
+/// Uptime monitoring for the reports community ISPs give their members
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct SlaSettings {
+    /// Seconds between checks, each check counts as one interval up or down
+    #[serde(default = "default_sla_check_interval")]
+    pub check_interval: u64,
+    /// Where the uptime history is kept across restarts
+    #[serde(default = "default_sla_file")]
+    pub file: String,
+    #[serde(default)]
+    pub targets: SlaTargets,
+}
+
+impl Default for SlaSettings {
+    fn default() -> Self {
+        SlaSettings {
+            check_interval: default_sla_check_interval(),
+            file: default_sla_file(),
+            targets: SlaTargets::default(),
+        }
+    }
+}
+
+fn default_health_memory_percent() -> u8 {
+    90
+}
+
+fn default_health_storage_free() -> u64 {
+    1024 // 1MB
+}
+
+fn default_health_conntrack_percent() -> u8 {
+    80
+}
+
+fn default_health_temperature() -> u32 {
+    90
+}
+
+/// Limits past which the router's resources are journaled as a watchdog warning when they're
+/// checked every slow loop tick
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct SystemHealthSettings {
+    /// Percent of memory in use, counting what the kernel could reclaim as free
+    #[serde(default = "default_health_memory_percent")]
+    pub memory_percent: u8,
+    /// Free space on the overlay (or root) filesystem, in kB
+    #[serde(default = "default_health_storage_free")]
+    pub storage_free_kb: u64,
+    /// Percent of the conntrack table in use, past it new connections are dropped
+    #[serde(default = "default_health_conntrack_percent")]
+    pub conntrack_percent: u8,
+    /// Hottest thermal zone in degrees celsius
+    #[serde(default = "default_health_temperature")]
+    pub temperature: u32,
+    /// Rita's own resident memory in kB, None for no limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rita_rss_kb: Option<u64>,
+}
+
+impl Default for SystemHealthSettings {
+    fn default() -> Self {
+        SystemHealthSettings {
+            memory_percent: default_health_memory_percent(),
+            storage_free_kb: default_health_storage_free(),
+            conntrack_percent: default_health_conntrack_percent(),
+            temperature: default_health_temperature(),
+            rita_rss_kb: None,
+        }
+    }
+}
+
+fn default_max_clock_skew() -> u64 {
+    300
+}
+
+fn default_ntp_servers() -> Vec<String> {
+    vec![
+        "0.openwrt.pool.ntp.org".to_string(),
+        "1.openwrt.pool.ntp.org".to_string(),
+    ]
+}
+
+/// How far our clock may drift from the clocks of our neighbors and exit before we stop trusting
+/// it, see the time sanity module in rita_common
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct TimeSanitySettings {
+    /// Seconds of skew past which the clock is resynced and debts aren't enforced
+    #[serde(default = "default_max_clock_skew")]
+    pub max_skew: u64,
+    /// Servers to resync from, empty to never touch the clock
+    #[serde(default = "default_ntp_servers")]
+    pub ntp_servers: Vec<String>,
+}
+
+impl Default for TimeSanitySettings {
+    fn default() -> Self {
+        TimeSanitySettings {
+            max_skew: default_max_clock_skew(),
+            ntp_servers: default_ntp_servers(),
+        }
+    }
+}
+
+fn default_hello_interval() -> u32 {
+    4000
+}
+
+fn default_max_rtt_penalty() -> u16 {
+    500
+}
+
+fn default_rtt_min() -> u16 {
+    10
+}
+
+fn default_rtt_max() -> u16 {
+    120
+}
+
+fn default_max_reputation_penalty() -> u16 {
+    300
+}
+
+/// Babel's per interface parameters, applied to every tunnel we have babel monitor
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct BabelSettings {
+    /// How often hellos are sent in milliseconds, a shorter interval notices a dead link sooner
+    /// at the cost of more chatter
+    #[serde(default = "default_hello_interval")]
+    pub hello_interval: u32,
+    /// The most that is added to a link's cost when its rtt is high, 0 turns rtt based metrics off
+    #[serde(default = "default_max_rtt_penalty")]
+    pub max_rtt_penalty: u16,
+    /// The rtt in milliseconds below which a link isn't penalized at all
+    #[serde(default = "default_rtt_min")]
+    pub rtt_min: u16,
+    /// The rtt in milliseconds at which a link gets the full penalty
+    #[serde(default = "default_rtt_max")]
+    pub rtt_max: u16,
+    /// The most, in percent, that is added to a link's cost when the neighbor on the other end
+    /// pays late, disagrees with our traffic counts or drops out often, 0 turns it off
+    #[serde(default = "default_max_reputation_penalty")]
+    pub max_reputation_penalty: u16,
+    /// Smooth each tunnel's rtt over a couple of minutes and add the rtt penalty to the
+    /// tunnel's cost ourselves, rather than having babel penalize the latest few measurements
+    #[serde(default)]
+    pub smooth_rtt: bool,
+}
+
+impl Default for BabelSettings {
+    fn default() -> Self {
+        BabelSettings {
+            hello_interval: default_hello_interval(),
+            max_rtt_penalty: default_max_rtt_penalty(),
+            rtt_min: default_rtt_min(),
+            rtt_max: default_rtt_max(),
+            max_reputation_penalty: default_max_reputation_penalty(),
+            smooth_rtt: false,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct NetworkSettings {
     /// How much non-financial metrics matter compared to a route's cost. By default a 2x more
@@ -69,6 +425,9 @@ pub struct NetworkSettings {
     pub rita_dashboard_port: u16,
     /// The password for dashboard authentication
     pub rita_dashboard_password: Option<String>,
+    /// Unix socket the dashboard api is also served on for rita-ctl, None to disable it
+    #[serde(default = "default_control_socket")]
+    pub control_socket: Option<String>,
     /// Port over which the bounty hunter will be contacted
     pub bounty_port: u16,
     /// The tick interval in seconds between rita hellos, traffic watcher measurements and payments
@@ -84,6 +443,14 @@ pub struct NetworkSettings {
     /// The starting port for per hop tunnels, is a range as we need a different wg interface for
     /// each neighbor to enable billing, and each wg interface needs an unique port.
     pub wg_start_port: u16,
+    /// The end of the per hop tunnel port range, tunnels use ports from `wg_start_port` up to
+    /// but not including this one
+    #[serde(default = "default_wg_end_port")]
+    pub wg_end_port: u16,
+    /// Ports within the tunnel range used by other services on this device, these are never
+    /// handed out to tunnels. Rita's own ports are always excluded and don't need to be listed
+    #[serde(default)]
+    pub wg_reserved_ports: Vec<u16>,
     /// Interfaces on which we accept rita hellos
     pub peer_interfaces: HashSet<String>,
     /// List of URLs/IPs which we will manually send hellos to, used when neighbor detection fails,
@@ -97,9 +464,22 @@ pub struct NetworkSettings {
     /// globally routable ip
     #[serde(skip_serializing_if = "Option::is_none")]
     pub external_nic: Option<String>,
+    /// A secondary uplink, such as an LTE modem (wwan0/usb0), which the default route is moved
+    /// to when the external_nic fails its connectivity checks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup_external_nic: Option<String>,
+    /// An internet host pinged over the uplinks to determine if they are actually working
+    #[serde(default = "default_wan_check_ip")]
+    pub wan_check_ip: IpAddr,
     /// This in memory variable specifies if we are a gateway or not
     #[serde(skip_deserializing, default)]
     pub is_gateway: bool,
+    /// This in memory variable is true if our external_nic only has IPv6
+    #[serde(skip_deserializing, default)]
+    pub ipv6_only_wan: bool,
+    /// Set up 464XLAT when our external_nic only has IPv6, so IPv4 peers and exits stay reachable
+    #[serde(default = "default_auto_clat")]
+    pub auto_clat: bool,
     /// How long do we wait without contact from a peer before we delete the associated tunnel?
     #[serde(default = "default_tunnel_timeout")]
     pub tunnel_timeout_seconds: u64,
@@ -127,6 +507,56 @@ pub struct NetworkSettings {
     /// the maximum bandwidth of the fastest interface of the device.
     #[serde(default = "default_starting_bandwidth_limit")]
     pub starting_bandwidth_limit: usize,
+    /// Rate limits applied to the hello, payment and exit endpoints
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+    /// Refuse to open tunnels with neighbors that can't prove they hold the private key for
+    /// the wg key they advertise. Authentication is always attempted, this only controls if
+    /// older nodes that don't support it are still accepted. Off by default until routers on
+    /// releases without hello auth are out of the field, see hello_handler::auth
+    #[serde(default)]
+    pub require_hello_auth: bool,
+    /// Interval in seconds of the WireGuard keepalives every tunnel is opened with, 0 for none
+    #[serde(default = "default_tunnel_keepalive_interval")]
+    pub tunnel_keepalive_interval: u16,
+    /// Interval in seconds of the WireGuard keepalives sent to peers that appear to be behind a
+    /// NAT (their endpoint keeps changing), replacing `tunnel_keepalive_interval` for them. 0
+    /// leaves their tunnels alone
+    #[serde(default = "default_nat_keepalive_interval")]
+    pub nat_keepalive_interval: u16,
+    /// How many tunnels to new neighbors are set up per tick, the rest wait for the following
+    /// ticks. 0 removes the limit
+    #[serde(default = "default_tunnel_setup_concurrency")]
+    pub tunnel_setup_concurrency: usize,
+    /// Alerts and optional throttling for neighbors with anomalous traffic
+    #[serde(default)]
+    pub traffic_anomaly: TrafficAnomalySettings,
+    /// Prioritization of latency sensitive traffic on wg tunnels
+    #[serde(default)]
+    pub tunnel_qos: TunnelQosSettings,
+    /// Babel tuning for our tunnels
+    #[serde(default)]
+    pub babel: BabelSettings,
+    /// Gossip of the services nodes offer to the mesh
+    #[serde(default)]
+    pub service_discovery: ServiceDiscoverySettings,
+    /// Exit, uplink and mesh uptime monitoring
+    #[serde(default)]
+    pub sla: SlaSettings,
+    /// Warning thresholds for the router's own resources
+    #[serde(default)]
+    pub system_health: SystemHealthSettings,
+    /// Checking our clock against our neighbors' and exit's
+    #[serde(default)]
+    pub time_sanity: TimeSanitySettings,
+    /// Neighbors we've stopped meshing with for now, by wg key. We don't open tunnels to them or
+    /// answer their hellos until they're resumed
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub paused_neighbors: HashSet<WgKey>,
+    /// Neighbors that have authenticated a hello with us, by wg key. Their hellos are refused
+    /// unless authenticated whatever features they advertise
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub authenticated_neighbors: HashSet<WgKey>,
 }
 
 impl Default for NetworkSettings {
@@ -146,21 +576,42 @@ impl Default for NetworkSettings {
             light_client_router_ip: None,
             rita_dashboard_port: 4877,
             rita_dashboard_password: None,
+            control_socket: default_control_socket(),
             bounty_port: 8888,
             rita_tick_interval: 5,
             wg_private_key: None,
             wg_private_key_path: String::new(),
             wg_public_key: None,
             wg_start_port: 60000,
+            wg_end_port: default_wg_end_port(),
+            wg_reserved_ports: Vec::new(),
             peer_interfaces: HashSet::new(),
             manual_peers: Vec::new(),
             external_nic: None,
+            backup_external_nic: None,
+            wan_check_ip: default_wan_check_ip(),
             default_route: Vec::new(),
             is_gateway: false,
+            ipv6_only_wan: false,
+            auto_clat: default_auto_clat(),
             tunnel_timeout_seconds: default_tunnel_timeout(),
             device: None,
             nickname: None,
             usage_tracker_file: default_usage_tracker_file(),
+            rate_limit: RateLimitSettings::default(),
+            require_hello_auth: false,
+            tunnel_keepalive_interval: default_tunnel_keepalive_interval(),
+            nat_keepalive_interval: default_nat_keepalive_interval(),
+            tunnel_setup_concurrency: default_tunnel_setup_concurrency(),
+            traffic_anomaly: TrafficAnomalySettings::default(),
+            tunnel_qos: TunnelQosSettings::default(),
+            babel: BabelSettings::default(),
+            service_discovery: ServiceDiscoverySettings::default(),
+            sla: SlaSettings::default(),
+            system_health: SystemHealthSettings::default(),
+            time_sanity: TimeSanitySettings::default(),
+            paused_neighbors: HashSet::new(),
+            authenticated_neighbors: HashSet::new(),
         }
     }
 }
@@ -71,6 +71,123 @@ fn default_debts_file() -> String {
     "/etc/rita-debts.json".to_string()
 }
 
+fn default_debt_ledger_file() -> String {
+    "/etc/rita-debt-ledger.json".to_string()
+}
+
+fn default_debt_ledger_max_size() -> u64 {
+    2_000_000
+}
+
+fn default_unacked_payments_file() -> String {
+    "/etc/rita-unacked-payments.json".to_string()
+}
+
+fn default_payment_history_file() -> String {
+    "/etc/rita-payment-history.json".to_string()
+}
+
+fn default_daily_spending_file() -> String {
+    "/etc/rita-daily-spending.json".to_string()
+}
+
+fn default_guac_credited_file() -> String {
+    "/etc/rita-guac-credited.json".to_string()
+}
+
+fn default_debt_journal_file() -> String {
+    "/etc/rita-debt-journal.json".to_string()
+}
+
+fn default_iou_cap() -> Uint256 {
+    // the same as the default close threshold
+    8_400_000_000_000_000u64.into()
+}
+
+/// Settings for routers on intermittent links, see debt_keeper::journal in rita
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct DebtJournalSettings {
+    /// Exchange IOUs with neighbors for debts that can't be paid right now
+    #[serde(default)]
+    pub enabled: bool,
+    /// The most of a neighbor's IOU we count, debt beyond the close threshold is only forgiven
+    /// up to this amount before we enforce
+    #[serde(default = "default_iou_cap")]
+    pub iou_cap: Uint256,
+    /// Full file path for the IOUs we've signed and received
+    #[serde(default = "default_debt_journal_file")]
+    pub journal_file: String,
+}
+
+impl Default for DebtJournalSettings {
+    fn default() -> Self {
+        DebtJournalSettings {
+            enabled: false,
+            iou_cap: default_iou_cap(),
+            journal_file: default_debt_journal_file(),
+        }
+    }
+}
+
+fn default_billing_audit_hours() -> u32 {
+    24
+}
+
+/// Settings for the billing audit trail, see traffic_watcher::audit in rita
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct BillingAuditSettings {
+    /// Record what each neighbor was billed for each destination and at what price
+    #[serde(default)]
+    pub enabled: bool,
+    /// How many hours of samples are kept, in memory
+    #[serde(default = "default_billing_audit_hours")]
+    pub hours: u32,
+}
+
+impl Default for BillingAuditSettings {
+    fn default() -> Self {
+        BillingAuditSettings {
+            enabled: false,
+            hours: default_billing_audit_hours(),
+        }
+    }
+}
+
+/// A time of day window with its own prices and free tier, like cheaper night rates
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct ScheduleWindow {
+    pub name: String,
+    /// The days of the week the window starts on, 0 is Monday. Empty for every day
+    #[serde(default)]
+    pub days: Vec<u8>,
+    /// Minutes after midnight, a window with an end before its start runs past midnight, they
+    /// can't be equal
+    pub start: u16,
+    pub end: u16,
+    /// Replaces local_fee while the window is active
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_fee: Option<u32>,
+    /// Replaces the exit price while the window is active, only used on exits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_price: Option<u64>,
+    /// Replaces free_tier_throughput while the window is active
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub free_tier_throughput: Option<u32>,
+}
+
+/// Price and bandwidth windows, see schedule in rita. When windows overlap the first one listed
+/// wins
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Default)]
+pub struct ScheduleSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Minutes east of UTC the window times are in, routers keep their clocks in UTC
+    #[serde(default)]
+    pub utc_offset: i16,
+    #[serde(default)]
+    pub windows: Vec<ScheduleWindow>,
+}
+
 fn default_bridge_addresses() -> TokenBridgeAddresses {
     TokenBridgeAddresses {
         uniswap_address: Address::from_str("0x2a1530C4C41db0B0b2bB646CB5Eb1A67b7158667").unwrap(),
@@ -111,6 +228,86 @@ fn default_max_gas() -> u64 {
     XDAI_MAX_GAS
 }
 
+fn default_signer_timeout() -> u64 {
+    10
+}
+
+/// What happens to a payment when the external signer can't be reached or won't sign it
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum SignerFallback {
+    /// The payment fails and the debt is paid with the next round of payments
+    Fail,
+    /// The payment is signed with eth_private_key instead, if there is one
+    LocalKey,
+}
+
+impl Default for SignerFallback {
+    fn default() -> Self {
+        SignerFallback::Fail
+    }
+}
+
+/// A signing service holding our eth key so that it doesn't have to be stored on the router,
+/// for example a small daemon in front of a usb hardware wallet
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct ExternalSignerSettings {
+    /// Base url of the signer, unsigned transactions are posted to `<url>/sign`
+    pub url: String,
+    /// The address the signer holds the key for, used as our eth address
+    pub address: Address,
+    /// Seconds to wait for a signature
+    #[serde(default = "default_signer_timeout")]
+    pub timeout_seconds: u64,
+    #[serde(default)]
+    pub fallback: SignerFallback,
+}
+
+/// What to do when the route to our exit is priced over max_fee
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceCapPolicy {
+    /// Pay max_fee for the route anyway and journal that the price is being capped, the
+    /// neighbors along it may cut us off for underpaying
+    Cap,
+    /// Have babel route around the neighbor so that it picks a route within max_fee, capping as
+    /// above when there isn't one
+    Refuse,
+}
+
+impl Default for PriceCapPolicy {
+    fn default() -> Self {
+        PriceCapPolicy::Cap
+    }
+}
+
+/// What an outgoing payment is for, each purpose can be paid out of its own wallet
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Hash, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentPurpose {
+    /// paying neighbors for forwarding our traffic
+    Relay,
+    /// paying our exit for internet access
+    Exit,
+}
+
+/// An operational wallet kept apart from the main one, so that for example exit bills can be
+/// paid out of a wallet topped up for that while relay earnings pile up in the main one
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct WalletSettings {
+    pub eth_private_key: PrivateKey,
+    /// The payments made out of this wallet, purposes no wallet lists are paid from the main one
+    pub purposes: Vec<PaymentPurpose>,
+    /// The most this wallet may send in a day in wei, payments past it are refused until the
+    /// next day starts
+    #[serde(default)]
+    pub daily_cap: Option<Uint256>,
+    /// Kept up to date by the oracle
+    #[serde(default)]
+    pub balance: Uint256,
+    #[serde(default)]
+    pub nonce: Uint256,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct TokenBridgeAddresses {
     pub uniswap_address: Address,
@@ -142,6 +339,9 @@ pub struct PaymentSettings {
     /// false the NAT rule will be removed while the router is in the low balance state
     #[serde(default = "default_client_can_use_free_tier")]
     pub client_can_use_free_tier: bool,
+    /// What to do when the route to our exit is priced over max_fee
+    #[serde(default)]
+    pub price_cap_policy: PriceCapPolicy,
     /// The threshold above which we will kick off a payment
     #[serde(default = "default_pay_threshold")]
     pub pay_threshold: Int256,
@@ -179,6 +379,21 @@ pub struct PaymentSettings {
     /// Full file path for Debts storage
     #[serde(default = "default_debts_file")]
     pub debts_file: String,
+    /// Full file path for the append only debt ledger, a history of every traffic update
+    /// and payment per counterparty
+    #[serde(default = "default_debt_ledger_file")]
+    pub debt_ledger_file: String,
+    /// Size in bytes at which the debt ledger is rotated, one rotated file is kept
+    #[serde(default = "default_debt_ledger_max_size")]
+    pub debt_ledger_max_size: u64,
+    /// Full file path for payments we've published that our neighbor hasn't acknowledged yet,
+    /// they're sent again after a restart
+    #[serde(default = "default_unacked_payments_file")]
+    pub unacked_payments_file: String,
+    /// Full file path for the payments we've credited and the payment ids we've seen, so that
+    /// they can't be replayed after a restart
+    #[serde(default = "default_payment_history_file")]
+    pub payment_history_file: String,
     #[serde(default = "default_bridge_enabled")]
     pub bridge_enabled: bool,
     /// A value used to divide and add to a payment, essentailly a cheating tool for
@@ -201,6 +416,13 @@ pub struct PaymentSettings {
     /// on deposit
     #[serde(default = "default_debt_limit_enabled")]
     pub debt_limit_enabled: bool,
+    #[serde(default)]
+    pub debt_journal: DebtJournalSettings,
+    #[serde(default)]
+    pub billing_audit: BillingAuditSettings,
+    /// Time of day windows for local_fee, the exit price and the free tier
+    #[serde(default)]
+    pub schedule: ScheduleSettings,
     /// Token Bridge addresses
     #[serde(default = "default_bridge_addresses")]
     pub bridge_addresses: TokenBridgeAddresses,
@@ -218,6 +440,28 @@ pub struct PaymentSettings {
     /// the minimum we will pay for gas on our current blockchain
     #[serde(default = "default_min_gas")]
     pub min_gas: u64,
+    /// Url of the local Guac light client, when set payments are made over payment channels
+    /// where we have one open with the neighbor and incoming channel payments are accepted
+    #[serde(default)]
+    pub guac_url: Option<String>,
+    /// Full file path for the channel totals we have credited neighbors with, without it a
+    /// restart would credit the next update on every channel again
+    #[serde(default = "default_guac_credited_file")]
+    pub guac_credited_file: String,
+    /// When set transactions are signed by this service instead of with eth_private_key, which
+    /// is then only used for the token bridge, payment receipts and as a fallback
+    #[serde(default)]
+    pub external_signer: Option<ExternalSignerSettings>,
+    /// Wallets other than the main one that some kinds of payments are made from
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub wallets: Vec<WalletSettings>,
+    /// The most the main wallet may send in a day in wei
+    #[serde(default)]
+    pub daily_spending_cap: Option<Uint256>,
+    /// Full file path for what each wallet has spent today, so that a restart doesn't reset the
+    /// daily caps
+    #[serde(default = "default_daily_spending_file")]
+    pub daily_spending_file: String,
 }
 
 impl Default for PaymentSettings {
@@ -225,6 +469,7 @@ impl Default for PaymentSettings {
         PaymentSettings {
             local_fee: default_local_fee(),
             max_fee: default_max_fee(),
+            price_cap_policy: PriceCapPolicy::default(),
             dynamic_fee_multiplier: default_dynamic_fee_multiplier(),
             free_tier_throughput: default_free_tier_throughput(),
             client_can_use_free_tier: default_client_can_use_free_tier(),
@@ -245,15 +490,28 @@ impl Default for PaymentSettings {
             system_chain: default_system_chain(),
             withdraw_chain: default_system_chain(),
             debts_file: default_debts_file(),
+            debt_ledger_file: default_debt_ledger_file(),
+            debt_ledger_max_size: default_debt_ledger_max_size(),
+            unacked_payments_file: default_unacked_payments_file(),
+            payment_history_file: default_payment_history_file(),
             bridge_enabled: default_bridge_enabled(),
             fudge_factor: 0u8,
             debt_limit_enabled: default_debt_limit_enabled(),
+            debt_journal: DebtJournalSettings::default(),
+            billing_audit: BillingAuditSettings::default(),
+            schedule: ScheduleSettings::default(),
             apply_incoming_credit_immediately: default_apply_incoming_credit(),
             bridge_addresses: default_bridge_addresses(),
             simulated_transaction_fee_address: default_simulated_transaction_fee_address(),
             simulated_transaction_fee: default_simulated_transaction_fee(),
             min_gas: default_min_gas(),
             max_gas: default_max_gas(),
+            guac_url: None,
+            guac_credited_file: default_guac_credited_file(),
+            external_signer: None,
+            wallets: Vec::new(),
+            daily_spending_cap: None,
+            daily_spending_file: default_daily_spending_file(),
         }
     }
 }
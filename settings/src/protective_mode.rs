@@ -0,0 +1,35 @@
+use num256::Uint256;
+
+fn default_threshold() -> Uint256 {
+    (2_000_000_000_000_000u64).into()
+}
+
+fn default_trickle_mbps() -> usize {
+    1
+}
+
+/// Settings for protective mode, which stretches out what's left in a nearly empty wallet
+/// rather than letting the router get cut off abruptly. While the balance is below the threshold
+/// LAN traffic is limited to a trickle and the captive portal shows the low balance notice
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct ProtectiveModeSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    /// The balance in wei below which protective mode kicks in, it's lifted once the balance is
+    /// topped up above it again
+    #[serde(default = "default_threshold")]
+    pub threshold: Uint256,
+    /// How fast the LAN can download while protective mode is active in mbit/s
+    #[serde(default = "default_trickle_mbps")]
+    pub trickle_mbps: usize,
+}
+
+impl Default for ProtectiveModeSettings {
+    fn default() -> Self {
+        ProtectiveModeSettings {
+            enabled: false,
+            threshold: default_threshold(),
+            trickle_mbps: default_trickle_mbps(),
+        }
+    }
+}
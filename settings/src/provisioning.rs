@@ -0,0 +1,86 @@
+use althea_types::SigningPubkey;
+
+/// How to reach whoever runs the network this router was provisioned for
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct OperatorContact {
+    pub name: String,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub phone: Option<String>,
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
+fn default_checkin_interval() -> u64 {
+    300
+}
+
+fn default_audit_log_file() -> String {
+    "/etc/rita-remote-audit.json".to_string()
+}
+
+/// Commands from the operator, like a new price or exit list, picked up when we check in with
+/// them and only applied if signed with their key
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct RemoteManagementSettings {
+    /// Ed25519 public key commands must be signed with, without one no command is ever applied
+    #[serde(default)]
+    pub pubkey: Option<SigningPubkey>,
+    /// Where to check in for commands, must be https
+    #[serde(default)]
+    pub checkin_url: Option<String>,
+    /// Seconds between check ins
+    #[serde(default = "default_checkin_interval")]
+    pub checkin_interval: u64,
+    /// The sequence number of the last command taken, anything at or below it is a replay
+    #[serde(default)]
+    pub last_command: u64,
+    /// Where every command we're sent is logged along with what came of it
+    #[serde(default = "default_audit_log_file")]
+    pub audit_log_file: String,
+}
+
+impl Default for RemoteManagementSettings {
+    fn default() -> Self {
+        RemoteManagementSettings {
+            pubkey: None,
+            checkin_url: None,
+            checkin_interval: default_checkin_interval(),
+            last_command: 0,
+            audit_log_file: default_audit_log_file(),
+        }
+    }
+}
+
+/// Settings for zero touch provisioning. Images built for an operator ship with a profile url or
+/// file and the key profiles are signed with, a router that has never been set up fetches the
+/// profile on first boot and applies it instead of waiting for someone to go through the
+/// dashboard. Without a key nothing is ever applied
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Default)]
+pub struct ProvisioningSettings {
+    /// Where to download the profile from, must be https
+    #[serde(default)]
+    pub profile_url: Option<String>,
+    /// A path to read the profile from, say on a usb stick, tried before the url
+    #[serde(default)]
+    pub profile_file: Option<String>,
+    /// Ed25519 public key profiles must be signed with
+    #[serde(default)]
+    pub pubkey: Option<SigningPubkey>,
+    /// The id of the profile that was applied, once set we never provision again
+    #[serde(default)]
+    pub applied_profile: Option<String>,
+    /// If the operator has been told we applied the profile
+    #[serde(default)]
+    pub adoption_reported: bool,
+    /// Where to report adoption to, from the profile
+    #[serde(default)]
+    pub adoption_url: Option<String>,
+    /// From the profile, shown on the dashboard
+    #[serde(default)]
+    pub operator_contact: Option<OperatorContact>,
+    /// Signed commands from the operator once we're set up
+    #[serde(default)]
+    pub remote_management: RemoteManagementSettings,
+}
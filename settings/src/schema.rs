@@ -0,0 +1,321 @@
+//! Versioning, migration and validation of the settings file. Settings are hand edited often
+//! enough that a single value of the wrong type can't be allowed to take down Rita, or worse
+//! silently break the subsystem it belongs to. So loading goes like this
+//!
+//! 1. the file is read as untyped json and fields that have been renamed since the version it was
+//!    written with are moved to their new names
+//! 2. it is then parsed, any value serde chokes on is set aside so that its default is used
+//!    instead and the problem is recorded, these are served on the `/settings/problems` endpoint.
+//!    Only single values are ever set aside, never a whole section, so one bad value can't take
+//!    its neighbors with it. Values the settings have no field for are recorded too
+//! 3. Rita writes the settings back out once loaded, backfilling every new default. The values
+//!    that were set aside and those without a field are written back as they were, unless the
+//!    setting has been changed since, so that nothing in the file is lost to a typo or to an older
+//!    build that doesn't know about a setting
+//!
+//! Only problems with required values that have no default stop Rita from starting.
+
+use config::Config;
+use failure::{bail, Error};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::sync::RwLock;
+
+/// The settings version written by this build, bump it when adding a migration
+pub const SETTINGS_VERSION: u32 = 1;
+
+const VERSION_KEY: &str = "settings_version";
+
+/// Fields renamed between versions as dotted paths, `MIGRATIONS[n]` takes a version n file to
+/// version n + 1. Files from before versioning are version 0
+const MIGRATIONS: &[&[(&str, &str)]] = &[
+    // 0 -> 1
+    &[("network.own_ip", "network.mesh_ip")],
+];
+
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct SettingsProblem {
+    /// dotted path to the offending value, empty if it's the file as a whole
+    pub path: String,
+    pub problem: String,
+    /// if the default was used in place of the value
+    pub defaulted: bool,
+    /// if the value is kept in the file as it was, problems with neither set were fatal
+    #[serde(default)]
+    pub kept: bool,
+}
+
+/// A value from the file the settings couldn't hold, written back out in its place
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeptValue {
+    path: Vec<String>,
+    value: Value,
+    /// what the settings had there instead when loaded, if that has changed since the change wins
+    replaced_by: Option<Value>,
+}
+
+lazy_static! {
+    static ref PROBLEMS: RwLock<Vec<SettingsProblem>> = RwLock::new(Vec::new());
+    static ref KEPT: RwLock<Vec<KeptValue>> = RwLock::new(Vec::new());
+}
+
+/// The problems found when the settings were last loaded
+pub fn get_problems() -> Vec<SettingsProblem> {
+    PROBLEMS.read().unwrap().clone()
+}
+
+fn get_path<'a>(value: &'a Value, path: &[String]) -> Option<&'a Value> {
+    let mut current = value;
+    for key in path {
+        current = current.as_object()?.get(key)?;
+    }
+    Some(current)
+}
+
+fn take_path(value: &mut Value, path: &[String]) -> Option<Value> {
+    let (last, parents) = path.split_last()?;
+    let mut current = value;
+    for key in parents {
+        current = current.as_object_mut()?.get_mut(key)?;
+    }
+    current.as_object_mut()?.remove(last)
+}
+
+fn put_path(value: &mut Value, path: &[String], new: Value) {
+    let mut current = value;
+    for key in path {
+        if !current.is_object() {
+            *current = Value::Object(Map::new());
+        }
+        current = current
+            .as_object_mut()
+            .unwrap()
+            .entry(key.clone())
+            .or_insert(Value::Null);
+    }
+    *current = new;
+}
+
+fn split(path: &str) -> Vec<String> {
+    path.split('.').map(|s| s.to_string()).collect()
+}
+
+/// Brings settings written by any older version up to `SETTINGS_VERSION`
+pub fn migrate(settings: &mut Value) -> Result<(), Error> {
+    let version = match settings.get(VERSION_KEY) {
+        Some(val) => match val.as_u64() {
+            Some(val) => val as usize,
+            None => bail!("{} must be a number not {}", VERSION_KEY, val),
+        },
+        None => 0,
+    };
+    if version > SETTINGS_VERSION as usize {
+        bail!(
+            "Settings are version {}, newer than the {} this build understands",
+            version,
+            SETTINGS_VERSION
+        );
+    }
+
+    for (from, renames) in MIGRATIONS.iter().enumerate().skip(version) {
+        for (old, new) in renames.iter() {
+            // a file could contain both if it was edited by hand, the new name wins
+            if let Some(val) = take_path(settings, &split(old)) {
+                if get_path(settings, &split(new)).is_none() {
+                    info!("Settings v{}: moving {} to {}", from, old, new);
+                    put_path(settings, &split(new), val);
+                }
+            }
+        }
+    }
+    if let Value::Object(map) = settings {
+        map.insert(VERSION_KEY.to_string(), SETTINGS_VERSION.into());
+    }
+    Ok(())
+}
+
+fn parse<T: DeserializeOwned>(settings: &Value) -> Result<T, String> {
+    serde_json::from_value(settings.clone()).map_err(|e| e.to_string())
+}
+
+/// Finds the value serde is choking on, that's the value whose removal gets us past the current
+/// error without trading it for the value being missing. Sections are searched but never removed
+/// as a whole. Err is a required value that's the culprit, if one was seen
+fn find_culprit<T: DeserializeOwned>(
+    settings: &Value,
+    path: &mut Vec<String>,
+    error: &str,
+) -> Result<Vec<String>, Option<Vec<String>>> {
+    let keys: Vec<String> = match get_path(settings, path) {
+        Some(Value::Object(map)) => map.keys().cloned().collect(),
+        _ => return Err(None),
+    };
+    let mut required = None;
+    for key in keys {
+        path.push(key);
+        if let Some(Value::Object(_)) = get_path(settings, path) {
+            match find_culprit::<T>(settings, path, error) {
+                Ok(found) => return Ok(found),
+                Err(found) => required = required.or(found),
+            }
+            path.pop();
+            continue;
+        }
+        let mut candidate = settings.clone();
+        take_path(&mut candidate, path);
+        match parse::<T>(&candidate) {
+            Ok(_) => return Ok(path.clone()),
+            Err(ref e) if e == error => {}
+            Err(ref e) if e.starts_with("missing field") => {
+                // only the culprit if nothing else is, a problem elsewhere could also
+                // turn into this value missing once it's out of the way
+                if required.is_none() {
+                    required = Some(path.clone());
+                }
+            }
+            Err(_) => return Ok(path.clone()),
+        }
+        path.pop();
+    }
+    Err(required)
+}
+
+/// Every value in the file that the parsed settings have no field for
+fn find_unknown(file: &Value, parsed: &Value, path: &mut Vec<String>, found: &mut Vec<KeptValue>) {
+    let (file, parsed) = match (file, parsed) {
+        (Value::Object(file), Value::Object(parsed)) => (file, parsed),
+        _ => return,
+    };
+    for (key, value) in file {
+        path.push(key.clone());
+        match parsed.get(key) {
+            Some(parsed) => find_unknown(value, parsed, path, found),
+            None => found.push(KeptValue {
+                path: path.clone(),
+                value: value.clone(),
+                replaced_by: None,
+            }),
+        }
+        path.pop();
+    }
+}
+
+/// Parses the settings, setting aside whatever values are in the way so that their defaults are
+/// used instead. Each value set aside, or that there's no field for, is added to `problems` and
+/// `kept`
+pub fn validate<T: DeserializeOwned + Serialize>(
+    mut settings: Value,
+    problems: &mut Vec<SettingsProblem>,
+    kept: &mut Vec<KeptValue>,
+) -> Result<T, Error> {
+    loop {
+        let error = match parse::<T>(&settings) {
+            Ok(val) => {
+                let parsed = serde_json::to_value(&val)?;
+                for value in kept.iter_mut() {
+                    value.replaced_by = get_path(&parsed, &value.path).cloned();
+                }
+                let mut unknown = Vec::new();
+                find_unknown(&settings, &parsed, &mut Vec::new(), &mut unknown);
+                for value in unknown {
+                    let path_str = value.path.join(".");
+                    warn!("Unknown setting {}, keeping it as is", path_str);
+                    problems.push(SettingsProblem {
+                        path: path_str,
+                        problem: "unknown setting".to_string(),
+                        defaulted: false,
+                        kept: true,
+                    });
+                    kept.push(value);
+                }
+                return Ok(val);
+            }
+            Err(e) => e,
+        };
+        match find_culprit::<T>(&settings, &mut Vec::new(), &error) {
+            Ok(path) => {
+                let path_str = path.join(".");
+                warn!(
+                    "Bad setting {}: {}, using the default and keeping it in the file",
+                    path_str, error
+                );
+                if let Some(value) = take_path(&mut settings, &path) {
+                    kept.push(KeptValue {
+                        path,
+                        value,
+                        replaced_by: None,
+                    });
+                }
+                problems.push(SettingsProblem {
+                    path: path_str,
+                    problem: error,
+                    defaulted: true,
+                    kept: true,
+                });
+            }
+            Err(required) => {
+                problems.push(SettingsProblem {
+                    path: required.map(|path| path.join(".")).unwrap_or_default(),
+                    problem: error.clone(),
+                    defaulted: false,
+                    kept: false,
+                });
+                bail!("Settings can't be loaded: {}", error);
+            }
+        }
+    }
+}
+
+/// Puts the values the settings couldn't hold back into serialized settings about to be written,
+/// unless the setting has been changed since it was loaded
+pub fn restore_kept(settings: &mut Value) {
+    for kept in KEPT.read().unwrap().iter() {
+        if get_path(settings, &kept.path) == kept.replaced_by.as_ref() {
+            put_path(settings, &kept.path, kept.value.clone());
+        }
+    }
+}
+
+/// Drops nulls from objects, they're what a None looks like in json and toml has no such thing
+pub fn remove_nulls(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            map.retain(|_, value| !value.is_null());
+            for value in map.values_mut() {
+                remove_nulls(value);
+            }
+        }
+        Value::Array(values) => {
+            for value in values.iter_mut() {
+                remove_nulls(value);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Reads, migrates and validates a settings file, recording any problems found
+pub fn load<T: DeserializeOwned + Serialize>(file_name: &str) -> Result<T, Error> {
+    let mut s = Config::new();
+    s.merge(config::File::with_name(file_name).required(false))?;
+    let mut settings: Value = s.try_into()?;
+
+    let mut problems = Vec::new();
+    let mut kept = Vec::new();
+    let res = match migrate(&mut settings) {
+        Ok(()) => validate(settings, &mut problems, &mut kept),
+        Err(e) => {
+            problems.push(SettingsProblem {
+                path: VERSION_KEY.to_string(),
+                problem: e.to_string(),
+                defaulted: false,
+                kept: false,
+            });
+            Err(e)
+        }
+    };
+    *PROBLEMS.write().unwrap() = problems;
+    *KEPT.write().unwrap() = kept;
+    res
+}
@@ -0,0 +1,27 @@
+fn default_ssid() -> String {
+    "Free WiFi".to_string()
+}
+
+/// Settings for the sponsored network, an open wifi network whose traffic the router owner pays
+/// for. It's counted apart from the rest of the router's traffic and can be capped each day
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct SponsoredNetworkSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_ssid")]
+    pub ssid: String,
+    /// Bytes up and down per day (UTC) before the network is cut off until the next day, no cap
+    /// if None
+    #[serde(default)]
+    pub daily_cap: Option<u64>,
+}
+
+impl Default for SponsoredNetworkSettings {
+    fn default() -> Self {
+        SponsoredNetworkSettings {
+            enabled: false,
+            ssid: default_ssid(),
+            daily_cap: None,
+        }
+    }
+}